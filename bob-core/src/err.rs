@@ -0,0 +1,34 @@
+#[derive(Debug)]
+pub enum BobErr {
+    PartitionParse,
+    MissingArgument,
+    IO(std::io::Error),
+    ImageTooSmall,
+    PartitionNameTooLong,
+    NoEFISystemPartition,
+    InvalidGptHeader,
+    InvalidProtectiveMbr,
+    PartitionOutOfBounds,
+    PartitionOverlap,
+    DuplicatePartitionGuid(String),
+    InvalidAlignment,
+    InvalidSectorSize,
+    NoFreeSpace,
+    PartitionTableFull,
+    InvalidPartitionCount,
+    InvalidHybridMbr(String),
+    InvalidMbr(String),
+    TableParse(String),
+    Squashfs(String),
+    Snapshot(String),
+    Monitor(String),
+    Flamegraph(String),
+    Manifest(String),
+    Provision(String),
+    PartitionNotFound(String),
+    HashPartitionTooSmall,
+    InvalidKey,
+    BlockDevice(String),
+    /// Arguments that parse on their own but don't go together.
+    InvalidArgument(String),
+}
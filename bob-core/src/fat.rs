@@ -1,7 +1,7 @@
+use tracing::debug;
 use crate::err::BobErr;
 use crate::gpt::{GptImage, Partition};
 
-const SECTOR_SIZE: u16 = 512;
 const SECTORS_PER_CLUSTER: u8 = 2;
 const NUM_FATS: u8 = 2;
 
@@ -62,7 +62,12 @@ struct FileAllocationTable {
 /// Formats the given partition as a FAT32 filesystem.
 pub fn format_as_fat<T: Partition>(p: &mut T) -> Result<(), BobErr> {
     let meta = FatMeta::new(p);
-    p.write(&meta.bytes()).map_err(BobErr::IO)?;
+    let mut bytes = meta.bytes();
+    // Pad out to whole sectors.
+    let sector_size = p.sector_size();
+    bytes.resize(bytes.len().div_ceil(sector_size) * sector_size, 0);
+    p.write_sectors(0, &bytes).map_err(BobErr::IO)?;
+    debug!(len = bytes.len(), crc = format_args!("{:#010x}", crc32fast::hash(&bytes)), "wrote FAT boot sector and metadata");
     Ok(())
 }
 
@@ -92,12 +97,12 @@ impl FatMeta {
 
 impl Bpb {
     fn new<T: Partition>(p: &T) -> Self {
-	let total_sectors_long = (p.size() / SECTOR_SIZE as usize) as u32;
+	let total_sectors_long = p.sectors() as u32;
 
 	Self {
 	    jmp_short: [0xEB, 0x3C, 0x90],
 	    oem_identifier: [0;8],
-	    bytes_per_sector: SECTOR_SIZE,
+	    bytes_per_sector: p.sector_size() as u16,
 	    sectors_per_cluster: SECTORS_PER_CLUSTER,
 	    reserved_sectors: 0,
 	    num_fats: NUM_FATS,
@@ -0,0 +1,3653 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::{
+    io,
+    io::{
+	SeekFrom,
+	Write,
+	Seek,
+	ErrorKind
+    }
+};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
+use crc32fast::Hasher;
+use memmap2::MmapMut;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use tracing::{debug, trace, warn};
+use crate::err::BobErr;
+use crate::guid::{self, Guid};
+use crate::path::{host_path, names_match};
+use crate::table::{LayoutPartition, TableLayout};
+use crate::sink::{self, ImageSink, IoBackend, ZeroMode};
+
+/// Logical block sizes bob can make images with, the first is the default.
+pub const SECTOR_SIZES: [usize; 2] = [512, 4096];
+/// The spec requires at least 16KiB for each partition entry array, 128 entries of 128
+/// bytes. It's also how many entries a table gets unless asked for more.
+pub const MIN_PARTITION_ENTRIES: u32 = 128;
+const PARTITION_NAME_MAX_BYTES: usize = 72;
+/// How much of a deleted partition gets zeroed: enough to cover the FAT, ext and
+/// squashfs superblocks.
+pub const WIPE_SZ: u64 = 64 * 1024;
+/// The MBR's four partition records, 16 bytes each.
+const MBR_RECORDS_OFFSET: u64 = 446;
+/// Where the protective MBR's partition record keeps its size.
+const MBR_SIZE_IN_LBA_OFFSET: u64 = MBR_RECORDS_OFFSET + 12;
+/// Where the 32 bit disk signature lives in an MBR.
+const MBR_SIGNATURE_OFFSET: u64 = 440;
+/// Partition alignment unless told otherwise, what most partitioning tools use.
+pub const DEFAULT_ALIGNMENT: usize = 1024 * 1024;
+const GPT_SIGNATURE: u64 = 0x5452415020494645; // ASCII string “EFI PART”
+const GPT_HEADER_SZ: usize = 92;
+const GPT_ENTRY_SZ: usize = 128;
+/// Room for header fields past revision 1.0's, a header fills at most a 4KiB block.
+const MAX_HEADER_EXT_SZ: usize = 4096 - GPT_HEADER_SZ;
+/// Upper bound on the size of a partition entry array we're willing to read.
+const MAX_PARTITION_ARRAY_SZ: usize = 1024 * 1024;
+
+pub struct GptImage {
+    hdr: GptHeader,
+    bkp_hdr: GptHeader,
+    /// The backup header on disk didn't check out, `bkp_hdr` is what it should be.
+    bkp_damaged: bool,
+    /// Entries by their slot in the array, None where a slot is unused. Partitions are
+    /// numbered by slot, as the kernel and other tools number them, so a gap stays a gap.
+    /// Unused slots after the last used one are left off.
+    pentry: Vec<Option<GptPartitionEntry>>,
+    /// Logical block size, read from the image's headers.
+    block_sz: usize,
+    fd: File,
+    /// With `IoBackend::Mmap`, the image mapped for partition views.
+    map: Option<MmapMut>,
+}
+
+/// An image with a classic MBR partition table and nothing of GPT. The entries keep the
+/// names and types the partitions were created with, the MBR itself only has a type byte.
+pub struct MbrImage {
+    entries: Vec<GptPartitionEntry>,
+    block_sz: usize,
+    fd: File,
+    map: Option<MmapMut>,
+}
+
+/// Looking up an image's partitions, whichever partition table it has.
+pub trait DiskImage {
+    /// Names of the partitions with the given type.
+    fn partitions_of_type(&self, pt: PartitionType) -> Vec<String>;
+    /// Returns a view of the first partition with the given name (ignoring case).
+    fn get_partition_view(&mut self, name: &str) -> Option<PartitionView<'_>>;
+    /// Switch how partition views read and write the image from here on.
+    fn set_io_backend(&mut self, backend: IoBackend) -> Result<(), BobErr>;
+}
+
+/// A partition addressed in whole sectors, relative to the start of the partition.
+/// Formatters only go through this so they work on anything that can read and write
+/// sectors, not just a region of an image file.
+pub trait Partition {
+    fn ptype(&self) -> PartitionType;
+    fn name(&self) -> &str;
+    /// Size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+    /// Number of sectors in the partition.
+    fn sectors(&self) -> u64;
+    /// Read `buf.len() / sector_size()` sectors starting at `sector`. `buf` must be a
+    /// whole number of sectors.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Write `buf.len() / sector_size()` sectors starting at `sector`. `buf` must be a
+    /// whole number of sectors.
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> io::Result<()>;
+    /// Zero `count` sectors starting at `sector`. Writes zeros unless the partition has a
+    /// cheaper way, like leaving a hole in a sparse image.
+    fn zero_sectors(&mut self, sector: u64, count: u64) -> io::Result<()> {
+	let zeros = vec![0; 64 * self.sector_size()];
+	let mut done = 0;
+	while done < count {
+	    let n = (count - done).min(64);
+	    self.write_sectors(sector + done, &zeros[..n as usize * self.sector_size()])?;
+	    done += n;
+	}
+	Ok(())
+    }
+}
+
+/// A 'view' into a partition. Allows for reading and writing to
+/// only the given partition specified by the GptPartitonEntry
+/// without having access to other parts of the GptImage.
+pub struct PartitionView<'a> {
+    meta: &'a GptPartitionEntry,
+    block_sz: usize,
+    offset: u64,
+    fd: &'a mut File,
+    map: Option<&'a mut MmapMut>,
+}
+
+// Builders and input strutures
+
+/// Partitoion input data collected from the cmd line
+#[derive(Clone, Debug)]
+pub struct PartitionInput {
+    pt: PartitionType,
+    /// Defaults to the name of the type.
+    name: Option<String>,
+    /// Placed after the previous partition when missing.
+    start_offset: Option<usize>,
+    /// Derived from the size when missing.
+    end_offset: Option<usize>,
+    size: Option<usize>,
+    attributes: u64,
+    /// Random when missing.
+    unique_guid: Option<Guid>,
+}
+
+pub struct DiskImgBuilder {
+    image_size: Option<usize>,
+    output: Option<PathBuf>,
+    partitions: Vec<PartitionInput>,
+    layout: Option<TableLayout>,
+    alignment: usize,
+    sector_size: usize,
+    max_partitions: u32,
+    /// Partitions (indices into `partitions`) to mirror into a hybrid MBR.
+    hybrid_mbr: Vec<usize>,
+    table: PartitionTable,
+    /// Seed for GUIDs and the like, random if None.
+    seed: Option<u64>,
+    /// Random (or seeded) when missing.
+    disk_guid: Option<Guid>,
+    zero: Option<ZeroMode>,
+}
+
+/// Everything needed to write a disk image, worked out up front so it can be validated
+/// (and shown to the user) before touching the filesystem.
+pub struct ImagePlan {
+    path: PathBuf,
+    image_size: usize,
+    block_sz: usize,
+    /// Size of the partition entry arrays, in entries.
+    num_entries: u32,
+    disk_guid: Guid,
+    entries: Vec<GptPartitionEntry>,
+    hybrid_mbr: Vec<usize>,
+    table: PartitionTable,
+    mbr_signature: u32,
+    zero: Option<ZeroMode>,
+}
+
+pub struct PartitionBuilder {
+    pt: Option<PartitionType>,
+    name: Option<String>,
+    start_offset: Option<usize>,
+    end_offset: Option<usize>,
+    size: Option<usize>,
+    attributes: u64,
+    unique_guid: Option<Guid>,
+}
+
+// GPT Metadata structures
+
+#[derive(Clone, Copy)]
+struct GptHeader {
+    signature: u64,
+    revision: u32,
+    header_sz: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alt_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    partition_entry_lba: u64,
+    num_partition_entries: u32,
+    partition_entry_sz: u32,
+    partition_entry_array_crc32: u32,
+    /// What a header larger than revision 1.0's (a newer revision, or a vendor extension)
+    /// has after the fields above, up to `header_sz`. Kept as read so rewriting a foreign
+    /// table doesn't drop it.
+    ext: [u8; MAX_HEADER_EXT_SZ],
+}
+
+/// What `GptImage::inspect_json` prints.
+#[derive(Serialize)]
+struct Inspection {
+    /// Bytes, up to and including the backup header.
+    size: u64,
+    primary_header: HeaderFields,
+    /// What it should hold if `backup_damaged`.
+    backup_header: HeaderFields,
+    backup_damaged: bool,
+    #[serde(flatten)]
+    table: TableLayout,
+}
+
+/// A header's fields for JSON output, the disk GUID is in the layout.
+#[derive(Serialize)]
+struct HeaderFields {
+    signature: String,
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    partition_entry_lba: u64,
+    partition_entries: u32,
+    partition_entry_size: u32,
+    entry_array_crc32: u32,
+}
+
+impl From<&GptHeader> for HeaderFields {
+    fn from(hdr: &GptHeader) -> Self {
+	Self {
+	    signature: String::from_utf8_lossy(&hdr.signature.to_le_bytes()).into_owned(),
+	    revision: hdr.revision,
+	    header_size: hdr.header_sz,
+	    header_crc32: hdr.header_crc32,
+	    my_lba: hdr.my_lba,
+	    alternate_lba: hdr.alt_lba,
+	    first_usable_lba: hdr.first_usable_lba,
+	    last_usable_lba: hdr.last_usable_lba,
+	    partition_entry_lba: hdr.partition_entry_lba,
+	    partition_entries: hdr.num_partition_entries,
+	    partition_entry_size: hdr.partition_entry_sz,
+	    entry_array_crc32: hdr.partition_entry_array_crc32,
+	}
+    }
+}
+
+#[derive(Debug)]
+struct GptPartitionEntry {
+    partition_type_guid: Guid,
+    unique_partition_guid: Guid,
+    starting_lba: u64,
+    ending_lba: u64,
+    attributes: u64,
+    partition_name: String,
+    /// The name field as read. Tools leave whatever they like after the terminating NUL,
+    /// it's written back as it was as long as the name hasn't changed.
+    raw_name: Option<[u8; PARTITION_NAME_MAX_BYTES]>,
+    /// Bytes past `GPT_ENTRY_SZ` in an array with larger entries.
+    ext: Vec<u8>,
+}
+
+struct PartitionRecord {
+    boot_indicator: u8,
+    starting_chs: [u8;3],
+    os_type: u8,
+    ending_chs: [u8;3],
+    starting_lba: u32,
+    size_in_lba: u32,
+}
+
+/// Partition Type GUID
+/// https://en.wikipedia.org/wiki/GUID_Partition_Table#Partition_type_GUIDs
+#[derive(Clone, Copy, Debug)]
+pub enum PartitionType {
+    EFISystem,
+    LinuxFilesystem,
+    LinuxSwap,
+    MicrosoftBasicData,
+    BIOSBoot,
+    LinuxRootX86_64,
+    /// Settings the kernel applies on its first boot, see common::provision.
+    Provision,
+    /// Any other type, given by its GUID.
+    Other(Guid),
+}
+
+/// The kind of partition table an image is created with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionTable {
+    Gpt,
+    /// A classic MBR with up to four primary partitions, for targets that don't do GPT.
+    Mbr,
+}
+
+impl GptImage {
+    /// Opens an existing disk image without allowing any modifications. Partition entries
+    /// that are out of bounds or overlap are only a warning, so a bad table can still be
+    /// inspected.
+    pub fn open_read_only(path: &str) -> Result<Self, BobErr> {
+	let fd = File::options()
+	    .read(true)
+	    .open(host_path(path)).map_err(BobErr::IO)?;
+	let img = Self::read(fd)?;
+	if let Err(e) = img.check_entries() {
+	    warn!("partition entries don't check out: {e:?}");
+	}
+	Ok(img)
+    }
+
+    /// Opens an existing disk image for reading and writing. Fails if any partition entry
+    /// is out of bounds or overlaps another, nothing is written on the word of a bad table.
+    pub fn open(path: &str) -> Result<Self, BobErr> {
+	let fd = File::options()
+	    .read(true)
+	    .write(true)
+	    .open(host_path(path)).map_err(BobErr::IO)?;
+	let img = Self::read(fd)?;
+	img.check_entries()?;
+	Ok(img)
+    }
+
+    /// The checks a planned layout goes through, on every used slot of the table read.
+    fn check_entries(&self) -> Result<(), BobErr> {
+	check_partitions(self.used(), self.hdr.first_usable_lba, self.hdr.last_usable_lba)
+    }
+
+    /// Reads and validates the protective MBR, GPT headers and partition entry array of an
+    /// image. A damaged backup header or array CRC mismatch is only a warning, rewriting the
+    /// tables fixes both. If the primary header is damaged the backup is used instead.
+    fn read(mut fd: File) -> Result<Self, BobErr> {
+	read_protective_mbr(&mut fd)?;
+	let block_sz = detect_block_sz(&mut fd)?;
+
+	let primary = GptHeader::read(&mut fd, 1, block_sz);
+	let hdr = match primary {
+	    Ok(hdr) => hdr,
+	    Err(e) => {
+		let last_lba = (fd.metadata().map_err(BobErr::IO)?.len() / block_sz as u64).saturating_sub(1);
+		let Ok(bkp) = GptHeader::read(&mut fd, last_lba, block_sz) else {
+		    return Err(e);
+		};
+		warn!("primary GPT header is corrupt, using the backup at LBA {last_lba}");
+		bkp.as_primary()
+	    },
+	};
+
+	let (bkp_hdr, bkp_damaged) = match GptHeader::read(&mut fd, hdr.alt_lba, block_sz) {
+	    Ok(bkp) => (bkp, false),
+	    Err(_) => {
+		warn!("backup GPT header at LBA {} is corrupt", hdr.alt_lba);
+		(hdr.as_backup(block_sz), true)
+	    },
+	};
+
+	let (pentry, array_crc) = GptPartitionEntry::read_array(&mut fd, &hdr, block_sz)?;
+	if array_crc != hdr.partition_entry_array_crc32 {
+	    warn!(
+		"partition entry array CRC is {:#010x}, header says {:#010x}",
+		array_crc, hdr.partition_entry_array_crc32
+	    );
+	}
+
+	Ok(Self {
+	    hdr,
+	    bkp_hdr,
+	    bkp_damaged,
+	    pentry,
+	    block_sz,
+	    fd,
+	    map: None,
+	})
+    }
+
+    /// Rewrite both GPT headers and partition entry arrays from the in-memory table, with
+    /// fresh CRCs.
+    pub fn write_tables(&mut self) -> Result<(), BobErr> {
+	let array = self.entry_array()?;
+	// Over the whole array, the unused entries' zeros included, as the spec has it.
+	let crc = crc32fast::hash(&array);
+	self.hdr.partition_entry_array_crc32 = crc;
+	self.hdr.crc();
+	let block_sz = self.block_sz as u64;
+	self.fd.seek(SeekFrom::Start(self.hdr.my_lba * block_sz)).map_err(BobErr::IO)?;
+	self.hdr.write(&mut self.fd, self.block_sz)?;
+
+	self.write_entry_array(self.hdr.partition_entry_lba, &array)?;
+	debug!(offset = self.hdr.partition_entry_lba * block_sz, len = array.len(), crc = format_args!("{crc:#010x}"), "wrote primary partition entry array");
+
+	let backup_table_lba = self.hdr.alt_lba.checked_sub(self.hdr.array_blocks(self.block_sz)).ok_or(BobErr::InvalidGptHeader)?;
+	self.write_entry_array(backup_table_lba, &array)?;
+	debug!(offset = backup_table_lba * block_sz, len = array.len(), crc = format_args!("{crc:#010x}"), "wrote backup partition entry array");
+
+	// The backup points back at the primary and at its own array, so it needs its own CRC.
+	self.fd.seek(SeekFrom::Start(self.hdr.alt_lba * block_sz)).map_err(BobErr::IO)?;
+	self.bkp_hdr = self.hdr.as_backup(self.block_sz);
+	self.bkp_hdr.crc();
+	self.bkp_hdr.write(&mut self.fd, self.block_sz)?;
+	self.bkp_damaged = false;
+
+	Ok(())
+    }
+
+    /// What `write_tables` would change, one line per GPT structure whose bytes on disk
+    /// differ from what it would write, without writing anything.
+    pub fn table_changes(&self) -> Result<Vec<String>, BobErr> {
+	let array = self.entry_array()?;
+	let mut hdr = self.hdr;
+	hdr.partition_entry_array_crc32 = crc32fast::hash(&array);
+	hdr.crc();
+	let mut bkp = hdr.as_backup(self.block_sz);
+	bkp.crc();
+	let backup_table_lba = hdr.alt_lba.checked_sub(hdr.array_blocks(self.block_sz)).ok_or(BobErr::InvalidGptHeader)?;
+
+	let entry_sz = hdr.partition_entry_sz as usize;
+	let mut changes = Vec::new();
+	for (what, lba, bytes) in [
+	    ("primary GPT header", hdr.my_lba, hdr.to_bytes(self.block_sz)),
+	    ("primary partition entry array", hdr.partition_entry_lba, array.clone()),
+	    ("backup partition entry array", backup_table_lba, array),
+	    ("backup GPT header", hdr.alt_lba, bkp.to_bytes(self.block_sz)),
+	] {
+	    let mut fd = &self.fd;
+	    let mut on_disk = Vec::with_capacity(bytes.len());
+	    fd.seek(SeekFrom::Start(lba * self.block_sz as u64)).map_err(BobErr::IO)?;
+	    // Past the end of the image everything differs.
+	    fd.take(bytes.len() as u64).read_to_end(&mut on_disk).map_err(BobErr::IO)?;
+	    if on_disk == bytes {
+		continue;
+	    }
+	    if what.ends_with("header") {
+		changes.push(format!("rewrite the {what} at LBA {lba}"));
+		continue;
+	    }
+	    let slots: Vec<String> = bytes.chunks(entry_sz).enumerate()
+		.filter(|(i, entry)| on_disk.get(i * entry_sz..(i + 1) * entry_sz) != Some(*entry))
+		.map(|(i, _)| (i + 1).to_string())
+		.collect();
+	    changes.push(format!("rewrite the {what} at LBA {lba}, entries {}", slots.join(", ")));
+	}
+	Ok(changes)
+    }
+
+    /// The whole partition entry array as it goes on disk: each entry in its slot, zeros
+    /// for unused slots and the rest of `num_partition_entries`.
+    fn entry_array(&self) -> Result<Vec<u8>, BobErr> {
+	if self.pentry.len() > self.hdr.num_partition_entries as usize {
+	    return Err(BobErr::PartitionTableFull);
+	}
+	let entry_sz = self.hdr.partition_entry_sz as usize;
+	let mut array = Vec::with_capacity(self.hdr.num_partition_entries as usize * entry_sz);
+	for p in &self.pentry {
+	    let start = array.len();
+	    let Some(p) = p else {
+		array.resize(start + entry_sz, 0);
+		continue;
+	    };
+	    array.extend_from_slice(&p.to_bytes()?);
+	    // Larger entries keep what they had past the standard fields.
+	    array.extend_from_slice(&p.ext);
+	    array.resize(start + entry_sz, 0);
+	    trace!(
+		index = start / entry_sz,
+		crc = format_args!("{:#010x}", crc32fast::hash(&array[start..])),
+		first_lba = p.starting_lba,
+		last_lba = p.ending_lba,
+		name = %p.partition_name,
+		"partition entry"
+	    );
+	}
+	array.resize(self.hdr.num_partition_entries as usize * entry_sz, 0);
+	Ok(array)
+    }
+
+    /// Write `array` at `lba`: the slots up to the last used one in one write, then the
+    /// zeros of the rest, which a sparse image can leave as a hole.
+    fn write_entry_array(&mut self, lba: u64, array: &[u8]) -> Result<(), BobErr> {
+	let used = self.pentry.len() * self.hdr.partition_entry_sz as usize;
+	self.fd.seek(SeekFrom::Start(lba * self.block_sz as u64)).map_err(BobErr::IO)?;
+	self.fd.write_all(&array[..used]).map_err(BobErr::IO)?;
+	self.fd.write_zeroes(lba * self.block_sz as u64 + used as u64, (array.len() - used) as u64)?;
+	Ok(())
+    }
+
+    /// Human readable dump of both GPT headers and the partition entries.
+    pub fn inspect(&self) -> String {
+	let hdr = &self.hdr;
+	let mut s = String::new();
+	s.push_str(&format!("Disk GUID: {}\n", hdr.disk_guid));
+	let blocks = hdr.alt_lba.saturating_add(1);
+	s.push_str(&format!("Size: {} ({blocks} sectors of {} bytes)\n\n", human_size(blocks.saturating_mul(self.block_sz as u64)), self.block_sz));
+
+	s.push_str(&format!("{:<24} {:>20} {:>20}\n", "GPT header", "Primary", "Backup"));
+	let mut field = |name: &str, primary: String, backup: String| {
+	    s.push_str(&format!("{name:<24} {primary:>20} {backup:>20}\n"));
+	};
+	let bkp = &self.bkp_hdr;
+	field("Signature", format!("{:#018x}", hdr.signature), format!("{:#018x}", bkp.signature));
+	field("Revision", format!("{:#010x}", hdr.revision), format!("{:#010x}", bkp.revision));
+	field("Header size", hdr.header_sz.to_string(), bkp.header_sz.to_string());
+	field("Header CRC32", format!("{:#010x}", hdr.header_crc32), format!("{:#010x}", bkp.header_crc32));
+	field("My LBA", hdr.my_lba.to_string(), bkp.my_lba.to_string());
+	field("Alternate LBA", hdr.alt_lba.to_string(), bkp.alt_lba.to_string());
+	field("First usable LBA", hdr.first_usable_lba.to_string(), bkp.first_usable_lba.to_string());
+	field("Last usable LBA", hdr.last_usable_lba.to_string(), bkp.last_usable_lba.to_string());
+	field("Partition entry LBA", hdr.partition_entry_lba.to_string(), bkp.partition_entry_lba.to_string());
+	field("Partition entries", hdr.num_partition_entries.to_string(), bkp.num_partition_entries.to_string());
+	field("Partition entry size", hdr.partition_entry_sz.to_string(), bkp.partition_entry_sz.to_string());
+	field("Entry array CRC32", format!("{:#010x}", hdr.partition_entry_array_crc32), format!("{:#010x}", bkp.partition_entry_array_crc32));
+	if self.bkp_damaged {
+	    s.push_str("The backup header is damaged, the values shown are what it should hold.\n");
+	}
+
+	s.push_str(&format!("\n{:>3} {:>12} {:>12} {:>10} {:>18}  {:<36}  {}\n", "#", "Start LBA", "End LBA", "Size", "Attributes", "Type", "Name"));
+	for (i, p) in self.pentry.iter().enumerate() {
+	    let Some(p) = p else {
+		continue;
+	    };
+	    s.push_str(&format!("{:>3} {:>12} {:>12} {:>10} {:>#18x}  {:<36}  {}\n",
+				i + 1,
+				p.starting_lba,
+				p.ending_lba,
+				// Saturating, the entries of a corrupt table can say anything.
+				human_size((p.ending_lba.saturating_sub(p.starting_lba)).saturating_add(1).saturating_mul(self.block_sz as u64)),
+				p.attributes,
+				p.partition_type_guid,
+				p.partition_name));
+	}
+	if self.pentry.is_empty() {
+	    s.push_str("    no partitions\n");
+	}
+
+	s
+    }
+
+    /// `inspect` as JSON: the table layout (as `export-table` writes it) plus both headers.
+    pub fn inspect_json(&self) -> String {
+	let mut s = serde_json::to_string_pretty(&self.inspection()).expect("inspection to serialize");
+	s.push('\n');
+	s
+    }
+
+    /// What `inspect_json` prints, for comparing images field by field.
+    pub fn inspect_value(&self) -> serde_json::Value {
+	serde_json::to_value(self.inspection()).expect("inspection to serialize")
+    }
+
+    fn inspection(&self) -> Inspection {
+	Inspection {
+	    size: self.hdr.alt_lba.saturating_add(1).saturating_mul(self.block_sz as u64),
+	    primary_header: HeaderFields::from(&self.hdr),
+	    backup_header: HeaderFields::from(&self.bkp_hdr),
+	    backup_damaged: self.bkp_damaged,
+	    table: self.layout(),
+	}
+    }
+
+    /// The number of partitions, not counting unused slots.
+    pub fn partition_count(&self) -> usize {
+	self.used().count()
+    }
+
+    /// The used entries, in slot order.
+    fn used(&self) -> impl Iterator<Item = &GptPartitionEntry> {
+	self.pentry.iter().flatten()
+    }
+
+    /// Drop the unused slots after the last used one.
+    fn trim_unused(&mut self) {
+	while self.pentry.last().is_some_and(Option::is_none) {
+	    self.pentry.pop();
+	}
+    }
+
+    /// Add a partition to the table in its first unused slot and rewrite it. A partition
+    /// given by size alone goes in the first gap it fits in, starting on an `alignment`
+    /// boundary. Returns its index and its first and last LBA.
+    pub fn add_partition(&mut self, p: &PartitionInput, alignment: usize) -> Result<(usize, u64, u64), BobErr> {
+	if alignment == 0 || !alignment.is_multiple_of(self.block_sz) {
+	    return Err(BobErr::InvalidAlignment);
+	}
+	let index = self.pentry.iter().position(Option::is_none).unwrap_or(self.pentry.len());
+	if index >= self.hdr.num_partition_entries as usize {
+	    return Err(BobErr::PartitionTableFull);
+	}
+	let align_lbas = (alignment / self.block_sz) as u64;
+
+	let start = match (p.start_offset, p.size) {
+	    (None, Some(size)) => {
+		let len = size.div_ceil(self.block_sz) as u64;
+		self.find_free(len, align_lbas).ok_or(BobErr::NoFreeSpace)?
+	    },
+	    _ => 0,
+	};
+	let entry = GptPartitionEntry::from_partition(p, start, align_lbas, self.block_sz, guid::new_v4());
+	if !entry.starting_lba.is_multiple_of(align_lbas) {
+	    warn!(name = entry.partition_name, start_lba = entry.starting_lba, alignment, "partition start isn't aligned");
+	}
+	let added = (index, entry.starting_lba, entry.ending_lba);
+
+	if index == self.pentry.len() {
+	    self.pentry.push(None);
+	}
+	self.pentry[index] = Some(entry);
+	if let Err(e) = check_partitions(self.used(), self.hdr.first_usable_lba, self.hdr.last_usable_lba) {
+	    self.pentry[index] = None;
+	    self.trim_unused();
+	    return Err(e);
+	}
+	self.write_tables()?;
+	Ok(added)
+    }
+
+    /// Index of the partition with this name.
+    pub fn find_by_name(&self, name: &str) -> Option<usize> {
+	self.pentry.iter().position(|p| p.as_ref().is_some_and(|p| names_match(&p.partition_name, name)))
+    }
+
+    /// Index of the partition with this unique partition GUID.
+    pub fn find_by_guid(&self, guid: Guid) -> Option<usize> {
+	self.pentry.iter().position(|p| p.as_ref().is_some_and(|p| p.unique_partition_guid == guid))
+    }
+
+    /// A view of the partition at `index`.
+    pub fn partition_view(&mut self, index: usize) -> Option<PartitionView<'_>> {
+	let meta = self.pentry.get(index)?.as_ref()?;
+	Some(PartitionView::new(&mut self.fd, self.map.as_mut(), meta, self.block_sz))
+    }
+
+    /// Copy the contents of the partition at `index` to `out`, returning its size.
+    pub fn extract_partition(&mut self, index: usize, out: &mut impl Write) -> Result<u64, BobErr> {
+	let (start, len) = self.extent(slot(&self.pentry, index)?)?;
+	self.fd.seek(SeekFrom::Start(start)).map_err(BobErr::IO)?;
+	let copied = io::copy(&mut (&mut self.fd).take(len), out).map_err(BobErr::IO)?;
+	if copied != len {
+	    // The image was truncated.
+	    return Err(BobErr::IO(io::Error::from(ErrorKind::UnexpectedEof)));
+	}
+	Ok(copied)
+    }
+
+    /// Byte offset and length of a partition. An entry from a damaged or crafted table can
+    /// end before it starts or lie outside the usable LBAs, that's an error rather than a
+    /// range reaching into the GPT structures.
+    fn extent(&self, p: &GptPartitionEntry) -> Result<(u64, u64), BobErr> {
+	if p.starting_lba > p.ending_lba || p.starting_lba < self.hdr.first_usable_lba || p.ending_lba > self.hdr.last_usable_lba {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+	let block_sz = self.block_sz as u64;
+	let start = p.starting_lba.checked_mul(block_sz);
+	let len = (p.ending_lba - p.starting_lba).checked_add(1).and_then(|n| n.checked_mul(block_sz));
+	start.zip(len).ok_or(BobErr::PartitionOutOfBounds)
+    }
+
+    /// Remove the partition at `index` from the table and rewrite it. Its slot is zeroed
+    /// and the other partitions keep their numbers, as with gdisk and sfdisk. With `wipe`, the first `WIPE_SZ` bytes of the partition are zeroed
+    /// first so filesystem signatures don't outlive it, and the rest is discarded on a
+    /// device that supports it. Returns the partition's name.
+    pub fn delete_partition(&mut self, index: usize, wipe: bool) -> Result<String, BobErr> {
+	let p = slot(&self.pentry, index)?;
+	if wipe {
+	    // Nothing is zeroed or rewritten for an entry that doesn't say where its data is.
+	    let (start, len) = self.extent(p)?;
+	    let wiped = len.min(WIPE_SZ);
+	    self.fd.write_zeroes(start, wiped)?;
+	    if self.fd.discard(start + wiped, len - wiped)? {
+		debug!(name = p.partition_name, "discarded the rest of the partition");
+	    }
+	}
+	let p = self.pentry[index].take().expect("looked up above");
+	self.trim_unused();
+	self.write_tables()?;
+	Ok(p.partition_name)
+    }
+
+    /// Zero the partition at `index`, all of it or with `ZeroMode::Quick` just its ends,
+    /// which is where filesystem, RAID and LVM signatures live. The table is left alone.
+    /// Returns the bytes zeroed.
+    pub fn wipe_partition(&mut self, index: usize, mode: ZeroMode) -> Result<u64, BobErr> {
+	let p = slot(&self.pentry, index)?;
+	let (start, len) = self.extent(p)?;
+	let mut zeroed = 0;
+	for (offset, n) in mode.ranges(start, len) {
+	    let method = self.fd.write_zeroes(offset, n)?;
+	    debug!(name = p.partition_name, offset, len = n, ?method, "wiped");
+	    zeroed += n;
+	}
+	self.fd.flush().map_err(BobErr::IO)?;
+	Ok(zeroed)
+    }
+
+    /// Move the backup header and array to the end of the image file if the file has grown
+    /// since the table was written, making the new space usable. Nothing is written until
+    /// the tables are. Returns whether it moved.
+    pub fn grow_to_file(&mut self) -> Result<bool, BobErr> {
+	let block_sz = self.block_sz as u64;
+	let blocks = self.fd.metadata().map_err(BobErr::IO)?.len() / block_sz;
+	if blocks <= self.hdr.alt_lba + 1 {
+	    return Ok(false);
+	}
+	// The old backup header is in usable space now, don't leave it for anyone to find.
+	self.fd.seek(SeekFrom::Start(self.hdr.alt_lba * block_sz)).map_err(BobErr::IO)?;
+	self.fd.write_all(&vec![0; self.block_sz]).map_err(BobErr::IO)?;
+	// And the protective MBR's partition covers the whole disk, unless it's a hybrid MBR
+	// where it only covers the start.
+	let mut record = [0; 16];
+	self.fd.seek(SeekFrom::Start(MBR_RECORDS_OFFSET)).map_err(BobErr::IO)?;
+	self.fd.read_exact(&mut record).map_err(BobErr::IO)?;
+	if record[4] == 0xEE && le_u32(&record, 12) as u64 >= self.hdr.alt_lba {
+	    self.fd.seek(SeekFrom::Start(MBR_SIZE_IN_LBA_OFFSET)).map_err(BobErr::IO)?;
+	    self.fd.write_all(&(blocks.min(u32::MAX as u64) as u32).to_le_bytes()).map_err(BobErr::IO)?;
+	}
+
+	self.hdr.alt_lba = blocks - 1;
+	self.hdr.last_usable_lba = blocks - 2 - self.hdr.array_blocks(self.block_sz);
+	Ok(true)
+    }
+
+    /// Move the end of the partition at `index` so it's `size` bytes, or as big as the free
+    /// space after it allows with None, and rewrite the table. The data isn't touched,
+    /// shrinking a partition below its filesystem loses data. Returns the new last LBA.
+    pub fn resize_partition(&mut self, index: usize, size: Option<usize>) -> Result<u64, BobErr> {
+	let p = slot(&self.pentry, index)?;
+	let next = self.used()
+	    .map(|q| q.starting_lba)
+	    .filter(|start| *start > p.starting_lba)
+	    .min();
+	let limit = next.map_or(self.hdr.last_usable_lba, |start| start - 1);
+	let end = match size {
+	    Some(0) => return Err(BobErr::PartitionOutOfBounds),
+	    Some(size) => p.starting_lba + size.div_ceil(self.block_sz) as u64 - 1,
+	    None => limit,
+	};
+	if end > limit {
+	    return Err(if next.is_some() { BobErr::PartitionOverlap } else { BobErr::PartitionOutOfBounds });
+	}
+
+	self.pentry[index].as_mut().expect("looked up above").ending_lba = end;
+	self.write_tables()?;
+	Ok(end)
+    }
+
+    /// First aligned LBA with `len` free blocks after it.
+    fn find_free(&self, len: u64, align_lbas: u64) -> Option<u64> {
+	let mut sorted: Vec<_> = self.used().collect();
+	sorted.sort_by_key(|p| p.starting_lba);
+
+	let mut candidate = self.hdr.first_usable_lba.next_multiple_of(align_lbas);
+	for p in sorted {
+	    if candidate.checked_add(len)? <= p.starting_lba {
+		break;
+	    }
+	    candidate = candidate.max((p.ending_lba + 1).next_multiple_of(align_lbas));
+	}
+	(candidate.checked_add(len)? - 1 <= self.hdr.last_usable_lba).then_some(candidate)
+    }
+
+    /// Describes the partition table so it can be exported.
+    pub fn layout(&self) -> TableLayout {
+	TableLayout {
+	    disk_guid: self.hdr.disk_guid,
+	    sector_size: self.block_sz as u64,
+	    first_usable_lba: self.hdr.first_usable_lba,
+	    last_usable_lba: self.hdr.last_usable_lba,
+	    last_lba: self.hdr.alt_lba,
+	    max_partitions: self.hdr.num_partition_entries,
+	    partitions: self.used().map(|p| LayoutPartition {
+		type_guid: p.partition_type_guid,
+		unique_guid: p.unique_partition_guid,
+		first_lba: p.starting_lba,
+		last_lba: p.ending_lba,
+		attributes: p.attributes,
+		name: p.partition_name.clone(),
+	    }).collect(),
+	}
+    }
+
+}
+
+impl DiskImage for GptImage {
+    fn partitions_of_type(&self, pt: PartitionType) -> Vec<String> {
+	entries_of_type(self.used(), pt)
+    }
+
+    fn get_partition_view(&mut self, name: &str) -> Option<PartitionView<'_>> {
+	let matches: Vec<_> = self.pentry.iter().flatten().filter(|p| names_match(&p.partition_name, name)).collect();
+	if let Some(meta) = matches.into_iter().next() {
+	    Some(PartitionView::new(&mut self.fd, self.map.as_mut(), meta, self.block_sz))
+	} else {
+	    None
+	}
+    }
+
+    fn set_io_backend(&mut self, backend: IoBackend) -> Result<(), BobErr> {
+	self.map = sink::map_image(&self.fd, backend)?;
+	Ok(())
+    }
+}
+
+impl DiskImage for MbrImage {
+    fn partitions_of_type(&self, pt: PartitionType) -> Vec<String> {
+	entries_of_type(&self.entries, pt)
+    }
+
+    fn get_partition_view(&mut self, name: &str) -> Option<PartitionView<'_>> {
+	let meta = self.entries.iter().find(|p| names_match(&p.partition_name, name))?;
+	Some(PartitionView::new(&mut self.fd, self.map.as_mut(), meta, self.block_sz))
+    }
+
+    fn set_io_backend(&mut self, backend: IoBackend) -> Result<(), BobErr> {
+	self.map = sink::map_image(&self.fd, backend)?;
+	Ok(())
+    }
+}
+
+/// The bytes of `map` at `offset`, if the image was long enough when it was mapped.
+fn mapped_range(map: &[u8], offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+    let start = usize::try_from(offset).map_err(|_| io::Error::from(ErrorKind::UnexpectedEof))?;
+    match start.checked_add(len) {
+	Some(end) if end <= map.len() => Ok(start..end),
+	_ => Err(io::Error::new(ErrorKind::UnexpectedEof, "Past the end of the mapped image.")),
+    }
+}
+
+/// The entry in slot `index`, an error naming it by number if the slot is unused.
+fn slot(pentry: &[Option<GptPartitionEntry>], index: usize) -> Result<&GptPartitionEntry, BobErr> {
+    pentry.get(index).and_then(Option::as_ref).ok_or_else(|| BobErr::PartitionNotFound(format!("#{}", index + 1)))
+}
+
+fn entries_of_type<'a>(entries: impl IntoIterator<Item = &'a GptPartitionEntry>, pt: PartitionType) -> Vec<String> {
+    entries.into_iter()
+	.filter(|p| p.partition_type_guid == pt.uuid())
+	.map(|p| p.partition_name.clone())
+	.collect()
+}
+
+/// Copy `len` bytes from `src` into the partition, starting `offset` bytes in. Sectors
+/// only partly covered keep the rest of what they had. Nothing is written if it would
+/// run past the end of the partition.
+pub fn write_partition_bytes(p: &mut impl Partition, offset: u64, len: u64, src: &mut impl Read) -> Result<(), BobErr> {
+    let sector_sz = p.sector_size();
+    let capacity = p.sectors() * sector_sz as u64;
+    if offset.checked_add(len).is_none_or(|end| end > capacity) {
+	return Err(BobErr::PartitionOutOfBounds);
+    }
+
+    let mut buf = vec![0; 64 * sector_sz];
+    let (mut pos, end) = (offset, offset + len);
+    while pos < end {
+	let sector = pos / sector_sz as u64;
+	let skip = (pos % sector_sz as u64) as usize;
+	let n = ((end - pos) as usize).min(buf.len() - skip);
+	let whole = (skip + n).next_multiple_of(sector_sz);
+	if whole != n {
+	    p.read_sectors(sector, &mut buf[..whole]).map_err(BobErr::IO)?;
+	}
+	src.read_exact(&mut buf[skip..skip + n]).map_err(BobErr::IO)?;
+	if buf[..whole].iter().all(|b| *b == 0) {
+	    // Runs of zeros, common in filesystem images, stay sparse.
+	    p.zero_sectors(sector, (whole / sector_sz) as u64).map_err(BobErr::IO)?;
+	} else {
+	    p.write_sectors(sector, &buf[..whole]).map_err(BobErr::IO)?;
+	}
+	pos += n as u64;
+    }
+    Ok(())
+}
+
+/// Copy the whole of `src` to the start of `dst`, which may have another sector size.
+/// Returns the bytes copied.
+pub fn copy_partition(src: &mut impl Partition, dst: &mut impl Partition) -> Result<u64, BobErr> {
+    let len = src.sectors() * src.sector_size() as u64;
+    if len > dst.sectors() * dst.sector_size() as u64 {
+	return Err(BobErr::PartitionOutOfBounds);
+    }
+    // A multiple of every sector size, so each chunk starts on a sector on both sides.
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = 0;
+    while pos < len {
+	let n = (len - pos).min(buf.len() as u64) as usize;
+	src.read_sectors(pos / src.sector_size() as u64, &mut buf[..n]).map_err(BobErr::IO)?;
+	write_partition_bytes(dst, pos, n as u64, &mut &buf[..n])?;
+	pos += n as u64;
+    }
+    Ok(len)
+}
+
+impl<'a> PartitionView<'a> {
+    fn new(fd: &'a mut File, map: Option<&'a mut MmapMut>, meta: &'a GptPartitionEntry, block_sz: usize) -> Self {
+	Self {
+	    fd,
+	    map,
+	    meta,
+	    block_sz,
+	    offset: 0,
+	}
+    }
+}
+
+impl<'a> Partition for PartitionView<'a> {
+    fn name(&self) -> &str {
+	&self.meta.partition_name
+    }
+
+    fn ptype(&self) -> PartitionType {
+	PartitionType::from_guid(self.meta.partition_type_guid)
+    }
+
+    fn sector_size(&self) -> usize {
+	self.block_sz
+    }
+
+    /// The ending LBA is inclusive.
+    fn sectors(&self) -> u64 {
+	self.meta.ending_lba.checked_sub(self.meta.starting_lba).map_or(0, |n| n + 1)
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+	let offset = self.sector_offset(sector, buf.len())?;
+	if let Some(map) = self.map.as_deref() {
+	    buf.copy_from_slice(&map[mapped_range(map, offset, buf.len())?]);
+	    return Ok(());
+	}
+	self.fd.seek(SeekFrom::Start(offset))?;
+	self.fd.read_exact(buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> io::Result<()> {
+	let offset = self.sector_offset(sector, buf.len())?;
+	if let Some(map) = self.map.as_deref_mut() {
+	    let range = mapped_range(map, offset, buf.len())?;
+	    map[range].copy_from_slice(buf);
+	} else {
+	    self.fd.seek(SeekFrom::Start(offset))?;
+	    self.fd.write_all(buf)?;
+	}
+	trace!(offset, len = buf.len(), "wrote partition sectors");
+	Ok(())
+    }
+
+    /// Goes through the file even when mapped, holes can only be punched there and the
+    /// mapping sees them all the same.
+    fn zero_sectors(&mut self, sector: u64, count: u64) -> io::Result<()> {
+	let len = count as usize * self.block_sz;
+	let offset = self.sector_offset(sector, len)?;
+	let method = self.fd.write_zeroes(offset, len as u64).map_err(|e| match e {
+	    BobErr::IO(e) => e,
+	    e => io::Error::other(format!("{e:?}")),
+	})?;
+	trace!(offset, len, ?method, "zeroed partition sectors");
+	Ok(())
+    }
+}
+
+impl<'a> PartitionView<'a> {
+    /// Image offset of `sector`, checking a transfer of `len` bytes from there is whole
+    /// sectors and stays inside the partition.
+    fn sector_offset(&self, sector: u64, len: usize) -> io::Result<u64> {
+	if !len.is_multiple_of(self.block_sz) {
+	    return Err(io::Error::new(ErrorKind::InvalidInput, "Buffer isn't a whole number of sectors."));
+	}
+
+	let count = (len / self.block_sz) as u64;
+	if sector.checked_add(count).is_none_or(|end| end > self.sectors()) {
+	    return Err(io::Error::new(ErrorKind::UnexpectedEof, "Sectors are past the partition end."));
+	}
+
+	Ok((self.meta.starting_lba + sector) * self.block_sz as u64)
+    }
+
+    /// Read into `buf` from image offset `offset`, from the mapping or the file.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+	if let Some(map) = self.map.as_deref() {
+	    buf.copy_from_slice(&map[mapped_range(map, offset, buf.len())?]);
+	    return Ok(buf.len());
+	}
+	self.fd.seek(SeekFrom::Start(offset))?;
+	self.fd.read(buf)
+    }
+
+    /// Copy `buf` to image offset `offset`, into the mapping or the file.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+	if let Some(map) = self.map.as_deref_mut() {
+	    let range = mapped_range(map, offset, buf.len())?;
+	    map[range].copy_from_slice(buf);
+	    return Ok(buf.len());
+	}
+	self.fd.seek(SeekFrom::Start(offset))?;
+	self.fd.write(buf)
+    }
+
+    /// Image offset of the partition's first byte, and its size in bytes.
+    fn extent(&self) -> (u64, u64) {
+	(self.meta.starting_lba * self.block_sz as u64, self.sectors() * self.block_sz as u64)
+    }
+}
+
+/// Reads from the partition's current offset, ending at the partition's end as a file's
+/// would.
+impl<'a> Read for PartitionView<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	let (base, size) = self.extent();
+	let remaining = size.saturating_sub(self.offset);
+	let len = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+	if len == 0 {
+	    return Ok(0);
+	}
+
+	let read = self.read_at(base + self.offset, &mut buf[..len])?;
+	trace!(offset = base + self.offset, len = read, "read partition data");
+	self.offset += read as u64;
+	Ok(read)
+    }
+}
+
+impl<'a> Write for PartitionView<'a> {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+	let (base, size) = self.extent();
+
+	let space_remaining = size - self.offset;
+	if buf.len() as u64 > space_remaining {
+	    return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "Cursor would pass partition end writing this buffer."));
+	}
+
+	// read_sectors and write_sectors move the file without moving the cursor.
+	let written = self.write_at(base + self.offset, buf)?;
+	trace!(offset = base + self.offset, len = written, "wrote partition data");
+	self.offset += written as u64;
+
+	Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+	match &self.map {
+	    Some(map) => map.flush(),
+	    None => self.fd.flush(),
+	}
+    }
+}
+
+/// Positions are relative to the start of the partition. Unlike a file's, the cursor can't
+/// go past the end: there's no growing a partition by writing there.
+impl<'a> Seek for PartitionView<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+	let (base, size) = self.extent();
+	let offset = match pos {
+	    SeekFrom::Start(offset) => Some(offset),
+	    SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+	    SeekFrom::End(delta) => size.checked_add_signed(delta),
+	};
+	let offset = match offset {
+	    Some(offset) if offset <= size => offset,
+	    Some(_) => return Err(io::Error::new(ErrorKind::InvalidInput, "Seek past the partition end.")),
+	    None => return Err(io::Error::new(ErrorKind::InvalidInput, "Seek before the partition start.")),
+	};
+
+	self.fd.seek(SeekFrom::Start(base + offset))?;
+	self.offset = offset;
+	Ok(offset)
+    }
+}
+
+impl Default for DiskImgBuilder {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl DiskImgBuilder {
+    pub fn new() -> Self {
+        Self {
+            image_size: None,
+            output: None,
+            partitions: Vec::new(),
+            layout: None,
+            alignment: DEFAULT_ALIGNMENT,
+            sector_size: SECTOR_SIZES[0],
+            max_partitions: MIN_PARTITION_ENTRIES,
+            hybrid_mbr: Vec::new(),
+            table: PartitionTable::Gpt,
+            seed: None,
+            disk_guid: None,
+            zero: None,
+        }
+    }
+
+    /// Total size of the output disk image
+    pub fn total_size(mut self, s: usize) -> Self {
+        self.image_size = Some(s);
+        self
+    }
+
+    /// Filename to use for the created disk image.
+    pub fn output_file(mut self, o: &str) -> Self {
+        self.output = Some(host_path(o));
+        self
+    }
+
+    /// Adds a partition to create in the generated disk image.
+    pub fn partition(mut self, p: PartitionInput) -> Self {
+	self.partitions.push(p);
+        self
+    }
+
+    /// Boundary in bytes that partition starts are aligned to, a multiple of the sector
+    /// size. Partitions given by size are placed on it, explicit offsets off it are
+    /// warned about.
+    pub fn alignment(mut self, bytes: usize) -> Self {
+	self.alignment = bytes;
+	self
+    }
+
+    /// Logical block size of the image, one of `SECTOR_SIZES`.
+    pub fn sector_size(mut self, bytes: usize) -> Self {
+	self.sector_size = bytes;
+	self
+    }
+
+    /// Number of entries in the partition entry arrays, at least `MIN_PARTITION_ENTRIES`.
+    /// More entries push the first usable LBA further in.
+    pub fn max_partitions(mut self, n: u32) -> Self {
+	self.max_partitions = n;
+	self
+    }
+
+    /// Mirror up to three partitions, by their index in the order they were added, into
+    /// real MBR partition records so BIOS machines can boot the image too. The protective
+    /// 0xEE record then only covers the space before the first of them.
+    pub fn hybrid_mbr(mut self, partitions: Vec<usize>) -> Self {
+	self.hybrid_mbr = partitions;
+	self
+    }
+
+    /// The kind of partition table to write, GPT unless asked otherwise. An MBR takes at
+    /// most four partitions of types that have an MBR type byte.
+    pub fn table(mut self, table: PartitionTable) -> Self {
+	self.table = table;
+	self
+    }
+
+    /// Derive every GUID and the MBR disk signature from `seed` instead of the system's
+    /// randomness, and name the image after the seed rather than the time, so building
+    /// the same inputs twice gives the same bytes. The generator is only stable for a given
+    /// version of the rand crate, which Cargo.lock pins.
+    pub fn seed(mut self, seed: u64) -> Self {
+	self.seed = Some(seed);
+	self
+    }
+
+    /// Pin the GPT disk GUID, for provisioning systems that identify disks by it. The
+    /// partitions' GUIDs are unaffected, seeded ones stay the same with or without it.
+    pub fn disk_guid(mut self, guid: Guid) -> Self {
+	self.disk_guid = Some(guid);
+	self
+    }
+
+    /// Clear what's left in the partitions from before, for writing to a device or over
+    /// an old image.
+    pub fn zero_partitions(mut self, mode: ZeroMode) -> Self {
+	self.zero = Some(mode);
+	self
+    }
+
+    /// Recreate an exported partition table exactly (GUIDs, names, attributes and all)
+    /// instead of building one from partition inputs. If no size is given the image will
+    /// be as large as the layout describes.
+    pub fn layout(mut self, layout: TableLayout) -> Self {
+	self.layout = Some(layout);
+	self
+    }
+
+
+    /// Build the disk image file.
+    pub fn build(self) -> Result<GptImage, BobErr> {
+	self.plan()?.write()
+    }
+
+    /// Work out and validate the image layout without writing anything.
+    pub fn plan(self) -> Result<ImagePlan, BobErr> {
+	// Default to append the current time since UNIX EPOCH to avoid overwriting any old
+	// images using the default filename by accident.
+	let path = if let Some(f) = self.output {
+	    f
+	} else if let Some(seed) = self.seed {
+	    PathBuf::from(format!("disk_image_{seed}.img"))
+	} else {
+	    let suffix = SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap()
+		.as_secs().to_string();
+	    PathBuf::from(format!("disk_image_{suffix}.img"))
+	};
+
+	// An applied layout keeps the sector size it was exported with.
+	let block_sz = self.layout.as_ref().map_or(self.sector_size, |l| l.sector_size as usize);
+	if !SECTOR_SIZES.contains(&block_sz) {
+	    return Err(BobErr::InvalidSectorSize);
+	}
+	let num_entries = self.layout.as_ref().map_or(self.max_partitions, |l| l.max_partitions);
+	if num_entries < MIN_PARTITION_ENTRIES {
+	    return Err(BobErr::InvalidPartitionCount);
+	}
+	if self.table == PartitionTable::Mbr && self.disk_guid.is_some() {
+	    return Err(BobErr::InvalidMbr(String::from("an MBR has no disk GUID to set")));
+	}
+	let layout_size = self.layout.as_ref().map(|l| (l.last_lba as usize + 1) * block_sz);
+	let mut rng = match self.seed {
+	    Some(seed) => StdRng::seed_from_u64(seed),
+	    None => StdRng::from_entropy(),
+	};
+	// This is already enforced by clap, just being careful.
+	let image_size = self.image_size.or(layout_size).ok_or(BobErr::MissingArgument)?;
+
+	let (disk_guid, entries) = if let Some(layout) = self.layout {
+	    (self.disk_guid.unwrap_or(layout.disk_guid), layout.partitions.iter().map(GptPartitionEntry::from_layout).collect())
+	} else {
+	    if self.alignment == 0 || !self.alignment.is_multiple_of(block_sz) {
+		return Err(BobErr::InvalidAlignment);
+	    }
+	    let align_lbas = (self.alignment / block_sz) as u64;
+	    let (first_usable, _) = self.table.usable_lbas(image_size, block_sz, num_entries)?;
+	    // Drawn either way so the partitions' seeded GUIDs don't move.
+	    let disk_guid = self.disk_guid.unwrap_or(guid::from_rng(&mut rng));
+	    let mut next_free = first_usable;
+	    let entries = self.partitions.iter().map(|p| {
+		let e = GptPartitionEntry::from_partition(p, next_free, align_lbas, block_sz, guid::from_rng(&mut rng));
+		if !e.starting_lba.is_multiple_of(align_lbas) {
+		    warn!(name = e.partition_name, start_lba = e.starting_lba, alignment = self.alignment, "partition start isn't aligned");
+		}
+		next_free = next_free.max(e.ending_lba + 1);
+		e
+	    }).collect();
+	    (disk_guid, entries)
+	};
+
+	let plan = ImagePlan {
+	    path,
+	    image_size,
+	    block_sz,
+	    num_entries,
+	    disk_guid,
+	    entries,
+	    hybrid_mbr: self.hybrid_mbr,
+	    table: self.table,
+	    mbr_signature: rng.gen(),
+	    zero: self.zero,
+	};
+	plan.validate()?;
+	Ok(plan)
+    }
+
+    /// Write the Protective MBR Header.
+    /// Ref: https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html#protective-mbr
+    fn write_protective_mbr_header(f: &mut File, size: usize, block_sz: usize) -> Result<(), BobErr> {
+	// Only the first partition record and the signature are set, the rest of the block
+	// (boot code, disk signature, the other records, padding to the block size) is zero.
+	let mut block = vec![0; block_sz];
+
+	let mut first_record = PartitionRecord::new();
+	first_record.starting_chs = [0x00, 0x02, 0x00];
+
+	let ending_chs = size / block_sz;
+	if ending_chs >= 0xFF_FF_FF {
+	    first_record.ending_chs = [0xFF, 0xFF, 0xFF];
+	} else {
+	    first_record.ending_chs = [(ending_chs | 0xFF) as u8, ((ending_chs >> 8) | 0xFF) as u8, ((ending_chs >> 16) | 0xFF) as u8];
+	}
+
+	first_record.os_type = 0xEE;
+	first_record.starting_lba = 0x00000001;
+	first_record.size_in_lba = (size / block_sz) as u32;
+	let records = MBR_RECORDS_OFFSET as usize;
+	block[records..records + 16].copy_from_slice(&first_record.to_bytes());
+
+	// signature, set to 0xAA55.
+	block[records + 4 * 16..records + 4 * 16 + 2].copy_from_slice(&[0x55, 0xAA]);
+	f.seek(SeekFrom::Start(0)).map_err(BobErr::IO)?;
+	f.write_all(&block).map_err(BobErr::IO)?;
+
+	debug!(len = block_sz, size_in_lba = size / block_sz, "wrote protective MBR");
+	Ok(())
+    }
+
+    /// Write the partition table
+    /// Header reference: https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html#gpt-header
+    /// Entry reference: https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html#gpt-partition-entry-array
+    fn write_gpt_partition_table(gpt: &mut GptImage, image_size: usize, num_entries: u32, disk_guid: Guid, partition_entries: Vec<GptPartitionEntry>) -> Result<(), BobErr> {
+	let mut header = GptHeader::new();
+	header.disk_guid = disk_guid;
+
+	// We don't extend the Protective MBR beyond 1 logical block in size
+	// so this header is the second (or index 1).
+	header.my_lba = 1;
+	// Alternate (backup) header is located in the last logical block
+	header.alt_lba = (image_size / gpt.block_sz) as u64 - 1;
+	(header.first_usable_lba, header.last_usable_lba) = usable_lbas(image_size, gpt.block_sz, num_entries)?;
+
+	// Partiton table information
+	header.partition_entry_lba = 2;
+	header.num_partition_entries = num_entries;
+	header.partition_entry_sz = GPT_ENTRY_SZ as u32;
+
+	gpt.hdr = header;
+	gpt.pentry = partition_entries.into_iter().map(Some).collect();
+	gpt.write_tables()
+    }
+}
+
+impl ImagePlan {
+    /// Check the image is big enough and the partitions fit in the usable area without
+    /// overlapping each other.
+    fn validate(&self) -> Result<(), BobErr> {
+	let (first_usable, last_usable) = self.table.usable_lbas(self.image_size, self.block_sz, self.num_entries)?;
+	match self.table {
+	    PartitionTable::Gpt => {
+		if self.entries.len() > self.num_entries as usize {
+		    return Err(BobErr::PartitionTableFull);
+		}
+		check_partitions(&self.entries, first_usable, last_usable)?;
+		self.hybrid_mbr_records().map(|_| ())
+	    },
+	    PartitionTable::Mbr => {
+		if self.entries.len() > 4 {
+		    return Err(BobErr::PartitionTableFull);
+		}
+		if !self.hybrid_mbr.is_empty() {
+		    return Err(BobErr::InvalidHybridMbr(String::from("an MBR partition table has no GPT to mirror")));
+		}
+		check_partitions(&self.entries, first_usable, last_usable)?;
+		self.mbr_records().map(|_| ())
+	    },
+	}
+    }
+
+    /// The records of a plain MBR partition table, in the order the partitions were given.
+    fn mbr_records(&self) -> Result<[PartitionRecord; 4], BobErr> {
+	let mut records = [PartitionRecord::new(), PartitionRecord::new(), PartitionRecord::new(), PartitionRecord::new()];
+	for (i, (r, p)) in records.iter_mut().zip(&self.entries).enumerate() {
+	    let (os_type, start, size) = mbr_geometry(p).map_err(|e| match e {
+		MbrGeometryErr::NoType(name) => BobErr::InvalidMbr(format!("{name} partitions have no MBR type")),
+		MbrGeometryErr::TooFar => BobErr::InvalidMbr(format!("partition {} is beyond what an MBR can address", i + 1)),
+	    })?;
+	    *r = PartitionRecord::lba_only(os_type, start, size);
+	    if p.attributes & LEGACY_BOOT != 0 {
+		r.boot_indicator = 0x80;
+	    }
+	}
+	Ok(records)
+    }
+
+    /// The four MBR partition records of a hybrid MBR: the 0xEE record up to the first
+    /// mirrored partition, then the mirrored partitions in disk order.
+    fn hybrid_mbr_records(&self) -> Result<[PartitionRecord; 4], BobErr> {
+	let mut records = [PartitionRecord::new(), PartitionRecord::new(), PartitionRecord::new(), PartitionRecord::new()];
+	if self.hybrid_mbr.len() > 3 {
+	    return Err(BobErr::InvalidHybridMbr(String::from("at most 3 partitions fit next to the 0xEE record")));
+	}
+	let mut mirrored = Vec::new();
+	for i in &self.hybrid_mbr {
+	    let p = self.entries.get(*i).ok_or_else(|| BobErr::InvalidHybridMbr(format!("there is no partition {}", i + 1)))?;
+	    let (os_type, start, size) = mbr_geometry(p).map_err(|e| match e {
+		MbrGeometryErr::NoType(name) => BobErr::InvalidHybridMbr(format!("{name} partitions have no MBR type")),
+		MbrGeometryErr::TooFar => BobErr::InvalidHybridMbr(format!("partition {} is beyond what an MBR can address", i + 1)),
+	    })?;
+	    mirrored.push((start, size, os_type, p.attributes & LEGACY_BOOT != 0));
+	}
+	mirrored.sort_by_key(|m| m.0);
+	mirrored.dedup_by_key(|m| m.0);
+
+	if let Some((first, ..)) = mirrored.first() {
+	    records[0] = PartitionRecord::lba_only(0xEE, 1, first - 1);
+	}
+	for (r, (start, size, os_type, bootable)) in records[1..].iter_mut().zip(mirrored) {
+	    *r = PartitionRecord::lba_only(os_type, start, size);
+	    if bootable {
+		r.boot_indicator = 0x80;
+	    }
+	}
+	Ok(records)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+	&self.path
+    }
+
+    pub fn image_size(&self) -> usize {
+	self.image_size
+    }
+
+    /// Partitions mirrored into a hybrid MBR, as indices into the entries.
+    pub fn hybrid_mbr(&self) -> &[usize] {
+	&self.hybrid_mbr
+    }
+
+    pub fn table(&self) -> PartitionTable {
+	self.table
+    }
+
+    pub fn zero(&self) -> Option<ZeroMode> {
+	self.zero
+    }
+
+    /// Write the planned image to disk, for a GPT plan.
+    pub fn write(self) -> Result<GptImage, BobErr> {
+	assert_eq!(self.table, PartitionTable::Gpt, "an MBR plan is written with write_mbr");
+	let f = File::options()
+	    .read(true)
+	    .write(true)
+	    .create(true)
+	    .open(&self.path).map_err(BobErr::IO)?;
+
+	let mut gpt = GptImage {
+	    hdr: GptHeader::new(),
+	    bkp_hdr: GptHeader::new(),
+	    bkp_damaged: false,
+	    pentry: Vec::new(),
+	    block_sz: self.block_sz,
+	    fd: f,
+	    map: None,
+	};
+
+	set_image_len(&gpt.fd, self.image_size as u64)?;
+	self.zero_partitions(&mut gpt.fd)?;
+	DiskImgBuilder::write_protective_mbr_header(&mut gpt.fd, self.image_size, self.block_sz)?;
+	if !self.hybrid_mbr.is_empty() {
+	    let records: Vec<u8> = self.hybrid_mbr_records()?.iter().flat_map(|r| r.to_bytes()).collect();
+	    gpt.fd.seek(SeekFrom::Start(MBR_RECORDS_OFFSET)).map_err(BobErr::IO)?;
+	    gpt.fd.write_all(&records).map_err(BobErr::IO)?;
+	    debug!(partitions = ?self.hybrid_mbr, "wrote hybrid MBR");
+	}
+	DiskImgBuilder::write_gpt_partition_table(&mut gpt, self.image_size, self.num_entries, self.disk_guid, self.entries)?;
+
+	Ok(gpt)
+    }
+
+    /// Write the planned image to disk, for an MBR plan: the partition records and the
+    /// disk signature, nothing else outside the partitions.
+    pub fn write_mbr(self) -> Result<MbrImage, BobErr> {
+	assert_eq!(self.table, PartitionTable::Mbr, "a GPT plan is written with write");
+	let records = self.mbr_records()?;
+	let mut fd = File::options()
+	    .read(true)
+	    .write(true)
+	    .create(true)
+	    .truncate(true)
+	    .open(&self.path).map_err(BobErr::IO)?;
+	set_image_len(&fd, self.image_size as u64)?;
+	self.zero_partitions(&mut fd)?;
+
+	// The disk signature, two reserved bytes, the records and the boot signature.
+	let signature = self.mbr_signature;
+	let mut tail = Vec::with_capacity(512 - MBR_SIGNATURE_OFFSET as usize);
+	tail.extend_from_slice(&signature.to_le_bytes());
+	tail.extend_from_slice(&[0; 2]);
+	records.iter().for_each(|r| tail.extend_from_slice(&r.to_bytes()));
+	tail.extend_from_slice(&[0x55, 0xAA]);
+	fd.seek(SeekFrom::Start(MBR_SIGNATURE_OFFSET)).map_err(BobErr::IO)?;
+	fd.write_all(&tail).map_err(BobErr::IO)?;
+	debug!(signature = format!("{signature:08x}"), partitions = self.entries.len(), "wrote MBR partition table");
+
+	Ok(MbrImage { entries: self.entries, block_sz: self.block_sz, fd, map: None })
+    }
+
+    /// Zero the planned partitions' ranges, if asked to.
+    fn zero_partitions(&self, f: &mut File) -> Result<(), BobErr> {
+	let Some(mode) = self.zero else { return Ok(()) };
+	let block_sz = self.block_sz as u64;
+	for p in &self.entries {
+	    let (start, len) = (p.starting_lba * block_sz, (p.ending_lba - p.starting_lba + 1) * block_sz);
+	    for (offset, n) in mode.ranges(start, len) {
+		let method = f.write_zeroes(offset, n)?;
+		debug!(name = p.partition_name, offset, len = n, ?method, "zeroed");
+	    }
+	}
+	Ok(())
+    }
+
+    /// Human readable description of the layout: partition ranges, their alignment, and
+    /// the free space left between them.
+    pub fn describe(&self) -> String {
+	let block_sz = self.block_sz as u64;
+	let sectors = self.image_size as u64 / block_sz;
+	let (first_usable, last_usable) = self.table.usable_lbas(self.image_size, self.block_sz, self.num_entries).unwrap_or((0, 0));
+
+	let mut s = String::new();
+	s.push_str(&format!("Image: {}\n", self.path.display()));
+	s.push_str(&format!("Size: {} ({} bytes, {} sectors of {} bytes)\n", human_size(self.image_size as u64), self.image_size, sectors, block_sz));
+	match self.table {
+	    PartitionTable::Gpt => s.push_str(&format!("Disk GUID: {}\n", self.disk_guid)),
+	    PartitionTable::Mbr => s.push_str("Partition table: MBR\n"),
+	}
+	s.push_str(&format!("Usable LBAs: {first_usable} - {last_usable}\n\n"));
+
+	s.push_str(&format!("{:>3} {:>12} {:>12} {:>10} {:>9}  {:<36}  {}\n", "#", "Start LBA", "End LBA", "Size", "Align", "Type", "Name"));
+	let mut sorted: Vec<_> = self.entries.iter().enumerate().collect();
+	sorted.sort_by_key(|(_, p)| p.starting_lba);
+	for (i, p) in &sorted {
+	    let start = p.starting_lba * block_sz;
+	    // Largest power of two the start offset is a multiple of.
+	    let align = if start == 0 { 0 } else { 1 << start.trailing_zeros() };
+	    s.push_str(&format!("{:>3} {:>12} {:>12} {:>10} {:>9}  {:<36}  {}\n",
+				i + 1,
+				p.starting_lba,
+				p.ending_lba,
+				human_size((p.ending_lba - p.starting_lba + 1) * block_sz),
+				human_size(align),
+				p.partition_type_guid,
+				p.partition_name));
+	}
+
+	s.push_str("\nFree space:\n");
+	let mut next_free = first_usable;
+	let mut any_free = false;
+	for (_, p) in &sorted {
+	    if p.starting_lba > next_free {
+		s.push_str(&format!("    {:>12} {:>12} {:>10}\n", next_free, p.starting_lba - 1, human_size((p.starting_lba - next_free) * block_sz)));
+		any_free = true;
+	    }
+	    next_free = std::cmp::max(next_free, p.ending_lba + 1);
+	}
+	if last_usable >= next_free {
+	    s.push_str(&format!("    {:>12} {:>12} {:>10}\n", next_free, last_usable, human_size((last_usable - next_free + 1) * block_sz)));
+	    any_free = true;
+	}
+	if !any_free {
+	    s.push_str("    none\n");
+	}
+
+	s
+    }
+
+    /// Names of the planned partitions with the given type.
+    pub fn partitions_of_type(&self, pt: PartitionType) -> Vec<&str> {
+	self.entries.iter()
+	    .filter(|p| p.partition_type_guid == pt.uuid())
+	    .map(|p| p.partition_name.as_str())
+	    .collect()
+    }
+}
+
+impl Default for PartitionBuilder {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl PartitionBuilder {
+    pub fn new() -> Self {
+	Self {
+	    pt: None,
+	    name: None,
+	    start_offset: None,
+	    end_offset: None,
+	    size: None,
+	    attributes: 0,
+	    unique_guid: None,
+	}
+    }
+
+    pub fn partition_type(mut self, pt: PartitionType) -> Self {
+	self.pt = Some(pt);
+	self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+	self.name = Some(String::from(name));
+	self
+    }
+
+    pub fn start_offset(mut self, start_offset: usize) -> Self {
+	self.start_offset = Some(start_offset);
+	self
+    }
+
+    pub fn end_offset(mut self, end_offset: usize) -> Self {
+	self.end_offset = Some(end_offset);
+	self
+    }
+
+    /// Size in bytes, instead of an end offset. Without a start offset either the
+    /// partition is placed after the previous one.
+    pub fn size(mut self, size: usize) -> Self {
+	self.size = Some(size);
+	self
+    }
+
+    /// Attribute bits, see `parse_attributes`.
+    pub fn attributes(mut self, attributes: u64) -> Self {
+	self.attributes = attributes;
+	self
+    }
+
+    /// Pin the unique partition GUID, e.g. for a `root=PARTUUID=` command line.
+    pub fn unique_guid(mut self, guid: Guid) -> Self {
+	self.unique_guid = Some(guid);
+	self
+    }
+
+    pub fn build(self) -> Result<PartitionInput, BobErr> {
+	let placed = self.start_offset.is_some() && self.end_offset.is_some() && self.size.is_none();
+	let sized = self.end_offset.is_none() && self.size.is_some_and(|s| s > 0);
+	if self.pt.is_none() || !(placed || sized) {
+	    return Err(BobErr::PartitionParse);
+	}
+	if self.name.as_ref().is_some_and(|n| n.encode_utf16().count() * 2 > PARTITION_NAME_MAX_BYTES) {
+	    return Err(BobErr::PartitionNameTooLong);
+	}
+
+	Ok(PartitionInput {
+	    pt: self.pt.unwrap(),
+	    name: self.name,
+	    start_offset: self.start_offset,
+	    end_offset: self.end_offset,
+	    size: self.size,
+	    attributes: self.attributes,
+	    unique_guid: self.unique_guid,
+	})
+    }
+}
+
+impl PartitionType {
+    /// Looks up a type by its short name in a `-p` partition spec.
+    pub fn from_name(name: &str) -> Option<Self> {
+	match name {
+	    "esp" => Some(Self::EFISystem),
+	    "linux" => Some(Self::LinuxFilesystem),
+	    "swap" => Some(Self::LinuxSwap),
+	    "msdata" => Some(Self::MicrosoftBasicData),
+	    "bios" => Some(Self::BIOSBoot),
+	    "root" => Some(Self::LinuxRootX86_64),
+	    "provision" => Some(Self::Provision),
+	    _ => None,
+	}
+    }
+
+    /// The known type with this GUID, or `Other`.
+    pub fn from_guid(guid: Guid) -> Self {
+	[Self::EFISystem, Self::LinuxFilesystem, Self::LinuxSwap, Self::MicrosoftBasicData, Self::BIOSBoot, Self::LinuxRootX86_64, Self::Provision]
+	    .into_iter()
+	    .find(|pt| pt.uuid() == guid)
+	    .unwrap_or(Self::Other(guid))
+    }
+
+    fn uuid(&self) -> Guid {
+	let s = match self {
+	    Self::EFISystem => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+	    Self::LinuxFilesystem => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+	    Self::LinuxSwap => "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+	    Self::MicrosoftBasicData => "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+	    Self::BIOSBoot => "21686148-6449-6E6F-744E-656564454649",
+	    Self::LinuxRootX86_64 => "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+	    Self::Provision => return Guid::from_bytes(common::provision::TYPE),
+	    Self::Other(guid) => return *guid,
+	};
+	s.parse().unwrap()
+    }
+
+    /// The MBR partition type to use when mirroring a partition into a hybrid MBR.
+    pub fn mbr_type(&self) -> Option<u8> {
+	match self {
+	    Self::EFISystem => Some(0xEF),
+	    Self::LinuxFilesystem | Self::LinuxRootX86_64 => Some(0x83),
+	    Self::LinuxSwap => Some(0x82),
+	    // FAT32 with LBA, what basic data partitions usually hold.
+	    Self::MicrosoftBasicData => Some(0x0C),
+	    Self::BIOSBoot | Self::Provision | Self::Other(_) => None,
+	}
+    }
+
+    pub fn name(&self) -> String {
+	let name = match self {
+	    Self::EFISystem => "EFI system partition",
+	    Self::LinuxFilesystem => "Linux filesystem",
+	    Self::LinuxSwap => "Linux swap",
+	    Self::MicrosoftBasicData => "Basic data partition",
+	    Self::BIOSBoot => "BIOS boot partition",
+	    Self::LinuxRootX86_64 => "Linux root (x86-64)",
+	    Self::Provision => "yoyo provisioning",
+	    // Exactly 36 characters, the longest name that fits.
+	    Self::Other(guid) => return guid.to_string(),
+	};
+	String::from(name)
+    }
+}
+
+/// A short name (see `from_name`) or a type GUID.
+impl FromStr for PartitionType {
+    type Err = BobErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	if let Some(pt) = Self::from_name(s) {
+	    return Ok(pt);
+	}
+	s.parse::<Guid>()
+	    .map(Self::from_guid)
+	    .map_err(|_| BobErr::PartitionParse)
+    }
+}
+
+impl PartitionRecord {
+    fn new() -> Self {
+	Self {
+	    boot_indicator: 0,
+	    starting_chs: [0;3],
+	    os_type: 0,
+	    ending_chs: [0;3],
+	    starting_lba: 0,
+	    size_in_lba: 0,
+	}
+    }
+
+    /// A record addressed by LBA only, CHS fields set to the "too big for CHS" marker.
+    fn lba_only(os_type: u8, starting_lba: u32, size_in_lba: u32) -> Self {
+	Self {
+	    boot_indicator: 0,
+	    starting_chs: [0xFE, 0xFF, 0xFF],
+	    os_type,
+	    ending_chs: [0xFE, 0xFF, 0xFF],
+	    starting_lba,
+	    size_in_lba,
+	}
+    }
+
+    fn to_bytes(&self) -> [u8; 16] {
+	let mut b = [0; 16];
+	b[0] = self.boot_indicator;
+	b[1..4].copy_from_slice(&self.starting_chs);
+	b[4] = self.os_type;
+	b[5..8].copy_from_slice(&self.ending_chs);
+	b[8..12].copy_from_slice(&self.starting_lba.to_le_bytes());
+	b[12..16].copy_from_slice(&self.size_in_lba.to_le_bytes());
+	b
+    }
+}
+
+impl GptHeader {
+
+    fn new() -> Self {
+	Self {
+	    signature: GPT_SIGNATURE,
+	    revision: 0x00010000,
+	    header_sz: 92,
+	    header_crc32: 0,
+	    reserved: 0,
+	    my_lba: 0,
+	    alt_lba: 0,
+	    first_usable_lba: 0,
+	    last_usable_lba: 0,
+	    disk_guid: guid::new_v4(),
+	    partition_entry_lba: 0,
+	    num_partition_entries: 0,
+	    partition_entry_sz: 0,
+	    partition_entry_array_crc32: 0,
+	    ext: [0; MAX_HEADER_EXT_SZ],
+	}
+    }
+
+    /// Read the header stored in the given logical block.
+    fn read(f: &mut File, lba: u64, block_sz: usize) -> Result<Self, BobErr> {
+	let (hdr, b) = Self::read_unchecked(f, lba, block_sz)?;
+	if !hdr.is_sane(block_sz) || hdr.computed_crc(&b) != hdr.header_crc32 {
+	    return Err(BobErr::InvalidGptHeader);
+	}
+	Ok(hdr)
+    }
+
+    /// Read and parse the given logical block without checking it holds a valid header.
+    fn read_unchecked(f: &mut File, lba: u64, block_sz: usize) -> Result<(Self, Vec<u8>), BobErr> {
+	let mut b = vec![0; block_sz];
+	let offset = lba.checked_mul(block_sz as u64).ok_or(BobErr::InvalidGptHeader)?;
+	f.seek(SeekFrom::Start(offset)).map_err(BobErr::IO)?;
+	f.read_exact(&mut b).map_err(BobErr::IO)?;
+
+	let mut hdr = Self {
+	    signature: le_u64(&b, 0),
+	    revision: le_u32(&b, 8),
+	    header_sz: le_u32(&b, 12),
+	    header_crc32: le_u32(&b, 16),
+	    reserved: le_u32(&b, 20),
+	    my_lba: le_u64(&b, 24),
+	    alt_lba: le_u64(&b, 32),
+	    first_usable_lba: le_u64(&b, 40),
+	    last_usable_lba: le_u64(&b, 48),
+	    disk_guid: Guid::from_bytes(b[56..72].try_into().unwrap()),
+	    partition_entry_lba: le_u64(&b, 72),
+	    num_partition_entries: le_u32(&b, 80),
+	    partition_entry_sz: le_u32(&b, 84),
+	    partition_entry_array_crc32: le_u32(&b, 88),
+	    ext: [0; MAX_HEADER_EXT_SZ],
+	};
+	let ext_len = hdr.ext_len(block_sz);
+	hdr.ext[..ext_len].copy_from_slice(&b[GPT_HEADER_SZ..GPT_HEADER_SZ + ext_len]);
+	Ok((hdr, b))
+    }
+
+    /// Images may come from anywhere, so check the signature and that the array size and
+    /// location can't overflow before trusting anything else.
+    fn is_sane(&self, block_sz: usize) -> bool {
+	let array_sz = (self.num_partition_entries as usize).saturating_mul(self.partition_entry_sz as usize);
+	self.signature == GPT_SIGNATURE
+	    && self.partition_entry_lba.checked_mul(block_sz as u64).is_some()
+	    && (self.header_sz as usize) >= GPT_HEADER_SZ
+	    && self.header_sz as usize <= block_sz
+	    && (self.partition_entry_sz as usize) >= GPT_ENTRY_SZ
+	    && array_sz <= MAX_PARTITION_ARRAY_SZ
+    }
+
+    /// CRC of the raw header block `b`, over header_sz bytes with the CRC field itself
+    /// zeroed. Only meaningful for a sane header.
+    fn computed_crc(&self, b: &[u8]) -> u32 {
+	let mut h = Hasher::new();
+	h.update(&b[..16]);
+	h.update(&[0; 4]);
+	h.update(&b[20..self.header_sz as usize]);
+	h.finalize()
+    }
+
+    /// Fields of this header that differ from `expected`, ignoring the header CRC.
+    fn differences(&self, expected: &Self) -> Vec<String> {
+	let mut d = Vec::new();
+	let mut cmp = |name: &str, actual: u64, expected: u64| {
+	    if actual != expected {
+		d.push(format!("{name} is {actual}, expected {expected}"));
+	    }
+	};
+	cmp("my LBA", self.my_lba, expected.my_lba);
+	cmp("alternate LBA", self.alt_lba, expected.alt_lba);
+	cmp("first usable LBA", self.first_usable_lba, expected.first_usable_lba);
+	cmp("last usable LBA", self.last_usable_lba, expected.last_usable_lba);
+	cmp("partition entry LBA", self.partition_entry_lba, expected.partition_entry_lba);
+	cmp("partition entries", self.num_partition_entries as u64, expected.num_partition_entries as u64);
+	cmp("partition entry size", self.partition_entry_sz as u64, expected.partition_entry_sz as u64);
+	if self.partition_entry_array_crc32 != expected.partition_entry_array_crc32 {
+	    d.push(format!("entry array CRC is {:#010x}, expected {:#010x}", self.partition_entry_array_crc32, expected.partition_entry_array_crc32));
+	}
+	if self.disk_guid != expected.disk_guid {
+	    d.push(format!("disk GUID is {}, expected {}", self.disk_guid, expected.disk_guid));
+	}
+	d
+    }
+
+    /// Bytes of `ext` in use, clamped to what a block has room for.
+    fn ext_len(&self, block_sz: usize) -> usize {
+	(self.header_sz as usize).clamp(GPT_HEADER_SZ, block_sz.min(4096)) - GPT_HEADER_SZ
+    }
+
+    /// Size of the partition entry array in logical blocks.
+    fn array_blocks(&self, block_sz: usize) -> u64 {
+	(self.num_partition_entries as u64 * self.partition_entry_sz as u64).div_ceil(block_sz as u64)
+    }
+
+    /// The backup header matching this primary, its array just before it at the end of the disk.
+    fn as_backup(&self, block_sz: usize) -> Self {
+	let mut bkp = *self;
+	bkp.my_lba = self.alt_lba;
+	bkp.alt_lba = self.my_lba;
+	bkp.partition_entry_lba = self.alt_lba.saturating_sub(self.array_blocks(block_sz));
+	bkp
+    }
+
+    /// The primary header matching this backup, its array just after it.
+    fn as_primary(&self) -> Self {
+	let mut hdr = *self;
+	hdr.my_lba = self.alt_lba;
+	hdr.alt_lba = self.my_lba;
+	hdr.partition_entry_lba = self.alt_lba.saturating_add(1);
+	hdr
+    }
+
+    fn write(&self, f: &mut File, block_sz: usize) -> Result<(), BobErr> {
+	let offset = f.stream_position().map_err(BobErr::IO)?;
+	let b = self.to_bytes(block_sz);
+	f.write_all(&b).map_err(BobErr::IO)?;
+	// The rest of the block is reserved, whatever is there stays.
+	f.seek(SeekFrom::Current((block_sz - b.len()) as i64)).map_err(BobErr::IO)?;
+
+	debug!(
+	    offset,
+	    len = GPT_HEADER_SZ,
+	    crc = format_args!("{:#010x}", self.header_crc32),
+	    my_lba = self.my_lba,
+	    alt_lba = self.alt_lba,
+	    "wrote GPT header"
+	);
+	Ok(())
+    }
+
+    /// The header as it goes on disk, without the reserved rest of its block.
+    fn to_bytes(self, block_sz: usize) -> Vec<u8> {
+	let ext_len = self.ext_len(block_sz);
+	let mut b = Vec::with_capacity(GPT_HEADER_SZ + ext_len);
+	b.extend_from_slice(&self.signature.to_le_bytes());
+	b.extend_from_slice(&self.revision.to_le_bytes());
+	b.extend_from_slice(&self.header_sz.to_le_bytes());
+	b.extend_from_slice(&self.header_crc32.to_le_bytes());
+	b.extend_from_slice(&self.reserved.to_le_bytes());
+	b.extend_from_slice(&self.my_lba.to_le_bytes());
+	b.extend_from_slice(&self.alt_lba.to_le_bytes());
+	b.extend_from_slice(&self.first_usable_lba.to_le_bytes());
+	b.extend_from_slice(&self.last_usable_lba.to_le_bytes());
+	b.extend_from_slice(&self.disk_guid.to_bytes());
+	b.extend_from_slice(&self.partition_entry_lba.to_le_bytes());
+	b.extend_from_slice(&self.num_partition_entries.to_le_bytes());
+	b.extend_from_slice(&self.partition_entry_sz.to_le_bytes());
+	b.extend_from_slice(&self.partition_entry_array_crc32.to_le_bytes());
+	b.extend_from_slice(&self.ext[..ext_len]);
+	b
+    }
+
+    fn crc(&mut self) {
+	let mut h = Hasher::new();
+	self.header_crc32 = 0;
+
+	h.update(&self.signature.to_le_bytes());
+	h.update(&self.revision.to_le_bytes());
+	h.update(&self.header_sz.to_le_bytes());
+	h.update(&self.header_crc32.to_le_bytes());
+	h.update(&self.reserved.to_le_bytes());
+	h.update(&self.my_lba.to_le_bytes());
+	h.update(&self.alt_lba.to_le_bytes());
+	h.update(&self.first_usable_lba.to_le_bytes());
+	h.update(&self.last_usable_lba.to_le_bytes());
+	h.update(&self.disk_guid.to_bytes());
+	h.update(&self.partition_entry_lba.to_le_bytes());
+	h.update(&self.num_partition_entries.to_le_bytes());
+	h.update(&self.partition_entry_sz.to_le_bytes());
+	h.update(&self.partition_entry_array_crc32.to_le_bytes());
+	// header_sz is at most the block size, checked when the header was read.
+	h.update(&self.ext[..(self.header_sz as usize).saturating_sub(GPT_HEADER_SZ).min(MAX_HEADER_EXT_SZ)]);
+
+	self.header_crc32 = h.finalize();
+    }
+}
+
+impl GptPartitionEntry {
+
+    /// A new entry for a partition input. Partitions given by size alone start at the
+    /// first LBA from `next_free` that's a multiple of `align_lbas`. `random_guid` is
+    /// used unless the input pins its GUID.
+    fn from_partition(p: &PartitionInput, next_free: u64, align_lbas: u64, block_sz: usize, random_guid: Guid) -> Self {
+	let partition_type_guid = p.pt.uuid();
+	let starting_lba = match p.start_offset {
+	    Some(so) => (so / block_sz) as u64,
+	    None => next_free.next_multiple_of(align_lbas),
+	};
+	let ending_lba = match (p.end_offset, p.size) {
+	    (Some(eo), _) => (eo / block_sz) as u64,
+	    (None, Some(size)) => starting_lba + (size.div_ceil(block_sz) as u64) - 1,
+	    (None, None) => unreachable!("checked by PartitionBuilder"),
+	};
+	let partition_name = p.name.clone().unwrap_or_else(|| p.pt.name());
+
+	Self {
+	    partition_type_guid,
+	    unique_partition_guid: p.unique_guid.unwrap_or(random_guid),
+	    starting_lba,
+	    ending_lba,
+	    attributes: p.attributes,
+	    partition_name,
+	    raw_name: None,
+	    ext: Vec::new(),
+	}
+    }
+
+    fn from_layout(p: &LayoutPartition) -> Self {
+	Self {
+	    partition_type_guid: p.type_guid,
+	    unique_partition_guid: p.unique_guid,
+	    starting_lba: p.first_lba,
+	    ending_lba: p.last_lba,
+	    attributes: p.attributes,
+	    partition_name: p.name.clone(),
+	    raw_name: None,
+	    ext: Vec::new(),
+	}
+    }
+
+    /// Read the entries of the partition entry array described by `hdr` by slot, along
+    /// with the CRC of the whole array.
+    fn read_array(f: &mut File, hdr: &GptHeader, block_sz: usize) -> Result<(Vec<Option<Self>>, u32), BobErr> {
+	let b = Self::read_array_bytes(f, hdr, block_sz)?;
+	Ok((Self::parse_array(&b, hdr), crc32fast::hash(&b)))
+    }
+
+    /// The raw partition entry array described by `hdr`.
+    fn read_array_bytes(f: &mut File, hdr: &GptHeader, block_sz: usize) -> Result<Vec<u8>, BobErr> {
+	let mut b = vec![0; hdr.num_partition_entries as usize * hdr.partition_entry_sz as usize];
+	f.seek(SeekFrom::Start(hdr.partition_entry_lba * block_sz as u64)).map_err(BobErr::IO)?;
+	f.read_exact(&mut b).map_err(BobErr::IO)?;
+	Ok(b)
+    }
+
+    /// The entries of a raw partition entry array by slot, None for unused slots (a zero
+    /// type GUID). Unused slots after the last used one are left off.
+    fn parse_array(b: &[u8], hdr: &GptHeader) -> Vec<Option<Self>> {
+	let mut entries: Vec<_> = b.chunks_exact(hdr.partition_entry_sz as usize)
+	    .map(|e| (e[..16] != [0; 16]).then(|| Self::parse(e)))
+	    .collect();
+	while entries.last().is_some_and(Option::is_none) {
+	    entries.pop();
+	}
+	entries
+    }
+
+    fn parse(b: &[u8]) -> Self {
+	let name: Vec<u16> = b[56..56 + PARTITION_NAME_MAX_BYTES]
+	    .chunks_exact(2)
+	    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+	    .take_while(|c| *c != 0)
+	    .collect();
+
+	Self {
+	    partition_type_guid: Guid::from_bytes(b[0..16].try_into().unwrap()),
+	    unique_partition_guid: Guid::from_bytes(b[16..32].try_into().unwrap()),
+	    starting_lba: le_u64(b, 32),
+	    ending_lba: le_u64(b, 40),
+	    attributes: le_u64(b, 48),
+	    partition_name: String::from_utf16_lossy(&name),
+	    raw_name: Some(b[56..56 + PARTITION_NAME_MAX_BYTES].try_into().unwrap()),
+	    ext: b[GPT_ENTRY_SZ..].to_vec(),
+	}
+    }
+
+    /// The name field: the one read if the name is unchanged, else the name encoded and
+    /// padded with NULs.
+    fn name_bytes(&self) -> Result<[u8; PARTITION_NAME_MAX_BYTES], BobErr> {
+	if let Some(raw) = self.raw_name {
+	    let units: Vec<u16> = raw.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|c| *c != 0).collect();
+	    if String::from_utf16_lossy(&units) == self.partition_name {
+		return Ok(raw);
+	    }
+	}
+	let encoded: Vec<u8> = str::encode_utf16(&self.partition_name).map(|c| c.to_le_bytes()).flatten().collect();
+	// Already checked by PartitionBuilder, tables applied from a file are not.
+	if encoded.len() > PARTITION_NAME_MAX_BYTES {
+	    return Err(BobErr::PartitionNameTooLong);
+	}
+	let mut b = [0; PARTITION_NAME_MAX_BYTES];
+	b[..encoded.len()].copy_from_slice(&encoded);
+	Ok(b)
+    }
+
+    /// The standard fields as they're laid out on disk.
+    fn to_bytes(&self) -> Result<[u8; GPT_ENTRY_SZ], BobErr> {
+	let mut b = [0; GPT_ENTRY_SZ];
+	b[0..16].copy_from_slice(&self.partition_type_guid.to_bytes());
+	b[16..32].copy_from_slice(&self.unique_partition_guid.to_bytes());
+	b[32..40].copy_from_slice(&self.starting_lba.to_le_bytes());
+	b[40..48].copy_from_slice(&self.ending_lba.to_le_bytes());
+	b[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+	b[56..].copy_from_slice(&self.name_bytes()?);
+	Ok(b)
+    }
+}
+
+/// The outcome of one of `verify`'s checks.
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    /// What's wrong, None if the check passed.
+    pub problem: Option<String>,
+}
+
+/// Check an image's GPT metadata without trusting any of it: the protective MBR, header
+/// and entry array CRCs, that the backup header and array agree with the primary, and
+/// that partitions lie within the usable LBAs without overlapping. Errors only if the
+/// image can't be read at all.
+pub fn verify(path: &str) -> Result<Vec<Check>, BobErr> {
+    let mut f = File::options()
+	.read(true)
+	.open(host_path(path)).map_err(BobErr::IO)?;
+    let block_sz = detect_block_sz(&mut f)?;
+    let blocks = f.metadata().map_err(BobErr::IO)?.len() / block_sz as u64;
+
+    let mut checks = Vec::new();
+    let mut check = |name: &'static str, problems: Vec<String>| {
+	let problem = if problems.is_empty() { None } else { Some(problems.join("; ")) };
+	checks.push(Check { name, problem });
+    };
+    let crc_problem = |stored: u32, computed: u32| -> Vec<String> {
+	if stored == computed {
+	    Vec::new()
+	} else {
+	    vec![format!("stored {stored:#010x}, computed {computed:#010x}")]
+	}
+    };
+
+    let mbr = read_protective_mbr(&mut f).err().map(|_| String::from("no boot signature or 0xEE partition record"));
+    check("protective MBR", mbr.into_iter().collect());
+
+    let (hdr, raw) = GptHeader::read_unchecked(&mut f, 1, block_sz)?;
+    if !hdr.is_sane(block_sz) {
+	check("primary header", vec![String::from("no valid GPT header at LBA 1")]);
+	return Ok(checks);
+    }
+    check("primary header CRC", crc_problem(hdr.header_crc32, hdr.computed_crc(&raw)));
+
+    let array = GptPartitionEntry::read_array_bytes(&mut f, &hdr, block_sz)?;
+    check("primary entry array CRC", crc_problem(hdr.partition_entry_array_crc32, crc32fast::hash(&array)));
+
+    let mut location = Vec::new();
+    if hdr.my_lba != 1 {
+	location.push(format!("primary header says it's at LBA {}", hdr.my_lba));
+    }
+    if hdr.alt_lba != blocks.saturating_sub(1) {
+	location.push(format!("backup header is at LBA {}, the last LBA is {}", hdr.alt_lba, blocks.saturating_sub(1)));
+    }
+    check("header locations", location);
+
+    match GptHeader::read_unchecked(&mut f, hdr.alt_lba, block_sz) {
+	Ok((bkp, raw)) if bkp.is_sane(block_sz) => {
+	    check("backup header CRC", crc_problem(bkp.header_crc32, bkp.computed_crc(&raw)));
+	    check("backup header matches primary", bkp.differences(&hdr.as_backup(block_sz)));
+	    match GptPartitionEntry::read_array_bytes(&mut f, &bkp, block_sz) {
+		Ok(bkp_array) => {
+		    check("backup entry array CRC", crc_problem(bkp.partition_entry_array_crc32, crc32fast::hash(&bkp_array)));
+		    let differs = bkp_array != array;
+		    check("backup entry array matches primary", differs.then(|| String::from("contents differ")).into_iter().collect());
+		},
+		Err(_) => check("backup entry array", vec![format!("can't read LBA {}", bkp.partition_entry_lba)]),
+	    }
+	},
+	_ => check("backup header", vec![format!("no valid GPT header at LBA {}", hdr.alt_lba)]),
+    }
+
+    let entries: Vec<_> = GptPartitionEntry::parse_array(&array, &hdr).into_iter()
+	.enumerate()
+	.filter_map(|(i, p)| Some((i, p?)))
+	.collect();
+    let ranges = entries.iter().map(|(i, p)| (*i, p))
+	.filter(|(_, p)| p.starting_lba > p.ending_lba || p.starting_lba < hdr.first_usable_lba || p.ending_lba > hdr.last_usable_lba)
+	.map(|(i, p)| format!("#{} ({}) spans LBAs {}-{}, usable are {}-{}", i + 1, p.partition_name, p.starting_lba, p.ending_lba, hdr.first_usable_lba, hdr.last_usable_lba))
+	.collect();
+    check("partition ranges", ranges);
+
+    let mut sorted: Vec<_> = entries.iter().map(|(i, p)| (*i, p)).collect();
+    sorted.sort_by_key(|(_, p)| p.starting_lba);
+    let overlaps = sorted.windows(2)
+	.filter(|w| w[0].1.ending_lba >= w[1].1.starting_lba)
+	.map(|w| format!("#{} ({}) overlaps #{} ({})", w[0].0 + 1, w[0].1.partition_name, w[1].0 + 1, w[1].1.partition_name))
+	.collect();
+    check("partition overlaps", overlaps);
+
+    Ok(checks)
+}
+
+/// Check the first block holds a protective MBR: the boot signature and a partition
+/// record of type 0xEE covering the GPT.
+fn read_protective_mbr(f: &mut File) -> Result<(), BobErr> {
+    let mut b = [0; 512];
+    f.seek(SeekFrom::Start(0)).map_err(BobErr::IO)?;
+    f.read_exact(&mut b).map_err(BobErr::IO)?;
+
+    // Partition records start at 446 and are 16 bytes each, the OS type is at offset 4.
+    let protective = (0..4).any(|i| b[446 + i * 16 + 4] == 0xEE);
+    if b[510..512] != [0x55, 0xAA] || !protective {
+	return Err(BobErr::InvalidProtectiveMbr);
+    }
+    Ok(())
+}
+
+/// Check partitions lie within the usable LBAs without overlapping each other.
+fn check_partitions<'a>(entries: impl IntoIterator<Item = &'a GptPartitionEntry>, first_usable: u64, last_usable: u64) -> Result<(), BobErr> {
+    let entries: Vec<_> = entries.into_iter().collect();
+    for p in &entries {
+	if p.starting_lba > p.ending_lba || p.starting_lba < first_usable || p.ending_lba > last_usable {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+    }
+
+    let mut sorted = entries.clone();
+    sorted.sort_by_key(|p| p.starting_lba);
+    if sorted.windows(2).any(|w| w[0].ending_lba >= w[1].starting_lba) {
+	return Err(BobErr::PartitionOverlap);
+    }
+    for (i, p) in entries.iter().enumerate() {
+	if entries[..i].iter().any(|q| q.unique_partition_guid == p.unique_partition_guid) {
+	    return Err(BobErr::DuplicatePartitionGuid(p.unique_partition_guid.to_string()));
+	}
+    }
+
+    Ok(())
+}
+
+/// Size a new image file to `len`. A block device can't be resized, it only has to be
+/// large enough.
+fn set_image_len(f: &File, len: u64) -> Result<(), BobErr> {
+    if !sink::is_block_device(f) {
+	return f.set_len(len).map_err(BobErr::IO);
+    }
+    let mut f = f;
+    let device_len = f.seek(SeekFrom::End(0)).map_err(BobErr::IO)?;
+    f.rewind().map_err(BobErr::IO)?;
+    if device_len < len {
+	return Err(BobErr::ImageTooSmall);
+    }
+    Ok(())
+}
+
+/// The logical block size of an image: the first of `SECTOR_SIZES` with a GPT signature
+/// at LBA 1, or failing that at the last LBA.
+fn detect_block_sz(f: &mut File) -> Result<usize, BobErr> {
+    let len = f.metadata().map_err(BobErr::IO)?.len();
+    let has_signature = |f: &mut File, lba: u64, block_sz: usize| {
+	GptHeader::read_unchecked(f, lba, block_sz).is_ok_and(|(h, _)| h.signature == GPT_SIGNATURE)
+    };
+    SECTOR_SIZES.iter().copied().find(|sz| has_signature(f, 1, *sz))
+	.or_else(|| SECTOR_SIZES.iter().copied().find(|sz| has_signature(f, (len / *sz as u64).saturating_sub(1), *sz)))
+	.ok_or(BobErr::InvalidGptHeader)
+}
+
+/// Blocks taken by a partition entry array of `num_entries` entries.
+pub fn array_blocks(num_entries: u32, block_sz: usize) -> u64 {
+    (num_entries as u64 * GPT_ENTRY_SZ as u64).div_ceil(block_sz as u64)
+}
+
+/// First and last usable LBAs of an image, the space outside of them is reserved for the
+/// protective MBR and the primary and backup GPT headers and partition entry arrays.
+fn usable_lbas(image_size: usize, block_sz: usize, num_entries: u32) -> Result<(u64, u64), BobErr> {
+    let size_in_blocks = (image_size / block_sz) as u64;
+    let array_blocks = array_blocks(num_entries, block_sz);
+    // Protective MBR, GPT header and array at the start, array and header at the end.
+    let first_usable = 2 + array_blocks;
+    if size_in_blocks < first_usable + array_blocks + 2 {
+	return Err(BobErr::ImageTooSmall);
+    }
+    Ok((first_usable, size_in_blocks - 2 - array_blocks))
+}
+
+impl PartitionTable {
+    pub fn from_name(name: &str) -> Option<Self> {
+	match name {
+	    "gpt" => Some(Self::Gpt),
+	    "mbr" => Some(Self::Mbr),
+	    _ => None,
+	}
+    }
+
+    /// First and last usable LBAs for partitions. An MBR only reserves its own sector.
+    fn usable_lbas(&self, image_size: usize, block_sz: usize, num_entries: u32) -> Result<(u64, u64), BobErr> {
+	match self {
+	    PartitionTable::Gpt => usable_lbas(image_size, block_sz, num_entries),
+	    PartitionTable::Mbr => {
+		let size_in_blocks = (image_size / block_sz) as u64;
+		if size_in_blocks < 2 {
+		    return Err(BobErr::ImageTooSmall);
+		}
+		Ok((1, size_in_blocks - 1))
+	    },
+	}
+    }
+}
+
+enum MbrGeometryErr {
+    /// The partition type has no MBR type byte, with the type's name.
+    NoType(String),
+    /// It starts or ends past what 32 bit LBAs reach.
+    TooFar,
+}
+
+/// A partition's MBR type byte, starting LBA and size in LBAs.
+fn mbr_geometry(p: &GptPartitionEntry) -> Result<(u8, u32, u32), MbrGeometryErr> {
+    let pt = PartitionType::from_guid(p.partition_type_guid);
+    let os_type = pt.mbr_type().ok_or_else(|| MbrGeometryErr::NoType(pt.name()))?;
+    let (Ok(start), Ok(size)) = (u32::try_from(p.starting_lba), u32::try_from(p.ending_lba - p.starting_lba + 1)) else {
+	return Err(MbrGeometryErr::TooFar);
+    };
+    Ok((os_type, start, size))
+}
+
+/// Partition attribute bits every partition type has, by their names in a `-p` spec.
+/// UEFI 2.10, table 5.8.
+const ATTRIBUTES: [(&str, u64); 3] = [
+    ("required", 1 << 0),
+    ("no-block-io", 1 << 1),
+    ("legacy-boot", LEGACY_BOOT),
+];
+/// Legacy BIOS bootable, the active flag of a mirrored partition in a hybrid MBR.
+const LEGACY_BOOT: u64 = 1 << 2;
+
+/// Parses partition attributes from a `-p` spec: names from `ATTRIBUTES` and `guid:<bit>`
+/// for the type specific bits 48 to 63, joined with `+`, e.g. `required+guid:60`.
+pub fn parse_attributes(s: &str) -> Option<u64> {
+    s.split('+').try_fold(0, |attributes, attr| {
+	let attr = attr.trim();
+	let bit = match ATTRIBUTES.iter().find(|(name, _)| attr.eq_ignore_ascii_case(name)) {
+	    Some((_, bit)) => *bit,
+	    None => 1 << attr.strip_prefix("guid:")?.parse::<u32>().ok().filter(|b| (48..64).contains(b))?,
+	};
+	Some(attributes | bit)
+    })
+}
+
+/// Formats a byte count with a binary unit, e.g. 64.0 MiB.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+	size /= 1024.0;
+	unit += 1;
+    }
+    if unit == 0 {
+	format!("{bytes} B")
+    } else {
+	format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parses a byte count with an optional binary suffix: K, M, G or T (e.g. 64M).
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last()?.to_ascii_uppercase() {
+	'K' => (&s[..s.len() - 1], 10),
+	'M' => (&s[..s.len() - 1], 20),
+	'G' => (&s[..s.len() - 1], 30),
+	'T' => (&s[..s.len() - 1], 40),
+	_ => (s, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+fn le_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(b: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(b[offset..offset + 8].try_into().unwrap())
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::table::TableFormat;
+
+    /// A unique path in the temp dir for a test image, removed when dropped.
+    #[allow(dead_code)]
+    struct TempImage(String);
+
+    impl TempImage {
+	#[allow(dead_code)]
+	fn new(name: &str) -> Self {
+	    let p = std::env::temp_dir().join(format!("bob-test-{}-{name}.img", std::process::id()));
+	    Self(String::from(p.to_str().unwrap()))
+	}
+    }
+
+    impl Drop for TempImage {
+	fn drop(&mut self) {
+	    let _ = std::fs::remove_file(&self.0);
+	}
+    }
+
+    #[allow(dead_code)]
+    fn esp() -> PartitionInput {
+	PartitionBuilder::new()
+	    .partition_type(PartitionType::EFISystem)
+	    .start_offset(1024 * 1024)
+	    .end_offset(2 * 1024 * 1024)
+	    .build()
+	    .unwrap()
+    }
+
+    #[test]
+    fn partition_type_guids() {
+	let esp = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+	assert_eq!(PartitionType::EFISystem.uuid(), Guid::from_bytes(esp));
+	assert_eq!(PartitionType::from_name("linux").unwrap().uuid().to_string(), "0FC63DAF-8483-4772-8E79-3D69D8477DE4");
+	assert_eq!(PartitionType::from_name("root").unwrap().name(), "Linux root (x86-64)");
+	assert_eq!(PartitionType::Provision.uuid().to_string(), "E3A1F09B-6F2C-4D58-9B7E-59C0B05F5052");
+	assert!(PartitionType::from_name("efi").is_none());
+    }
+
+    #[test]
+    fn partition_type_from_guid_string() {
+	let pt: PartitionType = "0fc63daf-8483-4772-8e79-3d69d8477de4".parse().unwrap();
+	assert!(matches!(pt, PartitionType::LinuxFilesystem));
+
+	let pt: PartitionType = "{E6D6D379-F507-44C2-A23C-238F2A3DF928}".parse().unwrap();
+	let PartitionType::Other(guid) = pt else { panic!("expected an unknown type") };
+	assert_eq!(pt.uuid(), guid);
+	assert_eq!(pt.name(), "E6D6D379-F507-44C2-A23C-238F2A3DF928");
+	assert!("not-a-guid".parse::<PartitionType>().is_err());
+    }
+
+    #[test]
+    fn partition_names_ignore_case() {
+	let tmp = TempImage::new("names");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+
+	assert!(img.get_partition_view("EFI system partition").is_some());
+	assert!(img.get_partition_view("efi SYSTEM Partition").is_some());
+	assert!(img.get_partition_view("EFI").is_none());
+    }
+
+    #[test]
+    fn custom_partition_names() {
+	let tmp = TempImage::new("custom-names");
+	let named = PartitionBuilder::new()
+	    .partition_type(PartitionType::EFISystem)
+	    .name("boot")
+	    .start_offset(1024 * 1024)
+	    .end_offset(2 * 1024 * 1024)
+	    .build()
+	    .unwrap();
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(named)
+	    .build()
+	    .unwrap();
+
+	assert!(img.get_partition_view("boot").is_some());
+	assert!(img.get_partition_view("EFI system partition").is_none());
+	assert_eq!(img.partitions_of_type(PartitionType::EFISystem), ["boot"]);
+
+	// 36 UTF-16 code units fit, 37 don't. Each of these takes two.
+	let name = |n| PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .name(&"\u{1F600}".repeat(n))
+	    .start_offset(0)
+	    .end_offset(0)
+	    .build();
+	assert!(name(18).is_ok());
+	assert!(matches!(name(19), Err(BobErr::PartitionNameTooLong)));
+    }
+
+    #[test]
+    fn read_back_created_image() {
+	let tmp = TempImage::new("read");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+
+	let layout = GptImage::open_read_only(&tmp.0).unwrap().layout();
+	assert_eq!(layout.partitions.len(), 1);
+	assert_eq!(layout.partitions[0].first_lba, 2048);
+	assert_eq!(layout.partitions[0].name, "EFI system partition");
+	assert_eq!(layout.last_lba, 8191);
+    }
+
+    #[test]
+    fn sector_size_4k() {
+	let tmp = TempImage::new("4kn");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * 1024 * 1024)
+	    .sector_size(4096)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+
+	let mut img = GptImage::open_read_only(&tmp.0).unwrap();
+	let layout = img.layout();
+	assert_eq!(layout.sector_size, 4096);
+	assert_eq!((layout.first_usable_lba, layout.last_usable_lba, layout.last_lba), (6, 2042, 2047));
+	assert_eq!(layout.partitions[0].first_lba, 256);
+	assert_eq!(img.get_partition_view("EFI system partition").unwrap().sector_size(), 4096);
+
+	// The same known failures as a 512 byte sector image, nothing new.
+	let failed: Vec<_> = verify(&tmp.0).unwrap().into_iter().filter(|c| c.problem.is_some()).map(|c| c.name).collect();
+	assert!(!failed.iter().any(|c| c.contains("partition") || c.contains("location") || c.contains("MBR")));
+
+	let bad = DiskImgBuilder::new().output_file("unused.img").total_size(8 * 1024 * 1024).sector_size(1024).plan();
+	assert!(matches!(bad, Err(BobErr::InvalidSectorSize)));
+    }
+
+    #[test]
+    fn max_partitions() {
+	let tmp = TempImage::new("max-partitions");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * 1024 * 1024)
+	    .max_partitions(256)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+
+	// Two arrays of 64 blocks each.
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	let layout = img.layout();
+	assert_eq!((layout.first_usable_lba, layout.last_usable_lba, layout.max_partitions), (66, 16318, 256));
+	let failed: Vec<_> = verify(&tmp.0).unwrap().into_iter().filter(|c| c.problem.is_some()).map(|c| c.name).collect();
+	assert!(!failed.iter().any(|c| c.contains("partition") || c.contains("location") || c.contains("MBR")));
+
+	// A table exported from it makes the same layout again.
+	let sfdisk = layout.export(TableFormat::Sfdisk);
+	assert!(sfdisk.contains("table-length: 256\n"));
+	assert_eq!(TableLayout::import(&sfdisk, None).unwrap(), layout);
+
+	let few = DiskImgBuilder::new().output_file("unused.img").total_size(8 * 1024 * 1024).max_partitions(16).plan();
+	assert!(matches!(few, Err(BobErr::InvalidPartitionCount)));
+    }
+
+    #[test]
+    fn hybrid_mbr() {
+	let tmp = TempImage::new("hybrid");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(1024 * 1024)
+	    .attributes(LEGACY_BOOT)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(6 * 1024 * 1024)
+	    .partition(esp())
+	    .partition(linux)
+	    .hybrid_mbr(vec![1, 0])
+	    .build()
+	    .unwrap();
+
+	let mbr = std::fs::read(&tmp.0).unwrap()[..512].to_vec();
+	let record = |i: usize| &mbr[446 + i * 16..][..16];
+	// 0xEE up to the ESP, then the ESP and the bootable Linux partition in disk order.
+	assert_eq!((record(0)[4], le_u32(record(0), 8), le_u32(record(0), 12)), (0xEE, 1, 2047));
+	assert_eq!((record(1)[0], record(1)[4], le_u32(record(1), 8)), (0, 0xEF, 2048));
+	assert_eq!((record(2)[0], record(2)[4], le_u32(record(2), 8), le_u32(record(2), 12)), (0x80, 0x83, 6144, 2048));
+	assert_eq!(record(3), &[0; 16]);
+	assert_eq!(&mbr[510..], &[0x55, 0xAA]);
+	assert!(GptImage::open_read_only(&tmp.0).is_ok());
+
+	let plan = |mirrored| DiskImgBuilder::new().output_file("unused.img").total_size(4 * 1024 * 1024).partition(esp()).hybrid_mbr(mirrored).plan();
+	assert!(matches!(plan(vec![0, 0, 0, 0]), Err(BobErr::InvalidHybridMbr(_))));
+	assert!(matches!(plan(vec![1]), Err(BobErr::InvalidHybridMbr(_))));
+    }
+
+    #[test]
+    fn mbr_table() {
+	let tmp = TempImage::new("mbr");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(1024 * 1024)
+	    .attributes(LEGACY_BOOT)
+	    .build()
+	    .unwrap();
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(6 * 1024 * 1024)
+	    .table(PartitionTable::Mbr)
+	    .partition(esp())
+	    .partition(linux)
+	    .plan()
+	    .unwrap()
+	    .write_mbr()
+	    .unwrap();
+	assert_eq!(img.partitions_of_type(PartitionType::EFISystem), ["EFI system partition"]);
+	assert!(img.get_partition_view("linux filesystem").is_some());
+
+	let data = std::fs::read(&tmp.0).unwrap();
+	let record = |i: usize| &data[446 + i * 16..][..16];
+	assert_eq!((record(0)[0], record(0)[4], le_u32(record(0), 8), le_u32(record(0), 12)), (0, 0xEF, 2048, 2049));
+	assert_eq!((record(1)[0], record(1)[4], le_u32(record(1), 8), le_u32(record(1), 12)), (0x80, 0x83, 6144, 2048));
+	assert_eq!(record(2), &[0; 16]);
+	assert_eq!(&data[510..512], &[0x55, 0xAA]);
+	// No GPT header, primary or backup.
+	assert!(data.chunks(512).all(|b| &b[..8] != b"EFI PART"));
+
+	let plan = |table, n: usize| {
+	    (0..n).fold(DiskImgBuilder::new().output_file("unused.img").total_size(8 * 1024 * 1024).table(table), |b, _| {
+		b.partition(PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(1024 * 1024).build().unwrap())
+	    }).plan()
+	};
+	assert!(plan(PartitionTable::Mbr, 4).is_ok());
+	assert!(matches!(plan(PartitionTable::Mbr, 5), Err(BobErr::PartitionTableFull)));
+	assert!(plan(PartitionTable::Gpt, 5).is_ok());
+	let bios = PartitionBuilder::new().partition_type(PartitionType::BIOSBoot).size(1024 * 1024).build().unwrap();
+	let plan = DiskImgBuilder::new().output_file("unused.img").total_size(4 * 1024 * 1024).table(PartitionTable::Mbr).partition(bios).plan();
+	assert!(matches!(plan, Err(BobErr::InvalidMbr(_))));
+    }
+
+    #[test]
+    fn seeded_builds_are_reproducible() {
+	let build = |name: &str, seed: u64, table: PartitionTable| {
+	    let tmp = TempImage::new(name);
+	    let plan = DiskImgBuilder::new()
+		.output_file(&tmp.0)
+		.total_size(4 * 1024 * 1024)
+		.table(table)
+		.seed(seed)
+		.partition(esp())
+		.plan()
+		.unwrap();
+	    match table {
+		PartitionTable::Gpt => drop(plan.write().unwrap()),
+		PartitionTable::Mbr => drop(plan.write_mbr().unwrap()),
+	    }
+	    std::fs::read(&tmp.0).unwrap()
+	};
+	for table in [PartitionTable::Gpt, PartitionTable::Mbr] {
+	    assert_eq!(build("seed-a", 7, table), build("seed-b", 7, table));
+	    assert_ne!(build("seed-c", 7, table), build("seed-d", 8, table));
+	}
+    }
+
+    #[test]
+    fn pinned_disk_guid() {
+	let guid = "0d1e2f30-4152-4637-8899-aabbccddeeff".parse::<Guid>().unwrap();
+	let tmp = TempImage::new("disk-guid");
+	let build = |pinned: Option<Guid>| {
+	    let builder = DiskImgBuilder::new().output_file(&tmp.0).total_size(4 * 1024 * 1024).seed(3).partition(esp());
+	    match pinned {
+		Some(g) => builder.disk_guid(g),
+		None => builder,
+	    }.build().unwrap().layout()
+	};
+	let (plain, pinned) = (build(None), build(Some(guid)));
+	assert_eq!(pinned.disk_guid, guid);
+	assert_ne!(plain.disk_guid, guid);
+	assert_eq!(pinned.partitions[0].unique_guid, plain.partitions[0].unique_guid);
+
+	let mbr = DiskImgBuilder::new().output_file(&tmp.0).total_size(4 * 1024 * 1024).table(PartitionTable::Mbr).disk_guid(guid).partition(esp()).plan();
+	assert!(matches!(mbr, Err(BobErr::InvalidMbr(_))));
+    }
+
+    #[test]
+    fn zero_partitions() {
+	const MIB: usize = 1024 * 1024;
+	let tmp = TempImage::new("zero");
+	let build = |mode| {
+	    std::fs::write(&tmp.0, vec![0xAA; 8 * MIB]).unwrap();
+	    let p = PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(4 * MIB).build().unwrap();
+	    let b = DiskImgBuilder::new().output_file(&tmp.0).total_size(8 * MIB).partition(p);
+	    match mode {
+		Some(mode) => b.zero_partitions(mode),
+		None => b,
+	    }.build().unwrap();
+	    std::fs::read(&tmp.0).unwrap()
+	};
+	let all = |data: &[u8], b| data.iter().all(|x| *x == b);
+
+	// The partition is at 1 MiB to 5 MiB.
+	let data = build(None);
+	assert!(all(&data[MIB..5 * MIB], 0xAA));
+	let data = build(Some(ZeroMode::Quick));
+	assert!(all(&data[MIB..2 * MIB], 0) && all(&data[4 * MIB..5 * MIB], 0));
+	assert!(all(&data[2 * MIB..4 * MIB], 0xAA));
+	// Outside the partitions is left alone.
+	assert!(all(&data[5 * MIB..6 * MIB], 0xAA));
+	let data = build(Some(ZeroMode::Full));
+	assert!(all(&data[MIB..5 * MIB], 0));
+    }
+
+    #[test]
+    fn sparse_contents() {
+	const MIB: usize = 1024 * 1024;
+	let tmp = TempImage::new("sparse");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(32 * MIB)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(64 * MIB)
+	    .partition(linux)
+	    .build()
+	    .unwrap();
+
+	// Mostly empty contents, like a fresh filesystem image, over old data.
+	let mut contents = vec![0; 31 * MIB];
+	contents[..4096].fill(0x11);
+	contents[20 * MIB + 100] = 0x22;
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let mut p = img.partition_view(0).unwrap();
+	write_partition_bytes(&mut p, 0, 31 * MIB as u64, &mut &vec![0xAA; 31 * MIB][..]).unwrap();
+	write_partition_bytes(&mut p, 0, contents.len() as u64, &mut &contents[..]).unwrap();
+	let start = img.pentry[0].as_ref().unwrap().starting_lba as usize * 512;
+	drop(img);
+
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert!(bytes[start..start + 31 * MIB] == contents[..]);
+	assert_eq!(&bytes[510..512], &[0x55, 0xAA]);
+	#[cfg(target_os = "linux")]
+	{
+	    // The zero runs are holes: 64 MiB of image in far less disk space.
+	    use std::os::unix::fs::MetadataExt;
+	    let allocated = std::fs::metadata(&tmp.0).unwrap().blocks() * 512;
+	    assert!(allocated < 4 * MIB as u64, "{allocated} bytes allocated");
+	}
+    }
+
+    #[test]
+    fn rewrite_tables() {
+	let tmp = TempImage::new("rewrite");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let created = std::fs::read(&tmp.0).unwrap();
+
+	// Rewriting an untouched table gives back the same bytes.
+	GptImage::open(&tmp.0).unwrap().write_tables().unwrap();
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), created);
+
+	// The backup points back at the primary and checks out on its own.
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	assert!(!img.bkp_damaged);
+	assert_eq!((img.bkp_hdr.my_lba, img.bkp_hdr.alt_lba, img.bkp_hdr.partition_entry_lba), (8191, 1, 8159));
+
+	// A damaged primary header falls back to the backup, and rewriting restores it.
+	let mut damaged = created.clone();
+	damaged[512 + 40] ^= 0xFF;
+	std::fs::write(&tmp.0, &damaged).unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert_eq!((img.hdr.my_lba, img.hdr.partition_entry_lba), (1, 2));
+	img.write_tables().unwrap();
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), created);
+
+	let mut no_mbr = created.clone();
+	no_mbr[510] = 0;
+	std::fs::write(&tmp.0, &no_mbr).unwrap();
+	assert!(matches!(GptImage::open_read_only(&tmp.0), Err(BobErr::InvalidProtectiveMbr)));
+    }
+
+    #[test]
+    fn planned_table_changes() {
+	let tmp = TempImage::new("table-changes");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let created = std::fs::read(&tmp.0).unwrap();
+	assert!(GptImage::open_read_only(&tmp.0).unwrap().table_changes().unwrap().is_empty());
+
+	let mut damaged = created.clone();
+	damaged[512 + 40] ^= 0xFF;
+	damaged[8159 * 512 + 16] ^= 0xFF;
+	std::fs::write(&tmp.0, &damaged).unwrap();
+	let changes = GptImage::open_read_only(&tmp.0).unwrap().table_changes().unwrap();
+	assert_eq!(changes, [
+	    "rewrite the primary GPT header at LBA 1",
+	    "rewrite the backup partition entry array at LBA 8159, entries 1",
+	]);
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), damaged);
+    }
+
+    #[test]
+    fn add_partitions() {
+	let tmp = TempImage::new("add");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * 1024 * 1024)
+	    .partition(esp_at_5m())
+	    .build()
+	    .unwrap();
+	let sized = |size| PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(size)
+	    .build()
+	    .unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	// Fills the gap before the ESP, then there's only 1MiB left after it.
+	assert_eq!(img.add_partition(&sized(2 * 1024 * 1024), DEFAULT_ALIGNMENT).unwrap(), (1, 2048, 6143));
+	assert_eq!(img.add_partition(&sized(2 * 1024 * 1024), DEFAULT_ALIGNMENT).unwrap(), (2, 6144, 10239));
+	assert!(matches!(img.add_partition(&sized(2 * 1024 * 1024), DEFAULT_ALIGNMENT), Err(BobErr::NoFreeSpace)));
+	assert!(matches!(img.add_partition(&esp_at_5m(), DEFAULT_ALIGNMENT), Err(BobErr::PartitionOverlap)));
+
+	// The table on disk has them.
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	assert_eq!(img.partition_count(), 3);
+	assert_eq!(img.partitions_of_type(PartitionType::LinuxFilesystem).len(), 2);
+    }
+
+    #[test]
+    fn pinned_unique_guid() {
+	let tmp = TempImage::new("pinned-guid");
+	let guid = "6f1c9a52-3b7e-4d0a-9c21-5e8b7f3a2d14".parse::<Guid>().unwrap();
+	let root = |start: usize| PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .start_offset(start)
+	    .size(1024 * 1024)
+	    .unique_guid(guid)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * 1024 * 1024)
+	    .partition(esp())
+	    .partition(root(3 * 1024 * 1024))
+	    .build()
+	    .unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert_eq!(img.find_by_guid(guid), Some(1));
+	// A second partition can't have it too.
+	assert!(matches!(img.add_partition(&root(5 * 1024 * 1024), DEFAULT_ALIGNMENT), Err(BobErr::DuplicatePartitionGuid(_))));
+    }
+
+    #[test]
+    fn read_partition_view() {
+	let tmp = TempImage::new("view-read");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let mut p = img.get_partition_view("EFI system partition").unwrap();
+	let size = p.sectors() * 512;
+	p.write_all(b"hello").unwrap();
+	p.write_sectors(p.sectors() - 1, &[0xAB; 512]).unwrap();
+
+	let mut p = img.get_partition_view("EFI system partition").unwrap();
+	let mut head = [0; 5];
+	p.read_exact(&mut head).unwrap();
+	assert_eq!(&head, b"hello");
+	// Reads carry on from where the last one stopped and end with the partition.
+	let mut rest = Vec::new();
+	assert_eq!(p.read_to_end(&mut rest).unwrap() as u64, size - 5);
+	assert_eq!(&rest[rest.len() - 512..], &[0xAB; 512]);
+	assert_eq!(p.read(&mut head).unwrap(), 0);
+
+	// Right up to the final byte, the same bytes extract gives.
+	let meta = img.pentry[0].as_ref().unwrap();
+	assert_eq!(size, (meta.ending_lba - meta.starting_lba + 1) * 512);
+	let mut extracted = Vec::new();
+	img.extract_partition(0, &mut extracted).unwrap();
+	assert_eq!(extracted[..5], head);
+	assert_eq!(extracted[5..], rest);
+    }
+
+    #[test]
+    fn seek_partition_view() {
+	let tmp = TempImage::new("view-seek");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let mut p = img.get_partition_view("EFI system partition").unwrap();
+	let size = p.sectors() * 512;
+
+	assert_eq!(p.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+	p.write_all(b"abc").unwrap();
+	assert_eq!(p.stream_position().unwrap(), 1003);
+	assert_eq!(p.seek(SeekFrom::Current(-3)).unwrap(), 1000);
+	let mut b = [0; 3];
+	p.read_exact(&mut b).unwrap();
+	assert_eq!(&b, b"abc");
+
+	// Writes land where the cursor is, even after sector I/O moved the file.
+	p.read_sectors(0, &mut [0; 512]).unwrap();
+	assert_eq!(p.seek(SeekFrom::End(-2)).unwrap(), size - 2);
+	p.write_all(b"yz").unwrap();
+	p.read_sectors(p.sectors() - 1, &mut [0; 512]).unwrap();
+	p.seek(SeekFrom::End(-2)).unwrap();
+	p.read_exact(&mut b[..2]).unwrap();
+	assert_eq!(&b[..2], b"yz");
+
+	// The end is past the inclusive ending LBA.
+	let meta = p.meta;
+	assert_eq!(p.seek(SeekFrom::End(0)).unwrap(), (meta.ending_lba - meta.starting_lba + 1) * 512);
+	assert_eq!(p.seek(SeekFrom::End(0)).unwrap(), size);
+	assert_eq!(p.seek(SeekFrom::End(1)).unwrap_err().kind(), ErrorKind::InvalidInput);
+	assert_eq!(p.seek(SeekFrom::Start(0)).and_then(|_| p.seek(SeekFrom::Current(-1))).unwrap_err().kind(), ErrorKind::InvalidInput);
+	// A failed seek leaves the cursor alone.
+	assert_eq!(p.stream_position().unwrap(), 0);
+    }
+
+    #[test]
+    fn mmap_backend() {
+	let tmp = TempImage::new("mmap");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	img.set_io_backend(IoBackend::Mmap).unwrap();
+	let mut p = img.partition_view(0).unwrap();
+	p.write_sectors(1, &[0xAB; 1024]).unwrap();
+	p.zero_sectors(2, 1).unwrap();
+	p.seek(SeekFrom::Start(10)).unwrap();
+	p.write_all(b"mapped").unwrap();
+	p.flush().unwrap();
+	let mut b = [0; 1024];
+	p.read_sectors(1, &mut b).unwrap();
+	assert_eq!((b[0], b[511], b[512]), (0xAB, 0xAB, 0));
+	assert_eq!(p.read_sectors(p.sectors(), &mut b[..512]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+	// The table and file see what went through the mapping.
+	img.write_tables().unwrap();
+	drop(img);
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let mut p = img.partition_view(0).unwrap();
+	p.read_sectors(0, &mut b).unwrap();
+	assert_eq!((&b[10..16], b[512], b[1023]), (&b"mapped"[..], 0xAB, 0xAB));
+	p.read_sectors(2, &mut b[..512]).unwrap();
+	assert!(b[..512].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn extract_partitions() {
+	let tmp = TempImage::new("extract");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let (start, len) = (img.pentry[0].as_ref().unwrap().starting_lba as usize * 512, (img.pentry[0].as_ref().unwrap().ending_lba - img.pentry[0].as_ref().unwrap().starting_lba + 1) * 512);
+	img.fd.seek(SeekFrom::Start(start as u64)).unwrap();
+	img.fd.write_all(b"FAT").unwrap();
+
+	let mut out = Vec::new();
+	assert_eq!(img.extract_partition(0, &mut out).unwrap(), len);
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert_eq!(out, &bytes[start..start + out.len()]);
+	assert!(out.starts_with(b"FAT"));
+	assert!(matches!(img.extract_partition(1, &mut out), Err(BobErr::PartitionNotFound(_))));
+    }
+
+    #[test]
+    fn write_bytes_into_partitions() {
+	let tmp = TempImage::new("write-bytes");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let start = img.pentry[0].as_ref().unwrap().starting_lba as usize * 512;
+	let mut p = img.partition_view(0).unwrap();
+	p.write_sectors(0, &[0xAA; 2048]).unwrap();
+	// Unaligned at both ends, the rest of the sectors is kept.
+	write_partition_bytes(&mut p, 500, 100, &mut &[1; 100][..]).unwrap();
+	let capacity = p.sectors() * 512;
+	assert!(matches!(write_partition_bytes(&mut p, capacity - 10, 11, &mut &[2; 11][..]), Err(BobErr::PartitionOutOfBounds)));
+	write_partition_bytes(&mut p, capacity - 10, 10, &mut &[2; 10][..]).unwrap();
+	assert!(img.partition_view(1).is_none());
+
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	let part = &bytes[start..];
+	assert!(part[..500].iter().all(|b| *b == 0xAA));
+	assert!(part[500..600].iter().all(|b| *b == 1));
+	assert!(part[600..2048].iter().all(|b| *b == 0xAA));
+	assert_eq!(&part[capacity as usize - 11..capacity as usize], &[0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn write_whole_partition() {
+	let tmp = TempImage::new("write-whole");
+	let data = PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(1024 * 1024).build().unwrap();
+	let mut img = DiskImgBuilder::new().output_file(&tmp.0).total_size(4 * 1024 * 1024).partition(data).build().unwrap();
+	// The ending LBA is inclusive, so a 1 MiB partition takes exactly 1 MiB.
+	let (first, last) = (img.pentry[0].as_ref().unwrap().starting_lba, img.pentry[0].as_ref().unwrap().ending_lba);
+	let mut p = img.partition_view(0).unwrap();
+	assert_eq!(p.sectors(), last - first + 1);
+	assert_eq!(p.sectors() * 512, 1024 * 1024);
+	let payload = vec![0x5A; 1024 * 1024];
+	write_partition_bytes(&mut p, 0, payload.len() as u64, &mut &payload[..]).unwrap();
+	assert!(matches!(write_partition_bytes(&mut p, 1, payload.len() as u64, &mut &payload[..]), Err(BobErr::PartitionOutOfBounds)));
+
+	let mut out = Vec::new();
+	img.extract_partition(0, &mut out).unwrap();
+	assert_eq!(out, payload);
+    }
+
+    #[test]
+    fn copy_between_images() {
+	let (a, b) = (TempImage::new("copy-a"), TempImage::new("copy-b"));
+	let mut src = DiskImgBuilder::new().output_file(&a.0).total_size(4 * 1024 * 1024).partition(esp()).build().unwrap();
+	let big = PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(2 * 1024 * 1024).build().unwrap();
+	let mut dst = DiskImgBuilder::new()
+	    .output_file(&b.0)
+	    .total_size(8 * 1024 * 1024)
+	    .sector_size(4096)
+	    .partition(PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(64 * 1024).build().unwrap())
+	    .partition(big)
+	    .build()
+	    .unwrap();
+	let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+	src.partition_view(0).unwrap().write_sectors(0, &data).unwrap();
+
+	let mut s = src.partition_view(0).unwrap();
+	assert!(matches!(copy_partition(&mut s, &mut dst.partition_view(0).unwrap()), Err(BobErr::PartitionOutOfBounds)));
+	let len = copy_partition(&mut s, &mut dst.partition_view(1).unwrap()).unwrap();
+	let mut out = Vec::new();
+	dst.extract_partition(1, &mut out).unwrap();
+	assert_eq!(&out[..data.len()], data);
+	assert_eq!(len, s.sectors() * 512);
+    }
+
+    #[test]
+    fn clone_round_trip() {
+	let (a, b) = (TempImage::new("clone-a"), TempImage::new("clone-b"));
+	let part = || PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(1024 * 1024).build().unwrap();
+	let mut src = DiskImgBuilder::new().output_file(&a.0).total_size(4 * 1024 * 1024).partition(part()).build().unwrap();
+	let mut dst = DiskImgBuilder::new().output_file(&b.0).total_size(4 * 1024 * 1024).partition(part()).build().unwrap();
+	// Data all the way to the last sector, which a short copy would drop.
+	let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 253) as u8 + 1).collect();
+	src.partition_view(0).unwrap().write_sectors(0, &data).unwrap();
+
+	let len = copy_partition(&mut src.partition_view(0).unwrap(), &mut dst.partition_view(0).unwrap()).unwrap();
+	assert_eq!(len, 1024 * 1024);
+	let (mut from, mut to) = (Vec::new(), Vec::new());
+	src.extract_partition(0, &mut from).unwrap();
+	dst.extract_partition(0, &mut to).unwrap();
+	assert_eq!(from, data);
+	assert_eq!(to, from);
+    }
+
+    /// A 4 MiB image with what other partitioning tools write but bob doesn't: a revision
+    /// 1.1 header 8 bytes longer than 1.0's, 256 byte entries with data past the standard
+    /// fields, junk after the NUL in a name (as left over after renames), and vendor
+    /// attribute bits (63 and 60, "no drive letter" and "read-only" for Windows basic data).
+    #[allow(dead_code)]
+    fn foreign_image(path: &str) {
+	let (sectors, entries, entry_sz) = (8192u64, 128u32, 256usize);
+	let array_blocks = (entries as u64 * entry_sz as u64).div_ceil(512);
+	let mut img = vec![0; sectors as usize * 512];
+	img[446 + 4] = 0xEE;
+	img[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+	img[446 + 12..446 + 16].copy_from_slice(&(sectors as u32 - 1).to_le_bytes());
+	img[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+	let mut array = vec![0; entries as usize * entry_sz];
+	let e = &mut array[..entry_sz];
+	e[..16].copy_from_slice(&PartitionType::MicrosoftBasicData.uuid().to_bytes());
+	e[16..32].copy_from_slice(&[0x11; 16]);
+	e[32..40].copy_from_slice(&2048u64.to_le_bytes());
+	e[40..48].copy_from_slice(&4095u64.to_le_bytes());
+	e[48..56].copy_from_slice(&(1u64 << 63 | 1 << 60).to_le_bytes());
+	for (i, c) in "data".encode_utf16().enumerate() {
+	    e[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+	}
+	e[56 + 10..56 + 14].copy_from_slice(b"junk");
+	e[128..136].copy_from_slice(b"VENDOR!!");
+	let array_crc = crc32fast::hash(&array);
+
+	for (my_lba, alt_lba, array_lba) in [(1, sectors - 1, 2), (sectors - 1, 1, sectors - 1 - array_blocks)] {
+	    let h = &mut img[my_lba as usize * 512..][..512];
+	    h[..8].copy_from_slice(b"EFI PART");
+	    h[8..12].copy_from_slice(&0x00010001u32.to_le_bytes());
+	    h[12..16].copy_from_slice(&100u32.to_le_bytes());
+	    h[24..32].copy_from_slice(&my_lba.to_le_bytes());
+	    h[32..40].copy_from_slice(&alt_lba.to_le_bytes());
+	    h[40..48].copy_from_slice(&(2 + array_blocks).to_le_bytes());
+	    h[48..56].copy_from_slice(&(sectors - 2 - array_blocks).to_le_bytes());
+	    h[56..72].copy_from_slice(&[0x22; 16]);
+	    h[72..80].copy_from_slice(&array_lba.to_le_bytes());
+	    h[80..84].copy_from_slice(&entries.to_le_bytes());
+	    h[84..88].copy_from_slice(&(entry_sz as u32).to_le_bytes());
+	    h[88..92].copy_from_slice(&array_crc.to_le_bytes());
+	    h[92..100].copy_from_slice(b"REV1.1EX");
+	    h[200] = 0x5A;
+	    let crc = crc32fast::hash(&h[..100]);
+	    h[16..20].copy_from_slice(&crc.to_le_bytes());
+	    img[array_lba as usize * 512..][..array.len()].copy_from_slice(&array);
+	}
+	std::fs::write(path, img).unwrap();
+    }
+
+    #[test]
+    fn foreign_fields_survive_rewrites() {
+	let tmp = TempImage::new("foreign");
+	foreign_image(&tmp.0);
+	let before = std::fs::read(&tmp.0).unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let linux = PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(1024 * 1024).build().unwrap();
+	img.add_partition(&linux, 1024 * 1024).unwrap();
+	drop(img);
+	let after = std::fs::read(&tmp.0).unwrap();
+
+	// Both headers still check out, with the extended fields and reserved bytes intact.
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert!(!img.bkp_damaged);
+	assert_eq!(img.partition_count(), 2);
+	assert_eq!((img.hdr.revision, img.hdr.header_sz), (0x00010001, 100));
+	for lba in [1, 8191] {
+	    let h = &after[lba * 512..][..512];
+	    assert_eq!(&h[92..100], b"REV1.1EX");
+	    assert_eq!(h[200], 0x5A);
+	    assert_eq!(GptHeader::read(&mut img.fd, lba as u64, 512).unwrap().header_sz, 100);
+	}
+	// The foreign entry is untouched in both arrays: attributes, name field and the
+	// vendor bytes past the standard fields.
+	let backup_array = (8191 - 64) * 512;
+	for array in [2 * 512, backup_array] {
+	    assert_eq!(&after[array..array + 256], &before[array..array + 256]);
+	}
+	assert_eq!(img.pentry[0].as_ref().unwrap().attributes, 1 << 63 | 1 << 60);
+	// The new entry gets zeros past the standard fields.
+	assert!(after[2 * 512 + 256 + 128..2 * 512 + 512].iter().all(|b| *b == 0));
+	assert_eq!(img.pentry[1].as_ref().unwrap().name_bytes().unwrap()[..10], *b"L\0i\0n\0u\0x\0");
+    }
+
+    #[test]
+    fn delete_partitions() {
+	let tmp = TempImage::new("delete");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(1024 * 1024)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * 1024 * 1024)
+	    .partition(esp())
+	    .partition(linux)
+	    .build()
+	    .unwrap();
+
+	// Something that looks like a filesystem in the ESP.
+	let esp_start = 1024 * 1024;
+	let mut bytes = std::fs::read(&tmp.0).unwrap();
+	bytes[esp_start..esp_start + 1024 * 1024].fill(0xAA);
+	std::fs::write(&tmp.0, &bytes).unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let guid = img.pentry[1].as_ref().unwrap().unique_partition_guid;
+	assert_eq!(img.find_by_guid(guid), Some(1));
+	assert_eq!(img.find_by_name("efi system partition"), Some(0));
+	assert_eq!(img.delete_partition(0, true).unwrap(), "EFI system partition");
+	assert!(img.delete_partition(0, false).is_err());
+
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	assert_eq!(img.partition_count(), 1);
+	assert_eq!(img.find_by_guid(guid), Some(1));
+	// Only the start of it is zeroed.
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	let wiped = esp_start + WIPE_SZ as usize;
+	assert!(bytes[esp_start..wiped].iter().all(|b| *b == 0));
+	assert_eq!(bytes[wiped], 0xAA);
+    }
+
+    #[test]
+    fn delete_keeps_numbers() {
+	let tmp = TempImage::new("delete-numbers");
+	four_partitions(&tmp.0);
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert_eq!(img.delete_partition(1, false).unwrap(), "two");
+	drop(img);
+
+	let b = std::fs::read(&tmp.0).unwrap();
+	assert!(b[2 * 512 + GPT_ENTRY_SZ..][..GPT_ENTRY_SZ].iter().all(|b| *b == 0));
+	assert!(verify(&tmp.0).unwrap().iter().all(|c| c.problem.is_none()));
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let names = ["one", "three", "four"].map(|n| img.find_by_name(n));
+	assert_eq!(names, [Some(0), Some(2), Some(3)]);
+
+	// The last one goes and so does its slot, the empty one before it stays.
+	assert_eq!(img.delete_partition(3, false).unwrap(), "four");
+	assert_eq!(img.pentry.len(), 3);
+	assert!(img.pentry[1].is_none());
+    }
+
+    #[test]
+    fn wipe_partitions() {
+	const MIB: usize = 1024 * 1024;
+	let tmp = TempImage::new("wipe");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(4 * MIB)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(8 * MIB)
+	    .partition(esp())
+	    .partition(linux)
+	    .build()
+	    .unwrap();
+	let img = GptImage::open(&tmp.0).unwrap();
+	let ranges: Vec<_> = img.used().map(|p| (p.starting_lba as usize * 512, (p.ending_lba + 1) as usize * 512)).collect();
+	drop(img);
+	let mut bytes = std::fs::read(&tmp.0).unwrap();
+	for (start, end) in &ranges {
+	    bytes[*start..*end].fill(0xAA);
+	}
+	std::fs::write(&tmp.0, &bytes).unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let (esp_len, linux_len) = (ranges[0].1 - ranges[0].0, ranges[1].1 - ranges[1].0);
+	assert_eq!(img.wipe_partition(0, ZeroMode::Full).unwrap(), esp_len as u64);
+	assert_eq!(img.wipe_partition(1, ZeroMode::Quick).unwrap(), 2 * MIB as u64);
+	assert!(img.wipe_partition(2, ZeroMode::Full).is_err());
+	drop(img);
+
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	let (esp, linux) = (&bytes[ranges[0].0..ranges[0].1], &bytes[ranges[1].0..ranges[1].1]);
+	assert!(esp.iter().all(|b| *b == 0));
+	assert!(linux[..MIB].iter().all(|b| *b == 0));
+	assert!(linux[MIB..linux_len - MIB].iter().all(|b| *b == 0xAA));
+	assert!(linux[linux_len - MIB..].iter().all(|b| *b == 0));
+	// The table is untouched.
+	assert_eq!(GptImage::open_read_only(&tmp.0).unwrap().partition_count(), 2);
+    }
+
+    #[test]
+    fn resize_partitions() {
+	let tmp = TempImage::new("resize");
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(1024 * 1024)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(6 * 1024 * 1024)
+	    .partition(esp())
+	    .partition(linux)
+	    .build()
+	    .unwrap();
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert!(!img.grow_to_file().unwrap());
+	// The ESP can't grow into the next partition, but can shrink.
+	assert!(matches!(img.resize_partition(0, Some(3 * 1024 * 1024)), Err(BobErr::PartitionOverlap)));
+	assert_eq!(img.resize_partition(0, Some(512 * 1024)).unwrap(), 2048 + 1023);
+	assert!(matches!(img.resize_partition(1, Some(4 * 1024 * 1024)), Err(BobErr::PartitionOutOfBounds)));
+	drop(img);
+
+	// Extend the file, then grow the last partition into the new space.
+	std::fs::OpenOptions::new().write(true).open(&tmp.0).unwrap().set_len(8 * 1024 * 1024).unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert!(img.grow_to_file().unwrap());
+	let end = img.resize_partition(1, None).unwrap();
+	assert_eq!(end, 16384 - 2 - 32);
+
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	assert_eq!((img.hdr.alt_lba, img.hdr.last_usable_lba), (16383, end));
+	assert_eq!(img.pentry[1].as_ref().unwrap().ending_lba, end);
+	// The backup header moved to the new last LBA, the old one is gone.
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert_eq!(&bytes[16383 * 512..][..8], b"EFI PART");
+	assert_ne!(&bytes[12287 * 512..][..8], b"EFI PART");
+	assert!(!GptImage::open_read_only(&tmp.0).unwrap().bkp_damaged);
+    }
+
+    #[test]
+    fn inspect_image() {
+	let tmp = TempImage::new("inspect");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+
+	let img = GptImage::open_read_only(&tmp.0).unwrap();
+	let s = img.inspect();
+	assert!(s.contains("Size: 4.0 MiB (8192 sectors of 512 bytes)"));
+	assert!(s.lines().any(|l| l.starts_with("Partition entry LBA") && l.ends_with(" 2                 8159")));
+	assert!(s.lines().any(|l| l.contains("2048         4096    1.0 MiB") && l.ends_with("EFI system partition")));
+
+	let v: serde_json::Value = serde_json::from_str(&img.inspect_json()).unwrap();
+	assert_eq!(v["size"], 4 * 1024 * 1024);
+	assert_eq!(v["sector_size"], 512);
+	assert_eq!(v["primary_header"]["signature"], "EFI PART");
+	assert_eq!((&v["primary_header"]["partition_entry_lba"], &v["backup_header"]["partition_entry_lba"]), (&2.into(), &8159.into()));
+	assert_eq!(v["backup_damaged"], false);
+	assert_eq!(v["partitions"][0]["name"], "EFI system partition");
+	assert_eq!((&v["partitions"][0]["first_lba"], &v["partitions"][0]["last_lba"]), (&2048.into(), &4096.into()));
+    }
+
+    /// A 8 MiB image with partitions one to four, 1 MiB each.
+    #[allow(dead_code)]
+    fn four_partitions(path: &str) {
+	let mut b = DiskImgBuilder::new().output_file(path).total_size(8 * 1024 * 1024);
+	for name in ["one", "two", "three", "four"] {
+	    b = b.partition(PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).name(name).size(1024 * 1024).build().unwrap());
+	}
+	b.build().unwrap();
+    }
+
+    /// Empty the entry slots `slots` of the primary array, as deleting them with another
+    /// tool leaves them.
+    #[allow(dead_code)]
+    fn empty_slots(path: &str, slots: &[usize]) {
+	let mut b = std::fs::read(path).unwrap();
+	for slot in slots {
+	    b[2 * 512 + slot * GPT_ENTRY_SZ..][..GPT_ENTRY_SZ].fill(0);
+	}
+	std::fs::write(path, &b).unwrap();
+    }
+
+    #[test]
+    fn entries_keep_their_slots() {
+	let tmp = TempImage::new("slots");
+	four_partitions(&tmp.0);
+	empty_slots(&tmp.0, &[1, 2]);
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	assert_eq!(img.partition_count(), 2);
+	assert_eq!(img.find_by_name("four"), Some(3));
+	assert!(img.partition_view(1).is_none());
+	assert!(img.inspect().lines().any(|l| l.starts_with("  4 ") && l.ends_with("four")));
+	assert_eq!(img.layout().partitions.len(), 2);
+
+	// Rewritten, the entry stays in slot 4 and the empty ones stay empty.
+	img.write_tables().unwrap();
+	drop(img);
+	let b = std::fs::read(&tmp.0).unwrap();
+	let entry = |slot: usize| &b[2 * 512 + slot * GPT_ENTRY_SZ..][..GPT_ENTRY_SZ];
+	assert!(entry(1).iter().all(|b| *b == 0));
+	assert_eq!(entry(3)[56..64], *b"f\0o\0u\0r\0");
+	assert!(verify(&tmp.0).unwrap().iter().all(|c| c.problem.is_none()));
+	assert_eq!(GptImage::open(&tmp.0).unwrap().find_by_name("four"), Some(3));
+
+	// A new partition fills the first empty slot.
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let new = PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).size(512 * 1024).build().unwrap();
+	assert_eq!(img.add_partition(&new, DEFAULT_ALIGNMENT).unwrap().0, 1);
+	assert_eq!(img.find_by_name("four"), Some(3));
+    }
+
+    #[test]
+    fn resize_keeps_slots() {
+	let tmp = TempImage::new("resize-slots");
+	four_partitions(&tmp.0);
+	empty_slots(&tmp.0, &[1]);
+
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	let three = img.find_by_name("three").unwrap();
+	assert_eq!(three, 2);
+	img.resize_partition(three, Some(512 * 1024)).unwrap();
+	img.resize_partition(3, None).unwrap();
+	drop(img);
+
+	let b = std::fs::read(&tmp.0).unwrap();
+	let entry = |slot: usize| &b[2 * 512 + slot * GPT_ENTRY_SZ..][..GPT_ENTRY_SZ];
+	assert!(entry(1).iter().all(|b| *b == 0));
+	assert_eq!(entry(2)[56..66], *b"t\0h\0r\0e\0e\0");
+	assert_eq!(entry(3)[56..64], *b"f\0o\0u\0r\0");
+	let img = GptImage::open(&tmp.0).unwrap();
+	assert_eq!((img.find_by_name("three"), img.find_by_name("four")), (Some(2), Some(3)));
+	let three = img.pentry[2].as_ref().unwrap();
+	assert_eq!((three.ending_lba - three.starting_lba + 1) * 512, 512 * 1024);
+	assert_eq!(img.pentry[3].as_ref().unwrap().ending_lba, img.hdr.last_usable_lba);
+    }
+
+    #[test]
+    fn verify_checks() {
+	let tmp = TempImage::new("verify");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let failed = |path: &str| -> Vec<&'static str> {
+	    verify(path).unwrap().into_iter().filter(|c| c.problem.is_some()).map(|c| c.name).collect()
+	};
+
+	assert!(failed(&tmp.0).is_empty());
+	let json = serde_json::to_value(verify(&tmp.0).unwrap()).unwrap();
+	assert_eq!(json[0], serde_json::json!({ "name": "protective MBR", "problem": null }));
+
+	// Push the partition's end past the last usable LBA, in the primary array only.
+	let mut b = std::fs::read(&tmp.0).unwrap();
+	b[2 * 512 + 40..2 * 512 + 48].copy_from_slice(&9000u64.to_le_bytes());
+	b[0x1FE] = 0;
+	std::fs::write(&tmp.0, &b).unwrap();
+	let f = failed(&tmp.0);
+	assert!(f.contains(&"protective MBR"));
+	assert!(f.contains(&"partition ranges"));
+	assert!(f.contains(&"backup entry array matches primary"));
+    }
+
+    #[test]
+    fn partition_sectors() {
+	let tmp = TempImage::new("sectors");
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let mut p = img.get_partition_view("EFI system partition").unwrap();
+	let last = p.sectors() - 1;
+
+	p.write_sectors(last, &[0xAB; 512]).unwrap();
+	let mut buf = [0; 512];
+	p.read_sectors(last, &mut buf).unwrap();
+	assert_eq!(buf, [0xAB; 512]);
+
+	assert!(p.write_sectors(last, &[0; 1024]).is_err());
+	assert!(p.write_sectors(0, &[0; 100]).is_err());
+	assert!(p.read_sectors(u64::MAX, &mut buf).is_err());
+    }
+
+    #[test]
+    fn corrupt_images_dont_panic() {
+	let tmp = TempImage::new("corrupt");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let good = std::fs::read(&tmp.0).unwrap();
+
+	// Deterministic xorshift so failures are reproducible.
+	let mut x: u64 = 0x9E37_79B9_7F4A_7C15;
+	let mut next = || {
+	    x ^= x << 13;
+	    x ^= x >> 7;
+	    x ^= x << 17;
+	    x
+	};
+
+	// Corrupt the primary header and the start of the entry array.
+	for _ in 0..500 {
+	    let mut img = good.clone();
+	    for _ in 0..1 + next() % 8 {
+		let i = 512 + (next() % 1024) as usize;
+		img[i] = next() as u8;
+	    }
+	    std::fs::write(&tmp.0, &img).unwrap();
+	    if let Ok(gpt) = GptImage::open_read_only(&tmp.0) {
+		let _ = gpt.layout();
+		let _ = gpt.inspect();
+		let _ = gpt.inspect_json();
+	    }
+	}
+
+	// An entry ending at the last LBA there could be, only a warning with its array CRC
+	// wrong, found by fuzzing.
+	let mut img = good.clone();
+	img[1024 + 40..1024 + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+	std::fs::write(&tmp.0, &img).unwrap();
+	let gpt = GptImage::open_read_only(&tmp.0).unwrap();
+	assert!(gpt.inspect().contains(&u64::MAX.to_string()));
+	let _ = gpt.inspect_json();
+    }
+
+    /// An image whose first entry ends before it starts, CRCs and all in order.
+    #[allow(dead_code)]
+    fn inverted_entry_image(tmp: &TempImage) {
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	img.pentry[0].as_mut().unwrap().ending_lba = 10;
+	img.write_tables().unwrap();
+    }
+
+    #[test]
+    fn extract_inverted_entry() {
+	let tmp = TempImage::new("extract-inverted");
+	inverted_entry_image(&tmp);
+	let mut img = GptImage::open_read_only(&tmp.0).unwrap();
+	assert!(matches!(img.extract_partition(0, &mut io::sink()), Err(BobErr::PartitionOutOfBounds)));
+    }
+
+    #[test]
+    fn wipe_inverted_entry() {
+	let tmp = TempImage::new("wipe-inverted");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let before = std::fs::read(&tmp.0).unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	img.pentry[0].as_mut().unwrap().ending_lba = 10;
+	for mode in [ZeroMode::Quick, ZeroMode::Full] {
+	    assert!(matches!(img.wipe_partition(0, mode), Err(BobErr::PartitionOutOfBounds)));
+	}
+	drop(img);
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), before);
+    }
+
+    #[test]
+    fn delete_wipe_inverted_entry() {
+	let tmp = TempImage::new("delete-inverted");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .build()
+	    .unwrap();
+	let before = std::fs::read(&tmp.0).unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	img.pentry[0].as_mut().unwrap().ending_lba = 10;
+	assert!(matches!(img.delete_partition(0, true), Err(BobErr::PartitionOutOfBounds)));
+	assert_eq!(img.partition_count(), 1);
+	drop(img);
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), before);
+    }
+
+    #[test]
+    fn open_checks_entries() {
+	let tmp = TempImage::new("open-inverted");
+	inverted_entry_image(&tmp);
+	assert!(matches!(GptImage::open(&tmp.0), Err(BobErr::PartitionOutOfBounds)));
+	assert_eq!(GptImage::open_read_only(&tmp.0).unwrap().partition_count(), 1);
+
+	// Two entries over the same blocks.
+	let linux = PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .start_offset(3 * 1024 * 1024)
+	    .end_offset(3 * 1024 * 1024 + 512 * 1024)
+	    .build()
+	    .unwrap();
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(esp())
+	    .partition(linux)
+	    .build()
+	    .unwrap();
+	let mut img = GptImage::open(&tmp.0).unwrap();
+	img.pentry[1].as_mut().unwrap().starting_lba = img.pentry[0].as_ref().unwrap().ending_lba;
+	img.write_tables().unwrap();
+	assert!(matches!(GptImage::open(&tmp.0), Err(BobErr::PartitionOverlap)));
+	assert!(GptImage::open_read_only(&tmp.0).is_ok());
+    }
+
+    #[test]
+    fn plan_rejects_bad_layouts() {
+	let part = |so, eo| PartitionBuilder::new()
+	    .partition_type(PartitionType::EFISystem)
+	    .start_offset(so)
+	    .end_offset(eo)
+	    .build()
+	    .unwrap();
+	let plan = |parts: &[PartitionInput]| {
+	    let mut b = DiskImgBuilder::new().output_file("unused.img").total_size(4 * 1024 * 1024);
+	    for p in parts {
+		b = b.partition(p.clone());
+	    }
+	    b.plan()
+	};
+
+	assert!(plan(&[esp()]).is_ok());
+	assert!(matches!(plan(&[part(0, 1024 * 1024)]), Err(BobErr::PartitionOutOfBounds)));
+	assert!(matches!(plan(&[part(1024 * 1024, 8 * 1024 * 1024)]), Err(BobErr::PartitionOutOfBounds)));
+	assert!(matches!(plan(&[esp(), part(1536 * 1024, 3 * 1024 * 1024)]), Err(BobErr::PartitionOverlap)));
+	assert!(!std::path::Path::new("unused.img").exists());
+    }
+
+    #[test]
+    fn sized_partitions_are_placed_in_order() {
+	let sized = |pt, size| PartitionBuilder::new()
+	    .partition_type(pt)
+	    .size(size)
+	    .build()
+	    .unwrap();
+	let plan = DiskImgBuilder::new()
+	    .output_file("unused.img")
+	    .total_size(16 * 1024 * 1024)
+	    .partition(sized(PartitionType::EFISystem, parse_size("3M").unwrap()))
+	    .partition(esp_at_5m())
+	    .partition(sized(PartitionType::LinuxFilesystem, 1000))
+	    .plan()
+	    .unwrap();
+
+	let lbas: Vec<_> = plan.entries.iter().map(|e| (e.starting_lba, e.ending_lba)).collect();
+	// The explicitly placed partition ends at 6MiB, the next one starts at 7MiB.
+	assert_eq!(lbas, [(2048, 8191), (10240, 12288), (14336, 14337)]);
+
+	assert!(PartitionBuilder::new().partition_type(PartitionType::EFISystem).build().is_err());
+	assert!(PartitionBuilder::new().partition_type(PartitionType::EFISystem).size(1).end_offset(2).build().is_err());
+    }
+
+    #[test]
+    fn alignment() {
+	let sized = || PartitionBuilder::new()
+	    .partition_type(PartitionType::LinuxFilesystem)
+	    .size(4096)
+	    .build()
+	    .unwrap();
+	let plan = |align| DiskImgBuilder::new()
+	    .output_file("unused.img")
+	    .total_size(4 * 1024 * 1024)
+	    .alignment(align)
+	    .partition(sized())
+	    .partition(sized())
+	    .plan();
+
+	let lbas: Vec<_> = plan(4096).unwrap().entries.iter().map(|e| e.starting_lba).collect();
+	assert_eq!(lbas, [40, 48]);
+	let lbas: Vec<_> = plan(512).unwrap().entries.iter().map(|e| e.starting_lba).collect();
+	assert_eq!(lbas, [34, 42]);
+	assert!(matches!(plan(1000), Err(BobErr::InvalidAlignment)));
+	assert!(matches!(plan(0), Err(BobErr::InvalidAlignment)));
+    }
+
+    #[allow(dead_code)]
+    fn esp_at_5m() -> PartitionInput {
+	PartitionBuilder::new()
+	    .partition_type(PartitionType::EFISystem)
+	    .start_offset(5 * 1024 * 1024)
+	    .end_offset(6 * 1024 * 1024)
+	    .build()
+	    .unwrap()
+    }
+
+    #[test]
+    fn sizes() {
+	assert_eq!(parse_size("512"), Some(512));
+	assert_eq!(parse_size("64M"), Some(64 << 20));
+	assert_eq!(parse_size("2g"), Some(2 << 30));
+	assert_eq!(parse_size("M"), None);
+	assert_eq!(parse_size("1.5G"), None);
+    }
+
+    #[test]
+    fn attributes() {
+	assert_eq!(parse_attributes("required"), Some(1));
+	assert_eq!(parse_attributes("No-Block-IO+legacy-boot"), Some(0b110));
+	assert_eq!(parse_attributes("required+guid:60"), Some(1 | 1 << 60));
+	assert_eq!(parse_attributes("guid:3"), None);
+	assert_eq!(parse_attributes("bootable"), None);
+
+	let esp = PartitionBuilder::new()
+	    .partition_type(PartitionType::EFISystem)
+	    .size(1024 * 1024)
+	    .attributes(parse_attributes("required").unwrap())
+	    .build()
+	    .unwrap();
+	let plan = DiskImgBuilder::new().output_file("unused.img").total_size(4 * 1024 * 1024).partition(esp).plan().unwrap();
+	assert_eq!(plan.entries[0].attributes, 1);
+    }
+}
@@ -0,0 +1,16 @@
+/// GUIDs are shared with the kernel through the common crate, bob only needs to
+/// supply the randomness.
+pub use common::guid::Guid;
+
+/// Generate a new Guid from random bytes
+/// https://datatracker.ietf.org/doc/html/rfc4122#section-4.4
+pub fn new_v4() -> Guid {
+    Guid::from_random_bytes(rand::random())
+}
+
+/// Generate a new Guid from the given generator, a seeded one for reproducible images.
+pub fn from_rng(rng: &mut impl rand::RngCore) -> Guid {
+    let mut b = [0; 16];
+    rng.fill_bytes(&mut b);
+    Guid::from_random_bytes(b)
+}
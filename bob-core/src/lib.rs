@@ -0,0 +1,47 @@
+//! GPT disk images and FAT filesystems, the library behind bob.
+//!
+//! Everything bob does to an image short of parsing its command line lives here, so other
+//! tools and tests can build images without shelling out to it. An image is described
+//! with a [`DiskImgBuilder`] and written by [`DiskImgBuilder::build`], which hands back
+//! the [`GptImage`] (or [`MbrImage`], with [`PartitionTable::Mbr`]) to keep working on.
+//! Partitions are reached through [`Partition`] views, which [`format_as_fat`] and
+//! [`copy_partition`] write through.
+//!
+//! ```
+//! use bob_core::{format_as_fat, DiskImgBuilder, PartitionBuilder, PartitionType};
+//!
+//! let path = std::env::temp_dir().join(format!("bob-core-doc-{}.img", std::process::id()));
+//! let esp = PartitionBuilder::new()
+//!     .partition_type(PartitionType::EFISystem)
+//!     .size(2 * 1024 * 1024)
+//!     .build()?;
+//! let mut img = DiskImgBuilder::new()
+//!     .output_file(path.to_str().unwrap())
+//!     .total_size(4 * 1024 * 1024)
+//!     .partition(esp)
+//!     .build()?;
+//! format_as_fat(&mut img.partition_view(0).unwrap())?;
+//! assert_eq!(img.partition_count(), 1);
+//! # std::fs::remove_file(&path).unwrap();
+//! # Ok::<(), bob_core::BobErr>(())
+//! ```
+//!
+//! The modules stay public for the less common pieces: `table` for reading and writing
+//! partition tables as JSON or sfdisk scripts, `sink` for block devices and how unused
+//! space gets zeroed, `path` for Windows paths.
+
+pub mod err;
+pub mod fat;
+pub mod gpt;
+pub mod guid;
+pub mod path;
+pub mod sink;
+pub mod table;
+
+pub use err::BobErr;
+pub use fat::format_as_fat;
+pub use gpt::{
+    copy_partition, verify, write_partition_bytes, DiskImage, DiskImgBuilder, GptImage, ImagePlan, MbrImage, Partition, PartitionBuilder,
+    PartitionInput, PartitionTable, PartitionType, PartitionView,
+};
+pub use guid::Guid;
@@ -0,0 +1,79 @@
+//! Host path handling.
+//!
+//! Windows users pass drive letter paths (`C:\path\to\out.img`), UNC shares, and long
+//! path (`\\?\`) prefixed paths. Windows does no normalization at all on `\\?\` paths, so
+//! forward slashes and `.`/`..` components in them have to be dealt with before they're
+//! handed to the OS. Everything else is left for the OS (and std) to interpret.
+
+use std::path::PathBuf;
+
+const VERBATIM_PREFIX: &str = r"\\?\";
+
+/// Convert a path given on the command line into one for the host.
+pub fn host_path(s: &str) -> PathBuf {
+    if cfg!(windows) {
+	PathBuf::from(windows_path(s))
+    } else {
+	PathBuf::from(s)
+    }
+}
+
+/// Normalize verbatim (`\\?\`) paths, anything else is returned as is.
+fn windows_path(s: &str) -> String {
+    let rest = if let Some(rest) = s.strip_prefix(VERBATIM_PREFIX).or_else(|| s.strip_prefix("//?/")) {
+	rest
+    } else {
+	return String::from(s);
+    };
+
+    // The first component is the drive (C:) or UNC marker, which .. never climbs above.
+    let mut parts: Vec<&str> = Vec::new();
+    for (i, part) in rest.split(['\\', '/']).enumerate() {
+	match part {
+	    "" | "." if i > 0 => {},
+	    ".." if i > 0 => {
+		if parts.len() > 1 {
+		    parts.pop();
+		}
+	    },
+	    _ => parts.push(part),
+	}
+    }
+
+    format!("{VERBATIM_PREFIX}{}", parts.join(r"\"))
+}
+
+/// Partition names are matched case-insensitively, the way Windows treats names.
+pub fn names_match(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn plain_paths_untouched() {
+	assert_eq!(windows_path(r"C:\path\to\out.img"), r"C:\path\to\out.img");
+	assert_eq!(windows_path("C:/path/to/out.img"), "C:/path/to/out.img");
+	assert_eq!(windows_path(r"\\server\share\out.img"), r"\\server\share\out.img");
+	assert_eq!(windows_path("out.img"), "out.img");
+    }
+
+    #[test]
+    fn verbatim_paths_normalized() {
+	assert_eq!(windows_path(r"\\?\C:\path/to\\.\out.img"), r"\\?\C:\path\to\out.img");
+	assert_eq!(windows_path(r"\\?\C:\path\tmp\..\out.img"), r"\\?\C:\path\out.img");
+	assert_eq!(windows_path(r"\\?\C:\..\..\out.img"), r"\\?\C:\out.img");
+	assert_eq!(windows_path("//?/C:/out.img"), r"\\?\C:\out.img");
+	assert_eq!(windows_path(r"\\?\UNC\server\share\out.img"), r"\\?\UNC\server\share\out.img");
+    }
+
+    #[test]
+    fn case_insensitive_names() {
+	assert!(names_match("EFI system partition", "efi SYSTEM Partition"));
+	assert!(names_match("ÉFI", "éfi"));
+	assert!(!names_match("EFI", "EFI2"));
+    }
+}
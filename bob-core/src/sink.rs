@@ -0,0 +1,322 @@
+//! Where an image gets written: a file, or a block device being flashed.
+//!
+//! Much of what bob writes is zeros: padding in the partition entry arrays, wiped
+//! partition starts, partitions cleared with `--zero-partitions`. A fresh image file is
+//! sparse and needs none of it written, but on a device the old contents are still there,
+//! and pushing megabytes of zero buffers through a USB stick is slow. `ImageSink` lets a
+//! target zero or discard ranges its own way and falls back to plain writes.
+//!
+//! On Linux a block device is asked to write zeroes itself (`BLKZEROOUT`, which uses the
+//! device's write-zeroes or discard support when it has it) and can be told about ranges
+//! that are no longer used (`BLKDISCARD`, a TRIM on SSDs). A regular file gets zeroed
+//! ranges deallocated. Anything else, or a kernel that refuses, gets zeros written.
+//!
+//! Partition contents normally go through the file, a seek and a read or write for every
+//! access. With `IoBackend::Mmap` the image is mapped instead and partition views copy to
+//! and from memory, which pays off when the accesses are many and small (FAT cluster
+//! chains, in-place edits): the kernel writes back whole dirty pages when it likes. The
+//! mapping is shared, so the partition table code writing through the file sees the same
+//! bytes and zeroing keeps going through `ImageSink`.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use crate::err::BobErr;
+
+/// Bytes cleared at each end of a partition in quick mode.
+pub const QUICK_ZERO_BYTES: u64 = 1024 * 1024;
+
+/// How much of each partition `--zero-partitions` clears. Stale filesystem signatures
+/// that blkid or the firmware may pick up live at the start and end, filesystem, RAID
+/// and LVM superblocks alike.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZeroMode {
+    /// The whole partition.
+    Full,
+    /// The first and last `QUICK_ZERO_BYTES` of the partition.
+    Quick,
+}
+
+impl ZeroMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+	match name {
+	    "full" => Some(Self::Full),
+	    "quick" => Some(Self::Quick),
+	    _ => None,
+	}
+    }
+
+    /// The byte ranges, as (offset, length), to zero for a partition.
+    pub fn ranges(&self, start: u64, len: u64) -> Vec<(u64, u64)> {
+	match self {
+	    ZeroMode::Quick if len > 2 * QUICK_ZERO_BYTES => {
+		vec![(start, QUICK_ZERO_BYTES), (start + len - QUICK_ZERO_BYTES, QUICK_ZERO_BYTES)]
+	    },
+	    _ => vec![(start, len)],
+	}
+    }
+}
+
+/// How partition contents are read and written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoBackend {
+    /// Seek, then read or write the image file.
+    File,
+    /// Copy to and from a shared mapping of the image.
+    Mmap,
+}
+
+impl IoBackend {
+    pub fn from_name(name: &str) -> Option<Self> {
+	match name {
+	    "file" => Some(Self::File),
+	    "mmap" => Some(Self::Mmap),
+	    _ => None,
+	}
+    }
+}
+
+/// A mapping of all of `f` for `backend`, None if it doesn't use one. The mapping covers
+/// the image as long as it was when mapped.
+pub fn map_image(f: &File, backend: IoBackend) -> Result<Option<MmapMut>, BobErr> {
+    if backend == IoBackend::File {
+	return Ok(None);
+    }
+    // SAFETY: nothing but bob should touch the image while it runs. If something truncates
+    // it anyway, accesses past the new end raise SIGBUS instead of failing, the price of
+    // asking for a mapping.
+    let map = unsafe { MmapMut::map_mut(f) }.map_err(BobErr::IO)?;
+    Ok(Some(map))
+}
+
+/// How a range ended up zeroed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    /// The block device zeroed it (`BLKZEROOUT`).
+    Device,
+    /// The file's blocks were deallocated.
+    PunchHole,
+    /// Zeros were written.
+    Write,
+}
+
+pub trait ImageSink: Write + Seek {
+    /// Zero `len` bytes at `offset`. The position afterwards is unspecified.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<Method, BobErr> {
+	write_zeros(self, offset, len)?;
+	Ok(Method::Write)
+    }
+
+    /// Tell the target `len` bytes at `offset` are no longer used. Only a hint: the range
+    /// may read back as anything afterwards, including what was there. Returns whether
+    /// the target took it.
+    fn discard(&mut self, _offset: u64, _len: u64) -> Result<bool, BobErr> {
+	Ok(false)
+    }
+}
+
+impl ImageSink for File {
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<Method, BobErr> {
+	if len == 0 {
+	    return Ok(Method::Write);
+	}
+	if let Some(method) = os::zero_range(self, offset, len) {
+	    return Ok(method);
+	}
+	write_zeros(self, offset, len)?;
+	Ok(Method::Write)
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> Result<bool, BobErr> {
+	Ok(len > 0 && os::discard(self, offset, len))
+    }
+}
+
+impl ImageSink for std::io::Cursor<Vec<u8>> {}
+
+fn write_zeros<W: Write + Seek + ?Sized>(w: &mut W, offset: u64, len: u64) -> Result<(), BobErr> {
+    let zeros = [0; 64 * 1024];
+    w.seek(SeekFrom::Start(offset)).map_err(BobErr::IO)?;
+    let mut left = len;
+    while left > 0 {
+	let n = left.min(zeros.len() as u64) as usize;
+	w.write_all(&zeros[..n]).map_err(BobErr::IO)?;
+	left -= n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod os {
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::FileTypeExt;
+
+    use tracing::debug;
+
+    use super::Method;
+
+    /// `_IO(0x12, 119)` from linux/fs.h.
+    const BLKDISCARD: libc::c_ulong = 0x1277;
+    /// `_IO(0x12, 127)` from linux/fs.h.
+    const BLKZEROOUT: libc::c_ulong = 0x127f;
+
+    /// Zero the range without writing the zeros, None if that isn't possible here.
+    pub fn zero_range(f: &File, offset: u64, len: u64) -> Option<Method> {
+	let file_type = f.metadata().ok()?.file_type();
+	if file_type.is_block_device() {
+	    if range_ioctl(f, BLKZEROOUT, offset, len) {
+		return Some(Method::Device);
+	    }
+	    debug!(error = %std::io::Error::last_os_error(), "BLKZEROOUT failed, writing zeros");
+	} else if file_type.is_file() {
+	    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+	    // SAFETY: plain syscall on a file descriptor we own.
+	    let ret = unsafe { libc::fallocate(f.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) };
+	    if ret == 0 {
+		return Some(Method::PunchHole);
+	    }
+	    debug!(error = %std::io::Error::last_os_error(), "punching a hole failed, writing zeros");
+	}
+	None
+    }
+
+    /// Discard the range on a block device. Files are left alone: punching a hole would
+    /// change what they read back as, which a hint mustn't.
+    pub fn discard(f: &File, offset: u64, len: u64) -> bool {
+	if !f.metadata().is_ok_and(|m| m.file_type().is_block_device()) {
+	    return false;
+	}
+	let ok = range_ioctl(f, BLKDISCARD, offset, len);
+	if !ok {
+	    debug!(error = %std::io::Error::last_os_error(), "BLKDISCARD failed");
+	}
+	ok
+    }
+
+    fn range_ioctl(f: &File, request: libc::c_ulong, offset: u64, len: u64) -> bool {
+	let range = [offset, len];
+	// SAFETY: both ioctls read a [u64; 2] of offset and length, which outlives the call.
+	unsafe { libc::ioctl(f.as_raw_fd(), request, range.as_ptr()) == 0 }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod os {
+    use std::fs::File;
+
+    use super::Method;
+
+    pub fn zero_range(_f: &File, _offset: u64, _len: u64) -> Option<Method> {
+	None
+    }
+
+    pub fn discard(_f: &File, _offset: u64, _len: u64) -> bool {
+	false
+    }
+}
+
+/// Whether `f` is a block device rather than a file.
+#[cfg(unix)]
+pub fn is_block_device(f: &File) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    f.metadata().is_ok_and(|m| m.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+pub fn is_block_device(_f: &File) -> bool {
+    false
+}
+
+/// The size of the block device at `path`, None if it isn't one (or doesn't exist yet).
+pub fn device_size(path: &Path) -> Option<u64> {
+    let mut f = File::open(path).ok()?;
+    if !is_block_device(&f) {
+	return None;
+    }
+    f.seek(SeekFrom::End(0)).ok()
+}
+
+/// Where the block device at `path`, or any of its partitions, is mounted. Writing to a
+/// mounted device corrupts whatever is mounted and then the image.
+pub fn mount_points(path: &Path) -> Vec<String> {
+    let device = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounted_on(&mounts, &device.to_string_lossy())
+}
+
+/// Mount points in /proc/mounts style `mounts` of `device` or its partitions, which are
+/// the device's name followed by a number, or by `p` and a number (nvme0n1p1).
+fn mounted_on(mounts: &str, device: &str) -> Vec<String> {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    mounts.lines().filter_map(|line| {
+	let mut fields = line.split_whitespace();
+	let (source, target) = (fields.next()?, fields.next()?);
+	let rest = source.strip_prefix(device)?;
+	(rest.is_empty() || is_number(rest) || rest.strip_prefix('p').is_some_and(is_number)).then(|| target.to_string())
+    }).collect()
+}
+
+/// Flush everything written to the block device at `path` out to the hardware, so the
+/// stick can be pulled once bob exits.
+pub fn sync_device(path: &Path) -> Result<(), BobErr> {
+    File::open(path).and_then(|f| f.sync_all()).map_err(BobErr::IO)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn quick_ranges() {
+	const MIB: u64 = 1024 * 1024;
+	assert_eq!(ZeroMode::Quick.ranges(MIB, 16 * MIB), [(MIB, MIB), (16 * MIB, MIB)]);
+	// Small partitions are cleared whole.
+	assert_eq!(ZeroMode::Quick.ranges(MIB, 2 * MIB), [(MIB, 2 * MIB)]);
+	assert_eq!(ZeroMode::Full.ranges(MIB, 16 * MIB), [(MIB, 16 * MIB)]);
+	assert_eq!(ZeroMode::from_name("quick"), Some(ZeroMode::Quick));
+	assert_eq!(ZeroMode::from_name("fast"), None);
+    }
+
+    #[test]
+    fn zeroes_old_data() {
+	let p = std::env::temp_dir().join(format!("bob-test-{}-zero.img", std::process::id()));
+	std::fs::write(&p, [0xAA; 3 * 4096]).unwrap();
+	let mut f = File::options().read(true).write(true).open(&p).unwrap();
+	f.write_zeroes(4096, 4096).unwrap();
+	// A discard on a file is a no-op.
+	assert!(!f.discard(8192, 4096).unwrap());
+	drop(f);
+	let data = std::fs::read(&p).unwrap();
+	let _ = std::fs::remove_file(&p);
+	assert_eq!(data.len(), 3 * 4096);
+	assert!(data[..4096].iter().all(|b| *b == 0xAA));
+	assert!(data[4096..8192].iter().all(|b| *b == 0));
+	assert!(data[8192..].iter().all(|b| *b == 0xAA));
+    }
+
+    #[test]
+    fn device_mounts() {
+	let mounts = "/dev/sdb1 /media/stick vfat rw 0 0\n/dev/sdb /mnt ext4 rw 0 0\n\
+		      /dev/sdba1 /other ext4 rw 0 0\n/dev/nvme0n1p2 / ext4 rw 0 0\nproc /proc proc rw 0 0\n";
+	assert_eq!(mounted_on(mounts, "/dev/sdb"), ["/media/stick", "/mnt"]);
+	assert_eq!(mounted_on(mounts, "/dev/nvme0n1"), ["/"]);
+	assert!(mounted_on(mounts, "/dev/sdc").is_empty());
+	let p = std::env::temp_dir().join(format!("bob-test-{}-device.img", std::process::id()));
+	std::fs::write(&p, [0; 512]).unwrap();
+	assert_eq!(device_size(&p), None);
+	let _ = std::fs::remove_file(&p);
+    }
+
+    #[test]
+    fn write_fallback() {
+	let mut c = std::io::Cursor::new(vec![0xAA; 100_000]);
+	assert_eq!(c.write_zeroes(10, 70_000).unwrap(), Method::Write);
+	let data = c.into_inner();
+	assert_eq!((data[9], data[10], data[70_009], data[70_010]), (0xAA, 0, 0, 0xAA));
+    }
+}
@@ -0,0 +1,364 @@
+//! Partition table export and import.
+//!
+//! A `TableLayout` is a plain description of a GPT partition table, it can be written out
+//! as JSON or as an sfdisk script (the format `sfdisk --dump` produces) and read back in to
+//! recreate the same layout on a new image.
+//! sfdisk script reference: https://man7.org/linux/man-pages/man8/sfdisk.8.html
+
+use serde::{Deserialize, Serialize};
+
+use crate::err::BobErr;
+use crate::gpt::{array_blocks, MIN_PARTITION_ENTRIES};
+use crate::guid::Guid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFormat {
+    Json,
+    Sfdisk,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TableLayout {
+    #[serde(with = "guid_str")]
+    pub disk_guid: Guid,
+    pub sector_size: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// The last LBA of the disk, where the backup header lives.
+    pub last_lba: u64,
+    /// Entries in the partition entry arrays.
+    #[serde(default = "default_max_partitions")]
+    pub max_partitions: u32,
+    pub partitions: Vec<LayoutPartition>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LayoutPartition {
+    #[serde(with = "guid_str")]
+    pub type_guid: Guid,
+    #[serde(with = "guid_str")]
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+/// GPT attribute bits sfdisk has names for. Bits 48-63 are written as GUID:<bit>.
+const SFDISK_ATTRS: [(u32, &str); 3] = [
+    (0, "RequiredPartition"),
+    (1, "NoBlockIOProtocol"),
+    (2, "LegacyBIOSBootable"),
+];
+
+impl TableFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+	match name {
+	    "json" => Some(Self::Json),
+	    "sfdisk" => Some(Self::Sfdisk),
+	    _ => None,
+	}
+    }
+}
+
+impl TableLayout {
+    pub fn export(&self, format: TableFormat) -> String {
+	match format {
+	    TableFormat::Json => {
+		let mut s = serde_json::to_string_pretty(self).expect("layout to serialize");
+		s.push('\n');
+		s
+	    },
+	    TableFormat::Sfdisk => self.to_sfdisk(),
+	}
+    }
+
+    /// Parse a layout, if `format` isn't given it's guessed from the contents.
+    pub fn import(s: &str, format: Option<TableFormat>) -> Result<Self, BobErr> {
+	let format = format.unwrap_or(if s.trim_start().starts_with('{') {
+	    TableFormat::Json
+	} else {
+	    TableFormat::Sfdisk
+	});
+
+	match format {
+	    TableFormat::Json => serde_json::from_str(s).map_err(|e| BobErr::TableParse(e.to_string())),
+	    TableFormat::Sfdisk => Self::from_sfdisk(s),
+	}
+    }
+
+    fn to_sfdisk(&self) -> String {
+	let mut s = String::new();
+	s.push_str("label: gpt\n");
+	s.push_str(&format!("label-id: {}\n", self.disk_guid));
+	s.push_str("unit: sectors\n");
+	s.push_str(&format!("first-lba: {}\n", self.first_usable_lba));
+	s.push_str(&format!("last-lba: {}\n", self.last_usable_lba));
+	s.push_str(&format!("sector-size: {}\n", self.sector_size));
+	s.push_str(&format!("table-length: {}\n", self.max_partitions));
+	s.push('\n');
+
+	for p in &self.partitions {
+	    s.push_str(&format!("start={}, size={}, type={}, uuid={}",
+				p.first_lba,
+				p.last_lba - p.first_lba + 1,
+				p.type_guid,
+				p.unique_guid));
+	    if !p.name.is_empty() {
+		s.push_str(&format!(", name=\"{}\"", p.name.replace('"', "\\\"")));
+	    }
+	    let attrs = sfdisk_attrs(p.attributes);
+	    if !attrs.is_empty() {
+		s.push_str(&format!(", attrs=\"{attrs}\""));
+	    }
+	    s.push('\n');
+	}
+
+	s
+    }
+
+    fn from_sfdisk(s: &str) -> Result<Self, BobErr> {
+	let err = |msg: String| BobErr::TableParse(msg);
+
+	let mut disk_guid = None;
+	let mut first_usable_lba = None;
+	let mut last_usable_lba = None;
+	let mut sector_size = 512;
+	let mut max_partitions = MIN_PARTITION_ENTRIES;
+	let mut partitions = Vec::new();
+
+	for (n, line) in s.lines().enumerate() {
+	    let line = line.trim();
+	    if line.is_empty() || line.starts_with('#') {
+		continue;
+	    }
+
+	    // Partition lines may be prefixed with a device name, "image.img1 : start=..."
+	    let fields = match line.split_once(" : ") {
+		Some((_, fields)) => fields,
+		None if line.contains('=') => line,
+		None => {
+		    let (key, value) = line.split_once(':').ok_or(err(format!("line {}: expected 'key: value'", n + 1)))?;
+		    let value = value.trim();
+		    let parse_u64 = |v: &str| v.parse::<u64>().map_err(|_| err(format!("line {}: bad number {v}", n + 1)));
+		    match key.trim() {
+			"label" if value != "gpt" => return Err(err(format!("line {}: only gpt labels are supported", n + 1))),
+			"label-id" => disk_guid = Some(parse_guid(value).map_err(|_| err(format!("line {}: bad GUID {value}", n + 1)))?),
+			"first-lba" => first_usable_lba = Some(parse_u64(value)?),
+			"last-lba" => last_usable_lba = Some(parse_u64(value)?),
+			"sector-size" => sector_size = parse_u64(value)?,
+			"table-length" => max_partitions = u32::try_from(parse_u64(value)?).map_err(|_| err(format!("line {}: table-length {value} is too big", n + 1)))?,
+			"unit" if value != "sectors" => return Err(err(format!("line {}: only sector units are supported", n + 1))),
+			_ => {},
+		    }
+		    continue;
+		}
+	    };
+
+	    partitions.push(parse_sfdisk_partition(fields).map_err(|msg| err(format!("line {}: {msg}", n + 1)))?);
+	}
+
+	let last_usable_lba = last_usable_lba.ok_or(err(String::from("missing last-lba")))?;
+	let array_blocks = array_blocks(max_partitions, sector_size as usize);
+	Ok(Self {
+	    disk_guid: disk_guid.unwrap_or_else(crate::guid::new_v4),
+	    sector_size,
+	    // After the protective MBR, primary header and array.
+	    first_usable_lba: first_usable_lba.unwrap_or(2 + array_blocks),
+	    last_usable_lba,
+	    // Backup partition entry array and header follow the last usable block.
+	    last_lba: last_usable_lba + array_blocks + 1,
+	    max_partitions,
+	    partitions,
+	})
+    }
+}
+
+fn default_max_partitions() -> u32 {
+    MIN_PARTITION_ENTRIES
+}
+
+fn parse_guid(s: &str) -> Result<Guid, ()> {
+    s.parse::<Guid>().map_err(|_| ())
+}
+
+fn sfdisk_attrs(attributes: u64) -> String {
+    let mut names: Vec<String> = SFDISK_ATTRS.iter()
+	.filter(|(bit, _)| attributes & (1 << bit) != 0)
+	.map(|(_, name)| String::from(*name))
+	.collect();
+    names.extend((48..64).filter(|bit| attributes & (1 << bit) != 0).map(|bit| format!("GUID:{bit}")));
+    names.join(" ")
+}
+
+fn parse_sfdisk_attrs(s: &str) -> Result<u64, String> {
+    let mut attributes = 0;
+    for attr in s.split([' ', ',']).filter(|a| !a.is_empty()) {
+	if let Some((bit, _)) = SFDISK_ATTRS.iter().find(|(_, name)| *name == attr) {
+	    attributes |= 1 << bit;
+	} else if let Some(bit) = attr.strip_prefix("GUID:").and_then(|b| b.parse::<u32>().ok()).filter(|b| (48..64).contains(b)) {
+	    attributes |= 1 << bit;
+	} else {
+	    return Err(format!("unknown attribute {attr}"));
+	}
+    }
+    Ok(attributes)
+}
+
+/// Split an sfdisk partition line into key=value pairs, respecting quoted values.
+fn sfdisk_fields(s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut fields = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+	while chars.peek().is_some_and(|c| c.is_whitespace() || *c == ',') {
+	    chars.next();
+	}
+	if chars.peek().is_none() {
+	    return Ok(fields);
+	}
+
+	let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+	while chars.peek().is_some_and(|c| c.is_whitespace()) {
+	    chars.next();
+	}
+
+	let mut value = String::new();
+	if chars.peek() == Some(&'"') {
+	    chars.next();
+	    loop {
+		match chars.next() {
+		    Some('\\') => value.extend(chars.next()),
+		    Some('"') => break,
+		    Some(c) => value.push(c),
+		    None => return Err(String::from("unterminated quote")),
+		}
+	    }
+	} else {
+	    while let Some(c) = chars.peek().copied() {
+		if c == ',' {
+		    break;
+		}
+		value.push(c);
+		chars.next();
+	    }
+	}
+
+	fields.push((String::from(key.trim()), String::from(value.trim())));
+    }
+}
+
+fn parse_sfdisk_partition(s: &str) -> Result<LayoutPartition, String> {
+    let mut start = None;
+    let mut size = None;
+    let mut type_guid = None;
+    let mut unique_guid = None;
+    let mut name = String::new();
+    let mut attributes = 0;
+
+    for (key, value) in sfdisk_fields(s)? {
+	let bad = || format!("bad {key} value {value}");
+	match key.as_str() {
+	    "start" => start = Some(value.parse::<u64>().map_err(|_| bad())?),
+	    "size" => size = Some(value.parse::<u64>().map_err(|_| bad())?),
+	    "type" => type_guid = Some(parse_guid(&value).map_err(|_| bad())?),
+	    "uuid" => unique_guid = Some(parse_guid(&value).map_err(|_| bad())?),
+	    "name" => name = value,
+	    "attrs" => attributes = parse_sfdisk_attrs(&value)?,
+	    _ => return Err(format!("unsupported field {key}")),
+	}
+    }
+
+    let first_lba = start.ok_or(String::from("missing start"))?;
+    let size = size.filter(|s| *s > 0).ok_or(String::from("missing size"))?;
+    Ok(LayoutPartition {
+	type_guid: type_guid.ok_or(String::from("missing type"))?,
+	unique_guid: unique_guid.unwrap_or_else(crate::guid::new_v4),
+	first_lba,
+	last_lba: first_lba + size - 1,
+	attributes,
+	name,
+    })
+}
+
+/// (De)serialize GUIDs as strings rather than their fields.
+mod guid_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use crate::guid::Guid;
+
+    pub fn serialize<S: Serializer>(guid: &Guid, s: S) -> Result<S::Ok, S::Error> {
+	s.collect_str(guid)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Guid, D::Error> {
+	let s = String::deserialize(d)?;
+	s.parse().map_err(|_| D::Error::custom(format!("invalid GUID {s}")))
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn layout() -> TableLayout {
+	TableLayout {
+	    disk_guid: "01234567-89AB-CDEF-0123-456789ABCDEF".parse().unwrap(),
+	    sector_size: 512,
+	    first_usable_lba: 34,
+	    last_usable_lba: 93716,
+	    last_lba: 93749,
+	    max_partitions: 128,
+	    partitions: vec![
+		LayoutPartition {
+		    type_guid: "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".parse().unwrap(),
+		    unique_guid: "A1B2C3D4-0000-4000-8000-000000000001".parse().unwrap(),
+		    first_lba: 2048,
+		    last_lba: 4095,
+		    attributes: 1 | 1 << 60,
+		    name: String::from("EFI \"system\" partition"),
+		},
+	    ],
+	}
+    }
+
+    #[test]
+    fn json_round_trip() {
+	let l = layout();
+	let s = l.export(TableFormat::Json);
+	assert_eq!(TableLayout::import(&s, None).unwrap(), l);
+    }
+
+    #[test]
+    fn sfdisk_round_trip() {
+	let l = layout();
+	let s = l.export(TableFormat::Sfdisk);
+	assert!(s.contains("start=2048, size=2048, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+	assert!(s.contains("attrs=\"RequiredPartition GUID:60\""));
+	assert_eq!(TableLayout::import(&s, None).unwrap(), l);
+    }
+
+    #[test]
+    fn sfdisk_dump_with_device_names() {
+	let dump = "label: gpt\n\
+		    label-id: 01234567-89AB-CDEF-0123-456789ABCDEF\n\
+		    device: disk.img\n\
+		    unit: sectors\n\
+		    first-lba: 34\n\
+		    last-lba: 93716\n\
+		    \n\
+		    disk.img1 : start=        2048, size=        2048, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=A1B2C3D4-0000-4000-8000-000000000001, name=\"EFI\"\n";
+	let l = TableLayout::import(dump, Some(TableFormat::Sfdisk)).unwrap();
+	assert_eq!(l.partitions.len(), 1);
+	assert_eq!(l.partitions[0].first_lba, 2048);
+	assert_eq!(l.partitions[0].last_lba, 4095);
+	assert_eq!(l.partitions[0].name, "EFI");
+	assert_eq!(l.last_lba, 93749);
+    }
+
+    #[test]
+    fn sfdisk_rejects_mbr() {
+	assert!(TableLayout::import("label: dos\nlast-lba: 100\n", Some(TableFormat::Sfdisk)).is_err());
+    }
+}
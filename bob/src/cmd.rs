@@ -1,10 +1,377 @@
 use clap::ArgMatches;
 
-use crate::err::BobErr;
-use crate::gpt::{DiskImgBuilder, PartitionInput, GptImage, PartitionType};
+use bob_core::err::BobErr;
+use bob_core::gpt::{copy_partition, human_size, write_partition_bytes, DiskImage, Partition, DiskImgBuilder, ImagePlan, PartitionInput, GptImage, PartitionTable, PartitionType, DEFAULT_ALIGNMENT};
+use bob_core::guid::Guid;
+use bob_core::path::host_path;
+use bob_core::sink::{IoBackend, ZeroMode, QUICK_ZERO_BYTES};
+use bob_core::table::{TableFormat, TableLayout};
+use crate::incremental::{Build, BuildRecord};
+use crate::manifest::{fill_partition, ArtifactCache, Contents, Layout, Manifest, TargetPlan};
+use crate::serve::ServeConfig;
+use crate::verity::HashTree;
+use crate::watch::{affected, Changes, Image, Qemu, Spec, Watcher};
 
-/// Creates a disk image from the provided argument matches.
-pub fn create_disk_image(create_matches: &ArgMatches) -> Result<GptImage, BobErr> {
+/// Creates a disk image from the provided argument matches or --config layout, formats
+/// its EFI system partition if it has one and copies in the layout's partition contents.
+pub fn create_disk_image(create_matches: &ArgMatches) -> Result<(), BobErr> {
+    check_threads(create_matches)?;
+    let (builder, contents) = image_builder(create_matches)?;
+    let plan = builder.plan()?;
+    let path = plan.path().clone();
+    let device = check_device(create_matches, &plan)?;
+    if device.is_some() && !create_matches.get_flag("yes-i-know") {
+	return Err(BobErr::BlockDevice(format!("{} is a block device and everything on it will be erased, pass --yes-i-know to go ahead", path.display())));
+    }
+    let format = create_matches.get_one::<String>("format").map(String::as_str);
+    if plan.table() == PartitionTable::Mbr && matches!(format, Some("iso" | "iso-hybrid")) {
+	return Err(BobErr::InvalidMbr(String::from("an ISO is made from the ESP of a GPT image")));
+    }
+    if matches!(format, Some("iso" | "iso-hybrid")) && plan.partitions_of_type(PartitionType::EFISystem).is_empty() {
+	return Err(BobErr::NoEFISystemPartition);
+    }
+    let mut cache = ArtifactCache::new()?;
+    let backend = io_backend(create_matches);
+    match plan.table() {
+	PartitionTable::Gpt => fill_image(&mut plan.write()?, backend, &contents, &mut cache)?,
+	PartitionTable::Mbr => fill_image(&mut plan.write_mbr()?, backend, &contents, &mut cache)?,
+    }
+    match format {
+	Some("qcow2") => {
+	    let stats = crate::qcow2::convert_in_place(&path)?;
+	    println!("Wrote {} as qcow2, {} ({} data clusters)", path.display(), human_size(stats.file_size), stats.data_clusters);
+	},
+	Some("vhd") => {
+	    let (uuid, timestamp) = crate::vhd::identity(seed(create_matches));
+	    let mut f = std::fs::OpenOptions::new().read(true).write(true).open(&path).map_err(BobErr::IO)?;
+	    let size = crate::vhd::append_footer(&mut f, uuid, timestamp)?;
+	    if !size.is_multiple_of(1024 * 1024) {
+		tracing::warn!(size, "VHD size isn't a whole number of MiB, Azure won't take it");
+	    }
+	    println!("Wrote {} as a fixed VHD of {}", path.display(), human_size(size));
+	},
+	Some("vmdk") => {
+	    let stats = crate::vmdk::convert_to_flat(&path, crate::vmdk::content_id(seed(create_matches)))?;
+	    println!("Wrote {} as a VMDK descriptor for {}, {}", path.display(), crate::vmdk::flat_extent_path(&path).display(), human_size(stats.file_size));
+	},
+	Some("vmdk-stream") => {
+	    let (start, size) = (std::time::Instant::now(), std::fs::metadata(&path).map_err(BobErr::IO)?.len());
+	    let threads = threads(create_matches);
+	    let stats = crate::vmdk::convert_to_stream(&path, crate::vmdk::content_id(seed(create_matches)), threads)?;
+	    let secs = start.elapsed().as_secs_f64();
+	    println!("Wrote {} as a streamOptimized VMDK, {} ({} data grains)", path.display(), human_size(stats.file_size), stats.data_grains.unwrap_or(0));
+	    let unit = if threads == 1 { "thread" } else { "threads" };
+	    println!("Compressed {} in {secs:.1}s on {threads} {unit}, {}/s", human_size(size), human_size((size as f64 / secs.max(1e-3)) as u64));
+	},
+	Some(format @ ("iso" | "iso-hybrid")) => {
+	    let stats = crate::iso::convert_in_place(&path, format == "iso-hybrid")?;
+	    println!("Wrote {} as an ISO, {} (ESP {})", path.display(), human_size(stats.file_size), human_size(stats.esp_size));
+	},
+	_ => {},
+    }
+    if device.is_some() {
+	bob_core::sink::sync_device(&path)?;
+	println!("Wrote and synced {}, it can be removed", path.display());
+    } else {
+	record_versions(&path, &contents)?;
+    }
+    Ok(())
+}
+
+/// Writes the builds found in `contents` to the image's versions record, next to it. With
+/// none found there's nothing to record, and a record from an earlier build of the image
+/// would be wrong, so it goes.
+fn record_versions(image: &std::path::Path, contents: &[(String, Contents)]) -> Result<(), BobErr> {
+    let found = crate::versions::scan(contents)?;
+    if found.is_empty() {
+	match std::fs::remove_file(crate::versions::versions_path(image)) {
+	    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(BobErr::IO(e)),
+	    _ => return Ok(()),
+	}
+    }
+    let path = crate::versions::write_versions(image, &found)?;
+    println!("Recorded {} component builds in {}", found.len(), path.display());
+    Ok(())
+}
+
+/// Checks a plan whose output is a block device: it has to be big enough, not mounted,
+/// and get a raw image. Returns the device's size, None if the output isn't a device.
+fn check_device(create_matches: &ArgMatches, plan: &ImagePlan) -> Result<Option<u64>, BobErr> {
+    let Some(device_size) = bob_core::sink::device_size(plan.path()) else {
+	return Ok(None);
+    };
+    let device = plan.path().display();
+    if plan.image_size() as u64 > device_size {
+	return Err(BobErr::BlockDevice(format!("{device} holds {} but the image is {}", human_size(device_size), human_size(plan.image_size() as u64))));
+    }
+    let mounts = bob_core::sink::mount_points(plan.path());
+    if !mounts.is_empty() {
+	return Err(BobErr::BlockDevice(format!("{device} is mounted on {}, unmount it first", mounts.join(", "))));
+    }
+    match create_matches.get_one::<String>("format").map(String::as_str) {
+	Some("raw") | None => Ok(Some(device_size)),
+	Some(format) => Err(BobErr::BlockDevice(format!("--format {format} is a file format, {device} can only be written raw"))),
+    }
+}
+
+/// Only vmdk-stream output is compressed on a pool of threads, with any other format
+/// `--threads` would be silently ignored.
+fn check_threads(create_matches: &ArgMatches) -> Result<(), BobErr> {
+    let format = create_matches.get_one::<String>("format").map_or("raw", String::as_str);
+    if create_matches.contains_id("threads") && format != "vmdk-stream" {
+	return Err(BobErr::InvalidArgument(format!("--threads only applies to --format vmdk-stream, not {format}")));
+    }
+    Ok(())
+}
+
+/// `--threads`, or one per CPU.
+fn threads(create_matches: &ArgMatches) -> usize {
+    create_matches.get_one::<usize>("threads").copied().unwrap_or_else(crate::pipeline::default_threads).max(1)
+}
+
+/// `--seed`, or 0 for `--deterministic`.
+fn seed(create_matches: &ArgMatches) -> Option<u64> {
+    create_matches.get_one::<u64>("seed").copied()
+	.or(create_matches.get_flag("deterministic").then_some(0))
+}
+
+/// Validates and prints the disk image layout `create` would write, without writing it.
+pub fn plan_disk_image(create_matches: &ArgMatches) -> Result<(), BobErr> {
+    check_threads(create_matches)?;
+    let (builder, contents) = image_builder(create_matches)?;
+    let plan = builder.plan()?;
+    print!("{}", plan.describe());
+
+    println!("\nActions:");
+    match check_device(create_matches, &plan)? {
+	Some(size) => println!("    erase block device {} ({}) and write the image to it", plan.path().display(), human_size(size)),
+	None => println!("    create {}", plan.path().display()),
+    }
+    match create_matches.get_one::<String>("format").map(String::as_str) {
+	Some("qcow2") => println!("    convert it to qcow2"),
+	Some("vhd") => println!("    append a fixed VHD footer"),
+	Some("vmdk") => println!("    move it to {} and write a VMDK descriptor in its place", crate::vmdk::flat_extent_path(plan.path()).display()),
+	Some("vmdk-stream") => println!("    convert it to a streamOptimized VMDK, compressing on {} threads", threads(create_matches)),
+	_ => {},
+    }
+    if plan.table() == PartitionTable::Mbr {
+	println!("    write an MBR partition table, without any GPT structures");
+    }
+    match plan.zero() {
+	Some(ZeroMode::Full) => println!("    zero every partition"),
+	Some(ZeroMode::Quick) => println!("    zero the first and last {} of every partition", human_size(QUICK_ZERO_BYTES)),
+	None => {},
+    }
+    if !plan.hybrid_mbr().is_empty() {
+	let numbers: Vec<_> = plan.hybrid_mbr().iter().map(|i| (i + 1).to_string()).collect();
+	println!("    write a hybrid MBR mirroring partitions {}", numbers.join(", "));
+    }
+    match plan.partitions_of_type(PartitionType::EFISystem).first() {
+	Some(name) => println!("    format '{name}' as FAT32"),
+	None => println!("    leave the partitions unformatted, there is no EFI system partition"),
+    }
+    for (name, contents) in &contents {
+	println!("    fill '{name}' with {contents:?}");
+    }
+    Ok(())
+}
+
+/// Builds every target of a manifest, or prints their layouts with --dry-run. A target
+/// built before from the same layout only gets the partitions whose contents changed
+/// refilled, unless it's --full.
+pub fn create_from_manifest(create_matches: &ArgMatches) -> Result<(), BobErr> {
+    let path = host_path(create_matches.get_one::<String>("manifest").ok_or(BobErr::MissingArgument)?);
+    let manifest = Manifest::load(&path)?;
+    let base = path.parent().unwrap_or(std::path::Path::new("."));
+    let zero = create_matches.get_one::<String>("zero-partitions").and_then(|m| ZeroMode::from_name(m));
+    let dry_run = create_matches.get_flag("dry-run");
+    let full = create_matches.get_flag("full");
+    let backend = io_backend(create_matches);
+
+    let mut cache = ArtifactCache::new()?;
+    for target in manifest.targets(base)? {
+	let record = BuildRecord::new(&target)?;
+	let builder = match zero {
+	    Some(mode) => target.builder.zero_partitions(mode),
+	    None => target.builder,
+	};
+	let plan = builder.plan()?;
+	// Zeroing is a fresh start, and an MBR image can't be reopened to refill.
+	let build = match (full, zero, plan.table()) {
+	    (false, None, PartitionTable::Gpt) => record.plan(&target.output),
+	    _ => Build::Full,
+	};
+	let refills = |names: &[String]| target.contents.iter().filter(|(n, _)| names.contains(n)).collect::<Vec<_>>();
+	if dry_run {
+	    print!("{}", plan.describe());
+	    match &build {
+		Build::Full => for (name, contents) in &target.contents {
+		    println!("    fill '{name}' with {contents:?}");
+		},
+		Build::Refill(names) if names.is_empty() => println!("    up to date, nothing to write"),
+		Build::Refill(names) => for (name, contents) in refills(names) {
+		    println!("    refill '{name}' with {contents:?}");
+		},
+	    }
+	    println!();
+	    continue;
+	}
+
+	match &build {
+	    Build::Full => {
+		match plan.table() {
+		    PartitionTable::Gpt => fill_image(&mut plan.write()?, backend, &target.contents, &mut cache)?,
+		    PartitionTable::Mbr => fill_image(&mut plan.write_mbr()?, backend, &target.contents, &mut cache)?,
+		}
+		println!("Built {}", target.output.display());
+	    },
+	    Build::Refill(names) if names.is_empty() => println!("{} is up to date", target.output.display()),
+	    Build::Refill(names) => for (name, c) in refills(names) {
+		let len = refill(&target.output, name, c, backend, &mut cache)?;
+		println!("Refilled {name} in {} ({})", target.output.display(), human_size(len));
+	    },
+	}
+	record_versions(&target.output, &target.contents)?;
+	record.save(&target.output)?;
+    }
+    Ok(())
+}
+
+/// Builds a watched manifest's or layout's images from scratch.
+pub fn build_images(targets: Vec<TargetPlan>, backend: IoBackend, cache: &mut ArtifactCache) -> Result<Vec<Image>, BobErr> {
+    let mut images = Vec::new();
+    for target in targets {
+	let plan = target.builder.plan()?;
+	let table = plan.table();
+	match table {
+	    PartitionTable::Gpt => fill_image(&mut plan.write()?, backend, &target.contents, cache)?,
+	    PartitionTable::Mbr => fill_image(&mut plan.write_mbr()?, backend, &target.contents, cache)?,
+	}
+	println!("Built {}", target.output.display());
+	images.push(Image { output: target.output, table, contents: target.contents });
+    }
+    Ok(images)
+}
+
+/// Brings watched images up to date with `changes`: partitions built from a changed
+/// source are refilled, a changed manifest rebuilds everything. An MBR image can't be
+/// reopened to refill, so it's rebuilt too.
+pub fn rebuild(watcher: &mut Watcher, images: &mut Vec<Image>, changes: &Changes, backend: IoBackend, cache: &mut ArtifactCache) -> Result<(), BobErr> {
+    for c in &changes.contents {
+	cache.forget(c);
+    }
+    let refills = affected(images, &changes.contents);
+    if changes.spec || refills.iter().any(|(img, _, _)| img.table == PartitionTable::Mbr) {
+	*images = build_images(watcher.spec().targets()?, backend, cache)?;
+	watcher.reset(images);
+	return Ok(());
+    }
+    for (img, name, c) in refills {
+	let len = refill(&img.output, name, c, backend, cache)?;
+	println!("Refilled {name} in {} ({})", img.output.display(), human_size(len));
+    }
+    Ok(())
+}
+
+/// Copies `contents` over partition `name` of the GPT image at `output`, built before.
+/// Returns the bytes copied.
+fn refill(output: &std::path::Path, name: &str, contents: &Contents, backend: IoBackend, cache: &mut ArtifactCache) -> Result<u64, BobErr> {
+    let mut gpt = GptImage::open(&output.to_string_lossy())?;
+    gpt.set_io_backend(backend)?;
+    let src = cache.get(contents)?;
+    let mut p = gpt.get_partition_view(name).ok_or_else(|| BobErr::PartitionNotFound(String::from(name)))?;
+    fill_partition(&mut p, &src)
+}
+
+/// Builds the images of a manifest or layout, then rebuilds what changes as their
+/// partitions' sources do, restarting --qemu after each rebuild. Runs until interrupted.
+pub fn watch(watch_matches: &ArgMatches) -> Result<(), BobErr> {
+    let spec = match (watch_matches.get_one::<String>("manifest"), watch_matches.get_one::<String>("config")) {
+	(Some(m), _) => Spec::Manifest(host_path(m)),
+	(None, Some(c)) => Spec::Layout(host_path(c)),
+	(None, None) => return Err(BobErr::MissingArgument),
+    };
+    let interval = std::time::Duration::from_millis(watch_matches.get_one::<u64>("interval").copied().unwrap_or(500));
+    let backend = io_backend(watch_matches);
+    let mut qemu = watch_matches.get_one::<String>("qemu").map(|c| Qemu::new(c));
+
+    let mut cache = ArtifactCache::new()?;
+    let mut images = build_images(spec.targets()?, backend, &mut cache)?;
+    let mut watcher = Watcher::new(spec, &images);
+    if let Some(q) = &mut qemu {
+	println!("Started QEMU (pid {})", q.restart()?);
+    }
+    println!("Watching {} and the partition contents, Ctrl-C to stop", watcher.spec().path().display());
+    loop {
+	std::thread::sleep(interval);
+	let mut changes = watcher.poll();
+	if changes.is_empty() {
+	    continue;
+	}
+	// Whatever set it off may still be writing, wait for a quiet poll.
+	loop {
+	    std::thread::sleep(interval);
+	    let more = watcher.poll();
+	    if more.is_empty() {
+		break;
+	    }
+	    changes.merge(more);
+	}
+
+	let start = std::time::Instant::now();
+	if let Err(e) = rebuild(&mut watcher, &mut images, &changes, backend, &mut cache) {
+	    // Likely a half edited manifest or source, the next change gets another go.
+	    eprintln!("Rebuild failed: {e:?}");
+	    continue;
+	}
+	println!("Rebuilt in {:.1}s", start.elapsed().as_secs_f64());
+	if let Some(q) = &mut qemu {
+	    println!("Restarted QEMU (pid {})", q.restart()?);
+	}
+    }
+}
+
+/// Formats the ESP of a newly built image, if it has one, and copies in the partition
+/// contents, through `backend`.
+fn fill_image(img: &mut impl DiskImage, backend: IoBackend, contents: &[(String, Contents)], cache: &mut ArtifactCache) -> Result<(), BobErr> {
+    img.set_io_backend(backend)?;
+    if !write_fat_fs(img)? {
+	tracing::debug!("no EFI system partition to format");
+    }
+    for (name, c) in contents {
+	let src = cache.get(c)?;
+	let mut p = img.get_partition_view(name).ok_or_else(|| BobErr::PartitionNotFound(name.clone()))?;
+	fill_partition(&mut p, &src)?;
+    }
+    Ok(())
+}
+
+/// The image `create` builds, from the --config layout if there is one, and the partition
+/// contents to copy in once it's written.
+fn image_builder(create_matches: &ArgMatches) -> Result<(DiskImgBuilder, Vec<(String, Contents)>), BobErr> {
+    let Some(config) = create_matches.get_one::<String>("config") else {
+	return Ok((disk_image_builder(create_matches), Vec::new()));
+    };
+    let path = host_path(config);
+    // -o is relative to where bob runs, not to the layout.
+    let output = match create_matches.get_one::<String>("output") {
+	Some(o) => Some(std::path::absolute(host_path(o)).map_err(BobErr::IO)?.to_string_lossy().into_owned()),
+	None => None,
+    };
+    let target = Layout::load(&path)?.target(output, path.parent().unwrap_or(std::path::Path::new(".")))?;
+    let builder = match create_matches.get_one::<String>("zero-partitions").and_then(|m| ZeroMode::from_name(m)) {
+	Some(mode) => target.builder.zero_partitions(mode),
+	None => target.builder,
+    };
+    Ok((builder, target.contents))
+}
+
+/// The --io-backend argument, reading and writing the file unless it's mmap.
+fn io_backend(matches: &ArgMatches) -> IoBackend {
+    matches.get_one::<String>("io-backend").and_then(|b| IoBackend::from_name(b)).unwrap_or(IoBackend::File)
+}
+
+fn disk_image_builder(create_matches: &ArgMatches) -> DiskImgBuilder {
     let mut img_builder = DiskImgBuilder::new();
 
     if let Some(output_filename) = create_matches.get_one::<String>("output") {
@@ -15,22 +382,862 @@ pub fn create_disk_image(create_matches: &ArgMatches) -> Result<GptImage, BobErr
         img_builder = img_builder.total_size(*size);
     }
 
+    if let Some(align) = create_matches.get_one::<usize>("align") {
+	img_builder = img_builder.alignment(*align);
+    }
+
+    if let Some(sector_size) = create_matches.get_one::<usize>("sector-size") {
+	img_builder = img_builder.sector_size(*sector_size);
+    }
+
+    if let Some(table) = create_matches.get_one::<String>("table").and_then(|t| PartitionTable::from_name(t)) {
+	img_builder = img_builder.table(table);
+    }
+
+    if let Some(n) = create_matches.get_one::<u32>("max-partitions") {
+	img_builder = img_builder.max_partitions(*n);
+    }
+
+    if let Some(mirrored) = create_matches.get_many::<u64>("hybrid-mbr") {
+	img_builder = img_builder.hybrid_mbr(mirrored.map(|n| *n as usize - 1).collect());
+    }
+
+    if let Some(mode) = create_matches.get_one::<String>("zero-partitions").and_then(|m| ZeroMode::from_name(m)) {
+	img_builder = img_builder.zero_partitions(mode);
+    }
+
+    if let Some(seed) = seed(create_matches) {
+	img_builder = img_builder.seed(seed);
+    }
+
+    if let Some(guid) = create_matches.get_one::<Guid>("disk-guid") {
+	img_builder = img_builder.disk_guid(*guid);
+    }
+
     if let Some(partitions) = create_matches.get_many::<PartitionInput>("partition") {
 	for p in partitions {
-	    img_builder = img_builder.partition(*p);
+	    img_builder = img_builder.partition(p.clone());
 	}
     }
 
-    img_builder.build()
+    img_builder
+}
+
+/// Adds the given partitions to an existing image.
+pub fn add_partition(add_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = add_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let alignment = add_matches.get_one::<usize>("align").copied().unwrap_or(DEFAULT_ALIGNMENT);
+    let mut img = GptImage::open(image)?;
+    for p in add_matches.get_many::<PartitionInput>("partition").into_iter().flatten() {
+	let (index, first, last) = img.add_partition(p, alignment)?;
+	println!("Added partition {} at LBAs {first}-{last}", index + 1);
+    }
+    Ok(())
+}
+
+/// Removes a partition, picked by index, name or unique GUID, from an existing image.
+pub fn delete_partition(delete_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = delete_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let mut img = GptImage::open(image)?;
+    let index = selected_partition(delete_matches, &img)?;
+    let name = img.delete_partition(index, delete_matches.get_flag("wipe"))?;
+    println!("Deleted partition {} ({name})", index + 1);
+    Ok(())
+}
+
+/// Grows or shrinks a partition of an existing image, after moving the backup table to the
+/// end of the file if it has been extended.
+pub fn resize_partition(resize_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = resize_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let mut img = GptImage::open(image)?;
+    if img.grow_to_file()? {
+	println!("Moved the backup partition table to the end of {image}");
+    }
+    let index = selected_partition(resize_matches, &img)?;
+    let size = resize_matches.get_one::<usize>("size").copied();
+    let end = img.resize_partition(index, size)?;
+    println!("Partition {} now ends at LBA {end}", index + 1);
+    Ok(())
+}
+
+/// Zeroes a partition of an existing image, leaving the table as it is.
+pub fn wipe_partition(wipe_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = wipe_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let mode = if wipe_matches.get_flag("quick") { ZeroMode::Quick } else { ZeroMode::Full };
+    let mut img = GptImage::open(image)?;
+    let index = selected_partition(wipe_matches, &img)?;
+    let zeroed = img.wipe_partition(index, mode)?;
+    println!("Wiped {} of partition {}", human_size(zeroed), index + 1);
+    Ok(())
+}
+
+/// Copies a file into a partition, picked by index, name or unique GUID, of an existing
+/// image.
+pub fn write_partition(write_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = write_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let input = write_matches.get_one::<String>("input").ok_or(BobErr::MissingArgument)?;
+    let offset = write_matches.get_one::<usize>("offset").copied().unwrap_or(0) as u64;
+    let mut img = GptImage::open(image)?;
+    img.set_io_backend(io_backend(write_matches))?;
+    let index = selected_partition(write_matches, &img)?;
+
+    let mut f = std::fs::File::open(host_path(input)).map_err(BobErr::IO)?;
+    let len = f.metadata().map_err(BobErr::IO)?.len();
+    let mut p = img.partition_view(index).ok_or(BobErr::PartitionNotFound(format!("#{}", index + 1)))?;
+    let capacity = p.sectors() * p.sector_size() as u64;
+    if offset + len > capacity {
+	eprintln!("{input} is {} and the partition {}, from offset {offset} it doesn't fit", human_size(len), human_size(capacity));
+	return Err(BobErr::PartitionOutOfBounds);
+    }
+    write_partition_bytes(&mut p, offset, len, &mut f)?;
+    println!("Wrote {} to partition {} at offset {offset}", human_size(len), index + 1);
+    Ok(())
+}
+
+/// Copies a partition of one image into a partition of another (or the same) image.
+/// Both are given as IMAGE:PARTITION, the partition by number or name.
+pub fn clone_partition(clone_matches: &ArgMatches) -> Result<(), BobErr> {
+    let from = clone_matches.get_one::<String>("from").ok_or(BobErr::MissingArgument)?;
+    let to = clone_matches.get_one::<String>("to").ok_or(BobErr::MissingArgument)?;
+    let (src_image, src_part) = split_partition_spec(from)?;
+    let (dst_image, dst_part) = split_partition_spec(to)?;
+
+    let mut src = GptImage::open_read_only(src_image)?;
+    let src_index = partition_by_spec(&src, src_part)?;
+    let mut dst = GptImage::open(dst_image)?;
+    let dst_index = partition_by_spec(&dst, dst_part)?;
+
+    let mut s = src.partition_view(src_index).ok_or_else(|| BobErr::PartitionNotFound(String::from(from)))?;
+    let mut d = dst.partition_view(dst_index).ok_or_else(|| BobErr::PartitionNotFound(String::from(to)))?;
+    let (len, capacity) = (s.sectors() * s.sector_size() as u64, d.sectors() * d.sector_size() as u64);
+    if len > capacity {
+	eprintln!("{from} is {} but {to} only {}", human_size(len), human_size(capacity));
+	return Err(BobErr::PartitionOutOfBounds);
+    }
+    copy_partition(&mut s, &mut d)?;
+    println!("Copied {} from {from} to {to}", human_size(len));
+    Ok(())
+}
+
+/// Splits IMAGE:PARTITION at the last colon, so Windows drive letters survive.
+fn split_partition_spec(spec: &str) -> Result<(&str, &str), BobErr> {
+    spec.rsplit_once(':')
+	.filter(|(image, part)| !image.is_empty() && !part.is_empty())
+	.ok_or_else(|| BobErr::PartitionNotFound(format!("{spec} (expected IMAGE:PARTITION)")))
+}
+
+/// The index of a partition given by number (counting from 1) or name.
+fn partition_by_spec(img: &GptImage, part: &str) -> Result<usize, BobErr> {
+    let index = match part.parse::<usize>() {
+	Ok(n) => n.checked_sub(1).filter(|i| *i < img.partition_count()),
+	Err(_) => img.find_by_name(part),
+    };
+    index.ok_or_else(|| BobErr::PartitionNotFound(String::from(part)))
+}
+
+/// Copies a partition, picked by index, name or unique GUID, out of an image to a file.
+pub fn extract_partition(extract_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = extract_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let output = extract_matches.get_one::<String>("output").ok_or(BobErr::MissingArgument)?;
+    let mut img = GptImage::open_read_only(image)?;
+    let index = selected_partition(extract_matches, &img)?;
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(host_path(output)).map_err(BobErr::IO)?);
+    let len = img.extract_partition(index, &mut out)?;
+    std::io::Write::flush(&mut out).map_err(BobErr::IO)?;
+    println!("Extracted partition {} ({}) to {output}", index + 1, human_size(len));
+    Ok(())
 }
 
-/// Writes FAT filesystem to the EFI system partition on the GPT disc image.
-pub fn write_fat_fs(gpt: &mut GptImage) -> Result<(), BobErr> {
-    let mut efi_system_partition = if let Some(p) = gpt.get_partition_view(&PartitionType::EFISystem.name()) {
-	p
+/// The index of the partition picked by the --partition (number or name), --index
+/// (counting from 1), --name or --guid argument.
+fn selected_partition(matches: &ArgMatches, img: &GptImage) -> Result<usize, BobErr> {
+    // Not every subcommand has --partition.
+    if let Some(part) = matches.try_get_one::<String>("partition").ok().flatten() {
+	partition_by_spec(img, part)
+    } else if let Some(i) = matches.get_one::<usize>("index") {
+	i.checked_sub(1)
+	    .filter(|i| *i < img.partition_count())
+	    .ok_or_else(|| BobErr::PartitionNotFound(format!("#{i}")))
+    } else if let Some(name) = matches.get_one::<String>("name") {
+	img.find_by_name(name).ok_or_else(|| BobErr::PartitionNotFound(name.clone()))
+    } else if let Some(guid) = matches.get_one::<Guid>("guid") {
+	img.find_by_guid(*guid).ok_or_else(|| BobErr::PartitionNotFound(guid.to_string()))
     } else {
-	return Err(BobErr::NoEFISystemPartition);
+	Err(BobErr::MissingArgument)
+    }
+}
+
+/// Writes FAT filesystem to the EFI system partition on the disc image. False if the
+/// image has no EFI system partition, which is left as it is.
+pub fn write_fat_fs(img: &mut impl DiskImage) -> Result<bool, BobErr> {
+    let Some(name) = img.partitions_of_type(PartitionType::EFISystem).into_iter().next() else {
+	return Ok(false);
     };
+    let mut efi_system_partition = img.get_partition_view(&name).ok_or(BobErr::NoEFISystemPartition)?;
+    bob_core::fat::format_as_fat(&mut efi_system_partition)?;
+    Ok(true)
+}
+
+/// Reads an existing image's partition table and writes it back out, both copies with
+/// fresh CRCs. With --dry-run only prints what that would change.
+pub fn update_disk_image(update_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = update_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    if update_matches.get_flag("dry-run") {
+	let img = GptImage::open_read_only(image)?;
+	print!("{}", img.inspect());
+	let changes = img.table_changes()?;
+	if changes.is_empty() {
+	    println!("\nThe partition table of {image} is up to date, nothing to write");
+	} else {
+	    println!("\nActions:");
+	    for change in changes {
+		println!("    {change}");
+	    }
+	}
+	return Ok(());
+    }
+    let mut img = GptImage::open(image)?;
+    img.write_tables()?;
+    println!("Rewrote the partition table of {image} ({} partitions)", img.partition_count());
+    Ok(())
+}
 
-    crate::fat::format_as_fat(&mut efi_system_partition)
+/// Prints the GPT headers and partition entries of an existing image.
+pub fn inspect(inspect_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = inspect_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let img = GptImage::open_read_only(image)?;
+    if inspect_matches.get_flag("json") {
+	print!("{}", img.inspect_json());
+    } else {
+	print!("{}", img.inspect());
+    }
+    Ok(())
+}
+
+/// Checks an image's GPT metadata, exiting with status 1 if any check fails.
+pub fn verify(verify_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = verify_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let checks = bob_core::gpt::verify(image)?;
+
+    let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+    if verify_matches.get_flag("json") {
+	let report = serde_json::json!({ "image": image, "passed": failed == 0, "checks": checks });
+	println!("{}", serde_json::to_string_pretty(&report).expect("checks to serialize"));
+	if failed > 0 {
+	    std::process::exit(1);
+	}
+	return Ok(());
+    }
+    for c in &checks {
+	match &c.problem {
+	    None => println!("ok      {}", c.name),
+	    Some(problem) => println!("FAILED  {}: {problem}", c.name),
+	}
+    }
+
+    if failed > 0 {
+	println!("\n{failed} of {} checks failed", checks.len());
+	std::process::exit(1);
+    }
+    println!("\nAll {} checks passed", checks.len());
+    Ok(())
+}
+
+/// Compares two images' headers and partition tables, exiting with status 1 if they
+/// differ.
+pub fn diff(diff_matches: &ArgMatches) -> Result<(), BobErr> {
+    let old = diff_matches.get_one::<String>("OLD").ok_or(BobErr::MissingArgument)?;
+    let new = diff_matches.get_one::<String>("NEW").ok_or(BobErr::MissingArgument)?;
+    let mut old_img = GptImage::open_read_only(old)?;
+    let mut new_img = GptImage::open_read_only(new)?;
+    let diffs = crate::diff::diff(&mut old_img, &mut new_img, diff_matches.get_flag("content"))?;
+
+    if diffs.is_empty() {
+	println!("No differences");
+	return Ok(());
+    }
+    println!("--- {old}\n+++ {new}");
+    for d in &diffs {
+	println!("{d}");
+    }
+    println!("\n{} differences", diffs.len());
+    std::process::exit(1);
+}
+
+/// Writes out the partition table of an existing image.
+pub fn export_table(export_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = export_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let format = export_matches.get_one::<String>("format")
+	.and_then(|f| TableFormat::from_name(f))
+	.ok_or(BobErr::MissingArgument)?;
+
+    let table = GptImage::open_read_only(image)?.layout().export(format);
+
+    if let Some(output) = export_matches.get_one::<String>("output") {
+	std::fs::write(host_path(output), table).map_err(BobErr::IO)
+    } else {
+	print!("{table}");
+	Ok(())
+    }
+}
+
+/// Creates a new disk image from an exported partition table.
+pub fn apply_table(apply_matches: &ArgMatches) -> Result<(), BobErr> {
+    let table = apply_matches.get_one::<String>("table").ok_or(BobErr::MissingArgument)?;
+    let format = apply_matches.get_one::<String>("format").and_then(|f| TableFormat::from_name(f));
+    let layout = TableLayout::import(&std::fs::read_to_string(host_path(table)).map_err(BobErr::IO)?, format)?;
+
+    let mut img_builder = DiskImgBuilder::new().layout(layout);
+
+    if let Some(output_filename) = apply_matches.get_one::<String>("output") {
+        img_builder = img_builder.output_file(output_filename);
+    }
+
+    if let Some(size) = apply_matches.get_one::<usize>("size") {
+        img_builder = img_builder.total_size(*size);
+    }
+
+    img_builder.build().map(|_| ())
+}
+
+/// Serves the bootloader and kernel for netbooting until interrupted.
+pub fn serve(serve_matches: &ArgMatches) -> Result<(), BobErr> {
+    let path = |name: &str| serve_matches.get_one::<String>(name).map(|p| host_path(p));
+
+    let cfg = ServeConfig {
+	addr: serve_matches.get_one::<String>("addr").cloned().ok_or(BobErr::MissingArgument)?,
+	tftp_port: *serve_matches.get_one::<u16>("tftp-port").ok_or(BobErr::MissingArgument)?,
+	http_port: *serve_matches.get_one::<u16>("http-port").ok_or(BobErr::MissingArgument)?,
+	bootloader: path("bootloader").ok_or(BobErr::MissingArgument)?,
+	kernel: path("kernel").ok_or(BobErr::MissingArgument)?,
+	config: path("config"),
+    };
+
+    crate::serve::serve(cfg)
+}
+
+/// Follows a kernel's console until it exits, exiting 1 if the run didn't pass.
+pub fn monitor(monitor_matches: &ArgMatches) -> Result<(), BobErr> {
+    let output = monitor_matches.get_one::<String>("output").map(|o| host_path(o));
+    let stdout = std::io::stdout();
+
+    let summary = if let Some(addr) = monitor_matches.get_one::<String>("listen") {
+	let listener = std::net::TcpListener::bind(addr).map_err(BobErr::IO)?;
+	println!("Waiting for the kernel on {addr}");
+	let (stream, peer) = listener.accept().map_err(BobErr::IO)?;
+	tracing::debug!(%peer, "monitor connection");
+	crate::monitor::monitor(stream, stdout, output.as_deref())?
+    } else {
+	let input = monitor_matches.get_one::<String>("input").ok_or(BobErr::MissingArgument)?;
+	if input == "-" {
+	    crate::monitor::monitor(std::io::stdin(), stdout, output.as_deref())?
+	} else {
+	    let f = std::fs::File::open(host_path(input)).map_err(BobErr::IO)?;
+	    crate::monitor::monitor(f, stdout, output.as_deref())?
+	}
+    };
+
+    if summary.exit.is_none() {
+	println!("The stream ended before the kernel exited, last boot stage: {}",
+		 summary.stage.map_or("none", |s| s.name()));
+    }
+    if !summary.success() {
+	std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Receives a snapshot from a serial device, console log or TCP connection.
+pub fn receive_snapshot(snapshot_matches: &ArgMatches) -> Result<(), BobErr> {
+    let output = snapshot_matches.get_one::<String>("output").ok_or(BobErr::MissingArgument)?;
+    let output = host_path(output);
+
+    let received = if let Some(addr) = snapshot_matches.get_one::<String>("listen") {
+	let listener = std::net::TcpListener::bind(addr).map_err(BobErr::IO)?;
+	println!("Waiting for the snapshot on {addr}");
+	let (stream, peer) = listener.accept().map_err(BobErr::IO)?;
+	tracing::debug!(%peer, "snapshot connection");
+	crate::snapshot::receive(std::io::BufReader::new(stream), &output)?
+    } else {
+	let input = snapshot_matches.get_one::<String>("input").ok_or(BobErr::MissingArgument)?;
+	let f = std::fs::File::open(host_path(input)).map_err(BobErr::IO)?;
+	crate::snapshot::receive(std::io::BufReader::new(f), &output)?
+    };
+    println!("Received {} files, {} into {}", received.files, human_size(received.bytes), output.display());
+    Ok(())
+}
+
+/// Folds the kernel profiler samples in a console log into symbolized stacks.
+pub fn flamegraph(flamegraph_matches: &ArgMatches) -> Result<(), BobErr> {
+    let input = flamegraph_matches.get_one::<String>("input").ok_or(BobErr::MissingArgument)?;
+    let kernel = flamegraph_matches.get_one::<String>("kernel").ok_or(BobErr::MissingArgument)?;
+
+    let symbols = crate::flamegraph::Symbols::from_elf(&std::fs::read(host_path(kernel)).map_err(BobErr::IO)?)?;
+    let folded = if input == "-" {
+	crate::flamegraph::fold(std::io::stdin().lock(), &symbols)?
+    } else {
+	let f = std::fs::File::open(host_path(input)).map_err(BobErr::IO)?;
+	crate::flamegraph::fold(std::io::BufReader::new(f), &symbols)?
+    };
+    match flamegraph_matches.get_one::<String>("output") {
+	Some(output) => {
+	    let mut out = std::io::BufWriter::new(std::fs::File::create(host_path(output)).map_err(BobErr::IO)?);
+	    folded.write(&mut out)?;
+	},
+	None => folded.write(&mut std::io::stdout().lock())?,
+    }
+
+    // The stacks may be going to stdout.
+    eprintln!("{} samples, {} distinct stacks, {} unresolved addresses", folded.samples, folded.stacks.len(), folded.unresolved);
+    if folded.samples == 0 {
+	tracing::warn!("no @prof lines in the input, was the profiler started and exported?");
+    }
+    Ok(())
+}
+
+/// Packs a host directory into a squashfs image.
+pub fn pack_squashfs(squashfs_matches: &ArgMatches) -> Result<(), BobErr> {
+    let dir = squashfs_matches.get_one::<String>("dir").ok_or(BobErr::MissingArgument)?;
+    let output = squashfs_matches.get_one::<String>("output").ok_or(BobErr::MissingArgument)?;
+
+    let timestamp = squashfs_matches.get_flag("deterministic").then(|| {
+	std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.parse().ok()).unwrap_or(0)
+    });
+    let stats = crate::squashfs::pack_dir(&host_path(dir), &host_path(output), timestamp)?;
+    println!("Packed {} inodes ({} fragments) into {output}, {}",
+	     stats.inodes, stats.fragments, human_size(stats.bytes_used));
+    Ok(())
+}
+
+/// Builds a verity hash tree over one partition of an image and writes it to another.
+pub fn verity(verity_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = verity_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let data = verity_matches.get_one::<String>("data").ok_or(BobErr::MissingArgument)?;
+    let hash = verity_matches.get_one::<String>("hash").ok_or(BobErr::MissingArgument)?;
+    let salt = match verity_matches.get_one::<Vec<u8>>("salt") {
+	Some(s) => s.clone(),
+	None => (0..crate::verity::DEFAULT_SALT_SZ).map(|_| rand::random()).collect(),
+    };
+
+    let mut img = GptImage::open(image)?;
+    let tree = {
+	let mut p = img.get_partition_view(data).ok_or_else(|| BobErr::PartitionNotFound(data.clone()))?;
+	HashTree::build(&mut p, &salt, bob_core::guid::new_v4().to_bytes())?
+    };
+    let mut p = img.get_partition_view(hash).ok_or_else(|| BobErr::PartitionNotFound(hash.clone()))?;
+    tree.write(&mut p)?;
+
+    let root = crate::hex::encode(tree.root());
+    println!("Data blocks: {}", tree.geometry().data_blocks);
+    println!("Hash blocks: {}", tree.geometry().hash_blocks());
+    println!("Salt: {}", crate::hex::encode(&salt));
+    println!("Root hash: {root}");
+    println!("Kernel command line: roothash={root}");
+    Ok(())
+}
+
+/// Formats a partition as an encrypted container, optionally encrypting a plain image
+/// into it.
+pub fn encrypt_partition(encrypt_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = encrypt_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let key_file = encrypt_matches.get_one::<String>("key-file").ok_or(BobErr::MissingArgument)?;
+    let iterations = encrypt_matches.get_one::<u32>("iterations").copied().unwrap_or(common::crypt::DEFAULT_ITERATIONS);
+
+    let key = std::fs::read(host_path(key_file)).map_err(BobErr::IO)?;
+    let passphrase = key.strip_suffix(b"\n").unwrap_or(&key);
+    // It reaches the kernel as a single command line argument.
+    if passphrase.is_empty() || passphrase.iter().any(|b| b.is_ascii_whitespace() || !b.is_ascii_graphic()) {
+	eprintln!("The passphrase in {key_file} must be printable ASCII without spaces");
+	return Err(BobErr::InvalidKey);
+    }
+
+    let mut img = GptImage::open(image)?;
+    let index = selected_partition(encrypt_matches, &img)?;
+    let mut input = match encrypt_matches.get_one::<String>("input") {
+	Some(f) => Some(std::fs::File::open(host_path(f)).map_err(BobErr::IO)?),
+	None => None,
+    };
+    let mut p = img.partition_view(index).ok_or(BobErr::PartitionNotFound(format!("#{}", index + 1)))?;
+    let header = crate::crypt::format(&mut p, passphrase, iterations, input.as_mut().map(|f| f as &mut dyn std::io::Read))?;
+
+    println!("Encrypted partition {}: {} of data", index + 1, human_size(header.data_sectors * common::crypt::SECTOR_SZ as u64));
+    println!("UUID: {}", crate::hex::encode(&header.uuid));
+    println!("Kernel command line: crypt.key=<passphrase from {key_file}>");
+    Ok(())
+}
+
+/// Writes first boot settings into a provisioning partition, picked by index, name or
+/// unique GUID, or the first one there is.
+pub fn provision(provision_matches: &ArgMatches) -> Result<(), BobErr> {
+    let image = provision_matches.get_one::<String>("image").ok_or(BobErr::MissingArgument)?;
+    let file = match provision_matches.get_one::<String>("config") {
+	Some(f) => std::fs::read_to_string(host_path(f)).map_err(BobErr::IO)?,
+	None => String::new(),
+    };
+    let sets: Vec<String> = provision_matches.get_many::<String>("set").map(|s| s.cloned().collect()).unwrap_or_default();
+    let text = crate::provision::settings_text(&file, &sets)?;
+    let provisioned = crate::provision::check(&text)?;
+
+    let mut img = GptImage::open(image)?;
+    let index = match selected_partition(provision_matches, &img) {
+	Err(BobErr::MissingArgument) => img.partitions_of_type(PartitionType::Provision).first()
+	    .and_then(|name| img.find_by_name(name))
+	    .ok_or_else(|| BobErr::PartitionNotFound(PartitionType::Provision.name()))?,
+	r => r?,
+    };
+    let mut p = img.partition_view(index).ok_or(BobErr::PartitionNotFound(format!("#{}", index + 1)))?;
+    crate::provision::write(&mut p, &text)?;
+
+    println!("Wrote {} of settings to partition {}", human_size(text.len() as u64), index + 1);
+    if let Some(hostname) = provisioned.hostname() {
+	println!("Hostname: {hostname}");
+    }
+    Ok(())
+}
+
+/// Generates a key pair for signing boot configuration.
+pub fn keygen(keygen_matches: &ArgMatches) -> Result<(), BobErr> {
+    let name = keygen_matches.get_one::<String>("output").ok_or(BobErr::MissingArgument)?;
+    let (secret, public) = crate::sign::keygen(&host_path(name))?;
+    println!("Secret key: {}", secret.display());
+    println!("Public key: {}", public.display());
+    println!("Build the bootloader with YOYO_CONFIG_PUBKEY=$(cat {}) to check signatures", public.display());
+    Ok(())
+}
+
+/// Signs each of the given files, or checks their signatures.
+pub fn sign(sign_matches: &ArgMatches) -> Result<(), BobErr> {
+    let files = sign_matches.get_many::<String>("FILE").ok_or(BobErr::MissingArgument)?;
+
+    if let Some(public) = sign_matches.get_one::<String>("check") {
+	let mut all_good = true;
+	for f in files {
+	    let good = crate::sign::verify_file(&host_path(public), &host_path(f))?;
+	    println!("{f}: {}", if good { "good signature" } else { "BAD signature" });
+	    all_good &= good;
+	}
+	if !all_good {
+	    std::process::exit(1);
+	}
+	return Ok(());
+    }
+
+    let key = sign_matches.get_one::<String>("key").ok_or(BobErr::MissingArgument)?;
+    for f in files {
+	let sig = crate::sign::sign_file(&host_path(key), &host_path(f))?;
+	println!("{f}: {}", sig.display());
+    }
+    Ok(())
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use bob_core::gpt::PartitionBuilder;
+
+    /// A unique path in the temp dir, removed when dropped.
+    #[allow(dead_code)]
+    struct TempImage(String);
+
+    impl TempImage {
+	#[allow(dead_code)]
+	fn new(name: &str) -> Self {
+	    let p = std::env::temp_dir().join(format!("bob-cmd-{}-{name}.img", std::process::id()));
+	    Self(String::from(p.to_str().unwrap()))
+	}
+    }
+
+    impl Drop for TempImage {
+	fn drop(&mut self) {
+	    let _ = std::fs::remove_file(&self.0);
+	}
+    }
+
+    #[allow(dead_code)]
+    fn linux(name: &str, pt: PartitionType) -> PartitionInput {
+	PartitionBuilder::new().partition_type(pt).name(name).size(1024 * 1024).build().unwrap()
+    }
+
+    #[allow(dead_code)]
+    fn extract_command() -> clap::Command {
+	clap::Command::new("extract").args(&[
+	    clap::arg!(-i --image <FILE>),
+	    clap::arg!(--partition <PART>),
+	    clap::arg!(--index <N>).value_parser(clap::value_parser!(usize)),
+	    clap::arg!(-o --output <FILE>),
+	])
+    }
+
+    #[test]
+    fn extract_by_number_or_name() {
+	let (tmp, out) = (TempImage::new("extract-by"), TempImage::new("extract-by-out"));
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(linux("data", PartitionType::LinuxFilesystem))
+	    .partition(linux("swap", PartitionType::LinuxSwap))
+	    .build()
+	    .unwrap();
+	let mut bytes = std::fs::read(&tmp.0).unwrap();
+	bytes[2 * 1024 * 1024..][..4].copy_from_slice(b"swap");
+	std::fs::write(&tmp.0, &bytes).unwrap();
+
+	for args in [&["--partition", "2"][..], &["--partition", "swap"], &["--index", "2"]] {
+	    let m = extract_command().get_matches_from([&["extract", "-i", &tmp.0, "-o", &out.0][..], args].concat());
+	    extract_partition(&m).unwrap();
+	    assert_eq!(std::fs::read(&out.0).unwrap()[..4], *b"swap");
+	}
+	for part in ["3", "0", "home"] {
+	    let m = extract_command().get_matches_from(["extract", "-i", &tmp.0, "-o", &out.0, "--partition", part]);
+	    assert!(matches!(extract_partition(&m), Err(BobErr::PartitionNotFound(p)) if p == part));
+	}
+    }
+
+    #[test]
+    fn write_by_number_or_name() {
+	let (tmp, input) = (TempImage::new("write-by"), TempImage::new("write-by-in"));
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(linux("data", PartitionType::LinuxFilesystem))
+	    .partition(linux("swap", PartitionType::LinuxSwap))
+	    .build()
+	    .unwrap();
+	std::fs::write(&input.0, b"blob").unwrap();
+	let write = clap::Command::new("write").args(&[
+	    clap::arg!(-i --image <FILE>),
+	    clap::arg!(--partition <PART>),
+	    clap::arg!(--index <N>).value_parser(clap::value_parser!(usize)),
+	    clap::arg!(--name <NAME>),
+	    clap::arg!(--guid <GUID>).value_parser(|s: &str| s.parse::<Guid>().map_err(|_| "GUID")),
+	    clap::arg!(--input <FILE>),
+	    clap::arg!(--offset <BYTES>).value_parser(clap::value_parser!(usize)),
+	    clap::arg!(--"io-backend" <BACKEND>),
+	]);
+
+	for (args, at) in [(["--partition", "1"], 1), (["--partition", "swap"], 2), (["--name", "data"], 1)] {
+	    let offset = at.to_string();
+	    let m = write.clone().get_matches_from([&["write", "-i", &tmp.0, "--input", &input.0, "--offset", &offset][..], &args].concat());
+	    write_partition(&m).unwrap();
+	    assert_eq!(std::fs::read(&tmp.0).unwrap()[at * 1024 * 1024 + at..][..4], *b"blob");
+	}
+    }
+
+    #[test]
+    fn encrypt_by_name() {
+	let (tmp, key) = (TempImage::new("encrypt-by"), TempImage::new("encrypt-by-key"));
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(linux("data", PartitionType::LinuxFilesystem))
+	    .partition(linux("secret", PartitionType::LinuxFilesystem))
+	    .build()
+	    .unwrap();
+	std::fs::write(&key.0, b"hunter2\n").unwrap();
+	let encrypt = clap::Command::new("encrypt").args(&[
+	    clap::arg!(-i --image <FILE>),
+	    clap::arg!(--partition <PART>),
+	    clap::arg!(--"key-file" <FILE>),
+	    clap::arg!(--input <FILE>),
+	    clap::arg!(--iterations <N>).value_parser(clap::value_parser!(u32)),
+	]);
+
+	let m = encrypt.clone().get_matches_from(["encrypt", "-i", &tmp.0, "--key-file", &key.0, "--iterations", "1", "--partition", "secret"]);
+	encrypt_partition(&m).unwrap();
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert!(bytes[1024 * 1024..2 * 1024 * 1024].iter().all(|b| *b == 0));
+	assert_eq!(bytes[2 * 1024 * 1024..][..8], *b"YOYOCRYP");
+
+	let m = encrypt.get_matches_from(["encrypt", "-i", &tmp.0, "--key-file", &key.0, "--partition", "3"]);
+	assert!(matches!(encrypt_partition(&m), Err(BobErr::PartitionNotFound(p)) if p == "3"));
+    }
+
+    #[test]
+    fn update_dry_run_writes_nothing() {
+	let tmp = TempImage::new("update-dry-run");
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(linux("data", PartitionType::LinuxFilesystem))
+	    .build()
+	    .unwrap();
+	let mut bytes = std::fs::read(&tmp.0).unwrap();
+	// A damaged backup header, which a real update would rewrite.
+	let last = bytes.len() - 512;
+	bytes[last + 40] ^= 0xFF;
+	std::fs::write(&tmp.0, &bytes).unwrap();
+
+	let update = clap::Command::new("update").args(&[clap::arg!(-i --image <FILE>), clap::arg!(--"dry-run")]);
+	update_disk_image(&update.clone().get_matches_from(["update", "-i", &tmp.0, "--dry-run"])).unwrap();
+	assert_eq!(std::fs::read(&tmp.0).unwrap(), bytes);
+	assert_eq!(GptImage::open_read_only(&tmp.0).unwrap().table_changes().unwrap().len(), 1);
+
+	update_disk_image(&update.get_matches_from(["update", "-i", &tmp.0])).unwrap();
+	assert_ne!(std::fs::read(&tmp.0).unwrap(), bytes);
+	assert!(GptImage::open_read_only(&tmp.0).unwrap().table_changes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn versions_only_when_found() {
+	let (tmp, data) = (TempImage::new("versions"), TempImage::new("versions-data"));
+	let image = std::path::Path::new(&tmp.0);
+	let record = crate::versions::versions_path(image);
+	std::fs::write(&record, b"{}").unwrap();
+	std::fs::write(&data.0, b"no build ids in here").unwrap();
+	record_versions(image, &[(String::from("data"), Contents::File(data.0.clone().into()))]).unwrap();
+	assert!(!record.exists());
+	record_versions(image, &[]).unwrap();
+	assert!(!record.exists());
+    }
+
+    #[test]
+    fn threads_only_for_vmdk_stream() {
+	let create = clap::Command::new("create").args(&[
+	    clap::arg!(--format <FORMAT>).default_value("raw"),
+	    clap::arg!(--threads <N>).value_parser(clap::value_parser!(usize)),
+	]);
+	let check = |args: &[&str]| check_threads(&create.clone().get_matches_from([&["create"][..], args].concat()));
+	assert!(check(&[]).is_ok());
+	assert!(check(&["--format", "qcow2"]).is_ok());
+	assert!(check(&["--format", "vmdk-stream", "--threads", "2"]).is_ok());
+	for format in ["raw", "qcow2", "vhd", "vmdk"] {
+	    assert!(matches!(check(&["--format", format, "--threads", "2"]), Err(BobErr::InvalidArgument(_))));
+	}
+    }
+
+    #[test]
+    fn fills_without_esp() {
+	let (tmp, data) = (TempImage::new("no-esp"), TempImage::new("no-esp-data"));
+	std::fs::write(&data.0, b"data").unwrap();
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .total_size(4 * 1024 * 1024)
+	    .partition(linux("data", PartitionType::LinuxFilesystem))
+	    .partition(linux("swap", PartitionType::LinuxSwap))
+	    .build()
+	    .unwrap();
+	assert!(!write_fat_fs(&mut img).unwrap());
+	let contents = [(String::from("data"), Contents::File(data.0.clone().into()))];
+	fill_image(&mut img, IoBackend::File, &contents, &mut ArtifactCache::new().unwrap()).unwrap();
+	drop(img);
+
+	// Only the contents were written, neither partition got a boot sector.
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert_eq!(bytes[1024 * 1024..][..4], *b"data");
+	assert!(bytes[1024 * 1024 + 4..3 * 1024 * 1024].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn mbr_layout_without_esp() {
+	let (tmp, data) = (TempImage::new("mbr-no-esp"), TempImage::new("mbr-no-esp-data"));
+	std::fs::write(&data.0, b"data").unwrap();
+	let layout: Layout = toml::from_str(&format!(r#"
+	    size = "4M"
+	    table = "mbr"
+
+	    [[partition]]
+	    name = "data"
+	    type = "linux"
+	    size = "1M"
+	    contents = {{ file = "{}" }}
+
+	    [[partition]]
+	    name = "swap"
+	    type = "swap"
+	    size = "1M"
+	"#, data.0)).unwrap();
+	let target = layout.target(Some(tmp.0.clone()), std::path::Path::new("/")).unwrap();
+	let images = build_images(vec![target], IoBackend::File, &mut ArtifactCache::new().unwrap()).unwrap();
+	assert_eq!(images[0].table, PartitionTable::Mbr);
+
+	let bytes = std::fs::read(&tmp.0).unwrap();
+	assert_eq!(bytes[510..512], [0x55, 0xAA]);
+	assert_eq!(bytes[1024 * 1024..][..4], *b"data");
+	assert!(bytes[1024 * 1024 + 4..3 * 1024 * 1024].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn manifest_without_esp() {
+	let dir = std::env::temp_dir().join(format!("bob-cmd-{}-manifest", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("data.img"), b"data").unwrap();
+	std::fs::write(dir.join("image.json"), r#"{
+	    "size": "4M",
+	    "partitions": [
+		{ "name": "data", "type": "linux", "size": "1M", "contents": { "file": "data.img" } },
+		{ "name": "swap", "type": "swap", "size": "1M" }
+	    ],
+	    "targets": [{ "output": "full.img" }, { "output": "data.img.raw", "partitions": ["data"] }]
+	}"#).unwrap();
+
+	let matches = clap::Command::new("create")
+	    .arg(clap::arg!(--manifest <FILE>))
+	    .arg(clap::arg!(--"zero-partitions" [MODE]))
+	    .arg(clap::arg!(--"dry-run"))
+	    .arg(clap::arg!(--full))
+	    .arg(clap::arg!(--"io-backend" <BACKEND>))
+	    .get_matches_from(["create", "--manifest", dir.join("image.json").to_str().unwrap()]);
+	create_from_manifest(&matches).unwrap();
+	for (output, count) in [("full.img", 2), ("data.img.raw", 1)] {
+	    let img = GptImage::open_read_only(&dir.join(output).to_string_lossy()).unwrap();
+	    assert_eq!(img.partition_count(), count);
+	    assert_eq!(std::fs::read(dir.join(output)).unwrap()[1024 * 1024..][..4], *b"data");
+	}
+	let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn manifest_refills_changed_contents() {
+	let dir = std::env::temp_dir().join(format!("bob-cmd-{}-incremental", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("kernel"), b"v1").unwrap();
+	std::fs::write(dir.join("data"), b"data").unwrap();
+	std::fs::write(dir.join("image.json"), r#"{
+	    "size": "8M",
+	    "partitions": [
+		{ "name": "ESP", "type": "esp", "size": "2M" },
+		{ "name": "boot", "type": "linux", "size": "1M", "contents": { "file": "kernel" } },
+		{ "name": "data", "type": "linux", "size": "1M", "contents": { "file": "data" } }
+	    ],
+	    "targets": [{ "output": "disk.img" }]
+	}"#).unwrap();
+	let create = |args: &[&str]| {
+	    let manifest = dir.join("image.json");
+	    let matches = clap::Command::new("create")
+		.arg(clap::arg!(--manifest <FILE>))
+		.arg(clap::arg!(--"zero-partitions" [MODE]))
+		.arg(clap::arg!(--"dry-run"))
+		.arg(clap::arg!(--full))
+		.arg(clap::arg!(--"io-backend" <BACKEND>))
+		.get_matches_from(["create", "--manifest", manifest.to_str().unwrap()].iter().chain(args));
+	    create_from_manifest(&matches).unwrap();
+	};
+	let image = dir.join("disk.img");
+	create(&[]);
+	let built = std::fs::read(&image).unwrap();
+	// ESP at 1 MiB, boot and data after it.
+	let (boot, data) = (3 * 1024 * 1024, 4 * 1024 * 1024);
+	assert_eq!(built[boot..][..2], *b"v1");
+
+	// Only the new kernel is written, the GUIDs and the ESP's filesystem stay.
+	std::fs::write(dir.join("kernel"), b"v2").unwrap();
+	create(&[]);
+	let mut refilled = std::fs::read(&image).unwrap();
+	assert_eq!(refilled[boot..][..2], *b"v2");
+	refilled[boot..][..2].copy_from_slice(b"v1");
+	assert!(refilled == built);
+	create(&["--dry-run"]);
+
+	// --full builds it again, with new GUIDs.
+	create(&["--full"]);
+	let rebuilt = std::fs::read(&image).unwrap();
+	assert_eq!((&rebuilt[boot..][..2], &rebuilt[data..][..4]), (&b"v2"[..], &b"data"[..]));
+	assert!(rebuilt[512..1024] != built[512..1024]);
+	let _ = std::fs::remove_dir_all(&dir);
+    }
 }
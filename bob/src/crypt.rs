@@ -0,0 +1,130 @@
+//! Encrypted partitions.
+//!
+//! Formats a partition as a container (see common/src/crypt.rs for the layout) and
+//! optionally fills it with an encrypted copy of a plain image. Whatever the input doesn't
+//! cover is left as it was, and reads back as noise once unlocked, like a fresh LUKS
+//! volume.
+
+use std::io::Read;
+
+use tracing::debug;
+
+use common::crypt::{derive_key, Header, Xts, HEADER_SZ, KEY_SZ, SALT_SZ, SECTOR_SZ};
+use bob_core::err::BobErr;
+use bob_core::gpt::{write_partition_bytes, Partition};
+
+/// Plaintext encrypted per write.
+const CHUNK_SZ: usize = 64 * 1024;
+
+/// Format `p` for `passphrase` and encrypt `input`, if any, into the start of the data
+/// area. Returns the header written.
+pub fn format<P: Partition>(p: &mut P, passphrase: &[u8], iterations: u32, input: Option<&mut dyn Read>) -> Result<Header, BobErr> {
+    let capacity = p.sectors() * p.sector_size() as u64;
+    let salt: [u8; SALT_SZ] = rand::random();
+    let key: [u8; KEY_SZ] = derive_key(passphrase, &salt, iterations);
+    let header = Header::new(&key, salt, iterations, capacity, bob_core::guid::new_v4().to_bytes())
+	.map_err(|_| BobErr::PartitionOutOfBounds)?;
+
+    let mut b = [0; HEADER_SZ];
+    header.write(&mut b);
+    write_partition_bytes(p, 0, HEADER_SZ as u64, &mut &b[..])?;
+
+    let Some(input) = input else {
+	return Ok(header);
+    };
+    let xts = Xts::new(&key);
+    let data_bytes = header.data_sectors * SECTOR_SZ as u64;
+    let mut chunk = Vec::with_capacity(CHUNK_SZ);
+    let mut written = 0;
+    loop {
+	chunk.clear();
+	(&mut *input).take(CHUNK_SZ as u64).read_to_end(&mut chunk).map_err(BobErr::IO)?;
+	if chunk.is_empty() {
+	    break;
+	}
+	if written + chunk.len() as u64 > data_bytes {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+	// The last sector is padded with zeros.
+	chunk.resize(chunk.len().next_multiple_of(SECTOR_SZ), 0);
+	let first = written / SECTOR_SZ as u64;
+	for (i, sector) in chunk.chunks_exact_mut(SECTOR_SZ).enumerate() {
+	    xts.encrypt_sector(first + i as u64, sector);
+	}
+	write_partition_bytes(p, header.data_offset + written, chunk.len() as u64, &mut &chunk[..])?;
+	written += chunk.len() as u64;
+    }
+    debug!(bytes = written, sectors = header.data_sectors, "encrypted partition contents");
+    Ok(header)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use common::crypt::Mapper;
+
+    /// A partition held in memory.
+    #[allow(dead_code)]
+    struct MemPartition(Vec<u8>);
+
+    impl Partition for MemPartition {
+	fn ptype(&self) -> bob_core::gpt::PartitionType {
+	    bob_core::gpt::PartitionType::LinuxFilesystem
+	}
+
+	fn name(&self) -> &str {
+	    "mem"
+	}
+
+	fn sector_size(&self) -> usize {
+	    4096
+	}
+
+	fn sectors(&self) -> u64 {
+	    self.0.len() as u64 / 4096
+	}
+
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 4096;
+	    buf.copy_from_slice(&self.0[at..at + buf.len()]);
+	    Ok(())
+	}
+
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 4096;
+	    self.0[at..at + buf.len()].copy_from_slice(buf);
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn format_and_unlock() {
+	let plain: Vec<u8> = (0..100_000u32).map(|i| (i * 7 / 3) as u8).collect();
+	let mut p = MemPartition(vec![0xEE; 64 * 4096]);
+	let header = format(&mut p, b"swordfish", 2, Some(&mut &plain[..])).unwrap();
+	assert_eq!(Header::parse(&p.0).unwrap(), header);
+	assert_eq!(header.data_sectors, 63 * 8);
+	// No plaintext made it to the disk.
+	assert!(!p.0.windows(64).any(|w| w == &plain[4096..4160]));
+
+	let mapper = Mapper::unlock(&header, b"swordfish").unwrap();
+	let mut sector = [0; SECTOR_SZ];
+	for n in [0, 1, 100, 194] {
+	    let at = mapper.sector_offset(n).unwrap() as usize;
+	    sector.copy_from_slice(&p.0[at..at + SECTOR_SZ]);
+	    mapper.decrypt(n, &mut sector).unwrap();
+	    assert_eq!(&sector[..], &plain[n as usize * SECTOR_SZ..(n as usize + 1) * SECTOR_SZ]);
+	}
+	// The tail of the last sector is padding.
+	let at = mapper.sector_offset(195).unwrap() as usize;
+	sector.copy_from_slice(&p.0[at..at + SECTOR_SZ]);
+	mapper.decrypt(195, &mut sector).unwrap();
+	assert!(sector[100_000 - 195 * SECTOR_SZ..].iter().all(|b| *b == 0));
+
+	let mut small = MemPartition(vec![0; 2 * 4096]);
+	assert!(matches!(format(&mut small, b"swordfish", 2, Some(&mut &plain[..])), Err(BobErr::PartitionOutOfBounds)));
+	assert!(matches!(format(&mut MemPartition(vec![0; 4096]), b"x", 2, None), Err(BobErr::PartitionOutOfBounds)));
+    }
+}
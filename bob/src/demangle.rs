@@ -0,0 +1,470 @@
+//! Rust symbol names back to paths, for showing kernel addresses to people.
+//!
+//! Both manglings rustc uses are handled: the legacy one (`_ZN...E`, still the default on
+//! stable) and v0 (`_R...`, the nightly default the kernel is built with). Crate
+//! disambiguators and hashes are left out, `kernel::sched::run` reads better in a
+//! flamegraph than `kernel[1a2b3c4d5e6f7a8b]::sched::run`. Names that don't parse come
+//! back as they were.
+
+/// Deepest nesting of paths and types followed, backrefs included.
+const MAX_DEPTH: u32 = 64;
+
+pub fn demangle(name: &str) -> String {
+    // LLVM appends suffixes like `.llvm.1234` to local copies.
+    let base = name.find(".llvm.").map_or(name, |at| &name[..at]);
+    let demangled = if let Some(legacy) = base.strip_prefix("_ZN") {
+	demangle_legacy(legacy)
+    } else if let Some(v0) = base.strip_prefix("_R") {
+	V0 { s: v0.as_bytes(), pos: 0, out: String::new(), depth: 0 }.symbol()
+    } else {
+	None
+    };
+    demangled.unwrap_or_else(|| name.to_string())
+}
+
+/// Length prefixed path segments up to `E`, the last one a hash.
+fn demangle_legacy(mut rest: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    while !rest.starts_with('E') {
+	let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+	let len = rest[..digits].parse::<usize>().ok().filter(|len| digits + len <= rest.len())?;
+	segments.push(rest.get(digits..digits + len)?);
+	rest = &rest[digits + len..];
+    }
+    if let Some(last) = segments.last() {
+	if last.len() == 17 && last.starts_with('h') && last[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+	    segments.pop();
+	}
+    }
+    Some(segments.iter().map(|s| unescape(s)).collect::<Vec<_>>().join("::"))
+}
+
+/// `$LT$impl$u20$Foo$GT$` style escapes in a legacy mangled segment.
+fn unescape(segment: &str) -> String {
+    let segment = segment.strip_prefix("_$").map_or(segment.to_string(), |s| format!("${s}"));
+    let mut out = String::new();
+    let mut rest = segment.as_str();
+    while let Some(at) = rest.find(['$', '.']) {
+	out.push_str(&rest[..at]);
+	rest = &rest[at..];
+	if let Some(r) = rest.strip_prefix("..") {
+	    out.push_str("::");
+	    rest = r;
+	    continue;
+	}
+	if let Some(r) = rest.strip_prefix('.') {
+	    out.push('.');
+	    rest = r;
+	    continue;
+	}
+	let Some(end) = rest[1..].find('$') else {
+	    break;
+	};
+	let c = match &rest[1..end + 1] {
+	    "SP" => Some('@'),
+	    "BP" => Some('*'),
+	    "RF" => Some('&'),
+	    "LT" => Some('<'),
+	    "GT" => Some('>'),
+	    "LP" => Some('('),
+	    "RP" => Some(')'),
+	    "C" => Some(','),
+	    e => e.strip_prefix('u').and_then(|hex| u32::from_str_radix(hex, 16).ok()).and_then(char::from_u32),
+	};
+	match c {
+	    Some(c) => out.push(c),
+	    None => out.push_str(&rest[..end + 2]),
+	}
+	rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A v0 symbol after `_R`, printed as it's parsed. Backref positions count from here too.
+struct V0<'a> {
+    s: &'a [u8],
+    pos: usize,
+    out: String,
+    depth: u32,
+}
+
+impl V0<'_> {
+    fn symbol(mut self) -> Option<String> {
+	// An encoding version, only ever absent so far.
+	while self.peek()?.is_ascii_digit() {
+	    self.pos += 1;
+	}
+	self.path(true)?;
+	// Whatever's left is the instantiating crate, which doesn't get printed.
+	Some(self.out)
+    }
+
+    fn peek(&self) -> Option<u8> {
+	self.s.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+	let c = self.peek()?;
+	self.pos += 1;
+	Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+	let ate = self.peek() == Some(c);
+	self.pos += ate as usize;
+	ate
+    }
+
+    /// `_` for 0, otherwise base 62 digits for one less, then `_`.
+    fn base62(&mut self) -> Option<u64> {
+	if self.eat(b'_') {
+	    return Some(0);
+	}
+	let mut n: u64 = 0;
+	loop {
+	    let d = match self.next()? {
+		c @ b'0'..=b'9' => c - b'0',
+		c @ b'a'..=b'z' => c - b'a' + 10,
+		c @ b'A'..=b'Z' => c - b'A' + 36,
+		b'_' => return n.checked_add(1),
+		_ => return None,
+	    };
+	    n = n.checked_mul(62)?.checked_add(d as u64)?;
+	}
+    }
+
+    fn disambiguator(&mut self) -> Option<u64> {
+	if self.eat(b's') { self.base62()?.checked_add(1) } else { Some(0) }
+    }
+
+    fn decimal(&mut self) -> Option<usize> {
+	let start = self.pos;
+	while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+	    self.pos += 1;
+	}
+	let digits = core::str::from_utf8(&self.s[start..self.pos]).ok()?;
+	if digits.len() > 1 && digits.starts_with('0') {
+	    return None;
+	}
+	digits.parse().ok()
+    }
+
+    /// An identifier, without its disambiguator. Punycode ones are shown as encoded.
+    fn ident(&mut self) -> Option<&str> {
+	self.eat(b'u');
+	let len = self.decimal()?;
+	self.eat(b'_');
+	let bytes = self.s.get(self.pos..self.pos.checked_add(len)?)?;
+	self.pos += len;
+	core::str::from_utf8(bytes).ok()
+    }
+
+    /// Follow a backref, printing what it points to with `f`, then carry on after it.
+    fn backref(&mut self, f: impl FnOnce(&mut Self) -> Option<()>) -> Option<()> {
+	let at = self.pos - 1;
+	let target = self.base62()? as usize;
+	if target >= at {
+	    return None;
+	}
+	let resume = self.pos;
+	self.pos = target;
+	f(self)?;
+	self.pos = resume;
+	Some(())
+    }
+
+    /// Parse without printing, for the paths of impls.
+    fn skip(&mut self, f: impl FnOnce(&mut Self) -> Option<()>) -> Option<()> {
+	let len = self.out.len();
+	f(self)?;
+	self.out.truncate(len);
+	Some(())
+    }
+
+    fn nested(&mut self) -> Option<()> {
+	self.depth += 1;
+	(self.depth <= MAX_DEPTH).then_some(())
+    }
+
+    /// `in_value` paths print generic arguments as `::<..>`.
+    fn path(&mut self, in_value: bool) -> Option<()> {
+	self.nested()?;
+	match self.next()? {
+	    b'C' => {
+		self.disambiguator()?;
+		let name = self.ident()?.to_string();
+		self.out.push_str(&name);
+	    },
+	    b'N' => {
+		let ns = self.next()?;
+		self.path(in_value)?;
+		let dis = self.disambiguator()?;
+		let name = self.ident()?.to_string();
+		if ns.is_ascii_uppercase() {
+		    let kind = match ns {
+			b'C' => "closure".to_string(),
+			b'S' => "shim".to_string(),
+			c => (c as char).to_string(),
+		    };
+		    let name = if name.is_empty() { name } else { format!(":{name}") };
+		    self.out.push_str(&format!("::{{{kind}{name}#{dis}}}"));
+		} else if !name.is_empty() {
+		    self.out.push_str("::");
+		    self.out.push_str(&name);
+		}
+	    },
+	    b'M' => {
+		self.disambiguator()?;
+		self.skip(|v| v.path(false))?;
+		self.out.push('<');
+		self.ty()?;
+		self.out.push('>');
+	    },
+	    b'X' => {
+		self.disambiguator()?;
+		self.skip(|v| v.path(false))?;
+		self.trait_impl()?;
+	    },
+	    b'Y' => self.trait_impl()?,
+	    b'I' => {
+		self.path(in_value)?;
+		if in_value {
+		    self.out.push_str("::");
+		}
+		self.out.push('<');
+		self.list(b", ", Self::generic_arg)?;
+		self.out.push('>');
+	    },
+	    b'B' => self.backref(|v| v.path(in_value))?,
+	    _ => return None,
+	}
+	self.depth -= 1;
+	Some(())
+    }
+
+    /// `<Type as Trait>`
+    fn trait_impl(&mut self) -> Option<()> {
+	self.out.push('<');
+	self.ty()?;
+	self.out.push_str(" as ");
+	self.path(false)?;
+	self.out.push('>');
+	Some(())
+    }
+
+    /// Items printed with `f` and separated by `sep` up to an `E`, returning how many.
+    fn list(&mut self, sep: &[u8], mut f: impl FnMut(&mut Self) -> Option<()>) -> Option<usize> {
+	let mut n = 0;
+	while !self.eat(b'E') {
+	    if n > 0 {
+		self.out.push_str(core::str::from_utf8(sep).ok()?);
+	    }
+	    f(self)?;
+	    n += 1;
+	}
+	Some(n)
+    }
+
+    fn generic_arg(&mut self) -> Option<()> {
+	if self.eat(b'L') {
+	    self.base62()?;
+	    self.out.push_str("'_");
+	    Some(())
+	} else if self.eat(b'K') {
+	    self.constant()
+	} else {
+	    self.ty()
+	}
+    }
+
+    fn basic(c: u8) -> Option<&'static str> {
+	Some(match c {
+	    b'a' => "i8",
+	    b'b' => "bool",
+	    b'c' => "char",
+	    b'd' => "f64",
+	    b'e' => "str",
+	    b'f' => "f32",
+	    b'h' => "u8",
+	    b'i' => "isize",
+	    b'j' => "usize",
+	    b'l' => "i32",
+	    b'm' => "u32",
+	    b'n' => "i128",
+	    b'o' => "u128",
+	    b's' => "i16",
+	    b't' => "u16",
+	    b'u' => "()",
+	    b'v' => "...",
+	    b'x' => "i64",
+	    b'y' => "u64",
+	    b'z' => "!",
+	    b'p' => "_",
+	    _ => return None,
+	})
+    }
+
+    fn ty(&mut self) -> Option<()> {
+	self.nested()?;
+	let c = self.peek()?;
+	if let Some(basic) = Self::basic(c) {
+	    self.pos += 1;
+	    self.out.push_str(basic);
+	    self.depth -= 1;
+	    return Some(());
+	}
+	match c {
+	    b'R' | b'Q' => {
+		self.pos += 1;
+		self.out.push('&');
+		if self.eat(b'L') {
+		    self.base62()?;
+		}
+		if c == b'Q' {
+		    self.out.push_str("mut ");
+		}
+		self.ty()?;
+	    },
+	    b'P' | b'O' => {
+		self.pos += 1;
+		self.out.push_str(if c == b'P' { "*const " } else { "*mut " });
+		self.ty()?;
+	    },
+	    b'A' | b'S' => {
+		self.pos += 1;
+		self.out.push('[');
+		self.ty()?;
+		if c == b'A' {
+		    self.out.push_str("; ");
+		    self.constant()?;
+		}
+		self.out.push(']');
+	    },
+	    b'T' => {
+		self.pos += 1;
+		self.out.push('(');
+		if self.list(b", ", Self::ty)? == 1 {
+		    self.out.push(',');
+		}
+		self.out.push(')');
+	    },
+	    b'F' => {
+		self.pos += 1;
+		if self.eat(b'G') {
+		    self.base62()?;
+		}
+		if self.eat(b'U') {
+		    self.out.push_str("unsafe ");
+		}
+		if self.eat(b'K') {
+		    let abi = if self.eat(b'C') { "C".to_string() } else { self.ident()?.replace('_', "-") };
+		    self.out.push_str(&format!("extern \"{abi}\" "));
+		}
+		self.out.push_str("fn(");
+		self.list(b", ", Self::ty)?;
+		self.out.push(')');
+		if !self.eat(b'u') {
+		    self.out.push_str(" -> ");
+		    self.ty()?;
+		}
+	    },
+	    b'D' => {
+		self.pos += 1;
+		if self.eat(b'G') {
+		    self.base62()?;
+		}
+		self.out.push_str("dyn ");
+		self.list(b" + ", Self::dyn_trait)?;
+		if !self.eat(b'L') {
+		    return None;
+		}
+		self.base62()?;
+	    },
+	    b'B' => {
+		self.pos += 1;
+		self.backref(Self::ty)?;
+	    },
+	    _ => self.path(false)?,
+	}
+	self.depth -= 1;
+	Some(())
+    }
+
+    /// A trait and its associated type bindings, `Iterator<Item = u8>`.
+    fn dyn_trait(&mut self) -> Option<()> {
+	self.path(false)?;
+	let mut first = true;
+	while self.eat(b'p') {
+	    self.out.push_str(if first { "<" } else { ", " });
+	    first = false;
+	    let name = self.ident()?.to_string();
+	    self.out.push_str(&name);
+	    self.out.push_str(" = ");
+	    self.ty()?;
+	}
+	if !first {
+	    self.out.push('>');
+	}
+	Some(())
+    }
+
+    fn constant(&mut self) -> Option<()> {
+	match self.next()? {
+	    b'p' => self.out.push('_'),
+	    b'B' => self.backref(Self::constant)?,
+	    ty => {
+		let negative = self.eat(b'n');
+		let start = self.pos;
+		while self.peek()? != b'_' {
+		    self.pos += 1;
+		}
+		let hex = core::str::from_utf8(&self.s[start..self.pos]).ok()?;
+		self.pos += 1;
+		let value = if hex.is_empty() { 0 } else { u128::from_str_radix(hex, 16).ok()? };
+		match ty {
+		    b'b' => self.out.push_str(if value == 0 { "false" } else { "true" }),
+		    b'c' => self.out.push_str(&format!("{:?}", char::from_u32(value as u32)?)),
+		    _ => self.out.push_str(&format!("{}{value}", if negative { "-" } else { "" })),
+		}
+	    },
+	}
+	Some(())
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn legacy() {
+	assert_eq!(demangle("_ZN6kernel5sched3run17h0123456789abcdefE"), "kernel::sched::run");
+	assert_eq!(demangle("_ZN4core3ptr46drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$17h0123456789abcdefE"),
+		   "core::ptr::drop_in_place<alloc::vec::Vec<u8>>");
+	assert_eq!(demangle("_ZN57_$LT$kernel..dmesg..Dmesg$u20$as$u20$core..fmt..Write$GT$9write_str17h0123456789abcdefE"),
+		   "<kernel::dmesg::Dmesg as core::fmt::Write>::write_str");
+	assert_eq!(demangle("_ZN6kernel4main17h0123456789abcdefE.llvm.42"), "kernel::main");
+	assert_eq!(demangle("kmain"), "kmain");
+	assert_eq!(demangle("_ZN99broken"), "_ZN99broken");
+    }
+
+    #[test]
+    fn v0() {
+	assert_eq!(demangle("_RNvNtCs1234_6kernel5sched3run"), "kernel::sched::run");
+	// Generic arguments, a closure and backrefs to both crates.
+	assert_eq!(demangle("_RINvNtNtCsi4IsKQVxMg0_3std6thread9lifecycle15spawn_uncheckedNCNvNtCsaKM70z6le5X_3bob5serve8run_http0uEB12_"),
+		   "std::thread::lifecycle::spawn_unchecked::<bob::serve::run_http::{closure#0}, ()>");
+	assert_eq!(demangle("_RINvNtCs8NwYtU1Mohg_4core3ptr9drop_glueNCNvNtCsaKM70z6le5X_3bob5serve8run_http0EBH_"),
+		   "core::ptr::drop_glue::<bob::serve::run_http::{closure#0}>");
+	// Inherent impls on a slice, tuples, references and arrays.
+	assert_eq!(demangle("_RNvMNtCs1_4core5sliceSTyQAhj4_E4sort"), "<[(u64, &mut [u8; 4])]>::sort");
+	// A trait impl, a dyn type with a lifetime.
+	assert_eq!(demangle("_RNvXCs1_3bobDNtCs2_4core5DebugEL_NtBd_7Display3fmt"),
+		   "<dyn core::Debug as core::Display>::fmt");
+	// A backref can only point backwards.
+	assert_eq!(demangle("_RNvB_3foo"), "_RNvB_3foo");
+	assert_eq!(demangle("_RNvCs1_3foo3bar.llvm.42"), "foo::bar");
+    }
+}
@@ -0,0 +1,187 @@
+//! Structural comparison of two disk images.
+//!
+//! Both images are compared as `inspect --json` shows them: every header field and
+//! partition entry is flattened to a path like `primary_header.header_crc32` or
+//! `partitions[1].last_lba`, and paths whose values differ are reported. Partitions are
+//! matched by their slot in the table, and one only one image has is reported once rather
+//! than field by field. Optionally each partition's contents are hashed as well, for
+//! images with the same layout but different files in them.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use bob_core::err::BobErr;
+use bob_core::gpt::GptImage;
+use crate::hex;
+
+/// A path whose value differs, `None` where the image doesn't have it.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let side = |v: &Option<String>| v.clone().unwrap_or_else(|| String::from("(none)"));
+	write!(f, "{}: {} -> {}", self.path, side(&self.old), side(&self.new))
+    }
+}
+
+/// The differences between `old` and `new`, headers first and then partitions in table
+/// order. With `content`, partitions in both images are also compared by SHA-256.
+pub fn diff(old: &mut GptImage, new: &mut GptImage, content: bool) -> Result<Vec<Difference>, BobErr> {
+    let (mut old_value, mut new_value) = (old.inspect_value(), new.inspect_value());
+    let old_parts = take_partitions(&mut old_value);
+    let new_parts = take_partitions(&mut new_value);
+
+    let mut diffs = Vec::new();
+    compare(flatten(&old_value), flatten(&new_value), &mut diffs);
+    for i in 0..old_parts.len().max(new_parts.len()) {
+	let path = format!("partitions[{i}]");
+	match (old_parts.get(i), new_parts.get(i)) {
+	    (Some(o), Some(n)) => {
+		let mut fields = Vec::new();
+		compare(flatten(o), flatten(n), &mut fields);
+		diffs.extend(fields.into_iter().map(|d| Difference { path: format!("{path}.{}", d.path), ..d }));
+		if content {
+		    let (o, n) = (content_hash(old, i)?, content_hash(new, i)?);
+		    if o != n {
+			diffs.push(Difference { path: format!("{path}.sha256"), old: Some(o), new: Some(n) });
+		    }
+		}
+	    },
+	    (o, n) => diffs.push(Difference { path, old: o.map(summary), new: n.map(summary) }),
+	}
+    }
+    Ok(diffs)
+}
+
+fn take_partitions(v: &mut Value) -> Vec<Value> {
+    match v.get_mut("partitions").map(Value::take) {
+	Some(Value::Array(parts)) => parts,
+	_ => Vec::new(),
+    }
+}
+
+/// `(path, value)` for every leaf, values as JSON.
+fn flatten(v: &Value) -> Vec<(String, String)> {
+    fn walk(v: &Value, path: String, out: &mut Vec<(String, String)>) {
+	let join = |key: &str| if path.is_empty() { String::from(key) } else { format!("{path}.{key}") };
+	match v {
+	    Value::Object(map) => map.iter().for_each(|(k, v)| walk(v, join(k), out)),
+	    Value::Array(items) => items.iter().enumerate().for_each(|(i, v)| walk(v, format!("{path}[{i}]"), out)),
+	    v => out.push((path, v.to_string())),
+	}
+    }
+    let mut out = Vec::new();
+    walk(v, String::new(), &mut out);
+    out
+}
+
+fn compare(old: Vec<(String, String)>, new: Vec<(String, String)>, diffs: &mut Vec<Difference>) {
+    for (path, o) in &old {
+	let n = new.iter().find(|(p, _)| p == path).map(|(_, v)| v);
+	if n != Some(o) {
+	    diffs.push(Difference { path: path.clone(), old: Some(o.clone()), new: n.cloned() });
+	}
+    }
+    for (path, n) in new.into_iter().filter(|(p, _)| !old.iter().any(|(o, _)| o == p)) {
+	diffs.push(Difference { path, old: None, new: Some(n) });
+    }
+}
+
+/// A partition only one image has, by name and LBA range.
+fn summary(p: &Value) -> String {
+    format!("{} LBA {}-{}", p["name"], p["first_lba"], p["last_lba"])
+}
+
+fn content_hash(img: &mut GptImage, index: usize) -> Result<String, BobErr> {
+    struct Hasher(Sha256);
+
+    impl Write for Hasher {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    self.0.update(buf);
+	    Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    let mut h = Hasher(Sha256::new());
+    img.extract_partition(index, &mut h)?;
+    Ok(hex::encode(&h.0.finalize()))
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use bob_core::gpt::{DiskImgBuilder, PartitionBuilder, PartitionType};
+
+    /// A unique path in the temp dir, removed when dropped.
+    #[allow(dead_code)]
+    struct TempImage(String);
+
+    impl TempImage {
+	#[allow(dead_code)]
+	fn new(name: &str) -> Self {
+	    let p = std::env::temp_dir().join(format!("bob-diff-{}-{name}.img", std::process::id()));
+	    Self(String::from(p.to_str().unwrap()))
+	}
+    }
+
+    impl Drop for TempImage {
+	fn drop(&mut self) {
+	    let _ = std::fs::remove_file(&self.0);
+	}
+    }
+
+    #[allow(dead_code)]
+    fn image(path: &str, extra: bool) -> GptImage {
+	let mut b = DiskImgBuilder::new()
+	    .output_file(path)
+	    .total_size(4 * 1024 * 1024)
+	    .seed(7)
+	    .partition(PartitionBuilder::new().partition_type(PartitionType::EFISystem).start_offset(1024 * 1024).end_offset(2 * 1024 * 1024).build().unwrap());
+	if extra {
+	    b = b.partition(PartitionBuilder::new().partition_type(PartitionType::LinuxFilesystem).name("data").size(512 * 1024).build().unwrap());
+	}
+	b.build().unwrap()
+    }
+
+    #[test]
+    fn differences() {
+	let (a, b) = (TempImage::new("a"), TempImage::new("b"));
+	let mut old = image(&a.0, false);
+	let mut new = image(&b.0, true);
+	assert_eq!(diff(&mut old, &mut GptImage::open_read_only(&a.0).unwrap(), true).unwrap(), []);
+
+	let diffs = diff(&mut old, &mut new, false).unwrap();
+	let paths: Vec<&str> = diffs.iter().map(|d| d.path.as_str()).collect();
+	assert!(paths.contains(&"primary_header.entry_array_crc32"));
+	assert!(!paths.iter().any(|p| p.starts_with("partitions[0]")));
+	let added = diffs.last().unwrap();
+	assert_eq!(added.path, "partitions[1]");
+	assert_eq!(added.old, None);
+	assert!(added.to_string().starts_with("partitions[1]: (none) -> \"data\" LBA "));
+
+	// Same layout, different bytes in the ESP.
+	std::fs::copy(&a.0, &b.0).unwrap();
+	let mut f = std::fs::OpenOptions::new().write(true).open(&b.0).unwrap();
+	io::Seek::seek(&mut f, io::SeekFrom::Start(1024 * 1024)).unwrap();
+	f.write_all(b"FAT").unwrap();
+	let mut new = GptImage::open_read_only(&b.0).unwrap();
+	assert_eq!(diff(&mut old, &mut new, false).unwrap(), []);
+	let diffs = diff(&mut old, &mut new, true).unwrap();
+	assert_eq!(diffs.len(), 1);
+	assert_eq!(diffs[0].path, "partitions[0].sha256");
+    }
+}
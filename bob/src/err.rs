@@ -1,9 +0,0 @@
-#[derive(Debug)]
-pub enum BobErr {
-    PartitionParse,
-    MissingArgument,
-    IO(std::io::Error),
-    ImageTooSmall,
-    PartitionNameTooLong,
-    NoEFISystemPartition,
-}
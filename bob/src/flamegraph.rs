@@ -0,0 +1,125 @@
+//! Turning kernel profiler samples into folded stacks.
+//!
+//! The kernel writes its samples as `@prof` lines (see `common::profile`) to the serial
+//! port. This picks them out of a console log, symbolizes every address with the
+//! kernel ELF's symbol table and counts identical stacks, giving the folded format that
+//! flamegraph.pl, inferno and speedscope read:
+//!
+//! ```text
+//! kernel::kmain;kernel::sched::run;kernel::memory::frame::alloc 42
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+use common::elf::{load_elf, STT_FUNC};
+use common::profile::Sample;
+
+use bob_core::err::BobErr;
+use crate::demangle::demangle;
+
+/// The kernel's functions, sorted by address.
+pub struct Symbols {
+    funcs: Vec<(u64, u64, String)>,
+}
+
+impl Symbols {
+    pub fn from_elf(bytes: &[u8]) -> Result<Self, BobErr> {
+	let elf = load_elf(bytes).map_err(|e| BobErr::Flamegraph(format!("kernel ELF: {e:?}")))?;
+	let symbols = elf.symbols().map_err(|e| BobErr::Flamegraph(format!("kernel ELF: {e:?}")))?;
+	let mut funcs: Vec<_> = symbols
+	    .filter(|s| s.st_type == STT_FUNC && s.st_value != 0)
+	    .map(|s| (s.st_value, s.st_value + s.st_size.max(1), demangle(s.name)))
+	    .collect();
+	if funcs.is_empty() {
+	    return Err(BobErr::Flamegraph("the kernel ELF has no function symbols, is it stripped?".to_string()));
+	}
+	funcs.sort_by_key(|f| f.0);
+	Ok(Self { funcs })
+    }
+
+    /// The function containing `addr`.
+    pub fn lookup(&self, addr: u64) -> Option<&str> {
+	let i = self.funcs.partition_point(|f| f.0 <= addr).checked_sub(1)?;
+	let (start, end, name) = &self.funcs[i];
+	(*start..*end).contains(&addr).then_some(name.as_str())
+    }
+}
+
+/// Stacks counted from a console log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Folded {
+    pub stacks: BTreeMap<String, u64>,
+    pub samples: u64,
+    /// Addresses no function covers, left as hex in the stacks.
+    pub unresolved: u64,
+}
+
+impl Folded {
+    fn add(&mut self, sample: &Sample, symbols: &Symbols) {
+	// Return addresses point after the call, which can be past the end of a function
+	// ending in a call that doesn't return.
+	let frames = sample.frames().iter().enumerate().rev().map(|(i, addr)| {
+	    let at = if i == 0 { *addr } else { addr.saturating_sub(1) };
+	    match symbols.lookup(at) {
+		Some(name) => name.to_string(),
+		None => {
+		    self.unresolved += 1;
+		    format!("{addr:#x}")
+		},
+	    }
+	});
+	let stack = frames.collect::<Vec<_>>().join(";");
+	*self.stacks.entry(stack).or_default() += 1;
+	self.samples += 1;
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), BobErr> {
+	for (stack, count) in &self.stacks {
+	    writeln!(out, "{stack} {count}").map_err(BobErr::IO)?;
+	}
+	Ok(())
+    }
+}
+
+/// Fold every `@prof` line of `input`, skipping everything else.
+pub fn fold(input: impl BufRead, symbols: &Symbols) -> Result<Folded, BobErr> {
+    let mut folded = Folded::default();
+    for line in input.split(b'\n') {
+	let line = line.map_err(BobErr::IO)?;
+	// Console output around the samples isn't necessarily UTF-8.
+	if let Some(sample) = Sample::parse(&String::from_utf8_lossy(&line)) {
+	    folded.add(&sample, symbols);
+	}
+    }
+    Ok(folded)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn folds_samples() {
+	let symbols = Symbols {
+	    funcs: vec![
+		(0x1000, 0x1100, "kmain".to_string()),
+		(0x1100, 0x1180, "run".to_string()),
+		(0x2000, 0x2040, "alloc".to_string()),
+	    ],
+	};
+	assert_eq!(symbols.lookup(0x10ff), Some("kmain"));
+	assert_eq!(symbols.lookup(0x1180), None);
+	assert_eq!(symbols.lookup(0xfff), None);
+
+	// The last return address is one past the end of kmain, which ends in the call.
+	let log = "boot\n@prof 2010,1150,1100\n@prof 2020,1120,1100\n[ 2.0] done\n@prof 1150,1100\n@prof 9000\n";
+	let folded = fold(log.as_bytes(), &symbols).unwrap();
+	assert_eq!(folded.samples, 4);
+	assert_eq!(folded.unresolved, 1);
+	let mut out = Vec::new();
+	folded.write(&mut out).unwrap();
+	assert_eq!(String::from_utf8(out).unwrap(), "0x9000 1\nkmain;run 1\nkmain;run;alloc 2\n");
+    }
+}
@@ -0,0 +1,199 @@
+//! Golden image regression tests.
+//!
+//! Small images are built from fixed layouts (so every GUID is known up front) and
+//! compared byte for byte against the dumps in `testdata/golden`. Images are mostly
+//! zeros so the dumps only hold the 16 byte rows that aren't, as `<offset>: <hex>` lines,
+//! which keeps them small and makes a failing diff readable.
+//!
+//! After an intentional change to the on-disk format, regenerate the dumps with
+//! `BOB_BLESS=1 cargo test -p bob golden` and check the diff in with the change.
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use std::fs;
+    #[allow(unused_imports)]
+    use bob_core::gpt::{DiskImgBuilder, GptImage, PartitionType};
+    #[allow(unused_imports)]
+    use bob_core::guid::Guid;
+    #[allow(unused_imports)]
+    use bob_core::table::{LayoutPartition, TableLayout};
+
+    #[allow(dead_code)]
+    const ROW_SZ: usize = 16;
+
+    #[allow(dead_code)]
+    fn golden_path(name: &str) -> String {
+	format!("{}/testdata/golden/{name}.txt", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    /// Dump the non-zero rows of an image.
+    #[allow(dead_code)]
+    fn dump(name: &str, bytes: &[u8]) -> String {
+	let mut s = format!("# {name}: {} bytes, all zero rows omitted\n", bytes.len());
+	for (i, row) in bytes.chunks(ROW_SZ).enumerate() {
+	    if row.iter().all(|b| *b == 0) {
+		continue;
+	    }
+	    let hex: Vec<String> = row.iter().map(|b| format!("{b:02x}")).collect();
+	    s.push_str(&format!("{:08x}: {}\n", i * ROW_SZ, hex.join(" ")));
+	}
+	s
+    }
+
+    /// Compare `bytes` against the named golden dump, or rewrite it if BOB_BLESS is set.
+    #[allow(dead_code)]
+    fn check_golden(name: &str, bytes: &[u8]) {
+	let actual = dump(name, bytes);
+	let path = golden_path(name);
+	if std::env::var_os("BOB_BLESS").is_some() {
+	    fs::write(&path, &actual).unwrap();
+	    return;
+	}
+
+	let expected = fs::read_to_string(&path)
+	    .unwrap_or_else(|e| panic!("missing golden file {path} ({e}), run with BOB_BLESS=1 to create it"));
+	if expected != actual {
+	    let first_diff = expected.lines().zip(actual.lines())
+		.find(|(e, a)| e != a)
+		.map(|(e, a)| format!("expected: {e}\n  actual: {a}"))
+		.unwrap_or_else(|| String::from("dumps differ in length"));
+	    panic!("{name} doesn't match {path}\n{first_diff}");
+	}
+    }
+
+    /// A unique path in the temp dir, removed when dropped.
+    #[allow(dead_code)]
+    struct TempImage(String);
+
+    impl TempImage {
+	#[allow(dead_code)]
+	fn new(name: &str) -> Self {
+	    let p = std::env::temp_dir().join(format!("bob-golden-{}-{name}.img", std::process::id()));
+	    Self(String::from(p.to_str().unwrap()))
+	}
+    }
+
+    impl Drop for TempImage {
+	fn drop(&mut self) {
+	    let _ = fs::remove_file(&self.0);
+	}
+    }
+
+    #[allow(dead_code)]
+    fn guid(s: &str) -> Guid {
+	s.parse().unwrap()
+    }
+
+    #[allow(dead_code)]
+    const ESP_TYPE: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
+    #[allow(dead_code)]
+    fn esp_layout() -> TableLayout {
+	TableLayout {
+	    disk_guid: guid("6B2A1F3E-0C4D-4E8A-9F21-5D3C7A9B1E40"),
+	    sector_size: 512,
+	    first_usable_lba: 34,
+	    last_usable_lba: 8158,
+	    last_lba: 8191,
+	    max_partitions: 128,
+	    partitions: vec![LayoutPartition {
+		type_guid: guid(ESP_TYPE),
+		unique_guid: guid("0F8E1D2C-3B4A-4596-8877-66554433AA11"),
+		first_lba: 2048,
+		last_lba: 4096,
+		attributes: 0,
+		name: PartitionType::EFISystem.name(),
+	    }],
+	}
+    }
+
+    #[allow(dead_code)]
+    fn two_partition_layout() -> TableLayout {
+	TableLayout {
+	    disk_guid: guid("A1B2C3D4-E5F6-4718-8293-A4B5C6D7E8F9"),
+	    sector_size: 512,
+	    first_usable_lba: 34,
+	    last_usable_lba: 8158,
+	    last_lba: 8191,
+	    max_partitions: 128,
+	    partitions: vec![
+		LayoutPartition {
+		    type_guid: guid(ESP_TYPE),
+		    unique_guid: guid("11111111-2222-4333-8444-555555555555"),
+		    first_lba: 2048,
+		    last_lba: 4096,
+		    attributes: 1,
+		    name: PartitionType::EFISystem.name(),
+		},
+		LayoutPartition {
+		    type_guid: guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+		    unique_guid: guid("66666666-7777-4888-9999-AAAAAAAAAAAA"),
+		    first_lba: 6144,
+		    last_lba: 8000,
+		    attributes: 1 << 60,
+		    name: String::from("root"),
+		},
+	    ],
+	}
+    }
+
+    /// Build an image from `layout`, optionally formatting the ESP, and return its bytes.
+    #[allow(dead_code)]
+    fn build(name: &str, layout: TableLayout, format_esp: bool) -> Vec<u8> {
+	let tmp = TempImage::new(name);
+	let mut img = DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .layout(layout)
+	    .build()
+	    .unwrap();
+	if format_esp {
+	    assert!(crate::cmd::write_fat_fs(&mut img).unwrap());
+	}
+	drop(img);
+	fs::read(&tmp.0).unwrap()
+    }
+
+    #[test]
+    fn golden_esp_image() {
+	let bytes = build("esp", esp_layout(), true);
+	check_golden("esp", &bytes);
+    }
+
+    #[test]
+    fn golden_two_partition_image() {
+	let bytes = build("two-partition", two_partition_layout(), false);
+	check_golden("two-partition", &bytes);
+    }
+
+    /// The GPT reader should see exactly the layout the image was built from.
+    #[allow(dead_code)]
+    fn assert_reads_back(name: &str, layout: fn() -> TableLayout) {
+	let tmp = TempImage::new(name);
+	DiskImgBuilder::new()
+	    .output_file(&tmp.0)
+	    .layout(layout())
+	    .build()
+	    .unwrap();
+
+	assert_eq!(GptImage::open_read_only(&tmp.0).unwrap().layout(), layout());
+    }
+
+    #[test]
+    fn golden_images_read_back() {
+	assert_reads_back("esp-read", esp_layout);
+	assert_reads_back("two-partition-read", two_partition_layout);
+    }
+
+    #[test]
+    fn golden_esp_is_fat() {
+	let bytes = build("esp-fat", esp_layout(), true);
+	let esp = &bytes[2048 * 512..];
+	// Jump instruction, then the BPB's bytes per sector and sectors per cluster.
+	assert_eq!(&esp[0..3], &[0xEB, 0x3C, 0x90]);
+	assert_eq!(u16::from_le_bytes([esp[11], esp[12]]), 512);
+	assert_eq!(esp[13], 2);
+    }
+}
@@ -0,0 +1,27 @@
+//! Hex strings for keys, salts and hashes on the command line.
+
+pub fn encode(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+	return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+	assert_eq!(encode(&[0, 0xAB, 0x10]), "00ab10");
+	assert_eq!(decode("00ab10").unwrap(), [0, 0xAB, 0x10]);
+	assert_eq!(decode("00AB10").unwrap(), [0, 0xAB, 0x10]);
+	assert_eq!(decode("0"), None);
+	assert_eq!(decode("zz"), None);
+    }
+}
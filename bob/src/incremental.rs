@@ -0,0 +1,197 @@
+//! Incremental builds for `create --manifest`.
+//!
+//! After building a target bob writes `<image>.build.json` next to it: a hash of the
+//! target's layout, a hash of the sources of each partition it filled, and the size and
+//! modification time the image was left with. The next build of the target compares
+//! against it. With the same layout and an image nobody has touched since, only the
+//! partitions whose sources hash differently are refilled and the rest of the image,
+//! the ESP's filesystem included, stays as it is. Anything else builds the image from
+//! scratch, as does `--full`.
+//!
+//! Unlike `bob watch`, which stamps sources by name, size and modification time, sources
+//! are hashed here: a build may be days after the last and a copied or checked out file
+//! gets a new time without new contents. A directory packed as squashfs is hashed with
+//! its tree, every name, mode, modification time and file, all of which end up in the
+//! packed image.
+//!
+//! A refill writes the new contents over the start of the partition, as in watch.rs.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use bob_core::err::BobErr;
+use crate::hex;
+use crate::manifest::{Contents, TargetPlan};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// Hash of the target's resolved layout.
+    pub layout: String,
+    /// Hash of each filled partition's sources, by partition name.
+    pub partitions: BTreeMap<String, String>,
+    /// The image as the build left it, in bytes and nanoseconds since the epoch.
+    pub image_len: u64,
+    pub image_mtime: u64,
+}
+
+/// What a build of a target has to do.
+#[derive(Debug, PartialEq)]
+pub enum Build {
+    /// Build the image from scratch.
+    Full,
+    /// Refill these partitions, by name, and leave the rest of the image.
+    Refill(Vec<String>),
+}
+
+impl BuildRecord {
+    /// The record of `target` as it is now. The image fields are filled in by `save`.
+    pub fn new(target: &TargetPlan) -> Result<Self, BobErr> {
+	let partitions = target.contents.iter()
+	    .map(|(name, c)| Ok((name.clone(), hash_contents(c)?)))
+	    .collect::<Result<_, BobErr>>()?;
+	Ok(Self { layout: hex::encode(&Sha256::digest(&target.layout)), partitions, image_len: 0, image_mtime: 0 })
+    }
+
+    /// What to do to bring `image` up to this record.
+    pub fn plan(&self, image: &Path) -> Build {
+	let Some(old) = Self::load(image) else {
+	    return Build::Full;
+	};
+	let same_partitions = old.partitions.keys().eq(self.partitions.keys());
+	if old.layout != self.layout || !same_partitions || image_stamp(image).ok() != Some((old.image_len, old.image_mtime)) {
+	    return Build::Full;
+	}
+	Build::Refill(self.partitions.iter().filter(|(name, hash)| old.partitions[*name] != **hash).map(|(name, _)| name.clone()).collect())
+    }
+
+    /// Write the record next to `image`, after building it.
+    pub fn save(mut self, image: &Path) -> Result<(), BobErr> {
+	(self.image_len, self.image_mtime) = image_stamp(image)?;
+	let mut text = serde_json::to_string_pretty(&self).expect("record to serialize");
+	text.push('\n');
+	fs::write(record_path(image), text).map_err(BobErr::IO)
+    }
+
+    /// The record next to `image`, None if there isn't one bob can read.
+    fn load(image: &Path) -> Option<Self> {
+	serde_json::from_str(&fs::read_to_string(record_path(image)).ok()?).ok()
+    }
+}
+
+pub fn record_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".build.json");
+    PathBuf::from(path)
+}
+
+fn image_stamp(image: &Path) -> Result<(u64, u64), BobErr> {
+    let m = fs::metadata(image).map_err(BobErr::IO)?;
+    let mtime = m.modified().map_err(BobErr::IO)?.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    Ok((m.len(), mtime))
+}
+
+fn hash_contents(contents: &Contents) -> Result<String, BobErr> {
+    let mut h = Sha256::new();
+    match contents {
+	Contents::File(f) => {
+	    h.update(b"file");
+	    hash_file(&mut h, f)?;
+	},
+	Contents::Squashfs(dir) => {
+	    h.update(b"squashfs");
+	    hash_tree(&mut h, dir, Path::new(""))?;
+	},
+    }
+    Ok(hex::encode(&h.finalize()))
+}
+
+fn hash_file(h: &mut Sha256, path: &Path) -> Result<(), BobErr> {
+    let mut f = File::open(path).map_err(BobErr::IO)?;
+    io::copy(&mut f, h).map_err(BobErr::IO)?;
+    Ok(())
+}
+
+/// Everything under `path` squashfs::pack_dir puts in the image. `name` is its path
+/// relative to the packed directory.
+fn hash_tree(h: &mut Sha256, path: &Path, name: &Path) -> Result<(), BobErr> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::symlink_metadata(path).map_err(BobErr::IO)?;
+    // Lengths first, so one entry's bytes can't pass for another's.
+    let bytes = name.as_os_str().as_encoded_bytes();
+    h.update((bytes.len() as u64).to_le_bytes());
+    h.update(bytes);
+    h.update(meta.mode().to_le_bytes());
+    h.update(meta.mtime().to_le_bytes());
+    if meta.is_dir() {
+	let mut entries: Vec<_> = fs::read_dir(path).map_err(BobErr::IO)?.collect::<Result<_, _>>().map_err(BobErr::IO)?;
+	entries.sort_by_key(|e| e.file_name());
+	h.update((entries.len() as u64).to_le_bytes());
+	for e in entries {
+	    hash_tree(h, &e.path(), &name.join(e.file_name()))?;
+	}
+    } else if meta.file_type().is_symlink() {
+	h.update(fs::read_link(path).map_err(BobErr::IO)?.as_os_str().as_encoded_bytes());
+    } else {
+	h.update(meta.len().to_le_bytes());
+	hash_file(h, path)?;
+    }
+    Ok(())
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::manifest::Manifest;
+
+    #[allow(dead_code)]
+    fn target(dir: &Path, size: &str) -> TargetPlan {
+	let m: Manifest = serde_json::from_str(&format!(r#"{{
+	    "size": "{size}",
+	    "partitions": [
+		{{ "name": "ESP", "type": "esp", "size": "1M", "contents": {{ "file": "esp.img" }} }},
+		{{ "name": "root", "type": "root", "size": "1M", "contents": {{ "squashfs": "rootfs" }} }}
+	    ],
+	    "targets": [{{ "output": "disk.img" }}]
+	}}"#)).unwrap();
+	m.targets(dir).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn plans_refills() {
+	let dir = std::env::temp_dir().join(format!("bob-incremental-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(dir.join("rootfs").join("etc")).unwrap();
+	fs::write(dir.join("esp.img"), b"fat").unwrap();
+	fs::write(dir.join("rootfs").join("init"), b"v1").unwrap();
+	let image = dir.join("disk.img");
+	let record = || BuildRecord::new(&target(&dir, "4M")).unwrap();
+	assert_eq!(record().plan(&image), Build::Full);
+
+	fs::write(&image, b"built").unwrap();
+	record().save(&image).unwrap();
+	assert_eq!(record().plan(&image), Build::Refill(Vec::new()));
+
+	// The same bytes written again: nothing to do. A new empty directory does count.
+	fs::write(dir.join("esp.img"), b"fat").unwrap();
+	assert_eq!(record().plan(&image), Build::Refill(Vec::new()));
+	fs::create_dir(dir.join("rootfs").join("etc").join("empty")).unwrap();
+	assert_eq!(record().plan(&image), Build::Refill(vec![String::from("root")]));
+	fs::write(dir.join("esp.img"), b"fat32").unwrap();
+	assert_eq!(record().plan(&image), Build::Refill(vec![String::from("ESP"), String::from("root")]));
+
+	// Another layout, or an image changed since, is built again.
+	assert_eq!(BuildRecord::new(&target(&dir, "8M")).unwrap().plan(&image), Build::Full);
+	fs::write(&image, b"changed").unwrap();
+	assert_eq!(record().plan(&image), Build::Full);
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,388 @@
+//! ISO 9660 output, for booting from a virtual CD drive or burning the image to a disc.
+//!
+//! The image is built as usual and its EFI system partition wrapped afterwards. UEFI
+//! firmware boots a CD through an El Torito catalog entry for platform 0xEF pointing at
+//! a FAT image, so that's all there is besides the ISO 9660 structures: the ESP goes in
+//! as EFI.IMG, the catalog as BOOT.CAT, and the rest of the disk image is left out.
+//!
+//! Layout, in 2048 byte sectors:
+//!
+//! ```text
+//!  0  system area, zeros, or a GPT with the hybrid option
+//! 16  primary volume descriptor
+//! 17  El Torito boot record
+//! 18  volume descriptor set terminator
+//! 19  path table, little endian
+//! 20  path table, big endian
+//! 21  root directory
+//! 22  boot catalog
+//! 23  the ESP, to the end of the volume
+//! ```
+//!
+//! With the hybrid option the system area holds a protective MBR and a GPT whose one
+//! partition is the embedded ESP, so the same file written to a USB stick boots as a
+//! disk. Its backup table goes after the end of the ISO volume. Dates are left unset so
+//! the same image always gives the same ISO.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use tracing::warn;
+
+use bob_core::err::BobErr;
+use bob_core::gpt::{DiskImage, GptImage, PartitionType};
+use bob_core::guid::Guid;
+use bob_core::table::LayoutPartition;
+use common::snapshot::crc32;
+
+const SECTOR_SZ: usize = 2048;
+const PVD: usize = 16;
+const BOOT_RECORD: usize = 17;
+const TERMINATOR: usize = 18;
+const L_PATH_TABLE: usize = 19;
+const M_PATH_TABLE: usize = 20;
+const ROOT: usize = 21;
+const CATALOG: usize = 22;
+const ESP: usize = 23;
+/// The path table has just the root directory in it.
+const PATH_TABLE_SZ: u32 = 10;
+/// A no emulation entry counts in these, whatever the sector size.
+const VIRTUAL_SECTOR_SZ: u64 = 512;
+/// Catalog sector count that has the firmware load to the end of the volume, for ESPs
+/// too big for the 16 bit count.
+const TO_END: u16 = 1;
+
+/// The hybrid GPT is in 512 byte blocks, 128 entries of 128 bytes after the header.
+const LBA_SZ: u64 = 512;
+const GPT_ENTRIES: usize = 128;
+const GPT_ENTRY_SZ: usize = 128;
+const GPT_ARRAY_LBAS: u64 = (GPT_ENTRIES * GPT_ENTRY_SZ) as u64 / LBA_SZ;
+const GPT_HEADER_SZ: u32 = 92;
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub esp_size: u64,
+    pub file_size: u64,
+}
+
+fn both_endian_u16(buf: &mut [u8], v: u16) {
+    buf[..2].copy_from_slice(&v.to_le_bytes());
+    buf[2..4].copy_from_slice(&v.to_be_bytes());
+}
+
+fn both_endian_u32(buf: &mut [u8], v: u32) {
+    buf[..4].copy_from_slice(&v.to_le_bytes());
+    buf[4..8].copy_from_slice(&v.to_be_bytes());
+}
+
+/// `s` in a field of `len` bytes padded with spaces, as the descriptors' strings are.
+fn padded(s: &str, len: usize) -> Vec<u8> {
+    let mut v = s.as_bytes().to_vec();
+    v.resize(len, b' ');
+    v
+}
+
+/// A directory record for `name` at sector `extent`.
+fn dir_record(name: &[u8], extent: usize, len: u64, dir: bool) -> Vec<u8> {
+    let rec_len = (33 + name.len()).next_multiple_of(2);
+    let mut r = vec![0; rec_len];
+    r[0] = rec_len as u8;
+    both_endian_u32(&mut r[2..10], extent as u32);
+    both_endian_u32(&mut r[10..18], len as u32);
+    // 1970-01-01 00:00 UTC, years count from 1900.
+    r[18..25].copy_from_slice(&[70, 1, 1, 0, 0, 0, 0]);
+    r[25] = if dir { 2 } else { 0 };
+    both_endian_u16(&mut r[28..32], 1);
+    r[32] = name.len() as u8;
+    r[33..33 + name.len()].copy_from_slice(name);
+    r
+}
+
+fn volume_descriptor(ty: u8) -> [u8; SECTOR_SZ] {
+    let mut d = [0; SECTOR_SZ];
+    d[0] = ty;
+    d[1..6].copy_from_slice(b"CD001");
+    d[6] = 1;
+    d
+}
+
+fn primary_descriptor(volume_sectors: u32) -> [u8; SECTOR_SZ] {
+    let mut d = volume_descriptor(1);
+    d[8..40].copy_from_slice(&padded("", 32));
+    d[40..72].copy_from_slice(&padded("YOYO", 32));
+    both_endian_u32(&mut d[80..88], volume_sectors);
+    both_endian_u16(&mut d[120..124], 1);
+    both_endian_u16(&mut d[124..128], 1);
+    both_endian_u16(&mut d[128..132], SECTOR_SZ as u16);
+    both_endian_u32(&mut d[132..140], PATH_TABLE_SZ);
+    d[140..144].copy_from_slice(&(L_PATH_TABLE as u32).to_le_bytes());
+    d[148..152].copy_from_slice(&(M_PATH_TABLE as u32).to_be_bytes());
+    d[156..190].copy_from_slice(&dir_record(&[0], ROOT, SECTOR_SZ as u64, true));
+    d[190..574].copy_from_slice(&padded("", 384));
+    d[574..702].copy_from_slice(&padded("BOB", 128));
+    d[702..813].copy_from_slice(&padded("", 111));
+    // Creation, modification, expiration and effective dates, all unset.
+    for at in [813, 830, 847, 864] {
+	d[at..at + 16].copy_from_slice(b"0000000000000000");
+    }
+    d[881] = 1;
+    d
+}
+
+fn boot_record() -> [u8; SECTOR_SZ] {
+    let mut d = volume_descriptor(0);
+    d[7..7 + 23].copy_from_slice(b"EL TORITO SPECIFICATION");
+    d[71..75].copy_from_slice(&(CATALOG as u32).to_le_bytes());
+    d
+}
+
+fn path_table(big_endian: bool) -> [u8; SECTOR_SZ] {
+    let mut t = [0; SECTOR_SZ];
+    t[0] = 1;
+    let extent = ROOT as u32;
+    t[2..6].copy_from_slice(&if big_endian { extent.to_be_bytes() } else { extent.to_le_bytes() });
+    t[6..8].copy_from_slice(&if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() });
+    t
+}
+
+fn root_directory(esp_size: u64) -> [u8; SECTOR_SZ] {
+    let mut d = [0; SECTOR_SZ];
+    let records = [
+	dir_record(&[0], ROOT, SECTOR_SZ as u64, true),
+	dir_record(&[1], ROOT, SECTOR_SZ as u64, true),
+	dir_record(b"BOOT.CAT;1", CATALOG, SECTOR_SZ as u64, false),
+	dir_record(b"EFI.IMG;1", ESP, esp_size, false),
+    ];
+    let mut at = 0;
+    for r in records {
+	d[at..at + r.len()].copy_from_slice(&r);
+	at += r.len();
+    }
+    d
+}
+
+/// The boot catalog: a validation entry for the EFI platform and a default entry
+/// booting the ESP without emulation.
+fn boot_catalog(esp_size: u64) -> [u8; SECTOR_SZ] {
+    let mut c = [0; SECTOR_SZ];
+    c[0] = 1;
+    c[1] = 0xEF;
+    c[30] = 0x55;
+    c[31] = 0xAA;
+    // The validation entry's 16 bit words sum to zero.
+    let sum = c[..32].chunks(2).fold(0u16, |s, w| s.wrapping_add(u16::from_le_bytes([w[0], w[1]])));
+    c[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    let count = u16::try_from(esp_size.div_ceil(VIRTUAL_SECTOR_SZ)).unwrap_or(TO_END);
+    c[32] = 0x88;
+    c[38..40].copy_from_slice(&count.to_le_bytes());
+    c[40..44].copy_from_slice(&(ESP as u32).to_le_bytes());
+    c
+}
+
+/// Sectors 0 up to the ESP, for an ESP of `esp_size` bytes.
+fn head(esp_size: u64) -> Vec<u8> {
+    let volume_sectors = ESP as u64 + esp_size.div_ceil(SECTOR_SZ as u64);
+    let mut h = vec![0; ESP * SECTOR_SZ];
+    for (at, s) in [
+	(PVD, primary_descriptor(volume_sectors as u32)),
+	(BOOT_RECORD, boot_record()),
+	(TERMINATOR, volume_descriptor(255)),
+	(L_PATH_TABLE, path_table(false)),
+	(M_PATH_TABLE, path_table(true)),
+	(ROOT, root_directory(esp_size)),
+	(CATALOG, boot_catalog(esp_size)),
+    ] {
+	h[at * SECTOR_SZ..(at + 1) * SECTOR_SZ].copy_from_slice(&s);
+    }
+    h
+}
+
+/// The hybrid GPT for an ISO of `iso_size` bytes, as the system area's start and the
+/// backup table that follows the volume.
+fn hybrid_tables(iso_size: u64, disk_guid: Guid, esp: &LayoutPartition, esp_size: u64) -> (Vec<u8>, Vec<u8>) {
+    let last_lba = iso_size / LBA_SZ + GPT_ARRAY_LBAS;
+    let first_usable = (PVD * SECTOR_SZ) as u64 / LBA_SZ;
+    let last_usable = last_lba - GPT_ARRAY_LBAS - 1;
+
+    let mut entries = vec![0; GPT_ENTRIES * GPT_ENTRY_SZ];
+    let e = &mut entries[..GPT_ENTRY_SZ];
+    let first = (ESP * SECTOR_SZ) as u64 / LBA_SZ;
+    e[..16].copy_from_slice(&esp.type_guid.to_bytes());
+    e[16..32].copy_from_slice(&esp.unique_guid.to_bytes());
+    e[32..40].copy_from_slice(&first.to_le_bytes());
+    e[40..48].copy_from_slice(&(first + esp_size / LBA_SZ - 1).to_le_bytes());
+    e[48..56].copy_from_slice(&esp.attributes.to_le_bytes());
+    for (i, c) in esp.name.encode_utf16().take(36).enumerate() {
+	e[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+    let entries_crc = crc32(&entries);
+
+    let header = |my_lba: u64, alt_lba: u64, entries_lba: u64| {
+	let mut h = [0; LBA_SZ as usize];
+	h[..8].copy_from_slice(b"EFI PART");
+	h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+	h[12..16].copy_from_slice(&GPT_HEADER_SZ.to_le_bytes());
+	h[24..32].copy_from_slice(&my_lba.to_le_bytes());
+	h[32..40].copy_from_slice(&alt_lba.to_le_bytes());
+	h[40..48].copy_from_slice(&first_usable.to_le_bytes());
+	h[48..56].copy_from_slice(&last_usable.to_le_bytes());
+	h[56..72].copy_from_slice(&disk_guid.to_bytes());
+	h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+	h[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+	h[84..88].copy_from_slice(&(GPT_ENTRY_SZ as u32).to_le_bytes());
+	h[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+	let crc = crc32(&h[..GPT_HEADER_SZ as usize]);
+	h[16..20].copy_from_slice(&crc.to_le_bytes());
+	h
+    };
+
+    // A protective MBR covering the disk, as far as 32 bits go.
+    let mut primary = vec![0; LBA_SZ as usize];
+    let mbr = &mut primary[446..462];
+    mbr[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    mbr[4] = 0xEE;
+    mbr[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    mbr[8..12].copy_from_slice(&1u32.to_le_bytes());
+    mbr[12..16].copy_from_slice(&u32::try_from(last_lba).unwrap_or(u32::MAX).to_le_bytes());
+    primary[510..512].copy_from_slice(&[0x55, 0xAA]);
+    primary.extend_from_slice(&header(1, last_lba, 2));
+    primary.extend_from_slice(&entries);
+
+    let mut backup = entries;
+    backup.extend_from_slice(&header(last_lba, 1, last_lba - GPT_ARRAY_LBAS));
+    (primary, backup)
+}
+
+/// Write the ISO holding `img`'s ESP to `out`.
+fn write(img: &mut GptImage, out: &mut impl Write, hybrid: bool) -> Result<Stats, BobErr> {
+    let layout = img.layout();
+    let name = img.partitions_of_type(PartitionType::EFISystem).into_iter().next()
+	.ok_or_else(|| BobErr::PartitionNotFound(String::from("EFI system partition")))?;
+    let index = img.find_by_name(&name).ok_or_else(|| BobErr::PartitionNotFound(name.clone()))?;
+    if layout.partitions.len() > 1 {
+	warn!(partitions = layout.partitions.len(), "only the ESP goes in the ISO, the other partitions are left out");
+    }
+    let esp = &layout.partitions[index];
+    let esp_size = (esp.last_lba - esp.first_lba + 1) * layout.sector_size;
+
+    let mut head = head(esp_size);
+    let iso_size = (ESP * SECTOR_SZ) as u64 + esp_size.next_multiple_of(SECTOR_SZ as u64);
+    let backup = if hybrid {
+	let (primary, backup) = hybrid_tables(iso_size, layout.disk_guid, esp, esp_size);
+	head[..primary.len()].copy_from_slice(&primary);
+	backup
+    } else {
+	Vec::new()
+    };
+    out.write_all(&head).map_err(BobErr::IO)?;
+    img.extract_partition(index, out)?;
+    let padding = iso_size - head.len() as u64 - esp_size;
+    io::copy(&mut io::repeat(0).take(padding), out).map_err(BobErr::IO)?;
+    out.write_all(&backup).map_err(BobErr::IO)?;
+    out.flush().map_err(BobErr::IO)?;
+    Ok(Stats { esp_size, file_size: iso_size + backup.len() as u64 })
+}
+
+/// Replace the GPT image at `path` with an ISO of its ESP, hybrid or not. The ISO is
+/// written next to it and renamed over it once complete.
+pub fn convert_in_place(path: &Path, hybrid: bool) -> Result<Stats, BobErr> {
+    let mut img = GptImage::open_read_only(&path.to_string_lossy())?;
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".iso.tmp");
+    let tmp = Path::new(&tmp_name);
+    let mut out = io::BufWriter::new(File::create(tmp).map_err(BobErr::IO)?);
+    let stats = match write(&mut img, &mut out, hybrid) {
+	Ok(stats) => stats,
+	Err(e) => {
+	    drop(out);
+	    let _ = std::fs::remove_file(tmp);
+	    return Err(e);
+	},
+    };
+    drop(out);
+    drop(img);
+    std::fs::rename(tmp, path).map_err(BobErr::IO)?;
+    Ok(stats)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use bob_core::{DiskImgBuilder, PartitionBuilder};
+
+    #[allow(dead_code)]
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+	u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    #[allow(dead_code)]
+    fn u64_at(buf: &[u8], at: usize) -> u64 {
+	u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn boot_catalog_checks_out() {
+	let c = boot_catalog(2 * 1024 * 1024);
+	let sum = c[..32].chunks(2).fold(0u16, |s, w| s.wrapping_add(u16::from_le_bytes([w[0], w[1]])));
+	assert_eq!(sum, 0);
+	assert_eq!((c[1], c[32], c[33]), (0xEF, 0x88, 0));
+	assert_eq!(u16::from_le_bytes([c[38], c[39]]), 4096);
+	assert_eq!(u32_at(&c, 40), ESP as u32);
+	let big = boot_catalog(64 * 1024 * 1024);
+	assert_eq!(u16::from_le_bytes([big[38], big[39]]), TO_END);
+    }
+
+    #[test]
+    fn wraps_the_esp() {
+	let dir = std::env::temp_dir().join(format!("bob-iso-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let path = dir.join("disk.img");
+	let esp = PartitionBuilder::new().partition_type(PartitionType::EFISystem).size(1024 * 1024).build().unwrap();
+	let mut img = DiskImgBuilder::new().output_file(path.to_str().unwrap()).total_size(4 * 1024 * 1024).partition(esp).build().unwrap();
+	bob_core::write_partition_bytes(&mut img.partition_view(0).unwrap(), 0, 4, &mut &b"FAT!"[..]).unwrap();
+	let disk_guid = img.layout().disk_guid;
+	drop(img);
+
+	for hybrid in [false, true] {
+	    let mut img = GptImage::open_read_only(path.to_str().unwrap()).unwrap();
+	    let mut iso = Vec::new();
+	    let stats = write(&mut img, &mut iso, hybrid).unwrap();
+	    assert_eq!(stats, Stats { esp_size: 1024 * 1024, file_size: iso.len() as u64 });
+	    let sector = |n: usize| &iso[n * SECTOR_SZ..(n + 1) * SECTOR_SZ];
+	    assert_eq!(&sector(PVD)[..7], b"\x01CD001\x01");
+	    assert_eq!(u32_at(sector(PVD), 80) as usize, ESP + 512);
+	    assert_eq!(&sector(BOOT_RECORD)[7..30], b"EL TORITO SPECIFICATION");
+	    assert_eq!(sector(TERMINATOR)[0], 255);
+	    assert_eq!(&sector(ESP)[..4], b"FAT!");
+	    let root = sector(ROOT);
+	    assert_eq!(&root[34 * 2 + 33..34 * 2 + 43], b"BOOT.CAT;1");
+
+	    if !hybrid {
+		assert_eq!(iso.len(), (ESP + 512) * SECTOR_SZ);
+		assert!(iso[..PVD * SECTOR_SZ].iter().all(|b| *b == 0));
+		continue;
+	    }
+	    let lba = |n: u64| &iso[(n * LBA_SZ) as usize..((n + 1) * LBA_SZ) as usize];
+	    let last = iso.len() as u64 / LBA_SZ - 1;
+	    assert_eq!(lba(0)[450], 0xEE);
+	    for (hdr, entries) in [(lba(1), 2), (lba(last), last - GPT_ARRAY_LBAS)] {
+		assert_eq!(&hdr[..8], b"EFI PART");
+		let mut zeroed = hdr[..GPT_HEADER_SZ as usize].to_vec();
+		zeroed[16..20].fill(0);
+		assert_eq!(crc32(&zeroed), u32_at(hdr, 16));
+		assert_eq!(u64_at(hdr, 72), entries);
+		let array = &iso[(entries * LBA_SZ) as usize..][..GPT_ENTRIES * GPT_ENTRY_SZ];
+		assert_eq!(crc32(array), u32_at(hdr, 88));
+		assert_eq!(Guid::from_bytes(hdr[56..72].try_into().unwrap()), disk_guid);
+		// The ESP entry points at EFI.IMG.
+		assert_eq!(u64_at(array, 32), (ESP * SECTOR_SZ) as u64 / LBA_SZ);
+		assert_eq!(&iso[(u64_at(array, 32) * LBA_SZ) as usize..][..4], b"FAT!");
+	    }
+	}
+	let _ = std::fs::remove_dir_all(&dir);
+    }
+}
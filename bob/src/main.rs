@@ -1,16 +1,38 @@
 mod cmd;
-mod err;
-mod fat;
-mod gpt;
-mod guid;
+mod crypt;
+mod demangle;
+mod diff;
+mod flamegraph;
+mod golden;
+mod hex;
+mod incremental;
+mod iso;
+mod manifest;
+mod monitor;
+mod pipeline;
+mod provision;
+mod qcow2;
+mod serve;
+mod sign;
+mod snapshot;
+mod squashfs;
+mod verity;
+mod versions;
+mod watch;
+mod vhd;
+mod vmdk;
 
 use clap::{
-    arg, command, Arg, Command, value_parser,
+    arg, command, Arg, ArgGroup, Command, value_parser,
+    builder::{PossibleValuesParser, TypedValueParser},
     error::ErrorKind,
 };
-use cmd::{create_disk_image, write_fat_fs};
-use err::BobErr;
-use gpt::{PartitionInput, PartitionBuilder, PartitionType};
+use cmd::{
+    add_partition, apply_table, clone_partition, create_disk_image, create_from_manifest, delete_partition, diff, encrypt_partition, export_table, extract_partition, flamegraph, inspect, keygen, monitor, pack_squashfs, plan_disk_image, provision, serve, sign,
+    receive_snapshot, resize_partition, update_disk_image, verify, verity, watch, wipe_partition, write_partition,
+};
+use bob_core::err::BobErr;
+use bob_core::gpt::{parse_attributes, parse_size, PartitionInput, PartitionBuilder, PartitionType, MIN_PARTITION_ENTRIES};
 
 #[derive(Clone)]
 struct PartitionParser;
@@ -34,18 +56,34 @@ impl clap::builder::TypedValueParser for PartitionParser {
 	    for field in val.split(',') {
 		// fields "should be" one of:
 		// - t=<value>
+		// - n=<value>
 		// - so=<value>
 		// - eo=<value>
-		// where t, so, and eo stand for type, start offset, and end offset respectively
+		// - s=<value>
+		// - a=<value>
+		// - u=<value>
+		// where t, n, so, eo, s, a and u stand for type, name, start offset, end offset, size, attributes and unique GUID respectively
 		if let Some((key, value)) = field.split_once('=') {
 		    if key == "t" {
-			partition_builder = partition_builder.partition_type(PartitionType::EFISystem);
+			let pt = value.trim().parse::<PartitionType>().map_err(|_| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
+			partition_builder = partition_builder.partition_type(pt);
+		    } else if key == "n" {
+			partition_builder = partition_builder.name(value);
 		    } else if key == "so" {
 			let so = value.trim().parse::<usize>().map_err(|_| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
 			partition_builder = partition_builder.start_offset(so);
 		    } else if key == "eo" {
 			let eo = value.parse::<usize>().map_err(|_| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
 			partition_builder = partition_builder.end_offset(eo);
+		    } else if key == "s" {
+			let size = parse_size(value).ok_or_else(|| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
+			partition_builder = partition_builder.size(size);
+		    } else if key == "u" {
+			let guid = value.trim().parse::<bob_core::guid::Guid>().map_err(|_| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
+			partition_builder = partition_builder.unique_guid(guid);
+		    } else if key == "a" {
+			let attributes = parse_attributes(value).ok_or_else(|| clap::Error::new(ErrorKind::InvalidValue).with_cmd(cmd))?;
+			partition_builder = partition_builder.attributes(attributes);
 		    }
 		}
 	    }
@@ -60,36 +98,510 @@ fn main() -> Result<(), BobErr> {
     let matches = command!()
         .subcommand_required(true)
         .arg_required_else_help(true)
+	.arg(arg!(-v --verbose ... "Log on-disk structures as they're written, -vv for every partition entry and data write")
+	    .global(true))
         .subcommand(
 	    Command::new("create")
 		.about("Create a new disk image")
 		.args(&[
-		    arg!(-o --output <FILE> "Output filename"),
+		    arg!(-o --output <FILE> "Output filename, or a block device such as /dev/sdX to write a USB stick directly"),
 		    arg!(-s --size <SIZE> "Total size of the desired disk image")
-			.required(true)
+			.required_unless_present_any(["manifest", "config"])
 			.value_parser(value_parser!(usize)),
+		    arg!(--manifest <FILE> "Build every target of a JSON manifest of shared partitions and per-target overrides, instead of one image from the arguments")
+			.conflicts_with_all(["output", "size", "partition", "align", "sector-size", "table", "max-partitions", "hybrid-mbr", "seed", "deterministic", "disk-guid", "format", "threads"]),
+		    arg!(--config <FILE> "Lay the image out from a TOML file of size, sector size and partitions with their contents, instead of -s and -p. -o overrides its output")
+			.conflicts_with_all(["manifest", "size", "partition", "align", "sector-size", "table", "max-partitions", "hybrid-mbr", "seed", "deterministic", "disk-guid"]),
 		    Arg::new("partition").short('p').required(false)
 			.action(clap::ArgAction::Append)
 			.value_parser(PartitionParser {})
-			.value_name("t=<type>,so=<offset>,eo=<offset>")
-			.help("A GPT partition specification. t=<val> specifies the parition type, so=<val> is the start offset, eo=<val> is the end offset.")
+			.value_name("t=<type>,n=<name>,so=<offset>,eo=<offset>|s=<size>,a=<attributes>,u=<guid>")
+			.help("A partition specification. t=<val> specifies the parition type (esp, linux, swap, msdata, bios, root, provision or a type GUID), n=<val> names it (up to 36 characters, defaults to the type's name), so=<val> is the start offset, eo=<val> is the end offset. Instead of eo=<val>, s=<val> gives the size (e.g. 64M), without so=<val> the partition is placed after the previous one on the --align boundary. a=<val> sets attribute bits: required, no-block-io, legacy-boot or guid:<48-63>, joined with +. u=<val> pins the unique partition GUID (e.g. for root=PARTUUID=), random otherwise."),
+		    arg!(--align <SIZE> "Partition alignment, e.g. 4K or 1M. Partitions placed by size start on it, explicit offsets off it get a warning")
+			.default_value("1M")
+			.value_parser(|s: &str| parse_size(s).ok_or("expected a size like 1M")),
+		    arg!(--"sector-size" <BYTES> "Logical block size of the image, 4096 for 4Kn disks")
+			.default_value("512")
+			.value_parser(PossibleValuesParser::new(["512", "4096"]).map(|s| s.parse::<usize>().unwrap())),
+		    arg!(--table <TABLE> "Partition table to write. mbr writes a classic MBR with up to four primary partitions and no GPT structures")
+			.value_parser(["gpt", "mbr"])
+			.default_value("gpt"),
+		    arg!(--"max-partitions" <N> "Number of entries in the GPT partition table, at least 128")
+			.default_value("128")
+			.value_parser(value_parser!(u32).range(MIN_PARTITION_ENTRIES as i64..)),
+		    arg!(--"hybrid-mbr" <N> "Mirror up to three partitions, numbered from 1 in -p order, into the MBR for BIOS booting, e.g. 1,2. A partition with a=legacy-boot is marked active")
+			.value_delimiter(',')
+			.value_parser(value_parser!(u64).range(1..)),
+		    arg!(--"zero-partitions" [MODE] "Clear old data in the partitions when writing to a device or over an old image: full, or quick for the first and last 1 MiB of each")
+			.value_parser(["full", "quick"])
+			.default_missing_value("full"),
+		    arg!(--"dry-run" "Validate and print the planned layout without writing anything"),
+		    arg!(--full "With --manifest, build every target from scratch instead of refilling only the partitions whose contents changed since the last build")
+			.requires("manifest"),
+		    arg!(--format <FORMAT> "Image file format. qcow2 can be attached to QEMU or libvirt as is, vhd (fixed size) to Hyper-V or Azure, vmdk (a descriptor plus NAME-flat.vmdk) to VMware or VirtualBox, vmdk-stream (streamOptimized) goes in OVAs, iso wraps the ESP in an El Torito CD image and iso-hybrid adds a GPT so it boots from USB too")
+			.value_parser(["raw", "qcow2", "vhd", "vmdk", "vmdk-stream", "iso", "iso-hybrid"])
+			.default_value("raw"),
+		    arg!(--threads <N> "Threads compressing a vmdk-stream image, one per CPU by default. Only for --format vmdk-stream, the other formats aren't compressed")
+			.value_parser(value_parser!(usize)),
+		    arg!(--seed <N> "Seed the disk and partition GUIDs, so the same command gives a byte-identical image")
+			.value_parser(value_parser!(u64)),
+		    arg!(--deterministic "Same as --seed 0")
+			.conflicts_with("seed"),
+		    arg!(--"disk-guid" <GUID> "GPT disk GUID to give the image instead of a random (or seeded) one")
+			.value_parser(|s: &str| s.parse::<bob_core::guid::Guid>().map_err(|_| "expected a GUID")),
+		    arg!(--"yes-i-know" "Confirm that the block device given with -o gets erased"),
+		    io_backend_arg(),
 		])
 	)
 	.subcommand(
 	    Command::new("update")
 		.about("Update a disk image")
-		.arg(arg!(-i --image <FILE> "Disk image file to update"))
+		.arg(arg!(-i --image <FILE> "Disk image file to update")
+		     .required(true))
+		.arg(arg!(--"dry-run" "Print the table and what rewriting it would change without writing anything"))
+	)
+	.subcommand(
+	    Command::new("add-partition")
+		.about("Add partitions to an existing disk image")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image to add the partitions to")
+			.required(true),
+		    Arg::new("partition").short('p').required(true)
+			.action(clap::ArgAction::Append)
+			.value_parser(PartitionParser {})
+			.value_name("t=<type>,n=<name>,so=<offset>,eo=<offset>|s=<size>,a=<attributes>,u=<guid>")
+			.help("A partition specification, as for create. Without so=<val> the partition goes in the first free space it fits in."),
+		    arg!(--align <SIZE> "Partition alignment, e.g. 4K or 1M")
+			.default_value("1M")
+			.value_parser(|s: &str| parse_size(s).ok_or("expected a size like 1M")),
+		])
+	)
+	.subcommand(
+	    Command::new("delete-partition")
+		.about("Remove a partition from an existing disk image")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image to remove the partition from")
+			.required(true),
+		    arg!(--index <N> "Number of the partition, as shown by inspect. The others keep their numbers")
+			.value_parser(value_parser!(usize)),
+		    arg!(--wipe "Zero the start of the partition so its filesystem signatures go too"),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["index", "name", "guid"]).required(true))
+	)
+	.subcommand(
+	    Command::new("resize-partition")
+		.about("Grow or shrink a partition of an existing disk image. If the image file has been extended (e.g. with fallocate) the new space is made usable first")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    arg!(--index <N> "Number of the partition, as shown by inspect")
+			.value_parser(value_parser!(usize)),
+		    arg!(-s --size <SIZE> "New size of the partition, e.g. 64M")
+			.value_parser(|s: &str| parse_size(s).ok_or("expected a size like 64M")),
+		    arg!(--max "Grow the partition into all the free space after it"),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["index", "name", "guid"]).required(true))
+		.group(ArgGroup::new("how").args(["size", "max"]).required(true))
+	)
+	.subcommand(
+	    Command::new("wipe")
+		.about("Zero a partition of an existing disk image so it can be reused, filesystem signatures and all")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    arg!(--index <N> "Number of the partition, as shown by inspect")
+			.visible_alias("partition")
+			.value_parser(value_parser!(usize)),
+		    arg!(--quick "Only zero the first and last MiB, where signatures live"),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["index", "name", "guid"]).required(true))
+	)
+	.subcommand(
+	    Command::new("write")
+		.about("Copy a file into a partition of a disk image, e.g. a prebuilt filesystem or a bootloader blob")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    partition_arg(),
+		    arg!(--index <N> "Number of the partition, as shown by inspect")
+			.value_parser(value_parser!(usize)),
+		    arg!(--input <FILE> "File to write into the partition")
+			.required(true),
+		    arg!(--offset <BYTES> "Where in the partition to start writing, e.g. 4K")
+			.default_value("0")
+			.value_parser(|s: &str| parse_size(s).ok_or("expected a size like 4K")),
+		    io_backend_arg(),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["partition", "index", "name", "guid"]).required(true))
+	)
+	.subcommand(
+	    Command::new("clone-partition")
+		.about("Copy a partition's contents into a partition of another image, e.g. to promote a tested ESP into a new layout")
+		.args(&[
+		    arg!(--from <SOURCE> "Image and partition to copy, as IMAGE:PARTITION with the partition by number or name, e.g. a.img:1")
+			.required(true),
+		    arg!(--to <DESTINATION> "Image and partition to copy to, at least as large as the source, e.g. b.img:2")
+			.required(true),
+		])
+	)
+	.subcommand(
+	    Command::new("extract")
+		.about("Copy a partition's contents out of a disk image, e.g. the ESP to mount or diff it")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    partition_arg(),
+		    arg!(--index <N> "Number of the partition, as shown by inspect")
+			.value_parser(value_parser!(usize)),
+		    arg!(-o --output <FILE> "File to write the partition to")
+			.required(true),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["partition", "index", "name", "guid"]).required(true))
+	)
+	.subcommand(
+	    Command::new("inspect")
+		.about("Print an image's GPT headers and partition table")
+		.arg(arg!(-i --image <FILE> "Disk image to inspect")
+		     .required(true))
+		.arg(arg!(--json "Print the headers and partition entries as JSON"))
+	)
+	.subcommand(
+	    Command::new("verify")
+		.about("Check an image's GPT headers, entry arrays and partition ranges, exits 1 if any check fails")
+		.arg(arg!(-i --image <FILE> "Disk image to check")
+		     .required(true))
+		.arg(arg!(--json "Print the check results as JSON"))
+	)
+	.subcommand(
+	    Command::new("diff")
+		.about("Compare two images' GPT headers and partition entries, exits 1 if they differ")
+		.args(&[
+		    arg!(<OLD> "Image to compare against"),
+		    arg!(<NEW> "Image to compare"),
+		    arg!(--content "Also compare each partition's contents by SHA-256"),
+		])
+	)
+	.subcommand(
+	    Command::new("export-table")
+		.about("Export a disk image's partition table")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image to read the partition table from")
+			.required(true),
+		    arg!(-f --format <FORMAT> "Output format")
+			.value_parser(["json", "sfdisk"])
+			.default_value("json"),
+		    arg!(-o --output <FILE> "Write the table here instead of stdout"),
+		])
+	)
+	.subcommand(
+	    Command::new("apply-table")
+		.about("Create a new disk image with a previously exported partition table")
+		.args(&[
+		    arg!(-t --table <FILE> "Exported partition table")
+			.required(true),
+		    arg!(-f --format <FORMAT> "Format of the table, guessed from its contents if not given")
+			.value_parser(["json", "sfdisk"]),
+		    arg!(-o --output <FILE> "Output filename"),
+		    arg!(-s --size <SIZE> "Total size of the disk image, defaults to the size the table describes")
+			.value_parser(value_parser!(usize)),
+		])
+	)
+	.subcommand(
+	    Command::new("watch")
+		.about("Build a manifest's or layout's images, then rebuild the partitions whose contents change and restart QEMU, until interrupted")
+		.args(&[
+		    arg!(--manifest <FILE> "JSON manifest of the images to build, as for create --manifest"),
+		    arg!(--config <FILE> "TOML layout of a single image, as for create --config"),
+		    arg!(--qemu <COMMAND> "Command to run once the images are built and restart after every rebuild, e.g. \"qemu-system-x86_64 -drive file=disk.img,format=raw\""),
+		    arg!(--interval <MS> "How often to look for changes, in milliseconds")
+			.default_value("500")
+			.value_parser(value_parser!(u64).range(10..)),
+		    io_backend_arg(),
+		])
+		.group(ArgGroup::new("spec").args(["manifest", "config"]).required(true))
+	)
+	.subcommand(
+	    Command::new("serve")
+		.about("Serve the bootloader and kernel over TFTP and HTTP for netbooting")
+		.args(&[
+		    arg!(--bootloader <FILE> "Bootloader EFI binary, served as bootx64.efi")
+			.required(true),
+		    arg!(--kernel <FILE> "Kernel binary, served as kernel")
+			.required(true),
+		    arg!(--config <FILE> "Optional boot config, served as config"),
+		    arg!(--addr <ADDR> "Address to listen on")
+			.default_value("0.0.0.0"),
+		    arg!(--"tftp-port" <PORT> "TFTP port to listen on")
+			.value_parser(value_parser!(u16))
+			.default_value("69"),
+		    arg!(--"http-port" <PORT> "HTTP port to listen on")
+			.value_parser(value_parser!(u16))
+			.default_value("8080"),
+		])
+	)
+	.subcommand(
+	    Command::new("monitor")
+		.about("Follow a kernel's console, decoding its boot progress, test results and files. Exits 1 unless the kernel exits with 0 and no test failed")
+		.args(&[
+		    arg!(-i --input <FILE> "Serial device or captured console log to read, - for stdin"),
+		    arg!(--listen <ADDR> "Accept one TCP connection on ADDR (e.g. 0.0.0.0:5556) and read from it"),
+		    arg!(-o --output <DIR> "Directory to write files the kernel sends to, they're dropped if not given"),
+		])
+		.group(ArgGroup::new("from").args(["input", "listen"]).required(true))
+	)
+	.subcommand(
+	    Command::new("flamegraph")
+		.about("Symbolize kernel profiler samples from a console log into folded stacks for flamegraph.pl or inferno")
+		.args(&[
+		    arg!(-i --input <FILE> "Serial device or captured console log with the kernel's @prof lines, - for stdin")
+			.required(true),
+		    arg!(-k --kernel <FILE> "The kernel ELF that was running, with its symbols")
+			.required(true),
+		    arg!(-o --output <FILE> "File to write the folded stacks to, stdout if not given"),
+		])
+	)
+	.subcommand(
+	    Command::new("snapshot")
+		.about("Receive a filesystem snapshot exported by a running kernel over serial or TCP")
+		.args(&[
+		    arg!(-i --input <FILE> "Serial device or captured console log to read the snapshot from"),
+		    arg!(--listen <ADDR> "Accept one TCP connection on ADDR (e.g. 0.0.0.0:5555) and read the snapshot from it"),
+		    arg!(-o --output <DIR> "Directory to write the files to")
+			.required(true),
+		])
+		.group(ArgGroup::new("from").args(["input", "listen"]).required(true))
+	)
+	.subcommand(
+	    Command::new("squashfs")
+		.about("Pack a directory into a compressed read-only squashfs filesystem")
+		.args(&[
+		    arg!(-d --dir <DIR> "Directory to pack")
+			.required(true),
+		    arg!(-o --output <FILE> "Output filename")
+			.required(true),
+		    arg!(--deterministic "Use SOURCE_DATE_EPOCH (or 0) for every timestamp, so the same tree gives the same image"),
+		])
+	)
+	.subcommand(
+	    Command::new("verity")
+		.about("Build a hash tree over a read-only partition into a companion hash partition")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image holding both partitions")
+			.required(true),
+		    arg!(--data <NAME> "Name of the read-only partition to protect")
+			.required(true),
+		    arg!(--hash <NAME> "Name of the partition to write the hash tree to")
+			.required(true),
+		    arg!(--salt <HEX> "Salt as hex, random if not given")
+			.value_parser(|s: &str| hex::decode(s).ok_or("expected an even number of hex digits")),
+		])
+	)
+	.subcommand(
+	    Command::new("encrypt")
+		.about("Format a partition as an encrypted container (AES-XTS), optionally filled from a plain image")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    partition_arg(),
+		    arg!(--index <N> "Number of the partition, as shown by inspect")
+			.value_parser(value_parser!(usize)),
+		    arg!(--"key-file" <FILE> "File holding the passphrase; a trailing newline is ignored")
+			.required(true),
+		    arg!(--input <FILE> "Plain image to encrypt into the partition")
+			.required(false),
+		    arg!(--iterations <N> "PBKDF2 iterations for deriving the key")
+			.default_value("100000")
+			.value_parser(value_parser!(u32).range(1..)),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["partition", "index", "name", "guid"]).required(true))
+	)
+	.subcommand(
+	    Command::new("provision")
+		.about("Write settings (hostname, console, test parameters) for the kernel to apply on its first boot into a provisioning partition")
+		.args(&[
+		    arg!(-i --image <FILE> "Disk image with the partition")
+			.required(true),
+		    arg!(--index <N> "Number of the partition, as shown by inspect; the first provisioning partition by default")
+			.value_parser(value_parser!(usize)),
+		    arg!(-c --config <FILE> "Settings in the format of the kernel's config.toml, plus hostname under [system]"),
+		    arg!(--set <SETTING> "A setting as section.key=value, e.g. system.hostname=ci-1; wins over the file's")
+			.action(clap::ArgAction::Append),
+		])
+		.args(partition_selector())
+		.group(ArgGroup::new("which").args(["index", "name", "guid"]))
+		.group(ArgGroup::new("settings").args(["config", "set"]).multiple(true).required(true))
+	)
+	.subcommand(
+	    Command::new("keygen")
+		.about("Generate an Ed25519 key pair for signing boot configuration")
+		.arg(arg!(-o --output <NAME> "Writes the secret key to NAME.key and the public key to NAME.pub")
+		     .required(true))
+	)
+	.subcommand(
+	    Command::new("sign")
+		.about("Sign files (boot config, kernels) for the bootloader to check, writing FILE.sig")
+		.args(&[
+		    arg!(-k --key <FILE> "Secret key from keygen")
+			.required_unless_present("check"),
+		    arg!(--check <PUBKEY> "Check the files' existing signatures against this public key instead")
+			.conflicts_with("key"),
+		    arg!(<FILE> ... "Files to sign"),
+		])
 	)
 	.get_matches();
 
+    init_tracing(matches.get_count("verbose"));
+
     if let Some(sub_matches) = matches.subcommand_matches("create") {
-	let mut img = create_disk_image(sub_matches)?;
-        return write_fat_fs(&mut img);
+	if sub_matches.contains_id("manifest") {
+	    return create_from_manifest(sub_matches);
+	}
+	if sub_matches.get_flag("dry-run") {
+	    return plan_disk_image(sub_matches);
+	}
+	return create_disk_image(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("update") {
+	return update_disk_image(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("add-partition") {
+	return add_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("delete-partition") {
+	return delete_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("resize-partition") {
+	return resize_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("wipe") {
+	return wipe_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("write") {
+	return write_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("clone-partition") {
+	return clone_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("extract") {
+	return extract_partition(sub_matches);
     }
 
-    if let Some(_sub_matches) = matches.subcommand_matches("update") {
-        todo!("Updating GPT disk images is not yet implemented :(");
+    if let Some(sub_matches) = matches.subcommand_matches("inspect") {
+	return inspect(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("diff") {
+	return diff(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("verify") {
+	return verify(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("export-table") {
+	return export_table(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("apply-table") {
+	return apply_table(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("watch") {
+	return watch(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("serve") {
+	return serve(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("monitor") {
+	return monitor(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("flamegraph") {
+	return flamegraph(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("snapshot") {
+	return receive_snapshot(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("squashfs") {
+	return pack_squashfs(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("verity") {
+	return verity(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("encrypt") {
+	return encrypt_partition(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("provision") {
+	return provision(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("keygen") {
+	return keygen(sub_matches);
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("sign") {
+	return sign(sub_matches);
     }
 
     Ok(())
 }
+
+/// Log to stderr so verbose output doesn't get mixed into tables written to stdout.
+fn init_tracing(verbosity: u8) {
+    let level = match verbosity {
+	0 => tracing::Level::WARN,
+	1 => tracing::Level::DEBUG,
+	_ => tracing::Level::TRACE,
+    };
+
+    tracing_subscriber::fmt()
+	.with_max_level(level)
+	.with_target(false)
+	.without_time()
+	.with_writer(std::io::stderr)
+	.init();
+}
+
+/// The --name and --guid ways of picking a partition of an existing image, next to an
+/// --index the subcommand describes itself.
+fn partition_selector() -> [Arg; 2] {
+    [
+	arg!(--name <NAME> "Name of the partition"),
+	arg!(--guid <GUID> "Unique GUID of the partition")
+	    .value_parser(|s: &str| s.parse::<bob_core::guid::Guid>().map_err(|_| "expected a GUID")),
+    ]
+}
+
+/// Picks a partition by number or name in one argument. The subcommands that take it keep
+/// --index, --name and --guid too.
+fn partition_arg() -> Arg {
+    arg!(--partition <PART> "Partition by number, as shown by inspect, or by name")
+}
+
+/// How partition contents are read and written, for the subcommands that write a lot of them.
+fn io_backend_arg() -> Arg {
+    arg!(--"io-backend" <BACKEND> "How partition contents are written: through the file, or mmap to map the image and copy in memory, faster for many small scattered writes")
+	.value_parser(["file", "mmap"])
+	.default_value("file")
+}
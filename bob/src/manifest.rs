@@ -0,0 +1,404 @@
+//! Building several disk images from one manifest.
+//!
+//! A debug image, a release image and a recovery image usually share most of their
+//! partitions and differ in a size or a root filesystem. A manifest defines the
+//! partitions once and lists the targets, each picking partitions and overriding what
+//! differs:
+//!
+//! ```json
+//! {
+//!   "size": "64M",
+//!   "partitions": [
+//!     { "name": "ESP", "type": "esp", "size": "16M" },
+//!     { "name": "root", "type": "root", "size": "32M", "contents": { "squashfs": "rootfs" } }
+//!   ],
+//!   "targets": [
+//!     { "output": "debug.img" },
+//!     { "output": "release.img", "seed": 0, "overrides": { "root": { "contents": { "squashfs": "rootfs-release" } } } },
+//!     { "output": "recovery.img", "size": "24M", "partitions": ["ESP"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Top level settings (`size`, `align`, `sector_size`, `table`, `seed`) are defaults a
+//! target can override. Paths are relative to the manifest. Partition contents are built
+//! once per run and shared by every target using them, so a squashfs image two targets
+//! put in their root partition is packed only once. Building a target again refills
+//! only the partitions whose contents changed, see incremental.rs.
+//!
+//! A single image can also be laid out in TOML for `create --config`, with the same
+//! settings and partitions and no targets:
+//!
+//! ```toml
+//! output = "disk.img"
+//! size = "64M"
+//! sector_size = 512
+//!
+//! [[partition]]
+//! name = "ESP"
+//! type = "esp"
+//! size = "16M"
+//!
+//! [[partition]]
+//! name = "root"
+//! type = "root"
+//! size = "32M"
+//! contents = { squashfs = "rootfs" }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::Deserialize;
+use tracing::debug;
+
+use bob_core::err::BobErr;
+use bob_core::gpt::{parse_attributes, parse_size, write_partition_bytes, DiskImgBuilder, Partition, PartitionBuilder, PartitionTable, PartitionType};
+
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(flatten)]
+    pub defaults: Settings,
+    pub partitions: Vec<PartitionDef>,
+    pub targets: Vec<Target>,
+}
+
+/// Image wide settings, each falling back to the manifest's and then to `create`'s
+/// defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Settings {
+    pub size: Option<String>,
+    pub align: Option<String>,
+    pub sector_size: Option<usize>,
+    pub table: Option<String>,
+    pub seed: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionDef {
+    pub name: String,
+    /// A short type name as for `-p t=`, or a type GUID.
+    #[serde(rename = "type")]
+    pub ptype: String,
+    pub size: Option<String>,
+    pub attributes: Option<String>,
+    pub contents: Option<Contents>,
+}
+
+/// What a target changes about a shared partition.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionOverride {
+    #[serde(rename = "type")]
+    pub ptype: Option<String>,
+    pub size: Option<String>,
+    pub attributes: Option<String>,
+    pub contents: Option<Contents>,
+}
+
+/// What gets written into a partition after the image is created.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Contents {
+    /// A file copied in as it is, e.g. a prebuilt filesystem image.
+    File(PathBuf),
+    /// A directory packed into a squashfs image.
+    Squashfs(PathBuf),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    pub output: String,
+    #[serde(flatten)]
+    pub settings: Settings,
+    /// Names of the partitions the target has, in order. All of them if missing.
+    pub partitions: Option<Vec<String>>,
+    #[serde(default)]
+    pub overrides: BTreeMap<String, PartitionOverride>,
+}
+
+/// One image laid out in a TOML file.
+#[derive(Debug, Deserialize)]
+pub struct Layout {
+    /// Relative to the file, `-o` takes precedence.
+    pub output: Option<String>,
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(rename = "partition", default)]
+    pub partitions: Vec<PartitionDef>,
+}
+
+/// One target, ready to build.
+pub struct TargetPlan {
+    pub output: PathBuf,
+    pub builder: DiskImgBuilder,
+    /// Partitions to fill after the image is written, by name.
+    pub contents: Vec<(String, Contents)>,
+    /// The resolved settings and partitions, all that shapes the image but the contents.
+    /// An image built from the same layout can be refilled in place.
+    pub layout: String,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self, BobErr> {
+	let s = fs::read_to_string(path).map_err(BobErr::IO)?;
+	serde_json::from_str(&s).map_err(|e| BobErr::Manifest(format!("{}: {e}", path.display())))
+    }
+
+    /// Resolve every target's partitions and settings. Relative paths are taken from `base`.
+    pub fn targets(&self, base: &Path) -> Result<Vec<TargetPlan>, BobErr> {
+	self.targets.iter().map(|t| self.target(t, base)).collect()
+    }
+
+    fn target(&self, t: &Target, base: &Path) -> Result<TargetPlan, BobErr> {
+	let err = |msg: String| BobErr::Manifest(format!("{}: {msg}", t.output));
+	let output = base.join(&t.output);
+	let settings = Settings {
+	    size: t.settings.size.clone().or_else(|| self.defaults.size.clone()),
+	    align: t.settings.align.clone().or_else(|| self.defaults.align.clone()),
+	    sector_size: t.settings.sector_size.or(self.defaults.sector_size),
+	    table: t.settings.table.clone().or_else(|| self.defaults.table.clone()),
+	    seed: t.settings.seed.or(self.defaults.seed),
+	};
+
+	let mut layout = format!("{settings:?}");
+	let size = settings.size.as_deref().ok_or_else(|| err(String::from("no size")))?;
+	let mut builder = DiskImgBuilder::new()
+	    .output_file(output.to_str().ok_or_else(|| err(String::from("output path isn't UTF-8")))?)
+	    .total_size(parse_size(size).ok_or_else(|| err(format!("bad size {size}")))?);
+	if let Some(align) = &settings.align {
+	    builder = builder.alignment(parse_size(align).ok_or_else(|| err(format!("bad alignment {align}")))?);
+	}
+	if let Some(sector_size) = settings.sector_size {
+	    builder = builder.sector_size(sector_size);
+	}
+	if let Some(table) = &settings.table {
+	    builder = builder.table(PartitionTable::from_name(table).ok_or_else(|| err(format!("unknown table {table}")))?);
+	}
+	if let Some(seed) = settings.seed {
+	    builder = builder.seed(seed);
+	}
+
+	if let Some(name) = t.overrides.keys().find(|n| !self.partitions.iter().any(|p| &p.name == *n)) {
+	    return Err(err(format!("override for unknown partition {name}")));
+	}
+	let names: Vec<&str> = match &t.partitions {
+	    Some(names) => names.iter().map(String::as_str).collect(),
+	    None => self.partitions.iter().map(|p| p.name.as_str()).collect(),
+	};
+	let mut contents = Vec::new();
+	for name in names {
+	    let def = self.partitions.iter().find(|p| p.name == name).ok_or_else(|| err(format!("unknown partition {name}")))?;
+	    let o = t.overrides.get(name).cloned().unwrap_or_default();
+
+	    let ptype = o.ptype.as_ref().unwrap_or(&def.ptype);
+	    let (size, attributes) = (o.size.as_ref().or(def.size.as_ref()), o.attributes.as_ref().or(def.attributes.as_ref()));
+	    layout.push_str(&format!("\n{name} {ptype} {size:?} {attributes:?}"));
+	    let mut p = PartitionBuilder::new()
+		.name(name)
+		.partition_type(ptype.parse::<PartitionType>().map_err(|_| err(format!("unknown partition type {ptype}")))?);
+	    if let Some(size) = size {
+		p = p.size(parse_size(size).ok_or_else(|| err(format!("bad size {size} for {name}")))?);
+	    }
+	    if let Some(attributes) = attributes {
+		p = p.attributes(parse_attributes(attributes).ok_or_else(|| err(format!("bad attributes {attributes} for {name}")))?);
+	    }
+	    builder = builder.partition(p.build()?);
+
+	    if let Some(c) = o.contents.as_ref().or(def.contents.as_ref()) {
+		let c = match c {
+		    Contents::File(f) => Contents::File(base.join(f)),
+		    Contents::Squashfs(d) => Contents::Squashfs(base.join(d)),
+		};
+		contents.push((String::from(name), c));
+	    }
+	}
+
+	Ok(TargetPlan { output, builder, contents, layout })
+    }
+}
+
+impl Layout {
+    pub fn load(path: &Path) -> Result<Self, BobErr> {
+	let s = fs::read_to_string(path).map_err(BobErr::IO)?;
+	toml::from_str(&s).map_err(|e| BobErr::Manifest(format!("{}: {e}", path.display())))
+    }
+
+    /// Resolve the layout as a manifest with a single target writing to `output`, or to
+    /// the layout's own output if not given.
+    pub fn target(self, output: Option<String>, base: &Path) -> Result<TargetPlan, BobErr> {
+	let output = output.or(self.output).ok_or_else(|| BobErr::Manifest(String::from("no output, set one in the layout or with -o")))?;
+	let manifest = Manifest { defaults: self.settings, partitions: self.partitions, targets: Vec::new() };
+	manifest.target(&Target { output, settings: Settings::default(), partitions: None, overrides: BTreeMap::new() }, base)
+    }
+}
+
+/// Partition contents built so far in this run, removed when dropped.
+pub struct ArtifactCache {
+    dir: PathBuf,
+    built: HashMap<Contents, PathBuf>,
+    /// Artifacts that had to be built, rather than found in the cache.
+    pub misses: u32,
+}
+
+impl ArtifactCache {
+    pub fn new() -> Result<Self, BobErr> {
+	// One directory per cache, as dropping one removes it.
+	static CACHES: AtomicU32 = AtomicU32::new(0);
+	let n = CACHES.fetch_add(1, Ordering::Relaxed);
+	let dir = std::env::temp_dir().join(format!("bob-artifacts-{}-{n}", std::process::id()));
+	fs::create_dir_all(&dir).map_err(BobErr::IO)?;
+	Ok(Self { dir, built: HashMap::new(), misses: 0 })
+    }
+
+    /// The file holding `contents`, building it on first use.
+    pub fn get(&mut self, contents: &Contents) -> Result<PathBuf, BobErr> {
+	if let Some(path) = self.built.get(contents) {
+	    return Ok(path.clone());
+	}
+	let path = match contents {
+	    Contents::File(f) => f.clone(),
+	    Contents::Squashfs(dir) => {
+		// Numbered by build, a rebuild after `forget` mustn't reuse another's name.
+		let out = self.dir.join(format!("{}.squashfs", self.misses));
+		let stats = crate::squashfs::pack_dir(dir, &out, None)?;
+		debug!(dir = %dir.display(), bytes = stats.bytes_used, "packed squashfs artifact");
+		out
+	    },
+	};
+	self.misses += 1;
+	self.built.insert(contents.clone(), path.clone());
+	Ok(path)
+    }
+
+    /// Drop what was built for `contents` so the next `get` builds it again, after its
+    /// source changed.
+    pub fn forget(&mut self, contents: &Contents) {
+	if let Some(path) = self.built.remove(contents) {
+	    if path.starts_with(&self.dir) {
+		let _ = fs::remove_file(path);
+	    }
+	}
+    }
+}
+
+impl Drop for ArtifactCache {
+    fn drop(&mut self) {
+	let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Copy the file at `src` to the start of the partition. Returns the bytes copied.
+pub fn fill_partition(p: &mut impl Partition, src: &Path) -> Result<u64, BobErr> {
+    let mut f = File::open(src).map_err(BobErr::IO)?;
+    let len = f.metadata().map_err(BobErr::IO)?.len();
+    let capacity = p.sectors() * p.sector_size() as u64;
+    if len > capacity {
+	return Err(BobErr::Manifest(format!("{} ({len} bytes) doesn't fit in {} ({capacity} bytes)", src.display(), p.name())));
+    }
+    write_partition_bytes(p, 0, len, &mut f)?;
+    Ok(len)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use bob_core::gpt::DiskImage;
+
+    #[allow(dead_code)]
+    const MANIFEST: &str = r#"{
+	"size": "8M",
+	"seed": 1,
+	"partitions": [
+	    { "name": "ESP", "type": "esp", "size": "2M" },
+	    { "name": "root", "type": "root", "size": "2M", "contents": { "squashfs": "rootfs" } },
+	    { "name": "data", "type": "linux", "size": "1M" }
+	],
+	"targets": [
+	    { "output": "debug.img" },
+	    { "output": "release.img", "overrides": { "root": { "size": "3M" } } },
+	    { "output": "recovery.img", "size": "6M", "partitions": ["ESP", "root"] }
+	]
+    }"#;
+
+    #[test]
+    fn builds_targets() {
+	let base = std::env::temp_dir().join(format!("bob-test-{}-manifest", std::process::id()));
+	let _ = fs::remove_dir_all(&base);
+	fs::create_dir_all(base.join("rootfs")).unwrap();
+	fs::write(base.join("rootfs/motd"), "hello\n").unwrap();
+
+	let manifest: Manifest = serde_json::from_str(MANIFEST).unwrap();
+	let mut cache = ArtifactCache::new().unwrap();
+	let mut sizes = Vec::new();
+	for t in manifest.targets(&base).unwrap() {
+	    let mut img = t.builder.build().unwrap();
+	    sizes.push((img.partition_count(), fs::metadata(&t.output).unwrap().len()));
+	    for (name, c) in &t.contents {
+		let src = cache.get(c).unwrap();
+		fill_partition(&mut img.get_partition_view(name).unwrap(), &src).unwrap();
+	    }
+	}
+	let recovery = fs::read(base.join("recovery.img")).unwrap();
+	let _ = fs::remove_dir_all(&base);
+
+	assert_eq!(sizes, [(3, 8 << 20), (3, 8 << 20), (2, 6 << 20)]);
+	// One squashfs image for all three targets.
+	assert_eq!(cache.misses, 1);
+	// The root partition starts after the 1 MiB gap and the ESP.
+	assert_eq!(&recovery[3 << 20..(3 << 20) + 4], b"hsqs");
+    }
+
+    #[test]
+    fn toml_layout() {
+	let layout = r#"
+	    output = "disk.img"
+	    size = "8M"
+	    sector_size = 4096
+	    seed = 3
+
+	    [[partition]]
+	    name = "ESP"
+	    type = "esp"
+	    size = "2M"
+
+	    [[partition]]
+	    name = "root"
+	    type = "root"
+	    size = "2M"
+	    contents = { file = "root.img" }
+	"#;
+	let base = Path::new("/tmp/layout");
+	let t = toml::from_str::<Layout>(layout).unwrap().target(None, base).unwrap();
+	assert_eq!(t.output, base.join("disk.img"));
+	assert_eq!(t.contents, [(String::from("root"), Contents::File(base.join("root.img")))]);
+	let plan = t.builder.plan().unwrap();
+	assert_eq!((plan.image_size(), plan.partitions_of_type(PartitionType::LinuxRootX86_64)), (8 << 20, vec!["root"]));
+
+	let t = toml::from_str::<Layout>(layout).unwrap().target(Some(String::from("/dev/null")), base).unwrap();
+	assert_eq!(t.output, Path::new("/dev/null"));
+	assert!(toml::from_str::<Layout>("size = \"8M\"").unwrap().target(None, base).is_err());
+	assert!(toml::from_str::<Layout>("[[partition]]\nname = \"a\"\ntype = \"esp\"\nsise = \"1M\"\n").is_err());
+    }
+
+    #[test]
+    fn bad_manifests() {
+	let target = |t: &str| {
+	    let m = format!(r#"{{ "size": "8M", "partitions": [{{ "name": "ESP", "type": "esp", "size": "1M" }}], "targets": [{t}] }}"#);
+	    let m: Manifest = serde_json::from_str(&m).unwrap();
+	    match m.targets(Path::new(".")) {
+		Err(BobErr::Manifest(msg)) => msg,
+		_ => panic!("expected an error"),
+	    }
+	};
+	assert!(target(r#"{ "output": "a.img", "partitions": ["root"] }"#).contains("unknown partition root"));
+	assert!(target(r#"{ "output": "a.img", "overrides": { "root": {} } }"#).contains("override for unknown"));
+	assert!(target(r#"{ "output": "a.img", "table": "apm" }"#).contains("unknown table"));
+	assert!(serde_json::from_str::<Manifest>(r#"{ "partitions": [{ "name": "a", "type": "esp", "sise": "1M" }], "targets": [] }"#).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Following a kernel's serial output or TCP stream and decoding its `common::proto`
+//! messages.
+//!
+//! Console output between the frames is copied through as it is. Boot progress and test
+//! results are printed as they arrive, files the kernel sends are written under an output
+//! directory, and the run's outcome is taken from the messages rather than from the text
+//! of the log. A damaged frame is reported and skipped, the rest of the run still counts.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use common::proto::{Decoder, Feed, Message, Outcome, Stage};
+use common::snapshot::crc32;
+use tracing::warn;
+
+use bob_core::err::BobErr;
+use crate::snapshot::relative_path;
+
+/// What the kernel reported.
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    /// The last boot stage reached.
+    pub stage: Option<Stage>,
+    pub passed: u32,
+    pub failed: u32,
+    pub ignored: u32,
+    /// Whether the kernel said all tests have run.
+    pub tests_done: bool,
+    pub files: u32,
+    pub bad_frames: u32,
+    /// The kernel's exit code, None if the stream ended without one.
+    pub exit: Option<u32>,
+}
+
+impl Summary {
+    /// The run passed: the kernel exited with 0 and no test failed.
+    pub fn success(&self) -> bool {
+	self.exit == Some(0) && self.failed == 0
+    }
+}
+
+/// A file being received.
+struct Incoming {
+    path: PathBuf,
+    f: Option<File>,
+    len: u64,
+    data: Vec<u8>,
+}
+
+/// Decode `input` until the kernel exits or the stream ends. Console output goes to
+/// `console`, reports to stdout. Files are written under `out`, or dropped if there's none.
+pub fn monitor(input: impl Read, mut console: impl Write, out: Option<&Path>) -> Result<Summary, BobErr> {
+    let mut decoder = Decoder::new();
+    let mut summary = Summary::default();
+    let mut incoming: Option<Incoming> = None;
+
+    for byte in BufReader::new(input).bytes() {
+	let byte = byte.map_err(BobErr::IO)?;
+	let msg = match decoder.push(byte) {
+	    Feed::Console(b) => {
+		console.write_all(&[b]).map_err(BobErr::IO)?;
+		if b == b'\n' {
+		    console.flush().map_err(BobErr::IO)?;
+		}
+		continue;
+	    },
+	    Feed::Pending => continue,
+	    Feed::Error(e) => {
+		warn!(?e, "dropped a bad frame");
+		summary.bad_frames += 1;
+		continue;
+	    },
+	    Feed::Message(m) => m,
+	};
+
+	match msg {
+	    Message::Boot { stage, ms } => {
+		println!("[boot] {} at {ms} ms", stage.name());
+		summary.stage = Some(stage);
+	    },
+	    Message::Test { name, outcome, ms } => {
+		let result = match outcome {
+		    Outcome::Passed => { summary.passed += 1; "ok" },
+		    Outcome::Failed => { summary.failed += 1; "FAILED" },
+		    Outcome::Ignored => { summary.ignored += 1; "ignored" },
+		};
+		println!("[test] {name} ... {result} ({ms} ms)");
+	    },
+	    Message::TestsDone { passed, failed, ignored } => {
+		println!("[test] done: {passed} passed, {failed} failed, {ignored} ignored");
+		if (passed, failed, ignored) != (summary.passed, summary.failed, summary.ignored) {
+		    warn!("the kernel counted different results than were received, some were lost");
+		    summary.failed = summary.failed.max(failed);
+		}
+		summary.tests_done = true;
+	    },
+	    Message::FileStart { path, len } => {
+		let dst = out.map(|o| relative_path(path).map(|p| o.join(p))).transpose()?;
+		let f = match &dst {
+		    Some(dst) => {
+			if let Some(dir) = dst.parent() {
+			    std::fs::create_dir_all(dir).map_err(BobErr::IO)?;
+			}
+			Some(File::create(dst).map_err(BobErr::IO)?)
+		    },
+		    None => None,
+		};
+		incoming = Some(Incoming { path: dst.unwrap_or_else(|| PathBuf::from(path)), f, len, data: Vec::new() });
+	    },
+	    Message::FileData(data) => match incoming.as_mut() {
+		Some(i) => i.data.extend_from_slice(data),
+		None => warn!("file data without a file"),
+	    },
+	    Message::FileEnd { crc } => {
+		let Some(mut i) = incoming.take() else {
+		    warn!("end of a file that wasn't started");
+		    continue;
+		};
+		if i.data.len() as u64 != i.len || crc32(&i.data) != crc {
+		    return Err(BobErr::Monitor(format!("{} arrived damaged", i.path.display())));
+		}
+		match i.f.as_mut() {
+		    Some(f) => {
+			f.write_all(&i.data).map_err(BobErr::IO)?;
+			println!("[file] {} ({} bytes)", i.path.display(), i.len);
+		    },
+		    None => println!("[file] {} ({} bytes) dropped, no output directory", i.path.display(), i.len),
+		}
+		summary.files += 1;
+	    },
+	    Message::Exit { code } => {
+		println!("[exit] {code}");
+		summary.exit = Some(code);
+		break;
+	    },
+	}
+    }
+    console.flush().map_err(BobErr::IO)?;
+    Ok(summary)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use common::proto::MAX_FRAME;
+
+    /// Console text and frames as they'd come off the port.
+    #[allow(dead_code)]
+    fn stream(messages: &[Message]) -> Vec<u8> {
+	let mut s = b"yoyo booting\r\n".to_vec();
+	for m in messages {
+	    let mut frame = [0; MAX_FRAME];
+	    let len = m.encode(&mut frame).unwrap();
+	    s.extend_from_slice(&frame[..len]);
+	    s.extend_from_slice(b"$ ");
+	}
+	s
+    }
+
+    #[test]
+    fn test_run() {
+	let s = stream(&[
+	    Message::Boot { stage: Stage::ShellReady, ms: 40 },
+	    Message::Test { name: "a", outcome: Outcome::Passed, ms: 1 },
+	    Message::Test { name: "b", outcome: Outcome::Ignored, ms: 0 },
+	    Message::TestsDone { passed: 1, failed: 0, ignored: 1 },
+	    Message::FileStart { path: "log.txt", len: 2 },
+	    Message::FileData(b"hi"),
+	    Message::FileEnd { crc: crc32(b"hi") },
+	    Message::Exit { code: 0 },
+	    Message::Test { name: "after exit", outcome: Outcome::Failed, ms: 0 },
+	]);
+	let mut console = Vec::new();
+	let summary = monitor(s.as_slice(), &mut console, None).unwrap();
+	assert_eq!(summary, Summary {
+	    stage: Some(Stage::ShellReady),
+	    passed: 1,
+	    ignored: 1,
+	    tests_done: true,
+	    files: 1,
+	    exit: Some(0),
+	    ..Default::default()
+	});
+	assert!(summary.success());
+	assert_eq!(console, b"yoyo booting\r\n$ $ $ $ $ $ $ ");
+    }
+
+    #[test]
+    fn failures() {
+	let mut s = stream(&[
+	    Message::Test { name: "a", outcome: Outcome::Failed, ms: 1 },
+	    Message::Exit { code: 0 },
+	]);
+	// Flip a CRC bit of the test result: it's lost, but the kernel's count catches it.
+	let crc_byte = s.iter().position(|b| *b == b'$').unwrap() - 1;
+	s[crc_byte] ^= 1;
+	s.splice(crc_byte + 3..crc_byte + 3, stream(&[Message::TestsDone { passed: 0, failed: 1, ignored: 0 }]));
+	let summary = monitor(s.as_slice(), Vec::new(), None).unwrap();
+	assert_eq!((summary.bad_frames, summary.failed, summary.exit), (1, 1, Some(0)));
+	assert!(!summary.success());
+
+	// No exit message: the kernel hung or crashed.
+	let summary = monitor(stream(&[Message::Boot { stage: Stage::MemoryInit, ms: 1 }]).as_slice(), Vec::new(), None).unwrap();
+	assert!(!summary.success());
+    }
+}
@@ -0,0 +1,135 @@
+//! A read, work, write pipeline that keeps the order, for compressing images on every core.
+//!
+//! One thread reads items, a pool of workers does the expensive part (deflating a grain,
+//! say) and the calling thread writes the results in the order they were read. The
+//! reader can only get `window` items ahead of the writer, so a slow worker doesn't let
+//! the results waiting behind it pile up in memory.
+//!
+//! An error from any stage ends the pipeline. Errors from the reader and the workers
+//! travel in order with the items, so the one returned is the first in image order.
+
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Items in flight per worker.
+const WINDOW_PER_THREAD: usize = 4;
+
+/// One worker per CPU, the default for `--threads`.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Run `read` until it returns None, `work` on each item on `threads` workers, and
+/// `write` on the results in read order. Returns the number of items.
+pub fn ordered<T, U, E, R, F, W>(threads: usize, mut read: R, work: F, mut write: W) -> Result<u64, E>
+where
+    T: Send,
+    U: Send,
+    E: Send,
+    R: FnMut() -> Result<Option<T>, E> + Send,
+    F: Fn(T) -> Result<U, E> + Sync,
+    W: FnMut(U) -> Result<(), E>,
+{
+    let threads = threads.max(1);
+    let window = threads * WINDOW_PER_THREAD;
+    thread::scope(|s| {
+	let (credit_tx, credit_rx) = sync_channel::<()>(window);
+	let (job_tx, job_rx) = sync_channel::<(u64, Result<T, E>)>(window);
+	let (done_tx, done_rx) = channel::<(u64, Result<U, E>)>();
+	for _ in 0..window {
+	    credit_tx.send(()).expect("receiver is alive");
+	}
+
+	s.spawn(move || {
+	    for i in 0.. {
+		// The writer hung up, it's done or failed.
+		if credit_rx.recv().is_err() {
+		    return;
+		}
+		let item = match read() {
+		    Ok(Some(item)) => Ok(item),
+		    Ok(None) => return,
+		    Err(e) => Err(e),
+		};
+		let failed = item.is_err();
+		if job_tx.send((i, item)).is_err() || failed {
+		    return;
+		}
+	    }
+	});
+	let job_rx = Arc::new(Mutex::new(job_rx));
+	for _ in 0..threads {
+	    let (job_rx, done_tx, work) = (job_rx.clone(), done_tx.clone(), &work);
+	    s.spawn(move || loop {
+		let job = job_rx.lock().expect("no worker panics holding it").recv();
+		let Ok((i, item)) = job else {
+		    return;
+		};
+		if done_tx.send((i, item.and_then(work))).is_err() {
+		    return;
+		}
+	    });
+	}
+	drop((job_rx, done_tx));
+
+	let (mut next, mut waiting) = (0, BTreeMap::new());
+	for (i, result) in done_rx {
+	    waiting.insert(i, result);
+	    while let Some(result) = waiting.remove(&next) {
+		write(result?)?;
+		next += 1;
+		// The reader may have stopped already.
+		let _ = credit_tx.send(());
+	    }
+	}
+	Ok(next)
+    })
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn keeps_order() {
+	for threads in [1, 3, 8] {
+	    let mut n = 0;
+	    let read = || {
+		n += 1;
+		Ok::<_, String>((n <= 100).then_some(n))
+	    };
+	    // Later items finish first.
+	    let work = |i: u32| {
+		thread::sleep(std::time::Duration::from_micros(u64::from(100 - i % 10) * 20));
+		Ok(i * 2)
+	    };
+	    let mut out = Vec::new();
+	    assert_eq!(ordered(threads, read, work, |x| { out.push(x); Ok(()) }), Ok(100));
+	    assert_eq!(out, (1..=100).map(|i| i * 2).collect::<Vec<_>>());
+	}
+    }
+
+    #[test]
+    fn first_error_wins() {
+	let mut n = 0;
+	let read = || {
+	    n += 1;
+	    if n == 50 { Err(String::from("read 50")) } else { Ok(Some(n)) }
+	};
+	let work = |i: u32| if i == 20 || i == 30 { Err(format!("work {i}")) } else { Ok(i) };
+	let mut written = 0;
+	assert_eq!(ordered(4, read, work, |_| { written += 1; Ok(()) }), Err(String::from("work 20")));
+	assert_eq!(written, 19);
+
+	// A failed write stops the reader too.
+	let mut n = 0;
+	let read = || {
+	    n += 1;
+	    Ok(Some(n))
+	};
+	assert_eq!(ordered(2, read, Ok, |i: u32| if i == 5 { Err(i) } else { Ok(()) }), Err(5));
+    }
+}
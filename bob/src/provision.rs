@@ -0,0 +1,137 @@
+//! Filling a provisioning partition.
+//!
+//! The kernel applies the settings in it on its first boot, see
+//! common/src/provision.rs. They come from a file in the same format as the kernel's
+//! config.toml and from `section.key=value` settings on the command line, which go after
+//! the file's and so win. Everything is checked the way the kernel will read it before
+//! anything is written, a typo here would only show up in a boot log.
+
+use bob_core::err::BobErr;
+use bob_core::gpt::{write_partition_bytes, Partition};
+use common::provision::{self, Provisioned};
+use common::settings::{Problem, Settings};
+
+/// `file` followed by a line for each of `sets`, `section.key=value` with strings left
+/// unquoted.
+pub fn settings_text(file: &str, sets: &[String]) -> Result<String, BobErr> {
+    let mut text = String::from(file);
+    if !text.is_empty() && !text.ends_with('\n') {
+	text.push('\n');
+    }
+    for set in sets {
+	let bad = || BobErr::Provision(format!("{set} isn't section.key=value"));
+	let (name, value) = set.split_once('=').ok_or_else(bad)?;
+	let (section, key) = name.split_once('.').ok_or_else(bad)?;
+	let value = value.trim();
+	if value.contains('"') {
+	    return Err(bad());
+	}
+	let int = value.trim_start_matches('-').bytes().all(|b| b.is_ascii_digit() || b == b'_');
+	if value == "true" || value == "false" || (int && !value.is_empty()) {
+	    text.push_str(&format!("[{}]\n{} = {value}\n", section.trim(), key.trim()));
+	} else {
+	    text.push_str(&format!("[{}]\n{} = \"{value}\"\n", section.trim(), key.trim()));
+	}
+    }
+    Ok(text)
+}
+
+fn describe(p: Problem) -> &'static str {
+    match p {
+	Problem::Syntax => "isn't a [section], key = value or comment",
+	Problem::BadValue => "has a value that isn't a quoted string, integer or boolean",
+	Problem::Unknown => "isn't a setting the kernel knows",
+	Problem::Invalid => "has a value the setting doesn't take",
+    }
+}
+
+/// What the kernel will make of `text`, an error for the first line it would skip.
+pub fn check(text: &str) -> Result<Provisioned, BobErr> {
+    let mut p = Provisioned::new(Settings::default());
+    let mut first = None;
+    p.apply(text, |line, problem| {
+	first.get_or_insert((line, problem));
+    });
+    match first {
+	Some((line, problem)) => {
+	    let shown = text.lines().nth(line - 1).unwrap_or_default().trim();
+	    Err(BobErr::Provision(format!("line {line} ({shown}) {}", describe(problem))))
+	},
+	None => Ok(p),
+    }
+}
+
+/// Write `text` to the provisioning partition `p`, to be applied on the next boot.
+pub fn write<P: Partition>(p: &mut P, text: &str) -> Result<(), BobErr> {
+    let mut bytes = provision::header(text.as_bytes()).to_vec();
+    bytes.extend_from_slice(text.as_bytes());
+    if bytes.len() as u64 > p.sectors() * p.sector_size() as u64 {
+	return Err(BobErr::PartitionOutOfBounds);
+    }
+    write_partition_bytes(p, 0, bytes.len() as u64, &mut &bytes[..])
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use common::settings::Console;
+
+    /// A partition held in memory.
+    #[allow(dead_code)]
+    struct MemPartition(Vec<u8>);
+
+    impl Partition for MemPartition {
+	fn ptype(&self) -> bob_core::gpt::PartitionType {
+	    bob_core::gpt::PartitionType::Provision
+	}
+
+	fn name(&self) -> &str {
+	    "mem"
+	}
+
+	fn sector_size(&self) -> usize {
+	    512
+	}
+
+	fn sectors(&self) -> u64 {
+	    self.0.len() as u64 / 512
+	}
+
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 512;
+	    buf.copy_from_slice(&self.0[at..at + buf.len()]);
+	    Ok(())
+	}
+
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 512;
+	    self.0[at..at + buf.len()].copy_from_slice(buf);
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn writes_settings() {
+	let sets = [String::from("system.hostname=ci-runner-3"), String::from("test.enabled=true")];
+	let text = settings_text("[console]\noutput = \"both\"", &sets).unwrap();
+	assert_eq!(text, "[console]\noutput = \"both\"\n[system]\nhostname = \"ci-runner-3\"\n[test]\nenabled = true\n");
+	let p = check(&text).unwrap();
+	assert_eq!((p.hostname(), p.settings.console, p.settings.test_mode), (Some("ci-runner-3"), Console::Both, true));
+
+	let mut part = MemPartition(vec![0; 4 * 512]);
+	write(&mut part, &text).unwrap();
+	assert_eq!(part.0[..512], provision::header(text.as_bytes()));
+	assert_eq!(&part.0[512..512 + text.len()], text.as_bytes());
+	assert!(matches!(write(&mut MemPartition(vec![0; 512]), &text), Err(BobErr::PartitionOutOfBounds)));
+    }
+
+    #[test]
+    fn rejects_what_the_kernel_would_skip() {
+	assert!(matches!(settings_text("", &[String::from("hostname=x")]), Err(BobErr::Provision(_))));
+	let Err(BobErr::Provision(e)) = check("[system]\nhostname = \"x y\"\n") else { panic!("expected a bad hostname") };
+	assert_eq!(e, "line 2 (hostname = \"x y\") has a value the setting doesn't take");
+	assert!(check("[log]\ncolour = true\n").is_err());
+    }
+}
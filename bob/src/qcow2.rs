@@ -0,0 +1,255 @@
+//! qcow2 output, for handing images straight to QEMU or libvirt.
+//!
+//! The image is built raw as usual and converted afterwards. The result is as simple as a
+//! qcow2 file gets: version 3, 64 KiB clusters, no backing file, compression, snapshots or
+//! extensions. Clusters that are all zeros aren't allocated, so it's as small as a sparse
+//! raw image without relying on the filesystem it's copied to.
+//!
+//! Layout, in clusters: the header, the L1 table, the refcount table, the refcount
+//! blocks, the L2 tables, then the data clusters in guest order. All integers are big
+//! endian.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use tracing::debug;
+
+use bob_core::err::BobErr;
+
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+const VERSION: u32 = 3;
+const HEADER_SZ: u32 = 104;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SZ: u64 = 1 << CLUSTER_BITS;
+/// 16 bit refcounts.
+const REFCOUNT_ORDER: u32 = 4;
+const L2_ENTRIES: u64 = CLUSTER_SZ / 8;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SZ / 2;
+/// Set on L1 and L2 entries of clusters with a refcount of exactly one.
+const COPIED: u64 = 1 << 63;
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    /// Guest clusters with data in them.
+    pub data_clusters: u64,
+    pub file_size: u64,
+}
+
+/// Where everything goes, in clusters from the start of the file.
+struct Layout {
+    l1_entries: u64,
+    l1_start: u64,
+    refcount_table_start: u64,
+    refcount_table_clusters: u64,
+    refcount_blocks_start: u64,
+    refcount_blocks: u64,
+    l2_start: u64,
+    data_start: u64,
+    total: u64,
+}
+
+impl Layout {
+    fn new(size: u64, l2_tables: u64, data_clusters: u64) -> Self {
+	let l1_entries = size.div_ceil(CLUSTER_SZ * L2_ENTRIES);
+	let l1_clusters = (l1_entries * 8).div_ceil(CLUSTER_SZ).max(1);
+	// The refcounts cover themselves, so grow them until they do.
+	let (mut refcount_blocks, mut refcount_table_clusters) = (1, 1);
+	loop {
+	    let total = 1 + l1_clusters + refcount_table_clusters + refcount_blocks + l2_tables + data_clusters;
+	    let blocks = total.div_ceil(REFCOUNTS_PER_BLOCK);
+	    let table = (blocks * 8).div_ceil(CLUSTER_SZ);
+	    if blocks == refcount_blocks && table == refcount_table_clusters {
+		let refcount_table_start = 1 + l1_clusters;
+		let refcount_blocks_start = refcount_table_start + refcount_table_clusters;
+		let l2_start = refcount_blocks_start + refcount_blocks;
+		return Self {
+		    l1_entries,
+		    l1_start: 1,
+		    refcount_table_start,
+		    refcount_table_clusters,
+		    refcount_blocks_start,
+		    refcount_blocks,
+		    l2_start,
+		    data_start: l2_start + l2_tables,
+		    total,
+		};
+	    }
+	    (refcount_blocks, refcount_table_clusters) = (blocks.max(1), table.max(1));
+	}
+    }
+}
+
+/// Read guest cluster `index` of a `size` byte raw image, zero padded at the end.
+fn read_cluster<R: Read + Seek>(raw: &mut R, size: u64, index: u64, buf: &mut [u8]) -> io::Result<()> {
+    let start = index * CLUSTER_SZ;
+    let n = (size - start).min(CLUSTER_SZ) as usize;
+    raw.seek(SeekFrom::Start(start))?;
+    raw.read_exact(&mut buf[..n])?;
+    buf[n..].fill(0);
+    Ok(())
+}
+
+/// Write `size` bytes of raw image as qcow2.
+pub fn write<R: Read + Seek, W: Write>(raw: &mut R, size: u64, out: &mut W) -> Result<Stats, BobErr> {
+    let guest_clusters = size.div_ceil(CLUSTER_SZ);
+    let mut buf = vec![0; CLUSTER_SZ as usize];
+    let mut used = Vec::with_capacity(guest_clusters as usize);
+    for i in 0..guest_clusters {
+	read_cluster(raw, size, i, &mut buf).map_err(BobErr::IO)?;
+	used.push(buf.iter().any(|b| *b != 0));
+    }
+    let data_clusters = used.iter().filter(|u| **u).count() as u64;
+    let l2_used: Vec<bool> = used.chunks(L2_ENTRIES as usize).map(|c| c.contains(&true)).collect();
+    let l2_tables = l2_used.iter().filter(|u| **u).count() as u64;
+    let layout = Layout::new(size, l2_tables, data_clusters);
+    let offset = |cluster: u64| cluster * CLUSTER_SZ;
+
+    let mut header = vec![0; CLUSTER_SZ as usize];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4..8].copy_from_slice(&VERSION.to_be_bytes());
+    header[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&size.to_be_bytes());
+    header[36..40].copy_from_slice(&(layout.l1_entries as u32).to_be_bytes());
+    header[40..48].copy_from_slice(&offset(layout.l1_start).to_be_bytes());
+    header[48..56].copy_from_slice(&offset(layout.refcount_table_start).to_be_bytes());
+    header[56..60].copy_from_slice(&(layout.refcount_table_clusters as u32).to_be_bytes());
+    header[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+    header[100..104].copy_from_slice(&HEADER_SZ.to_be_bytes());
+    out.write_all(&header).map_err(BobErr::IO)?;
+
+    // Each cluster-sized table is written whole, padded with zeros.
+    let mut write_table = |entries: &mut dyn Iterator<Item = u64>, clusters: u64, width: usize| -> Result<(), BobErr> {
+	let mut t = vec![0; (clusters * CLUSTER_SZ) as usize];
+	for (i, e) in entries.enumerate() {
+	    t[i * width..(i + 1) * width].copy_from_slice(&e.to_be_bytes()[8 - width..]);
+	}
+	out.write_all(&t).map_err(BobErr::IO)
+    };
+
+    let mut next_l2 = layout.l2_start;
+    let mut l1 = l2_used.iter().map(|u| if *u {
+	next_l2 += 1;
+	offset(next_l2 - 1) | COPIED
+    } else {
+	0
+    });
+    write_table(&mut l1, layout.refcount_table_start - layout.l1_start, 8)?;
+    let mut refcount_table = (0..layout.refcount_blocks).map(|i| offset(layout.refcount_blocks_start + i));
+    write_table(&mut refcount_table, layout.refcount_table_clusters, 8)?;
+    let mut refcounts = (0..layout.total).map(|_| 1);
+    write_table(&mut refcounts, layout.refcount_blocks, 2)?;
+
+    let mut next_data = layout.data_start;
+    for chunk in used.chunks(L2_ENTRIES as usize).filter(|c| c.contains(&true)) {
+	let mut l2 = chunk.iter().map(|u| if *u {
+	    next_data += 1;
+	    offset(next_data - 1) | COPIED
+	} else {
+	    0
+	});
+	write_table(&mut l2, 1, 8)?;
+    }
+
+    for (i, _) in used.iter().enumerate().filter(|(_, u)| **u) {
+	read_cluster(raw, size, i as u64, &mut buf).map_err(BobErr::IO)?;
+	out.write_all(&buf).map_err(BobErr::IO)?;
+    }
+    out.flush().map_err(BobErr::IO)?;
+
+    debug!(guest_clusters, data_clusters, l2_tables, refcount_blocks = layout.refcount_blocks, "wrote qcow2");
+    Ok(Stats { data_clusters, file_size: offset(layout.total) })
+}
+
+/// Replace the raw image at `path` with a qcow2 one, through a temporary file next to it.
+pub fn convert_in_place(path: &Path) -> Result<Stats, BobErr> {
+    let mut raw = File::open(path).map_err(BobErr::IO)?;
+    let size = raw.metadata().map_err(BobErr::IO)?.len();
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".qcow2.tmp");
+    let tmp = Path::new(&tmp_name);
+    let mut out = io::BufWriter::new(File::create(tmp).map_err(BobErr::IO)?);
+    let stats = match write(&mut raw, size, &mut out) {
+	Ok(stats) => stats,
+	Err(e) => {
+	    drop(out);
+	    let _ = std::fs::remove_file(tmp);
+	    return Err(e);
+	},
+    };
+    drop(out);
+    std::fs::rename(tmp, path).map_err(BobErr::IO)?;
+    Ok(stats)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Guest bytes at `pos` of a qcow2 image, following the L1 and L2 tables the way
+    /// QEMU does.
+    #[allow(dead_code)]
+    fn read_guest(img: &[u8], pos: u64) -> u8 {
+	let be64 = |at: u64| u64::from_be_bytes(img[at as usize..at as usize + 8].try_into().unwrap());
+	let l1_table = be64(40);
+	let l1 = be64(l1_table + pos / (CLUSTER_SZ * L2_ENTRIES) * 8) & !COPIED;
+	if l1 == 0 {
+	    return 0;
+	}
+	let l2 = be64(l1 + (pos / CLUSTER_SZ % L2_ENTRIES) * 8) & !COPIED;
+	if l2 == 0 {
+	    return 0;
+	}
+	img[(l2 + pos % CLUSTER_SZ) as usize]
+    }
+
+    #[test]
+    fn round_trip() {
+	// Past the 512 MiB one L2 table covers and not a whole number of clusters, with a
+	// few clusters of data in a sparse file.
+	let size = 520 * 1024 * 1024 + 1000;
+	let spots = [0, 511, 70_000, 515 * 1024 * 1024, size - 1];
+	let p = std::env::temp_dir().join(format!("bob-test-{}-qcow2.img", std::process::id()));
+	let mut raw = File::options().read(true).write(true).create(true).truncate(true).open(&p).unwrap();
+	raw.set_len(size).unwrap();
+	for (i, at) in spots.iter().enumerate() {
+	    raw.seek(SeekFrom::Start(*at)).unwrap();
+	    raw.write_all(&[i as u8 + 1]).unwrap();
+	}
+
+	let mut out = Vec::new();
+	let stats = write(&mut raw, size, &mut out);
+	let _ = std::fs::remove_file(&p);
+	let stats = stats.unwrap();
+	assert_eq!(stats.data_clusters, 4);
+	assert_eq!(stats.file_size, out.len() as u64);
+	assert_eq!(&out[..4], MAGIC);
+	assert_eq!(u64::from_be_bytes(out[24..32].try_into().unwrap()), size);
+
+	for (i, at) in spots.iter().enumerate() {
+	    assert_eq!(read_guest(&out, *at), i as u8 + 1, "byte at {at}");
+	}
+	assert_eq!(read_guest(&out, 1), 0);
+	assert_eq!(read_guest(&out, 300 * 1024 * 1024), 0);
+	assert_eq!(u32::from_be_bytes(out[36..40].try_into().unwrap()), 2);
+
+	// Every cluster of the file is counted once.
+	let be64 = |at: usize| u64::from_be_bytes(out[at..at + 8].try_into().unwrap()) as usize;
+	let block = be64(be64(48));
+	let clusters = out.len() / CLUSTER_SZ as usize;
+	for c in 0..clusters {
+	    assert_eq!(u16::from_be_bytes([out[block + 2 * c], out[block + 2 * c + 1]]), 1);
+	}
+	assert_eq!(u16::from_be_bytes([out[block + 2 * clusters], out[block + 2 * clusters + 1]]), 0);
+    }
+
+    #[test]
+    fn refcounts_cover_themselves() {
+	// Big enough for more than one refcount block.
+	let layout = Layout::new(4 << 40, 8192, 40_000);
+	assert_eq!(layout.refcount_blocks, layout.total.div_ceil(REFCOUNTS_PER_BLOCK));
+	assert_eq!(layout.refcount_blocks, 2);
+	assert_eq!(layout.data_start + 40_000, layout.total);
+    }
+}
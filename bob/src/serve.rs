@@ -0,0 +1,310 @@
+//! Minimal TFTP and HTTP servers for netbooting.
+//!
+//! Serves the bootloader, kernel, and (optionally) a config file along with a generated
+//! iPXE script, so the loader chain can be tested over the network without building a
+//! disk image. Both servers are read-only and only know about the files they were given.
+//!
+//! Files are read from disk on every request so rebuilding while the server is running
+//! just works.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bob_core::err::BobErr;
+
+/// Name the bootloader is served under, matching what UEFI looks for on removable media.
+pub const BOOTLOADER_NAME: &str = "bootx64.efi";
+pub const KERNEL_NAME: &str = "kernel";
+pub const CONFIG_NAME: &str = "config";
+pub const IPXE_SCRIPT_NAME: &str = "boot.ipxe";
+
+// TFTP (RFC 1350)
+const TFTP_BLOCK_SZ: usize = 512;
+const TFTP_RETRIES: usize = 5;
+const TFTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+const ERR_ILLEGAL_OP: u16 = 4;
+
+pub struct ServeConfig {
+    pub addr: String,
+    pub tftp_port: u16,
+    pub http_port: u16,
+    pub bootloader: PathBuf,
+    pub kernel: PathBuf,
+    pub config: Option<PathBuf>,
+}
+
+/// The set of files available to clients.
+struct Files {
+    bootloader: PathBuf,
+    kernel: PathBuf,
+    config: Option<PathBuf>,
+}
+
+impl Files {
+    fn get(&self, name: &str) -> Option<io::Result<Vec<u8>>> {
+	let name = name.trim_start_matches('/');
+	if name.eq_ignore_ascii_case(IPXE_SCRIPT_NAME) {
+	    return Some(Ok(self.ipxe_script().into_bytes()));
+	}
+
+	let path = if name.eq_ignore_ascii_case(BOOTLOADER_NAME) {
+	    &self.bootloader
+	} else if name == KERNEL_NAME {
+	    &self.kernel
+	} else if name == CONFIG_NAME {
+	    self.config.as_ref()?
+	} else {
+	    return None;
+	};
+
+	Some(fs::read(path))
+    }
+
+    /// Generate an iPXE script that fetches everything and chains into the bootloader.
+    /// URIs are relative so they resolve against wherever the script itself came from,
+    /// which works for both TFTP and HTTP.
+    fn ipxe_script(&self) -> String {
+	let mut s = String::from("#!ipxe\n");
+	s.push_str(&format!("imgfetch {KERNEL_NAME}\n"));
+	if self.config.is_some() {
+	    s.push_str(&format!("imgfetch {CONFIG_NAME}\n"));
+	}
+	s.push_str(&format!("chain {BOOTLOADER_NAME}\n"));
+	s
+    }
+}
+
+/// Run the TFTP and HTTP servers until one of them fails.
+pub fn serve(cfg: ServeConfig) -> Result<(), BobErr> {
+    let files = Arc::new(Files {
+	bootloader: cfg.bootloader,
+	kernel: cfg.kernel,
+	config: cfg.config,
+    });
+
+    let tftp = UdpSocket::bind((cfg.addr.as_str(), cfg.tftp_port)).map_err(BobErr::IO)?;
+    let http = TcpListener::bind((cfg.addr.as_str(), cfg.http_port)).map_err(BobErr::IO)?;
+
+    println!("Serving TFTP on {}", tftp.local_addr().map_err(BobErr::IO)?);
+    println!("Serving HTTP on {}", http.local_addr().map_err(BobErr::IO)?);
+    println!("iPXE: chain tftp://<host>/{IPXE_SCRIPT_NAME} (or http://<host>:{}/{IPXE_SCRIPT_NAME})", cfg.http_port);
+
+    let tftp_files = files.clone();
+    let tftp_thread = thread::spawn(move || run_tftp(tftp, tftp_files));
+    let http_thread = thread::spawn(move || run_http(http, files));
+
+    tftp_thread.join().expect("tftp server thread panicked").map_err(BobErr::IO)?;
+    http_thread.join().expect("http server thread panicked").map_err(BobErr::IO)?;
+    Ok(())
+}
+
+fn run_tftp(sock: UdpSocket, files: Arc<Files>) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    loop {
+	let (n, peer) = sock.recv_from(&mut buf)?;
+	let req = TftpRequest::parse(&buf[..n]);
+	let files = files.clone();
+	// Each transfer gets its own socket (and so its own port), as per the RFC.
+	thread::spawn(move || {
+	    if let Err(e) = tftp_transfer(req, peer, &files) {
+		eprintln!("tftp: transfer to {peer} failed: {e}");
+	    }
+	});
+    }
+}
+
+fn tftp_transfer(req: Result<TftpRequest, u16>, peer: SocketAddr, files: &Files) -> io::Result<()> {
+    let sock = UdpSocket::bind((if peer.is_ipv4() { "0.0.0.0" } else { "::" }, 0))?;
+    sock.connect(peer)?;
+    sock.set_read_timeout(Some(TFTP_TIMEOUT))?;
+
+    let filename = match req {
+	Ok(TftpRequest::Read(filename)) => filename,
+	Ok(TftpRequest::Write) => return tftp_error(&sock, ERR_ACCESS, "read only server"),
+	Err(code) => return tftp_error(&sock, code, "bad request"),
+    };
+
+    let data = match files.get(&filename) {
+	Some(Ok(data)) => data,
+	Some(Err(e)) => return tftp_error(&sock, ERR_ACCESS, &e.to_string()),
+	None => return tftp_error(&sock, ERR_NOT_FOUND, "file not found"),
+    };
+    println!("tftp: {peer} {filename} ({} bytes)", data.len());
+
+    // A transfer ends with a block shorter than 512 bytes, so files that are an exact
+    // multiple of the block size need a trailing empty block.
+    let nblocks = data.len() / TFTP_BLOCK_SZ + 1;
+    let mut ack = [0; 516];
+    for i in 0..nblocks {
+	// Block numbers start at 1 and wrap for files larger than 32MiB.
+	let block = ((i + 1) % (u16::MAX as usize + 1)) as u16;
+	let start = i * TFTP_BLOCK_SZ;
+	let end = std::cmp::min(start + TFTP_BLOCK_SZ, data.len());
+
+	let mut pkt = Vec::with_capacity(4 + end - start);
+	pkt.extend(OP_DATA.to_be_bytes());
+	pkt.extend(block.to_be_bytes());
+	pkt.extend(&data[start..end]);
+
+	let mut acked = false;
+	for _ in 0..TFTP_RETRIES {
+	    sock.send(&pkt)?;
+	    match sock.recv(&mut ack) {
+		Ok(n) if n >= 4 => {
+		    let op = u16::from_be_bytes([ack[0], ack[1]]);
+		    let acked_block = u16::from_be_bytes([ack[2], ack[3]]);
+		    if op == OP_ERROR {
+			return Ok(());
+		    }
+		    if op == OP_ACK && acked_block == block {
+			acked = true;
+			break;
+		    }
+		},
+		Ok(_) => {},
+		Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {},
+		Err(e) => return Err(e),
+	    }
+	}
+
+	if !acked {
+	    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no ack for block {block}")));
+	}
+    }
+
+    Ok(())
+}
+
+fn tftp_error(sock: &UdpSocket, code: u16, msg: &str) -> io::Result<()> {
+    let mut pkt = Vec::new();
+    pkt.extend(OP_ERROR.to_be_bytes());
+    pkt.extend(code.to_be_bytes());
+    pkt.extend(msg.as_bytes());
+    pkt.push(0);
+    sock.send(&pkt)?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum TftpRequest {
+    Read(String),
+    Write,
+}
+
+impl TftpRequest {
+    /// Parse a request packet, on failure returns the TFTP error code to reply with.
+    fn parse(pkt: &[u8]) -> Result<Self, u16> {
+	if pkt.len() < 2 {
+	    return Err(ERR_ILLEGAL_OP);
+	}
+
+	match u16::from_be_bytes([pkt[0], pkt[1]]) {
+	    OP_RRQ => {},
+	    OP_WRQ => return Ok(Self::Write),
+	    _ => return Err(ERR_ILLEGAL_OP),
+	}
+
+	// RRQ: | 01 | filename | 0 | mode | 0 | (options ...)
+	let mut fields = pkt[2..].split(|b| *b == 0);
+	let filename = fields.next().ok_or(ERR_ILLEGAL_OP)?;
+	let mode = fields.next().ok_or(ERR_ILLEGAL_OP)?;
+	if !mode.eq_ignore_ascii_case(b"octet") && !mode.eq_ignore_ascii_case(b"netascii") {
+	    return Err(ERR_ILLEGAL_OP);
+	}
+
+	let filename = std::str::from_utf8(filename).map_err(|_| ERR_NOT_FOUND)?;
+	Ok(Self::Read(String::from(filename)))
+    }
+}
+
+fn run_http(listener: TcpListener, files: Arc<Files>) -> io::Result<()> {
+    for stream in listener.incoming() {
+	let stream = stream?;
+	let files = files.clone();
+	thread::spawn(move || {
+	    if let Err(e) = http_respond(stream, &files) {
+		eprintln!("http: {e}");
+	    }
+	});
+    }
+    Ok(())
+}
+
+fn http_respond(mut stream: TcpStream, files: &Files) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain the headers, we don't care about any of them.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+	line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    if method != "GET" && method != "HEAD" {
+	return http_write(&mut stream, "405 Method Not Allowed", b"", true);
+    }
+
+    match files.get(path) {
+	Some(Ok(data)) => {
+	    println!("http: {peer} {method} {path} ({} bytes)", data.len());
+	    http_write(&mut stream, "200 OK", &data, method == "GET")
+	},
+	Some(Err(e)) => http_write(&mut stream, "500 Internal Server Error", e.to_string().as_bytes(), method == "GET"),
+	None => http_write(&mut stream, "404 Not Found", b"not found\n", method == "GET"),
+    }
+}
+
+fn http_write(stream: &mut TcpStream, status: &str, body: &[u8], include_body: bool) -> io::Result<()> {
+    write!(stream, "HTTP/1.0 {status}\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n", body.len())?;
+    if include_body {
+	stream.write_all(body)?;
+    }
+    stream.flush()
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parse_read_request() {
+	let pkt = b"\x00\x01kernel\x00octet\x00";
+	assert_eq!(TftpRequest::parse(pkt), Ok(TftpRequest::Read(String::from("kernel"))));
+    }
+
+    #[test]
+    fn parse_read_request_with_options() {
+	let pkt = b"\x00\x01bootx64.efi\x00OCTET\x00blksize\x001468\x00";
+	assert_eq!(TftpRequest::parse(pkt), Ok(TftpRequest::Read(String::from("bootx64.efi"))));
+    }
+
+    #[test]
+    fn reject_bad_requests() {
+	assert_eq!(TftpRequest::parse(b"\x00"), Err(ERR_ILLEGAL_OP));
+	assert_eq!(TftpRequest::parse(b"\x00\x03kernel\x00octet\x00"), Err(ERR_ILLEGAL_OP));
+	assert_eq!(TftpRequest::parse(b"\x00\x01kernel\x00mail\x00"), Err(ERR_ILLEGAL_OP));
+	assert_eq!(TftpRequest::parse(b"\x00\x02kernel\x00octet\x00"), Ok(TftpRequest::Write));
+    }
+}
@@ -0,0 +1,86 @@
+//! Ed25519 signing of boot configuration.
+//!
+//! `keygen` writes a key pair as hex: `<name>.key` holds the secret seed and `<name>.pub`
+//! the public key, which is baked into the bootloader at build time through the
+//! `YOYO_CONFIG_PUBKEY` environment variable. `sign` writes the raw 64 byte signature of
+//! a file next to it as `<file>.sig`, which the bootloader checks before trusting it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use bob_core::err::BobErr;
+use crate::hex;
+
+pub const SIG_EXT: &str = "sig";
+
+/// Generate a key pair, returning the paths of the secret and public key files.
+pub fn keygen(name: &Path) -> Result<(PathBuf, PathBuf), BobErr> {
+    let key = SigningKey::from_bytes(&rand::random());
+    let secret = name.with_extension("key");
+    let public = name.with_extension("pub");
+
+    write_new(&secret, &hex::encode(key.as_bytes()))?;
+    write_new(&public, &hex::encode(key.verifying_key().as_bytes()))?;
+    Ok((secret, public))
+}
+
+/// Don't clobber an existing key, there's no getting it back.
+fn write_new(path: &Path, hex: &str) -> Result<(), BobErr> {
+    let mut f = fs::File::options().write(true).create_new(true).open(path).map_err(BobErr::IO)?;
+    std::io::Write::write_all(&mut f, format!("{hex}\n").as_bytes()).map_err(BobErr::IO)
+}
+
+fn read_key<const N: usize>(path: &Path) -> Result<[u8; N], BobErr> {
+    let s = fs::read_to_string(path).map_err(BobErr::IO)?;
+    hex::decode(s.trim()).and_then(|b| b.try_into().ok()).ok_or(BobErr::InvalidKey)
+}
+
+/// Sign `file` with the secret key in `key`, returning where the signature was written.
+pub fn sign_file(key: &Path, file: &Path) -> Result<PathBuf, BobErr> {
+    let key = SigningKey::from_bytes(&read_key(key)?);
+    let sig = key.sign(&fs::read(file).map_err(BobErr::IO)?);
+
+    let sig_path = sig_path(file);
+    fs::write(&sig_path, sig.to_bytes()).map_err(BobErr::IO)?;
+    Ok(sig_path)
+}
+
+/// Check `file` against its `.sig` with the public key in `public`.
+pub fn verify_file(public: &Path, file: &Path) -> Result<bool, BobErr> {
+    let key = VerifyingKey::from_bytes(&read_key(public)?).map_err(|_| BobErr::InvalidKey)?;
+    let sig = fs::read(sig_path(file)).map_err(BobErr::IO)?;
+    let sig = Signature::from_slice(&sig).map_err(|_| BobErr::InvalidKey)?;
+    Ok(key.verify(&fs::read(file).map_err(BobErr::IO)?, &sig).is_ok())
+}
+
+pub fn sig_path(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIG_EXT);
+    PathBuf::from(name)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+	let dir = std::env::temp_dir().join(format!("bob-sign-{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	let config = dir.join("config");
+	fs::write(&config, "kernel=\\efi\\boot\\kernel\n").unwrap();
+
+	let (secret, public) = keygen(&dir.join("boot")).unwrap();
+	assert!(keygen(&dir.join("boot")).is_err());
+	assert_eq!(sign_file(&secret, &config).unwrap(), dir.join("config.sig"));
+	assert!(verify_file(&public, &config).unwrap());
+
+	fs::write(&config, "kernel=\\efi\\boot\\evil\n").unwrap();
+	assert!(!verify_file(&public, &config).unwrap());
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,201 @@
+//! Receiving filesystem snapshots exported by a running kernel.
+//!
+//! The kernel writes the files as `@snap` lines (see `common::snapshot` for the format) to
+//! the serial port or a TCP connection. This reads those lines from a serial device, a
+//! captured log or a socket, skips everything else, and writes the files out under a
+//! directory. Lost or damaged lines fail the whole snapshot rather than leaving a file
+//! that looks complete but isn't.
+
+use std::fs::{self, File};
+use std::io::{BufRead, Write};
+use std::path::{Component, Path, PathBuf};
+
+use common::snapshot::{decode, Record, SnapErr, MAX_PAYLOAD};
+use tracing::debug;
+
+use bob_core::err::BobErr;
+
+/// What was received.
+#[derive(Debug, PartialEq)]
+pub struct Received {
+    pub files: u32,
+    pub bytes: u64,
+}
+
+/// The file being written.
+struct Current {
+    path: PathBuf,
+    f: File,
+    len: u64,
+    written: u64,
+}
+
+impl Current {
+    fn finish(self) -> Result<u64, BobErr> {
+	if self.written != self.len {
+	    return Err(BobErr::Snapshot(format!("{} ended after {} of {} bytes", self.path.display(), self.written, self.len)));
+	}
+	Ok(self.written)
+    }
+}
+
+/// Read lines until a complete snapshot has gone by, writing its files under `out`.
+/// Anything before its `Begin` is ignored, a later `Begin` starts over.
+pub fn receive(input: impl BufRead, out: &Path) -> Result<Received, BobErr> {
+    let mut buf = [0; MAX_PAYLOAD];
+    // The next sequence number, None until the snapshot begins.
+    let mut next_seq = None;
+    let mut current: Option<Current> = None;
+    let mut received = Received { files: 0, bytes: 0 };
+
+    for (n, line) in input.split(b'\n').enumerate() {
+	let line = line.map_err(BobErr::IO)?;
+	// Console output around the records isn't necessarily UTF-8.
+	let line = String::from_utf8_lossy(&line);
+	let (seq, record) = match decode(&line, &mut buf) {
+	    Ok(r) => r,
+	    Err(SnapErr::NotARecord) => continue,
+	    Err(e) if next_seq.is_none() => {
+		debug!(line = n + 1, ?e, "skipping a damaged record before the snapshot began");
+		continue;
+	    },
+	    Err(e) => return Err(BobErr::Snapshot(format!("line {}: {e:?}", n + 1))),
+	};
+
+	if record == Record::Begin {
+	    next_seq = Some(seq + 1);
+	    current = None;
+	    received = Received { files: 0, bytes: 0 };
+	    continue;
+	}
+	match next_seq {
+	    None => continue,
+	    Some(expected) if seq != expected => {
+		return Err(BobErr::Snapshot(format!("line {}: record {seq} where {expected} was expected, lines were lost", n + 1)));
+	    },
+	    Some(expected) => next_seq = Some(expected + 1),
+	}
+
+	match record {
+	    Record::Begin => unreachable!(),
+	    Record::File { path, len } => {
+		if let Some(c) = current.take() {
+		    received.bytes += c.finish()?;
+		}
+		let path = out.join(relative_path(path)?);
+		if let Some(dir) = path.parent() {
+		    fs::create_dir_all(dir).map_err(BobErr::IO)?;
+		}
+		let f = File::create(&path).map_err(BobErr::IO)?;
+		debug!(path = %path.display(), len, "receiving file");
+		current = Some(Current { path, f, len, written: 0 });
+		received.files += 1;
+	    },
+	    Record::Data(data) => {
+		let c = current.as_mut().ok_or_else(|| BobErr::Snapshot(format!("line {}: data before any file", n + 1)))?;
+		if c.written + data.len() as u64 > c.len {
+		    return Err(BobErr::Snapshot(format!("{} is longer than the {} bytes announced", c.path.display(), c.len)));
+		}
+		c.f.write_all(data).map_err(BobErr::IO)?;
+		c.written += data.len() as u64;
+	    },
+	    Record::End { files } => {
+		if let Some(c) = current.take() {
+		    received.bytes += c.finish()?;
+		}
+		if files != received.files {
+		    return Err(BobErr::Snapshot(format!("the snapshot has {files} files, {} were received", received.files)));
+		}
+		return Ok(received);
+	    },
+	}
+    }
+
+    Err(BobErr::Snapshot(String::from(if next_seq.is_some() {
+	"the input ended in the middle of the snapshot"
+    } else {
+	"no snapshot in the input"
+    })))
+}
+
+/// A path from the target as a relative path under the output directory. Anything that
+/// could climb out of it is refused.
+pub fn relative_path(path: &str) -> Result<PathBuf, BobErr> {
+    let mut rel = PathBuf::new();
+    for c in Path::new(path).components() {
+	match c {
+	    Component::Normal(c) => rel.push(c),
+	    Component::RootDir | Component::CurDir => {},
+	    Component::ParentDir | Component::Prefix(_) => return Err(BobErr::Snapshot(format!("refusing to write {path}"))),
+	}
+    }
+    if rel.as_os_str().is_empty() {
+	return Err(BobErr::Snapshot(format!("refusing to write {path}")));
+    }
+    Ok(rel)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use common::snapshot::Encoder;
+
+    /// A fresh output directory in the temp dir, removed when dropped.
+    #[allow(dead_code)]
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+	#[allow(dead_code)]
+	fn new(name: &str) -> Self {
+	    let p = std::env::temp_dir().join(format!("bob-test-{}-{name}", std::process::id()));
+	    let _ = fs::remove_dir_all(&p);
+	    Self(p)
+	}
+    }
+
+    impl Drop for TempDir {
+	fn drop(&mut self) {
+	    let _ = fs::remove_dir_all(&self.0);
+	}
+    }
+
+    /// A snapshot of two files as the kernel would send it, with console noise around it.
+    #[allow(dead_code)]
+    fn stream() -> String {
+	let mut s = String::from("[    0.5] booting\n@snap 7 D 00 00000000\n");
+	let mut enc = Encoder::new();
+	enc.write(&mut s, &Record::Begin).unwrap();
+	enc.write_file(&mut s, "/results/junit.xml", &[b'x'; 100]).unwrap();
+	s.push_str("$ ls\r\n");
+	enc.write_file(&mut s, "empty", &[]).unwrap();
+	enc.write(&mut s, &Record::End { files: 2 }).unwrap();
+	s
+    }
+
+    #[test]
+    fn receives_files() {
+	let out = TempDir::new("snapshot");
+	let received = receive(stream().as_bytes(), &out.0).unwrap();
+	assert_eq!(received, Received { files: 2, bytes: 100 });
+	assert_eq!(fs::read(out.0.join("results/junit.xml")).unwrap(), [b'x'; 100]);
+	assert!(fs::read(out.0.join("empty")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lost_and_bad_lines() {
+	let out = TempDir::new("snapshot-lost");
+	let s = stream();
+	// Drop the second data line of the first file.
+	let lost: Vec<_> = s.lines().enumerate().filter(|(i, _)| *i != 5).map(|(_, l)| l).collect();
+	let err = receive(lost.join("\n").as_bytes(), &out.0).unwrap_err();
+	assert!(matches!(err, BobErr::Snapshot(msg) if msg.contains("lost")));
+
+	let truncated: Vec<_> = s.lines().take(5).collect();
+	assert!(matches!(receive(truncated.join("\n").as_bytes(), &out.0), Err(BobErr::Snapshot(_))));
+
+	assert!(relative_path("../etc/passwd").is_err());
+	assert_eq!(relative_path("/a/./b").unwrap(), PathBuf::from("a/b"));
+    }
+}
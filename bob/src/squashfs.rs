@@ -0,0 +1,668 @@
+//! Squashfs (4.0) image generation.
+//!
+//! Packs a host directory into a gzip compressed, read-only squashfs filesystem for use
+//! as an immutable root partition. Regular files, directories and symlinks are packed,
+//! anything else (devices, fifos, sockets) is skipped with a warning. Every file is owned
+//! by root (uid/gid 0) since the host user means nothing on the target.
+//!
+//! Layout: superblock, data blocks and fragment blocks, inode table, directory table,
+//! fragment table, id table. No export or xattr tables are written.
+//! Format reference: https://dr-emann.github.io/squashfs/squashfs.html
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use flate2::{write::ZlibEncoder, Compression};
+use tracing::{debug, warn};
+
+use bob_core::err::BobErr;
+use common::squashfs::{
+    COMPRESSION_GZIP, INODE_DIR, INODE_FILE, INODE_LDIR, INODE_LFILE, INODE_SYMLINK, MAGIC,
+    METADATA_SZ, NO_FRAGMENT, NO_TABLE, SUPERBLOCK_SZ, UNCOMPRESSED_DATA, UNCOMPRESSED_METADATA,
+};
+
+pub const BLOCK_SZ: usize = 128 * 1024;
+const BLOCK_LOG: u16 = 17;
+
+const FLAG_NO_XATTRS: u16 = 0x0200;
+/// Images are padded out to a multiple of this so they can be loop mounted.
+const PAD_SZ: u64 = 4096;
+const NO_XATTR: u32 = u32::MAX;
+
+const MAX_NAME_LEN: usize = 256;
+/// A directory header covers at most this many entries.
+const MAX_DIR_RUN: usize = 256;
+
+/// A node in the directory tree being packed.
+struct Entry {
+    name: Vec<u8>,
+    mode: u16,
+    mtime: u32,
+    inode_number: u32,
+    node: Node,
+}
+
+enum Node {
+    Dir(Vec<Entry>),
+    File(std::path::PathBuf),
+    Symlink(Vec<u8>),
+}
+
+/// Summary of a packed image.
+pub struct SquashfsStats {
+    pub inodes: u32,
+    pub fragments: u32,
+    pub bytes_used: u64,
+}
+
+/// Pack the directory `dir` into a squashfs image written to `out`. With a `timestamp`
+/// (seconds since the epoch) every file and the image itself get that time instead of
+/// their own and the current one, so packing the same tree again gives the same bytes.
+pub fn pack_dir(dir: &Path, out: &Path, timestamp: Option<u32>) -> Result<SquashfsStats, BobErr> {
+    let mut next_inode = 1;
+    let root = scan(dir, Vec::new(), &mut next_inode, timestamp)?;
+
+    let mkfs_time = timestamp.unwrap_or_else(|| {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+    });
+    let mut f = File::create(out).map_err(BobErr::IO)?;
+    let stats = Writer::new(&mut f, mkfs_time).write(&root, next_inode - 1)?;
+    f.flush().map_err(BobErr::IO)?;
+    Ok(stats)
+}
+
+/// Build the tree under `path`. Inodes are numbered children first so the root gets the
+/// highest number, which is also the order they're written in.
+fn scan(path: &Path, name: Vec<u8>, next_inode: &mut u32, timestamp: Option<u32>) -> Result<Entry, BobErr> {
+    let meta = fs::symlink_metadata(path).map_err(BobErr::IO)?;
+    let mtime = timestamp.unwrap_or_else(|| {
+	meta.modified().ok()
+	    .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+	    .map_or(0, |d| d.as_secs() as u32)
+    });
+
+    if name.len() > MAX_NAME_LEN {
+	return Err(BobErr::Squashfs(format!("name too long: {}", path.display())));
+    }
+
+    let node = if meta.is_dir() {
+	let mut children = Vec::new();
+	let mut dir_entries: Vec<_> = fs::read_dir(path).map_err(BobErr::IO)?
+	    .collect::<Result<_, _>>().map_err(BobErr::IO)?;
+	// Lookups expect entries sorted by name.
+	dir_entries.sort_by(|a, b| a.file_name().as_encoded_bytes().cmp(b.file_name().as_encoded_bytes()));
+
+	for e in dir_entries {
+	    let ft = e.file_type().map_err(BobErr::IO)?;
+	    if !ft.is_dir() && !ft.is_file() && !ft.is_symlink() {
+		warn!(path = %e.path().display(), "skipping special file");
+		continue;
+	    }
+	    children.push(scan(&e.path(), e.file_name().as_encoded_bytes().to_vec(), next_inode, timestamp)?);
+	}
+	Node::Dir(children)
+    } else if meta.is_symlink() {
+	let target = fs::read_link(path).map_err(BobErr::IO)?;
+	Node::Symlink(target.as_os_str().as_encoded_bytes().to_vec())
+    } else {
+	Node::File(path.to_path_buf())
+    };
+
+    let inode_number = *next_inode;
+    *next_inode += 1;
+
+    Ok(Entry {
+	name,
+	mode: permissions(&meta),
+	mtime,
+	inode_number,
+	node,
+    })
+}
+
+#[cfg(unix)]
+fn permissions(meta: &fs::Metadata) -> u16 {
+    use std::os::unix::fs::PermissionsExt;
+    (meta.permissions().mode() & 0o7777) as u16
+}
+
+#[cfg(not(unix))]
+fn permissions(meta: &fs::Metadata) -> u16 {
+    if meta.is_dir() { 0o755 } else { 0o644 }
+}
+
+/// Compress `data`, or None if that doesn't make it any smaller.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut e = ZlibEncoder::new(Vec::new(), Compression::best());
+    e.write_all(data).ok()?;
+    let c = e.finish().ok()?;
+    if c.len() < data.len() {
+	Some(c)
+    } else {
+	None
+    }
+}
+
+/// Packs a table into 8KiB metadata blocks, each prefixed by its (compressed) size.
+struct MetadataWriter {
+    out: Vec<u8>,
+    buf: Vec<u8>,
+    /// Offset of every block in `out`, for tables that need an index.
+    block_starts: Vec<u64>,
+}
+
+impl MetadataWriter {
+    fn new() -> Self {
+	Self {
+	    out: Vec::new(),
+	    buf: Vec::new(),
+	    block_starts: Vec::new(),
+	}
+    }
+
+    /// Reference to the next byte written: block offset in the table and offset in that block.
+    fn pos(&self) -> (u32, u16) {
+	(self.out.len() as u32, self.buf.len() as u16)
+    }
+
+    fn write(&mut self, mut b: &[u8]) {
+	while !b.is_empty() {
+	    let n = std::cmp::min(METADATA_SZ - self.buf.len(), b.len());
+	    self.buf.extend(&b[..n]);
+	    b = &b[n..];
+	    if self.buf.len() == METADATA_SZ {
+		self.flush();
+	    }
+	}
+    }
+
+    fn flush(&mut self) {
+	if self.buf.is_empty() {
+	    return;
+	}
+
+	self.block_starts.push(self.out.len() as u64);
+	match compress(&self.buf) {
+	    Some(c) => {
+		self.out.extend((c.len() as u16).to_le_bytes());
+		self.out.extend(c);
+	    },
+	    None => {
+		self.out.extend((self.buf.len() as u16 | UNCOMPRESSED_METADATA).to_le_bytes());
+		self.out.extend(&self.buf);
+	    },
+	}
+	self.buf.clear();
+    }
+
+    fn finish(mut self) -> (Vec<u8>, Vec<u64>) {
+	self.flush();
+	(self.out, self.block_starts)
+    }
+}
+
+fn inode_ref((block, offset): (u32, u16)) -> u64 {
+    ((block as u64) << 16) | offset as u64
+}
+
+struct Writer<'a, W: Write + Seek> {
+    out: &'a mut W,
+    pos: u64,
+    inodes: MetadataWriter,
+    dirs: MetadataWriter,
+    /// (start, size) of each fragment block.
+    fragments: Vec<(u64, u32)>,
+    fragment_buf: Vec<u8>,
+    mkfs_time: u32,
+}
+
+/// What a directory needs to know about each child to list it.
+struct Listed<'e> {
+    name: &'e [u8],
+    inode_ref: u64,
+    inode_number: u32,
+    inode_type: u16,
+}
+
+impl<'a, W: Write + Seek> Writer<'a, W> {
+    fn new(out: &'a mut W, mkfs_time: u32) -> Self {
+	Self {
+	    out,
+	    pos: 0,
+	    inodes: MetadataWriter::new(),
+	    dirs: MetadataWriter::new(),
+	    fragments: Vec::new(),
+	    fragment_buf: Vec::new(),
+	    mkfs_time,
+	}
+    }
+
+    fn write(mut self, root: &Entry, inodes: u32) -> Result<SquashfsStats, BobErr> {
+	// The superblock is written last, once the table locations are known.
+	self.out.seek(SeekFrom::Start(SUPERBLOCK_SZ as u64)).map_err(BobErr::IO)?;
+	self.pos = SUPERBLOCK_SZ as u64;
+
+	let root_ref = self.add(root, inodes + 1)?.0;
+	self.flush_fragment().map_err(BobErr::IO)?;
+
+	let inodes_tbl = std::mem::replace(&mut self.inodes, MetadataWriter::new()).finish().0;
+	let inode_table_start = self.append(&inodes_tbl)?;
+	let dirs_tbl = std::mem::replace(&mut self.dirs, MetadataWriter::new()).finish().0;
+	let directory_table_start = self.append(&dirs_tbl)?;
+
+	let mut frag_tbl = MetadataWriter::new();
+	for (start, size) in &self.fragments {
+	    frag_tbl.write(&start.to_le_bytes());
+	    frag_tbl.write(&size.to_le_bytes());
+	    frag_tbl.write(&0u32.to_le_bytes());
+	}
+	let fragment_table_start = self.indexed_table(frag_tbl)?;
+
+	// Everything is owned by root, so the id table only holds 0.
+	let mut id_tbl = MetadataWriter::new();
+	id_tbl.write(&0u32.to_le_bytes());
+	let id_table_start = self.indexed_table(id_tbl)?;
+
+	let bytes_used = self.pos;
+	let padding = (PAD_SZ - bytes_used % PAD_SZ) % PAD_SZ;
+	self.append(&vec![0; padding as usize])?;
+
+	let mut sb = Vec::with_capacity(SUPERBLOCK_SZ);
+	sb.extend(MAGIC.to_le_bytes());
+	sb.extend(inodes.to_le_bytes());
+	sb.extend(self.mkfs_time.to_le_bytes());
+	sb.extend((BLOCK_SZ as u32).to_le_bytes());
+	sb.extend((self.fragments.len() as u32).to_le_bytes());
+	sb.extend(COMPRESSION_GZIP.to_le_bytes());
+	sb.extend(BLOCK_LOG.to_le_bytes());
+	sb.extend(FLAG_NO_XATTRS.to_le_bytes());
+	// Number of ids
+	sb.extend(1u16.to_le_bytes());
+	// Version 4.0
+	sb.extend(4u16.to_le_bytes());
+	sb.extend(0u16.to_le_bytes());
+	sb.extend(root_ref.to_le_bytes());
+	sb.extend(bytes_used.to_le_bytes());
+	sb.extend(id_table_start.to_le_bytes());
+	// xattr table
+	sb.extend(NO_TABLE.to_le_bytes());
+	sb.extend(inode_table_start.to_le_bytes());
+	sb.extend(directory_table_start.to_le_bytes());
+	sb.extend(fragment_table_start.to_le_bytes());
+	// export (NFS lookup) table
+	sb.extend(NO_TABLE.to_le_bytes());
+
+	self.out.seek(SeekFrom::Start(0)).map_err(BobErr::IO)?;
+	self.out.write_all(&sb).map_err(BobErr::IO)?;
+	debug!(inodes, fragments = self.fragments.len(), bytes_used, "wrote squashfs superblock");
+
+	Ok(SquashfsStats {
+	    inodes,
+	    fragments: self.fragments.len() as u32,
+	    bytes_used,
+	})
+    }
+
+    /// Write `b` at the end of the image, returning where it starts.
+    fn append(&mut self, b: &[u8]) -> Result<u64, BobErr> {
+	let start = self.pos;
+	self.out.write_all(b).map_err(BobErr::IO)?;
+	self.pos += b.len() as u64;
+	Ok(start)
+    }
+
+    /// Write a table's metadata blocks followed by the index of where each block starts,
+    /// returning the location of the index (which is what the superblock points at).
+    fn indexed_table(&mut self, tbl: MetadataWriter) -> Result<u64, BobErr> {
+	let (blocks, starts) = tbl.finish();
+	let start = self.append(&blocks)?;
+	let index: Vec<u8> = starts.iter().flat_map(|s| (start + s).to_le_bytes()).collect();
+	self.append(&index)
+    }
+
+    /// Write a data block, returning its size field for the block list.
+    fn write_block(&mut self, data: &[u8]) -> io::Result<u32> {
+	match compress(data) {
+	    Some(c) => {
+		self.out.write_all(&c)?;
+		self.pos += c.len() as u64;
+		Ok(c.len() as u32)
+	    },
+	    None => {
+		self.out.write_all(data)?;
+		self.pos += data.len() as u64;
+		Ok(data.len() as u32 | UNCOMPRESSED_DATA)
+	    },
+	}
+    }
+
+    fn flush_fragment(&mut self) -> io::Result<()> {
+	if self.fragment_buf.is_empty() {
+	    return Ok(());
+	}
+
+	let start = self.pos;
+	let data = std::mem::take(&mut self.fragment_buf);
+	let size = self.write_block(&data)?;
+	self.fragments.push((start, size));
+	Ok(())
+    }
+
+    /// Write the tail end of a file into the fragment block, returning (fragment, offset).
+    fn add_fragment(&mut self, tail: &[u8]) -> io::Result<(u32, u32)> {
+	if self.fragment_buf.len() + tail.len() > BLOCK_SZ {
+	    self.flush_fragment()?;
+	}
+
+	let offset = self.fragment_buf.len() as u32;
+	self.fragment_buf.extend(tail);
+	Ok((self.fragments.len() as u32, offset))
+    }
+
+    /// Write an entry (and everything under it), returning its inode reference and type.
+    fn add(&mut self, e: &Entry, parent: u32) -> Result<(u64, u16), BobErr> {
+	match &e.node {
+	    Node::Dir(children) => self.add_dir(e, children, parent),
+	    Node::File(path) => self.add_file(e, path),
+	    Node::Symlink(target) => {
+		let inode_ref = inode_ref(self.inodes.pos());
+		self.inode_header(INODE_SYMLINK, e);
+		self.inodes.write(&1u32.to_le_bytes());
+		self.inodes.write(&(target.len() as u32).to_le_bytes());
+		self.inodes.write(target);
+		Ok((inode_ref, INODE_SYMLINK))
+	    },
+	}
+    }
+
+    fn inode_header(&mut self, inode_type: u16, e: &Entry) {
+	self.inodes.write(&inode_type.to_le_bytes());
+	self.inodes.write(&e.mode.to_le_bytes());
+	// uid and gid, as indexes into the id table
+	self.inodes.write(&0u16.to_le_bytes());
+	self.inodes.write(&0u16.to_le_bytes());
+	self.inodes.write(&e.mtime.to_le_bytes());
+	self.inodes.write(&e.inode_number.to_le_bytes());
+    }
+
+    fn add_file(&mut self, e: &Entry, path: &Path) -> Result<(u64, u16), BobErr> {
+	let mut f = File::open(path).map_err(BobErr::IO)?;
+	let start_block = self.pos;
+	let mut blocks = Vec::new();
+	let mut file_size = 0u64;
+	let mut sparse = 0u64;
+	let mut fragment = (NO_FRAGMENT, 0);
+
+	let mut buf = vec![0; BLOCK_SZ];
+	loop {
+	    let n = read_full(&mut f, &mut buf).map_err(BobErr::IO)?;
+	    file_size += n as u64;
+	    if n < BLOCK_SZ {
+		if n > 0 {
+		    fragment = self.add_fragment(&buf[..n]).map_err(BobErr::IO)?;
+		}
+		break;
+	    }
+
+	    // All zero blocks are left out, a size of 0 marks a hole.
+	    if buf.iter().all(|b| *b == 0) {
+		blocks.push(0);
+		sparse += BLOCK_SZ as u64;
+	    } else {
+		blocks.push(self.write_block(&buf).map_err(BobErr::IO)?);
+	    }
+	}
+
+	let inode_ref = inode_ref(self.inodes.pos());
+	if start_block <= u32::MAX as u64 && file_size <= u32::MAX as u64 && sparse == 0 {
+	    self.inode_header(INODE_FILE, e);
+	    self.inodes.write(&(start_block as u32).to_le_bytes());
+	    self.inodes.write(&fragment.0.to_le_bytes());
+	    self.inodes.write(&fragment.1.to_le_bytes());
+	    self.inodes.write(&(file_size as u32).to_le_bytes());
+	} else {
+	    self.inode_header(INODE_LFILE, e);
+	    self.inodes.write(&start_block.to_le_bytes());
+	    self.inodes.write(&file_size.to_le_bytes());
+	    self.inodes.write(&sparse.to_le_bytes());
+	    self.inodes.write(&1u32.to_le_bytes());
+	    self.inodes.write(&fragment.0.to_le_bytes());
+	    self.inodes.write(&fragment.1.to_le_bytes());
+	    self.inodes.write(&NO_XATTR.to_le_bytes());
+	}
+	for b in blocks {
+	    self.inodes.write(&b.to_le_bytes());
+	}
+
+	Ok((inode_ref, INODE_FILE))
+    }
+
+    fn add_dir(&mut self, e: &Entry, children: &[Entry], parent: u32) -> Result<(u64, u16), BobErr> {
+	let mut listed = Vec::with_capacity(children.len());
+	for c in children {
+	    let (inode_ref, inode_type) = self.add(c, e.inode_number)?;
+	    listed.push(Listed {
+		name: &c.name,
+		inode_ref,
+		inode_number: c.inode_number,
+		inode_type,
+	    });
+	}
+
+	let (dir_block, dir_offset) = self.dirs.pos();
+	let listing_sz = self.write_listing(&listed);
+	// The size includes 3 bytes for the implicit . and .. entries.
+	let file_size = listing_sz + 3;
+	let subdirs = listed.iter().filter(|l| l.inode_type == INODE_DIR).count() as u32;
+
+	let inode_ref = inode_ref(self.inodes.pos());
+	if file_size <= u16::MAX as u32 {
+	    self.inode_header(INODE_DIR, e);
+	    self.inodes.write(&dir_block.to_le_bytes());
+	    self.inodes.write(&(2 + subdirs).to_le_bytes());
+	    self.inodes.write(&(file_size as u16).to_le_bytes());
+	    self.inodes.write(&dir_offset.to_le_bytes());
+	    self.inodes.write(&parent.to_le_bytes());
+	} else {
+	    self.inode_header(INODE_LDIR, e);
+	    self.inodes.write(&(2 + subdirs).to_le_bytes());
+	    self.inodes.write(&file_size.to_le_bytes());
+	    self.inodes.write(&dir_block.to_le_bytes());
+	    self.inodes.write(&parent.to_le_bytes());
+	    // No directory index
+	    self.inodes.write(&0u16.to_le_bytes());
+	    self.inodes.write(&dir_offset.to_le_bytes());
+	    self.inodes.write(&NO_XATTR.to_le_bytes());
+	}
+
+	Ok((inode_ref, INODE_DIR))
+    }
+
+    /// Write a directory's entries to the directory table, returning the bytes written.
+    /// Entries are grouped under headers, each group has to share an inode metadata
+    /// block and have inode numbers within an i16 of the header's.
+    fn write_listing(&mut self, listed: &[Listed]) -> u32 {
+	let mut written = 0;
+	let mut rest = listed;
+	while let Some(first) = rest.first() {
+	    let block = first.inode_ref >> 16;
+	    let base = first.inode_number;
+	    let run = rest.iter()
+		.take(MAX_DIR_RUN)
+		.take_while(|l| l.inode_ref >> 16 == block
+			    && i16::try_from(l.inode_number as i64 - base as i64).is_ok())
+		.count();
+
+	    self.dirs.write(&(run as u32 - 1).to_le_bytes());
+	    self.dirs.write(&(block as u32).to_le_bytes());
+	    self.dirs.write(&base.to_le_bytes());
+	    written += 12;
+
+	    for l in &rest[..run] {
+		self.dirs.write(&(l.inode_ref as u16).to_le_bytes());
+		self.dirs.write(&((l.inode_number as i64 - base as i64) as i16).to_le_bytes());
+		self.dirs.write(&l.inode_type.to_le_bytes());
+		self.dirs.write(&(l.name.len() as u16 - 1).to_le_bytes());
+		self.dirs.write(l.name);
+		written += 8 + l.name.len() as u32;
+	    }
+	    rest = &rest[run..];
+	}
+	written
+    }
+}
+
+/// Read until `buf` is full or the end of the file.
+fn read_full(f: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+	match f.read(&mut buf[n..])? {
+	    0 => break,
+	    r => n += r,
+	}
+    }
+    Ok(n)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn metadata_blocks_split() {
+	// Incompressible, so blocks are stored as is and flagged.
+	let mut x: u32 = 1;
+	let data: Vec<u8> = (0..METADATA_SZ + 10).map(|_| {
+	    x ^= x << 13;
+	    x ^= x >> 17;
+	    x ^= x << 5;
+	    x as u8
+	}).collect();
+
+	let mut m = MetadataWriter::new();
+	m.write(&data[..100]);
+	assert_eq!(m.pos(), (0, 100));
+	m.write(&data[100..]);
+	assert_eq!(m.pos(), (METADATA_SZ as u32 + 2, 10));
+
+	let (out, starts) = m.finish();
+	assert_eq!(starts, [0, METADATA_SZ as u64 + 2]);
+	assert_eq!(u16::from_le_bytes([out[0], out[1]]), METADATA_SZ as u16 | UNCOMPRESSED_METADATA);
+	assert_eq!(&out[2..METADATA_SZ + 2], &data[..METADATA_SZ]);
+    }
+
+    #[test]
+    fn compressed_metadata() {
+	let mut m = MetadataWriter::new();
+	m.write(&[0; 1000]);
+	let (out, _) = m.finish();
+	let len = u16::from_le_bytes([out[0], out[1]]);
+	assert_eq!(len & UNCOMPRESSED_METADATA, 0);
+	assert_eq!(len as usize, out.len() - 2);
+    }
+
+    #[test]
+    fn listing_split_into_runs() {
+	let mut out = std::io::Cursor::new(Vec::new());
+	let mut w = Writer::new(&mut out, 0);
+	let listed = [
+	    Listed { name: b"a", inode_ref: inode_ref((0, 10)), inode_number: 1, inode_type: INODE_FILE },
+	    Listed { name: b"b", inode_ref: inode_ref((0, 50)), inode_number: 2, inode_type: INODE_FILE },
+	    // Different inode metadata block, so it needs a new header.
+	    Listed { name: b"cc", inode_ref: inode_ref((300, 0)), inode_number: 3, inode_type: INODE_DIR },
+	];
+
+	// 2 headers, 3 entries of 8 bytes plus their names.
+	assert_eq!(w.write_listing(&listed), 2 * 12 + 3 * 8 + 4);
+	let (tbl, _) = std::mem::replace(&mut w.dirs, MetadataWriter::new()).finish();
+	let len = u16::from_le_bytes([tbl[0], tbl[1]]);
+	let b = if len & UNCOMPRESSED_METADATA != 0 {
+	    tbl[2..].to_vec()
+	} else {
+	    let mut b = Vec::new();
+	    flate2::read::ZlibDecoder::new(&tbl[2..]).read_to_end(&mut b).unwrap();
+	    b
+	};
+	// count - 1, inode block, base inode number
+	assert_eq!(&b[0..12], &[1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+	assert_eq!(&b[12 + 9 + 9..12 + 9 + 9 + 12], &[0, 0, 0, 0, 44, 1, 0, 0, 3, 0, 0, 0]);
+    }
+
+    /// Read `len` bytes of a metadata table at `block`/`offset`, inflating as needed.
+    #[allow(dead_code)]
+    fn read_metadata(img: &[u8], table: u64, block: u64, offset: usize, len: usize) -> Vec<u8> {
+	let mut out = Vec::new();
+	let mut at = (table + block) as usize;
+	while out.len() < offset + len {
+	    let (n, compressed) = common::squashfs::metadata_header(u16::from_le_bytes([img[at], img[at + 1]]));
+	    let raw = &img[at + 2..at + 2 + n];
+	    if compressed {
+		flate2::read::ZlibDecoder::new(raw).read_to_end(&mut out).unwrap();
+	    } else {
+		out.extend(raw);
+	    }
+	    at += 2 + n;
+	}
+	out[offset..offset + len].to_vec()
+    }
+
+    #[test]
+    fn read_back_with_common_parser() {
+	use common::squashfs::{lookup, Inode, Superblock};
+
+	let dir = std::env::temp_dir().join(format!("bob-squashfs-{}", std::process::id()));
+	let out = dir.with_extension("img");
+	fs::create_dir_all(dir.join("etc")).unwrap();
+	let motd: Vec<u8> = (0..BLOCK_SZ + 100).map(|i| (i % 251) as u8).collect();
+	fs::write(dir.join("etc/motd"), &motd).unwrap();
+
+	let fixed = |t| {
+	    pack_dir(&dir, &out, Some(t)).unwrap();
+	    fs::read(&out).unwrap()
+	};
+	let (a, b) = (fixed(0), fixed(0));
+	assert_eq!(a, b);
+	assert_ne!(a, fixed(1));
+
+	let stats = pack_dir(&dir, &out, None).unwrap();
+	let img = fs::read(&out).unwrap();
+	let _ = fs::remove_dir_all(&dir);
+	let _ = fs::remove_file(&out);
+
+	let sb = Superblock::parse(&img).unwrap();
+	assert_eq!(sb.inodes, 3);
+	assert_eq!(stats.fragments, 1);
+	assert_eq!(img.len() as u64 % PAD_SZ, 0);
+
+	// / -> etc -> motd
+	let mut inode_ref = sb.root_inode;
+	for name in [&b"etc"[..], b"motd"] {
+	    let b = read_metadata(&img, sb.inode_table_start, inode_ref >> 16, inode_ref as u16 as usize, 32);
+	    let Inode::Dir { start_block, offset, listing_size, .. } = Inode::parse(&b, sb.block_size).unwrap().1 else {
+		panic!("not a directory");
+	    };
+	    let listing = read_metadata(&img, sb.directory_table_start, start_block as u64, offset as usize, listing_size as usize);
+	    inode_ref = lookup(&listing, name).unwrap().unwrap().inode_ref;
+	}
+
+	let b = read_metadata(&img, sb.inode_table_start, inode_ref >> 16, inode_ref as u16 as usize, 36);
+	let Inode::File { start_block, file_size, fragment, fragment_offset, block_sizes } = Inode::parse(&b, sb.block_size).unwrap().1 else {
+	    panic!("not a file");
+	};
+	assert_eq!(file_size, motd.len() as u64);
+	assert_eq!((fragment, fragment_offset), (0, 0));
+
+	let size = u32::from_le_bytes(block_sizes.try_into().unwrap());
+	let raw = &img[start_block as usize..start_block as usize + (size & !UNCOMPRESSED_DATA) as usize];
+	let mut block = Vec::new();
+	if size & UNCOMPRESSED_DATA == 0 {
+	    flate2::read::ZlibDecoder::new(raw).read_to_end(&mut block).unwrap();
+	} else {
+	    block.extend(raw);
+	}
+	assert_eq!(block, &motd[..BLOCK_SZ]);
+    }
+}
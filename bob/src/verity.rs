@@ -0,0 +1,185 @@
+//! Hash trees for read-only partitions.
+//!
+//! Builds a dm-verity compatible tree (see common/src/verity.rs for the layout) over a
+//! data partition and writes it to a companion hash partition. The root hash has to
+//! reach the kernel some other way, on the command line, so tampering with both
+//! partitions at once can't go unnoticed.
+
+use tracing::debug;
+
+use common::verity::{hash_block, Geometry, Hash, Superblock, BLOCK_SZ, HASH_SZ};
+use bob_core::err::BobErr;
+use bob_core::gpt::Partition;
+
+pub const DEFAULT_SALT_SZ: usize = 32;
+
+pub struct HashTree {
+    geo: Geometry,
+    salt: Vec<u8>,
+    uuid: [u8; 16],
+    /// Levels bottom up, each padded out to whole blocks.
+    levels: Vec<Vec<u8>>,
+    root: Hash,
+}
+
+impl HashTree {
+    /// Hash every 4KiB block of `data`. A partial block at the end isn't covered.
+    pub fn build<P: Partition>(data: &mut P, salt: &[u8], uuid: [u8; 16]) -> Result<Self, BobErr> {
+	let sector_size = data.sector_size();
+	if !BLOCK_SZ.is_multiple_of(sector_size) {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+	let sectors_per_block = (BLOCK_SZ / sector_size) as u64;
+	let data_blocks = data.sectors() / sectors_per_block;
+	if data_blocks == 0 {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+
+	let geo = Geometry::new(data_blocks);
+	let mut block = vec![0; BLOCK_SZ];
+	let mut level = Vec::with_capacity(data_blocks as usize * HASH_SZ);
+	for i in 0..data_blocks {
+	    data.read_sectors(i * sectors_per_block, &mut block).map_err(BobErr::IO)?;
+	    level.extend(hash_block(salt, &block));
+	}
+
+	if geo.levels == 0 {
+	    // A single block has no tree, its hash is the root.
+	    return Ok(Self {
+		geo,
+		salt: salt.to_vec(),
+		uuid,
+		levels: Vec::new(),
+		root: level[..HASH_SZ].try_into().unwrap(),
+	    });
+	}
+
+	let mut levels = Vec::with_capacity(geo.levels);
+	for i in 0..geo.levels {
+	    level.resize(geo.level_blocks[i] as usize * BLOCK_SZ, 0);
+	    debug!(level = i, blocks = geo.level_blocks[i], start = geo.level_start[i], "hashed verity level");
+	    let next = level.chunks(BLOCK_SZ).flat_map(|b| hash_block(salt, b)).collect();
+	    levels.push(std::mem::replace(&mut level, next));
+	}
+
+	// The top level is a single block, so what's left is its hash.
+	Ok(Self {
+	    geo,
+	    salt: salt.to_vec(),
+	    uuid,
+	    levels,
+	    root: level[..HASH_SZ].try_into().unwrap(),
+	})
+    }
+
+    pub fn root(&self) -> &Hash {
+	&self.root
+    }
+
+    pub fn geometry(&self) -> &Geometry {
+	&self.geo
+    }
+
+    /// Write the superblock and tree to the start of `hash`.
+    pub fn write<P: Partition>(&self, hash: &mut P) -> Result<(), BobErr> {
+	let sector_size = hash.sector_size();
+	if !BLOCK_SZ.is_multiple_of(sector_size) {
+	    return Err(BobErr::PartitionOutOfBounds);
+	}
+	let sectors_per_block = (BLOCK_SZ / sector_size) as u64;
+	if hash.sectors() / sectors_per_block < self.geo.hash_blocks() {
+	    return Err(BobErr::HashPartitionTooSmall);
+	}
+
+	let sb = Superblock::new(self.uuid, self.geo.data_blocks, &self.salt)
+	    .map_err(|_| BobErr::PartitionOutOfBounds)?;
+	let mut first = vec![0; BLOCK_SZ];
+	first[..sb.to_bytes().len()].copy_from_slice(&sb.to_bytes());
+	hash.write_sectors(0, &first).map_err(BobErr::IO)?;
+
+	for (i, level) in self.levels.iter().enumerate() {
+	    hash.write_sectors(self.geo.level_start[i] * sectors_per_block, level).map_err(BobErr::IO)?;
+	}
+	debug!(blocks = self.geo.hash_blocks(), "wrote verity hash tree");
+	Ok(())
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use common::verity::{verify_block, VerityErr};
+
+    /// A partition held in memory.
+    #[allow(dead_code)]
+    struct MemPartition(Vec<u8>);
+
+    impl Partition for MemPartition {
+	fn ptype(&self) -> bob_core::gpt::PartitionType {
+	    bob_core::gpt::PartitionType::EFISystem
+	}
+
+	fn name(&self) -> &str {
+	    "mem"
+	}
+
+	fn sector_size(&self) -> usize {
+	    512
+	}
+
+	fn sectors(&self) -> u64 {
+	    self.0.len() as u64 / 512
+	}
+
+	fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 512;
+	    buf.copy_from_slice(&self.0[at..at + buf.len()]);
+	    Ok(())
+	}
+
+	fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> std::io::Result<()> {
+	    let at = sector as usize * 512;
+	    self.0[at..at + buf.len()].copy_from_slice(buf);
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn build_and_verify() {
+	let blocks = 300;
+	let mut data = MemPartition((0..blocks * BLOCK_SZ).map(|i| (i / BLOCK_SZ * 7 + i) as u8).collect());
+	let tree = HashTree::build(&mut data, b"pepper", [0; 16]).unwrap();
+	let geo = *tree.geometry();
+	assert_eq!(geo.levels, 2);
+
+	let mut hash = MemPartition(vec![0; 8 * BLOCK_SZ]);
+	assert!(matches!(tree.write(&mut MemPartition(vec![0; BLOCK_SZ])), Err(BobErr::HashPartitionTooSmall)));
+	tree.write(&mut hash).unwrap();
+	assert_eq!(Superblock::parse(&hash.0).unwrap().data_blocks, blocks as u64);
+
+	let read = |n: u64, buf: &mut [u8; BLOCK_SZ]| {
+	    let at = n as usize * BLOCK_SZ;
+	    buf.copy_from_slice(&hash.0[at..at + BLOCK_SZ]);
+	    Ok(())
+	};
+	let mut buf = [0; BLOCK_SZ];
+	for i in [0, 128, 299] {
+	    let block = &data.0[i * BLOCK_SZ..(i + 1) * BLOCK_SZ];
+	    assert_eq!(verify_block(&geo, b"pepper", tree.root(), i as u64, block, &mut buf, read), Ok(()));
+	}
+
+	let mut tampered = data.0[..BLOCK_SZ].to_vec();
+	tampered[100] ^= 1;
+	assert_eq!(verify_block(&geo, b"pepper", tree.root(), 0, &tampered, &mut buf, read), Err(VerityErr::Mismatch { level: 0 }));
+    }
+
+    #[test]
+    fn single_block() {
+	let mut data = MemPartition(vec![0xAA; BLOCK_SZ + 512]);
+	let tree = HashTree::build(&mut data, b"", [0; 16]).unwrap();
+	assert_eq!(tree.geometry().data_blocks, 1);
+	assert_eq!(tree.root(), &hash_block(b"", &[0xAA; BLOCK_SZ]));
+    }
+}
@@ -0,0 +1,180 @@
+//! Recording which builds went into an image.
+//!
+//! The bootloader and the kernel each carry a build id record (see
+//! common/src/build_id.rs). After filling an image, bob looks for records in everything it
+//! put in: files copied into a partition, which may be filesystem images holding the
+//! binaries, and every file under a directory packed as squashfs, since the packed image
+//! is compressed. What it finds goes into `<image>.versions.json` next to the image,
+//! along with bob's own build. An image without any builds in it gets no record, and
+//! loses an old one:
+//!
+//! ```json
+//! {
+//!   "image": "disk.img",
+//!   "bob": { "commit": "1a2b...", "dirty": false, "built": "2026-10-18T09:30:00Z" },
+//!   "components": [
+//!     { "component": "kernel", "partition": "ESP", "source": "esp.img", "commit": "1a2b...", "dirty": false, "built": "2026-10-18T09:30:00Z" }
+//!   ]
+//! }
+//! ```
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+use bob_core::err::BobErr;
+use common::build_id::{self, BuildId, Component, RECORD_SZ};
+use common::time::DateTime;
+use crate::hex;
+use crate::manifest::Contents;
+
+/// Bytes read from a file at a time.
+const CHUNK_SZ: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Found {
+    pub partition: String,
+    pub source: PathBuf,
+    pub component: Component,
+    pub id: BuildId,
+}
+
+/// The build id records in `contents`, once each per partition and file.
+pub fn scan(contents: &[(String, Contents)]) -> Result<Vec<Found>, BobErr> {
+    let mut found = Vec::new();
+    for (partition, c) in contents {
+	let files = match c {
+	    Contents::File(f) => vec![f.clone()],
+	    Contents::Squashfs(dir) => files_under(dir)?,
+	};
+	for source in files {
+	    for (component, id) in scan_file(&source)? {
+		let f = Found { partition: partition.clone(), source: source.clone(), component, id };
+		if !found.contains(&f) {
+		    found.push(f);
+		}
+	    }
+	}
+    }
+    Ok(found)
+}
+
+/// Every file under `dir`, in name order.
+pub fn files_under(dir: &Path) -> Result<Vec<PathBuf>, BobErr> {
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(dir).map_err(BobErr::IO)?.collect::<Result<_, _>>().map_err(BobErr::IO)?;
+    entries.sort_by_key(|e| e.file_name());
+    for e in entries {
+	let ty = e.file_type().map_err(BobErr::IO)?;
+	if ty.is_dir() {
+	    files.extend(files_under(&e.path())?);
+	} else if ty.is_file() {
+	    files.push(e.path());
+	}
+    }
+    Ok(files)
+}
+
+/// Records in the file at `path`, read a chunk at a time. The end of each chunk is kept
+/// for the next so a record straddling the two is still found.
+fn scan_file(path: &Path) -> Result<Vec<(Component, BuildId)>, BobErr> {
+    let mut f = File::open(path).map_err(BobErr::IO)?;
+    let mut buf = vec![0; CHUNK_SZ + RECORD_SZ];
+    let (mut kept, mut found) = (0, Vec::new());
+    loop {
+	let n = f.read(&mut buf[kept..]).map_err(BobErr::IO)?;
+	if n == 0 {
+	    return Ok(found);
+	}
+	let len = kept + n;
+	found.extend(build_id::find_records(&buf[..len]));
+	kept = len.min(RECORD_SZ - 1);
+	buf.copy_within(len - kept..len, 0);
+    }
+}
+
+fn id_json(id: &BuildId) -> serde_json::Value {
+    let t = DateTime::from_unix(id.time as i64);
+    json!({
+	"commit": id.commit.map(|c| hex::encode(&c)),
+	"dirty": id.dirty,
+	"built": format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", t.year, t.month, t.day, t.hour, t.minute, t.second),
+    })
+}
+
+/// Where the record of the builds in `image` goes.
+pub fn versions_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".versions.json");
+    PathBuf::from(path)
+}
+
+/// Write what `scan` found to `<image>.versions.json`, returning its path.
+pub fn write_versions(image: &Path, found: &[Found]) -> Result<PathBuf, BobErr> {
+    let components: Vec<_> = found.iter().map(|f| {
+	let mut c = id_json(&f.id);
+	c["component"] = json!(f.component.name());
+	c["partition"] = json!(f.partition);
+	c["source"] = json!(f.source.display().to_string());
+	c
+    }).collect();
+    let versions = json!({
+	"image": image.display().to_string(),
+	"bob": id_json(&build_id::THIS),
+	"components": components,
+    });
+
+    let path = versions_path(image);
+    let mut text = serde_json::to_string_pretty(&versions).expect("versions to serialize");
+    text.push('\n');
+    fs::write(&path, text).map_err(BobErr::IO)?;
+    Ok(path)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn finds_records() {
+	let dir = std::env::temp_dir().join(format!("bob-versions-{}", std::process::id()));
+	let boot = dir.join("rootfs").join("boot");
+	fs::create_dir_all(&boot).unwrap();
+	let kernel = BuildId::from_env("1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d", "1792315800");
+	let loader = BuildId::from_env("", "1792315800");
+
+	// The same record twice, and one straddling two chunks.
+	let mut esp = vec![0; CHUNK_SZ + 100];
+	esp[..RECORD_SZ].copy_from_slice(&kernel.record(Component::Kernel));
+	esp[200..200 + RECORD_SZ].copy_from_slice(&kernel.record(Component::Kernel));
+	esp[CHUNK_SZ - 10..CHUNK_SZ - 10 + RECORD_SZ].copy_from_slice(&loader.record(Component::Bootloader));
+	fs::write(dir.join("esp.img"), &esp).unwrap();
+	let mut kernel_elf = vec![7; 300];
+	kernel_elf[100..100 + RECORD_SZ].copy_from_slice(&kernel.record(Component::Kernel));
+	fs::write(boot.join("kernel"), &kernel_elf).unwrap();
+	fs::write(boot.join("config"), b"nothing here").unwrap();
+
+	let contents = [
+	    (String::from("ESP"), Contents::File(dir.join("esp.img"))),
+	    (String::from("root"), Contents::Squashfs(dir.join("rootfs"))),
+	];
+	let found = scan(&contents).unwrap();
+	assert_eq!(found, [
+	    Found { partition: String::from("ESP"), source: dir.join("esp.img"), component: Component::Kernel, id: kernel },
+	    Found { partition: String::from("ESP"), source: dir.join("esp.img"), component: Component::Bootloader, id: loader },
+	    Found { partition: String::from("root"), source: boot.join("kernel"), component: Component::Kernel, id: kernel },
+	]);
+
+	let image = dir.join("disk.img");
+	let path = write_versions(&image, &found).unwrap();
+	assert_eq!(path, dir.join("disk.img.versions.json"));
+	let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+	assert_eq!(written["components"][0]["commit"], "1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d");
+	assert_eq!(written["components"][0]["built"], "2026-10-18T09:30:00Z");
+	assert_eq!(written["components"][1]["commit"], serde_json::Value::Null);
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,131 @@
+//! Fixed VHD output, for Hyper-V and Azure.
+//!
+//! A fixed VHD is the raw image followed by a 512 byte footer describing it, so
+//! converting is appending the footer. Azure only takes images whose size is a whole
+//! number of MiB.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::SystemTime;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use bob_core::err::BobErr;
+
+pub const FOOTER_SZ: usize = 512;
+const COOKIE: &[u8; 8] = b"conectix";
+/// Reserved bit, always set.
+const FEATURES: u32 = 2;
+const FORMAT_VERSION: u32 = 0x0001_0000;
+/// No dynamic header.
+const FIXED_DATA_OFFSET: u64 = u64::MAX;
+const CREATOR_APP: &[u8; 4] = b"bob ";
+const CREATOR_VERSION: u32 = 0x0001_0000;
+const CREATOR_HOST: &[u8; 4] = b"Wi2k";
+const DISK_TYPE_FIXED: u32 = 2;
+const CHECKSUM_OFFSET: usize = 64;
+/// VHD timestamps count from 2000-01-01 00:00:00 UTC.
+pub const VHD_EPOCH: u64 = 946_684_800;
+
+/// Cylinders, heads and sectors per track for a disk of `size` bytes, computed as in the
+/// VHD specification's appendix.
+pub fn geometry(size: u64) -> (u16, u8, u8) {
+    let total = (size / 512).min(65535 * 16 * 255);
+    let (spt, heads, cylinder_times_heads) = if total >= 65535 * 16 * 63 {
+	(255, 16, total / 255)
+    } else {
+	let (mut spt, mut cth) = (17, total / 17);
+	let mut heads = cth.div_ceil(1024).max(4);
+	if cth >= heads * 1024 || heads > 16 {
+	    (spt, heads, cth) = (31, 16, total / 31);
+	}
+	if cth >= heads * 1024 {
+	    (spt, heads, cth) = (63, 16, total / 63);
+	}
+	(spt, heads, cth)
+    };
+    ((cylinder_times_heads / heads) as u16, heads as u8, spt as u8)
+}
+
+/// The footer for a fixed VHD of `size` bytes, `timestamp` seconds since `VHD_EPOCH`.
+pub fn footer(size: u64, uuid: [u8; 16], timestamp: u32) -> [u8; FOOTER_SZ] {
+    let mut f = [0; FOOTER_SZ];
+    f[0..8].copy_from_slice(COOKIE);
+    f[8..12].copy_from_slice(&FEATURES.to_be_bytes());
+    f[12..16].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    f[16..24].copy_from_slice(&FIXED_DATA_OFFSET.to_be_bytes());
+    f[24..28].copy_from_slice(&timestamp.to_be_bytes());
+    f[28..32].copy_from_slice(CREATOR_APP);
+    f[32..36].copy_from_slice(&CREATOR_VERSION.to_be_bytes());
+    f[36..40].copy_from_slice(CREATOR_HOST);
+    f[40..48].copy_from_slice(&size.to_be_bytes());
+    f[48..56].copy_from_slice(&size.to_be_bytes());
+    let (cylinders, heads, spt) = geometry(size);
+    f[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    f[58] = heads;
+    f[59] = spt;
+    f[60..64].copy_from_slice(&DISK_TYPE_FIXED.to_be_bytes());
+    f[68..84].copy_from_slice(&uuid);
+    let checksum = checksum(&f);
+    f[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+    f
+}
+
+/// One's complement of the byte sum, the checksum field counting as zero.
+fn checksum(f: &[u8; FOOTER_SZ]) -> u32 {
+    let sum = f.iter().enumerate()
+	.filter(|(i, _)| !(CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4).contains(i))
+	.fold(0u32, |sum, (_, b)| sum.wrapping_add(*b as u32));
+    !sum
+}
+
+/// The footer's UUID and timestamp. Seeded images get a UUID from the seed and the VHD
+/// epoch as their timestamp, so they come out the same every time.
+pub fn identity(seed: Option<u64>) -> ([u8; 16], u32) {
+    match seed {
+	Some(seed) => (StdRng::seed_from_u64(seed).gen(), 0),
+	None => {
+	    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	    (rand::random(), now.saturating_sub(VHD_EPOCH) as u32)
+	},
+    }
+}
+
+/// Turn the raw image in `f` into a fixed VHD by appending the footer.
+pub fn append_footer(f: &mut File, uuid: [u8; 16], timestamp: u32) -> Result<u64, BobErr> {
+    let size = f.seek(SeekFrom::End(0)).map_err(BobErr::IO)?;
+    f.write_all(&footer(size, uuid, timestamp)).map_err(BobErr::IO)?;
+    Ok(size)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn geometries() {
+	assert_eq!(geometry(1 << 30), (2080, 16, 63));
+	// Small disks stay at 17 sectors per track with at least 4 heads.
+	assert_eq!(geometry(8 << 20), (240, 4, 17));
+	// Past what 63 sectors per track can address, and the cap.
+	assert_eq!(geometry(64 << 30), (32896, 16, 255));
+	assert_eq!(geometry(4 << 40), (65535, 16, 255));
+    }
+
+    #[test]
+    fn footers() {
+	let f = footer(64 << 20, [7; 16], 12345);
+	assert_eq!(&f[..8], b"conectix");
+	assert_eq!(u32::from_be_bytes(f[60..64].try_into().unwrap()), DISK_TYPE_FIXED);
+	assert_eq!(u64::from_be_bytes(f[48..56].try_into().unwrap()), 64 << 20);
+	// The checksum is the complement of the other bytes' sum.
+	let sum = f.iter().fold(0u32, |s, b| s.wrapping_add(*b as u32));
+	let stored = u32::from_be_bytes(f[64..68].try_into().unwrap());
+	let field = f[64..68].iter().map(|b| *b as u32).sum::<u32>();
+	assert_eq!(sum - field, !stored);
+	assert_eq!(f, footer(64 << 20, [7; 16], 12345));
+	assert_eq!(identity(Some(3)), identity(Some(3)));
+	assert_ne!(identity(Some(3)).0, identity(Some(4)).0);
+    }
+}
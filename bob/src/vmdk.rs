@@ -0,0 +1,372 @@
+//! VMDK output, for VMware and VirtualBox.
+//!
+//! Two of VMDK's variants. monolithicFlat is the raw image renamed to `NAME-flat.vmdk`
+//! plus a text descriptor `NAME.vmdk` pointing at it, which VMware and VirtualBox open
+//! directly. streamOptimized is a single file of deflate compressed 64 KiB grains, what
+//! OVF/OVA appliances carry and vSphere imports; grains that are all zeros aren't stored.
+//!
+//! A streamOptimized file is: the header, the descriptor, then the grains in disk order,
+//! then a grain table per 32 MiB with any data, the grain directory, a footer repeating
+//! the header with the directory's location, and an end of stream marker. Everything
+//! after the descriptor is in 512 byte sectors and little endian.
+//!
+//! Deflating the grains is most of the time a conversion takes, so they're compressed on
+//! several threads (see pipeline.rs) and written in order as they come back.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::{write::ZlibEncoder, Compression};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::debug;
+
+use bob_core::err::BobErr;
+
+const SECTOR_SZ: u64 = 512;
+const MAGIC: &[u8; 4] = b"KDMV";
+const VERSION: u32 = 3;
+/// Valid newline detection, compressed grains, markers.
+const FLAGS: u32 = 1 | 1 << 16 | 1 << 17;
+const GRAIN_SECTORS: u64 = 128;
+const GRAIN_SZ: u64 = GRAIN_SECTORS * SECTOR_SZ;
+const GTES_PER_GT: u64 = 512;
+/// The grain directory's offset in the header when it's only known from the footer.
+const GD_AT_END: u64 = u64::MAX;
+const COMPRESS_DEFLATE: u16 = 1;
+const MARKER_EOS: u32 = 0;
+const MARKER_GT: u32 = 1;
+const MARKER_GD: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    MonolithicFlat,
+    StreamOptimized,
+}
+
+impl Variant {
+    fn create_type(&self) -> &'static str {
+	match self {
+	    Variant::MonolithicFlat => "monolithicFlat",
+	    Variant::StreamOptimized => "streamOptimized",
+	}
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    /// Grains with data in them, None for flat images.
+    pub data_grains: Option<u64>,
+    pub file_size: u64,
+}
+
+/// Cylinders, heads and sectors per track for an IDE disk of `sectors`, as qemu-img and
+/// VMware compute them.
+pub fn geometry(sectors: u64) -> (u64, u8, u8) {
+    ((sectors / (16 * 63)).min(16383), 16, 63)
+}
+
+/// The descriptor's content ID. Seeded images get one from the seed, so they come out
+/// the same every time.
+pub fn content_id(seed: Option<u64>) -> u32 {
+    match seed {
+	Some(seed) => StdRng::seed_from_u64(seed).gen(),
+	None => rand::random(),
+    }
+}
+
+/// The text descriptor for a disk of `sectors` in one extent, `extent` naming the file
+/// for flat images.
+pub fn descriptor(variant: Variant, sectors: u64, extent: &str, cid: u32) -> String {
+    let (cylinders, heads, spt) = geometry(sectors);
+    let extent = match variant {
+	Variant::MonolithicFlat => format!("RW {sectors} FLAT \"{extent}\" 0"),
+	Variant::StreamOptimized => format!("RW {sectors} SPARSE \"{extent}\""),
+    };
+    format!("# Disk DescriptorFile
+version=1
+encoding=\"UTF-8\"
+CID={cid:08x}
+parentCID=ffffffff
+createType=\"{}\"
+
+# Extent description
+{extent}
+
+# The Disk Data Base
+#DDB
+
+ddb.virtualHWVersion = \"4\"
+ddb.adapterType = \"ide\"
+ddb.geometry.cylinders = \"{cylinders}\"
+ddb.geometry.heads = \"{heads}\"
+ddb.geometry.sectors = \"{spt}\"
+", variant.create_type())
+}
+
+/// The file a flat image's data goes in: `disk.vmdk` gets `disk-flat.vmdk` next to it.
+pub fn flat_extent_path(path: &Path) -> PathBuf {
+    let stem = match path.extension() {
+	Some(ext) if ext == "vmdk" => path.file_stem(),
+	_ => path.file_name(),
+    };
+    let mut name = stem.unwrap_or_default().to_owned();
+    name.push("-flat.vmdk");
+    path.with_file_name(name)
+}
+
+/// Turn the raw image at `path` into a monolithicFlat VMDK: the data moves to its
+/// `-flat.vmdk` extent and `path` becomes the descriptor.
+pub fn convert_to_flat(path: &Path, cid: u32) -> Result<Stats, BobErr> {
+    let size = std::fs::metadata(path).map_err(BobErr::IO)?.len();
+    let extent = flat_extent_path(path);
+    std::fs::rename(path, &extent).map_err(BobErr::IO)?;
+    let name = extent.file_name().unwrap_or_default().to_string_lossy();
+    let d = descriptor(Variant::MonolithicFlat, size / SECTOR_SZ, &name, cid);
+    std::fs::write(path, &d).map_err(BobErr::IO)?;
+    Ok(Stats { data_grains: None, file_size: d.len() as u64 + size })
+}
+
+/// The sparse extent header, also used as the footer.
+fn header(capacity: u64, descriptor_sectors: u64, gd_offset: u64) -> [u8; SECTOR_SZ as usize] {
+    let mut h = [0; SECTOR_SZ as usize];
+    h[0..4].copy_from_slice(MAGIC);
+    h[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    h[8..12].copy_from_slice(&FLAGS.to_le_bytes());
+    h[12..20].copy_from_slice(&capacity.to_le_bytes());
+    h[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+    // The descriptor follows the header.
+    h[28..36].copy_from_slice(&1u64.to_le_bytes());
+    h[36..44].copy_from_slice(&descriptor_sectors.to_le_bytes());
+    h[44..48].copy_from_slice(&(GTES_PER_GT as u32).to_le_bytes());
+    h[56..64].copy_from_slice(&gd_offset.to_le_bytes());
+    // Overhead, in sectors before the first grain.
+    h[64..72].copy_from_slice(&(1 + descriptor_sectors).to_le_bytes());
+    h[73..77].copy_from_slice(b"\n \r\n");
+    h[77..79].copy_from_slice(&COMPRESS_DEFLATE.to_le_bytes());
+    h
+}
+
+fn marker(sectors: u64, ty: u32) -> [u8; SECTOR_SZ as usize] {
+    let mut m = [0; SECTOR_SZ as usize];
+    m[0..8].copy_from_slice(&sectors.to_le_bytes());
+    m[12..16].copy_from_slice(&ty.to_le_bytes());
+    m
+}
+
+/// Writes whole sectors, keeping count of where it is.
+struct Out<'w, W: Write> {
+    w: &'w mut W,
+    sector: u64,
+}
+
+impl<W: Write> Out<'_, W> {
+    /// Write `b` zero padded to a sector boundary, returning the sector it starts at.
+    fn write(&mut self, b: &[u8]) -> io::Result<u64> {
+	let at = self.sector;
+	self.w.write_all(b)?;
+	let pad = (SECTOR_SZ - b.len() as u64 % SECTOR_SZ) % SECTOR_SZ;
+	self.w.write_all(&[0; SECTOR_SZ as usize][..pad as usize])?;
+	self.sector += (b.len() as u64).div_ceil(SECTOR_SZ);
+	Ok(at)
+    }
+}
+
+/// Read grain `index` of a `size` byte raw image, zero padded at the end.
+fn read_grain<R: Read + Seek>(raw: &mut R, size: u64, index: u64, buf: &mut [u8]) -> io::Result<()> {
+    let start = index * GRAIN_SZ;
+    let n = (size - start).min(GRAIN_SZ) as usize;
+    raw.seek(SeekFrom::Start(start))?;
+    raw.read_exact(&mut buf[..n])?;
+    buf[n..].fill(0);
+    Ok(())
+}
+
+/// Grain tables and the directory hold 32 bit sector numbers, which limits the file to
+/// 2 TiB.
+fn sector_u32(sector: u64) -> Result<u32, BobErr> {
+    u32::try_from(sector).map_err(|_| BobErr::IO(io::Error::other("streamOptimized VMDK past 2 TiB")))
+}
+
+/// Deflate a grain of guest data at `index` into how it's stored: its LBA, the
+/// compressed length and the zlib stream. None if it's all zeros and isn't stored.
+fn compress_grain(index: u64, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    if data.iter().all(|b| *b == 0) {
+	return Ok(None);
+    }
+    let mut grain = Vec::with_capacity(GRAIN_SZ as usize / 2);
+    grain.extend_from_slice(&(index * GRAIN_SECTORS).to_le_bytes());
+    grain.extend_from_slice(&[0; 4]);
+    let mut e = ZlibEncoder::new(grain, Compression::default());
+    e.write_all(data)?;
+    let mut grain = e.finish()?;
+    let len = (grain.len() - 12) as u32;
+    grain[8..12].copy_from_slice(&len.to_le_bytes());
+    Ok(Some(grain))
+}
+
+/// Write `size` bytes of raw image as a streamOptimized VMDK whose descriptor names
+/// `extent` as its file, compressing on `threads` threads.
+pub fn write_stream<R: Read + Seek + Send, W: Write>(raw: &mut R, size: u64, out: &mut W, extent: &str, cid: u32, threads: usize) -> Result<Stats, BobErr> {
+    let capacity = size.div_ceil(SECTOR_SZ);
+    let d = descriptor(Variant::StreamOptimized, capacity, extent, cid);
+    let descriptor_sectors = (d.len() as u64).div_ceil(SECTOR_SZ);
+    let mut out = Out { w: out, sector: 0 };
+    out.write(&header(capacity, descriptor_sectors, GD_AT_END)).map_err(BobErr::IO)?;
+    out.write(d.as_bytes()).map_err(BobErr::IO)?;
+
+    let grains = size.div_ceil(GRAIN_SZ);
+    let mut grain_tables = vec![0u32; grains.next_multiple_of(GTES_PER_GT) as usize];
+    let mut data_grains = 0;
+    let mut next = 0;
+    let read = || {
+	if next == grains {
+	    return Ok(None);
+	}
+	let mut buf = vec![0; GRAIN_SZ as usize];
+	read_grain(raw, size, next, &mut buf).map_err(BobErr::IO)?;
+	next += 1;
+	Ok(Some((next - 1, buf)))
+    };
+    let compress = |(i, buf): (u64, Vec<u8>)| Ok((i, compress_grain(i, &buf).map_err(BobErr::IO)?));
+    crate::pipeline::ordered(threads, read, compress, |(i, grain)| {
+	if let Some(grain) = grain {
+	    let at = out.write(&grain).map_err(BobErr::IO)?;
+	    grain_tables[i as usize] = sector_u32(at)?;
+	    data_grains += 1;
+	}
+	Ok(())
+    })?;
+
+    // Grain tables only for the 32 MiB stretches with data, the rest read as zeros.
+    let mut directory = Vec::new();
+    for table in grain_tables.chunks(GTES_PER_GT as usize) {
+	if table.iter().all(|e| *e == 0) {
+	    directory.push(0u32);
+	    continue;
+	}
+	let bytes: Vec<u8> = table.iter().flat_map(|e| e.to_le_bytes()).collect();
+	out.write(&marker(bytes.len() as u64 / SECTOR_SZ, MARKER_GT)).map_err(BobErr::IO)?;
+	let at = out.write(&bytes).map_err(BobErr::IO)?;
+	directory.push(sector_u32(at)?);
+    }
+    let bytes: Vec<u8> = directory.iter().flat_map(|e| e.to_le_bytes()).collect();
+    out.write(&marker((bytes.len() as u64).div_ceil(SECTOR_SZ), MARKER_GD)).map_err(BobErr::IO)?;
+    let gd_offset = out.write(&bytes).map_err(BobErr::IO)?;
+
+    out.write(&marker(1, MARKER_FOOTER)).map_err(BobErr::IO)?;
+    out.write(&header(capacity, descriptor_sectors, gd_offset)).map_err(BobErr::IO)?;
+    out.write(&marker(0, MARKER_EOS)).map_err(BobErr::IO)?;
+    out.w.flush().map_err(BobErr::IO)?;
+
+    debug!(grains, data_grains, tables = directory.iter().filter(|e| **e != 0).count(), "wrote streamOptimized vmdk");
+    Ok(Stats { data_grains: Some(data_grains), file_size: out.sector * SECTOR_SZ })
+}
+
+/// Replace the raw image at `path` with a streamOptimized VMDK, through a temporary file
+/// next to it.
+pub fn convert_to_stream(path: &Path, cid: u32, threads: usize) -> Result<Stats, BobErr> {
+    let mut raw = File::open(path).map_err(BobErr::IO)?;
+    let size = raw.metadata().map_err(BobErr::IO)?.len();
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".vmdk.tmp");
+    let tmp = Path::new(&tmp_name);
+    let extent = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let mut out = io::BufWriter::new(File::create(tmp).map_err(BobErr::IO)?);
+    let stats = match write_stream(&mut raw, size, &mut out, &extent, cid, threads) {
+	Ok(stats) => stats,
+	Err(e) => {
+	    drop(out);
+	    let _ = std::fs::remove_file(tmp);
+	    return Err(e);
+	},
+    };
+    drop(out);
+    std::fs::rename(tmp, path).map_err(BobErr::IO)?;
+    Ok(stats)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Guest byte at `pos` of a streamOptimized image, following the footer's grain
+    /// directory the way a reader does.
+    #[allow(dead_code)]
+    fn read_guest(img: &[u8], pos: u64) -> u8 {
+	let le32 = |at: u64| u32::from_le_bytes(img[at as usize..at as usize + 4].try_into().unwrap()) as u64;
+	let footer = img.len() as u64 - 2 * SECTOR_SZ;
+	let gd = u64::from_le_bytes(img[footer as usize + 56..footer as usize + 64].try_into().unwrap());
+	let grain = pos / GRAIN_SZ;
+	let gt = le32(gd * SECTOR_SZ + grain / GTES_PER_GT * 4);
+	if gt == 0 {
+	    return 0;
+	}
+	let at = le32(gt * SECTOR_SZ + grain % GTES_PER_GT * 4) * SECTOR_SZ;
+	if at == 0 {
+	    return 0;
+	}
+	assert_eq!(u64::from_le_bytes(img[at as usize..at as usize + 8].try_into().unwrap()), grain * GRAIN_SECTORS);
+	let len = le32(at + 8) as usize;
+	let mut data = Vec::new();
+	flate2::read::ZlibDecoder::new(&img[at as usize + 12..at as usize + 12 + len]).read_to_end(&mut data).unwrap();
+	data[(pos % GRAIN_SZ) as usize]
+    }
+
+    #[test]
+    fn stream_round_trip() {
+	// Past the 32 MiB one grain table covers, a few grains of data in a sparse file.
+	let size = 40 * 1024 * 1024;
+	let spots = [0, 511, 70_000, 35 * 1024 * 1024, size - 1];
+	let p = std::env::temp_dir().join(format!("bob-test-{}-vmdk.img", std::process::id()));
+	let mut raw = File::options().read(true).write(true).create(true).truncate(true).open(&p).unwrap();
+	raw.set_len(size).unwrap();
+	for (i, at) in spots.iter().enumerate() {
+	    raw.seek(SeekFrom::Start(*at)).unwrap();
+	    raw.write_all(&[i as u8 + 1]).unwrap();
+	}
+
+	let mut out = Vec::new();
+	let stats = write_stream(&mut raw, size, &mut out, "disk.vmdk", 0x1234, 3);
+	// The same file on one thread.
+	let mut serial = Vec::new();
+	write_stream(&mut raw, size, &mut serial, "disk.vmdk", 0x1234, 1).unwrap();
+	let _ = std::fs::remove_file(&p);
+	let stats = stats.unwrap();
+	assert!(serial == out);
+	assert_eq!(stats.data_grains, Some(4));
+	assert_eq!(stats.file_size, out.len() as u64);
+	assert_eq!(&out[..4], MAGIC);
+	assert_eq!(u64::from_le_bytes(out[56..64].try_into().unwrap()), GD_AT_END);
+	let d = String::from_utf8_lossy(&out[512..1024]);
+	assert!(d.contains("createType=\"streamOptimized\""));
+	assert!(d.contains(&format!("RW {} SPARSE \"disk.vmdk\"", size / 512)));
+	// Ends with the footer marker, footer and end of stream marker.
+	let end = out.len() - 3 * 512;
+	assert_eq!(u32::from_le_bytes(out[end + 12..end + 16].try_into().unwrap()), MARKER_FOOTER);
+	assert_eq!(&out[end + 512..end + 516], MAGIC);
+	assert!(out[end + 1024..].iter().all(|b| *b == 0));
+
+	for (i, at) in spots.iter().enumerate() {
+	    assert_eq!(read_guest(&out, *at), i as u8 + 1, "byte at {at}");
+	}
+	assert_eq!(read_guest(&out, 1), 0);
+	assert_eq!(read_guest(&out, 20 * 1024 * 1024), 0);
+    }
+
+    #[test]
+    fn flat_descriptor() {
+	assert_eq!(flat_extent_path(Path::new("out/disk.vmdk")), Path::new("out/disk-flat.vmdk"));
+	assert_eq!(flat_extent_path(Path::new("disk.img")), Path::new("disk.img-flat.vmdk"));
+	assert_eq!(geometry(2 << 21), (4161, 16, 63));
+
+	let d = descriptor(Variant::MonolithicFlat, 131072, "disk-flat.vmdk", 0xabc);
+	assert!(d.starts_with("# Disk DescriptorFile\n"));
+	assert!(d.contains("CID=00000abc\n"));
+	assert!(d.contains("\nRW 131072 FLAT \"disk-flat.vmdk\" 0\n"));
+	assert!(d.contains("ddb.geometry.cylinders = \"130\""));
+	assert_eq!(content_id(Some(1)), content_id(Some(1)));
+    }
+}
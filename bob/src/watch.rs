@@ -0,0 +1,265 @@
+//! Rebuilding images as their inputs change, for `bob watch`.
+//!
+//! The images are built once from a JSON manifest or a TOML layout (see manifest.rs),
+//! then bob polls what went into them: the manifest itself and the source of every
+//! partition's contents, a file or each file under a squashfs directory. A change to a
+//! source refills only the partitions built from it, in every image that has them; their
+//! squashfs images are packed again, everything else comes from the build cache. A change
+//! to the manifest rebuilds the images from scratch. Sources are compared by name, size
+//! and modification time, no hashing of kernels on every poll.
+//!
+//! A refill writes the new contents over the start of the partition. Whatever the old
+//! contents had past the end of the new ones stays, a filesystem image doesn't look there.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+use bob_core::err::BobErr;
+use bob_core::gpt::PartitionTable;
+use crate::manifest::{Contents, Layout, Manifest, TargetPlan};
+
+/// What's being watched: a manifest of targets, or a single image's layout.
+pub enum Spec {
+    Manifest(PathBuf),
+    Layout(PathBuf),
+}
+
+impl Spec {
+    pub fn path(&self) -> &Path {
+	match self {
+	    Self::Manifest(p) | Self::Layout(p) => p,
+	}
+    }
+
+    /// Every image to build, read afresh.
+    pub fn targets(&self) -> Result<Vec<TargetPlan>, BobErr> {
+	let base = self.path().parent().unwrap_or(Path::new("."));
+	match self {
+	    Self::Manifest(p) => Manifest::load(p)?.targets(base),
+	    Self::Layout(p) => Ok(vec![Layout::load(p)?.target(None, base)?]),
+	}
+    }
+}
+
+/// An image as built, what a refill needs to know about it.
+pub struct Image {
+    pub output: PathBuf,
+    pub table: PartitionTable,
+    pub contents: Vec<(String, Contents)>,
+}
+
+/// Names, sizes and modification times of `files`. A file that's missing, say halfway
+/// through a relink, counts as a change too.
+fn stamp_files(files: &[PathBuf]) -> u64 {
+    let mut h = DefaultHasher::new();
+    for f in files {
+	f.hash(&mut h);
+	match fs::metadata(f) {
+	    Ok(m) => (m.len(), m.modified().ok()).hash(&mut h),
+	    Err(_) => 0u8.hash(&mut h),
+	}
+    }
+    h.finish()
+}
+
+/// A stamp of what `contents` is built from, different whenever a source file is
+/// changed, added or removed.
+pub fn stamp(contents: &Contents) -> u64 {
+    match contents {
+	Contents::File(f) => stamp_files(std::slice::from_ref(f)),
+	Contents::Squashfs(dir) => stamp_files(&crate::versions::files_under(dir).unwrap_or_default()),
+    }
+}
+
+/// What changed since the last poll.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// The manifest or layout itself.
+    pub spec: bool,
+    pub contents: Vec<Contents>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+	!self.spec && self.contents.is_empty()
+    }
+
+    pub fn merge(&mut self, other: Changes) {
+	self.spec |= other.spec;
+	for c in other.contents {
+	    if !self.contents.contains(&c) {
+		self.contents.push(c);
+	    }
+	}
+    }
+}
+
+pub struct Watcher {
+    spec: Spec,
+    spec_stamp: u64,
+    stamps: HashMap<Contents, u64>,
+}
+
+impl Watcher {
+    /// Watch `spec` and the sources of `images`' contents, as they are now.
+    pub fn new(spec: Spec, images: &[Image]) -> Self {
+	let spec_stamp = stamp_files(&[spec.path().to_path_buf()]);
+	let mut w = Self { spec, spec_stamp, stamps: HashMap::new() };
+	w.reset(images);
+	w
+    }
+
+    pub fn spec(&self) -> &Spec {
+	&self.spec
+    }
+
+    /// Start over with `images`' contents, after a full rebuild.
+    pub fn reset(&mut self, images: &[Image]) {
+	self.stamps = images.iter()
+	    .flat_map(|img| img.contents.iter().map(|(_, c)| (c.clone(), stamp(c))))
+	    .collect();
+    }
+
+    /// What changed since the last poll.
+    pub fn poll(&mut self) -> Changes {
+	let mut changes = Changes::default();
+	let spec_stamp = stamp_files(&[self.spec.path().to_path_buf()]);
+	if spec_stamp != self.spec_stamp {
+	    self.spec_stamp = spec_stamp;
+	    changes.spec = true;
+	}
+	for (c, old) in self.stamps.iter_mut() {
+	    let new = stamp(c);
+	    if new != *old {
+		*old = new;
+		changes.contents.push(c.clone());
+	    }
+	}
+	changes
+    }
+}
+
+/// The partitions to refill for `changed`, as (image, partition name, contents).
+pub fn affected<'a>(images: &'a [Image], changed: &[Contents]) -> Vec<(&'a Image, &'a str, &'a Contents)> {
+    images.iter()
+	.flat_map(|img| img.contents.iter().map(move |(name, c)| (img, name.as_str(), c)))
+	.filter(|(_, _, c)| changed.contains(c))
+	.collect()
+}
+
+/// A command, QEMU say, run through the shell and started again after every rebuild.
+/// It's stopped when dropped.
+pub struct Qemu {
+    command: String,
+    child: Option<Child>,
+}
+
+impl Qemu {
+    pub fn new(command: &str) -> Self {
+	Self { command: String::from(command), child: None }
+    }
+
+    /// Stop the command if it's running and start it again. Returns its pid.
+    pub fn restart(&mut self) -> Result<u32, BobErr> {
+	self.stop();
+	// exec, so the shell doesn't outlive the kill.
+	let child = Command::new("sh").arg("-c").arg(format!("exec {}", self.command)).spawn().map_err(BobErr::IO)?;
+	let pid = child.id();
+	self.child = Some(child);
+	Ok(pid)
+    }
+
+    fn stop(&mut self) {
+	if let Some(mut child) = self.child.take() {
+	    let _ = child.kill();
+	    let _ = child.wait();
+	}
+    }
+}
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+	self.stop();
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// The first bytes of partition `name` in `image`.
+    #[allow(dead_code)]
+    fn head(image: &Path, name: &str) -> [u8; 64] {
+	use std::io::Read;
+	let mut img = bob_core::gpt::GptImage::open_read_only(&image.to_string_lossy()).unwrap();
+	let mut b = [0; 64];
+	bob_core::gpt::DiskImage::get_partition_view(&mut img, name).unwrap().read_exact(&mut b).unwrap();
+	b
+    }
+
+    #[test]
+    fn notices_changes() {
+	let dir = std::env::temp_dir().join(format!("bob-watch-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(dir.join("rootfs")).unwrap();
+	fs::write(dir.join("esp.img"), b"fat").unwrap();
+	fs::write(dir.join("rootfs").join("init"), b"v1").unwrap();
+	let manifest = r#"{
+	    "size": "8M",
+	    "partitions": [
+		{ "name": "ESP", "type": "esp", "size": "2M", "contents": { "file": "esp.img" } },
+		{ "name": "root", "type": "root", "size": "2M", "contents": { "squashfs": "rootfs" } }
+	    ],
+	    "targets": [{ "output": "debug.img" }, { "output": "small.img", "partitions": ["root"] }]
+	}"#;
+	fs::write(dir.join("image.json"), manifest).unwrap();
+
+	let spec = Spec::Manifest(dir.join("image.json"));
+	let backend = bob_core::sink::IoBackend::File;
+	let mut cache = crate::manifest::ArtifactCache::new().unwrap();
+	let mut images = crate::cmd::build_images(spec.targets().unwrap(), backend, &mut cache).unwrap();
+	let (debug, small) = (dir.join("debug.img"), dir.join("small.img"));
+	assert_eq!(head(&debug, "ESP")[..3], *b"fat");
+	let root = head(&small, "root");
+	assert_eq!(root[..4], *b"hsqs");
+	let mut w = Watcher::new(spec, &images);
+	assert!(w.poll().is_empty());
+
+	// A new file in the squashfs directory refills root in both images, not the ESP.
+	fs::write(dir.join("rootfs").join("motd"), b"hello").unwrap();
+	let changes = w.poll();
+	let root_contents = Contents::Squashfs(dir.join("rootfs"));
+	assert_eq!(changes, Changes { spec: false, contents: vec![root_contents.clone()] });
+	let refills: Vec<_> = affected(&images, &changes.contents).into_iter().map(|(img, name, _)| (img.output.file_name().unwrap().to_owned(), name)).collect();
+	assert_eq!(refills, [(std::ffi::OsString::from("debug.img"), "root"), (std::ffi::OsString::from("small.img"), "root")]);
+	crate::cmd::rebuild(&mut w, &mut images, &changes, backend, &mut cache).unwrap();
+	let refilled = head(&small, "root");
+	assert_ne!(refilled, root);
+	assert_eq!(head(&debug, "root"), refilled);
+	assert_eq!(head(&debug, "ESP")[..3], *b"fat");
+	assert!(w.poll().is_empty());
+
+	// Removed, as while it's being rewritten, then back with other contents.
+	fs::remove_file(dir.join("esp.img")).unwrap();
+	let mut changes = w.poll();
+	fs::write(dir.join("esp.img"), b"fat32").unwrap();
+	changes.merge(w.poll());
+	assert_eq!(changes.contents, [Contents::File(dir.join("esp.img"))]);
+	crate::cmd::rebuild(&mut w, &mut images, &changes, backend, &mut cache).unwrap();
+	assert_eq!(head(&debug, "ESP")[..5], *b"fat32");
+
+	// A changed manifest rebuilds both images.
+	fs::write(dir.join("image.json"), manifest.replace("8M", "16M")).unwrap();
+	let changes = w.poll();
+	assert!(changes.spec);
+	crate::cmd::rebuild(&mut w, &mut images, &changes, backend, &mut cache).unwrap();
+	assert_eq!(fs::metadata(&small).unwrap().len(), 16 * 1024 * 1024);
+	assert_eq!(head(&small, "root"), refilled);
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
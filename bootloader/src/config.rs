@@ -0,0 +1,96 @@
+//! Boot configuration.
+//!
+//! The config sits next to the kernel on the ESP. If the bootloader was built with
+//! `YOYO_CONFIG_PUBKEY` set (the hex public key from `bob keygen`) the config has to come
+//! with a valid Ed25519 signature in config.sig (from `bob sign`), otherwise it's refused.
+//! Someone with write access to the ESP could otherwise point us at any kernel. Built
+//! without a key, the config is used as is with a warning.
+
+use core::fmt;
+
+use common::error::{ErrorCode, Subsystem};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{info, warn};
+use uefi::{
+    prelude::*,
+    table::boot::MemoryType,
+};
+use crate::read_file;
+
+const CONFIG_PATH: &str = "\\efi\\boot\\config";
+const CONFIG_SIG_PATH: &str = "\\efi\\boot\\config.sig";
+const CONFIG_PUBKEY: Option<&str> = option_env!("YOYO_CONFIG_PUBKEY");
+
+#[derive(Debug)]
+pub enum ConfigErr {
+    Read(Status),
+    BadPublicKey,
+    MissingSignature,
+    BadSignature,
+}
+
+impl ErrorCode for ConfigErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Config;
+
+    fn code(&self) -> (u16, u32) {
+	match self {
+	    // EFI error statuses are the high bit and a small number, keep the number.
+	    Self::Read(status) => (1, status.0 as u32),
+	    Self::BadPublicKey => (2, 0),
+	    Self::MissingSignature => (3, 0),
+	    Self::BadSignature => (4, 0),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "failed to read boot config: EFI status {arg}"),
+	    2 => write!(f, "built in YOYO_CONFIG_PUBKEY isn't a valid Ed25519 public key"),
+	    3 => write!(f, "boot config isn't signed ({} is missing)", CONFIG_SIG_PATH),
+	    4 => write!(f, "boot config signature doesn't match, refusing to boot"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+/// Load the boot config and check its signature, None if there isn't a config.
+pub fn load(image_handle: Handle, boot_services: &BootServices) -> Result<Option<&'static [u8]>, ConfigErr> {
+    let config = match read_file(image_handle, boot_services, CONFIG_PATH, MemoryType::LOADER_DATA) {
+	Ok(config) => config,
+	Err(e) if e.status() == Status::NOT_FOUND => {
+	    info!("No boot config");
+	    return Ok(None);
+	},
+	Err(e) => return Err(ConfigErr::Read(e.status())),
+    };
+
+    let Some(key) = CONFIG_PUBKEY else {
+	warn!("Boot config signature not checked, bootloader was built without YOYO_CONFIG_PUBKEY");
+	return Ok(Some(config));
+    };
+    let key = parse_key(key.trim()).ok_or(ConfigErr::BadPublicKey)?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| ConfigErr::BadPublicKey)?;
+
+    let sig = match read_file(image_handle, boot_services, CONFIG_SIG_PATH, MemoryType::LOADER_DATA) {
+	Ok(sig) => sig,
+	Err(e) if e.status() == Status::NOT_FOUND => return Err(ConfigErr::MissingSignature),
+	Err(e) => return Err(ConfigErr::Read(e.status())),
+    };
+    let sig = Signature::from_slice(sig).map_err(|_| ConfigErr::BadSignature)?;
+    key.verify_strict(config, &sig).map_err(|_| ConfigErr::BadSignature)?;
+
+    info!("Boot config signature is good");
+    Ok(Some(config))
+}
+
+fn parse_key(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+	return None;
+    }
+
+    let mut key = [0; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+	*b = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
@@ -0,0 +1,60 @@
+//! Build time defaults.
+//!
+//! Each can be overridden with an environment variable when building the bootloader, so a
+//! downstream build can be retuned without patching the source:
+//!
+//! - `YOYO_KERNEL_PATH`: kernel to load from the ESP, default `\efi\boot\kernel`
+//! - `YOYO_CMDLINE`: kernel command line, default empty
+//! - `YOYO_MENU_TIMEOUT`: seconds to show the boot menu for, default 3
+//! - `YOYO_SERIAL_PORT`: I/O port of the serial console, `0` to disable, default `0x3F8`
+//!
+//! Numbers are decimal or `0x` hex. A bad value fails the build rather than the boot.
+
+/// Longest path `read_file` can open, in UCS-2 characters including the terminator.
+pub const MAX_PATH_LEN: usize = 256;
+
+pub const KERNEL_PATH: &str = {
+    let path = env_or(option_env!("YOYO_KERNEL_PATH"), "\\efi\\boot\\kernel");
+    assert!(path.len() < MAX_PATH_LEN, "YOYO_KERNEL_PATH is too long");
+    path
+};
+pub const CMDLINE: &str = env_or(option_env!("YOYO_CMDLINE"), "");
+pub const MENU_TIMEOUT_SECS: u64 = parse_u64(env_or(option_env!("YOYO_MENU_TIMEOUT"), "3"));
+pub const SERIAL_PORT: u16 = {
+    let port = parse_u64(env_or(option_env!("YOYO_SERIAL_PORT"), "0x3F8"));
+    assert!(port <= u16::MAX as u64, "YOYO_SERIAL_PORT isn't an I/O port");
+    port as u16
+};
+
+const fn env_or(var: Option<&'static str>, default: &'static str) -> &'static str {
+    match var {
+	Some(v) => v,
+	None => default,
+    }
+}
+
+const fn parse_u64(s: &str) -> u64 {
+    let b = s.as_bytes();
+    let (radix, mut i) = if b.len() > 2 && b[0] == b'0' && (b[1] == b'x' || b[1] == b'X') {
+	(16, 2)
+    } else {
+	(10, 0)
+    };
+    assert!(i < b.len(), "expected a number");
+
+    let mut n: u64 = 0;
+    while i < b.len() {
+	let d = match b[i] {
+	    c @ b'0'..=b'9' => c - b'0',
+	    c @ b'a'..=b'f' if radix == 16 => c - b'a' + 10,
+	    c @ b'A'..=b'F' if radix == 16 => c - b'A' + 10,
+	    _ => panic!("expected a decimal or 0x hex number"),
+	};
+	n = match n.checked_mul(radix) {
+	    Some(n) if n <= u64::MAX - d as u64 => n + d as u64,
+	    _ => panic!("number too big"),
+	};
+	i += 1;
+    }
+    n
+}
@@ -0,0 +1,78 @@
+//! Bootloader logging.
+//!
+//! Messages go to the UEFI console and are also recorded in a `LogRing`, which is handed to
+//! the kernel through BootInfo so the loader's messages survive the kernel taking over the
+//! screen.
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use log::{Log, Metadata, Record};
+use uefi::{
+    prelude::*,
+    logger::Logger,
+    table::boot::{
+	AllocateType,
+	MemoryType,
+    },
+};
+use common::{
+    boot::MemRegion,
+    logbuf::LogRing,
+};
+
+const PAGE_SZ: usize = 4096;
+/// Size of the log ring buffer in pages.
+const LOG_PAGES: usize = 4;
+
+struct BootLogger {
+    console: Logger,
+    ring: UnsafeCell<Option<LogRing<'static>>>,
+}
+
+// The UEFI boot environment only uses one processor.
+unsafe impl Sync for BootLogger {}
+
+static LOGGER: BootLogger = BootLogger {
+    console: Logger::new(),
+    ring: UnsafeCell::new(None),
+};
+
+/// Install the bootloader logger. Returns the region holding the log ring buffer so it can
+/// be passed along to the kernel.
+pub fn init(system_table: &mut SystemTable<Boot>) -> uefi::Result<MemRegion> {
+    let len = LOG_PAGES * PAGE_SZ;
+    // LOADER_DATA stays untouched after exiting boot services until the kernel reclaims it.
+    let addr = system_table.boot_services().allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, LOG_PAGES)?;
+    let mem = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+
+    unsafe {
+	*LOGGER.ring.get() = LogRing::new(mem);
+	LOGGER.console.set_output(system_table.stdout());
+    }
+
+    log::set_logger(&LOGGER).expect("logger to only be initialized once");
+    log::set_max_level(log::STATIC_MAX_LEVEL);
+
+    Ok(MemRegion { addr, len: len as u64 })
+}
+
+/// Stop logging to the UEFI console. This must be called before exiting boot services,
+/// messages will still be recorded in the ring buffer afterwards.
+pub fn disable_console() {
+    LOGGER.console.disable();
+}
+
+impl Log for BootLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+	true
+    }
+
+    fn log(&self, record: &Record) {
+	self.console.log(record);
+	if let Some(ring) = unsafe { (*self.ring.get()).as_mut() } {
+	    let _ = writeln!(ring, "[{:>5}]: {}", record.level(), record.args());
+	}
+    }
+
+    fn flush(&self) {}
+}
@@ -1,6 +1,10 @@
 #![no_main]
 #![no_std]
 
+mod config;
+mod defaults;
+mod logger;
+
 use log::info;
 use uefi::{
     Result,
@@ -14,42 +18,69 @@ use uefi::{
     
     data_types::CStr16,
     table::boot::{
+	AllocateType,
 	MemoryType,
 	MemoryMap,
     },
 };
 use common::{
+    boot::{mem_kind, tag, BootInfo, BootInfoWriter, MemRegion, MemoryMapEntry},
+    build_id::{self, Component, RECORD_SZ},
     elf::{load_elf, Elf},
+    error::Context,
     memory::frame::FrameAllocator,
 };
 
-const KERNEL_PATH: &'static str = "\\efi\\boot\\kernel";
+/// Found by bob in the files it puts in an image, see common::build_id.
+#[used]
+static BUILD_ID: [u8; RECORD_SZ] = build_id::THIS.record(Component::Bootloader);
 
-/// Read the kernel binary from disk.
-fn load_kernel(image_handle: Handle, boot_services: &BootServices) -> Result<&'static mut [u8]> {
+const PAGE_SZ: usize = 4096;
+/// Size of the BootInfo buffer in pages, most of it goes to the memory map.
+const BOOT_INFO_PAGES: usize = 4;
+
+/// Read a whole file from the ESP the bootloader was loaded from.
+fn read_file(image_handle: Handle, boot_services: &BootServices, path: &str, memory_type: MemoryType) -> Result<&'static mut [u8]> {
     let mut simple_fs_proto = boot_services.get_image_file_system(image_handle)?;
     let mut root_dir = simple_fs_proto.open_volume()?;
-    let mut buf = [0;64];
-    let kernel = root_dir.open(CStr16::from_str_with_buf(KERNEL_PATH, &mut buf).unwrap(), FileMode::Read, FileAttribute::empty())?;
+    let mut buf = [0; defaults::MAX_PATH_LEN];
+    let file = root_dir.open(CStr16::from_str_with_buf(path, &mut buf).unwrap(), FileMode::Read, FileAttribute::empty())?;
 
-    info!("Hello, uefi!");
-    info!("Parsing kernel elf binary...");
-
-    let mut kernel = kernel.into_regular_file().expect("The kernel to be a regular file");
+    let mut file = file.into_regular_file().ok_or(Status::INVALID_PARAMETER)?;
     let mut buf = [0;512];
-    let file_info: &mut FileInfo = kernel.get_info(&mut buf).expect("file info");
-    let kernel_sz = usize::try_from(file_info.file_size()).unwrap();
+    let file_info: &mut FileInfo = file.get_info(&mut buf).map_err(|e| e.status())?;
+    let file_sz = usize::try_from(file_info.file_size()).unwrap();
 
-    let kbuf = boot_services.allocate_pool(MemoryType::RESERVED, kernel_sz).expect("kernel buf alloc");
-    unsafe { core::ptr::write_bytes(kbuf, 0, kernel_sz) }
-    let kbuf = unsafe { core::slice::from_raw_parts_mut(kbuf, kernel_sz) };
+    let fbuf = boot_services.allocate_pool(memory_type, file_sz)?;
+    unsafe { core::ptr::write_bytes(fbuf, 0, file_sz) }
+    let fbuf = unsafe { core::slice::from_raw_parts_mut(fbuf, file_sz) };
 
-    let bytes_read = kernel.read(kbuf).expect("read kernel");
-    assert!(bytes_read == kernel_sz);
-    Ok(kbuf)
+    let bytes_read = file.read(fbuf).map_err(|e| e.status())?;
+    assert!(bytes_read == file_sz);
+    Ok(fbuf)
 }
 
-fn switch_to_kernel<'a>(_kernel_elf: Elf<'a>, _frame_alloc: FrameAllocator) -> ! {
+/// Read the kernel binary from disk.
+fn load_kernel(image_handle: Handle, boot_services: &BootServices) -> Result<&'static mut [u8]> {
+    info!("Hello, uefi!");
+    info!("Parsing kernel elf binary...");
+    read_file(image_handle, boot_services, defaults::KERNEL_PATH, MemoryType::RESERVED)
+}
+
+/// Map UEFI memory types onto the ones the kernel cares about.
+fn memory_kind(ty: MemoryType) -> u32 {
+    match ty {
+	MemoryType::CONVENTIONAL
+	    | MemoryType::BOOT_SERVICES_CODE
+	    | MemoryType::BOOT_SERVICES_DATA => mem_kind::USABLE,
+	MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => mem_kind::LOADER,
+	MemoryType::ACPI_RECLAIM => mem_kind::ACPI_RECLAIMABLE,
+	MemoryType::ACPI_NON_VOLATILE => mem_kind::ACPI_NVS,
+	_ => mem_kind::RESERVED,
+    }
+}
+
+fn switch_to_kernel<'a>(_kernel_elf: Elf<'a>, _frame_alloc: FrameAllocator, _boot_info: &'static BootInfo) -> ! {
     loop {}
 }
 
@@ -65,16 +96,51 @@ fn switch_to_kernel<'a>(_kernel_elf: Elf<'a>, _frame_alloc: FrameAllocator) -> !
 #[entry]
 fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).unwrap();
+    let loader_log = logger::init(&mut system_table).expect("log buffer alloc");
+    info!("yoyo bootloader, {}", build_id::THIS);
+    info!(
+	"Kernel {}, command line \"{}\", menu timeout {}s, serial port {:#x}",
+	defaults::KERNEL_PATH, defaults::CMDLINE, defaults::MENU_TIMEOUT_SECS, defaults::SERIAL_PORT
+    );
 
     let boot_services = system_table.boot_services();
     let kernel = load_kernel(image_handle, boot_services).expect("Kernel bytes from disk");
-    let kernel_elf = load_elf(kernel).expect("Kernel is a valid ELF binary");
+    let kernel_elf = load_elf(kernel).context("loading the kernel").unwrap_or_else(|e| panic!("{e}"));
+    let config = config::load(image_handle, boot_services).context("loading the boot config").unwrap_or_else(|e| panic!("{e}"));
+
+    let boot_info = boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, BOOT_INFO_PAGES).expect("boot info alloc");
+    let boot_info_buf = unsafe { core::slice::from_raw_parts_mut(boot_info as *mut u8, BOOT_INFO_PAGES * PAGE_SZ) };
+    let mut boot_info_writer = BootInfoWriter::new(boot_info_buf).expect("boot info to be page aligned");
+    boot_info_writer.push_region(tag::LOADER_LOG, loader_log).expect("boot info space");
+    if let Some(config) = config {
+	boot_info_writer.push_region(tag::CONFIG, MemRegion {
+	    addr: config.as_ptr() as u64,
+	    len: config.len() as u64,
+	}).expect("boot info space");
+    }
+    boot_info_writer.push(tag::BUILD_ID, [&BUILD_ID]).expect("boot info space");
+    if !defaults::CMDLINE.is_empty() {
+	boot_info_writer.push(tag::CMDLINE, [defaults::CMDLINE.as_bytes()]).expect("boot info space");
+    }
 
     info!("exit boot services");
+    logger::disable_console();
     let (_system_table, mut memory_map) = system_table.exit_boot_services(MemoryType::RESERVED);
     memory_map.sort();
 
+    let entries = memory_map.entries().map(|d| MemoryMapEntry {
+	region: MemRegion {
+	    addr: d.phys_start,
+	    len: d.page_count * PAGE_SZ as u64,
+	},
+	kind: memory_kind(d.ty),
+    }.to_bytes());
+    // Can't panic (or print) anymore, the kernel finds out about a truncated map by its size.
+    let _ = boot_info_writer.push(tag::MEMORY_MAP, entries);
+    boot_info_writer.finish();
+    let boot_info = unsafe { &*(boot_info as *const BootInfo) };
+
     let frame_alloc = FrameAllocator::new(memory_map);
 
-    switch_to_kernel(kernel_elf, frame_alloc);
+    switch_to_kernel(kernel_elf, frame_alloc, boot_info);
 }
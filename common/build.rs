@@ -0,0 +1,30 @@
+//! Stamps common with the git commit and the time it's built, see src/build_id.rs.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git(args: &[&str]) -> Option<String> {
+    let out = Command::new("git").args(args).output().ok().filter(|o| o.status.success())?;
+    Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn main() {
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or_default();
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    // Reproducible builds pin the time.
+    let time = std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|t| t.parse::<u64>().ok())
+	.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+
+    println!("cargo:rustc-env=YOYO_GIT_COMMIT={commit}{}", if dirty { "-dirty" } else { "" });
+    println!("cargo:rustc-env=YOYO_BUILD_TIME={time}");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // A new commit, a checkout or staged changes. Edits that aren't staged yet don't
+    // rebuild common on their own.
+    if let Some(dir) = git(&["rev-parse", "--git-dir"]) {
+	println!("cargo:rerun-if-changed={dir}/HEAD");
+	println!("cargo:rerun-if-changed={dir}/index");
+	if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+	    println!("cargo:rerun-if-changed={dir}/{head}");
+	}
+    }
+}
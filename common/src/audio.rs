@@ -0,0 +1,182 @@
+//! Sound: tones for the PC speaker and PCM buffers for AC'97.
+//!
+//! The PC speaker is a square wave from PIT channel 2, so all it takes is a divisor of the
+//! PIT's clock per note. `Tune` parses a small note notation to play with it, e.g.
+//! `"C4:200 E4:200 G4:400 R:100"` (note and octave, or R for a rest, then milliseconds).
+//!
+//! AC'97 plays PCM from a ring of up to 32 buffer descriptors, each pointing at part of
+//! the buffer in physical memory. `fill_descriptors` splits a buffer into them.
+//! References: https://wiki.osdev.org/PC_Speaker, https://wiki.osdev.org/AC97
+
+/// The PIT's input clock in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// The PIT channel 2 reload value for a tone, None if it's out of range.
+pub fn pit_divisor(hz: u32) -> Option<u16> {
+    if hz == 0 {
+	return None;
+    }
+    u16::try_from(PIT_FREQUENCY / hz).ok().filter(|d| *d > 0)
+}
+
+/// Frequencies of C4 to B4 in Hz (equal temperament, A4 = 440), times 100.
+const OCTAVE_4: [u32; 12] = [26163, 27718, 29366, 31113, 32963, 34923, 36999, 39200, 41530, 44000, 46616, 49388];
+
+/// The frequency in Hz of a note, e.g. `("A", 4)` or `("C#", 5)`. Sharps only.
+pub fn note_hz(note: &str, octave: u8) -> Option<u32> {
+    let semitone = match note.as_bytes() {
+	[n] => natural(*n)?,
+	[n, b'#'] => natural(*n)? + 1,
+	_ => return None,
+    };
+    let hz = OCTAVE_4[semitone % 12] as u64;
+    let hz = if octave >= 4 {
+	hz.checked_shl((octave - 4) as u32)?
+    } else {
+	hz >> (4 - octave)
+    };
+    // Round to the nearest Hz.
+    u32::try_from((hz + 50) / 100).ok()
+}
+
+fn natural(n: u8) -> Option<usize> {
+    match n.to_ascii_uppercase() {
+	b'C' => Some(0),
+	b'D' => Some(2),
+	b'E' => Some(4),
+	b'F' => Some(5),
+	b'G' => Some(7),
+	b'A' => Some(9),
+	b'B' => Some(11),
+	_ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    /// 0 for a rest.
+    pub hz: u32,
+    pub ms: u32,
+}
+
+/// The tones of a tune, see the module docs for the notation.
+pub struct Tune<'a> {
+    notes: core::str::SplitAsciiWhitespace<'a>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TuneErr;
+
+impl<'a> Tune<'a> {
+    pub fn new(tune: &'a str) -> Self {
+	Self { notes: tune.split_ascii_whitespace() }
+    }
+}
+
+impl Iterator for Tune<'_> {
+    type Item = Result<Tone, TuneErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	let note = self.notes.next()?;
+	Some(parse_tone(note).ok_or(TuneErr))
+    }
+}
+
+fn parse_tone(s: &str) -> Option<Tone> {
+    let (note, ms) = s.split_once(':')?;
+    let ms = ms.parse().ok()?;
+    if note.eq_ignore_ascii_case("r") {
+	return Some(Tone { hz: 0, ms });
+    }
+    // The octave is the last character.
+    let (name, octave) = note.split_at(note.len().checked_sub(1)?);
+    let octave = octave.parse().ok()?;
+    Some(Tone { hz: note_hz(name, octave)?, ms })
+}
+
+/// Max descriptors in an AC'97 buffer descriptor list.
+pub const BDL_ENTRIES: usize = 32;
+/// Max samples one descriptor can point at.
+pub const MAX_DESCRIPTOR_SAMPLES: u16 = 0xFFFE;
+
+/// Interrupt when the controller finishes this descriptor.
+pub const BD_IOC: u16 = 1 << 15;
+/// Play silence after this descriptor instead of stopping (buffer underrun policy).
+pub const BD_BUP: u16 = 1 << 14;
+
+/// An AC'97 buffer descriptor list entry, as the controller reads it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct BufferDescriptor {
+    /// Physical address of the samples.
+    pub addr: u32,
+    /// In 16 bit samples, not frames.
+    pub samples: u16,
+    pub flags: u16,
+}
+
+/// Fill `bdl` with descriptors covering `len` bytes of 16 bit PCM at physical address
+/// `addr`. The last descriptor interrupts and plays silence after. Returns how many were
+/// used, None if the buffer doesn't fit in the list or isn't whole samples.
+pub fn fill_descriptors(addr: u32, len: usize, bdl: &mut [BufferDescriptor]) -> Option<usize> {
+    if len == 0 || !len.is_multiple_of(2) {
+	return None;
+    }
+    let max_bytes = MAX_DESCRIPTOR_SAMPLES as usize * 2;
+    let count = len.div_ceil(max_bytes);
+    if count > bdl.len().min(BDL_ENTRIES) {
+	return None;
+    }
+    for (i, bd) in bdl[..count].iter_mut().enumerate() {
+	let offset = i * max_bytes;
+	let bytes = (len - offset).min(max_bytes);
+	*bd = BufferDescriptor {
+	    addr: addr.checked_add(u32::try_from(offset).ok()?)?,
+	    samples: (bytes / 2) as u16,
+	    flags: if i == count - 1 { BD_IOC | BD_BUP } else { 0 },
+	};
+    }
+    Some(count)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn notes() {
+	assert_eq!(note_hz("A", 4), Some(440));
+	assert_eq!(note_hz("A", 2), Some(110));
+	assert_eq!(note_hz("c#", 5), Some(554));
+	assert_eq!(note_hz("H", 4), None);
+	assert_eq!(pit_divisor(440), Some(2711));
+	// Too low for 16 bits of divisor.
+	assert_eq!(pit_divisor(10), None);
+    }
+
+    #[test]
+    fn tunes() {
+	let mut t = Tune::new("C4:200  R:50 G#3:400");
+	assert_eq!(t.next(), Some(Ok(Tone { hz: 262, ms: 200 })));
+	assert_eq!(t.next(), Some(Ok(Tone { hz: 0, ms: 50 })));
+	assert_eq!(t.next(), Some(Ok(Tone { hz: 208, ms: 400 })));
+	assert_eq!(t.next(), None);
+
+	assert_eq!(Tune::new("C4").next(), Some(Err(TuneErr)));
+	assert_eq!(Tune::new(":10").next(), Some(Err(TuneErr)));
+    }
+
+    #[test]
+    fn descriptors() {
+	let mut bdl = [BufferDescriptor::default(); BDL_ENTRIES];
+	// One and a half descriptors' worth.
+	let len = 0xFFFE * 2 + 0x1000;
+	assert_eq!(fill_descriptors(0x10_0000, len, &mut bdl), Some(2));
+	assert_eq!(bdl[0], BufferDescriptor { addr: 0x10_0000, samples: 0xFFFE, flags: 0 });
+	assert_eq!(bdl[1], BufferDescriptor { addr: 0x10_0000 + 0x1FFFC, samples: 0x800, flags: BD_IOC | BD_BUP });
+
+	assert_eq!(fill_descriptors(0, 3, &mut bdl), None);
+	assert_eq!(fill_descriptors(0, 0xFFFE * 2 * 33, &mut bdl), None);
+    }
+}
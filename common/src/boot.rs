@@ -0,0 +1,413 @@
+//! Information handed from the bootloader to the kernel.
+//!
+//! BootInfo is a small fixed header followed by a list of tagged entries (TLV), ending
+//! with an `END` tag. Each entry is an 8 byte tag header (tag, payload length) followed by
+//! the payload, padded so the next entry starts 8 byte aligned. Everything is little
+//! endian.
+//!
+//! The list is how the boot protocol grows without a flag day:
+//! - a new bootloader feature gets a new tag, kernels that don't know it skip it,
+//! - known payloads can only grow at the end, readers ignore bytes past what they know,
+//! - `version` only changes if the header itself does.
+
+use crate::build_id::{BuildId, Component};
+
+pub const BOOT_INFO_MAGIC: u32 = u32::from_le_bytes(*b"yoyo");
+pub const BOOT_INFO_VERSION: u32 = 1;
+const HEADER_SZ: usize = 16;
+const TAG_HEADER_SZ: usize = 8;
+const TAG_ALIGN: usize = 8;
+
+/// Tag numbers, never reuse one.
+pub mod tag {
+    pub const END: u32 = 0;
+    /// A `MemRegion` holding the bootloader's log, a `logbuf::LogRing`.
+    pub const LOADER_LOG: u32 = 1;
+    /// A `MemRegion` holding the boot config file, signature already checked.
+    pub const CONFIG: u32 = 2;
+    /// An array of `MemoryMapEntry`.
+    pub const MEMORY_MAP: u32 = 3;
+    /// A `MemRegion` followed by the module's name (UTF-8, rest of the payload).
+    pub const MODULE: u32 = 4;
+    /// A `Framebuffer`.
+    pub const FRAMEBUFFER: u32 = 5;
+    /// The kernel command line, UTF-8.
+    pub const CMDLINE: u32 = 6;
+    /// The bootloader's `build_id` record.
+    pub const BUILD_ID: u32 = 7;
+}
+
+/// A region of physical memory.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemRegion {
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl MemRegion {
+    const SZ: usize = 16;
+
+    pub fn to_bytes(self) -> [u8; Self::SZ] {
+	let mut b = [0; Self::SZ];
+	b[0..8].copy_from_slice(&self.addr.to_le_bytes());
+	b[8..16].copy_from_slice(&self.len.to_le_bytes());
+	b
+    }
+
+    fn parse(b: &[u8]) -> Option<Self> {
+	Some(Self {
+	    addr: u64_at(b, 0)?,
+	    len: u64_at(b, 8)?,
+	})
+    }
+}
+
+/// What a range of physical memory can be used for.
+pub mod mem_kind {
+    pub const USABLE: u32 = 1;
+    pub const RESERVED: u32 = 2;
+    /// Bootloader data (this BootInfo, the loader log, ...), usable once the kernel is done with it.
+    pub const LOADER: u32 = 3;
+    pub const ACPI_RECLAIMABLE: u32 = 4;
+    pub const ACPI_NVS: u32 = 5;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryMapEntry {
+    pub region: MemRegion,
+    /// One of `mem_kind`, treat anything else as reserved.
+    pub kind: u32,
+}
+
+impl MemoryMapEntry {
+    pub const SZ: usize = 24;
+
+    pub fn to_bytes(&self) -> [u8; Self::SZ] {
+	let mut b = [0; Self::SZ];
+	b[0..16].copy_from_slice(&self.region.to_bytes());
+	b[16..20].copy_from_slice(&self.kind.to_le_bytes());
+	b
+    }
+
+    fn parse(b: &[u8]) -> Option<Self> {
+	Some(Self {
+	    region: MemRegion::parse(b)?,
+	    kind: u32_at(b, 16)?,
+	})
+    }
+}
+
+/// Iterator over the entries of a `MEMORY_MAP` tag.
+#[derive(Clone, Debug)]
+pub struct MemoryMapEntries<'a>(core::slice::ChunksExact<'a, u8>);
+
+impl Iterator for MemoryMapEntries<'_> {
+    type Item = MemoryMapEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	self.0.next().and_then(MemoryMapEntry::parse)
+    }
+}
+
+/// Framebuffer pixel formats, numbered like the UEFI GOP's.
+pub mod pixel_format {
+    /// 32 bits per pixel, red in the lowest byte.
+    pub const RGB: u32 = 0;
+    /// 32 bits per pixel, blue in the lowest byte.
+    pub const BGR: u32 = 1;
+    /// Anything else, the kernel has to work out the masks itself.
+    pub const BITMASK: u32 = 2;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Framebuffer {
+    pub addr: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row.
+    pub stride: u32,
+    /// One of `pixel_format`.
+    pub format: u32,
+}
+
+impl Framebuffer {
+    pub const SZ: usize = 24;
+
+    pub fn to_bytes(&self) -> [u8; Self::SZ] {
+	let mut b = [0; Self::SZ];
+	b[0..8].copy_from_slice(&self.addr.to_le_bytes());
+	b[8..12].copy_from_slice(&self.width.to_le_bytes());
+	b[12..16].copy_from_slice(&self.height.to_le_bytes());
+	b[16..20].copy_from_slice(&self.stride.to_le_bytes());
+	b[20..24].copy_from_slice(&self.format.to_le_bytes());
+	b
+    }
+
+    fn parse(b: &[u8]) -> Option<Self> {
+	Some(Self {
+	    addr: u64_at(b, 0)?,
+	    width: u32_at(b, 8)?,
+	    height: u32_at(b, 12)?,
+	    stride: u32_at(b, 16)?,
+	    format: u32_at(b, 20)?,
+	})
+    }
+}
+
+#[derive(Debug)]
+pub enum Tag<'a> {
+    LoaderLog(MemRegion),
+    Config(MemRegion),
+    MemoryMap(MemoryMapEntries<'a>),
+    Module { region: MemRegion, name: &'a str },
+    Framebuffer(Framebuffer),
+    Cmdline(&'a str),
+    BuildId(Component, BuildId),
+    /// A tag this version doesn't know, from a newer bootloader.
+    Unknown { tag: u32, data: &'a [u8] },
+    /// A known tag with a payload too short (or not UTF-8) to parse.
+    Malformed { tag: u32 },
+}
+
+impl<'a> Tag<'a> {
+    fn parse(tag: u32, data: &'a [u8]) -> Self {
+	let parsed = match tag {
+	    tag::LOADER_LOG => MemRegion::parse(data).map(Tag::LoaderLog),
+	    tag::CONFIG => MemRegion::parse(data).map(Tag::Config),
+	    tag::MEMORY_MAP => Some(Tag::MemoryMap(MemoryMapEntries(data.chunks_exact(MemoryMapEntry::SZ)))),
+	    tag::MODULE => MemRegion::parse(data).and_then(|region| {
+		let name = core::str::from_utf8(&data[MemRegion::SZ..]).ok()?;
+		Some(Tag::Module { region, name })
+	    }),
+	    tag::FRAMEBUFFER => Framebuffer::parse(data).map(Tag::Framebuffer),
+	    tag::CMDLINE => core::str::from_utf8(data).ok().map(Tag::Cmdline),
+	    tag::BUILD_ID => BuildId::parse_record(data).map(|(c, id)| Tag::BuildId(c, id)),
+	    _ => return Tag::Unknown { tag, data },
+	};
+	parsed.unwrap_or(Tag::Malformed { tag })
+    }
+}
+
+/// Iterator over the tags of a BootInfo. Stops at the `END` tag, or at an entry that runs
+/// past the end.
+#[derive(Clone, Debug)]
+pub struct Tags<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tags<'a> {
+    /// Parse the tag list of a whole BootInfo, header included. Empty if the header is bad.
+    pub fn new(bytes: &'a [u8]) -> Self {
+	let valid = u32_at(bytes, 0) == Some(BOOT_INFO_MAGIC)
+	    && u32_at(bytes, 4) == Some(BOOT_INFO_VERSION)
+	    && u32_at(bytes, 8).is_some_and(|sz| sz as usize <= bytes.len());
+	if !valid {
+	    return Self { bytes: &[], pos: 0 };
+	}
+
+	let size = u32_at(bytes, 8).unwrap() as usize;
+	Self {
+	    bytes: &bytes[..size],
+	    pos: HEADER_SZ,
+	}
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	let tag = u32_at(self.bytes, self.pos)?;
+	let len = u32_at(self.bytes, self.pos + 4)? as usize;
+	let start = self.pos + TAG_HEADER_SZ;
+	if tag == tag::END {
+	    return None;
+	}
+	let data = self.bytes.get(start..start.checked_add(len)?)?;
+	self.pos = align_up(start + len);
+	Some(Tag::parse(tag, data))
+    }
+}
+
+/// Header at the start of the BootInfo, the tags follow it. Passed to the kernel entry
+/// point by the bootloader.
+#[repr(C)]
+#[derive(Debug)]
+pub struct BootInfo {
+    pub magic: u32,
+    pub version: u32,
+    /// Size of the header and tags, `END` tag included.
+    pub size: u32,
+    _reserved: u32,
+}
+
+impl BootInfo {
+    /// The whole BootInfo, header and tags.
+    ///
+    /// # Safety
+    /// `self` must be followed by the `size` bytes the bootloader wrote.
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+	let size = if self.magic == BOOT_INFO_MAGIC { self.size as usize } else { HEADER_SZ };
+	core::slice::from_raw_parts(self as *const Self as *const u8, size.max(HEADER_SZ))
+    }
+
+    /// # Safety
+    /// See `as_bytes`.
+    pub unsafe fn tags(&self) -> Tags<'_> {
+	Tags::new(self.as_bytes())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BootInfoErr {
+    /// The buffer can't fit the tag plus the `END` tag.
+    Full,
+    Unaligned,
+}
+
+/// Builds a BootInfo in a buffer, for the bootloader.
+pub struct BootInfoWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BootInfoWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Result<Self, BootInfoErr> {
+	if !(buf.as_ptr() as usize).is_multiple_of(TAG_ALIGN) {
+	    return Err(BootInfoErr::Unaligned);
+	}
+	if buf.len() < HEADER_SZ + TAG_HEADER_SZ {
+	    return Err(BootInfoErr::Full);
+	}
+
+	buf[0..4].copy_from_slice(&BOOT_INFO_MAGIC.to_le_bytes());
+	buf[4..8].copy_from_slice(&BOOT_INFO_VERSION.to_le_bytes());
+	buf[8..HEADER_SZ].fill(0);
+	Ok(Self { buf, pos: HEADER_SZ })
+    }
+
+    /// Append a tag whose payload is made of `parts`, one after the other.
+    pub fn push<I>(&mut self, tag: u32, parts: I) -> Result<(), BootInfoErr>
+    where
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+    {
+	let start = self.pos + TAG_HEADER_SZ;
+	let mut end = start;
+	for part in parts {
+	    let part = part.as_ref();
+	    // Keep room for the END tag.
+	    if align_up(end + part.len()) + TAG_HEADER_SZ > self.buf.len() {
+		return Err(BootInfoErr::Full);
+	    }
+	    self.buf[end..end + part.len()].copy_from_slice(part);
+	    end += part.len();
+	}
+
+	let len = (end - start) as u32;
+	self.buf[self.pos..self.pos + 4].copy_from_slice(&tag.to_le_bytes());
+	self.buf[self.pos + 4..start].copy_from_slice(&len.to_le_bytes());
+	self.buf[end..align_up(end)].fill(0);
+	self.pos = align_up(end);
+	Ok(())
+    }
+
+    pub fn push_region(&mut self, tag: u32, region: MemRegion) -> Result<(), BootInfoErr> {
+	self.push(tag, [region.to_bytes()])
+    }
+
+    /// Write the `END` tag and the total size, returns the size.
+    pub fn finish(self) -> usize {
+	self.buf[self.pos..self.pos + TAG_HEADER_SZ].fill(0);
+	let size = self.pos + TAG_HEADER_SZ;
+	self.buf[8..12].copy_from_slice(&(size as u32).to_le_bytes());
+	size
+    }
+}
+
+fn align_up(n: usize) -> usize {
+    n.next_multiple_of(TAG_ALIGN)
+}
+
+fn u32_at(b: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(b.get(off..off.checked_add(4)?)?.try_into().unwrap()))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(b.get(off..off.checked_add(8)?)?.try_into().unwrap()))
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// An 8 byte aligned buffer.
+    #[allow(dead_code)]
+    #[repr(align(8))]
+    struct Buf([u8; 256]);
+
+    #[test]
+    fn round_trip() {
+	let mut buf = Buf([0xFF; 256]);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	w.push_region(tag::LOADER_LOG, MemRegion { addr: 0x1000, len: 0x4000 }).unwrap();
+	let entries = [
+	    MemoryMapEntry { region: MemRegion { addr: 0, len: 0x9F000 }, kind: mem_kind::USABLE },
+	    MemoryMapEntry { region: MemRegion { addr: 0x9F000, len: 0x1000 }, kind: mem_kind::RESERVED },
+	];
+	w.push(tag::MEMORY_MAP, entries.iter().map(MemoryMapEntry::to_bytes)).unwrap();
+	w.push(tag::MODULE, [&MemRegion { addr: 0x2000, len: 3 }.to_bytes()[..], b"initrd"]).unwrap();
+	let size = w.finish();
+	assert_eq!(size % TAG_ALIGN, 0);
+
+	let mut tags = Tags::new(&buf.0);
+	assert!(matches!(tags.next(), Some(Tag::LoaderLog(MemRegion { addr: 0x1000, len: 0x4000 }))));
+	let Some(Tag::MemoryMap(map)) = tags.next() else { panic!("expected a memory map") };
+	assert!(map.eq(entries));
+	assert!(matches!(tags.next(), Some(Tag::Module { region: MemRegion { addr: 0x2000, len: 3 }, name: "initrd" })));
+	assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn unknown_and_grown_tags() {
+	let mut buf = Buf([0; 256]);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	w.push(99, [&b"from the future"[..]]).unwrap();
+	// A newer CONFIG payload with extra fields still reads as a CONFIG.
+	w.push(tag::CONFIG, [&MemRegion { addr: 1, len: 2 }.to_bytes()[..], &[7; 5]]).unwrap();
+	w.push(tag::FRAMEBUFFER, [&[0u8; 4][..]]).unwrap();
+	w.push(tag::CMDLINE, [&b"root=/dev/sda2"[..]]).unwrap();
+	let id = BuildId::from_env("", "1");
+	w.push(tag::BUILD_ID, [id.record(Component::Bootloader)]).unwrap();
+	w.finish();
+
+	let mut tags = Tags::new(&buf.0);
+	assert!(matches!(tags.next(), Some(Tag::Unknown { tag: 99, data: b"from the future" })));
+	assert!(matches!(tags.next(), Some(Tag::Config(MemRegion { addr: 1, len: 2 }))));
+	assert!(matches!(tags.next(), Some(Tag::Malformed { tag: tag::FRAMEBUFFER })));
+	assert!(matches!(tags.next(), Some(Tag::Cmdline("root=/dev/sda2"))));
+	assert_eq!(tags.next().map(|t| matches!(t, Tag::BuildId(Component::Bootloader, got) if got == id)), Some(true));
+	assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn bad_input() {
+	let mut buf = Buf([0; 256]);
+	let mut w = BootInfoWriter::new(&mut buf.0[..48]).unwrap();
+	assert_eq!(w.push(tag::MODULE, [&[0u8; 20][..]]), Err(BootInfoErr::Full));
+	w.push_region(tag::CONFIG, MemRegion { addr: 0, len: 0 }).unwrap();
+	assert_eq!(w.finish(), 48);
+	assert_eq!(BootInfoWriter::new(&mut buf.0[1..]).err(), Some(BootInfoErr::Unaligned));
+
+	// Wrong magic, and a tag running past the end.
+	assert_eq!(Tags::new(&[0; 64]).count(), 0);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	w.push(tag::CONFIG, [&[0u8; 16][..]]).unwrap();
+	w.finish();
+	buf.0[20] = 0xFF;
+	assert_eq!(Tags::new(&buf.0).count(), 0);
+    }
+}
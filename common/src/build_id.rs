@@ -0,0 +1,240 @@
+//! Which sources a binary was built from.
+//!
+//! common's build script records the git commit, with `-dirty` if tracked files had
+//! changes, and the time of the build (`SOURCE_DATE_EPOCH` if set). Every crate using
+//! common gets them as [`THIS`]. The bootloader and the kernel log theirs at boot and the
+//! bootloader hands its own to the kernel in BootInfo. Both also keep a record of it in a
+//! static, which bob looks for in the files it puts into an image so the image's versions
+//! record can say what went into it. A record is 48 bytes:
+//!
+//! ```text
+//! offset
+//!   0  magic "YOYOBLD1"
+//!   8  component (u8)
+//!   9  flags (u8): bit 0 commit known, bit 1 dirty
+//!  10  zero (6 bytes)
+//!  16  commit, SHA-1 (20 bytes)
+//!  36  zero (4 bytes)
+//!  40  build time, seconds since the epoch (u64)
+//! ```
+
+use core::fmt;
+
+use crate::time::DateTime;
+
+pub const MAGIC: [u8; 8] = *b"YOYOBLD1";
+pub const RECORD_SZ: usize = 48;
+
+const COMMIT_KNOWN: u8 = 1 << 0;
+const DIRTY: u8 = 1 << 1;
+
+/// This build.
+pub const THIS: BuildId = BuildId::from_env(env!("YOYO_GIT_COMMIT"), env!("YOYO_BUILD_TIME"));
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Component {
+    Bootloader = 1,
+    Kernel = 2,
+}
+
+impl Component {
+    pub fn from_raw(n: u8) -> Option<Self> {
+	match n {
+	    1 => Some(Self::Bootloader),
+	    2 => Some(Self::Kernel),
+	    _ => None,
+	}
+    }
+
+    pub fn name(self) -> &'static str {
+	match self {
+	    Self::Bootloader => "bootloader",
+	    Self::Kernel => "kernel",
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BuildId {
+    /// None when built outside a git checkout.
+    pub commit: Option<[u8; 20]>,
+    pub dirty: bool,
+    /// Seconds since the epoch.
+    pub time: u64,
+}
+
+impl BuildId {
+    /// From the build script's strings: 40 hex digits with an optional `-dirty`, and
+    /// decimal seconds. A commit that isn't one reads as unknown, a time as 0.
+    pub const fn from_env(commit: &str, time: &str) -> Self {
+	let c = commit.as_bytes();
+	let mut dirty = c.len() == 46;
+	let mut i = 0;
+	while dirty && i < 6 {
+	    dirty = c[40 + i] == b"-dirty"[i];
+	    i += 1;
+	}
+	let mut hash = [0; 20];
+	let mut known = c.len() == 40 || dirty;
+	let mut i = 0;
+	while known && i < 40 {
+	    match (hex_digit(c[i]), hex_digit(c[i + 1])) {
+		(Some(hi), Some(lo)) => hash[i / 2] = hi << 4 | lo,
+		_ => known = false,
+	    }
+	    i += 2;
+	}
+
+	let t = time.as_bytes();
+	let mut secs: u64 = 0;
+	let mut i = 0;
+	while i < t.len() {
+	    if !t[i].is_ascii_digit() {
+		secs = 0;
+		break;
+	    }
+	    secs = secs.saturating_mul(10).saturating_add((t[i] - b'0') as u64);
+	    i += 1;
+	}
+	Self { commit: if known { Some(hash) } else { None }, dirty: known && dirty, time: secs }
+    }
+
+    /// The record for `component` built from this.
+    pub const fn record(&self, component: Component) -> [u8; RECORD_SZ] {
+	let mut r = [0; RECORD_SZ];
+	let mut i = 0;
+	while i < MAGIC.len() {
+	    r[i] = MAGIC[i];
+	    i += 1;
+	}
+	r[8] = component as u8;
+	if let Some(commit) = self.commit {
+	    r[9] = COMMIT_KNOWN | if self.dirty { DIRTY } else { 0 };
+	    let mut i = 0;
+	    while i < commit.len() {
+		r[16 + i] = commit[i];
+		i += 1;
+	    }
+	}
+	let time = self.time.to_le_bytes();
+	let mut i = 0;
+	while i < time.len() {
+	    r[40 + i] = time[i];
+	    i += 1;
+	}
+	r
+    }
+
+    /// A record at the start of `b`.
+    pub fn parse_record(b: &[u8]) -> Option<(Component, Self)> {
+	let r = b.get(..RECORD_SZ)?;
+	let flags = r[9];
+	if r[..8] != MAGIC || flags & !(COMMIT_KNOWN | DIRTY) != 0 || r[10..16].iter().chain(&r[36..40]).any(|b| *b != 0) {
+	    return None;
+	}
+	let component = Component::from_raw(r[8])?;
+	let commit = (flags & COMMIT_KNOWN != 0).then(|| r[16..36].try_into().unwrap());
+	let time = u64::from_le_bytes(r[40..48].try_into().unwrap());
+	Some((component, Self { commit, dirty: flags & DIRTY != 0, time }))
+    }
+
+    /// The first 12 digits of the commit, `-dirty` included.
+    pub fn short(&self) -> ShortCommit {
+	ShortCommit(*self)
+    }
+}
+
+const fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+	b'0'..=b'9' => Some(c - b'0'),
+	b'a'..=b'f' => Some(c - b'a' + 10),
+	b'A'..=b'F' => Some(c - b'A' + 10),
+	_ => None,
+    }
+}
+
+/// Every record in `bytes`, e.g. a kernel binary or a filesystem image with one in it.
+pub fn find_records(bytes: &[u8]) -> impl Iterator<Item = (Component, BuildId)> + '_ {
+    bytes.windows(MAGIC.len()).enumerate().filter(|(_, w)| *w == MAGIC).filter_map(|(i, _)| BuildId::parse_record(&bytes[i..]))
+}
+
+pub struct ShortCommit(BuildId);
+
+impl fmt::Display for ShortCommit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	match self.0.commit {
+	    Some(c) => {
+		for b in &c[..6] {
+		    write!(f, "{b:02x}")?;
+		}
+		if self.0.dirty {
+		    write!(f, "-dirty")?;
+		}
+		Ok(())
+	    },
+	    None => write!(f, "unknown commit"),
+	}
+    }
+}
+
+/// "commit 1a2b3c4d5e6f-dirty, built 2026-10-18 09:30:00 UTC"
+impl fmt::Display for BuildId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let t = DateTime::from_unix(self.time as i64);
+	write!(f, "commit {}, built {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", self.short(), t.year, t.month, t.day, t.hour, t.minute, t.second)
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use core::fmt::Write;
+
+    #[allow(dead_code)]
+    struct Buf<'a>(&'a mut [u8], usize);
+
+    impl fmt::Write for Buf<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.1 + s.len();
+	    self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.1 = end;
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn from_env() {
+	let id = BuildId::from_env("1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d-dirty", "1792315800");
+	assert_eq!(id.commit.unwrap()[..3], [0x1a, 0x2b, 0x3c]);
+	assert!(id.dirty);
+	assert_eq!(id.time, 1792315800);
+	let mut out = [0; 64];
+	let mut b = Buf(&mut out, 0);
+	write!(b, "{id}").unwrap();
+	let n = b.1;
+	assert_eq!(&out[..n], b"commit 1a2b3c4d5e6f-dirty, built 2026-10-18 09:30:00 UTC");
+
+	assert_eq!(BuildId::from_env("", "x"), BuildId { commit: None, dirty: false, time: 0 });
+	assert_eq!(BuildId::from_env("1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4z", "0").commit, None);
+    }
+
+    #[test]
+    fn records() {
+	let id = BuildId::from_env("1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d", "1792315800");
+	let unknown = BuildId::from_env("", "7");
+	let mut image = [0xaa; 300];
+	image[20..20 + RECORD_SZ].copy_from_slice(&id.record(Component::Kernel));
+	image[100..100 + RECORD_SZ].copy_from_slice(&unknown.record(Component::Bootloader));
+	// The magic followed by something that isn't a record, and a record cut short.
+	image[200..208].copy_from_slice(&MAGIC);
+	image[280..288].copy_from_slice(&MAGIC);
+
+	let mut found = find_records(&image);
+	assert_eq!(found.next(), Some((Component::Kernel, id)));
+	assert_eq!(found.next(), Some((Component::Bootloader, unknown)));
+	assert_eq!(found.next(), None);
+    }
+}
@@ -0,0 +1,510 @@
+//! Encrypted partitions: a small LUKS-like container.
+//!
+//! bob writes a header at the start of the partition and the data after it, encrypted
+//! with AES-256 in XTS mode, one 512 byte sector at a time with the sector number (counted
+//! from the start of the data) as the tweak. The key is derived from a passphrase with
+//! PBKDF2-HMAC-SHA256, and the kernel unlocks the partition with the passphrase given as
+//! `crypt.key=` on its command line. Unlike LUKS there are no key slots: the passphrase
+//! derives the data key directly, so changing it means re-encrypting.
+//!
+//! The header (all integers little endian):
+//!
+//! ```text
+//!   0  magic "YOYOCRYP"
+//!   8  version (u16), cipher (u8, 1 = aes-256-xts), kdf (u8, 1 = pbkdf2-hmac-sha256)
+//!  12  KDF iterations (u32)
+//!  16  salt (32 bytes)
+//!  48  key check, SHA-256 of KEY_CHECK_PREFIX and the derived key (32 bytes)
+//!  80  data offset in bytes from the start of the partition (u64)
+//!  88  data sectors (u64)
+//!  96  UUID (16 bytes)
+//! 112  CRC-32 of bytes 0..112 (u32)
+//! ```
+//!
+//! padded with zeros to `HEADER_SZ`.
+
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{ErrorCode, Subsystem};
+use crate::snapshot::crc32;
+
+/// Space the header takes at the start of the partition.
+pub const HEADER_SZ: usize = 4096;
+/// Unit of encryption, independent of the disk's sector size.
+pub const SECTOR_SZ: usize = 512;
+pub const KEY_SZ: usize = 64;
+pub const SALT_SZ: usize = 32;
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+const MAGIC: &[u8; 8] = b"YOYOCRYP";
+const VERSION: u16 = 1;
+const CIPHER_AES_XTS: u8 = 1;
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KEY_CHECK_PREFIX: &[u8] = b"yoyo crypt key check";
+const CRC_OFFSET: usize = 112;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CryptErr {
+    InputBounds,
+    Magic,
+    Version(u16),
+    /// An unknown cipher or KDF.
+    Unsupported,
+    Checksum,
+    WrongKey,
+}
+
+impl ErrorCode for CryptErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Crypt;
+
+    fn code(&self) -> (u16, u32) {
+	match *self {
+	    Self::InputBounds => (1, 0),
+	    Self::Magic => (2, 0),
+	    Self::Version(v) => (3, v as u32),
+	    Self::Unsupported => (4, 0),
+	    Self::Checksum => (5, 0),
+	    Self::WrongKey => (6, 0),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "out of bounds"),
+	    2 => write!(f, "not an encrypted partition"),
+	    3 => write!(f, "header version {arg} isn't supported"),
+	    4 => write!(f, "unknown cipher or KDF"),
+	    5 => write!(f, "header checksum doesn't match"),
+	    6 => write!(f, "wrong key"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub iterations: u32,
+    pub salt: [u8; SALT_SZ],
+    pub key_check: [u8; 32],
+    pub data_offset: u64,
+    pub data_sectors: u64,
+    pub uuid: [u8; 16],
+}
+
+impl Header {
+    /// A header for `key`, derived from the passphrase with `salt` and `iterations`, over
+    /// a partition of `partition_bytes`.
+    pub fn new(key: &[u8; KEY_SZ], salt: [u8; SALT_SZ], iterations: u32, partition_bytes: u64, uuid: [u8; 16]) -> Result<Self, CryptErr> {
+	let data = partition_bytes.checked_sub(HEADER_SZ as u64).filter(|d| *d >= SECTOR_SZ as u64).ok_or(CryptErr::InputBounds)?;
+	Ok(Self {
+	    iterations,
+	    salt,
+	    key_check: key_check(key),
+	    data_offset: HEADER_SZ as u64,
+	    data_sectors: data / SECTOR_SZ as u64,
+	    uuid,
+	})
+    }
+
+    pub fn parse(b: &[u8]) -> Result<Self, CryptErr> {
+	if b.len() < CRC_OFFSET + 4 {
+	    return Err(CryptErr::InputBounds);
+	}
+	if &b[..8] != MAGIC {
+	    return Err(CryptErr::Magic);
+	}
+	let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+	let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+	if crc32(&b[..CRC_OFFSET]) != u32_at(CRC_OFFSET) {
+	    return Err(CryptErr::Checksum);
+	}
+	let version = u16::from_le_bytes([b[8], b[9]]);
+	if version != VERSION {
+	    return Err(CryptErr::Version(version));
+	}
+	if b[10] != CIPHER_AES_XTS || b[11] != KDF_PBKDF2_SHA256 {
+	    return Err(CryptErr::Unsupported);
+	}
+	Ok(Self {
+	    iterations: u32_at(12),
+	    salt: b[16..48].try_into().unwrap(),
+	    key_check: b[48..80].try_into().unwrap(),
+	    data_offset: u64_at(80),
+	    data_sectors: u64_at(88),
+	    uuid: b[96..112].try_into().unwrap(),
+	})
+    }
+
+    pub fn write(&self, b: &mut [u8; HEADER_SZ]) {
+	b.fill(0);
+	b[..8].copy_from_slice(MAGIC);
+	b[8..10].copy_from_slice(&VERSION.to_le_bytes());
+	b[10] = CIPHER_AES_XTS;
+	b[11] = KDF_PBKDF2_SHA256;
+	b[12..16].copy_from_slice(&self.iterations.to_le_bytes());
+	b[16..48].copy_from_slice(&self.salt);
+	b[48..80].copy_from_slice(&self.key_check);
+	b[80..88].copy_from_slice(&self.data_offset.to_le_bytes());
+	b[88..96].copy_from_slice(&self.data_sectors.to_le_bytes());
+	b[96..112].copy_from_slice(&self.uuid);
+	let crc = crc32(&b[..CRC_OFFSET]);
+	b[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+fn key_check(key: &[u8; KEY_SZ]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(KEY_CHECK_PREFIX);
+    h.update(key);
+    h.finalize().into()
+}
+
+/// The data key for a passphrase.
+pub fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; KEY_SZ] {
+    let mut key = [0; KEY_SZ];
+    pbkdf2_sha256(passphrase, salt, iterations, &mut key);
+    key
+}
+
+/// The passphrase given as `crypt.key=` on the kernel command line.
+pub fn passphrase_from_cmdline(cmdline: &str) -> Option<&str> {
+    cmdline.split_ascii_whitespace().find_map(|arg| arg.strip_prefix("crypt.key="))
+}
+
+/// An unlocked container, translating between plaintext and what's on the disk.
+pub struct Mapper {
+    xts: Xts,
+    pub data_offset: u64,
+    pub data_sectors: u64,
+}
+
+impl Mapper {
+    pub fn unlock(header: &Header, passphrase: &[u8]) -> Result<Self, CryptErr> {
+	let key = derive_key(passphrase, &header.salt, header.iterations);
+	if key_check(&key) != header.key_check {
+	    return Err(CryptErr::WrongKey);
+	}
+	Ok(Self { xts: Xts::new(&key), data_offset: header.data_offset, data_sectors: header.data_sectors })
+    }
+
+    /// Byte offset in the partition of data sector `sector`.
+    pub fn sector_offset(&self, sector: u64) -> Result<u64, CryptErr> {
+	if sector >= self.data_sectors {
+	    return Err(CryptErr::InputBounds);
+	}
+	Ok(self.data_offset + sector * SECTOR_SZ as u64)
+    }
+
+    /// Decrypt a sector read from the disk, in place.
+    pub fn decrypt(&self, sector: u64, buf: &mut [u8; SECTOR_SZ]) -> Result<(), CryptErr> {
+	self.sector_offset(sector)?;
+	self.xts.decrypt_sector(sector, buf);
+	Ok(())
+    }
+
+    /// Encrypt a sector about to be written to the disk, in place.
+    pub fn encrypt(&self, sector: u64, buf: &mut [u8; SECTOR_SZ]) -> Result<(), CryptErr> {
+	self.sector_offset(sector)?;
+	self.xts.encrypt_sector(sector, buf);
+	Ok(())
+    }
+}
+
+/// XTS-AES (IEEE 1619) with a key twice the AES key size: the first half encrypts the
+/// data, the second the tweak.
+pub struct Xts {
+    data: Aes,
+    tweak: Aes,
+}
+
+impl Xts {
+    pub fn new(key: &[u8]) -> Self {
+	let (data, tweak) = key.split_at(key.len() / 2);
+	Self { data: Aes::new(data), tweak: Aes::new(tweak) }
+    }
+
+    pub fn encrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+	self.each_block(sector, buf, |aes, b| aes.encrypt_block(b));
+    }
+
+    pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+	self.each_block(sector, buf, |aes, b| aes.decrypt_block(b));
+    }
+
+    /// `buf` is a whole number of AES blocks, no ciphertext stealing.
+    fn each_block(&self, sector: u64, buf: &mut [u8], f: impl Fn(&Aes, &mut [u8; 16])) {
+	let mut t = [0; 16];
+	t[..8].copy_from_slice(&sector.to_le_bytes());
+	self.tweak.encrypt_block(&mut t);
+	for chunk in buf.chunks_exact_mut(16) {
+	    let block: &mut [u8; 16] = chunk.try_into().unwrap();
+	    xor(block, &t);
+	    f(&self.data, block);
+	    xor(block, &t);
+	    // Multiply the tweak by x in GF(2^128).
+	    let carry = t[15] >> 7;
+	    for i in (1..16).rev() {
+		t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+	    }
+	    t[0] = (t[0] << 1) ^ (carry * 0x87);
+	}
+    }
+}
+
+fn xor(a: &mut [u8; 16], b: &[u8; 16]) {
+    for (a, b) in a.iter_mut().zip(b) {
+	*a ^= b;
+    }
+}
+
+/// AES with a 128, 192 or 256 bit key. Plain byte oriented, without tables beyond the
+/// S-boxes: it's only used to unlock and read a data partition, not for bulk I/O.
+pub struct Aes {
+    round_keys: [[u8; 16]; 15],
+    rounds: usize,
+}
+
+const fn xtime(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+	if b & 1 != 0 {
+	    p ^= a;
+	}
+	a = xtime(a);
+	b >>= 1;
+    }
+    p
+}
+
+const SBOX: [u8; 256] = {
+    let mut s = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+	// The multiplicative inverse is x^254, 0 maps to 0.
+	let (mut inv, mut base, mut e) = (1u8, i as u8, 254u32);
+	while e > 0 {
+	    if e & 1 == 1 {
+		inv = gmul(inv, base);
+	    }
+	    base = gmul(base, base);
+	    e >>= 1;
+	}
+	s[i] = inv ^ inv.rotate_left(1) ^ inv.rotate_left(2) ^ inv.rotate_left(3) ^ inv.rotate_left(4) ^ 0x63;
+	i += 1;
+    }
+    s
+};
+
+const INV_SBOX: [u8; 256] = {
+    let mut s = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+	s[SBOX[i] as usize] = i as u8;
+	i += 1;
+    }
+    s
+};
+
+impl Aes {
+    pub fn new(key: &[u8]) -> Self {
+	assert!(matches!(key.len(), 16 | 24 | 32), "AES keys are 128, 192 or 256 bits");
+	let nk = key.len() / 4;
+	let rounds = nk + 6;
+	let mut w = [[0u8; 4]; 60];
+	for (i, word) in key.chunks_exact(4).enumerate() {
+	    w[i].copy_from_slice(word);
+	}
+	let mut rcon = 1;
+	for i in nk..4 * (rounds + 1) {
+	    let mut t = w[i - 1];
+	    if i % nk == 0 {
+		t = [SBOX[t[1] as usize] ^ rcon, SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize]];
+		rcon = xtime(rcon);
+	    } else if nk > 6 && i % nk == 4 {
+		t = t.map(|b| SBOX[b as usize]);
+	    }
+	    for j in 0..4 {
+		w[i][j] = w[i - nk][j] ^ t[j];
+	    }
+	}
+	let mut round_keys = [[0; 16]; 15];
+	for (r, k) in round_keys.iter_mut().enumerate().take(rounds + 1) {
+	    for c in 0..4 {
+		k[4 * c..4 * c + 4].copy_from_slice(&w[4 * r + c]);
+	    }
+	}
+	Self { round_keys, rounds }
+    }
+
+    pub fn encrypt_block(&self, s: &mut [u8; 16]) {
+	xor(s, &self.round_keys[0]);
+	for round in 1..=self.rounds {
+	    for b in s.iter_mut() {
+		*b = SBOX[*b as usize];
+	    }
+	    // Row r rotates left by r, the state is column major.
+	    let t = *s;
+	    for c in 0..4 {
+		for r in 0..4 {
+		    s[r + 4 * c] = t[r + 4 * ((c + r) % 4)];
+		}
+	    }
+	    if round != self.rounds {
+		for col in s.chunks_exact_mut(4) {
+		    let a = [col[0], col[1], col[2], col[3]];
+		    col[0] = xtime(a[0]) ^ xtime(a[1]) ^ a[1] ^ a[2] ^ a[3];
+		    col[1] = a[0] ^ xtime(a[1]) ^ xtime(a[2]) ^ a[2] ^ a[3];
+		    col[2] = a[0] ^ a[1] ^ xtime(a[2]) ^ xtime(a[3]) ^ a[3];
+		    col[3] = xtime(a[0]) ^ a[0] ^ a[1] ^ a[2] ^ xtime(a[3]);
+		}
+	    }
+	    xor(s, &self.round_keys[round]);
+	}
+    }
+
+    pub fn decrypt_block(&self, s: &mut [u8; 16]) {
+	for round in (1..=self.rounds).rev() {
+	    xor(s, &self.round_keys[round]);
+	    if round != self.rounds {
+		for col in s.chunks_exact_mut(4) {
+		    let a = [col[0], col[1], col[2], col[3]];
+		    col[0] = gmul(a[0], 14) ^ gmul(a[1], 11) ^ gmul(a[2], 13) ^ gmul(a[3], 9);
+		    col[1] = gmul(a[0], 9) ^ gmul(a[1], 14) ^ gmul(a[2], 11) ^ gmul(a[3], 13);
+		    col[2] = gmul(a[0], 13) ^ gmul(a[1], 9) ^ gmul(a[2], 14) ^ gmul(a[3], 11);
+		    col[3] = gmul(a[0], 11) ^ gmul(a[1], 13) ^ gmul(a[2], 9) ^ gmul(a[3], 14);
+		}
+	    }
+	    let t = *s;
+	    for c in 0..4 {
+		for r in 0..4 {
+		    s[r + 4 * ((c + r) % 4)] = t[r + 4 * c];
+		}
+	    }
+	    for b in s.iter_mut() {
+		*b = INV_SBOX[*b as usize];
+	    }
+	}
+	xor(s, &self.round_keys[0]);
+    }
+}
+
+/// PBKDF2 (RFC 8018) with HMAC-SHA256, filling `out`.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    // HMAC's inner and outer hashes after the padded key, cloned for every message.
+    let mut k = [0; 64];
+    if password.len() > 64 {
+	k[..32].copy_from_slice(&Sha256::digest(password));
+    } else {
+	k[..password.len()].copy_from_slice(password);
+    }
+    let (mut inner, mut outer) = (Sha256::new(), Sha256::new());
+    inner.update(k.map(|b| b ^ 0x36));
+    outer.update(k.map(|b| b ^ 0x5c));
+    let hmac = |parts: &[&[u8]]| -> [u8; 32] {
+	let mut h = inner.clone();
+	for p in parts {
+	    h.update(p);
+	}
+	let mut o = outer.clone();
+	o.update(h.finalize());
+	o.finalize().into()
+    };
+
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+	let mut u = hmac(&[salt, &(i as u32 + 1).to_be_bytes()]);
+	let mut t = u;
+	for _ in 1..iterations {
+	    u = hmac(&[&u]);
+	    for (t, u) in t.iter_mut().zip(&u) {
+		*t ^= u;
+	    }
+	}
+	chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn unhex<const N: usize>(s: &str) -> [u8; N] {
+	let mut b = [0; N];
+	for (i, byte) in b.iter_mut().enumerate() {
+	    *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+	}
+	b
+    }
+
+    #[test]
+    fn aes_vectors() {
+	// FIPS-197 appendix C.
+	let pt: [u8; 16] = unhex("00112233445566778899aabbccddeeff");
+	for (key, ct) in [
+	    (&unhex::<32>("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")[..], "8ea2b7ca516745bfeafc49904b496089"),
+	    (&unhex::<16>("000102030405060708090a0b0c0d0e0f")[..], "69c4e0d86a7b0430d8cdb78070b4c55a"),
+	] {
+	    let aes = Aes::new(key);
+	    let mut b = pt;
+	    aes.encrypt_block(&mut b);
+	    assert_eq!(b, unhex::<16>(ct));
+	    aes.decrypt_block(&mut b);
+	    assert_eq!(b, pt);
+	}
+    }
+
+    #[test]
+    fn xts_vector() {
+	// IEEE 1619 XTS-AES-128 vector 1.
+	let xts = Xts::new(&[0; 32]);
+	let mut b = [0; 32];
+	xts.encrypt_sector(0, &mut b);
+	assert_eq!(b, unhex::<32>("917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e"));
+	xts.decrypt_sector(0, &mut b);
+	assert_eq!(b, [0; 32]);
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+	let mut out = [0; 32];
+	pbkdf2_sha256(b"password", b"salt", 1, &mut out);
+	assert_eq!(out, unhex::<32>("120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"));
+	pbkdf2_sha256(b"password", b"salt", 4096, &mut out);
+	assert_eq!(out, unhex::<32>("c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"));
+    }
+
+    #[test]
+    fn header_and_mapper() {
+	let key = derive_key(b"hunter2", &[7; SALT_SZ], 10);
+	let header = Header::new(&key, [7; SALT_SZ], 10, 1 << 20, [1; 16]).unwrap();
+	assert_eq!(header.data_sectors, ((1 << 20) - HEADER_SZ as u64) / SECTOR_SZ as u64);
+	let mut b = [0; HEADER_SZ];
+	header.write(&mut b);
+	assert_eq!(Header::parse(&b), Ok(header));
+
+	let mut damaged = b;
+	damaged[20] ^= 1;
+	assert_eq!(Header::parse(&damaged), Err(CryptErr::Checksum));
+	assert_eq!(Header::parse(&[0; HEADER_SZ]), Err(CryptErr::Magic));
+	assert!(matches!(Mapper::unlock(&header, b"hunter3"), Err(CryptErr::WrongKey)));
+
+	let mapper = Mapper::unlock(&header, b"hunter2").unwrap();
+	let mut sector = [0x42; SECTOR_SZ];
+	mapper.encrypt(3, &mut sector).unwrap();
+	assert_ne!(sector, [0x42; SECTOR_SZ]);
+	let mut other = [0x42; SECTOR_SZ];
+	mapper.encrypt(4, &mut other).unwrap();
+	assert_ne!(sector, other);
+	mapper.decrypt(3, &mut sector).unwrap();
+	assert_eq!(sector, [0x42; SECTOR_SZ]);
+	assert_eq!(mapper.sector_offset(0), Ok(HEADER_SZ as u64));
+	assert_eq!(mapper.decrypt(header.data_sectors, &mut sector), Err(CryptErr::InputBounds));
+	assert_eq!(passphrase_from_cmdline("quiet crypt.key=hunter2 keymap=de"), Some("hunter2"));
+    }
+}
@@ -0,0 +1,365 @@
+//! Per-device I/O statistics: `/proc/diskstats` and what `iostat` makes of it.
+//!
+//! Wrapping a device in `Counted` counts its requests, sectors and time spent, and keeps
+//! a latency histogram per direction. Requests are synchronous for now, so a request's
+//! latency is also the time the device was busy with it, and there's never more than one
+//! in flight.
+//!
+//! `/proc/diskstats` has Linux's layout so existing tools read it: major, minor, name,
+//! then the 11 classic counters and the 6 discard and flush ones, which stay 0. After
+//! those come the read and then the write histogram, `BUCKETS` counts each. A reader that
+//! only knows Linux's fields stops before them.
+//!
+//! The `iostat` tool reads the file twice and prints `Rates` between the two samples.
+//! Reference: Linux Documentation/admin-guide/iostats.rst
+
+use core::fmt::{self, Write};
+
+use crate::dm::{BlockDevice, DmErr, SECTOR_SZ};
+
+/// Latency histogram buckets. Bucket 0 counts requests under 2us, bucket `i` those from
+/// 2^i up to 2^(i+1) us, and the last everything from about 32ms on.
+pub const BUCKETS: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub counts: [u64; BUCKETS],
+}
+
+impl Histogram {
+    pub fn bucket(us: u64) -> usize {
+	(us.max(1).ilog2() as usize).min(BUCKETS - 1)
+    }
+
+    pub fn record(&mut self, us: u64) {
+	self.counts[Self::bucket(us)] += 1;
+    }
+
+    /// What was recorded since `earlier`.
+    pub fn since(&self, earlier: &Histogram) -> Histogram {
+	let mut h = Histogram::default();
+	for (i, c) in h.counts.iter_mut().enumerate() {
+	    *c = self.counts[i].saturating_sub(earlier.counts[i]);
+	}
+	h
+    }
+
+    /// Upper bound in us of the bucket holding the `pct` percentile, None if empty.
+    pub fn percentile(&self, pct: u64) -> Option<u64> {
+	let total: u64 = self.counts.iter().sum();
+	if total == 0 {
+	    return None;
+	}
+	let rank = (total * pct).div_ceil(100).max(1);
+	let mut seen = 0;
+	for (i, c) in self.counts.iter().enumerate() {
+	    seen += c;
+	    if seen >= rank {
+		return Some(2 << i);
+	    }
+	}
+	None
+    }
+}
+
+/// Counters for one direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Direction {
+    /// Requests completed.
+    pub ios: u64,
+    pub sectors: u64,
+    /// Time spent on them.
+    pub ms: u64,
+    pub latency: Histogram,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub read: Direction,
+    pub write: Direction,
+    pub in_flight: u64,
+    /// Time with at least one request in flight.
+    pub busy_ms: u64,
+}
+
+/// A device that counts what goes through it, timed by `clock` in microseconds.
+pub struct Counted<D, C> {
+    dev: D,
+    clock: C,
+    stats: IoStats,
+    /// Sub-millisecond remainders, so fast requests still add up to busy time.
+    read_us: u64,
+    write_us: u64,
+}
+
+impl<D: BlockDevice, C: FnMut() -> u64> Counted<D, C> {
+    pub fn new(dev: D, clock: C) -> Self {
+	Self { dev, clock, stats: IoStats::default(), read_us: 0, write_us: 0 }
+    }
+
+    pub fn stats(&self) -> &IoStats {
+	&self.stats
+    }
+
+    /// Count a completed request that took `us` and moved `len` bytes.
+    fn complete(&mut self, write: bool, len: usize, us: u64) {
+	let (d, total_us) = match write {
+	    false => (&mut self.stats.read, &mut self.read_us),
+	    true => (&mut self.stats.write, &mut self.write_us),
+	};
+	d.ios += 1;
+	d.sectors += (len / SECTOR_SZ) as u64;
+	d.latency.record(us);
+	*total_us += us;
+	d.ms = *total_us / 1000;
+	self.stats.busy_ms = (self.read_us + self.write_us) / 1000;
+    }
+}
+
+impl<D: BlockDevice, C: FnMut() -> u64> BlockDevice for Counted<D, C> {
+    fn sectors(&self) -> u64 {
+	self.dev.sectors()
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	let start = (self.clock)();
+	self.stats.in_flight += 1;
+	let r = self.dev.read(sector, buf);
+	self.stats.in_flight -= 1;
+	let us = (self.clock)().saturating_sub(start);
+	if r.is_ok() {
+	    self.complete(false, buf.len(), us);
+	}
+	r
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	let start = (self.clock)();
+	self.stats.in_flight += 1;
+	let r = self.dev.write(sector, buf);
+	self.stats.in_flight -= 1;
+	let us = (self.clock)().saturating_sub(start);
+	if r.is_ok() {
+	    self.complete(true, buf.len(), us);
+	}
+	r
+    }
+}
+
+/// A `/proc/diskstats` line.
+pub fn write_line<W: Write>(w: &mut W, major: u32, minor: u32, name: &str, s: &IoStats) -> fmt::Result {
+    let (r, wr) = (&s.read, &s.write);
+    // Nothing gets merged, and the weighted time is the plain time with one in flight.
+    write!(w, "{major:4} {minor:7} {name} {} 0 {} {} {} 0 {} {} {} {} {}",
+	r.ios, r.sectors, r.ms, wr.ios, wr.sectors, wr.ms, s.in_flight, s.busy_ms, r.ms + wr.ms)?;
+    w.write_str(" 0 0 0 0 0 0")?;
+    for c in r.latency.counts.iter().chain(&wr.latency.counts) {
+	write!(w, " {c}")?;
+    }
+    w.write_char('\n')
+}
+
+/// The parts of a `/proc/diskstats` line `iostat` uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample<'a> {
+    pub name: &'a str,
+    pub stats: IoStats,
+}
+
+impl<'a> Sample<'a> {
+    /// Parse a line, ours or Linux's. A line without histograms has them empty.
+    pub fn parse(line: &'a str) -> Option<Self> {
+	let mut f = line.split_whitespace();
+	let (_major, _minor, name) = (f.next()?, f.next()?, f.next()?);
+	let mut n = [0u64; 17 + 2 * BUCKETS];
+	let mut count = 0;
+	for (slot, v) in n.iter_mut().zip(f) {
+	    *slot = v.parse().ok()?;
+	    count += 1;
+	}
+	if count < 11 {
+	    return None;
+	}
+	let mut stats = IoStats {
+	    read: Direction { ios: n[0], sectors: n[2], ms: n[3], latency: Histogram::default() },
+	    write: Direction { ios: n[4], sectors: n[6], ms: n[7], latency: Histogram::default() },
+	    in_flight: n[8],
+	    busy_ms: n[9],
+	};
+	if count == n.len() {
+	    stats.read.latency.counts.copy_from_slice(&n[17..17 + BUCKETS]);
+	    stats.write.latency.counts.copy_from_slice(&n[17 + BUCKETS..]);
+	}
+	Some(Self { name, stats })
+    }
+}
+
+/// A device's activity between two samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rates {
+    pub reads_per_s: f64,
+    pub writes_per_s: f64,
+    pub read_kb_per_s: f64,
+    pub write_kb_per_s: f64,
+    /// Average request latency in ms, 0 without requests.
+    pub read_await_ms: f64,
+    pub write_await_ms: f64,
+    /// 99th percentile latency, reads and writes together, if the kernel has histograms.
+    pub p99_us: Option<u64>,
+    /// Share of the time the device was busy.
+    pub util_pct: f64,
+}
+
+impl Rates {
+    pub fn between(before: &IoStats, after: &IoStats, elapsed_ms: u64) -> Self {
+	let secs = elapsed_ms.max(1) as f64 / 1000.0;
+	let delta = |a: u64, b: u64| a.saturating_sub(b) as f64;
+	let await_ms = |a: &Direction, b: &Direction| {
+	    let ios = delta(a.ios, b.ios);
+	    if ios == 0.0 { 0.0 } else { delta(a.ms, b.ms) / ios }
+	};
+	let mut latency = after.read.latency.since(&before.read.latency);
+	let writes = after.write.latency.since(&before.write.latency);
+	for (c, w) in latency.counts.iter_mut().zip(writes.counts) {
+	    *c += w;
+	}
+	let sector_kb = SECTOR_SZ as f64 / 1024.0;
+	Self {
+	    reads_per_s: delta(after.read.ios, before.read.ios) / secs,
+	    writes_per_s: delta(after.write.ios, before.write.ios) / secs,
+	    read_kb_per_s: delta(after.read.sectors, before.read.sectors) * sector_kb / secs,
+	    write_kb_per_s: delta(after.write.sectors, before.write.sectors) * sector_kb / secs,
+	    read_await_ms: await_ms(&after.read, &before.read),
+	    write_await_ms: await_ms(&after.write, &before.write),
+	    p99_us: latency.percentile(99),
+	    util_pct: (delta(after.busy_ms, before.busy_ms) / elapsed_ms.max(1) as f64 * 100.0).min(100.0),
+	}
+    }
+}
+
+pub const IOSTAT_HEADER: &str = "Device            r/s      w/s    rkB/s    wkB/s  r_await  w_await   p99_us  %util";
+
+/// One row of `iostat` output, under `IOSTAT_HEADER`.
+pub fn write_iostat_row<W: Write>(w: &mut W, name: &str, r: &Rates) -> fmt::Result {
+    write!(w, "{name:<12} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>8.2} {:>8.2} ",
+	r.reads_per_s, r.writes_per_s, r.read_kb_per_s, r.write_kb_per_s, r.read_await_ms, r.write_await_ms)?;
+    match r.p99_us {
+	Some(us) => write!(w, "{us:>8}")?,
+	None => write!(w, "{:>8}", "-")?,
+    }
+    writeln!(w, " {:>6.1}", r.util_pct)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use core::cell::Cell;
+
+    #[allow(dead_code)]
+    struct Buf<'a>(&'a mut [u8], usize);
+
+    impl Write for Buf<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.1 + s.len();
+	    self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.1 = end;
+	    Ok(())
+	}
+    }
+
+    /// A device whose every request takes `us` of `now`.
+    #[allow(dead_code)]
+    struct Slow<'a> {
+	now: &'a Cell<u64>,
+	us: u64,
+    }
+
+    impl BlockDevice for Slow<'_> {
+	fn sectors(&self) -> u64 {
+	    64
+	}
+
+	fn read(&mut self, sector: u64, _buf: &mut [u8]) -> Result<(), DmErr> {
+	    self.now.set(self.now.get() + self.us);
+	    if sector >= 64 { Err(DmErr::InputBounds) } else { Ok(()) }
+	}
+
+	fn write(&mut self, _sector: u64, _buf: &[u8]) -> Result<(), DmErr> {
+	    self.now.set(self.now.get() + 4 * self.us);
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn histograms() {
+	assert_eq!((Histogram::bucket(0), Histogram::bucket(1), Histogram::bucket(2), Histogram::bucket(1000)), (0, 0, 1, 9));
+	assert_eq!(Histogram::bucket(u64::MAX), BUCKETS - 1);
+	let mut h = Histogram::default();
+	assert_eq!(h.percentile(99), None);
+	(0..99).for_each(|_| h.record(100));
+	h.record(5000);
+	assert_eq!((h.percentile(50), h.percentile(99), h.percentile(100)), (Some(128), Some(128), Some(8192)));
+	assert_eq!(h.since(&h).percentile(50), None);
+    }
+
+    #[test]
+    fn counts_requests() {
+	let now = Cell::new(0);
+	let mut d = Counted::new(Slow { now: &now, us: 600 }, || now.get());
+	let mut buf = [0; 1024];
+	d.read(0, &mut buf).unwrap();
+	d.read(1, &mut buf[..512]).unwrap();
+	assert!(d.read(64, &mut buf).is_err());
+	d.write(0, &buf).unwrap();
+
+	let s = *d.stats();
+	assert_eq!((s.read.ios, s.read.sectors, s.read.ms), (2, 3, 1));
+	assert_eq!((s.write.ios, s.write.sectors, s.write.ms), (1, 2, 2));
+	assert_eq!((s.in_flight, s.busy_ms), (0, 3));
+	assert_eq!(s.read.latency.counts[Histogram::bucket(600)], 2);
+
+	let mut out = [0; 512];
+	let mut b = Buf(&mut out, 0);
+	write_line(&mut b, 8, 0, "sda", &s).unwrap();
+	let n = b.1;
+	let line = core::str::from_utf8(&out[..n]).unwrap();
+	assert!(line.starts_with("   8       0 sda 2 0 3 1 1 0 2 2 0 3 3 0 0 0 0 0 0 "));
+	assert_eq!(Sample::parse(line), Some(Sample { name: "sda", stats: s }));
+
+	// Linux's own lines have no histograms.
+	let linux = Sample::parse("   8       0 sda 100 5 2000 40 50 2 800 30 0 60 70 0 0 0 0 0 0").unwrap();
+	assert_eq!((linux.stats.read.sectors, linux.stats.write.ms, linux.stats.busy_ms), (2000, 30, 60));
+	assert_eq!(linux.stats.read.latency, Histogram::default());
+	assert_eq!(Sample::parse("8 0 sda 1 2 3"), None);
+    }
+
+    #[test]
+    fn iostat_rates() {
+	let mut before = IoStats::default();
+	before.read.latency.record(100);
+	let mut after = before;
+	after.read = Direction { ios: 200, sectors: 4096, ms: 100, latency: before.read.latency };
+	(0..200).for_each(|_| after.read.latency.record(300));
+	after.write.ios = 10;
+	after.write.ms = 50;
+	after.write.latency.record(40_000);
+	after.busy_ms = 500;
+
+	let r = Rates::between(&before, &after, 2000);
+	assert_eq!((r.reads_per_s, r.writes_per_s, r.read_kb_per_s), (100.0, 5.0, 1024.0));
+	assert_eq!((r.read_await_ms, r.write_await_ms, r.util_pct), (0.5, 5.0, 25.0));
+	assert_eq!(r.p99_us, Some(512));
+
+	let mut out = [0; 256];
+	let mut b = Buf(&mut out, 0);
+	write_iostat_row(&mut b, "sda", &r).unwrap();
+	let n = b.1;
+	assert_eq!(
+	    core::str::from_utf8(&out[..n]).unwrap(),
+	    "sda             100.0      5.0   1024.0      0.0     0.50     5.00      512   25.0\n"
+	);
+	assert_eq!(IOSTAT_HEADER.len(), n - 1);
+    }
+}
@@ -0,0 +1,454 @@
+//! Device mapper: virtual block devices stacked on top of others.
+//!
+//! Like Linux's device mapper, without the ioctls. A target is a `BlockDevice` that maps
+//! its sectors onto one or more underlying devices: `Linear` exposes a range of a disk
+//! (a GPT partition), `Crypt` decrypts a container written by `bob encrypt`, `Verity`
+//! checks every block against a hash tree written by `bob verity`. Targets take their
+//! devices by value, so stacking is nesting, e.g. `Crypt<Linear<Disk>>` for an encrypted
+//! partition, and a `Table` concatenates targets into one device.
+//!
+//! Several targets over the same disk each need it mutably: share it as a
+//! `&RefCell<Disk>`, which is itself a `BlockDevice`.
+//!
+//! Everything works in 512 byte sectors whatever the hardware's, and nothing allocates.
+
+use core::cell::RefCell;
+use core::fmt;
+
+use crate::crypt::{self, CryptErr, Header, Mapper};
+use crate::error::{self, ErrorCode, Subsystem};
+use crate::verity::{self, Geometry, Hash, Superblock, VerityErr, BLOCK_SZ, MAX_SALT_SZ};
+
+pub const SECTOR_SZ: usize = 512;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SZ / SECTOR_SZ) as u64;
+/// Segments in a `Table`.
+pub const MAX_SEGMENTS: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum DmErr {
+    /// Past the end of the device, or a buffer that isn't whole sectors.
+    InputBounds,
+    ReadOnly,
+    /// The device underneath failed.
+    Io,
+    TableFull,
+    Crypt(CryptErr),
+    Verity(VerityErr),
+}
+
+impl ErrorCode for DmErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Dm;
+
+    fn code(&self) -> (u16, u32) {
+	match self {
+	    Self::InputBounds => (1, 0),
+	    Self::ReadOnly => (2, 0),
+	    Self::Io => (3, 0),
+	    Self::TableFull => (4, 0),
+	    Self::Crypt(e) => (5, error::nest(e)),
+	    Self::Verity(e) => (6, error::nest(e)),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "past the end of the device"),
+	    2 => write!(f, "device is read-only"),
+	    3 => write!(f, "I/O error"),
+	    4 => write!(f, "table is full"),
+	    5 => {
+		write!(f, "crypt: ")?;
+		error::describe_nested::<CryptErr>(arg, f)
+	    },
+	    6 => {
+		write!(f, "verity: ")?;
+		error::describe_nested::<VerityErr>(arg, f)
+	    },
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+pub trait BlockDevice {
+    /// Size in 512 byte sectors.
+    fn sectors(&self) -> u64;
+
+    /// Read whole sectors starting at `sector` into `buf`.
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr>;
+
+    /// Write whole sectors starting at `sector` from `buf`.
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr>;
+}
+
+/// Check a request of `buf_len` bytes at `sector` fits a device of `sectors`.
+fn check(sectors: u64, sector: u64, buf_len: usize) -> Result<(), DmErr> {
+    if !buf_len.is_multiple_of(SECTOR_SZ) {
+	return Err(DmErr::InputBounds);
+    }
+    fits(sectors, sector, (buf_len / SECTOR_SZ) as u64)
+}
+
+/// Check `count` sectors at `sector` fit a device of `sectors`.
+fn fits(sectors: u64, sector: u64, count: u64) -> Result<(), DmErr> {
+    match sector.checked_add(count) {
+	Some(end) if end <= sectors => Ok(()),
+	_ => Err(DmErr::InputBounds),
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn sectors(&self) -> u64 {
+	(**self).sectors()
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	(**self).read(sector, buf)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	(**self).write(sector, buf)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for &RefCell<D> {
+    fn sectors(&self) -> u64 {
+	self.borrow().sectors()
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	self.borrow_mut().read(sector, buf)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	self.borrow_mut().write(sector, buf)
+    }
+}
+
+/// A contiguous range of another device.
+pub struct Linear<D> {
+    dev: D,
+    start: u64,
+    len: u64,
+}
+
+impl<D: BlockDevice> Linear<D> {
+    pub fn new(dev: D, start: u64, len: u64) -> Result<Self, DmErr> {
+	fits(dev.sectors(), start, len)?;
+	Ok(Self { dev, start, len })
+    }
+
+    /// A GPT partition, from the starting and (inclusive) ending LBA of its entry on a
+    /// disk with `lba_sz` byte blocks.
+    pub fn partition(dev: D, starting_lba: u64, ending_lba: u64, lba_sz: usize) -> Result<Self, DmErr> {
+	if ending_lba < starting_lba || !lba_sz.is_multiple_of(SECTOR_SZ) || lba_sz == 0 {
+	    return Err(DmErr::InputBounds);
+	}
+	let per_lba = (lba_sz / SECTOR_SZ) as u64;
+	Self::new(dev, starting_lba * per_lba, (ending_lba - starting_lba + 1) * per_lba)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Linear<D> {
+    fn sectors(&self) -> u64 {
+	self.len
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	check(self.len, sector, buf.len())?;
+	self.dev.read(self.start + sector, buf)
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	check(self.len, sector, buf.len())?;
+	self.dev.write(self.start + sector, buf)
+    }
+}
+
+/// The plaintext of an encrypted container.
+pub struct Crypt<D> {
+    dev: D,
+    mapper: Mapper,
+}
+
+impl<D: BlockDevice> Crypt<D> {
+    /// Read the header from the start of `dev` and unlock it.
+    pub fn open(mut dev: D, passphrase: &[u8]) -> Result<Self, DmErr> {
+	let mut b = [0; crypt::HEADER_SZ];
+	dev.read(0, &mut b)?;
+	let header = Header::parse(&b).map_err(DmErr::Crypt)?;
+	if header.data_offset % SECTOR_SZ as u64 != 0 {
+	    return Err(DmErr::Crypt(CryptErr::Unsupported));
+	}
+	fits(dev.sectors(), header.data_offset / SECTOR_SZ as u64, header.data_sectors)?;
+	let mapper = Mapper::unlock(&header, passphrase).map_err(DmErr::Crypt)?;
+	Ok(Self { dev, mapper })
+    }
+
+    fn first(&self) -> u64 {
+	self.mapper.data_offset / SECTOR_SZ as u64
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Crypt<D> {
+    fn sectors(&self) -> u64 {
+	self.mapper.data_sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	check(self.sectors(), sector, buf.len())?;
+	self.dev.read(self.first() + sector, buf)?;
+	for (i, s) in buf.chunks_exact_mut(SECTOR_SZ).enumerate() {
+	    self.mapper.decrypt(sector + i as u64, s.try_into().unwrap()).map_err(DmErr::Crypt)?;
+	}
+	Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	check(self.sectors(), sector, buf.len())?;
+	let mut s = [0; SECTOR_SZ];
+	for (i, plain) in buf.chunks_exact(SECTOR_SZ).enumerate() {
+	    let n = sector + i as u64;
+	    s.copy_from_slice(plain);
+	    self.mapper.encrypt(n, &mut s).map_err(DmErr::Crypt)?;
+	    self.dev.write(self.first() + n, &s)?;
+	}
+	Ok(())
+    }
+}
+
+/// A read-only device whose every block is checked against a hash tree before it's
+/// returned. Only as trustworthy as the root hash it's given.
+pub struct Verity<D, H> {
+    data: D,
+    hash: H,
+    geo: Geometry,
+    salt: [u8; MAX_SALT_SZ],
+    salt_size: usize,
+    root: Hash,
+}
+
+impl<D: BlockDevice, H: BlockDevice> Verity<D, H> {
+    /// Read the superblock from the start of `hash`.
+    pub fn open(data: D, mut hash: H, root: Hash) -> Result<Self, DmErr> {
+	let mut b = [0; verity::SUPERBLOCK_SZ];
+	hash.read(0, &mut b)?;
+	let sb = Superblock::parse(&b).map_err(DmErr::Verity)?;
+	let geo = Geometry::new(sb.data_blocks);
+	fits(data.sectors() / SECTORS_PER_BLOCK, 0, sb.data_blocks)?;
+	fits(hash.sectors() / SECTORS_PER_BLOCK, 0, geo.hash_blocks())?;
+	Ok(Self { data, hash, geo, salt: sb.salt, salt_size: sb.salt_size, root })
+    }
+}
+
+impl<D: BlockDevice, H: BlockDevice> BlockDevice for Verity<D, H> {
+    fn sectors(&self) -> u64 {
+	self.geo.data_blocks * SECTORS_PER_BLOCK
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	check(self.sectors(), sector, buf.len())?;
+	let Self { data, hash, geo, salt, salt_size, root } = self;
+	let (mut block, mut scratch) = ([0; BLOCK_SZ], [0; BLOCK_SZ]);
+	let mut sector = sector;
+	let mut out = buf;
+	while !out.is_empty() {
+	    let index = sector / SECTORS_PER_BLOCK;
+	    data.read(index * SECTORS_PER_BLOCK, &mut block)?;
+	    verity::verify_block(geo, &salt[..*salt_size], root, index, &block, &mut scratch, |n, b| {
+		hash.read(n * SECTORS_PER_BLOCK, b).map_err(|_| VerityErr::Read)
+	    }).map_err(DmErr::Verity)?;
+	    let at = (sector % SECTORS_PER_BLOCK) as usize * SECTOR_SZ;
+	    let n = out.len().min(BLOCK_SZ - at);
+	    let (head, rest) = out.split_at_mut(n);
+	    head.copy_from_slice(&block[at..at + n]);
+	    out = rest;
+	    sector += (n / SECTOR_SZ) as u64;
+	}
+	Ok(())
+    }
+
+    fn write(&mut self, _sector: u64, _buf: &[u8]) -> Result<(), DmErr> {
+	Err(DmErr::ReadOnly)
+    }
+}
+
+/// Targets laid end to end, like a device mapper table.
+pub struct Table<'a> {
+    segments: [Option<&'a mut dyn BlockDevice>; MAX_SEGMENTS],
+    count: usize,
+}
+
+impl Default for Table<'_> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl<'a> Table<'a> {
+    pub fn new() -> Self {
+	Self { segments: [const { None }; MAX_SEGMENTS], count: 0 }
+    }
+
+    /// Append `target` after the existing segments.
+    pub fn push(&mut self, target: &'a mut dyn BlockDevice) -> Result<(), DmErr> {
+	let slot = self.segments.get_mut(self.count).ok_or(DmErr::TableFull)?;
+	*slot = Some(target);
+	self.count += 1;
+	Ok(())
+    }
+
+    /// Call `f` for each piece of a request at `sector` covering `len` bytes, with the
+    /// segment, the sector in it and the range of the request it covers.
+    fn each<F>(&mut self, sector: u64, len: usize, mut f: F) -> Result<(), DmErr>
+    where
+	F: FnMut(&mut dyn BlockDevice, u64, core::ops::Range<usize>) -> Result<(), DmErr>,
+    {
+	check(self.sectors(), sector, len)?;
+	let (mut start, mut done) = (0, 0);
+	for seg in self.segments[..self.count].iter_mut().flatten() {
+	    let end = start + seg.sectors();
+	    let at = sector + (done / SECTOR_SZ) as u64;
+	    if done < len && at < end {
+		let n = (len - done).min((end - at) as usize * SECTOR_SZ);
+		f(&mut **seg, at - start, done..done + n)?;
+		done += n;
+	    }
+	    start = end;
+	}
+	Ok(())
+    }
+}
+
+impl BlockDevice for Table<'_> {
+    fn sectors(&self) -> u64 {
+	self.segments[..self.count].iter().flatten().map(|s| s.sectors()).sum()
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	self.each(sector, buf.len(), |seg, at, range| seg.read(at, &mut buf[range]))
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	self.each(sector, buf.len(), |seg, at, range| seg.write(at, &buf[range]))
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::verity::hash_block;
+
+    /// 64 sectors of memory.
+    #[allow(dead_code)]
+    struct MemDisk([u8; 64 * SECTOR_SZ]);
+
+    impl BlockDevice for MemDisk {
+	fn sectors(&self) -> u64 {
+	    64
+	}
+
+	fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	    check(64, sector, buf.len())?;
+	    let at = sector as usize * SECTOR_SZ;
+	    buf.copy_from_slice(&self.0[at..at + buf.len()]);
+	    Ok(())
+	}
+
+	fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	    check(64, sector, buf.len())?;
+	    let at = sector as usize * SECTOR_SZ;
+	    self.0[at..at + buf.len()].copy_from_slice(buf);
+	    Ok(())
+	}
+    }
+
+    #[allow(dead_code)]
+    fn disk() -> MemDisk {
+	let mut d = MemDisk([0; 64 * SECTOR_SZ]);
+	for (i, s) in d.0.chunks_exact_mut(SECTOR_SZ).enumerate() {
+	    s.fill(i as u8);
+	}
+	d
+    }
+
+    #[test]
+    fn linear_and_table() {
+	let disk = RefCell::new(disk());
+	// LBAs 2..=3 of 4KiB blocks.
+	let mut part = Linear::partition(&disk, 2, 3, 4096).unwrap();
+	assert_eq!(part.sectors(), 16);
+	let mut buf = [0; 2 * SECTOR_SZ];
+	part.read(15, &mut buf[..SECTOR_SZ]).unwrap();
+	assert_eq!(buf[0], 31);
+	assert_eq!(part.read(15, &mut buf), Err(DmErr::InputBounds));
+	assert!(Linear::new(&disk, 60, 5).is_err());
+
+	let mut a = Linear::new(&disk, 10, 1).unwrap();
+	let mut b = Linear::new(&disk, 40, 4).unwrap();
+	{
+	    let mut t = Table::new();
+	    t.push(&mut a).unwrap();
+	    t.push(&mut b).unwrap();
+	    assert_eq!(t.sectors(), 5);
+	    t.read(0, &mut buf).unwrap();
+	    assert_eq!((buf[0], buf[SECTOR_SZ]), (10, 40));
+	    t.write(0, &[0xEE; 2 * SECTOR_SZ]).unwrap();
+	}
+	assert_eq!((disk.borrow().0[10 * SECTOR_SZ], disk.borrow().0[40 * SECTOR_SZ]), (0xEE, 0xEE));
+	assert_eq!(disk.borrow().0[11 * SECTOR_SZ], 11);
+    }
+
+    #[test]
+    fn crypt_over_partition() {
+	let key = crypt::derive_key(b"pw", &[3; crypt::SALT_SZ], 2);
+	let header = Header::new(&key, [3; crypt::SALT_SZ], 2, 32 * SECTOR_SZ as u64, [0; 16]).unwrap();
+	let mut b = [0; crypt::HEADER_SZ];
+	header.write(&mut b);
+	let disk = RefCell::new(disk());
+	let mut part = Linear::new(&disk, 16, 32).unwrap();
+	part.write(0, &b).unwrap();
+
+	assert!(matches!(Crypt::open(&mut part, b"wrong"), Err(DmErr::Crypt(CryptErr::WrongKey))));
+	let mut c = Crypt::open(&mut part, b"pw").unwrap();
+	assert_eq!(c.sectors(), 24);
+	c.write(1, &[0x5A; 2 * SECTOR_SZ]).unwrap();
+	let mut buf = [0; 2 * SECTOR_SZ];
+	c.read(1, &mut buf).unwrap();
+	assert_eq!(buf, [0x5A; 2 * SECTOR_SZ]);
+	// Data starts after the 8 header sectors, and only ciphertext reaches the disk.
+	assert!(disk.borrow().0[(16 + 9) * SECTOR_SZ..(16 + 11) * SECTOR_SZ].iter().any(|b| *b != 0x5A));
+	assert_eq!(c.read(23, &mut buf), Err(DmErr::InputBounds));
+    }
+
+    #[test]
+    fn verity_over_partitions() {
+	// Two data blocks in sectors 0..16, their tree in 16..32: the superblock then a
+	// single hash block.
+	let salt = b"salt";
+	let disk = RefCell::new(disk());
+	let mut level0 = [0; BLOCK_SZ];
+	for i in 0..2 {
+	    let mut block = [0; BLOCK_SZ];
+	    disk.borrow_mut().read(i * SECTORS_PER_BLOCK, &mut block).unwrap();
+	    level0[i as usize * 32..(i as usize + 1) * 32].copy_from_slice(&hash_block(salt, &block));
+	}
+	let root = hash_block(salt, &level0);
+	let sb = Superblock::new([0; 16], 2, salt).unwrap();
+	disk.borrow_mut().write(16, &sb.to_bytes()).unwrap();
+	disk.borrow_mut().write(24, &level0).unwrap();
+
+	let mut v = Verity::open(Linear::new(&disk, 0, 16).unwrap(), Linear::new(&disk, 16, 16).unwrap(), root).unwrap();
+	assert_eq!(v.sectors(), 16);
+	// Across the block boundary.
+	let mut buf = [0; 2 * SECTOR_SZ];
+	v.read(7, &mut buf).unwrap();
+	assert_eq!((buf[0], buf[SECTOR_SZ]), (7, 8));
+	assert_eq!(v.write(0, &buf), Err(DmErr::ReadOnly));
+
+	disk.borrow_mut().0[9 * SECTOR_SZ] ^= 1;
+	assert_eq!(v.read(7, &mut buf), Err(DmErr::Verity(VerityErr::Mismatch { level: 0 })));
+	v.read(0, &mut buf).unwrap();
+    }
+}
@@ -1,3 +1,7 @@
+use core::fmt;
+
+use crate::error::{ErrorCode, Subsystem};
+
 pub fn load_elf<'a>(bytes: &'a [u8]) -> Result<Elf<'a>, ParseErr> {
     Elf::<'a>::parse(bytes)
 }
@@ -16,10 +20,44 @@ const EI_ABIVERSION: usize = 8;
 const EI_PAD: usize = 9;
 const E_IDENT_SZ: usize = 9;
 
+// ELF 64 header field offsets, read on demand.
+const E_ENTRY: usize = 0x18;
+const E_PHOFF: usize = 0x20;
+const E_SHOFF: usize = 0x28;
+const E_PHENTSIZE: usize = 0x36;
+const E_PHNUM: usize = 0x38;
+const E_SHENTSIZE: usize = 0x3A;
+const E_SHNUM: usize = 0x3C;
+const PHDR_SZ: usize = 56;
+const SHDR_SZ: usize = 64;
+
+// Program header types
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+
+// Section header types
+pub const SHT_SYMTAB: u32 = 2;
+
+// Symbol types, the low nibble of st_info
+pub const STT_FUNC: u8 = 2;
+const SYM_SZ: usize = 24;
+
+// Program header flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+// x86-64 relocation types
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+pub const R_X86_64_RELATIVE: u32 = 8;
+const RELA_SZ: usize = 24;
+
 /// An ELF Binary File.
 pub struct Elf<'a> {
     bytes: &'a [u8],
-    header: Header,
 }
 
 /// ELF 64 Header
@@ -36,11 +74,87 @@ pub struct Header {
     // pub e_phentsize: u16,
 }
 
+/// ELF 64 Program Header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+/// Where the contents of one page of a PT_LOAD segment come from, so segments can be
+/// paged in from the file on first touch instead of copied at exec.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageFill {
+    /// Offset in the page where file contents start, everything else is zero.
+    pub page_offset: usize,
+    pub file_offset: u64,
+    pub file_bytes: usize,
+    /// The whole page is a page-aligned run of a read-only segment's file contents, so
+    /// the page cache's copy can be mapped directly instead of copying it.
+    pub shareable: bool,
+}
+
+/// ELF 64 relocation with addend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rela {
+    pub r_offset: u64,
+    pub r_type: u32,
+    pub r_sym: u32,
+    pub r_addend: i64,
+}
+
+/// ELF 64 symbol table entry, with its name looked up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub st_type: u8,
+    pub st_value: u64,
+    pub st_size: u64,
+}
+
 #[derive(Debug)]
 pub enum ParseErr {
     MagicNumber,
     EIClass,
     InputBounds,
+    /// PT_INTERP isn't a NUL terminated UTF-8 path.
+    Interp,
+    /// A relocation type the loader doesn't handle.
+    Relocation(u32),
+    /// A relocation against a symbol that couldn't be resolved.
+    Symbol(u32),
+}
+
+impl ErrorCode for ParseErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Elf;
+
+    fn code(&self) -> (u16, u32) {
+	match *self {
+	    Self::MagicNumber => (1, 0),
+	    Self::EIClass => (2, 0),
+	    Self::InputBounds => (3, 0),
+	    Self::Interp => (4, 0),
+	    Self::Relocation(t) => (5, t),
+	    Self::Symbol(sym) => (6, sym),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "not an ELF file"),
+	    2 => write!(f, "not a 64-bit ELF file"),
+	    3 => write!(f, "truncated or out of bounds"),
+	    4 => write!(f, "PT_INTERP isn't a NUL terminated UTF-8 path"),
+	    5 => write!(f, "relocation type {arg} isn't supported"),
+	    6 => write!(f, "symbol {arg} couldn't be resolved"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
 }
 
 pub enum Endianness {
@@ -51,15 +165,162 @@ pub enum Endianness {
 impl<'a> Elf<'a> {
 
     fn parse(bytes: &'a [u8]) -> Result<Elf<'a>, ParseErr> {
-	let header = Header::parse(bytes)?;
+	// Fields are read from `bytes` on demand, the header only has to check out.
+	Header::parse(bytes)?;
+
+	Ok(Elf { bytes })
+    }
+
+    pub fn entry(&self) -> Result<u64, ParseErr> {
+	u64_at(self.bytes, E_ENTRY)
+    }
+
+    pub fn program_headers(&self) -> Result<impl Iterator<Item = ProgramHeader> + 'a, ParseErr> {
+	let phoff = u64_at(self.bytes, E_PHOFF)? as usize;
+	let phentsize = u16_at(self.bytes, E_PHENTSIZE)? as usize;
+	let phnum = u16_at(self.bytes, E_PHNUM)? as usize;
+	if phnum > 0 && phentsize < PHDR_SZ {
+	    return Err(ParseErr::InputBounds);
+	}
+	let table = phoff.checked_add(phentsize * phnum)
+	    .and_then(|end| self.bytes.get(phoff..end))
+	    .ok_or(ParseErr::InputBounds)?;
+
+	Ok(table.chunks_exact(phentsize.max(1)).take(phnum).map(|ph| ProgramHeader {
+	    p_type: u32::from_le_bytes(ph[0..4].try_into().unwrap()),
+	    p_flags: u32::from_le_bytes(ph[4..8].try_into().unwrap()),
+	    p_offset: u64::from_le_bytes(ph[8..16].try_into().unwrap()),
+	    p_vaddr: u64::from_le_bytes(ph[16..24].try_into().unwrap()),
+	    p_filesz: u64::from_le_bytes(ph[32..40].try_into().unwrap()),
+	    p_memsz: u64::from_le_bytes(ph[40..48].try_into().unwrap()),
+	    p_align: u64::from_le_bytes(ph[48..56].try_into().unwrap()),
+	}))
+    }
+
+    /// The program interpreter (dynamic linker) to load instead, if the binary asks for one.
+    pub fn interp(&self) -> Result<Option<&'a str>, ParseErr> {
+	let Some(ph) = self.program_headers()?.find(|ph| ph.p_type == PT_INTERP) else {
+	    return Ok(None);
+	};
+	let path = (ph.p_offset as usize).checked_add(ph.p_filesz as usize)
+	    .and_then(|end| self.bytes.get(ph.p_offset as usize..end))
+	    .ok_or(ParseErr::InputBounds)?;
+	let path = path.strip_suffix(&[0]).ok_or(ParseErr::Interp)?;
+	core::str::from_utf8(path).map(Some).map_err(|_| ParseErr::Interp)
+    }
+
+    /// The entries of the static symbol table (.symtab), empty for stripped binaries.
+    /// Names that aren't UTF-8 come out empty.
+    pub fn symbols(&self) -> Result<impl Iterator<Item = Symbol<'a>> + 'a, ParseErr> {
+	let shoff = u64_at(self.bytes, E_SHOFF)? as usize;
+	let shentsize = u16_at(self.bytes, E_SHENTSIZE)? as usize;
+	let shnum = u16_at(self.bytes, E_SHNUM)? as usize;
+	if shnum > 0 && shentsize < SHDR_SZ {
+	    return Err(ParseErr::InputBounds);
+	}
+	let sections = shoff.checked_add(shentsize * shnum)
+	    .and_then(|end| self.bytes.get(shoff..end))
+	    .ok_or(ParseErr::InputBounds)?;
+	let section = |sh: &[u8]| -> Result<&'a [u8], ParseErr> {
+	    let offset = u64_at(sh, 0x18)? as usize;
+	    let size = u64_at(sh, 0x20)? as usize;
+	    offset.checked_add(size).and_then(|end| self.bytes.get(offset..end)).ok_or(ParseErr::InputBounds)
+	};
+
+	let symtab = sections.chunks_exact(shentsize.max(1)).take(shnum).find(|sh| u32_at(sh, 4).ok() == Some(SHT_SYMTAB));
+	let (table, strings) = match symtab {
+	    Some(sh) => {
+		let link = u32_at(sh, 0x28)? as usize;
+		let strtab = sections.get(link * shentsize..(link + 1) * shentsize).ok_or(ParseErr::InputBounds)?;
+		(section(sh)?, section(strtab)?)
+	    },
+	    None => (&[][..], &[][..]),
+	};
 
-	Ok(Elf {
-	    bytes,
-	    header,
+	Ok(table.chunks_exact(SYM_SZ).map(move |sym| {
+	    let name = strings.get(u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize..)
+		.and_then(|s| s.split(|b| *b == 0).next())
+		.and_then(|s| core::str::from_utf8(s).ok())
+		.unwrap_or("");
+	    Symbol {
+		name,
+		st_type: sym[4] & 0xf,
+		st_value: u64::from_le_bytes(sym[8..16].try_into().unwrap()),
+		st_size: u64::from_le_bytes(sym[16..24].try_into().unwrap()),
+	    }
+	}))
+    }
+}
+
+impl ProgramHeader {
+    /// How to fill the page at `page_vaddr` (aligned to `page_sz`) of this segment, None
+    /// if the segment doesn't cover it.
+    pub fn page_fill(&self, page_vaddr: u64, page_sz: u64) -> Option<PageFill> {
+	let seg_end = self.p_vaddr.checked_add(self.p_memsz)?;
+	let page_end = page_vaddr.checked_add(page_sz)?;
+	if self.p_type != PT_LOAD || page_end <= self.p_vaddr || page_vaddr >= seg_end {
+	    return None;
+	}
+
+	let file_end = self.p_vaddr.saturating_add(self.p_filesz.min(self.p_memsz)).min(page_end);
+	let start = page_vaddr.max(self.p_vaddr);
+	let file_bytes = file_end.saturating_sub(start) as usize;
+	Some(PageFill {
+	    page_offset: (start - page_vaddr) as usize,
+	    file_offset: self.p_offset + (start - self.p_vaddr),
+	    file_bytes,
+	    shareable: self.p_flags & PF_W == 0
+		&& file_bytes as u64 == page_sz
+		&& (self.p_offset + (start - self.p_vaddr)).is_multiple_of(page_sz),
 	})
     }
 }
 
+/// The entries of a DT_RELA (or DT_JMPREL) table.
+pub fn relocations(table: &[u8]) -> impl Iterator<Item = Rela> + '_ {
+    table.chunks_exact(RELA_SZ).map(|r| {
+	let info = u64::from_le_bytes(r[8..16].try_into().unwrap());
+	Rela {
+	    r_offset: u64::from_le_bytes(r[0..8].try_into().unwrap()),
+	    r_type: info as u32,
+	    r_sym: (info >> 32) as u32,
+	    r_addend: i64::from_le_bytes(r[16..24].try_into().unwrap()),
+	}
+    })
+}
+
+/// Apply relocations to an image loaded at `base`, where `image[0]` is at link address 0.
+/// `resolve` gives the address of a symbol by its index in the dynamic symbol table.
+/// Used by the dynamic linker on itself, then on the program and its libraries.
+pub fn relocate(image: &mut [u8], base: u64, relas: impl Iterator<Item = Rela>, mut resolve: impl FnMut(u32) -> Option<u64>) -> Result<(), ParseErr> {
+    for r in relas {
+	let value = match r.r_type {
+	    R_X86_64_RELATIVE => base.wrapping_add_signed(r.r_addend),
+	    R_X86_64_64 => resolve(r.r_sym).ok_or(ParseErr::Symbol(r.r_sym))?.wrapping_add_signed(r.r_addend),
+	    R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => resolve(r.r_sym).ok_or(ParseErr::Symbol(r.r_sym))?,
+	    t => return Err(ParseErr::Relocation(t)),
+	};
+	let at = r.r_offset as usize;
+	at.checked_add(8)
+	    .and_then(|end| image.get_mut(at..end))
+	    .ok_or(ParseErr::InputBounds)?
+	    .copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(())
+}
+
+fn u16_at(b: &[u8], off: usize) -> Result<u16, ParseErr> {
+    b.get(off..off + 2).map(|b| u16::from_le_bytes(b.try_into().unwrap())).ok_or(ParseErr::InputBounds)
+}
+
+fn u32_at(b: &[u8], off: usize) -> Result<u32, ParseErr> {
+    b.get(off..off + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).ok_or(ParseErr::InputBounds)
+}
+
+fn u64_at(b: &[u8], off: usize) -> Result<u64, ParseErr> {
+    b.get(off..off + 8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).ok_or(ParseErr::InputBounds)
+}
+
 impl Header {
     fn parse(bytes: &[u8]) -> Result<Header, ParseErr> {
 	let mut e_ident = [0;E_IDENT_SZ];
@@ -143,4 +404,132 @@ mod tests {
 	let result = Elf::parse(&header);
 	assert!(matches!(result, Err(ParseErr::InputBounds)));
     }
+
+    /// A minimal ELF 64 image: the header and one program header per entry of `phdrs`,
+    /// followed by `data`.
+    #[allow(dead_code)]
+    fn image(phdrs: &[(u32, u64, u64)], data: &[u8]) -> [u8; 512] {
+	let mut b = [0u8; 512];
+	b[EI_MAG0..=EI_MAG3].copy_from_slice(&[0x7F, 0x45, 0x4C, 0x46]);
+	b[EI_CLASS] = 2;
+	b[E_ENTRY..E_ENTRY + 8].copy_from_slice(&0x401000u64.to_le_bytes());
+	b[E_PHOFF..E_PHOFF + 8].copy_from_slice(&64u64.to_le_bytes());
+	b[E_PHENTSIZE..E_PHENTSIZE + 2].copy_from_slice(&(PHDR_SZ as u16).to_le_bytes());
+	b[E_PHNUM..E_PHNUM + 2].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+	for (i, &(ty, offset, filesz)) in phdrs.iter().enumerate() {
+	    let ph = &mut b[64 + i * PHDR_SZ..64 + (i + 1) * PHDR_SZ];
+	    ph[0..4].copy_from_slice(&ty.to_le_bytes());
+	    ph[8..16].copy_from_slice(&offset.to_le_bytes());
+	    ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+	}
+	let data_at = 64 + phdrs.len() * PHDR_SZ;
+	b[data_at..data_at + data.len()].copy_from_slice(data);
+	b
+    }
+
+    #[test]
+    fn interp() {
+	let data_at = 64 + 2 * PHDR_SZ as u64;
+	let b = image(&[(PT_LOAD, 0, 0), (PT_INTERP, data_at, 11)], b"/lib/ld.so\0");
+	let elf = Elf::parse(&b).unwrap();
+	assert_eq!(elf.entry().unwrap(), 0x401000);
+	assert_eq!(elf.program_headers().unwrap().count(), 2);
+	assert!(matches!(elf.interp(), Ok(Some("/lib/ld.so"))));
+
+	let b = image(&[(PT_LOAD, 0, 0)], &[]);
+	assert!(matches!(Elf::parse(&b).unwrap().interp(), Ok(None)));
+
+	// Not NUL terminated.
+	let b = image(&[(PT_INTERP, 64 + PHDR_SZ as u64, 4)], b"/lib");
+	assert!(matches!(Elf::parse(&b).unwrap().interp(), Err(ParseErr::Interp)));
+    }
+
+    #[test]
+    fn relocate_image() {
+	let rela = |offset: u64, ty: u32, sym: u32, addend: i64| {
+	    let mut r = [0u8; RELA_SZ];
+	    r[0..8].copy_from_slice(&offset.to_le_bytes());
+	    r[8..16].copy_from_slice(&((sym as u64) << 32 | ty as u64).to_le_bytes());
+	    r[16..24].copy_from_slice(&addend.to_le_bytes());
+	    r
+	};
+	let mut table = [0u8; 3 * RELA_SZ];
+	table[0..24].copy_from_slice(&rela(0, R_X86_64_RELATIVE, 0, 0x100));
+	table[24..48].copy_from_slice(&rela(8, R_X86_64_JUMP_SLOT, 1, 0));
+	table[48..72].copy_from_slice(&rela(16, R_X86_64_64, 1, 4));
+
+	let mut img = [0u8; 24];
+	let resolve = |sym| (sym == 1).then_some(0x7000_0000);
+	relocate(&mut img, 0x40_0000, relocations(&table), resolve).unwrap();
+	assert_eq!(u64_at(&img, 0).unwrap(), 0x40_0100);
+	assert_eq!(u64_at(&img, 8).unwrap(), 0x7000_0000);
+	assert_eq!(u64_at(&img, 16).unwrap(), 0x7000_0004);
+
+	let unresolved = rela(0, R_X86_64_GLOB_DAT, 2, 0);
+	assert!(matches!(relocate(&mut img, 0, relocations(&unresolved), resolve), Err(ParseErr::Symbol(2))));
+	let unknown = rela(0, 37, 0, 0);
+	assert!(matches!(relocate(&mut img, 0, relocations(&unknown), resolve), Err(ParseErr::Relocation(37))));
+    }
+
+    #[test]
+    fn symbols() {
+	// Section headers at 0x100: null, .symtab at 0x40 linking to .strtab at 0xa0.
+	let mut b = image(&[], &[]);
+	b[E_SHOFF..E_SHOFF + 8].copy_from_slice(&0x100u64.to_le_bytes());
+	b[E_SHENTSIZE..E_SHENTSIZE + 2].copy_from_slice(&(SHDR_SZ as u16).to_le_bytes());
+	b[E_SHNUM..E_SHNUM + 2].copy_from_slice(&3u16.to_le_bytes());
+	let mut section = |i: usize, ty: u32, offset: u64, size: u64, link: u32| {
+	    let sh = &mut b[0x100 + i * SHDR_SZ..0x100 + (i + 1) * SHDR_SZ];
+	    sh[4..8].copy_from_slice(&ty.to_le_bytes());
+	    sh[0x18..0x20].copy_from_slice(&offset.to_le_bytes());
+	    sh[0x20..0x28].copy_from_slice(&size.to_le_bytes());
+	    sh[0x28..0x2c].copy_from_slice(&link.to_le_bytes());
+	};
+	section(1, SHT_SYMTAB, 0x40, 2 * SYM_SZ as u64, 2);
+	section(2, 3, 0xa0, 16, 0);
+	let mut symbol = |i: usize, name: u32, info: u8, value: u64, size: u64| {
+	    let sym = &mut b[0x40 + i * SYM_SZ..0x40 + (i + 1) * SYM_SZ];
+	    sym[0..4].copy_from_slice(&name.to_le_bytes());
+	    sym[4] = info;
+	    sym[8..16].copy_from_slice(&value.to_le_bytes());
+	    sym[16..24].copy_from_slice(&size.to_le_bytes());
+	};
+	symbol(0, 0, 0, 0, 0);
+	symbol(1, 1, 0x10 | STT_FUNC, 0xffff_ffff_8000_1000, 0x40);
+	b[0xa0..0xa8].copy_from_slice(b"\0kmain\0\0");
+
+	let elf = Elf::parse(&b).unwrap();
+	let mut syms = elf.symbols().unwrap();
+	assert_eq!(syms.next().map(|s| s.name), Some(""));
+	assert_eq!(syms.next(), Some(Symbol { name: "kmain", st_type: STT_FUNC, st_value: 0xffff_ffff_8000_1000, st_size: 0x40 }));
+	assert_eq!(syms.next(), None);
+
+	// Stripped.
+	let b = image(&[], &[]);
+	assert_eq!(Elf::parse(&b).unwrap().symbols().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn page_fills() {
+	// Data and bss: 0x1800 bytes from the file at 0x401200, 0x3000 in memory.
+	let ph = ProgramHeader {
+	    p_type: PT_LOAD,
+	    p_flags: PF_R | PF_W,
+	    p_offset: 0x2200,
+	    p_vaddr: 0x401200,
+	    p_filesz: 0x1800,
+	    p_memsz: 0x3000,
+	    p_align: 0x1000,
+	};
+	assert_eq!(ph.page_fill(0x400000, 0x1000), None);
+	assert_eq!(ph.page_fill(0x401000, 0x1000), Some(PageFill { page_offset: 0x200, file_offset: 0x2200, file_bytes: 0xE00, shareable: false }));
+	assert_eq!(ph.page_fill(0x402000, 0x1000), Some(PageFill { page_offset: 0, file_offset: 0x3000, file_bytes: 0xA00, shareable: false }));
+	// All bss.
+	assert_eq!(ph.page_fill(0x403000, 0x1000).map(|f| f.file_bytes), Some(0));
+	assert_eq!(ph.page_fill(0x405000, 0x1000), None);
+
+	// Text can come straight from the page cache.
+	let text = ProgramHeader { p_flags: PF_R | PF_X, p_offset: 0x1000, p_vaddr: 0x401000, p_filesz: 0x2000, p_memsz: 0x2000, ..ph };
+	assert!(text.page_fill(0x402000, 0x1000).unwrap().shareable);
+    }
 }
@@ -0,0 +1,243 @@
+//! Errors as the bootloader and the kernel report them.
+//!
+//! Every module keeps its own error enum, matched on wherever the error is handled. An
+//! [`Error`] is what one turns into when it's reported instead: the subsystem it came
+//! from, a code within the subsystem and one argument, plus a note of what was being done
+//! at the time. Without the note it's eight bytes ([`Error::to_raw`]), which is how an
+//! error gets from the bootloader to the kernel, and the kernel can still describe the
+//! ones from common.
+//!
+//! ```text
+//! loading the kernel: elf: relocation type 37 isn't supported
+//! ```
+
+use core::fmt;
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// Where an error came from. The numbers are part of the raw form, don't reuse them.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subsystem {
+    Elf = 1,
+    Dm = 2,
+    Crypt = 3,
+    Verity = 4,
+    Gpt = 5,
+    /// The bootloader's boot config.
+    Config = 6,
+    /// The kernel's RDRAND entropy source.
+    Entropy = 7,
+    /// The first boot's provisioning partition.
+    Provision = 8,
+}
+
+impl Subsystem {
+    pub fn from_raw(n: u8) -> Option<Self> {
+	Some(match n {
+	    1 => Self::Elf,
+	    2 => Self::Dm,
+	    3 => Self::Crypt,
+	    4 => Self::Verity,
+	    5 => Self::Gpt,
+	    6 => Self::Config,
+	    7 => Self::Entropy,
+	    8 => Self::Provision,
+	    _ => return None,
+	})
+    }
+
+    pub fn name(self) -> &'static str {
+	match self {
+	    Self::Elf => "elf",
+	    Self::Dm => "dm",
+	    Self::Crypt => "crypt",
+	    Self::Verity => "verity",
+	    Self::Gpt => "gpt",
+	    Self::Config => "config",
+	    Self::Entropy => "entropy",
+	    Self::Provision => "provision",
+	}
+    }
+
+    /// How to describe this subsystem's codes, for the ones common knows.
+    fn describer(self) -> Option<Describe> {
+	match self {
+	    Self::Elf => Some(crate::elf::ParseErr::describe),
+	    Self::Dm => Some(crate::dm::DmErr::describe),
+	    Self::Crypt => Some(crate::crypt::CryptErr::describe),
+	    Self::Verity => Some(crate::verity::VerityErr::describe),
+	    Self::Gpt => Some(crate::gpt::GptErr::describe),
+	    Self::Provision => Some(crate::provision::ProvisionErr::describe),
+	    Self::Config | Self::Entropy => None,
+	}
+    }
+}
+
+type Describe = fn(u16, u32, &mut fmt::Formatter) -> fmt::Result;
+
+/// An error enum that can be reported as an [`Error`].
+pub trait ErrorCode {
+    const SUBSYSTEM: Subsystem;
+
+    /// The code for this error, from 1, and its argument.
+    fn code(&self) -> (u16, u32);
+
+    /// What `code` with `arg` means, without the subsystem.
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result;
+}
+
+/// `e`'s code and argument packed into one argument, for a variant wrapping another
+/// subsystem's error: the code in the low byte, the argument above it. Wrapping nests, an
+/// argument loses its top byte each time.
+pub fn nest<E: ErrorCode>(e: &E) -> u32 {
+    let (code, arg) = e.code();
+    arg << 8 | (code as u32 & 0xff)
+}
+
+/// Describe an argument made by [`nest`].
+pub fn describe_nested<E: ErrorCode>(arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+    E::describe((arg & 0xff) as u16, arg >> 8, f)
+}
+
+#[derive(Clone, Copy)]
+pub struct Error {
+    pub subsystem: Subsystem,
+    pub code: u16,
+    pub arg: u32,
+    /// What was being done when it happened.
+    pub context: Option<&'static str>,
+    describe: Option<Describe>,
+}
+
+impl Error {
+    pub fn new<E: ErrorCode>(e: &E) -> Self {
+	let (code, arg) = e.code();
+	Self { subsystem: E::SUBSYSTEM, code, arg, context: None, describe: Some(E::describe) }
+    }
+
+    /// Note what was being done. A note that's already there is kept, it's the more
+    /// specific one.
+    pub fn context(mut self, what: &'static str) -> Self {
+	self.context = self.context.or(Some(what));
+	self
+    }
+
+    /// Subsystem, code and argument in one word. The context doesn't survive.
+    pub fn to_raw(&self) -> u64 {
+	(self.subsystem as u64) << 48 | (self.code as u64) << 32 | self.arg as u64
+    }
+
+    /// An error from `to_raw`, None for a subsystem this build doesn't know.
+    pub fn from_raw(raw: u64) -> Option<Self> {
+	let subsystem = Subsystem::from_raw((raw >> 48) as u8)?;
+	Some(Self { subsystem, code: (raw >> 32) as u16, arg: raw as u32, context: None, describe: subsystem.describer() })
+    }
+}
+
+impl<E: ErrorCode> From<E> for Error {
+    fn from(e: E) -> Self {
+	Self::new(&e)
+    }
+}
+
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+	self.to_raw() == other.to_raw() && self.context == other.context
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	f.debug_struct("Error")
+	    .field("subsystem", &self.subsystem)
+	    .field("code", &self.code)
+	    .field("arg", &self.arg)
+	    .field("context", &self.context)
+	    .finish()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	if let Some(what) = self.context {
+	    write!(f, "{what}: ")?;
+	}
+	write!(f, "{}: ", self.subsystem.name())?;
+	match self.describe {
+	    Some(describe) => describe(self.code, self.arg, f),
+	    None => write!(f, "error {} ({:#x})", self.code, self.arg),
+	}
+    }
+}
+
+/// Turning any reportable error into an [`Error`] with a note of what was being done.
+pub trait Context<T> {
+    fn context(self, what: &'static str) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for core::result::Result<T, E> {
+    fn context(self, what: &'static str) -> Result<T> {
+	self.map_err(|e| e.into().context(what))
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use core::fmt::Write;
+    #[allow(unused_imports)]
+    use crate::{dm::DmErr, elf::ParseErr, gpt::GptErr, verity::VerityErr};
+
+    #[allow(dead_code)]
+    struct Buf<'a>(&'a mut [u8], usize);
+
+    impl fmt::Write for Buf<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.1 + s.len();
+	    self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.1 = end;
+	    Ok(())
+	}
+    }
+
+    #[allow(dead_code)]
+    fn render(e: &Error, out: &mut [u8]) -> usize {
+	let mut b = Buf(out, 0);
+	write!(b, "{e}").unwrap();
+	b.1
+    }
+
+    #[test]
+    fn renders_and_crosses() {
+	let mut out = [0; 128];
+	let r: Result<()> = Err(ParseErr::Relocation(37)).context("loading the kernel");
+	let e = r.unwrap_err();
+	let n = render(&e, &mut out);
+	assert_eq!(&out[..n], b"loading the kernel: elf: relocation type 37 isn't supported");
+
+	let inner: Result<()> = Err(ParseErr::InputBounds).context("reading symbols");
+	assert_eq!(inner.context("loading the kernel").unwrap_err().context, Some("reading symbols"));
+
+	// The raw form keeps everything but the note, and renders the same on the other side.
+	let raw = e.to_raw();
+	let crossed = Error::from_raw(raw).unwrap();
+	assert_eq!(crossed, Error::from(ParseErr::Relocation(37)));
+	let n = render(&crossed, &mut out);
+	assert_eq!(&out[..n], b"elf: relocation type 37 isn't supported");
+	assert_eq!(Error::from_raw(0), None);
+	assert_eq!(Error::from_raw(0xff << 48), None);
+    }
+
+    #[test]
+    fn nested_errors() {
+	let mut out = [0; 128];
+	let e = Error::from(GptErr::Dm(DmErr::Verity(VerityErr::Mismatch { level: 2 })));
+	let n = render(&e, &mut out);
+	assert_eq!(&out[..n], b"gpt: device: verity: hash mismatch at level 2");
+	let n = render(&Error::from_raw(e.to_raw()).unwrap(), &mut out);
+	assert_eq!(&out[..n], b"gpt: device: verity: hash mismatch at level 2");
+    }
+}
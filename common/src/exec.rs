@@ -0,0 +1,133 @@
+//! The initial user stack for `exec`, laid out as the SysV x86-64 ABI says.
+//!
+//! From the stack pointer up: argc, the argv pointers and a NULL, the envp pointers and a
+//! NULL, auxiliary vector pairs ending with `AT_NULL`, then the strings they point at.
+//! The stack pointer is 16 byte aligned. A C-style `_start` reads argc at `(%rsp)` and
+//! passes `argc, argv, envp` on to `main`.
+//! Reference: System V AMD64 ABI, 3.4.1 "Initial Stack and Register State".
+
+/// Auxiliary vector entry types.
+pub mod auxv {
+    pub const AT_NULL: u64 = 0;
+    pub const AT_PHDR: u64 = 3;
+    pub const AT_PHENT: u64 = 4;
+    pub const AT_PHNUM: u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY: u64 = 9;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StackErr {
+    /// The arguments don't fit in the stack.
+    TooBig,
+}
+
+/// Write argv, envp and the auxiliary vector into `stack`, which is mapped at the user
+/// addresses just below `top`. Strings are copied with a terminating NUL added. Returns
+/// the user stack pointer to start with.
+pub fn write_initial_stack(stack: &mut [u8], top: u64, argv: &[&[u8]], envp: &[&[u8]], auxv: &[(u64, u64)]) -> Result<u64, StackErr> {
+    let base = top.checked_sub(stack.len() as u64).ok_or(StackErr::TooBig)?;
+    let strings_len: u64 = argv.iter().chain(envp).map(|s| s.len() as u64 + 1).sum();
+    let n_words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 1);
+    let sp = top.checked_sub(strings_len)
+	.and_then(|p| p.checked_sub(n_words as u64 * 8))
+	.map(|p| p & !0xF)
+	.filter(|p| *p >= base)
+	.ok_or(StackErr::TooBig)?;
+
+    let mut words = Words { stack, base, pos: sp };
+    // Strings go down from the top in order, argv first.
+    let mut string_pos = top;
+    let mut put_string = |words: &mut Words, s: &[u8]| {
+	string_pos -= s.len() as u64 + 1;
+	let o = (string_pos - base) as usize;
+	words.stack[o..o + s.len()].copy_from_slice(s);
+	words.stack[o + s.len()] = 0;
+	string_pos
+    };
+
+    words.put(argv.len() as u64);
+    for s in argv {
+	let addr = put_string(&mut words, s);
+	words.put(addr);
+    }
+    words.put(0);
+    for s in envp {
+	let addr = put_string(&mut words, s);
+	words.put(addr);
+    }
+    words.put(0);
+    for &(key, value) in auxv {
+	words.put(key);
+	words.put(value);
+    }
+    words.put(auxv::AT_NULL);
+    words.put(0);
+
+    Ok(sp)
+}
+
+/// Writes words upwards from a user address.
+struct Words<'a> {
+    stack: &'a mut [u8],
+    base: u64,
+    pos: u64,
+}
+
+impl Words<'_> {
+    fn put(&mut self, v: u64) {
+	let o = (self.pos - self.base) as usize;
+	self.stack[o..o + 8].copy_from_slice(&v.to_le_bytes());
+	self.pos += 8;
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Read the word at a user address of a test stack.
+    #[allow(dead_code)]
+    fn word(stack: &[u8], top: u64, addr: u64) -> u64 {
+	let o = (addr - (top - stack.len() as u64)) as usize;
+	u64::from_le_bytes(stack[o..o + 8].try_into().unwrap())
+    }
+
+    /// Read the NUL terminated string at a user address of a test stack.
+    #[allow(dead_code)]
+    fn string(stack: &[u8], top: u64, addr: u64) -> &[u8] {
+	let o = (addr - (top - stack.len() as u64)) as usize;
+	stack[o..].split(|b| *b == 0).next().unwrap()
+    }
+
+    #[test]
+    fn layout() {
+	const TOP: u64 = 0x7FFF_0000;
+	let mut stack = [0xAAu8; 256];
+	let argv: [&[u8]; 2] = [b"/bin/echo", b"hello"];
+	let envp: [&[u8]; 1] = [b"PATH=/bin"];
+	let sp = write_initial_stack(&mut stack, TOP, &argv, &envp, &[(auxv::AT_PAGESZ, 4096)]).unwrap();
+
+	assert_eq!(sp % 16, 0);
+	assert_eq!(word(&stack, TOP, sp), 2);
+	assert_eq!(string(&stack, TOP, word(&stack, TOP, sp + 8)), b"/bin/echo");
+	assert_eq!(string(&stack, TOP, word(&stack, TOP, sp + 16)), b"hello");
+	assert_eq!(word(&stack, TOP, sp + 24), 0);
+	assert_eq!(string(&stack, TOP, word(&stack, TOP, sp + 32)), b"PATH=/bin");
+	assert_eq!(word(&stack, TOP, sp + 40), 0);
+	assert_eq!((word(&stack, TOP, sp + 48), word(&stack, TOP, sp + 56)), (auxv::AT_PAGESZ, 4096));
+	assert_eq!((word(&stack, TOP, sp + 64), word(&stack, TOP, sp + 72)), (auxv::AT_NULL, 0));
+	// The strings are above the vectors.
+	assert!(word(&stack, TOP, sp + 8) >= sp + 80);
+    }
+
+    #[test]
+    fn too_big() {
+	let mut stack = [0u8; 64];
+	let arg = [b'x'; 40];
+	assert_eq!(write_initial_stack(&mut stack, 0x1000, &[&arg], &[], &[]), Err(StackErr::TooBig));
+	assert_eq!(write_initial_stack(&mut stack, 0x10, &[], &[], &[]), Err(StackErr::TooBig));
+	assert!(write_initial_stack(&mut stack, 0x1000, &[b"a"], &[], &[]).is_ok());
+    }
+}
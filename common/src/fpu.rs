@@ -0,0 +1,307 @@
+//! FPU, SSE and AVX state for user tasks.
+//!
+//! Rust and LLVM emit SSE for x86_64 by default, so user programs fault with #UD unless
+//! CR4.OSFXSR is set, and they corrupt each other's registers unless the vector state is
+//! part of the context switch. `Features::from_cpuid` finds out what the CPU has,
+//! `xcr0` picks the user state components to enable from it, and `cr0`/`cr4` give the
+//! control register values to load at boot. Each task then owns a save area of
+//! `Features::area_size` bytes, `init_area` sets up a new task's.
+//!
+//! By default the state is switched eagerly: saved for the outgoing task and restored for
+//! the incoming one on every switch. With the kernel's `lazy-fpu` feature the switch only
+//! sets CR0.TS, and the first FPU instruction of a task that doesn't own the registers
+//! traps with #NM, which saves the owner's state and loads the task's. That's cheaper
+//! when few tasks touch the FPU, but it's what LazyFP (CVE-2018-3665) leaks through, so
+//! it stays off by default. `Fpu` decides which saves and restores each switch and trap
+//! does under either policy.
+//! Reference: Intel SDM Vol. 1 ch. 13, "Managing State Using the XSAVE Feature Set"
+
+/// Save area size with FXSAVE, the x87 and SSE state only.
+pub const FXSAVE_AREA_SIZE: usize = 512;
+/// XSAVE areas must be 64 byte aligned, FXSAVE ones 16.
+pub const XSAVE_ALIGN: usize = 64;
+/// Where the XSAVE header, and its XSTATE_BV, start in the area.
+pub const XSAVE_HEADER_OFFSET: usize = 512;
+
+/// Control register bits.
+pub mod cr {
+    pub const CR0_MP: u64 = 1 << 1;
+    pub const CR0_EM: u64 = 1 << 2;
+    pub const CR0_TS: u64 = 1 << 3;
+    pub const CR0_NE: u64 = 1 << 5;
+    pub const CR4_OSFXSR: u64 = 1 << 9;
+    pub const CR4_OSXMMEXCPT: u64 = 1 << 10;
+    pub const CR4_OSXSAVE: u64 = 1 << 18;
+}
+
+/// XCR0 state components.
+pub mod xcr0 {
+    pub const X87: u64 = 1 << 0;
+    pub const SSE: u64 = 1 << 1;
+    pub const AVX: u64 = 1 << 2;
+    pub const OPMASK: u64 = 1 << 5;
+    pub const ZMM_HI256: u64 = 1 << 6;
+    pub const HI16_ZMM: u64 = 1 << 7;
+    /// AVX-512 is enabled all together or not at all.
+    pub const AVX512: u64 = OPMASK | ZMM_HI256 | HI16_ZMM;
+}
+
+/// Default x87 control word and MXCSR, all exceptions masked, round to nearest.
+pub const FCW_DEFAULT: u16 = 0x037F;
+pub const MXCSR_DEFAULT: u32 = 0x1F80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveMethod {
+    Fxsave,
+    Xsave,
+    /// XSAVEOPT skips components that are unmodified since the last XRSTOR.
+    Xsaveopt,
+}
+
+/// The CPU's FPU features, from CPUID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Features {
+    pub fxsr: bool,
+    pub sse: bool,
+    pub xsave: bool,
+    pub xsaveopt: bool,
+    /// Components XCR0 can have set, CPUID.(EAX=0DH,ECX=0):EDX:EAX. 0 without XSAVE.
+    pub xcr0_supported: u64,
+    /// Save area size for every supported component, CPUID.(EAX=0DH,ECX=0):ECX.
+    pub xsave_max_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FpuErr {
+    /// No FXSAVE or SSE, not a usable x86_64 CPU.
+    NoSse,
+    /// A save area smaller than `area_size` or misaligned.
+    BadArea,
+    /// #NM with eager switching, TS is never set so it's a kernel bug.
+    UnexpectedTrap,
+}
+
+impl Features {
+    /// From CPUID leaf 1's ECX and EDX, and leaf 0DH subleaves 0 (EAX, ECX, EDX) and 1
+    /// (EAX). The leaf 0DH values are ignored without XSAVE.
+    pub fn from_cpuid(leaf1_ecx: u32, leaf1_edx: u32, leafd0: (u32, u32, u32), leafd1_eax: u32) -> Self {
+	let xsave = leaf1_ecx & (1 << 26) != 0;
+	let (eax, ecx, edx) = leafd0;
+	Self {
+	    fxsr: leaf1_edx & (1 << 24) != 0,
+	    sse: leaf1_edx & (1 << 25) != 0,
+	    xsave,
+	    xsaveopt: xsave && leafd1_eax & 1 != 0,
+	    xcr0_supported: if xsave { (edx as u64) << 32 | eax as u64 } else { 0 },
+	    xsave_max_size: if xsave { ecx as usize } else { 0 },
+	}
+    }
+
+    pub fn check(&self) -> Result<(), FpuErr> {
+	if !self.fxsr || !self.sse {
+	    return Err(FpuErr::NoSse);
+	}
+	Ok(())
+    }
+
+    pub fn save_method(&self) -> SaveMethod {
+	match (self.xsave, self.xsaveopt) {
+	    (false, _) => SaveMethod::Fxsave,
+	    (true, false) => SaveMethod::Xsave,
+	    (true, true) => SaveMethod::Xsaveopt,
+	}
+    }
+
+    /// The user components to enable: x87 and SSE, AVX if there is, AVX-512 if all of
+    /// it is. Supervisor components and ones the kernel doesn't know stay off, their
+    /// state would be switched without anything using it.
+    pub fn xcr0(&self) -> u64 {
+	if !self.xsave {
+	    return 0;
+	}
+	let s = self.xcr0_supported;
+	let mut mask = xcr0::X87 | xcr0::SSE;
+	if s & xcr0::AVX != 0 {
+	    mask |= xcr0::AVX;
+	    if s & xcr0::AVX512 == xcr0::AVX512 {
+		mask |= xcr0::AVX512;
+	    }
+	}
+	mask
+    }
+
+    /// Bytes each task's save area needs. Sized for every supported component, a bit
+    /// more than the enabled ones need but known before XCR0 is written.
+    pub fn area_size(&self) -> usize {
+	if self.xsave {
+	    self.xsave_max_size.max(XSAVE_HEADER_OFFSET + 64)
+	} else {
+	    FXSAVE_AREA_SIZE
+	}
+    }
+
+    /// CR0 with the FPU native and present: MP and NE set, EM and TS clear.
+    pub fn cr0(&self, cr0: u64) -> u64 {
+	(cr0 | cr::CR0_MP | cr::CR0_NE) & !(cr::CR0_EM | cr::CR0_TS)
+    }
+
+    /// CR4 with SSE enabled for everyone, and XSAVE if there is.
+    pub fn cr4(&self, cr4: u64) -> u64 {
+	let mut cr4 = cr4 | cr::CR4_OSFXSR | cr::CR4_OSXMMEXCPT;
+	if self.xsave {
+	    cr4 |= cr::CR4_OSXSAVE;
+	}
+	cr4
+    }
+}
+
+/// Set up a new task's save area: default control words, every other register zero. With
+/// XSAVE, XSTATE_BV says only x87 and SSE are present and the rest load their init state.
+pub fn init_area(area: &mut [u8], features: &Features) -> Result<(), FpuErr> {
+    let align = if features.xsave { XSAVE_ALIGN } else { 16 };
+    if area.len() < features.area_size() || !(area.as_ptr() as usize).is_multiple_of(align) {
+	return Err(FpuErr::BadArea);
+    }
+    area.fill(0);
+    area[0..2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+    area[24..28].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+    if features.xsave {
+	let bv = xcr0::X87 | xcr0::SSE;
+	area[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + 8].copy_from_slice(&bv.to_le_bytes());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    Eager,
+    Lazy,
+}
+
+/// What to do to the registers: save into one task's area, restore from another's, and
+/// the value CR0.TS is left with. Saves come before restores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Switch {
+    pub save: Option<u64>,
+    pub restore: Option<u64>,
+    pub ts: bool,
+}
+
+/// A CPU's FPU bookkeeping: which task's state, by id, is in the registers.
+pub struct Fpu {
+    policy: Policy,
+    owner: Option<u64>,
+}
+
+impl Fpu {
+    pub const fn new(policy: Policy) -> Self {
+	Self { policy, owner: None }
+    }
+
+    pub fn owner(&self) -> Option<u64> {
+	self.owner
+    }
+
+    /// Switching from `prev` to `next`.
+    pub fn switch(&mut self, prev: u64, next: u64) -> Switch {
+	match self.policy {
+	    Policy::Eager => {
+		let save = self.owner.filter(|o| *o == prev);
+		self.owner = Some(next);
+		Switch { save, restore: Some(next), ts: false }
+	    },
+	    // The registers stay as they are until `next` touches them.
+	    Policy::Lazy => Switch { save: None, restore: None, ts: self.owner != Some(next) },
+	}
+    }
+
+    /// #NM in `current`: hand it the registers.
+    pub fn trap(&mut self, current: u64) -> Result<Switch, FpuErr> {
+	if self.policy == Policy::Eager {
+	    return Err(FpuErr::UnexpectedTrap);
+	}
+	if self.owner == Some(current) {
+	    return Ok(Switch { save: None, restore: None, ts: false });
+	}
+	let save = self.owner.replace(current);
+	Ok(Switch { save, restore: Some(current), ts: false })
+    }
+
+    /// `task` exited, whatever of its state is in the registers is garbage now.
+    pub fn exit(&mut self, task: u64) {
+	if self.owner == Some(task) {
+	    self.owner = None;
+	}
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    #[repr(C, align(64))]
+    struct Area([u8; 1024]);
+
+    #[test]
+    fn features_from_cpuid() {
+	// SSE only, no XSAVE.
+	let old = Features::from_cpuid(0, 3 << 24, (7, 832, 0), 1);
+	assert_eq!(old.check(), Ok(()));
+	assert_eq!(old.save_method(), SaveMethod::Fxsave);
+	assert_eq!((old.xcr0(), old.area_size()), (0, FXSAVE_AREA_SIZE));
+	assert_eq!(old.cr4(0) & cr::CR4_OSXSAVE, 0);
+	assert_eq!(Features::from_cpuid(0, 1 << 24, (0, 0, 0), 0).check(), Err(FpuErr::NoSse));
+
+	// AVX, partial AVX-512 and a supervisor component.
+	let avx = Features::from_cpuid(1 << 26, 3 << 24, (0x67 | 1 << 9, 1088, 0), 1);
+	assert_eq!(avx.save_method(), SaveMethod::Xsaveopt);
+	assert_eq!(avx.xcr0(), xcr0::X87 | xcr0::SSE | xcr0::AVX);
+	assert_eq!(avx.area_size(), 1088);
+	assert_eq!(avx.cr4(1 << 5), 1 << 5 | cr::CR4_OSFXSR | cr::CR4_OSXMMEXCPT | cr::CR4_OSXSAVE);
+	assert_eq!(avx.cr0(cr::CR0_EM | cr::CR0_TS | 1), 1 | cr::CR0_MP | cr::CR0_NE);
+
+	let avx512 = Features::from_cpuid(1 << 26, 3 << 24, (0xE7, 2696, 0), 0);
+	assert_eq!(avx512.save_method(), SaveMethod::Xsave);
+	assert_eq!(avx512.xcr0(), 0xE7);
+    }
+
+    #[test]
+    fn initial_area() {
+	let xsave = Features::from_cpuid(1 << 26, 3 << 24, (7, 832, 0), 0);
+	let mut a = Area([0xAA; 1024]);
+	init_area(&mut a.0, &xsave).unwrap();
+	assert_eq!(&a.0[0..2], &[0x7F, 0x03]);
+	assert_eq!(&a.0[24..28], &[0x80, 0x1F, 0, 0]);
+	assert_eq!(a.0[XSAVE_HEADER_OFFSET], 3);
+	assert!(a.0[XSAVE_HEADER_OFFSET + 1..].iter().all(|b| *b == 0));
+	assert_eq!(init_area(&mut a.0[..512], &xsave), Err(FpuErr::BadArea));
+	assert_eq!(init_area(&mut a.0[16..], &xsave), Err(FpuErr::BadArea));
+    }
+
+    #[test]
+    fn eager_switches() {
+	let mut f = Fpu::new(Policy::Eager);
+	assert_eq!(f.switch(0, 1), Switch { save: None, restore: Some(1), ts: false });
+	assert_eq!(f.switch(1, 2), Switch { save: Some(1), restore: Some(2), ts: false });
+	f.exit(2);
+	assert_eq!(f.switch(2, 1), Switch { save: None, restore: Some(1), ts: false });
+	assert_eq!(f.trap(1), Err(FpuErr::UnexpectedTrap));
+    }
+
+    #[test]
+    fn lazy_switches() {
+	let mut f = Fpu::new(Policy::Lazy);
+	assert_eq!(f.switch(0, 1), Switch { save: None, restore: None, ts: true });
+	assert_eq!(f.trap(1), Ok(Switch { save: None, restore: Some(1), ts: false }));
+	// 2 never touches the FPU, 1 gets its registers back untouched.
+	assert_eq!(f.switch(1, 2), Switch { save: None, restore: None, ts: true });
+	assert_eq!(f.switch(2, 1), Switch { save: None, restore: None, ts: false });
+	assert_eq!(f.switch(1, 3), Switch { save: None, restore: None, ts: true });
+	assert_eq!(f.trap(3), Ok(Switch { save: Some(1), restore: Some(3), ts: false }));
+	assert_eq!(f.owner(), Some(3));
+	f.exit(3);
+	assert_eq!(f.owner(), None);
+    }
+}
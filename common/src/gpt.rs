@@ -0,0 +1,421 @@
+//! Finding partitions at boot.
+//!
+//! Reads the GPT of a block device, the primary table or failing that the backup, and
+//! picks out the ESP and root partitions. `esp=` and `root=` on the kernel command line
+//! choose them by `PARTUUID=<guid>` or `PARTLABEL=<name>`; without them the first
+//! partition of the ESP or x86-64 root type wins, as in the Discoverable Partitions
+//! Specification. Nothing allocates: entries are read a block at a time.
+
+use core::fmt;
+
+use crate::dm::{BlockDevice, DmErr, SECTOR_SZ};
+use crate::error::{self, ErrorCode, Subsystem};
+use crate::guid::Guid;
+use crate::snapshot::{crc32, crc32_update};
+
+/// Logical block sizes tried, in order.
+pub const LBA_SIZES: [usize; 2] = [512, 4096];
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const HEADER_MIN_SZ: usize = 92;
+const ENTRY_MIN_SZ: usize = 128;
+const MAX_LBA_SZ: usize = 4096;
+const NAME_UNITS: usize = 36;
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B, in on-disk byte order.
+pub const ESP_TYPE: [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+/// 4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709, the x86-64 root partition.
+pub const ROOT_X86_64_TYPE: [u8; 16] = [0xE3, 0xBC, 0x68, 0x4F, 0xCD, 0xE8, 0xB1, 0x4D, 0x96, 0xE7, 0xFB, 0xCA, 0xF9, 0x84, 0xB7, 0x09];
+
+#[derive(Debug, PartialEq)]
+pub enum GptErr {
+    Dm(DmErr),
+    /// Neither the primary nor the backup header checks out.
+    NoTable,
+    /// The header is fine but its entry array doesn't match the checksum.
+    ArrayChecksum,
+    /// An `esp=` or `root=` value that isn't `PARTUUID=` or `PARTLABEL=`.
+    BadSelector,
+}
+
+impl ErrorCode for GptErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Gpt;
+
+    fn code(&self) -> (u16, u32) {
+	match self {
+	    Self::Dm(e) => (1, error::nest(e)),
+	    Self::NoTable => (2, 0),
+	    Self::ArrayChecksum => (3, 0),
+	    Self::BadSelector => (4, 0),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => {
+		write!(f, "device: ")?;
+		error::describe_nested::<DmErr>(arg, f)
+	    },
+	    2 => write!(f, "neither the primary nor the backup header is valid"),
+	    3 => write!(f, "partition entry array checksum doesn't match"),
+	    4 => write!(f, "partition selector isn't PARTUUID= or PARTLABEL="),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+impl From<DmErr> for GptErr {
+    fn from(e: DmErr) -> Self {
+	GptErr::Dm(e)
+    }
+}
+
+/// The parts of a header needed to find the entries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Table {
+    pub lba_sz: usize,
+    pub disk_guid: Guid,
+    /// Whether this is the backup, the primary being damaged.
+    pub backup: bool,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_sz: usize,
+    array_crc: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    name: [u16; NAME_UNITS],
+}
+
+impl Entry {
+    fn parse(b: &[u8]) -> Self {
+	let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+	let mut name = [0; NAME_UNITS];
+	for (i, unit) in name.iter_mut().enumerate() {
+	    *unit = u16::from_le_bytes([b[56 + 2 * i], b[57 + 2 * i]]);
+	}
+	Self {
+	    type_guid: Guid::from_bytes(b[0..16].try_into().unwrap()),
+	    unique_guid: Guid::from_bytes(b[16..32].try_into().unwrap()),
+	    first_lba: u64_at(32),
+	    last_lba: u64_at(40),
+	    attributes: u64_at(48),
+	    name,
+	}
+    }
+
+    fn used(&self) -> bool {
+	self.type_guid.to_bytes() != [0; 16]
+    }
+
+    pub fn is_esp(&self) -> bool {
+	self.type_guid.to_bytes() == ESP_TYPE
+    }
+
+    pub fn is_root(&self) -> bool {
+	self.type_guid.to_bytes() == ROOT_X86_64_TYPE
+    }
+
+    /// The name's UTF-16 code units, without the padding.
+    pub fn name_units(&self) -> &[u16] {
+	let len = self.name.iter().position(|u| *u == 0).unwrap_or(NAME_UNITS);
+	&self.name[..len]
+    }
+
+    pub fn name_is(&self, name: &str) -> bool {
+	name.encode_utf16().eq(self.name_units().iter().copied())
+    }
+}
+
+/// `"ESP" 2048..=67583 type C12A7328-... guid ...`
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	f.write_str("\"")?;
+	for c in char::decode_utf16(self.name_units().iter().copied()) {
+	    write!(f, "{}", c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+	}
+	write!(f, "\" {}..={} type {} guid {}", self.first_lba, self.last_lba, self.type_guid, self.unique_guid)
+    }
+}
+
+/// How `esp=` or `root=` picks a partition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selector<'a> {
+    Type([u8; 16]),
+    PartUuid(Guid),
+    PartLabel(&'a str),
+}
+
+impl<'a> Selector<'a> {
+    pub fn parse(s: &'a str) -> Result<Self, GptErr> {
+	if let Some(guid) = s.strip_prefix("PARTUUID=") {
+	    return guid.parse().map(Self::PartUuid).map_err(|_| GptErr::BadSelector);
+	}
+	s.strip_prefix("PARTLABEL=").map(Self::PartLabel).ok_or(GptErr::BadSelector)
+    }
+
+    /// The selector given as `key=` on the command line, `default` if there's none.
+    pub fn from_cmdline(cmdline: &'a str, key: &str, default: Self) -> Result<Self, GptErr> {
+	let value = cmdline.split_ascii_whitespace()
+	    .find_map(|arg| arg.strip_prefix(key).and_then(|rest| rest.strip_prefix('=')));
+	value.map_or(Ok(default), Self::parse)
+    }
+
+    pub fn matches(&self, e: &Entry) -> bool {
+	match self {
+	    Self::Type(t) => e.type_guid.to_bytes() == *t,
+	    Self::PartUuid(guid) => e.unique_guid == *guid,
+	    Self::PartLabel(name) => e.name_is(name),
+	}
+    }
+}
+
+fn read_lba<D: BlockDevice>(dev: &mut D, lba_sz: usize, lba: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+    dev.read(lba * (lba_sz / SECTOR_SZ) as u64, &mut buf[..lba_sz])
+}
+
+/// The header at `lba`, if it's valid and says it's there.
+fn read_header<D: BlockDevice>(dev: &mut D, lba_sz: usize, lba: u64) -> Result<Option<Table>, DmErr> {
+    let mut b = [0; MAX_LBA_SZ];
+    read_lba(dev, lba_sz, lba, &mut b)?;
+    let u32_at = |i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    let header_sz = u32_at(12) as usize;
+    if &b[..8] != SIGNATURE || !(HEADER_MIN_SZ..=lba_sz).contains(&header_sz) {
+	return Ok(None);
+    }
+    let crc = crc32_update(crc32(&b[..16]), &[0; 4]);
+    if crc32_update(crc, &b[20..header_sz]) != u32_at(16) || u64_at(24) != lba {
+	return Ok(None);
+    }
+    let entry_sz = u32_at(84) as usize;
+    if entry_sz < ENTRY_MIN_SZ || !entry_sz.is_power_of_two() || entry_sz > lba_sz {
+	return Ok(None);
+    }
+    Ok(Some(Table {
+	lba_sz,
+	disk_guid: Guid::from_bytes(b[56..72].try_into().unwrap()),
+	backup: lba != 1,
+	entries_lba: u64_at(72),
+	num_entries: u32_at(80),
+	entry_sz,
+	array_crc: u32_at(88),
+    }))
+}
+
+impl Table {
+    /// Find the table on `dev`, trying each of `LBA_SIZES`, the primary header then the
+    /// backup in the last block.
+    pub fn read<D: BlockDevice>(dev: &mut D) -> Result<Self, GptErr> {
+	for lba_sz in LBA_SIZES {
+	    let lbas = dev.sectors() / (lba_sz / SECTOR_SZ) as u64;
+	    if lbas < 3 {
+		continue;
+	    }
+	    for lba in [1, lbas - 1] {
+		if let Some(t) = read_header(dev, lba_sz, lba)? {
+		    // A primary with a damaged array may still have a good backup.
+		    match t.each_entry(dev, |_, _| {}) {
+			Err(GptErr::ArrayChecksum) => continue,
+			r => r?,
+		    }
+		    return Ok(t);
+		}
+	    }
+	}
+	Err(GptErr::NoTable)
+    }
+
+    /// Call `f` with the index and contents of each used entry, after checking the
+    /// array's checksum.
+    pub fn each_entry<D, F>(&self, dev: &mut D, mut f: F) -> Result<(), GptErr>
+    where
+	D: BlockDevice,
+	F: FnMut(usize, &Entry),
+    {
+	for pass in 0..2 {
+	    let mut b = [0; MAX_LBA_SZ];
+	    let mut crc = 0;
+	    let per_block = self.lba_sz / self.entry_sz;
+	    let blocks = (self.num_entries as usize).div_ceil(per_block);
+	    for block in 0..blocks {
+		read_lba(dev, self.lba_sz, self.entries_lba + block as u64, &mut b)?;
+		let count = per_block.min(self.num_entries as usize - block * per_block);
+		let bytes = &b[..count * self.entry_sz];
+		if pass == 0 {
+		    crc = crc32_update(crc, bytes);
+		    continue;
+		}
+		for (i, raw) in bytes.chunks_exact(self.entry_sz).enumerate() {
+		    let e = Entry::parse(raw);
+		    if e.used() {
+			f(block * per_block + i, &e);
+		    }
+		}
+	    }
+	    if pass == 0 && crc != self.array_crc {
+		return Err(GptErr::ArrayChecksum);
+	    }
+	}
+	Ok(())
+    }
+
+    /// The first used entry `sel` matches, with its index.
+    pub fn find<D: BlockDevice>(&self, dev: &mut D, sel: Selector) -> Result<Option<(usize, Entry)>, GptErr> {
+	let mut found = None;
+	self.each_entry(dev, |i, e| {
+	    if found.is_none() && sel.matches(e) {
+		found = Some((i, *e));
+	    }
+	})?;
+	Ok(found)
+    }
+}
+
+/// What boot needs from a disk.
+#[derive(Debug, PartialEq)]
+pub struct Discovered {
+    pub table: Table,
+    pub esp: Option<(usize, Entry)>,
+    pub root: Option<(usize, Entry)>,
+}
+
+/// Read the table on `dev` and pick the ESP and root partitions as the command line
+/// says. `log` sees every partition.
+pub fn discover<D, F>(dev: &mut D, cmdline: &str, mut log: F) -> Result<Discovered, GptErr>
+where
+    D: BlockDevice,
+    F: FnMut(usize, &Entry),
+{
+    let esp = Selector::from_cmdline(cmdline, "esp", Selector::Type(ESP_TYPE))?;
+    let root = Selector::from_cmdline(cmdline, "root", Selector::Type(ROOT_X86_64_TYPE))?;
+    let table = Table::read(dev)?;
+    let mut found = Discovered { table, esp: None, root: None };
+    table.each_entry(dev, |i, e| {
+	log(i, e);
+	if found.esp.is_none() && esp.matches(e) {
+	    found.esp = Some((i, *e));
+	}
+	if found.root.is_none() && root.matches(e) {
+	    found.root = Some((i, *e));
+	}
+    })?;
+    Ok(found)
+}
+
+/// Also builds disks for the lsblk tests.
+pub(crate) mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// 128 sectors of memory.
+    #[allow(dead_code)]
+    pub(crate) struct MemDisk(pub(crate) [u8; 128 * SECTOR_SZ]);
+
+    impl BlockDevice for MemDisk {
+	fn sectors(&self) -> u64 {
+	    128
+	}
+
+	fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	    let at = sector as usize * SECTOR_SZ;
+	    buf.copy_from_slice(self.0.get(at..at + buf.len()).ok_or(DmErr::InputBounds)?);
+	    Ok(())
+	}
+
+	fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), DmErr> {
+	    let at = sector as usize * SECTOR_SZ;
+	    self.0.get_mut(at..at + buf.len()).ok_or(DmErr::InputBounds)?.copy_from_slice(buf);
+	    Ok(())
+	}
+    }
+
+    /// Type, unique GUID, first and last LBA, name.
+    #[allow(dead_code)]
+    pub(crate) type Part<'a> = ([u8; 16], [u8; 16], u64, u64, &'a str);
+
+    /// A disk with 8 entries of 128 bytes, the arrays in 2 sectors after the primary
+    /// header and before the backup.
+    #[allow(dead_code)]
+    pub(crate) fn disk(parts: &[Part]) -> MemDisk {
+	let mut d = MemDisk([0; 128 * SECTOR_SZ]);
+	let mut array = [0; 8 * 128];
+	for (i, (ptype, guid, first, last, name)) in parts.iter().enumerate() {
+	    let e = &mut array[i * 128..(i + 1) * 128];
+	    e[..16].copy_from_slice(ptype);
+	    e[16..32].copy_from_slice(guid);
+	    e[32..40].copy_from_slice(&first.to_le_bytes());
+	    e[40..48].copy_from_slice(&last.to_le_bytes());
+	    for (j, u) in name.encode_utf16().enumerate() {
+		e[56 + 2 * j..58 + 2 * j].copy_from_slice(&u.to_le_bytes());
+	    }
+	}
+	for (my, alt, entries) in [(1u64, 127u64, 2u64), (127, 1, 125)] {
+	    let mut h = [0; 92];
+	    h[..8].copy_from_slice(SIGNATURE);
+	    h[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+	    h[12..16].copy_from_slice(&92u32.to_le_bytes());
+	    h[24..32].copy_from_slice(&my.to_le_bytes());
+	    h[32..40].copy_from_slice(&alt.to_le_bytes());
+	    h[56..72].fill(0x77);
+	    h[72..80].copy_from_slice(&entries.to_le_bytes());
+	    h[80..84].copy_from_slice(&8u32.to_le_bytes());
+	    h[84..88].copy_from_slice(&128u32.to_le_bytes());
+	    h[88..92].copy_from_slice(&crc32(&array).to_le_bytes());
+	    let crc = crc32(&h);
+	    h[16..20].copy_from_slice(&crc.to_le_bytes());
+	    d.0[my as usize * SECTOR_SZ..][..92].copy_from_slice(&h);
+	    d.0[entries as usize * SECTOR_SZ..][..array.len()].copy_from_slice(&array);
+	}
+	d
+    }
+
+    #[test]
+    fn discover_partitions() {
+	let linux = [0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4];
+	let parts = [
+	    (linux, [1; 16], 34, 39, "data"),
+	    (ESP_TYPE, [2; 16], 40, 79, "EFI system partition"),
+	    (ROOT_X86_64_TYPE, [3; 16], 80, 99, "root-a"),
+	    (ROOT_X86_64_TYPE, [4; 16], 100, 119, "root-b"),
+	];
+	let mut d = disk(&parts);
+
+	let mut seen = 0;
+	let found = discover(&mut d, "quiet", |_, _| seen += 1).unwrap();
+	assert_eq!(seen, 4);
+	assert!(!found.table.backup);
+	assert_eq!(found.table.lba_sz, 512);
+	let (esp, root) = (found.esp.unwrap(), found.root.unwrap());
+	assert_eq!((esp.0, esp.1.first_lba, esp.1.last_lba), (1, 40, 79));
+	assert!(esp.1.is_esp() && esp.1.name_is("EFI system partition"));
+	assert_eq!(root.0, 2);
+
+	let b = Guid::from_bytes([4; 16]);
+	let cmdline = "esp=PARTLABEL=data root=PARTUUID=04040404-0404-0404-0404-040404040404";
+	let found = discover(&mut d, cmdline, |_, _| {}).unwrap();
+	assert_eq!((found.esp.unwrap().0, found.root.unwrap().1.unique_guid), (0, b));
+	assert_eq!(discover(&mut d, "root=/dev/sda2", |_, _| {}), Err(GptErr::BadSelector));
+	assert_eq!(discover(&mut d, "root=PARTLABEL=root-c", |_, _| {}).unwrap().root, None);
+
+	// A damaged primary header or array falls back to the backup.
+	let mut damaged = disk(&parts);
+	damaged.0[SECTOR_SZ + 40] ^= 1;
+	assert!(discover(&mut damaged, "", |_, _| {}).unwrap().table.backup);
+	let mut damaged = disk(&parts);
+	damaged.0[2 * SECTOR_SZ + 60] ^= 1;
+	let t = Table::read(&mut damaged).unwrap();
+	assert!(t.backup);
+	assert_eq!(t.find(&mut damaged, Selector::PartLabel("root-b")).unwrap().unwrap().0, 3);
+
+	damaged.0[127 * SECTOR_SZ] = 0;
+	assert_eq!(Table::read(&mut damaged), Err(GptErr::NoTable));
+    }
+}
@@ -0,0 +1,194 @@
+use core::fmt;
+use core::str::FromStr;
+
+/// A module for implementing GUIDs
+/// https://datatracker.ietf.org/doc/html/rfc4122
+/// GUID's mostly follow this RFC, EXCEPT for the fact that time_low, time_mid, and time_high are little
+/// endian. In the RFC these fields are stored in big-endian format, so this code would not be compatible
+/// for any other uses besides in GPT disks.
+/// Apple also notes this: https://developer.apple.com/library/archive/technotes/tn2166/_index.html#//apple_ref/doc/uid/DTS10003927-CH1-SUBSECTION11
+///
+/// This type is shared between bob (running on the host) and the kernel, so it doesn't
+/// know where its randomness comes from. Callers gather 16 bytes from whatever entropy
+/// source they have and hand them to `from_random_bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid {
+    time_low: u32,
+    time_mid: [u8;2],
+    time_high_and_version: [u8;2],
+    clock_seq_hi_and_reserved: u8,
+    clock_seq_low: u8,
+    node: [u8;6],
+}
+
+impl Guid {
+
+    /// Generate a new version 4 Guid from random bytes
+    /// https://datatracker.ietf.org/doc/html/rfc4122#section-4.4
+    pub fn from_random_bytes(rb: [u8;16]) -> Self {
+	let mut s = Self::from_bytes(rb);
+	s.clock_seq_hi_and_reserved = (s.clock_seq_hi_and_reserved & 0b00111111) | 0b10000000;
+	s.time_high_and_version[1] = (s.time_high_and_version[1] & 0b00001111) | 0b01000000;
+	s
+    }
+
+    pub fn new(time_low: u32,
+	       time_mid: [u8;2],
+	       time_high_and_version: [u8;2],
+	       clock_seq_hi_and_reserved: u8,
+	       clock_seq_low: u8,
+	       node: [u8;6]) -> Self {
+	Self {
+	    time_low,
+	    time_mid,
+	    time_high_and_version,
+	    clock_seq_hi_and_reserved,
+	    clock_seq_low,
+	    node,
+	}
+    }
+
+    pub fn from_bytes(bytes: [u8;16]) -> Self {
+	Self {
+	    time_low: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+	    time_mid: [bytes[4], bytes[5]],
+	    time_high_and_version: [bytes[6], bytes[7]],
+	    clock_seq_hi_and_reserved: bytes[8],
+	    clock_seq_low: bytes[9],
+	    node: [ bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15],]
+	}
+    }
+
+    pub fn to_bytes(&self) -> [u8;16] {
+	let time_low = self.time_low.to_le_bytes();
+	[
+	    time_low[0],
+	    time_low[1],
+	    time_low[2],
+	    time_low[3],
+	    self.time_mid[0],
+	    self.time_mid[1],
+	    self.time_high_and_version[0],
+	    self.time_high_and_version[1],
+	    self.clock_seq_hi_and_reserved,
+	    self.clock_seq_low,
+	    self.node[0],
+	    self.node[1],
+	    self.node[2],
+	    self.node[3],
+	    self.node[4],
+	    self.node[5],
+	]
+    }
+}
+
+/// Formats as the usual registry format, e.g. C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+	       self.time_low,
+	       u16::from_le_bytes(self.time_mid),
+	       u16::from_le_bytes(self.time_high_and_version),
+	       self.clock_seq_hi_and_reserved,
+	       self.clock_seq_low)?;
+	for b in self.node {
+	    write!(f, "{:02X}", b)?;
+	}
+	Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GuidParseErr;
+
+/// Parses the registry format (case insensitive, optionally wrapped in braces).
+impl FromStr for Guid {
+    type Err = GuidParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+	let s = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or(s);
+	let b = s.as_bytes();
+	if b.len() != 36 || b[8] != b'-' || b[13] != b'-' || b[18] != b'-' || b[23] != b'-' {
+	    return Err(GuidParseErr);
+	}
+
+	// Hex digits in the order they're written, ignoring the dashes.
+	let mut digits = [0u8;16];
+	let mut hex = b.iter().filter(|c| **c != b'-');
+	for d in digits.iter_mut() {
+	    let hi = hex.next().and_then(|c| (*c as char).to_digit(16)).ok_or(GuidParseErr)?;
+	    let lo = hex.next().and_then(|c| (*c as char).to_digit(16)).ok_or(GuidParseErr)?;
+	    *d = (hi << 4 | lo) as u8;
+	}
+	if hex.next().is_some() {
+	    return Err(GuidParseErr);
+	}
+
+	Ok(Self::new(u32::from_be_bytes([digits[0], digits[1], digits[2], digits[3]]),
+		     [digits[5], digits[4]],
+		     [digits[7], digits[6]],
+		     digits[8],
+		     digits[9],
+		     [digits[10], digits[11], digits[12], digits[13], digits[14], digits[15]]))
+    }
+}
+
+mod tests {
+
+    // It's being used, but rust analyzer / flycheck / _something_ complains.
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn bytes_round_trip() {
+	let b = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+	assert_eq!(Guid::from_bytes(b).to_bytes(), b);
+    }
+
+    #[test]
+    fn random_sets_version_and_variant() {
+	let g = Guid::from_random_bytes([0xFF;16]).to_bytes();
+	// version lives in the high nibble of time_high_and_version (stored little endian)
+	assert_eq!(g[7] >> 4, 4);
+	// variant bits are 10xx xxxx
+	assert_eq!(g[8] >> 6, 0b10);
+
+	let g = Guid::from_random_bytes([0;16]).to_bytes();
+	assert_eq!(g[7] >> 4, 4);
+	assert_eq!(g[8] >> 6, 0b10);
+    }
+
+    #[test]
+    fn string_round_trip() {
+	let b = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+	let esp = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+	let mut buf = [0u8; 36];
+	let mut w = Buf(&mut buf, 0);
+	core::fmt::write(&mut w, format_args!("{}", Guid::from_bytes(b))).unwrap();
+	assert_eq!(&buf, esp.as_bytes());
+
+	assert_eq!(esp.parse::<Guid>().unwrap().to_bytes(), b);
+	assert_eq!("{c12a7328-f81f-11d2-ba4b-00a0c93ec93b}".parse::<Guid>().unwrap().to_bytes(), b);
+    }
+
+    #[test]
+    fn bad_strings() {
+	assert!("".parse::<Guid>().is_err());
+	assert!("C12A7328-F81F-11D2-BA4B-00A0C93EC93".parse::<Guid>().is_err());
+	assert!("C12A7328F81F-11D2-BA4B-00A0C93EC93BB".parse::<Guid>().is_err());
+	assert!("G12A7328-F81F-11D2-BA4B-00A0C93EC93B".parse::<Guid>().is_err());
+    }
+
+    // no_std, so there's no String to format into.
+    #[allow(dead_code)]
+    struct Buf<'a>(&'a mut [u8], usize);
+
+    impl<'a> core::fmt::Write for Buf<'a> {
+	fn write_str(&mut self, s: &str) -> core::fmt::Result {
+	    let end = self.1 + s.len();
+	    self.0.get_mut(self.1..end).ok_or(core::fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.1 = end;
+	    Ok(())
+	}
+    }
+}
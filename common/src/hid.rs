@@ -0,0 +1,174 @@
+//! USB HID boot protocol keyboards.
+//!
+//! A keyboard in the boot protocol sends an 8 byte report on every change: a modifier
+//! bitmap, a reserved byte and up to six usage codes of the keys held down. `BootKeyboard`
+//! compares each report with the last one and turns the difference into scancode set 1
+//! bytes, so USB keys go through the same `keymap::Decoder` (and whatever reads from it)
+//! as the PS/2 keyboard.
+//! Reference: USB HID 1.11, appendix B.1, and the HID usage tables, chapter 10.
+
+pub const BOOT_REPORT_SZ: usize = 8;
+
+/// Reported in every key slot when more keys are held down than fit.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+const EXTENDED: u8 = 0xE0;
+const RELEASE: u8 = 0x80;
+
+/// Scancodes of the modifier bits, in bit order. Extended ones are flagged.
+const MODIFIERS: [(bool, u8); 8] = [
+    (false, 0x1D), // left ctrl
+    (false, 0x2A), // left shift
+    (false, 0x38), // left alt
+    (true, 0x5B),  // left GUI
+    (true, 0x1D),  // right ctrl
+    (false, 0x36), // right shift
+    (true, 0x38),  // right alt (AltGr)
+    (true, 0x5C),  // right GUI
+];
+
+/// Scancode set 1 make codes for usages 0x04 (a) to 0x39 (caps lock).
+const KEYS: [u8; 0x36] = [
+    0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32, // a-m
+    0x31, 0x18, 0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C, // n-z
+    0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, // 1-0
+    0x1C, 0x01, 0x0E, 0x0F, 0x39, // enter, escape, backspace, tab, space
+    0x0C, 0x0D, 0x1A, 0x1B, 0x2B, 0x2B, 0x27, 0x28, 0x29, 0x33, 0x34, 0x35, // - = [ ] \ # ; ' ` , . /
+    0x3A, // caps lock
+];
+
+/// The key left of Z on ISO keyboards.
+const NON_US_BACKSLASH: (u8, u8) = (0x64, 0x56);
+
+/// The scancode for a usage, None for keys without one yet.
+fn scancode(usage: u8) -> Option<u8> {
+    match usage {
+	0x04..=0x39 => Some(KEYS[(usage - 0x04) as usize]),
+	u if u == NON_US_BACKSLASH.0 => Some(NON_US_BACKSLASH.1),
+	_ => None,
+    }
+}
+
+pub struct BootKeyboard {
+    last: [u8; BOOT_REPORT_SZ],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+	Self { last: [0; BOOT_REPORT_SZ] }
+    }
+
+    /// Handle a report from the keyboard's interrupt endpoint, calling `emit` with each
+    /// scancode byte. Keys released are emitted before keys pressed, so a report that
+    /// lets go of shift and presses a letter types it unshifted.
+    pub fn report(&mut self, report: &[u8; BOOT_REPORT_SZ], mut emit: impl FnMut(u8)) {
+	// Too many keys down, the report says nothing about which.
+	if report[2..].iter().all(|k| *k == ERROR_ROLL_OVER) {
+	    return;
+	}
+	let last = core::mem::replace(&mut self.last, *report);
+	let held = |r: &[u8; BOOT_REPORT_SZ], usage: u8| r[2..].contains(&usage);
+
+	for &usage in last[2..].iter().filter(|u| !held(report, **u)) {
+	    if let Some(code) = scancode(usage) {
+		emit(code | RELEASE);
+	    }
+	}
+	for (bit, &(extended, code)) in MODIFIERS.iter().enumerate() {
+	    let (was, is) = (last[0] >> bit & 1 != 0, report[0] >> bit & 1 != 0);
+	    if was && !is {
+		if extended {
+		    emit(EXTENDED);
+		}
+		emit(code | RELEASE);
+	    }
+	}
+
+	for (bit, &(extended, code)) in MODIFIERS.iter().enumerate() {
+	    let (was, is) = (last[0] >> bit & 1 != 0, report[0] >> bit & 1 != 0);
+	    if is && !was {
+		if extended {
+		    emit(EXTENDED);
+		}
+		emit(code);
+	    }
+	}
+	for &usage in report[2..].iter().filter(|u| !held(&last, **u)) {
+	    if let Some(code) = scancode(usage) {
+		emit(code);
+	    }
+	}
+    }
+}
+
+impl Default for BootKeyboard {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::keymap::{Decoder, DE, US};
+
+    /// Feed reports through a keyboard into a decoder, collecting what was typed.
+    #[allow(dead_code)]
+    fn type_reports(d: &mut Decoder, reports: &[[u8; 8]]) -> ([char; 16], usize) {
+	let mut kbd = BootKeyboard::new();
+	let mut out = ['\0'; 16];
+	let mut n = 0;
+	for r in reports {
+	    kbd.report(r, |b| {
+		if let Some(c) = d.feed(b) {
+		    out[n] = c;
+		    n += 1;
+		}
+	    });
+	}
+	(out, n)
+    }
+
+    #[test]
+    fn make_and_break() {
+	let mut kbd = BootKeyboard::new();
+	let mut bytes = [0u8; 8];
+	let mut n = 0;
+	// Press a, then shift + a + b, then release everything.
+	for r in [[0, 0, 0x04, 0, 0, 0, 0, 0], [0x02, 0, 0x04, 0x05, 0, 0, 0, 0], [0; 8]] {
+	    kbd.report(&r, |b| {
+		bytes[n] = b;
+		n += 1;
+	    });
+	}
+	assert_eq!(&bytes[..n], &[0x1E, 0x2A, 0x30, 0x9E, 0xB0, 0xAA]);
+    }
+
+    #[test]
+    fn roll_over_is_ignored() {
+	let mut kbd = BootKeyboard::new();
+	kbd.report(&[0, 0, 0x04, 0, 0, 0, 0, 0], |_| {});
+	kbd.report(&[0, 0, 1, 1, 1, 1, 1, 1], |_| panic!("nothing should be emitted"));
+	// a is still considered held.
+	kbd.report(&[0, 0, 0x04, 0, 0, 0, 0, 0], |_| panic!("nothing should be emitted"));
+    }
+
+    #[test]
+    fn through_the_decoder() {
+	let mut d = Decoder::new(&US);
+	let (out, n) = type_reports(&mut d, &[
+	    [0x02, 0, 0x0B, 0, 0, 0, 0, 0],
+	    [0, 0, 0, 0, 0, 0, 0, 0],
+	    [0, 0, 0x0C, 0, 0, 0, 0, 0],
+	    [0, 0, 0x2C, 0, 0, 0, 0, 0],
+	]);
+	assert_eq!(&out[..n], &['H', 'i', ' ']);
+
+	// Right alt is AltGr.
+	let mut d = Decoder::new(&DE);
+	let (out, n) = type_reports(&mut d, &[[0x40, 0, 0x14, 0, 0, 0, 0, 0]]);
+	assert_eq!(&out[..n], &['@']);
+    }
+}
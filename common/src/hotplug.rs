@@ -0,0 +1,231 @@
+//! Device add and remove events.
+//!
+//! Drivers publish to a `Channel` when a device appears (a virtio device at boot or
+//! hotplugged, later USB) or goes away. Subscribers, devfs creating and removing nodes or
+//! a userspace process through a syscall, each keep their own `Subscription` and poll it.
+//! Events are kept in a ring of `MAX_EVENTS`: a subscriber that falls further behind is
+//! told how many it missed and can catch up from `devices()`, the table of what's present
+//! now. That table is also how a new subscriber learns about devices that were there
+//! before it.
+//!
+//! Nothing allocates, the kernel keeps one channel in a static behind its lock.
+
+/// Events kept for subscribers that haven't seen them yet.
+pub const MAX_EVENTS: usize = 64;
+/// Devices present at once.
+pub const MAX_DEVICES: usize = 64;
+pub const NAME_SZ: usize = 16;
+/// Size of an event as handed to userspace, see `Event::to_bytes`.
+pub const EVENT_SZ: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HotplugErr {
+    NameTooLong,
+    /// Already `MAX_DEVICES` present.
+    Full,
+    UnknownDevice,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Action {
+    Add = 1,
+    Remove = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Bus {
+    Virtio = 1,
+    Pci = 2,
+    Usb = 3,
+    /// Virtual block devices, e.g. from the device mapper.
+    Block = 4,
+}
+
+/// Stays the same for as long as the device is present, and isn't reused while any event
+/// about it could still be in the ring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceId(pub u32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Device {
+    pub id: DeviceId,
+    pub bus: Bus,
+    name: [u8; NAME_SZ],
+    name_len: u8,
+}
+
+impl Device {
+    /// The driver's name for it, e.g. `vda` or `virtio-net0`, which devfs uses for the node.
+    pub fn name(&self) -> &str {
+	core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    /// Counts up from 0 over the channel's lifetime.
+    pub seq: u64,
+    pub action: Action,
+    pub device: Device,
+}
+
+impl Event {
+    /// Fixed layout for userspace, little endian: seq (u64), action (u8), bus (u8), name
+    /// length (u8), a zero byte, device id (u32), name (16 bytes).
+    pub fn to_bytes(&self) -> [u8; EVENT_SZ] {
+	let mut b = [0; EVENT_SZ];
+	b[0..8].copy_from_slice(&self.seq.to_le_bytes());
+	b[8] = self.action as u8;
+	b[9] = self.device.bus as u8;
+	b[10] = self.device.name_len;
+	b[12..16].copy_from_slice(&self.device.id.0.to_le_bytes());
+	b[16..32].copy_from_slice(&self.device.name);
+	b
+    }
+}
+
+/// What a subscriber gets from `poll`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Poll {
+    Event(Event),
+    /// This many events were overwritten before the subscriber saw them. The next poll
+    /// carries on with the oldest one still kept.
+    Lost(u64),
+}
+
+/// A subscriber's place in the channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subscription {
+    next: u64,
+}
+
+pub struct Channel {
+    events: [Option<Event>; MAX_EVENTS],
+    devices: [Option<Device>; MAX_DEVICES],
+    /// Sequence number of the next event.
+    published: u64,
+    next_id: u32,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl Channel {
+    pub const fn new() -> Self {
+	Self {
+	    events: [None; MAX_EVENTS],
+	    devices: [None; MAX_DEVICES],
+	    published: 0,
+	    next_id: 1,
+	}
+    }
+
+    /// Record a new device and tell subscribers.
+    pub fn add(&mut self, bus: Bus, name: &str) -> Result<DeviceId, HotplugErr> {
+	if name.len() > NAME_SZ {
+	    return Err(HotplugErr::NameTooLong);
+	}
+	let slot = self.devices.iter_mut().find(|d| d.is_none()).ok_or(HotplugErr::Full)?;
+	let mut d = Device { id: DeviceId(self.next_id), bus, name: [0; NAME_SZ], name_len: name.len() as u8 };
+	d.name[..name.len()].copy_from_slice(name.as_bytes());
+	self.next_id = self.next_id.wrapping_add(1).max(1);
+	*slot = Some(d);
+	self.publish(Action::Add, d);
+	Ok(d.id)
+    }
+
+    /// Forget a device and tell subscribers.
+    pub fn remove(&mut self, id: DeviceId) -> Result<(), HotplugErr> {
+	let slot = self.devices.iter_mut().find(|d| d.is_some_and(|d| d.id == id)).ok_or(HotplugErr::UnknownDevice)?;
+	let d = slot.take().unwrap();
+	self.publish(Action::Remove, d);
+	Ok(())
+    }
+
+    fn publish(&mut self, action: Action, device: Device) {
+	let seq = self.published;
+	self.events[(seq % MAX_EVENTS as u64) as usize] = Some(Event { seq, action, device });
+	self.published += 1;
+    }
+
+    /// The devices present now.
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+	self.devices.iter().flatten()
+    }
+
+    /// A subscription to events from now on. Devices already present are in `devices()`.
+    pub fn subscribe(&self) -> Subscription {
+	Subscription { next: self.published }
+    }
+
+    /// The subscriber's next event, None if it has seen them all.
+    pub fn poll(&self, sub: &mut Subscription) -> Option<Poll> {
+	if sub.next >= self.published {
+	    return None;
+	}
+	let oldest = self.published.saturating_sub(MAX_EVENTS as u64);
+	if sub.next < oldest {
+	    let lost = oldest - sub.next;
+	    sub.next = oldest;
+	    return Some(Poll::Lost(lost));
+	}
+	let e = self.events[(sub.next % MAX_EVENTS as u64) as usize]?;
+	sub.next += 1;
+	Some(Poll::Event(e))
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn subscribe_and_poll() {
+	let mut c = Channel::new();
+	let vda = c.add(Bus::Virtio, "vda").unwrap();
+	let mut sub = c.subscribe();
+	assert_eq!(c.poll(&mut sub), None);
+	let vdb = c.add(Bus::Virtio, "vdb").unwrap();
+	c.remove(vda).unwrap();
+	assert_eq!(c.remove(vda), Err(HotplugErr::UnknownDevice));
+	assert_eq!(c.add(Bus::Usb, "a-name-that-is-too-long"), Err(HotplugErr::NameTooLong));
+
+	let Some(Poll::Event(e)) = c.poll(&mut sub) else { panic!("expected an event") };
+	assert_eq!((e.seq, e.action, e.device.id, e.device.name()), (1, Action::Add, vdb, "vdb"));
+	let Some(Poll::Event(e)) = c.poll(&mut sub) else { panic!("expected an event") };
+	assert_eq!((e.action, e.device.id, e.device.name()), (Action::Remove, vda, "vda"));
+	assert_eq!(c.poll(&mut sub), None);
+	let mut present = c.devices();
+	assert_eq!(present.next().map(|d| d.id), Some(vdb));
+	assert_eq!(present.next(), None);
+
+	let b = e.to_bytes();
+	assert_eq!((b[0], b[8], b[9], b[10], &b[16..19]), (2, Action::Remove as u8, Bus::Virtio as u8, 3, &b"vda"[..]));
+	assert_eq!(u32::from_le_bytes(b[12..16].try_into().unwrap()), vda.0);
+    }
+
+    #[test]
+    fn slow_subscriber() {
+	let mut c = Channel::new();
+	let mut sub = c.subscribe();
+	for _ in 0..MAX_EVENTS + 10 {
+	    let id = c.add(Bus::Block, "dm").unwrap();
+	    c.remove(id).unwrap();
+	}
+	assert_eq!(c.poll(&mut sub), Some(Poll::Lost(MAX_EVENTS as u64 + 20)));
+	let Some(Poll::Event(e)) = c.poll(&mut sub) else { panic!("expected an event") };
+	assert_eq!(e.seq, MAX_EVENTS as u64 + 20);
+	let mut seen = 1;
+	while c.poll(&mut sub).is_some() {
+	    seen += 1;
+	}
+	assert_eq!(seen, MAX_EVENTS);
+	assert_eq!(c.devices().count(), 0);
+    }
+}
@@ -0,0 +1,161 @@
+//! Interrupt routing: which CPU a device interrupt goes to.
+//!
+//! Legacy and PCI INTx interrupts are routed by IO-APIC redirection table entries, MSI and
+//! MSI-X interrupts by the address and data the device writes. Both name the destination
+//! by local APIC id, physical destination mode, fixed delivery. `Balancer` hands out
+//! destinations for device vectors round robin so they don't all land on the BSP, and
+//! `IrqCounts` keeps per-CPU counts for the shell to show.
+//! Refs: Intel 82093AA IO-APIC datasheet, 3.2.4; Intel SDM Vol. 3A, 11.11 "Message
+//! Signalled Interrupts"
+
+/// First vector for device interrupts, below are CPU exceptions.
+pub const FIRST_DEVICE_VECTOR: u8 = 32;
+
+const IOAPIC_MASKED: u64 = 1 << 16;
+const IOAPIC_LEVEL: u64 = 1 << 15;
+const IOAPIC_ACTIVE_LOW: u64 = 1 << 13;
+
+/// How a line signals an interrupt. ISA interrupts are edge triggered active high, PCI
+/// ones level triggered active low.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// An IO-APIC redirection table entry, as written to the two 32 bit registers at
+/// `0x10 + 2 * pin` (low half) and the one after it (high half).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Redirection {
+    pub vector: u8,
+    pub dest_apic_id: u8,
+    pub trigger: Trigger,
+    pub active_low: bool,
+    pub masked: bool,
+}
+
+impl Redirection {
+    pub fn to_raw(&self) -> u64 {
+	let mut raw = self.vector as u64 | (self.dest_apic_id as u64) << 56;
+	if self.trigger == Trigger::Level {
+	    raw |= IOAPIC_LEVEL;
+	}
+	if self.active_low {
+	    raw |= IOAPIC_ACTIVE_LOW;
+	}
+	if self.masked {
+	    raw |= IOAPIC_MASKED;
+	}
+	raw
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+	Self {
+	    vector: raw as u8,
+	    dest_apic_id: (raw >> 56) as u8,
+	    trigger: if raw & IOAPIC_LEVEL != 0 { Trigger::Level } else { Trigger::Edge },
+	    active_low: raw & IOAPIC_ACTIVE_LOW != 0,
+	    masked: raw & IOAPIC_MASKED != 0,
+	}
+    }
+
+    /// The register index of the low half for a pin.
+    pub fn register(pin: u8) -> u8 {
+	0x10 + 2 * pin
+    }
+}
+
+/// The address and data an MSI capability (or MSI-X table entry) is programmed with to
+/// deliver `vector` to a CPU, edge triggered.
+pub fn msi_message(vector: u8, dest_apic_id: u8) -> (u64, u32) {
+    (0xFEE0_0000 | (dest_apic_id as u64) << 12, vector as u32)
+}
+
+/// Round robin assignment of device interrupts to CPUs.
+pub struct Balancer<'a> {
+    apic_ids: &'a [u8],
+    next: usize,
+}
+
+impl<'a> Balancer<'a> {
+    /// `apic_ids` are the local APIC ids of the CPUs taking interrupts, the BSP first.
+    pub fn new(apic_ids: &'a [u8]) -> Self {
+	Self { apic_ids, next: 0 }
+    }
+
+    /// The CPU to send the next device's interrupts to.
+    pub fn next_cpu(&mut self) -> u8 {
+	let id = self.apic_ids[self.next % self.apic_ids.len()];
+	self.next += 1;
+	id
+    }
+}
+
+/// Interrupts taken per vector on each of up to `CPUS` CPUs.
+pub struct IrqCounts<const CPUS: usize> {
+    counts: [[u64; 256]; CPUS],
+}
+
+impl<const CPUS: usize> IrqCounts<CPUS> {
+    pub const fn new() -> Self {
+	Self { counts: [[0; 256]; CPUS] }
+    }
+
+    /// Count an interrupt, from the handler on `cpu`.
+    pub fn record(&mut self, cpu: usize, vector: u8) {
+	self.counts[cpu][vector as usize] += 1;
+    }
+
+    pub fn get(&self, cpu: usize, vector: u8) -> u64 {
+	self.counts[cpu][vector as usize]
+    }
+
+    /// Vectors any CPU has taken an interrupt on, with the per-CPU counts, for a
+    /// `/proc/interrupts` style listing.
+    pub fn active(&self) -> impl Iterator<Item = (u8, [u64; CPUS])> + '_ {
+	(0..=255u8).filter_map(|v| {
+	    let row: [u64; CPUS] = core::array::from_fn(|cpu| self.counts[cpu][v as usize]);
+	    row.iter().any(|c| *c != 0).then_some((v, row))
+	})
+    }
+}
+
+impl<const CPUS: usize> Default for IrqCounts<CPUS> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn redirection_entries() {
+	let r = Redirection { vector: 0x21, dest_apic_id: 3, trigger: Trigger::Level, active_low: true, masked: false };
+	assert_eq!(r.to_raw(), 0x0300_0000_0000_A021);
+	assert_eq!(Redirection::from_raw(r.to_raw()), r);
+	assert_eq!(Redirection::register(2), 0x14);
+	assert_eq!(msi_message(0x40, 2), (0xFEE0_2000, 0x40));
+    }
+
+    #[test]
+    fn round_robin() {
+	let mut b = Balancer::new(&[0, 2, 4]);
+	let ids: [u8; 4] = core::array::from_fn(|_| b.next_cpu());
+	assert_eq!(ids, [0, 2, 4, 0]);
+    }
+
+    #[test]
+    fn counts() {
+	let mut counts = IrqCounts::<2>::new();
+	counts.record(0, 33);
+	counts.record(1, 33);
+	counts.record(1, 40);
+	let mut active = counts.active();
+	assert_eq!(active.next(), Some((33, [1, 1])));
+	assert_eq!(active.next(), Some((40, [0, 1])));
+	assert_eq!(active.next(), None);
+    }
+}
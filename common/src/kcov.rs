@@ -0,0 +1,203 @@
+//! Code coverage for coverage guided fuzzing, after Linux's KCOV.
+//!
+//! With the kernel's `kcov` feature the compiler calls `__sanitizer_cov_trace_pc` at every
+//! basic block (`-C passes=sancov-module -C llvm-args=-sanitizer-coverage-level=3
+//! -C llvm-args=-sanitizer-coverage-trace-pc`). The callback looks up the current task's
+//! `Coverage` and records its return address. A fuzzer running in the guest sets up and
+//! enables coverage for its own task, makes the syscalls of one input, then reads back
+//! the PCs they reached and hands them to the host fuzzer.
+//!
+//! There's no shadow memory or global bitmap: each task records into its own buffer of
+//! words, `words[0]` counting the PCs after it, exactly as KCOV lays its area out. A full
+//! buffer stops recording and remembers it overflowed rather than wrapping, so an input's
+//! coverage is never silently mixed with a later one's.
+//!
+//! Interrupt handlers run on whichever task they interrupt, so PCs recorded from
+//! interrupt context are dropped, they'd be noise unrelated to the input.
+
+/// Fewest words a buffer can have, the count and one PC.
+pub const MIN_WORDS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KcovErr {
+    /// `init` with fewer than `MIN_WORDS`.
+    TooSmall,
+    /// `init` on a task that already has a buffer, or `enable` while enabled.
+    Busy,
+    /// `enable` before `init`.
+    NoBuffer,
+    /// Not a `KcovOp`.
+    BadOp(u64),
+}
+
+/// What the coverage syscall was asked to do, `from_raw(op, arg)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KcovOp {
+    /// Give the calling task a buffer of `arg` words.
+    Init { words: usize },
+    /// Start recording from an empty buffer.
+    Enable,
+    Disable,
+    /// Copy the recorded PCs to the user buffer, return how many and start over.
+    Read,
+}
+
+impl KcovOp {
+    pub fn from_raw(op: u64, arg: u64) -> Result<Self, KcovErr> {
+	match op {
+	    0 => Ok(KcovOp::Init { words: arg as usize }),
+	    1 => Ok(KcovOp::Enable),
+	    2 => Ok(KcovOp::Disable),
+	    3 => Ok(KcovOp::Read),
+	    op => Err(KcovErr::BadOp(op)),
+	}
+    }
+}
+
+/// A task's coverage state, over the buffer the kernel allocated for it at `init`.
+pub struct Coverage<'a> {
+    words: Option<&'a mut [u64]>,
+    enabled: bool,
+    overflowed: bool,
+}
+
+impl Default for Coverage<'_> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl<'a> Coverage<'a> {
+    pub const fn new() -> Self {
+	Self { words: None, enabled: false, overflowed: false }
+    }
+
+    pub fn init(&mut self, words: &'a mut [u64]) -> Result<(), KcovErr> {
+	if self.words.is_some() {
+	    return Err(KcovErr::Busy);
+	}
+	if words.len() < MIN_WORDS {
+	    return Err(KcovErr::TooSmall);
+	}
+	words[0] = 0;
+	self.words = Some(words);
+	Ok(())
+    }
+
+    pub fn enable(&mut self) -> Result<(), KcovErr> {
+	if self.enabled {
+	    return Err(KcovErr::Busy);
+	}
+	self.reset()?;
+	self.enabled = true;
+	Ok(())
+    }
+
+    pub fn disable(&mut self) {
+	self.enabled = false;
+    }
+
+    pub fn enabled(&self) -> bool {
+	self.enabled
+    }
+
+    fn reset(&mut self) -> Result<(), KcovErr> {
+	let words = self.words.as_deref_mut().ok_or(KcovErr::NoBuffer)?;
+	words[0] = 0;
+	self.overflowed = false;
+	Ok(())
+    }
+
+    /// From the trace-pc callback. Kept short, it runs at every basic block.
+    #[inline]
+    pub fn record(&mut self, pc: u64, in_interrupt: bool) {
+	if !self.enabled || in_interrupt {
+	    return;
+	}
+	let Some(words) = self.words.as_deref_mut() else {
+	    return;
+	};
+	let n = words[0] as usize;
+	match words.get_mut(n + 1) {
+	    Some(w) => {
+		*w = pc;
+		words[0] += 1;
+	    },
+	    None => self.overflowed = true,
+	}
+    }
+
+    /// The PCs recorded since the last enable or read, in the order they were reached.
+    pub fn pcs(&self) -> &[u64] {
+	match self.words.as_deref() {
+	    Some(words) => &words[1..1 + words[0] as usize],
+	    None => &[],
+	}
+    }
+
+    /// Some PCs didn't fit since the last enable or read.
+    pub fn overflowed(&self) -> bool {
+	self.overflowed
+    }
+
+    /// `KcovOp::Read`: copy as many PCs as fit into `out` and start over, returning how
+    /// many were copied.
+    pub fn read(&mut self, out: &mut [u64]) -> Result<usize, KcovErr> {
+	let pcs = self.pcs();
+	let n = pcs.len().min(out.len());
+	out[..n].copy_from_slice(&pcs[..n]);
+	self.reset()?;
+	Ok(n)
+    }
+
+    /// Hand the buffer back when the task exits, so the kernel can free it.
+    pub fn release(&mut self) -> Option<&'a mut [u64]> {
+	self.enabled = false;
+	self.words.take()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn records_while_enabled() {
+	let (mut buf, mut small) = ([0xff; 4], [0; 1]);
+	let mut c = Coverage::new();
+	assert_eq!(c.enable(), Err(KcovErr::NoBuffer));
+	assert_eq!(c.init(&mut small), Err(KcovErr::TooSmall));
+	c.init(&mut buf).unwrap();
+	c.record(0x10, false);
+	assert!(c.pcs().is_empty());
+
+	c.enable().unwrap();
+	assert_eq!(c.enable(), Err(KcovErr::Busy));
+	c.record(0x10, false);
+	c.record(0x20, true);
+	c.record(0x30, false);
+	assert_eq!(c.pcs(), &[0x10, 0x30]);
+	assert!(!c.overflowed());
+	c.record(0x40, false);
+	c.record(0x50, false);
+	assert_eq!(c.pcs(), &[0x10, 0x30, 0x40]);
+	assert!(c.overflowed());
+
+	let mut out = [0; 2];
+	assert_eq!(c.read(&mut out), Ok(2));
+	assert_eq!(out, [0x10, 0x30]);
+	assert!(c.pcs().is_empty() && !c.overflowed());
+	c.disable();
+	c.record(0x60, false);
+	assert!(c.pcs().is_empty());
+	assert_eq!(c.release().map(|w| w[0]), Some(0));
+    }
+
+    #[test]
+    fn ops() {
+	assert_eq!(KcovOp::from_raw(0, 4096), Ok(KcovOp::Init { words: 4096 }));
+	assert_eq!(KcovOp::from_raw(3, 0), Ok(KcovOp::Read));
+	assert_eq!(KcovOp::from_raw(9, 0), Err(KcovErr::BadOp(9)));
+    }
+}
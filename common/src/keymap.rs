@@ -0,0 +1,283 @@
+//! Keyboard layouts for the PS/2 keyboard.
+//!
+//! Layouts are data only: a table of what each key (by scancode set 1 make code) types
+//! plain, with shift and with AltGr, the keys among those that are dead keys, and what a
+//! dead key combines with. `Decoder` turns scancodes into characters using a layout and
+//! keeps track of modifiers and pending dead keys. Adding a layout is adding a table.
+//!
+//! The layout is picked with `keymap=<name>` on the kernel command line, US by default.
+
+/// What one key types: plain, with shift, with AltGr. '\0' where it types nothing.
+pub type KeyDef = (u8, char, char, char);
+
+pub struct Layout {
+    pub name: &'static str,
+    pub keys: &'static [KeyDef],
+    /// Characters in `keys` that are dead keys, they combine with the next key typed.
+    pub dead: &'static [char],
+    /// (dead key, base, result).
+    pub compose: &'static [(char, char, char)],
+}
+
+pub static US: Layout = Layout {
+    name: "us",
+    keys: &[
+	(0x02, '1', '!', '\0'), (0x03, '2', '@', '\0'), (0x04, '3', '#', '\0'), (0x05, '4', '$', '\0'),
+	(0x06, '5', '%', '\0'), (0x07, '6', '^', '\0'), (0x08, '7', '&', '\0'), (0x09, '8', '*', '\0'),
+	(0x0A, '9', '(', '\0'), (0x0B, '0', ')', '\0'), (0x0C, '-', '_', '\0'), (0x0D, '=', '+', '\0'),
+	(0x10, 'q', 'Q', '\0'), (0x11, 'w', 'W', '\0'), (0x12, 'e', 'E', '\0'), (0x13, 'r', 'R', '\0'),
+	(0x14, 't', 'T', '\0'), (0x15, 'y', 'Y', '\0'), (0x16, 'u', 'U', '\0'), (0x17, 'i', 'I', '\0'),
+	(0x18, 'o', 'O', '\0'), (0x19, 'p', 'P', '\0'), (0x1A, '[', '{', '\0'), (0x1B, ']', '}', '\0'),
+	(0x1E, 'a', 'A', '\0'), (0x1F, 's', 'S', '\0'), (0x20, 'd', 'D', '\0'), (0x21, 'f', 'F', '\0'),
+	(0x22, 'g', 'G', '\0'), (0x23, 'h', 'H', '\0'), (0x24, 'j', 'J', '\0'), (0x25, 'k', 'K', '\0'),
+	(0x26, 'l', 'L', '\0'), (0x27, ';', ':', '\0'), (0x28, '\'', '"', '\0'), (0x29, '`', '~', '\0'),
+	(0x2B, '\\', '|', '\0'), (0x2C, 'z', 'Z', '\0'), (0x2D, 'x', 'X', '\0'), (0x2E, 'c', 'C', '\0'),
+	(0x2F, 'v', 'V', '\0'), (0x30, 'b', 'B', '\0'), (0x31, 'n', 'N', '\0'), (0x32, 'm', 'M', '\0'),
+	(0x33, ',', '<', '\0'), (0x34, '.', '>', '\0'), (0x35, '/', '?', '\0'),
+    ],
+    dead: &[],
+    compose: &[],
+};
+
+pub static UK: Layout = Layout {
+    name: "uk",
+    keys: &[
+	(0x02, '1', '!', '\0'), (0x03, '2', '"', '\0'), (0x04, '3', '£', '\0'), (0x05, '4', '$', '€'),
+	(0x06, '5', '%', '\0'), (0x07, '6', '^', '\0'), (0x08, '7', '&', '\0'), (0x09, '8', '*', '\0'),
+	(0x0A, '9', '(', '\0'), (0x0B, '0', ')', '\0'), (0x0C, '-', '_', '\0'), (0x0D, '=', '+', '\0'),
+	(0x10, 'q', 'Q', '\0'), (0x11, 'w', 'W', '\0'), (0x12, 'e', 'E', 'é'), (0x13, 'r', 'R', '\0'),
+	(0x14, 't', 'T', '\0'), (0x15, 'y', 'Y', '\0'), (0x16, 'u', 'U', 'ú'), (0x17, 'i', 'I', 'í'),
+	(0x18, 'o', 'O', 'ó'), (0x19, 'p', 'P', '\0'), (0x1A, '[', '{', '\0'), (0x1B, ']', '}', '\0'),
+	(0x1E, 'a', 'A', 'á'), (0x1F, 's', 'S', '\0'), (0x20, 'd', 'D', '\0'), (0x21, 'f', 'F', '\0'),
+	(0x22, 'g', 'G', '\0'), (0x23, 'h', 'H', '\0'), (0x24, 'j', 'J', '\0'), (0x25, 'k', 'K', '\0'),
+	(0x26, 'l', 'L', '\0'), (0x27, ';', ':', '\0'), (0x28, '\'', '@', '\0'), (0x29, '`', '¬', '¦'),
+	(0x2B, '#', '~', '\0'), (0x2C, 'z', 'Z', '\0'), (0x2D, 'x', 'X', '\0'), (0x2E, 'c', 'C', '\0'),
+	(0x2F, 'v', 'V', '\0'), (0x30, 'b', 'B', '\0'), (0x31, 'n', 'N', '\0'), (0x32, 'm', 'M', '\0'),
+	(0x33, ',', '<', '\0'), (0x34, '.', '>', '\0'), (0x35, '/', '?', '\0'), (0x56, '\\', '|', '\0'),
+    ],
+    dead: &[],
+    compose: &[],
+};
+
+pub static DE: Layout = Layout {
+    name: "de",
+    keys: &[
+	(0x02, '1', '!', '\0'), (0x03, '2', '"', '²'), (0x04, '3', '§', '³'), (0x05, '4', '$', '\0'),
+	(0x06, '5', '%', '\0'), (0x07, '6', '&', '\0'), (0x08, '7', '/', '{'), (0x09, '8', '(', '['),
+	(0x0A, '9', ')', ']'), (0x0B, '0', '=', '}'), (0x0C, 'ß', '?', '\\'), (0x0D, '´', '`', '\0'),
+	(0x10, 'q', 'Q', '@'), (0x11, 'w', 'W', '\0'), (0x12, 'e', 'E', '€'), (0x13, 'r', 'R', '\0'),
+	(0x14, 't', 'T', '\0'), (0x15, 'z', 'Z', '\0'), (0x16, 'u', 'U', '\0'), (0x17, 'i', 'I', '\0'),
+	(0x18, 'o', 'O', '\0'), (0x19, 'p', 'P', '\0'), (0x1A, 'ü', 'Ü', '\0'), (0x1B, '+', '*', '~'),
+	(0x1E, 'a', 'A', '\0'), (0x1F, 's', 'S', '\0'), (0x20, 'd', 'D', '\0'), (0x21, 'f', 'F', '\0'),
+	(0x22, 'g', 'G', '\0'), (0x23, 'h', 'H', '\0'), (0x24, 'j', 'J', '\0'), (0x25, 'k', 'K', '\0'),
+	(0x26, 'l', 'L', '\0'), (0x27, 'ö', 'Ö', '\0'), (0x28, 'ä', 'Ä', '\0'), (0x29, '^', '°', '\0'),
+	(0x2B, '#', '\'', '\0'), (0x2C, 'y', 'Y', '\0'), (0x2D, 'x', 'X', '\0'), (0x2E, 'c', 'C', '\0'),
+	(0x2F, 'v', 'V', '\0'), (0x30, 'b', 'B', '\0'), (0x31, 'n', 'N', '\0'), (0x32, 'm', 'M', 'µ'),
+	(0x33, ',', ';', '\0'), (0x34, '.', ':', '\0'), (0x35, '-', '_', '\0'), (0x56, '<', '>', '|'),
+    ],
+    dead: &['^', '´', '`'],
+    compose: &[
+	('^', 'a', 'â'), ('^', 'e', 'ê'), ('^', 'i', 'î'), ('^', 'o', 'ô'), ('^', 'u', 'û'),
+	('^', 'A', 'Â'), ('^', 'E', 'Ê'), ('^', 'I', 'Î'), ('^', 'O', 'Ô'), ('^', 'U', 'Û'),
+	('´', 'a', 'á'), ('´', 'e', 'é'), ('´', 'i', 'í'), ('´', 'o', 'ó'), ('´', 'u', 'ú'),
+	('´', 'A', 'Á'), ('´', 'E', 'É'), ('´', 'I', 'Í'), ('´', 'O', 'Ó'), ('´', 'U', 'Ú'),
+	('`', 'a', 'à'), ('`', 'e', 'è'), ('`', 'i', 'ì'), ('`', 'o', 'ò'), ('`', 'u', 'ù'),
+	('`', 'A', 'À'), ('`', 'E', 'È'), ('`', 'I', 'Ì'), ('`', 'O', 'Ò'), ('`', 'U', 'Ù'),
+    ],
+};
+
+pub static LAYOUTS: [&Layout; 3] = [&US, &UK, &DE];
+
+impl Layout {
+    pub fn by_name(name: &str) -> Option<&'static Layout> {
+	LAYOUTS.iter().copied().find(|l| l.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The layout named by `keymap=` on the command line, US if there isn't one or it's
+    /// unknown.
+    pub fn from_cmdline(cmdline: &str) -> &'static Layout {
+	cmdline.split_ascii_whitespace()
+	    .find_map(|arg| arg.strip_prefix("keymap="))
+	    .and_then(Self::by_name)
+	    .unwrap_or(&US)
+    }
+
+    fn key(&self, code: u8) -> Option<&KeyDef> {
+	self.keys.iter().find(|k| k.0 == code)
+    }
+}
+
+const EXTENDED: u8 = 0xE0;
+const RELEASE: u8 = 0x80;
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+/// Right Alt is AltGr, it's sent as EXTENDED + ALT.
+const ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3A;
+const BACKSPACE: u8 = 0x0E;
+const TAB: u8 = 0x0F;
+const ENTER: u8 = 0x1C;
+const SPACE: u8 = 0x39;
+
+/// Turns scancode set 1 bytes into characters.
+pub struct Decoder {
+    layout: &'static Layout,
+    extended: bool,
+    shift: u8,
+    altgr: bool,
+    caps_lock: bool,
+    dead: Option<char>,
+    /// A character held back when a dead key didn't combine, returned by the next call.
+    pending: Option<char>,
+}
+
+impl Decoder {
+    pub fn new(layout: &'static Layout) -> Self {
+	Self {
+	    layout,
+	    extended: false,
+	    shift: 0,
+	    altgr: false,
+	    caps_lock: false,
+	    dead: None,
+	    pending: None,
+	}
+    }
+
+    pub fn layout(&self) -> &'static Layout {
+	self.layout
+    }
+
+    /// A character held back by `feed`, call until None after each scancode byte.
+    pub fn pending(&mut self) -> Option<char> {
+	self.pending.take()
+    }
+
+    /// Handle one byte from the keyboard, returns the character typed if any.
+    pub fn feed(&mut self, byte: u8) -> Option<char> {
+	if byte == EXTENDED {
+	    self.extended = true;
+	    return None;
+	}
+	let extended = core::mem::take(&mut self.extended);
+	let released = byte & RELEASE != 0;
+	let code = byte & !RELEASE;
+
+	match (code, extended) {
+	    (LEFT_SHIFT, false) => self.shift = set_bit(self.shift, 0, !released),
+	    (RIGHT_SHIFT, false) => self.shift = set_bit(self.shift, 1, !released),
+	    (ALT, true) => self.altgr = !released,
+	    (CAPS_LOCK, false) if !released => self.caps_lock = !self.caps_lock,
+	    _ if released || extended => {},
+	    (BACKSPACE, _) => return Some('\x08'),
+	    (TAB, _) => return self.typed('\t'),
+	    (ENTER, _) => return self.typed('\n'),
+	    (SPACE, _) => return self.typed(' '),
+	    _ => {
+		let &(_, plain, shifted, altgr) = self.layout.key(code)?;
+		let c = if self.altgr {
+		    altgr
+		} else if (self.shift != 0) != (self.caps_lock && plain.is_alphabetic()) {
+		    shifted
+		} else {
+		    plain
+		};
+		if c == '\0' {
+		    return None;
+		}
+		if self.layout.dead.contains(&c) && self.dead.is_none() {
+		    self.dead = Some(c);
+		    return None;
+		}
+		return self.typed(c);
+	    },
+	}
+	None
+    }
+
+    /// Combine `c` with a pending dead key.
+    fn typed(&mut self, c: char) -> Option<char> {
+	let Some(dead) = self.dead.take() else {
+	    return Some(c);
+	};
+	// Dead key then space types the dead key itself.
+	if c == ' ' {
+	    return Some(dead);
+	}
+	if let Some(&(_, _, composed)) = self.layout.compose.iter().find(|(d, b, _)| *d == dead && *b == c) {
+	    return Some(composed);
+	}
+	self.pending = Some(c);
+	Some(dead)
+    }
+}
+
+fn set_bit(v: u8, bit: u8, on: bool) -> u8 {
+    if on { v | (1 << bit) } else { v & !(1 << bit) }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Press and release each key, collecting what was typed.
+    #[allow(dead_code)]
+    fn type_keys(d: &mut Decoder, bytes: &[u8], out: &mut [char]) -> usize {
+	let mut n = 0;
+	for &b in bytes {
+	    let mut c = d.feed(b);
+	    while let Some(ch) = c {
+		out[n] = ch;
+		n += 1;
+		c = d.pending();
+	    }
+	}
+	n
+    }
+
+    #[allow(dead_code)]
+    fn typed(layout: &'static Layout, bytes: &[u8]) -> ([char; 16], usize) {
+	let mut d = Decoder::new(layout);
+	let mut out = ['\0'; 16];
+	let n = type_keys(&mut d, bytes, &mut out);
+	(out, n)
+    }
+
+    #[test]
+    fn us_shift_and_caps() {
+	// h, shift+i, release shift, caps lock, a, 1
+	let (out, n) = typed(&US, &[0x23, 0xA3, 0x2A, 0x17, 0x97, 0xAA, 0x3A, 0xBA, 0x1E, 0x02]);
+	assert_eq!(&out[..n], &['h', 'I', 'A', '1']);
+    }
+
+    #[test]
+    fn layouts_differ() {
+	// The y key, shift+2.
+	let keys = [0x15, 0x2A, 0x03, 0xAA];
+	assert_eq!(&typed(&US, &keys).0[..2], &['y', '@']);
+	assert_eq!(&typed(&UK, &keys).0[..2], &['y', '"']);
+	assert_eq!(&typed(&DE, &keys).0[..2], &['z', '"']);
+    }
+
+    #[test]
+    fn altgr() {
+	// AltGr+q and AltGr+e on DE, released AltGr then q.
+	let (out, n) = typed(&DE, &[0xE0, 0x38, 0x10, 0x12, 0xE0, 0xB8, 0x10]);
+	assert_eq!(&out[..n], &['@', '€', 'q']);
+    }
+
+    #[test]
+    fn dead_keys() {
+	// ^ e, ^ space, ^ x (doesn't combine), shift+´ (`) a
+	let (out, n) = typed(&DE, &[0x29, 0x12, 0x29, 0x39, 0x29, 0x2D, 0x2A, 0x0D, 0xAA, 0x1E]);
+	assert_eq!(&out[..n], &['ê', '^', '^', 'x', 'à']);
+    }
+
+    #[test]
+    fn cmdline() {
+	assert_eq!(Layout::from_cmdline("quiet keymap=DE").name, "de");
+	assert_eq!(Layout::from_cmdline("keymap=dvorak").name, "us");
+	assert_eq!(Layout::from_cmdline("").name, "us");
+    }
+}
@@ -1,5 +1,43 @@
 #![no_std]
 
+pub mod audio;
+pub mod boot;
+pub mod build_id;
+pub mod crypt;
+pub mod diskstats;
+pub mod dm;
 pub mod elf;
+pub mod error;
+pub mod exec;
+pub mod fpu;
+pub mod gpt;
+pub mod guid;
+pub mod hid;
+pub mod hotplug;
+pub mod irq;
+pub mod kcov;
+pub mod keymap;
+pub mod limine;
+pub mod logbuf;
+pub mod lsblk;
+pub mod logfile;
 pub mod memory;
+pub mod mouse;
+pub mod multiboot2;
+pub mod preempt;
+pub mod profile;
+pub mod proto;
+pub mod provision;
+pub mod sched;
+pub mod serial_mux;
+pub mod settings;
+pub mod shutdown;
+pub mod snapshot;
+pub mod squashfs;
+pub mod stats;
+pub mod thread;
+pub mod time;
+pub mod uaccess;
+pub mod verity;
+pub mod vt;
 
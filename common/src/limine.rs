@@ -0,0 +1,260 @@
+//! The Limine boot protocol, so the kernel can be tested under an established bootloader.
+//!
+//! Instead of a header, the kernel image holds request structures that Limine finds by
+//! their ids and answers by filling in a pointer to a response before jumping to the
+//! kernel (already in long mode, with the higher half direct map set up). The responses
+//! are translated into a regular BootInfo, same as for Multiboot2.
+//! Reference: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md
+
+use core::cell::UnsafeCell;
+use core::ffi::{c_char, CStr};
+use crate::boot::{mem_kind, pixel_format, tag, BootInfoErr, BootInfoWriter, Framebuffer, MemRegion, MemoryMapEntry};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+pub const HHDM_ID: [u64; 4] = [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x48dcf1cb8ad2b852, 0x63984e959a98244b];
+pub const MEMMAP_ID: [u64; 4] = [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x67cf3d9d378a806f, 0xe304acdfc50c3c62];
+pub const MODULE_ID: [u64; 4] = [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x3e7e279702be32af, 0xca1c4f3bd1280cee];
+pub const FRAMEBUFFER_ID: [u64; 4] = [COMMON_MAGIC[0], COMMON_MAGIC[1], 0x9d5827dcd881dd75, 0xa3148604f6fab11b];
+
+/// The base revision tag, Limine zeroes the last element if it supports the revision.
+pub const BASE_REVISION: [u64; 3] = [0xf9562b2d5c95a6c8, 0x6a7b384944536bdc, 2];
+
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+
+const FRAMEBUFFER_RGB: u8 = 1;
+
+/// A request placed in the kernel image, with `R` the type of its response.
+#[repr(C)]
+pub struct Request<R> {
+    id: [u64; 4],
+    revision: u64,
+    /// Filled in by Limine before the kernel runs, so the compiler can't assume it's null.
+    response: UnsafeCell<*const R>,
+}
+
+// Only written by the bootloader, before the kernel starts.
+unsafe impl<R> Sync for Request<R> {}
+
+impl<R> Request<R> {
+    pub const fn new(id: [u64; 4]) -> Self {
+	Self {
+	    id,
+	    revision: 0,
+	    response: UnsafeCell::new(core::ptr::null()),
+	}
+    }
+
+    /// The bootloader's response, None if it didn't answer the request.
+    pub fn response(&self) -> Option<&R> {
+	unsafe { self.response.get().read_volatile().as_ref() }
+    }
+
+    #[allow(dead_code)]
+    fn respond(&self, response: &R) {
+	unsafe { *self.response.get() = response };
+    }
+}
+
+#[repr(C)]
+pub struct HhdmResponse {
+    pub revision: u64,
+    /// Virtual address of physical address 0.
+    pub offset: u64,
+}
+
+#[repr(C)]
+pub struct MemmapEntry {
+    pub base: u64,
+    pub length: u64,
+    pub ty: u64,
+}
+
+#[repr(C)]
+pub struct MemmapResponse {
+    pub revision: u64,
+    pub entry_count: u64,
+    pub entries: *const *const MemmapEntry,
+}
+
+#[repr(C)]
+pub struct File {
+    pub revision: u64,
+    pub address: *const u8,
+    pub size: u64,
+    pub path: *const c_char,
+    pub cmdline: *const c_char,
+    // Media type, TFTP and partition details follow, we don't need them.
+}
+
+#[repr(C)]
+pub struct ModuleResponse {
+    pub revision: u64,
+    pub module_count: u64,
+    pub modules: *const *const File,
+}
+
+#[repr(C)]
+pub struct LimineFramebuffer {
+    pub address: *const u8,
+    pub width: u64,
+    pub height: u64,
+    pub pitch: u64,
+    pub bpp: u16,
+    pub memory_model: u8,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8,
+    // EDID and video modes follow.
+}
+
+#[repr(C)]
+pub struct FramebufferResponse {
+    pub revision: u64,
+    pub framebuffer_count: u64,
+    pub framebuffers: *const *const LimineFramebuffer,
+}
+
+/// The requests the kernel makes, see `normalize`.
+pub struct Requests<'a> {
+    pub hhdm: &'a Request<HhdmResponse>,
+    pub memmap: &'a Request<MemmapResponse>,
+    pub modules: &'a Request<ModuleResponse>,
+    pub framebuffer: &'a Request<FramebufferResponse>,
+}
+
+/// Translate Limine's responses into BootInfo tags. Unanswered requests are skipped.
+/// Module and framebuffer addresses are turned back into physical ones.
+///
+/// # Safety
+/// The responses must be the ones Limine filled in (or laid out the same), with every
+/// pointer in them valid.
+pub unsafe fn normalize(requests: &Requests, out: &mut BootInfoWriter) -> Result<(), BootInfoErr> {
+    let hhdm = requests.hhdm.response().map_or(0, |r| r.offset);
+
+    if let Some(memmap) = requests.memmap.response() {
+	let entries = array(memmap.entries, memmap.entry_count).iter().map(|e| {
+	    let e = &**e;
+	    MemoryMapEntry {
+		region: MemRegion { addr: e.base, len: e.length },
+		kind: memory_kind(e.ty),
+	    }.to_bytes()
+	});
+	out.push(tag::MEMORY_MAP, entries)?;
+    }
+
+    if let Some(modules) = requests.modules.response() {
+	for m in array(modules.modules, modules.module_count) {
+	    let m = &**m;
+	    let region = MemRegion {
+		addr: (m.address as u64).wrapping_sub(hhdm),
+		len: m.size,
+	    };
+	    let name = if m.path.is_null() { &[] } else { CStr::from_ptr(m.path).to_bytes() };
+	    let name = core::str::from_utf8(name).unwrap_or("");
+	    out.push(tag::MODULE, [&region.to_bytes()[..], name.as_bytes()])?;
+	}
+    }
+
+    if let Some(fbs) = requests.framebuffer.response() {
+	// Only the first framebuffer, BootInfo has no notion of multiple displays yet.
+	if let Some(fb) = array(fbs.framebuffers, fbs.framebuffer_count).first() {
+	    let fb = &**fb;
+	    if fb.memory_model == FRAMEBUFFER_RGB {
+		let fb = Framebuffer {
+		    addr: (fb.address as u64).wrapping_sub(hhdm),
+		    width: fb.width as u32,
+		    height: fb.height as u32,
+		    stride: fb.pitch as u32,
+		    format: match (fb.bpp, fb.red_mask_shift) {
+			(32, 0) => pixel_format::RGB,
+			(32, 16) => pixel_format::BGR,
+			_ => pixel_format::BITMASK,
+		    },
+		};
+		out.push(tag::FRAMEBUFFER, [fb.to_bytes()])?;
+	    }
+	}
+    }
+
+    Ok(())
+}
+
+unsafe fn array<'a, T>(ptr: *const *const T, count: u64) -> &'a [*const T] {
+    if ptr.is_null() {
+	return &[];
+    }
+    core::slice::from_raw_parts(ptr, count as usize)
+}
+
+fn memory_kind(ty: u64) -> u32 {
+    match ty {
+	MEMMAP_USABLE => mem_kind::USABLE,
+	MEMMAP_ACPI_RECLAIMABLE => mem_kind::ACPI_RECLAIMABLE,
+	MEMMAP_ACPI_NVS => mem_kind::ACPI_NVS,
+	MEMMAP_BOOTLOADER_RECLAIMABLE => mem_kind::LOADER,
+	_ => mem_kind::RESERVED,
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::boot::{Tag, Tags};
+
+    #[allow(dead_code)]
+    #[repr(align(8))]
+    struct Buf([u8; 512]);
+
+    #[test]
+    fn normalize_responses() {
+	const HHDM: u64 = 0xFFFF_8000_0000_0000;
+	let hhdm = Request::new(HHDM_ID);
+	let memmap = Request::new(MEMMAP_ID);
+	let modules = Request::new(MODULE_ID);
+	let framebuffer = Request::new(FRAMEBUFFER_ID);
+
+	hhdm.respond(&HhdmResponse { revision: 0, offset: HHDM });
+
+	let entries = [
+	    MemmapEntry { base: 0, length: 0x9F000, ty: MEMMAP_USABLE },
+	    MemmapEntry { base: 0x100000, length: 0x10000, ty: MEMMAP_BOOTLOADER_RECLAIMABLE },
+	    MemmapEntry { base: 0xFD000000, length: 0x300000, ty: 7 },
+	];
+	let entry_ptrs = entries.each_ref().map(|e| e as *const MemmapEntry);
+	let mm = MemmapResponse { revision: 0, entry_count: 3, entries: entry_ptrs.as_ptr() };
+	memmap.respond(&mm);
+
+	let module = File {
+	    revision: 0,
+	    address: (HHDM + 0x200000) as *const u8,
+	    size: 0x1234,
+	    path: c"/boot/initrd".as_ptr(),
+	    cmdline: c"".as_ptr(),
+	};
+	let module_ptrs = [&module as *const File];
+	let mods = ModuleResponse { revision: 0, module_count: 1, modules: module_ptrs.as_ptr() };
+	modules.respond(&mods);
+
+	let requests = Requests { hhdm: &hhdm, memmap: &memmap, modules: &modules, framebuffer: &framebuffer };
+	let mut buf = Buf([0; 512]);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	unsafe { normalize(&requests, &mut w) }.unwrap();
+	w.finish();
+
+	let mut tags = Tags::new(&buf.0);
+	let Some(Tag::MemoryMap(map)) = tags.next() else { panic!("expected a memory map") };
+	let kinds = [mem_kind::USABLE, mem_kind::LOADER, mem_kind::RESERVED];
+	assert!(map.map(|e| e.kind).eq(kinds));
+	assert!(matches!(tags.next(), Some(Tag::Module { region: MemRegion { addr: 0x200000, len: 0x1234 }, name: "/boot/initrd" })));
+	// The framebuffer request went unanswered.
+	assert!(tags.next().is_none());
+    }
+}
@@ -0,0 +1,167 @@
+//! A ring buffer for log messages that can be handed from the bootloader to the kernel.
+//!
+//! The bootloader's messages are lost as soon as the kernel takes over the screen, so the
+//! loader also writes them into one of these. It lives in a plain region of memory (the
+//! header is stored in-band, at the start of the region) so the kernel can pick it up from
+//! the address in BootInfo and copy it into its own log.
+//!
+//! When the buffer fills up the oldest bytes are overwritten.
+
+use core::fmt;
+use core::mem::{align_of, size_of};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"YOYOLOG\0");
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    capacity: u64,
+    /// Total number of bytes ever written, the write cursor is `written % capacity`.
+    written: u64,
+}
+
+pub struct LogRing<'a> {
+    hdr: &'a mut Header,
+    data: &'a mut [u8],
+}
+
+impl<'a> LogRing<'a> {
+    /// Initialize an empty ring buffer in `mem`. Returns None if `mem` isn't 8 byte aligned
+    /// or is too small to hold the header and at least one byte of data.
+    pub fn new(mem: &'a mut [u8]) -> Option<Self> {
+	let (hdr, data) = Self::split(mem)?;
+	hdr.magic = MAGIC;
+	hdr.capacity = data.len() as u64;
+	hdr.written = 0;
+	Some(Self { hdr, data })
+    }
+
+    /// Attach to a ring buffer previously initialized with `new`, keeping its contents.
+    pub fn attach(mem: &'a mut [u8]) -> Option<Self> {
+	let (hdr, data) = Self::split(mem)?;
+	if hdr.magic != MAGIC || hdr.capacity != data.len() as u64 {
+	    return None;
+	}
+	Some(Self { hdr, data })
+    }
+
+    fn split(mem: &'a mut [u8]) -> Option<(&'a mut Header, &'a mut [u8])> {
+	if mem.len() <= size_of::<Header>() || mem.as_ptr().align_offset(align_of::<Header>()) != 0 {
+	    return None;
+	}
+	let (hdr, data) = mem.split_at_mut(size_of::<Header>());
+	// Safety: length and alignment were checked above and Header is plain old data.
+	let hdr = unsafe { &mut *(hdr.as_mut_ptr() as *mut Header) };
+	Some((hdr, data))
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+	let cap = self.data.len();
+	let written = self.hdr.written;
+	self.hdr.written += bytes.len() as u64;
+
+	// Only the tail end of anything larger than the buffer survives anyway.
+	let (bytes, written) = if bytes.len() > cap {
+	    let skip = bytes.len() - cap;
+	    (&bytes[skip..], written + skip as u64)
+	} else {
+	    (bytes, written)
+	};
+
+	let start = (written % cap as u64) as usize;
+	let first = core::cmp::min(bytes.len(), cap - start);
+	self.data[start..start + first].copy_from_slice(&bytes[..first]);
+	self.data[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+    }
+
+    /// Total number of bytes ever written into the buffer.
+    pub fn written(&self) -> u64 {
+	self.hdr.written
+    }
+
+    /// Number of bytes that have been overwritten.
+    pub fn lost(&self) -> u64 {
+	self.hdr.written.saturating_sub(self.data.len() as u64)
+    }
+
+    /// The buffered bytes, oldest first. Split into two slices since the contents may wrap
+    /// around the end of the buffer.
+    pub fn contents(&self) -> (&[u8], &[u8]) {
+	let cap = self.data.len();
+	if self.hdr.written <= cap as u64 {
+	    (&self.data[..self.hdr.written as usize], &[])
+	} else {
+	    let start = (self.hdr.written % cap as u64) as usize;
+	    (&self.data[start..], &self.data[..start])
+	}
+    }
+}
+
+impl<'a> fmt::Write for LogRing<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	self.write(s.as_bytes());
+	Ok(())
+    }
+}
+
+mod tests {
+
+    // It's being used, but rust analyzer / flycheck / _something_ complains.
+    #[allow(unused_imports)]
+    use super::*;
+
+    // Backing memory for a ring with 8 bytes of data.
+    #[allow(dead_code)]
+    fn mem() -> [u64; 4] {
+	[0; 4]
+    }
+
+    #[allow(dead_code)]
+    fn as_bytes(m: &mut [u64; 4]) -> &mut [u8] {
+	unsafe { core::slice::from_raw_parts_mut(m.as_mut_ptr() as *mut u8, 32) }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+	let mut m = mem();
+	let mut ring = LogRing::new(as_bytes(&mut m)).unwrap();
+	ring.write(b"abc");
+	assert_eq!(ring.contents(), (&b"abc"[..], &b""[..]));
+	assert_eq!(ring.lost(), 0);
+    }
+
+    #[test]
+    fn wraps_around() {
+	let mut m = mem();
+	let mut ring = LogRing::new(as_bytes(&mut m)).unwrap();
+	ring.write(b"012345");
+	ring.write(b"6789");
+	assert_eq!(ring.contents(), (&b"234567"[..], &b"89"[..]));
+	assert_eq!(ring.lost(), 2);
+    }
+
+    #[test]
+    fn oversized_write_keeps_tail() {
+	let mut m = mem();
+	let mut ring = LogRing::new(as_bytes(&mut m)).unwrap();
+	ring.write(b"abc");
+	ring.write(b"0123456789");
+	let (a, b) = ring.contents();
+	assert_eq!([a, b].concat(), b"23456789");
+	assert_eq!(ring.written(), 13);
+    }
+
+    #[test]
+    fn attach_keeps_contents() {
+	let mut m = mem();
+	LogRing::new(as_bytes(&mut m)).unwrap().write(b"hi");
+	let ring = LogRing::attach(as_bytes(&mut m)).unwrap();
+	assert_eq!(ring.contents(), (&b"hi"[..], &b""[..]));
+    }
+
+    #[test]
+    fn attach_rejects_garbage() {
+	let mut m = mem();
+	assert!(LogRing::attach(as_bytes(&mut m)).is_none());
+    }
+}
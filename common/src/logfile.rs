@@ -0,0 +1,227 @@
+//! A log sink appending the kernel log to a file on the ESP, with size-based rotation.
+//!
+//! Real hardware often has no serial port, and the screen is gone with a triple fault.
+//! Writing dmesg to `\yoyo\kernel.log` leaves something to read after pulling the disk (or
+//! its image). FAT writes are slow, so messages are collected in a buffer and written out
+//! when it fills, on `flush`, and from the panic handler. When the file would grow past
+//! the limit it's rotated: `kernel.log` becomes `kernel.log.1`, that becomes
+//! `kernel.log.2` and so on, dropping the oldest beyond `Rotation::keep`.
+
+/// Directory the log lives in, on the ESP.
+pub const LOG_DIR: &str = "\\yoyo";
+/// Path of the current log file.
+pub const LOG_PATH: &str = "\\yoyo\\kernel.log";
+/// Longest path of a rotated log file, `LOG_PATH` with a `.N` suffix.
+pub const MAX_PATH: usize = LOG_PATH.len() + 4;
+
+/// The filesystem operations the sink needs, implemented by the FAT driver.
+pub trait LogFs {
+    type Err;
+    /// Size of the file, None if it doesn't exist.
+    fn size(&mut self, path: &str) -> Result<Option<u64>, Self::Err>;
+    /// Append to the file, creating it (and `LOG_DIR`) if needed.
+    fn append(&mut self, path: &str, data: &[u8]) -> Result<(), Self::Err>;
+    /// Rename a file, replacing `to` if it exists. A missing `from` isn't an error.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Self::Err>;
+    /// Remove a file. A missing file isn't an error.
+    fn remove(&mut self, path: &str) -> Result<(), Self::Err>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rotation {
+    /// Size the current file is kept under.
+    pub max_bytes: u64,
+    /// Rotated files to keep, at most 99.
+    pub keep: u8,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+	Self { max_bytes: 1024 * 1024, keep: 3 }
+    }
+}
+
+pub struct FileSink<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Size of the current file, None until it's been asked for.
+    size: Option<u64>,
+    rotation: Rotation,
+}
+
+impl<const N: usize> FileSink<N> {
+    pub const fn new(rotation: Rotation) -> Self {
+	Self { buf: [0; N], len: 0, size: None, rotation }
+    }
+
+    /// Queue log output, writing the buffer out first if it doesn't fit.
+    pub fn write<F: LogFs>(&mut self, bytes: &[u8], fs: &mut F) -> Result<(), F::Err> {
+	if self.len + bytes.len() > N {
+	    self.flush(fs)?;
+	}
+	if bytes.len() > N {
+	    return self.write_out(bytes, fs);
+	}
+	self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+	self.len += bytes.len();
+	Ok(())
+    }
+
+    /// Write out everything queued.
+    pub fn flush<F: LogFs>(&mut self, fs: &mut F) -> Result<(), F::Err> {
+	if self.len == 0 {
+	    return Ok(());
+	}
+	let buf = self.buf;
+	let len = core::mem::take(&mut self.len);
+	self.write_out(&buf[..len], fs)
+    }
+
+    fn write_out<F: LogFs>(&mut self, bytes: &[u8], fs: &mut F) -> Result<(), F::Err> {
+	let size = match self.size {
+	    Some(s) => s,
+	    None => fs.size(LOG_PATH)?.unwrap_or(0),
+	};
+	// A file that's still empty takes the write whatever its size, or a single write
+	// larger than the limit would rotate forever.
+	let size = if size > 0 && size + bytes.len() as u64 > self.rotation.max_bytes {
+	    self.rotate(fs)?;
+	    0
+	} else {
+	    size
+	};
+	fs.append(LOG_PATH, bytes)?;
+	self.size = Some(size + bytes.len() as u64);
+	Ok(())
+    }
+
+    fn rotate<F: LogFs>(&mut self, fs: &mut F) -> Result<(), F::Err> {
+	let (mut from, mut to) = ([0; MAX_PATH], [0; MAX_PATH]);
+	let keep = self.rotation.keep.min(99);
+	if keep == 0 {
+	    return fs.remove(LOG_PATH);
+	}
+	fs.remove(rotated_path(keep, &mut to))?;
+	for i in (1..keep).rev() {
+	    fs.rename(rotated_path(i, &mut from), rotated_path(i + 1, &mut to))?;
+	}
+	fs.rename(LOG_PATH, rotated_path(1, &mut to))
+    }
+}
+
+/// `LOG_PATH` with `.n` appended.
+pub fn rotated_path(n: u8, buf: &mut [u8; MAX_PATH]) -> &str {
+    let base = LOG_PATH.len();
+    buf[..base].copy_from_slice(LOG_PATH.as_bytes());
+    buf[base] = b'.';
+    let len = if n >= 10 {
+	buf[base + 1] = b'0' + n / 10;
+	buf[base + 2] = b'0' + n % 10;
+	base + 3
+    } else {
+	buf[base + 1] = b'0' + n;
+	base + 2
+    };
+    // Only ASCII was written.
+    core::str::from_utf8(&buf[..len]).unwrap()
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Files as (name, size) pairs, content isn't kept.
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct Fs {
+	files: [([u8; MAX_PATH], usize, u64); 8],
+	appends: usize,
+    }
+
+    impl Fs {
+	#[allow(dead_code)]
+	fn find(&self, path: &str) -> Option<usize> {
+	    self.files.iter().position(|(name, len, _)| *len > 0 && &name[..*len] == path.as_bytes())
+	}
+
+	#[allow(dead_code)]
+	fn size_of(&mut self, path: &str) -> Option<u64> {
+	    self.size(path).unwrap()
+	}
+    }
+
+    impl LogFs for Fs {
+	type Err = ();
+
+	fn size(&mut self, path: &str) -> Result<Option<u64>, ()> {
+	    Ok(self.find(path).map(|i| self.files[i].2))
+	}
+
+	fn append(&mut self, path: &str, data: &[u8]) -> Result<(), ()> {
+	    self.appends += 1;
+	    let i = match self.find(path) {
+		Some(i) => i,
+		None => {
+		    let i = self.files.iter().position(|f| f.1 == 0).ok_or(())?;
+		    self.files[i].0[..path.len()].copy_from_slice(path.as_bytes());
+		    self.files[i].1 = path.len();
+		    i
+		},
+	    };
+	    self.files[i].2 += data.len() as u64;
+	    Ok(())
+	}
+
+	fn rename(&mut self, from: &str, to: &str) -> Result<(), ()> {
+	    self.remove(to)?;
+	    if let Some(i) = self.find(from) {
+		self.files[i].0[..to.len()].copy_from_slice(to.as_bytes());
+		self.files[i].1 = to.len();
+	    }
+	    Ok(())
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), ()> {
+	    if let Some(i) = self.find(path) {
+		self.files[i] = ([0; MAX_PATH], 0, 0);
+	    }
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn buffered() {
+	let mut fs = Fs::default();
+	let mut sink = FileSink::<16>::new(Rotation::default());
+	sink.write(b"hello\n", &mut fs).unwrap();
+	sink.write(b"world\n", &mut fs).unwrap();
+	assert_eq!(fs.appends, 0);
+	sink.write(b"overflow\n", &mut fs).unwrap();
+	assert_eq!(fs.size_of(LOG_PATH), Some(12));
+	sink.flush(&mut fs).unwrap();
+	assert_eq!(fs.size_of(LOG_PATH), Some(21));
+	// Bigger than the buffer goes straight out.
+	sink.write(&[b'x'; 40], &mut fs).unwrap();
+	assert_eq!((fs.size_of(LOG_PATH), fs.appends), (Some(61), 3));
+    }
+
+    #[test]
+    fn rotation() {
+	let mut fs = Fs::default();
+	fs.append(LOG_PATH, &[0; 90]).unwrap();
+	let mut sink = FileSink::<8>::new(Rotation { max_bytes: 100, keep: 2 });
+	sink.write(&[0; 60], &mut fs).unwrap();
+	sink.write(&[0; 60], &mut fs).unwrap();
+	let mut buf = [0; MAX_PATH];
+	// The 90 bytes that were there rotated to .1 and then .2.
+	assert_eq!(fs.size_of(LOG_PATH), Some(60));
+	assert_eq!(fs.size_of(rotated_path(1, &mut buf)), Some(60));
+	assert_eq!(fs.size_of(rotated_path(2, &mut buf)), Some(90));
+	// Then dropped.
+	sink.write(&[0; 60], &mut fs).unwrap();
+	assert_eq!(fs.size_of(rotated_path(2, &mut buf)), Some(60));
+	assert_eq!(fs.size_of("\\yoyo\\kernel.log.3"), None);
+	assert_eq!(rotated_path(12, &mut buf), "\\yoyo\\kernel.log.12");
+    }
+}
@@ -0,0 +1,254 @@
+//! Listing a disk's partitions from userspace, for the `lsblk` tool in the initramfs.
+//!
+//! The kernel gives every disk a raw node, `/dev/vda`, that reads any byte range of the
+//! disk. The tool wraps the node in a `RawNode`, which makes it a `BlockDevice` again, and
+//! hands it to the same `gpt::Table` reader the kernel uses at boot, so a table the kernel
+//! booted from lists the same way in userspace. Partitions get the node names the kernel
+//! gives them, `vda1` or `nvme0n1p1`.
+
+use core::fmt;
+
+use crate::dm::{BlockDevice, DmErr, SECTOR_SZ};
+use crate::gpt::{Entry, GptErr, Table};
+
+/// Longest disk name, leaving room for `p` and any `u32` partition number.
+pub const DISK_NAME_MAX: usize = 16;
+const NODE_NAME_SZ: usize = DISK_NAME_MAX + 1 + 10;
+/// Column headings for `Row`.
+pub const HEADER: &str = "NAME           SIZE TYPE LABEL";
+
+/// A node name under /dev.
+#[derive(Clone, Copy, PartialEq)]
+pub struct NodeName {
+    b: [u8; NODE_NAME_SZ],
+    len: usize,
+}
+
+impl NodeName {
+    pub fn disk(name: &str) -> Option<Self> {
+	let mut b = [0; NODE_NAME_SZ];
+	b.get_mut(..name.len()).filter(|_| name.len() <= DISK_NAME_MAX)?.copy_from_slice(name.as_bytes());
+	Some(Self { b, len: name.len() })
+    }
+
+    /// The node of partition `index`, counting from 0. Disks whose name ends in a digit
+    /// get a `p` before the partition number.
+    pub fn partition(&self, index: u32) -> Self {
+	let mut p = *self;
+	let mut w = Writer(&mut p);
+	// Can't fail, NODE_NAME_SZ has room for the longest number.
+	if self.as_str().ends_with(|c: char| c.is_ascii_digit()) {
+	    let _ = fmt::Write::write_str(&mut w, "p");
+	}
+	let _ = fmt::Write::write_fmt(&mut w, format_args!("{}", index as u64 + 1));
+	p
+    }
+
+    pub fn as_str(&self) -> &str {
+	core::str::from_utf8(&self.b[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Display for NodeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for NodeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Appends to a `NodeName`.
+struct Writer<'a>(&'a mut NodeName);
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	let end = self.0.len + s.len();
+	self.0.b.get_mut(self.0.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	self.0.len = end;
+	Ok(())
+    }
+}
+
+/// A raw disk node as a read-only `BlockDevice`, `read_at(offset, buf)` filling `buf`
+/// from the byte `offset`, as pread does.
+pub struct RawNode<F> {
+    bytes: u64,
+    read_at: F,
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> Result<(), DmErr>> RawNode<F> {
+    pub fn new(bytes: u64, read_at: F) -> Self {
+	Self { bytes, read_at }
+    }
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> Result<(), DmErr>> BlockDevice for RawNode<F> {
+    fn sectors(&self) -> u64 {
+	self.bytes / SECTOR_SZ as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	let end = sector.checked_mul(SECTOR_SZ as u64).and_then(|at| at.checked_add(buf.len() as u64));
+	if !buf.len().is_multiple_of(SECTOR_SZ) || end.is_none_or(|end| end > self.sectors() * SECTOR_SZ as u64) {
+	    return Err(DmErr::InputBounds);
+	}
+	(self.read_at)(sector * SECTOR_SZ as u64, buf)
+    }
+
+    fn write(&mut self, _sector: u64, _buf: &[u8]) -> Result<(), DmErr> {
+	Err(DmErr::ReadOnly)
+    }
+}
+
+/// A size in bytes, as `lsblk` shows it: `512B`, `1.5K`, `40.0M`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	let mut unit = 1u64;
+	let mut suffix = "B";
+	for s in ["K", "M", "G", "T"] {
+	    if self.0 < unit * 1024 {
+		break;
+	    }
+	    unit *= 1024;
+	    suffix = s;
+	}
+	let mut b = [0; 16];
+	let mut w = Buf { b: &mut b, len: 0 };
+	if unit == 1 {
+	    fmt::Write::write_fmt(&mut w, format_args!("{}{suffix}", self.0))?;
+	} else {
+	    let tenths = (self.0 as u128 * 10 / unit as u128) as u64;
+	    fmt::Write::write_fmt(&mut w, format_args!("{}.{}{suffix}", tenths / 10, tenths % 10))?;
+	}
+	let len = w.len;
+	f.pad(core::str::from_utf8(&b[..len]).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// Lets `Size` pad its whole text, which `Formatter` only does for a single str.
+struct Buf<'a> {
+    b: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Buf<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	let end = self.len + s.len();
+	self.b.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	self.len = end;
+	Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Disk,
+    Part(Entry),
+}
+
+/// A line of the listing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Row {
+    pub name: NodeName,
+    pub bytes: u64,
+    pub kind: Kind,
+}
+
+/// `vda1            40.0M part "ESP" 2048..=83967 type C12A7328-... guid ...`
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+	write!(f, "{:<12} {:>7} ", self.name, Size(self.bytes))?;
+	match &self.kind {
+	    Kind::Disk => f.write_str("disk"),
+	    Kind::Part(e) => write!(f, "part {e}"),
+	}
+    }
+}
+
+/// Call `f` with a row for `disk` itself, then one for each of its partitions.
+pub fn list<D, F>(disk: NodeName, dev: &mut D, mut f: F) -> Result<(), GptErr>
+where
+    D: BlockDevice,
+    F: FnMut(&Row),
+{
+    f(&Row { name: disk, bytes: dev.sectors() * SECTOR_SZ as u64, kind: Kind::Disk });
+    let table = Table::read(dev)?;
+    table.each_entry(dev, |i, e| {
+	let bytes = (e.last_lba.saturating_sub(e.first_lba) + 1) * table.lba_sz as u64;
+	f(&Row { name: disk.partition(i as u32), bytes, kind: Kind::Part(*e) });
+    })
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::gpt::tests::disk;
+    #[allow(unused_imports)]
+    use crate::gpt::{ESP_TYPE, ROOT_X86_64_TYPE};
+
+    #[test]
+    fn node_names() {
+	let vda = NodeName::disk("vda").unwrap();
+	assert_eq!(vda.partition(0).as_str(), "vda1");
+	assert_eq!(NodeName::disk("nvme0n1").unwrap().partition(11).as_str(), "nvme0n1p12");
+	let longest = NodeName::disk("sixteen-bytes-0").unwrap().partition(u32::MAX);
+	assert_eq!(longest.as_str(), "sixteen-bytes-0p4294967296");
+	assert!(NodeName::disk("seventeen-bytes-0").is_none());
+    }
+
+    #[test]
+    fn sizes() {
+	let s = |n| {
+	    let mut b = Buf { b: &mut [0; 16], len: 0 };
+	    fmt::Write::write_fmt(&mut b, format_args!("{:>6}", Size(n))).unwrap();
+	    let mut out = [0u8; 16];
+	    out[..b.len].copy_from_slice(&b.b[..b.len]);
+	    (out, b.len)
+	};
+	let (b, len) = s(512);
+	assert_eq!(&b[..len], b"  512B");
+	let (b, len) = s(40 << 20);
+	assert_eq!(&b[..len], b" 40.0M");
+	let (b, len) = s(1536);
+	assert_eq!(&b[..len], b"  1.5K");
+    }
+
+    #[test]
+    fn lists_partitions() {
+	let d = disk(&[
+	    (ESP_TYPE, [2; 16], 34, 79, "ESP"),
+	    (ROOT_X86_64_TYPE, [3; 16], 80, 119, "root"),
+	]);
+	let mut node = RawNode::new(d.0.len() as u64, |at, buf: &mut [u8]| {
+	    buf.copy_from_slice(&d.0[at as usize..at as usize + buf.len()]);
+	    Ok(())
+	});
+	assert_eq!(node.read(127, &mut [0; 1024]), Err(DmErr::InputBounds));
+	assert_eq!(node.write(0, &[0; 512]), Err(DmErr::ReadOnly));
+
+	let mut rows = [None; 3];
+	let mut n = 0;
+	list(NodeName::disk("vda").unwrap(), &mut node, |r| {
+	    rows[n] = Some(*r);
+	    n += 1;
+	}).unwrap();
+	assert_eq!(n, 3);
+	let [disk, esp, root] = rows.map(Option::unwrap);
+	assert_eq!((disk.name.as_str(), disk.bytes, disk.kind), ("vda", 128 * 512, Kind::Disk));
+	assert_eq!((esp.name.as_str(), esp.bytes), ("vda1", 46 * 512));
+	assert!(matches!(root.kind, Kind::Part(e) if e.is_root() && e.name_is("root")));
+
+	let mut b = Buf { b: &mut [0; 128], len: 0 };
+	fmt::Write::write_fmt(&mut b, format_args!("{esp}")).unwrap();
+	assert!(core::str::from_utf8(&b.b[..b.len]).unwrap().starts_with("vda1           23.0K part \"ESP\" 34..=79 type C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+    }
+}
@@ -1 +1,3 @@
+pub mod arena;
 pub mod frame;
+pub mod paging;
@@ -0,0 +1,161 @@
+//! Choosing page sizes for kernel mappings.
+//!
+//! The physmap and large kernel allocations are mapped with 2 MiB pages wherever the
+//! virtual and physical addresses line up on a 2 MiB boundary, and 4 KiB pages around
+//! them. That's far fewer TLB entries for the same memory. When part of a 2 MiB page needs
+//! different protections it's split into a page table of 512 small pages first.
+//! Ref: Intel SDM Vol. 3A, 4.5 "4-Level Paging and 5-Level Paging"
+
+pub const SMALL_PAGE_SZ: u64 = 4096;
+pub const HUGE_PAGE_SZ: u64 = 2 * 1024 * 1024;
+pub const ENTRIES_PER_TABLE: usize = 512;
+
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+/// In a page directory entry: maps a 2 MiB page instead of pointing at a page table.
+pub const HUGE_PAGE: u64 = 1 << 7;
+/// PAT bit of a 4 KiB page table entry, it's where the huge page bit is in a PDE.
+pub const PAT_SMALL: u64 = 1 << 7;
+/// PAT bit of a 2 MiB page directory entry.
+pub const PAT_HUGE: u64 = 1 << 12;
+pub const NO_EXECUTE: u64 = 1 << 63;
+pub const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const HUGE_ADDR_MASK: u64 = 0x000F_FFFF_FFE0_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageSize {
+    Small,
+    Huge,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+	match self {
+	    Self::Small => SMALL_PAGE_SZ,
+	    Self::Huge => HUGE_PAGE_SZ,
+	}
+    }
+}
+
+/// One page to map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    pub virt: u64,
+    pub phys: u64,
+    pub size: PageSize,
+}
+
+/// The pages mapping `len` bytes at `virt` to `phys`, huge ones wherever both addresses
+/// are 2 MiB aligned and a whole huge page fits. Addresses and length are rounded out to
+/// 4 KiB pages.
+pub struct MappingPlan {
+    virt: u64,
+    phys: u64,
+    end: u64,
+    allow_huge: bool,
+}
+
+impl MappingPlan {
+    pub fn new(virt: u64, phys: u64, len: u64, allow_huge: bool) -> Self {
+	let offset = virt % SMALL_PAGE_SZ;
+	Self {
+	    virt: virt - offset,
+	    phys: phys - phys % SMALL_PAGE_SZ,
+	    end: (virt + len).next_multiple_of(SMALL_PAGE_SZ),
+	    allow_huge,
+	}
+    }
+}
+
+impl Iterator for MappingPlan {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+	if self.virt >= self.end {
+	    return None;
+	}
+	let huge = self.allow_huge
+	    && self.virt.is_multiple_of(HUGE_PAGE_SZ)
+	    && self.phys.is_multiple_of(HUGE_PAGE_SZ)
+	    && self.end - self.virt >= HUGE_PAGE_SZ;
+	let size = if huge { PageSize::Huge } else { PageSize::Small };
+	let m = Mapping { virt: self.virt, phys: self.phys, size };
+	self.virt += size.bytes();
+	self.phys += size.bytes();
+	Some(m)
+    }
+}
+
+/// The 512 page table entries for a 2 MiB page directory entry, mapping the same memory
+/// with the same protections. Flags carry over except the huge page bit, and PAT moves to
+/// where page table entries keep it.
+pub fn split_huge(pde: u64) -> [u64; ENTRIES_PER_TABLE] {
+    let base = pde & HUGE_ADDR_MASK;
+    let mut flags = pde & !HUGE_ADDR_MASK & !HUGE_PAGE & !PAT_HUGE;
+    if pde & PAT_HUGE != 0 {
+	flags |= PAT_SMALL;
+    }
+    core::array::from_fn(|i| (base + i as u64 * SMALL_PAGE_SZ) | flags)
+}
+
+/// How many pages of each size are mapped, for meminfo.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MappingCounts {
+    pub huge: usize,
+    pub small: usize,
+}
+
+impl MappingCounts {
+    pub fn add(&mut self, size: PageSize) {
+	match size {
+	    PageSize::Small => self.small += 1,
+	    PageSize::Huge => self.huge += 1,
+	}
+    }
+
+    /// A huge page was split into small ones.
+    pub fn split(&mut self) {
+	self.huge -= 1;
+	self.small += ENTRIES_PER_TABLE;
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn huge_where_aligned() {
+	const MIB: u64 = 1024 * 1024;
+	// 1 MiB below a 2 MiB boundary to 1 MiB past the next one.
+	let plan = MappingPlan::new(0xFFFF_8000_0010_0000, MIB, 4 * MIB, true);
+	let mut counts = MappingCounts::default();
+	let mut huge = None;
+	for m in plan {
+	    counts.add(m.size);
+	    if m.size == PageSize::Huge {
+		huge = Some(m);
+	    }
+	}
+	assert_eq!(counts, MappingCounts { huge: 1, small: 512 });
+	assert_eq!(huge, Some(Mapping { virt: 0xFFFF_8000_0020_0000, phys: 2 * MIB, size: PageSize::Huge }));
+
+	// Physical memory that's off by a page can't use huge pages.
+	assert!(MappingPlan::new(0, SMALL_PAGE_SZ, 4 * MIB, true).all(|m| m.size == PageSize::Small));
+	assert!(MappingPlan::new(0, 0, 4 * MIB, false).all(|m| m.size == PageSize::Small));
+	assert_eq!(MappingPlan::new(0x1FFF, 0, 2, true).count(), 2);
+    }
+
+    #[test]
+    fn split() {
+	let pde = 0x4020_0000 | PRESENT | WRITABLE | HUGE_PAGE | PAT_HUGE | NO_EXECUTE;
+	let ptes = split_huge(pde);
+	assert_eq!(ptes[0], 0x4020_0000 | PRESENT | WRITABLE | PAT_SMALL | NO_EXECUTE);
+	assert_eq!(ptes[511] & ADDR_MASK, 0x4020_0000 + 511 * SMALL_PAGE_SZ);
+
+	let mut counts = MappingCounts { huge: 2, small: 0 };
+	counts.split();
+	assert_eq!(counts, MappingCounts { huge: 1, small: 512 });
+    }
+}
@@ -0,0 +1,213 @@
+//! Mouse input: the events drivers produce and the PS/2 mouse packet decoder.
+//!
+//! Drivers turn whatever their device sends into `Event`s, which are handed to userspace
+//! (the compositor) as fixed size records read from `/dev/mouse`, see `Event::to_bytes`.
+//! A USB HID mouse will produce the same events.
+//!
+//! A PS/2 mouse sends 3 byte packets: buttons and sign/overflow bits, then X and Y
+//! movement. After the IntelliMouse knock (sample rates 200, 100, 80) a mouse with a
+//! wheel reports id 3 and adds a fourth byte with the wheel movement.
+//! Reference: https://wiki.osdev.org/PS/2_Mouse
+
+/// Sample rates to set, in order, to ask for wheel reports.
+pub const WHEEL_SAMPLE_RATES: [u8; 3] = [200, 100, 80];
+/// Device id reported by a mouse that switched to 4 byte packets.
+pub const WHEEL_ID: u8 = 3;
+
+pub const EVENT_SZ: usize = 8;
+
+const KIND_MOVE: u8 = 1;
+const KIND_BUTTON: u8 = 2;
+const KIND_WHEEL: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// Relative movement, y grows downwards like screen coordinates.
+    Move { dx: i16, dy: i16 },
+    Button { button: Button, pressed: bool },
+    /// Positive scrolls down.
+    Wheel(i8),
+}
+
+impl Event {
+    /// The record read from `/dev/mouse`: kind, then kind specific fields, little endian.
+    pub fn to_bytes(&self) -> [u8; EVENT_SZ] {
+	let mut b = [0; EVENT_SZ];
+	match *self {
+	    Self::Move { dx, dy } => {
+		b[0] = KIND_MOVE;
+		b[4..6].copy_from_slice(&dx.to_le_bytes());
+		b[6..8].copy_from_slice(&dy.to_le_bytes());
+	    },
+	    Self::Button { button, pressed } => {
+		b[0] = KIND_BUTTON;
+		b[1] = button as u8;
+		b[2] = pressed as u8;
+	    },
+	    Self::Wheel(z) => {
+		b[0] = KIND_WHEEL;
+		b[1] = z as u8;
+	    },
+	}
+	b
+    }
+
+    pub fn from_bytes(b: &[u8; EVENT_SZ]) -> Option<Self> {
+	match b[0] {
+	    KIND_MOVE => Some(Self::Move {
+		dx: i16::from_le_bytes([b[4], b[5]]),
+		dy: i16::from_le_bytes([b[6], b[7]]),
+	    }),
+	    KIND_BUTTON => {
+		let button = [Button::Left, Button::Right, Button::Middle].get(b[1] as usize)?;
+		Some(Self::Button { button: *button, pressed: b[2] != 0 })
+	    },
+	    KIND_WHEEL => Some(Self::Wheel(b[1] as i8)),
+	    _ => None,
+	}
+    }
+}
+
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+const BUTTONS: [Button; 3] = [Button::Left, Button::Right, Button::Middle];
+
+/// Assembles PS/2 mouse packets from the bytes read off port 0x60.
+pub struct Ps2Mouse {
+    packet: [u8; 4],
+    len: usize,
+    wheel: bool,
+    buttons: u8,
+}
+
+impl Ps2Mouse {
+    pub fn new() -> Self {
+	Self {
+	    packet: [0; 4],
+	    len: 0,
+	    wheel: false,
+	    buttons: 0,
+	}
+    }
+
+    /// Expect 4 byte packets, once the mouse answered the knock with `WHEEL_ID`.
+    pub fn enable_wheel(&mut self) {
+	self.wheel = true;
+	self.len = 0;
+    }
+
+    /// Handle one byte from the mouse, calling `emit` for each event once a packet is
+    /// complete.
+    pub fn feed(&mut self, byte: u8, mut emit: impl FnMut(Event)) {
+	// Resync on a byte that can't start a packet, it was lost somewhere.
+	if self.len == 0 && byte & ALWAYS_ONE == 0 {
+	    return;
+	}
+	self.packet[self.len] = byte;
+	self.len += 1;
+	if self.len < if self.wheel { 4 } else { 3 } {
+	    return;
+	}
+	self.len = 0;
+
+	let [flags, x, y, z] = self.packet;
+	// Movement that overflowed is garbage, skip it but keep the buttons.
+	if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 {
+	    let dx = x as i16 - if flags & X_SIGN != 0 { 256 } else { 0 };
+	    let dy = y as i16 - if flags & Y_SIGN != 0 { 256 } else { 0 };
+	    if dx != 0 || dy != 0 {
+		emit(Event::Move { dx, dy: -dy });
+	    }
+	}
+
+	let changed = (self.buttons ^ flags) & 0b111;
+	for (bit, button) in BUTTONS.iter().enumerate() {
+	    if changed >> bit & 1 != 0 {
+		emit(Event::Button { button: *button, pressed: flags >> bit & 1 != 0 });
+	    }
+	}
+	self.buttons = flags & 0b111;
+
+	if self.wheel {
+	    // Sign extend the low nibble.
+	    let z = ((z << 4) as i8) >> 4;
+	    if z != 0 {
+		emit(Event::Wheel(z));
+	    }
+	}
+    }
+}
+
+impl Default for Ps2Mouse {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Feed bytes to a mouse, collecting the events.
+    #[allow(dead_code)]
+    fn events(mouse: &mut Ps2Mouse, bytes: &[u8]) -> ([Option<Event>; 8], usize) {
+	let mut out = [None; 8];
+	let mut n = 0;
+	for b in bytes {
+	    mouse.feed(*b, |e| {
+		out[n] = Some(e);
+		n += 1;
+	    });
+	}
+	(out, n)
+    }
+
+    #[test]
+    fn packets() {
+	let mut mouse = Ps2Mouse::new();
+	// Right and up, then left button down while moving left and down.
+	let (out, n) = events(&mut mouse, &[0x08, 5, 3, 0x39, 0xFE, 0xFF]);
+	assert_eq!(&out[..n], &[
+	    Some(Event::Move { dx: 5, dy: -3 }),
+	    Some(Event::Move { dx: -2, dy: 1 }),
+	    Some(Event::Button { button: Button::Left, pressed: true }),
+	]);
+
+	// Overflow drops the movement, the release still comes through.
+	let (out, n) = events(&mut mouse, &[0x48, 0xFF, 0]);
+	assert_eq!(&out[..n], &[Some(Event::Button { button: Button::Left, pressed: false })]);
+    }
+
+    #[test]
+    fn resync_and_wheel() {
+	let mut mouse = Ps2Mouse::new();
+	mouse.enable_wheel();
+	// A stray byte without bit 3 is dropped before the packet.
+	let (out, n) = events(&mut mouse, &[0x00, 0x08, 0, 0, 0x0F]);
+	assert_eq!(&out[..n], &[Some(Event::Wheel(-1))]);
+    }
+
+    #[test]
+    fn records() {
+	let all = [
+	    Event::Move { dx: -300, dy: 7 },
+	    Event::Button { button: Button::Middle, pressed: true },
+	    Event::Wheel(2),
+	];
+	for e in all {
+	    assert_eq!(Event::from_bytes(&e.to_bytes()), Some(e));
+	}
+	assert_eq!(Event::from_bytes(&[0; EVENT_SZ]), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Multiboot2, so the kernel can also be booted by GRUB.
+//!
+//! The kernel image carries a Multiboot2 header, and the info structure GRUB hands over is
+//! translated into a regular BootInfo so the rest of the kernel doesn't care which loader
+//! it came from. Only the parts BootInfo has a tag for are kept: the memory map, modules
+//! and the framebuffer.
+//! Reference: https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+
+use crate::boot::{mem_kind, pixel_format, tag, BootInfoErr, BootInfoWriter, Framebuffer, MemRegion, MemoryMapEntry};
+
+pub const HEADER_MAGIC: u32 = 0xE85250D6;
+/// Value of eax when a Multiboot2 loader jumps to the kernel.
+pub const BOOTLOADER_MAGIC: u32 = 0x36D76289;
+const ARCH_I386: u32 = 0;
+
+const TAG_ALIGN: usize = 8;
+const INFO_HEADER_SZ: usize = 8;
+
+const TAG_END: u32 = 0;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_NVS: u32 = 4;
+
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+/// The header a Multiboot2 loader looks for in the first 32KiB of the kernel image.
+#[repr(C, align(8))]
+pub struct Header {
+    magic: u32,
+    architecture: u32,
+    header_length: u32,
+    checksum: u32,
+    end_tag: [u32; 2],
+}
+
+pub const HEADER: Header = {
+    let header_length = core::mem::size_of::<Header>() as u32;
+    Header {
+	magic: HEADER_MAGIC,
+	architecture: ARCH_I386,
+	header_length,
+	checksum: 0u32.wrapping_sub(HEADER_MAGIC).wrapping_sub(ARCH_I386).wrapping_sub(header_length),
+	end_tag: [TAG_END, 8],
+    }
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Multiboot2Err {
+    /// The info structure is truncated or a tag runs past its end.
+    InputBounds,
+    BootInfo(BootInfoErr),
+}
+
+impl From<BootInfoErr> for Multiboot2Err {
+    fn from(e: BootInfoErr) -> Self {
+	Self::BootInfo(e)
+    }
+}
+
+/// Translate a Multiboot2 info structure into BootInfo tags. Tags with no BootInfo
+/// equivalent are skipped.
+pub fn normalize(info: &[u8], out: &mut BootInfoWriter) -> Result<(), Multiboot2Err> {
+    let total = u32_at(info, 0)? as usize;
+    let info = info.get(..total).ok_or(Multiboot2Err::InputBounds)?;
+
+    let mut pos = INFO_HEADER_SZ;
+    loop {
+	let ty = u32_at(info, pos)?;
+	let size = u32_at(info, pos + 4)? as usize;
+	if ty == TAG_END {
+	    return Ok(());
+	}
+	let t = info.get(pos..pos.checked_add(size).ok_or(Multiboot2Err::InputBounds)?)
+	    .filter(|t| t.len() >= 8)
+	    .ok_or(Multiboot2Err::InputBounds)?;
+
+	match ty {
+	    TAG_MODULE => {
+		let start = u32_at(t, 8)? as u64;
+		let end = u32_at(t, 12)? as u64;
+		// The name is NUL terminated, keep it only if it's UTF-8.
+		let name = t[16.min(t.len())..].split(|b| *b == 0).next().unwrap_or(&[]);
+		let name = core::str::from_utf8(name).unwrap_or("");
+		let region = MemRegion { addr: start, len: end.saturating_sub(start) };
+		out.push(tag::MODULE, [&region.to_bytes()[..], name.as_bytes()])?;
+	    },
+	    TAG_MEMORY_MAP => {
+		let entry_size = u32_at(t, 8)? as usize;
+		if entry_size < 24 {
+		    return Err(Multiboot2Err::InputBounds);
+		}
+		let entries = t[16..].chunks_exact(entry_size).map(|e| MemoryMapEntry {
+		    region: MemRegion {
+			addr: u64::from_le_bytes(e[0..8].try_into().unwrap()),
+			len: u64::from_le_bytes(e[8..16].try_into().unwrap()),
+		    },
+		    kind: memory_kind(u32::from_le_bytes(e[16..20].try_into().unwrap())),
+		}.to_bytes());
+		out.push(tag::MEMORY_MAP, entries)?;
+	    },
+	    TAG_FRAMEBUFFER => {
+		let fb_type = *t.get(29).ok_or(Multiboot2Err::InputBounds)?;
+		// Indexed and text mode framebuffers aren't any use to us.
+		if fb_type == FRAMEBUFFER_TYPE_RGB {
+		    let red_position = *t.get(32).ok_or(Multiboot2Err::InputBounds)?;
+		    let fb = Framebuffer {
+			addr: u64_at(t, 8)?,
+			stride: u32_at(t, 16)?,
+			width: u32_at(t, 20)?,
+			height: u32_at(t, 24)?,
+			format: match (t[28], red_position) {
+			    (32, 0) => pixel_format::RGB,
+			    (32, 16) => pixel_format::BGR,
+			    _ => pixel_format::BITMASK,
+			},
+		    };
+		    out.push(tag::FRAMEBUFFER, [fb.to_bytes()])?;
+		}
+	    },
+	    _ => {},
+	}
+
+	pos = (pos + size).next_multiple_of(TAG_ALIGN);
+    }
+}
+
+fn memory_kind(ty: u32) -> u32 {
+    match ty {
+	MEMORY_AVAILABLE => mem_kind::USABLE,
+	MEMORY_ACPI_RECLAIMABLE => mem_kind::ACPI_RECLAIMABLE,
+	MEMORY_NVS => mem_kind::ACPI_NVS,
+	_ => mem_kind::RESERVED,
+    }
+}
+
+fn u32_at(b: &[u8], off: usize) -> Result<u32, Multiboot2Err> {
+    let b = b.get(off..off.checked_add(4).ok_or(Multiboot2Err::InputBounds)?).ok_or(Multiboot2Err::InputBounds)?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u64_at(b: &[u8], off: usize) -> Result<u64, Multiboot2Err> {
+    let b = b.get(off..off.checked_add(8).ok_or(Multiboot2Err::InputBounds)?).ok_or(Multiboot2Err::InputBounds)?;
+    Ok(u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::boot::{Tag, Tags};
+
+    #[allow(dead_code)]
+    #[repr(align(8))]
+    struct Buf([u8; 512]);
+
+    /// Append a Multiboot2 tag to `info`, padded to 8 bytes.
+    #[allow(dead_code)]
+    fn push_tag(info: &mut [u8], pos: &mut usize, ty: u32, body: &[u8]) {
+	let size = 8 + body.len();
+	info[*pos..*pos + 4].copy_from_slice(&ty.to_le_bytes());
+	info[*pos + 4..*pos + 8].copy_from_slice(&(size as u32).to_le_bytes());
+	info[*pos + 8..*pos + size].copy_from_slice(body);
+	*pos = (*pos + size).next_multiple_of(8);
+    }
+
+    #[test]
+    fn header_checksum() {
+	let h = HEADER;
+	let sum = h.magic.wrapping_add(h.architecture).wrapping_add(h.header_length).wrapping_add(h.checksum);
+	assert_eq!(sum, 0);
+	assert_eq!(h.header_length, 24);
+    }
+
+    #[test]
+    fn normalize_info() {
+	let mut info = [0u8; 256];
+	let mut pos = 8;
+	// Boot loader name, no BootInfo equivalent.
+	push_tag(&mut info, &mut pos, 2, b"GRUB 2.12\0");
+
+	let mut module = [0u8; 8 + 7];
+	module[0..4].copy_from_slice(&0x10_0000u32.to_le_bytes());
+	module[4..8].copy_from_slice(&0x10_8000u32.to_le_bytes());
+	module[8..].copy_from_slice(b"initrd\0");
+	push_tag(&mut info, &mut pos, TAG_MODULE, &module);
+
+	let mut mmap = [0u8; 8 + 2 * 24];
+	mmap[0..4].copy_from_slice(&24u32.to_le_bytes());
+	mmap[16..24].copy_from_slice(&0x9F000u64.to_le_bytes());
+	mmap[24..28].copy_from_slice(&MEMORY_AVAILABLE.to_le_bytes());
+	mmap[32..40].copy_from_slice(&0xF0000u64.to_le_bytes());
+	mmap[40..48].copy_from_slice(&0x10000u64.to_le_bytes());
+	mmap[48..52].copy_from_slice(&2u32.to_le_bytes());
+	push_tag(&mut info, &mut pos, TAG_MEMORY_MAP, &mmap);
+
+	let mut fb = [0u8; 24 + 6];
+	fb[0..8].copy_from_slice(&0xFD00_0000u64.to_le_bytes());
+	fb[8..12].copy_from_slice(&4096u32.to_le_bytes());
+	fb[12..16].copy_from_slice(&1024u32.to_le_bytes());
+	fb[16..20].copy_from_slice(&768u32.to_le_bytes());
+	fb[20] = 32;
+	fb[21] = FRAMEBUFFER_TYPE_RGB;
+	fb[24] = 16;
+	push_tag(&mut info, &mut pos, TAG_FRAMEBUFFER, &fb);
+
+	push_tag(&mut info, &mut pos, TAG_END, &[]);
+	info[0..4].copy_from_slice(&(pos as u32).to_le_bytes());
+
+	let mut buf = Buf([0; 512]);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	normalize(&info, &mut w).unwrap();
+	w.finish();
+
+	let mut tags = Tags::new(&buf.0);
+	assert!(matches!(tags.next(), Some(Tag::Module { region: MemRegion { addr: 0x10_0000, len: 0x8000 }, name: "initrd" })));
+	let Some(Tag::MemoryMap(map)) = tags.next() else { panic!("expected a memory map") };
+	let kinds: [u32; 2] = [mem_kind::USABLE, mem_kind::RESERVED];
+	assert!(map.map(|e| e.kind).eq(kinds));
+	let Some(Tag::Framebuffer(fb)) = tags.next() else { panic!("expected a framebuffer") };
+	assert_eq!((fb.width, fb.height, fb.stride, fb.format), (1024, 768, 4096, pixel_format::BGR));
+	assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn truncated_info() {
+	let mut buf = Buf([0; 512]);
+	let mut w = BootInfoWriter::new(&mut buf.0).unwrap();
+	assert_eq!(normalize(&[16, 0, 0, 0], &mut w), Err(Multiboot2Err::InputBounds));
+
+	// A tag claiming to be bigger than the info structure.
+	let mut info = [0u8; 16];
+	info[0] = 16;
+	info[8] = TAG_MODULE as u8;
+	info[12] = 0xFF;
+	assert_eq!(normalize(&info, &mut w), Err(Multiboot2Err::InputBounds));
+    }
+}
@@ -0,0 +1,158 @@
+//! Kernel preemption bookkeeping and a latency tracer.
+//!
+//! Each CPU has a preempt count: non-zero while it holds a spinlock, runs an interrupt
+//! handler or otherwise can't switch tasks. The timer interrupt sets `need_resched`
+//! instead of switching when the count is non-zero, and the switch happens at the
+//! `enable` that brings it back to zero. That makes every lock release a preemption point
+//! without the kernel having to sprinkle explicit ones around.
+//!
+//! `LatencyTracer` keeps the worst cases of two things that decide how responsive the
+//! system feels: how long interrupts stay disabled, and how long a woken task waits before
+//! it runs. Times are in whatever unit the caller's clock counts (TSC ticks, nanoseconds).
+
+#[derive(Debug, Default)]
+pub struct PreemptCount {
+    depth: u32,
+    need_resched: bool,
+}
+
+impl PreemptCount {
+    pub const fn new() -> Self {
+	Self { depth: 0, need_resched: false }
+    }
+
+    pub fn disable(&mut self) {
+	self.depth += 1;
+    }
+
+    /// Undo a `disable`. Returns true if this is a preemption point that should call the
+    /// scheduler now: the count reached zero and a reschedule was asked for meanwhile.
+    pub fn enable(&mut self) -> bool {
+	self.depth = self.depth.checked_sub(1).expect("preempt count underflow");
+	self.depth == 0 && core::mem::take(&mut self.need_resched)
+    }
+
+    pub fn preemptible(&self) -> bool {
+	self.depth == 0
+    }
+
+    /// From the timer or a wakeup: switch now if nothing is in the way, returning true,
+    /// otherwise at the next preemption point.
+    pub fn request_resched(&mut self) -> bool {
+	if self.preemptible() {
+	    return true;
+	}
+	self.need_resched = true;
+	false
+    }
+}
+
+/// The worst latency seen, and where.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Worst {
+    pub duration: u64,
+    /// What re-enabled interrupts, or the task that was woken.
+    pub site: &'static str,
+}
+
+impl Worst {
+    fn update(&mut self, duration: u64, site: &'static str) {
+	if duration > self.duration {
+	    *self = Self { duration, site };
+	}
+    }
+}
+
+/// Latency histogram buckets, powers of two of the clock unit: bucket i counts durations
+/// below 2^(i + 1).
+pub const BUCKETS: usize = 32;
+
+#[derive(Debug)]
+pub struct LatencyTracer {
+    irqs_off_since: Option<u64>,
+    pub irqs_off: Worst,
+    pub wakeup: Worst,
+    pub wakeup_histogram: [u64; BUCKETS],
+}
+
+impl LatencyTracer {
+    pub const fn new() -> Self {
+	Self {
+	    irqs_off_since: None,
+	    irqs_off: Worst { duration: 0, site: "" },
+	    wakeup: Worst { duration: 0, site: "" },
+	    wakeup_histogram: [0; BUCKETS],
+	}
+    }
+
+    /// Interrupts were disabled at `now`. Nested disables keep the first time.
+    pub fn irqs_disabled(&mut self, now: u64) {
+	self.irqs_off_since.get_or_insert(now);
+    }
+
+    /// Interrupts were enabled again at `now` by `site`.
+    pub fn irqs_enabled(&mut self, now: u64, site: &'static str) {
+	if let Some(since) = self.irqs_off_since.take() {
+	    self.irqs_off.update(now.saturating_sub(since), site);
+	}
+    }
+
+    /// A task woken at `woken` started running at `now`.
+    pub fn scheduled(&mut self, woken: u64, now: u64, task: &'static str) {
+	let latency = now.saturating_sub(woken);
+	self.wakeup.update(latency, task);
+	let bucket = (u64::BITS - latency.leading_zeros()).saturating_sub(1) as usize;
+	self.wakeup_histogram[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    /// Forget the worst cases, e.g. after boot is done.
+    pub fn reset(&mut self) {
+	*self = Self { irqs_off_since: self.irqs_off_since, ..Self::new() };
+    }
+}
+
+impl Default for LatencyTracer {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn preemption_points() {
+	let mut p = PreemptCount::new();
+	assert!(p.request_resched());
+
+	p.disable();
+	p.disable();
+	assert!(!p.request_resched());
+	assert!(!p.enable());
+	// Only the outermost enable switches, and only once.
+	assert!(p.enable());
+	p.disable();
+	assert!(!p.enable());
+    }
+
+    #[test]
+    fn worst_cases() {
+	let mut t = LatencyTracer::new();
+	t.irqs_disabled(100);
+	t.irqs_disabled(150);
+	t.irqs_enabled(400, "ata::read");
+	t.irqs_disabled(1000);
+	t.irqs_enabled(1100, "timer");
+	assert_eq!(t.irqs_off, Worst { duration: 300, site: "ata::read" });
+
+	t.scheduled(10, 13, "shell");
+	t.scheduled(10, 10, "init");
+	assert_eq!(t.wakeup, Worst { duration: 3, site: "shell" });
+	assert_eq!(&t.wakeup_histogram[..3], &[1, 1, 0]);
+
+	t.reset();
+	assert_eq!(t.irqs_off, Worst::default());
+    }
+}
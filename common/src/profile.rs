@@ -0,0 +1,237 @@
+//! A sampling profiler for the kernel.
+//!
+//! The timer interrupt calls `Profiler::sample` with the interrupted RIP and RBP, which
+//! walks the frame pointer chain into a fixed size buffer. When the run is over the
+//! samples go out over the serial port as text lines,
+//!
+//! ```text
+//! @prof <rip hex>,<return address hex>,...
+//! ```
+//!
+//! innermost frame first, and `bob flamegraph` on the host symbolizes them with the kernel
+//! ELF into folded stacks. Like `@snap` lines they can be mixed in with console output.
+//! Walking frame pointers needs the kernel built with `-C force-frame-pointers=yes`.
+
+use core::fmt::{self, Write};
+
+pub const PREFIX: &str = "@prof ";
+/// Deepest call chain kept, deeper ones are cut off at the outermost end.
+pub const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    depth: u8,
+    frames: [u64; MAX_DEPTH],
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+	Self::EMPTY
+    }
+}
+
+impl Sample {
+    pub const EMPTY: Sample = Sample { depth: 0, frames: [0; MAX_DEPTH] };
+
+    /// The interrupted RIP, then a return address per frame outwards.
+    pub fn frames(&self) -> &[u64] {
+	&self.frames[..self.depth as usize]
+    }
+
+    fn push(&mut self, addr: u64) -> bool {
+	if self.depth as usize == MAX_DEPTH {
+	    return false;
+	}
+	self.frames[self.depth as usize] = addr;
+	self.depth += 1;
+	true
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> fmt::Result {
+	w.write_str(PREFIX)?;
+	for (i, f) in self.frames().iter().enumerate() {
+	    if i > 0 {
+		w.write_char(',')?;
+	    }
+	    write!(w, "{f:x}")?;
+	}
+	w.write_char('\n')
+    }
+
+    /// A sample from a line written by `write`, None for anything else.
+    pub fn parse(line: &str) -> Option<Sample> {
+	let body = line.trim_end().strip_prefix(PREFIX)?;
+	let mut s = Sample::EMPTY;
+	for f in body.split(',') {
+	    if !s.push(u64::from_str_radix(f, 16).ok()?) {
+		return None;
+	    }
+	}
+	(s.depth > 0).then_some(s)
+    }
+}
+
+/// Walk the frame pointer chain from `rbp`, reading stack words with `read`, which
+/// returns None for addresses that aren't mapped kernel stack. Each frame holds the
+/// caller's RBP at `rbp` and the return address above it. The walk stops at a null or
+/// misaligned RBP, one that doesn't move up the stack, a failed read or `MAX_DEPTH`.
+pub fn unwind(rip: u64, mut rbp: u64, mut read: impl FnMut(u64) -> Option<u64>) -> Sample {
+    let mut s = Sample::EMPTY;
+    s.push(rip);
+    while rbp != 0 && rbp.is_multiple_of(8) {
+	let (Some(next), Some(ret)) = (read(rbp), rbp.checked_add(8).and_then(&mut read)) else {
+	    break;
+	};
+	if ret == 0 || !s.push(ret) || next <= rbp {
+	    break;
+	}
+	rbp = next;
+    }
+    s
+}
+
+/// Samples collected so far. Once the buffer is full further samples are counted as
+/// dropped rather than overwriting, so a run's profile covers its start.
+pub struct Profiler<const N: usize> {
+    samples: [Sample; N],
+    len: usize,
+    dropped: u64,
+    running: bool,
+}
+
+impl<const N: usize> Default for Profiler<N> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl<const N: usize> Profiler<N> {
+    pub const fn new() -> Self {
+	Self { samples: [Sample::EMPTY; N], len: 0, dropped: 0, running: false }
+    }
+
+    /// Start sampling from scratch.
+    pub fn start(&mut self) {
+	self.len = 0;
+	self.dropped = 0;
+	self.running = true;
+    }
+
+    pub fn stop(&mut self) {
+	self.running = false;
+    }
+
+    pub fn running(&self) -> bool {
+	self.running
+    }
+
+    /// Take a sample of the interrupted code, from the timer interrupt.
+    pub fn sample(&mut self, rip: u64, rbp: u64, read: impl FnMut(u64) -> Option<u64>) {
+	if !self.running {
+	    return;
+	}
+	if self.len == N {
+	    self.dropped += 1;
+	    return;
+	}
+	self.samples[self.len] = unwind(rip, rbp, read);
+	self.len += 1;
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+	&self.samples[..self.len]
+    }
+
+    pub fn dropped(&self) -> u64 {
+	self.dropped
+    }
+
+    /// Write every sample as a `@prof` line.
+    pub fn export<W: Write>(&self, w: &mut W) -> fmt::Result {
+	self.samples().iter().try_for_each(|s| s.write(w))
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Stack words from `base` up.
+    #[allow(dead_code)]
+    fn stack(base: u64, words: &[u64]) -> impl FnMut(u64) -> Option<u64> + '_ {
+	move |addr| {
+	    let i = addr.checked_sub(base)? / 8;
+	    words.get(i as usize).copied()
+	}
+    }
+
+    /// A fixed buffer to write lines into.
+    #[allow(dead_code)]
+    struct Buf {
+	b: [u8; 128],
+	len: usize,
+    }
+
+    impl Buf {
+	#[allow(dead_code)]
+	fn as_str(&self) -> &str {
+	    core::str::from_utf8(&self.b[..self.len]).unwrap()
+	}
+    }
+
+    impl Write for Buf {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.len + s.len();
+	    self.b.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.len = end;
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn unwinds_frame_pointers() {
+	// Three frames: the interrupted one at 0x1000, its caller's at 0x1010, and the
+	// outermost at 0x1020 with a null saved RBP.
+	let words = [0x1010, 0xaaa, 0x1020, 0xbbb, 0, 0xccc];
+	let s = unwind(0x999, 0x1000, stack(0x1000, &words));
+	assert_eq!(s.frames(), &[0x999, 0xaaa, 0xbbb, 0xccc]);
+
+	// A loop in the chain stops the walk instead of filling the sample.
+	let words = [0x1000, 0xaaa];
+	assert_eq!(unwind(0x999, 0x1000, stack(0x1000, &words)).frames(), &[0x999, 0xaaa]);
+	// Unmapped and misaligned frame pointers.
+	assert_eq!(unwind(0x999, 0x8000, stack(0x1000, &words)).frames(), &[0x999]);
+	assert_eq!(unwind(0x999, 0x1003, stack(0x1000, &words)).frames(), &[0x999]);
+    }
+
+    #[test]
+    fn lines_round_trip() {
+	let words = [0x1010, 0xaaa, 0, 0xffff_ffff_8000_1234];
+	let s = unwind(0x999, 0x1000, stack(0x1000, &words));
+	let mut line = Buf { b: [0; 128], len: 0 };
+	s.write(&mut line).unwrap();
+	assert_eq!(line.as_str(), "@prof 999,aaa,ffffffff80001234\n");
+	assert_eq!(Sample::parse(line.as_str()), Some(s));
+	assert_eq!(Sample::parse("@prof "), None);
+	assert_eq!(Sample::parse("@prof 12,zz"), None);
+	assert_eq!(Sample::parse("[ 1.0] boot"), None);
+    }
+
+    #[test]
+    fn buffer_fills_up() {
+	let mut p = Profiler::<2>::new();
+	p.sample(1, 0, |_| None);
+	assert!(p.samples().is_empty());
+	p.start();
+	for rip in 1..=5 {
+	    p.sample(rip, 0, |_| None);
+	}
+	p.stop();
+	p.sample(6, 0, |_| None);
+	assert_eq!(p.samples().iter().map(|s| s.frames()[0]).sum::<u64>(), 3);
+	assert_eq!(p.dropped(), 3);
+	p.start();
+	assert_eq!((p.samples().len(), p.dropped()), (0, 0));
+    }
+}
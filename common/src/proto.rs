@@ -0,0 +1,318 @@
+//! Messages from the kernel to the host: boot progress, test results, files.
+//!
+//! Test runs used to be judged by grepping the serial log for strings, which breaks
+//! whenever a message is reworded or two lines get interleaved. Instead the kernel sends
+//! binary frames on the same port (or a TCP connection) and `bob monitor` decodes them,
+//! passing everything between frames through as console output. A frame is
+//!
+//! ```text
+//! STX | version | kind | payload length (u16 LE) | payload | CRC-32 (LE)
+//! ```
+//!
+//! with the CRC covering version through payload. STX never shows up in console text, so
+//! it marks the start of a frame. Frames of a version the host doesn't know are skipped
+//! with an error rather than misread. Integers in payloads are little endian, strings are
+//! UTF-8 and run to the end of the payload.
+
+use crate::snapshot::crc32;
+
+/// Start of a frame.
+pub const STX: u8 = 0x02;
+/// Bumped when a message's payload changes incompatibly.
+pub const VERSION: u8 = 1;
+/// Largest payload a frame carries.
+pub const MAX_PAYLOAD: usize = 512;
+const HEADER_SZ: usize = 5;
+/// Largest whole frame.
+pub const MAX_FRAME: usize = HEADER_SZ + MAX_PAYLOAD + 4;
+
+/// How far the kernel got booting, in order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    KernelEntry,
+    MemoryInit,
+    InterruptsInit,
+    DevicesInit,
+    ShellReady,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [Stage::KernelEntry, Stage::MemoryInit, Stage::InterruptsInit, Stage::DevicesInit, Stage::ShellReady];
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    Stage::KernelEntry => "kernel entry",
+	    Stage::MemoryInit => "memory init",
+	    Stage::InterruptsInit => "interrupts init",
+	    Stage::DevicesInit => "devices init",
+	    Stage::ShellReady => "shell ready",
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message<'a> {
+    /// A boot stage was reached, `ms` after kernel entry.
+    Boot { stage: Stage, ms: u32 },
+    Test { name: &'a str, outcome: Outcome, ms: u32 },
+    /// All tests have run.
+    TestsDone { passed: u32, failed: u32, ignored: u32 },
+    /// A file of `len` bytes follows in `FileData` messages.
+    FileStart { path: &'a str, len: u64 },
+    FileData(&'a [u8]),
+    /// The file is complete, with the CRC-32 of its contents.
+    FileEnd { crc: u32 },
+    /// The kernel is done and will power off, or wants the host to stop listening.
+    Exit { code: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtoErr {
+    /// A frame from a newer (or older) kernel.
+    Version(u8),
+    UnknownKind(u8),
+    Malformed,
+    Checksum,
+    /// The message doesn't fit in one frame, or the output buffer.
+    TooLong,
+}
+
+impl<'a> Message<'a> {
+    fn kind(&self) -> u8 {
+	match self {
+	    Message::Boot { .. } => 1,
+	    Message::Test { .. } => 2,
+	    Message::TestsDone { .. } => 3,
+	    Message::FileStart { .. } => 4,
+	    Message::FileData(_) => 5,
+	    Message::FileEnd { .. } => 6,
+	    Message::Exit { .. } => 7,
+	}
+    }
+
+    /// Write the message as a frame into `out`, returning the frame's length.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, ProtoErr> {
+	let mut payload = [0; MAX_PAYLOAD];
+	let len = {
+	    let mut w = Writer { buf: &mut payload, len: 0 };
+	    match *self {
+		Message::Boot { stage, ms } => {
+		    w.put(&[stage as u8])?;
+		    w.put(&ms.to_le_bytes())?;
+		},
+		Message::Test { name, outcome, ms } => {
+		    w.put(&[outcome as u8])?;
+		    w.put(&ms.to_le_bytes())?;
+		    w.put(name.as_bytes())?;
+		},
+		Message::TestsDone { passed, failed, ignored } => {
+		    for n in [passed, failed, ignored] {
+			w.put(&n.to_le_bytes())?;
+		    }
+		},
+		Message::FileStart { path, len } => {
+		    w.put(&len.to_le_bytes())?;
+		    w.put(path.as_bytes())?;
+		},
+		Message::FileData(data) => w.put(data)?,
+		Message::FileEnd { crc } => w.put(&crc.to_le_bytes())?,
+		Message::Exit { code } => w.put(&code.to_le_bytes())?,
+	    }
+	    w.len
+	};
+
+	let frame_len = HEADER_SZ + len + 4;
+	let frame = out.get_mut(..frame_len).ok_or(ProtoErr::TooLong)?;
+	frame[0] = STX;
+	frame[1] = VERSION;
+	frame[2] = self.kind();
+	frame[3..5].copy_from_slice(&(len as u16).to_le_bytes());
+	frame[HEADER_SZ..HEADER_SZ + len].copy_from_slice(&payload[..len]);
+	let crc = crc32(&frame[1..HEADER_SZ + len]);
+	frame[HEADER_SZ + len..].copy_from_slice(&crc.to_le_bytes());
+	Ok(frame_len)
+    }
+
+    /// Parse a frame's payload.
+    fn decode(kind: u8, p: &'a [u8]) -> Result<Self, ProtoErr> {
+	let u32_at = |i: usize| p.get(i..i + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).ok_or(ProtoErr::Malformed);
+	let str_from = |i: usize| p.get(i..).and_then(|b| core::str::from_utf8(b).ok()).ok_or(ProtoErr::Malformed);
+	Ok(match kind {
+	    1 => Message::Boot {
+		stage: *p.first().and_then(|s| Stage::ALL.get(*s as usize)).ok_or(ProtoErr::Malformed)?,
+		ms: u32_at(1)?,
+	    },
+	    2 => Message::Test {
+		outcome: match p.first() {
+		    Some(0) => Outcome::Passed,
+		    Some(1) => Outcome::Failed,
+		    Some(2) => Outcome::Ignored,
+		    _ => return Err(ProtoErr::Malformed),
+		},
+		ms: u32_at(1)?,
+		name: str_from(5)?,
+	    },
+	    3 => Message::TestsDone { passed: u32_at(0)?, failed: u32_at(4)?, ignored: u32_at(8)? },
+	    4 => Message::FileStart {
+		len: p.get(..8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).ok_or(ProtoErr::Malformed)?,
+		path: str_from(8)?,
+	    },
+	    5 => Message::FileData(p),
+	    6 => Message::FileEnd { crc: u32_at(0)? },
+	    7 => Message::Exit { code: u32_at(0)? },
+	    _ => return Err(ProtoErr::UnknownKind(kind)),
+	})
+    }
+}
+
+/// Appends to a fixed buffer.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), ProtoErr> {
+	let dst = self.buf.get_mut(self.len..self.len + bytes.len()).ok_or(ProtoErr::TooLong)?;
+	dst.copy_from_slice(bytes);
+	self.len += bytes.len();
+	Ok(())
+    }
+}
+
+/// What a byte from the port turned out to be.
+#[derive(Debug, PartialEq)]
+pub enum Feed<'a> {
+    /// Console output.
+    Console(u8),
+    /// Part of a frame.
+    Pending,
+    Message(Message<'a>),
+    /// A damaged or unknown frame was dropped.
+    Error(ProtoErr),
+}
+
+/// Splits a byte stream into console output and messages.
+pub struct Decoder {
+    buf: [u8; MAX_FRAME],
+    /// Bytes of the current frame so far, 0 outside a frame.
+    len: usize,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+	Self { buf: [0; MAX_FRAME], len: 0 }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Feed<'_> {
+	if self.len == 0 {
+	    if byte != STX {
+		return Feed::Console(byte);
+	    }
+	    self.buf[0] = byte;
+	    self.len = 1;
+	    return Feed::Pending;
+	}
+
+	self.buf[self.len] = byte;
+	self.len += 1;
+	if self.len < HEADER_SZ {
+	    return Feed::Pending;
+	}
+	let payload_len = u16::from_le_bytes([self.buf[3], self.buf[4]]) as usize;
+	if payload_len > MAX_PAYLOAD {
+	    // Most likely a stray STX in line noise, not a frame.
+	    self.len = 0;
+	    return Feed::Error(ProtoErr::TooLong);
+	}
+	let frame_len = HEADER_SZ + payload_len + 4;
+	if self.len < frame_len {
+	    return Feed::Pending;
+	}
+
+	self.len = 0;
+	let frame = &self.buf[..frame_len];
+	let crc = u32::from_le_bytes(frame[frame_len - 4..].try_into().unwrap());
+	if crc != crc32(&frame[1..frame_len - 4]) {
+	    return Feed::Error(ProtoErr::Checksum);
+	}
+	if frame[1] != VERSION {
+	    return Feed::Error(ProtoErr::Version(frame[1]));
+	}
+	match Message::decode(frame[2], &frame[HEADER_SZ..frame_len - 4]) {
+	    Ok(m) => Feed::Message(m),
+	    Err(e) => Feed::Error(e),
+	}
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+	let messages = [
+	    Message::Boot { stage: Stage::InterruptsInit, ms: 12 },
+	    Message::Test { name: "memory::frame::alloc", outcome: Outcome::Failed, ms: 3 },
+	    Message::TestsDone { passed: 10, failed: 1, ignored: 2 },
+	    Message::FileStart { path: "results.xml", len: 3 },
+	    Message::FileData(b"abc"),
+	    Message::FileEnd { crc: crc32(b"abc") },
+	    Message::Exit { code: 1 },
+	];
+	let mut d = Decoder::new();
+	for m in messages {
+	    let mut frame = [0; MAX_FRAME];
+	    let len = m.encode(&mut frame).unwrap();
+	    for b in &frame[..len - 1] {
+		assert_eq!(d.push(*b), Feed::Pending);
+	    }
+	    assert_eq!(d.push(frame[len - 1]), Feed::Message(m));
+	}
+    }
+
+    #[test]
+    fn console_and_damage() {
+	let mut frame = [0; MAX_FRAME];
+	let len = Message::Exit { code: 0 }.encode(&mut frame).unwrap();
+	let mut d = Decoder::new();
+	assert_eq!(d.push(b'o'), Feed::Console(b'o'));
+
+	let mut bad = frame;
+	bad[len - 1] ^= 1;
+	for b in &bad[..len - 1] {
+	    d.push(*b);
+	}
+	assert_eq!(d.push(bad[len - 1]), Feed::Error(ProtoErr::Checksum));
+	// Back to console output after the bad frame.
+	assert_eq!(d.push(b'k'), Feed::Console(b'k'));
+
+	let mut newer = frame;
+	newer[1] = VERSION + 1;
+	let crc = crc32(&newer[1..len - 4]);
+	newer[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+	for b in &newer[..len - 1] {
+	    d.push(*b);
+	}
+	assert_eq!(d.push(newer[len - 1]), Feed::Error(ProtoErr::Version(VERSION + 1)));
+
+	let mut small = [0; 4];
+	assert_eq!(Message::Exit { code: 0 }.encode(&mut small), Err(ProtoErr::TooLong));
+	assert_eq!(Message::FileData(&[0; MAX_PAYLOAD + 1]).encode(&mut frame), Err(ProtoErr::TooLong));
+    }
+}
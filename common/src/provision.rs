@@ -0,0 +1,286 @@
+//! Per-image settings for the first boot.
+//!
+//! bob can put a small provisioning partition in an image, holding settings that differ
+//! from one image to the next built from the same kernel: the hostname, the console, test
+//! parameters. The kernel looks for it by type on its first boot, applies what's there and
+//! marks it consumed so later boots leave it alone. The partition starts with one sector
+//! of header, the settings text follows:
+//!
+//! ```text
+//! offset
+//!   0  magic "YOYOPROV"
+//!   8  flags (u32): bit 0 consumed
+//!  12  text length (u32)
+//!  16  CRC32 of the text (u32)
+//!  20  zero to the end of the sector
+//! 512  text
+//! ```
+//!
+//! The text is in the settings file's TOML subset (see [`crate::settings`]), with
+//! `[system] hostname` on top of the settings that file knows:
+//!
+//! ```toml
+//! [system]
+//! hostname = "ci-runner-3"
+//!
+//! [console]
+//! output = "both"
+//! ```
+
+use core::fmt;
+
+use crate::dm::{BlockDevice, DmErr, Linear, SECTOR_SZ};
+use crate::error::{self, ErrorCode, Subsystem};
+use crate::gpt::{GptErr, Selector, Table};
+use crate::settings::{Parser, Problem, Settings, Value};
+use crate::snapshot::crc32;
+
+pub const MAGIC: [u8; 8] = *b"YOYOPROV";
+/// E3A1F09B-6F2C-4D58-9B7E-59C0B05F5052, in on-disk byte order.
+pub const TYPE: [u8; 16] = [0x9B, 0xF0, 0xA1, 0xE3, 0x2C, 0x6F, 0x58, 0x4D, 0x9B, 0x7E, 0x59, 0xC0, 0xB0, 0x5F, 0x50, 0x52];
+pub const HOSTNAME_MAX: usize = 64;
+
+const CONSUMED: u32 = 1 << 0;
+
+#[derive(Debug, PartialEq)]
+pub enum ProvisionErr {
+    Dm(DmErr),
+    Gpt(GptErr),
+    /// The partition doesn't start with a provisioning header.
+    NoHeader,
+    /// The text, this many bytes, doesn't fit the partition or the buffer for it.
+    TooLarge(u32),
+    Checksum,
+    NotUtf8,
+}
+
+impl ErrorCode for ProvisionErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Provision;
+
+    fn code(&self) -> (u16, u32) {
+	match self {
+	    Self::Dm(e) => (1, error::nest(e)),
+	    Self::Gpt(e) => (2, error::nest(e)),
+	    Self::NoHeader => (3, 0),
+	    Self::TooLarge(n) => (4, *n),
+	    Self::Checksum => (5, 0),
+	    Self::NotUtf8 => (6, 0),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => {
+		write!(f, "device: ")?;
+		error::describe_nested::<DmErr>(arg, f)
+	    },
+	    2 => {
+		write!(f, "gpt: ")?;
+		error::describe_nested::<GptErr>(arg, f)
+	    },
+	    3 => write!(f, "no provisioning header"),
+	    4 => write!(f, "{arg} bytes of settings don't fit"),
+	    5 => write!(f, "settings checksum doesn't match"),
+	    6 => write!(f, "settings aren't UTF-8"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+impl From<DmErr> for ProvisionErr {
+    fn from(e: DmErr) -> Self {
+	ProvisionErr::Dm(e)
+    }
+}
+
+impl From<GptErr> for ProvisionErr {
+    fn from(e: GptErr) -> Self {
+	ProvisionErr::Gpt(e)
+    }
+}
+
+/// The header sector for `text`, not yet consumed.
+pub fn header(text: &[u8]) -> [u8; SECTOR_SZ] {
+    let mut h = [0; SECTOR_SZ];
+    h[..8].copy_from_slice(&MAGIC);
+    h[12..16].copy_from_slice(&(text.len() as u32).to_le_bytes());
+    h[16..20].copy_from_slice(&crc32(text).to_le_bytes());
+    h
+}
+
+/// The text in the provisioning partition `dev`, read into `buf`. None once it's been
+/// consumed.
+pub fn read<'b, D: BlockDevice>(dev: &mut D, buf: &'b mut [u8]) -> Result<Option<&'b str>, ProvisionErr> {
+    let mut h = [0; SECTOR_SZ];
+    dev.read(0, &mut h)?;
+    let u32_at = |i: usize| u32::from_le_bytes(h[i..i + 4].try_into().unwrap());
+    if h[..8] != MAGIC {
+	return Err(ProvisionErr::NoHeader);
+    }
+    if u32_at(8) & CONSUMED != 0 {
+	return Ok(None);
+    }
+    let len = u32_at(12);
+    let whole = (len as usize).next_multiple_of(SECTOR_SZ);
+    if whole > buf.len() || (whole / SECTOR_SZ) as u64 >= dev.sectors() {
+	return Err(ProvisionErr::TooLarge(len));
+    }
+    dev.read(1, &mut buf[..whole])?;
+    let text = &buf[..len as usize];
+    if crc32(text) != u32_at(16) {
+	return Err(ProvisionErr::Checksum);
+    }
+    core::str::from_utf8(text).map(Some).map_err(|_| ProvisionErr::NotUtf8)
+}
+
+/// Set the consumed flag in `dev`'s header. The text stays, for looking at later.
+pub fn mark_consumed<D: BlockDevice>(dev: &mut D) -> Result<(), ProvisionErr> {
+    let mut h = [0; SECTOR_SZ];
+    dev.read(0, &mut h)?;
+    if h[..8] != MAGIC {
+	return Err(ProvisionErr::NoHeader);
+    }
+    let flags = u32::from_le_bytes(h[8..12].try_into().unwrap()) | CONSUMED;
+    h[8..12].copy_from_slice(&flags.to_le_bytes());
+    dev.write(0, &h)?;
+    Ok(())
+}
+
+/// What a provisioning partition sets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Provisioned {
+    pub settings: Settings,
+    hostname: [u8; HOSTNAME_MAX],
+    hostname_len: usize,
+}
+
+impl Provisioned {
+    /// Nothing provisioned yet, on top of `settings`.
+    pub fn new(settings: Settings) -> Self {
+	Self { settings, hostname: [0; HOSTNAME_MAX], hostname_len: 0 }
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+	match self.hostname_len {
+	    0 => None,
+	    n => core::str::from_utf8(&self.hostname[..n]).ok(),
+	}
+    }
+
+    /// Apply the settings in `text`, calling `report(line, problem)` for every line that's
+    /// skipped, as `Settings::apply` does.
+    pub fn apply<F: FnMut(usize, Problem)>(&mut self, text: &str, mut report: F) {
+	for item in Parser::new(text) {
+	    let r = match item {
+		Ok(item) => match (item.section, item.key, item.value) {
+		    ("system", "hostname", Value::Str(s)) => self.set_hostname(s),
+		    ("system", "hostname", _) => Err(Problem::Invalid),
+		    _ => self.settings.set(item),
+		}.map_err(|p| (item.line, p)),
+		Err(e) => Err(e),
+	    };
+	    if let Err((line, p)) = r {
+		report(line, p);
+	    }
+	}
+    }
+
+    /// Letters, digits, `-` and `.`, as a hostname can have.
+    fn set_hostname(&mut self, s: &str) -> Result<(), Problem> {
+	let valid = s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+	if s.is_empty() || s.len() > HOSTNAME_MAX || !valid {
+	    return Err(Problem::Invalid);
+	}
+	self.hostname[..s.len()].copy_from_slice(s.as_bytes());
+	self.hostname_len = s.len();
+	Ok(())
+    }
+}
+
+/// The first boot's step: find the provisioning partition on `disk`, apply its settings
+/// to `into` and mark it consumed. The text is read into `buf`. Returns whether there was
+/// anything to apply; a disk without the partition, or with one already consumed, has
+/// nothing.
+pub fn first_boot<D, F>(disk: &mut D, buf: &mut [u8], into: &mut Provisioned, report: F) -> Result<bool, ProvisionErr>
+where
+    D: BlockDevice,
+    F: FnMut(usize, Problem),
+{
+    let table = Table::read(disk)?;
+    let Some((_, e)) = table.find(disk, Selector::Type(TYPE))? else {
+	return Ok(false);
+    };
+    let mut part = Linear::partition(&mut *disk, e.first_lba, e.last_lba, table.lba_sz)?;
+    let Some(text) = read(&mut part, buf)? else {
+	return Ok(false);
+    };
+    into.apply(text, report);
+    mark_consumed(&mut part)?;
+    Ok(true)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use crate::gpt::tests::disk;
+    #[allow(unused_imports)]
+    use crate::settings::Console;
+
+    #[allow(dead_code)]
+    fn provisioned_disk(text: &str) -> crate::gpt::tests::MemDisk {
+	let mut d = disk(&[(crate::gpt::ESP_TYPE, [1; 16], 34, 39, "ESP"), (TYPE, [2; 16], 40, 47, "provision")]);
+	d.0[40 * SECTOR_SZ..41 * SECTOR_SZ].copy_from_slice(&header(text.as_bytes()));
+	d.0[41 * SECTOR_SZ..][..text.len()].copy_from_slice(text.as_bytes());
+	d
+    }
+
+    #[test]
+    fn applies_once() {
+	let text = "[system]\nhostname = \"ci-runner-3\"\n[console]\noutput = \"both\"\n[test]\nenabled = true\nretries = 3\n";
+	let mut d = provisioned_disk(text);
+	let mut buf = [0; 4 * SECTOR_SZ];
+	let mut p = Provisioned::new(Settings::default());
+	let mut problems = [None; 2];
+	let mut n = 0;
+	assert_eq!(first_boot(&mut d, &mut buf, &mut p, |line, problem| {
+	    problems[n] = Some((line, problem));
+	    n += 1;
+	}), Ok(true));
+	assert_eq!(p.hostname(), Some("ci-runner-3"));
+	assert_eq!(p.settings.console, Console::Both);
+	assert!(p.settings.test_mode);
+	assert_eq!(problems, [Some((7, Problem::Unknown)), None]);
+
+	// Consumed: the next boot finds nothing to do, the text is still there.
+	let mut again = Provisioned::new(Settings::default());
+	assert_eq!(first_boot(&mut d, &mut buf, &mut again, |_, _| {}), Ok(false));
+	assert_eq!(again.hostname(), None);
+	assert_eq!(&d.0[41 * SECTOR_SZ..][..9], b"[system]\n");
+
+	// A disk without the partition has nothing either.
+	let mut plain = disk(&[(crate::gpt::ESP_TYPE, [1; 16], 34, 39, "ESP")]);
+	assert_eq!(first_boot(&mut plain, &mut buf, &mut again, |_, _| {}), Ok(false));
+    }
+
+    #[test]
+    fn bad_partitions() {
+	let mut buf = [0; SECTOR_SZ];
+	let mut p = Provisioned::new(Settings::default());
+	let mut d = provisioned_disk("[system]\nhostname = \"bad name\"\n");
+	assert_eq!(first_boot(&mut d, &mut buf, &mut p, |line, problem| assert_eq!((line, problem), (2, Problem::Invalid))), Ok(true));
+	assert_eq!(p.hostname(), None);
+
+	let mut d = provisioned_disk("[system]\nhostname = \"x\"\n");
+	d.0[41 * SECTOR_SZ] ^= 1;
+	assert_eq!(first_boot(&mut d, &mut buf, &mut p, |_, _| {}), Err(ProvisionErr::Checksum));
+	d.0[40 * SECTOR_SZ] = 0;
+	assert_eq!(first_boot(&mut d, &mut buf, &mut p, |_, _| {}), Err(ProvisionErr::NoHeader));
+
+	// More text than the buffer holds.
+	let long = [b'#'; 600];
+	let mut d = provisioned_disk(core::str::from_utf8(&long).unwrap());
+	assert_eq!(first_boot(&mut d, &mut buf, &mut p, |_, _| {}), Err(ProvisionErr::TooLarge(600)));
+    }
+}
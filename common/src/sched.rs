@@ -0,0 +1,399 @@
+//! Scheduler policies and tunables that can be changed while the kernel runs.
+//!
+//! Each task has a `TaskSched`: a policy with Linux's numbering, so
+//! `sched_setscheduler` callers work unchanged, a nice value for the fair policies and a
+//! priority for the real-time ones. How long a task runs before the timer preempts it
+//! comes from `Tunables`, which can be read and written through the `sched_tunable`
+//! syscall or `/proc/sched`, so scheduling can be experimented with without rebuilding.
+//! `/proc/sched` lists the tunables as `name value` lines, then every task's parameters;
+//! writing `name value` lines to it sets tunables.
+//!
+//! Fair tasks get a slice scaled by their nice weight, from the same table Linux uses:
+//! each nice step is about 10% more or less CPU against a nice 0 task.
+//! Reference: sched(7), Linux kernel/sched/core.c sched_prio_to_weight
+
+use core::fmt::{self, Write};
+
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+pub const RT_PRIO_MIN: u32 = 1;
+pub const RT_PRIO_MAX: u32 = 99;
+/// Weight of a nice 0 task.
+pub const NICE_0_WEIGHT: u64 = 1024;
+/// What slices can be set to, in microseconds. Below 100us a switch costs about as much
+/// as the slice, above a second interactive tasks wait visibly.
+pub const SLICE_RANGE_US: (u64, u64) = (100, 1_000_000);
+
+/// Weights for nice -20 to 19.
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+pub fn nice_weight(nice: i32) -> u64 {
+    WEIGHTS[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedErr {
+    /// Unknown policy, tunable or syscall op. `-EINVAL`.
+    Invalid,
+    /// A value out of range for what it's set on. `-EINVAL`.
+    OutOfRange,
+    /// A real-time policy or a lower nice value asked for without the privilege. `-EPERM`.
+    NotPermitted,
+}
+
+/// Scheduling policies, numbered as `SCHED_*` in linux/sched.h.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// `SCHED_OTHER`, time shared by nice weight.
+    Normal,
+    /// Runs until it blocks or a higher priority task wakes.
+    Fifo,
+    /// As `Fifo` but round-robin among equal priorities, `rr_slice_us` each.
+    RoundRobin,
+    /// Like `Normal`, but assumed CPU bound and never favoured on wakeup.
+    Batch,
+    /// Only runs when nothing else will.
+    Idle,
+}
+
+impl Policy {
+    pub fn from_raw(policy: u64) -> Result<Self, SchedErr> {
+	match policy {
+	    0 => Ok(Self::Normal),
+	    1 => Ok(Self::Fifo),
+	    2 => Ok(Self::RoundRobin),
+	    3 => Ok(Self::Batch),
+	    5 => Ok(Self::Idle),
+	    _ => Err(SchedErr::Invalid),
+	}
+    }
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    Self::Normal => "normal",
+	    Self::Fifo => "fifo",
+	    Self::RoundRobin => "rr",
+	    Self::Batch => "batch",
+	    Self::Idle => "idle",
+	}
+    }
+
+    pub fn is_realtime(&self) -> bool {
+	matches!(self, Self::Fifo | Self::RoundRobin)
+    }
+}
+
+/// A task's scheduling parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TaskSched {
+    pub policy: Policy,
+    /// For the fair policies.
+    pub nice: i32,
+    /// For the real-time policies, 0 for the others.
+    pub rt_priority: u32,
+}
+
+impl Default for TaskSched {
+    fn default() -> Self {
+	Self { policy: Policy::Normal, nice: 0, rt_priority: 0 }
+    }
+}
+
+impl TaskSched {
+    /// `sched_setscheduler`: a real-time policy needs a priority from 1 to 99 and the
+    /// privilege, the others a priority of 0. The nice value is kept.
+    pub fn set_policy(&mut self, policy: Policy, priority: u32, privileged: bool) -> Result<(), SchedErr> {
+	let valid = match policy.is_realtime() {
+	    true => (RT_PRIO_MIN..=RT_PRIO_MAX).contains(&priority),
+	    false => priority == 0,
+	};
+	if !valid {
+	    return Err(SchedErr::OutOfRange);
+	}
+	if policy.is_realtime() && !privileged {
+	    return Err(SchedErr::NotPermitted);
+	}
+	self.policy = policy;
+	self.rt_priority = priority;
+	Ok(())
+    }
+
+    /// `setpriority`/`nice`: clamped to -20..=19 as Linux does, but only privileged tasks
+    /// may lower it.
+    pub fn set_nice(&mut self, nice: i32, privileged: bool) -> Result<(), SchedErr> {
+	let nice = nice.clamp(NICE_MIN, NICE_MAX);
+	if nice < self.nice && !privileged {
+	    return Err(SchedErr::NotPermitted);
+	}
+	self.nice = nice;
+	Ok(())
+    }
+}
+
+/// A scheduler setting, with its `sched_tunable` number and `/proc/sched` name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tunable {
+    /// The slice of a nice 0 task.
+    BaseSlice,
+    /// No slice is shorter, however high the nice value or many the tasks.
+    MinSlice,
+    /// Round-robin tasks' slice.
+    RrSlice,
+}
+
+pub const TUNABLES: [Tunable; 3] = [Tunable::BaseSlice, Tunable::MinSlice, Tunable::RrSlice];
+
+impl Tunable {
+    pub fn from_raw(id: u64) -> Result<Self, SchedErr> {
+	TUNABLES.get(id as usize).copied().ok_or(SchedErr::Invalid)
+    }
+
+    pub fn by_name(name: &str) -> Result<Self, SchedErr> {
+	TUNABLES.into_iter().find(|t| t.name() == name).ok_or(SchedErr::Invalid)
+    }
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    Self::BaseSlice => "base_slice_us",
+	    Self::MinSlice => "min_slice_us",
+	    Self::RrSlice => "rr_slice_us",
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tunables {
+    pub base_slice_us: u64,
+    pub min_slice_us: u64,
+    pub rr_slice_us: u64,
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+	Self { base_slice_us: 3000, min_slice_us: 750, rr_slice_us: 100_000 }
+    }
+}
+
+/// `sched_tunable(op, id, value)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunableOp {
+    Get(Tunable),
+    Set(Tunable, u64),
+}
+
+impl TunableOp {
+    pub fn from_raw(op: u64, id: u64, value: u64) -> Result<Self, SchedErr> {
+	let t = Tunable::from_raw(id)?;
+	match op {
+	    0 => Ok(Self::Get(t)),
+	    1 => Ok(Self::Set(t, value)),
+	    _ => Err(SchedErr::Invalid),
+	}
+    }
+}
+
+impl Tunables {
+    pub fn get(&self, t: Tunable) -> u64 {
+	match t {
+	    Tunable::BaseSlice => self.base_slice_us,
+	    Tunable::MinSlice => self.min_slice_us,
+	    Tunable::RrSlice => self.rr_slice_us,
+	}
+    }
+
+    /// Set `t`, keeping the minimum slice no longer than the base one.
+    pub fn set(&mut self, t: Tunable, value: u64) -> Result<(), SchedErr> {
+	let (min, max) = SLICE_RANGE_US;
+	let mut new = *self;
+	match t {
+	    Tunable::BaseSlice => new.base_slice_us = value,
+	    Tunable::MinSlice => new.min_slice_us = value,
+	    Tunable::RrSlice => new.rr_slice_us = value,
+	}
+	if !(min..=max).contains(&value) || new.min_slice_us > new.base_slice_us {
+	    return Err(SchedErr::OutOfRange);
+	}
+	*self = new;
+	Ok(())
+    }
+
+    /// How long `task` runs before it's preempted for another task of its policy, None
+    /// for FIFO tasks, which run until they block.
+    pub fn slice_us(&self, task: &TaskSched) -> Option<u64> {
+	match task.policy {
+	    Policy::Fifo => None,
+	    Policy::RoundRobin => Some(self.rr_slice_us),
+	    Policy::Idle => Some(self.min_slice_us),
+	    Policy::Normal | Policy::Batch => {
+		Some((self.base_slice_us * nice_weight(task.nice) / NICE_0_WEIGHT).max(self.min_slice_us))
+	    },
+	}
+    }
+
+    /// Render `/proc/sched`, `tasks` being each task's ID and parameters.
+    pub fn write_proc<W: Write>(&self, tasks: &[(u32, TaskSched)], w: &mut W) -> fmt::Result {
+	for t in TUNABLES {
+	    writeln!(w, "{} {}", t.name(), self.get(t))?;
+	}
+	writeln!(w, "\ntid policy nice prio slice_us")?;
+	for (tid, s) in tasks {
+	    write!(w, "{tid} {} {} {} ", s.policy.name(), s.nice, s.rt_priority)?;
+	    match self.slice_us(s) {
+		Some(us) => writeln!(w, "{us}")?,
+		None => writeln!(w, "-")?,
+	    }
+	}
+	Ok(())
+    }
+
+    /// A write to `/proc/sched`: `name value` lines, all of them applied or, on the first
+    /// bad one, none. Returns the line (counting from 1) and problem.
+    pub fn write_from_proc(&mut self, text: &str) -> Result<(), (usize, SchedErr)> {
+	let mut new = *self;
+	for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+	    let mut words = line.split_whitespace();
+	    let (Some(name), Some(value), None) = (words.next(), words.next(), words.next()) else {
+		return Err((i + 1, SchedErr::Invalid));
+	    };
+	    let t = Tunable::by_name(name).map_err(|e| (i + 1, e))?;
+	    let value = value.parse().map_err(|_| (i + 1, SchedErr::Invalid))?;
+	    new.set(t, value).map_err(|e| (i + 1, e))?;
+	}
+	*self = new;
+	Ok(())
+    }
+}
+
+/// What the `nice` tool was asked: `nice [-n N] COMMAND [ARGS]` runs a command with the
+/// adjustment, `nice [-n N] -p PID` applies it to a running task. N defaults to 10.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NiceArgs {
+    pub adjustment: i32,
+    pub target: NiceTarget,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NiceTarget {
+    /// The command starts at this index of the arguments.
+    Command(usize),
+    Pid(u32),
+}
+
+impl NiceArgs {
+    /// From the arguments after the program name.
+    pub fn parse(args: &[&str]) -> Option<Self> {
+	let mut adjustment = 10;
+	let mut i = 0;
+	while let Some(arg) = args.get(i) {
+	    match *arg {
+		"-n" => adjustment = args.get(i + 1)?.parse().ok()?,
+		"-p" => {
+		    let pid = args.get(i + 1)?.parse().ok()?;
+		    return (i + 2 == args.len()).then_some(Self { adjustment, target: NiceTarget::Pid(pid) });
+		},
+		"--" => return (i + 1 < args.len()).then_some(Self { adjustment, target: NiceTarget::Command(i + 1) }),
+		a if a.starts_with('-') => return None,
+		_ => return Some(Self { adjustment, target: NiceTarget::Command(i) }),
+	    }
+	    i += 2;
+	}
+	None
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    struct Buf<'a>(&'a mut [u8], usize);
+
+    impl Write for Buf<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.1 + s.len();
+	    self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.1 = end;
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn policies() {
+	let mut s = TaskSched::default();
+	assert_eq!(Policy::from_raw(4), Err(SchedErr::Invalid));
+	assert_eq!(s.set_policy(Policy::Fifo, 50, false), Err(SchedErr::NotPermitted));
+	assert_eq!(s.set_policy(Policy::Fifo, 0, true), Err(SchedErr::OutOfRange));
+	assert_eq!(s.set_policy(Policy::Batch, 1, true), Err(SchedErr::OutOfRange));
+	s.set_policy(Policy::from_raw(2).unwrap(), 99, true).unwrap();
+	assert_eq!((s.policy, s.rt_priority), (Policy::RoundRobin, 99));
+
+	s.set_nice(40, false).unwrap();
+	assert_eq!(s.nice, NICE_MAX);
+	assert_eq!(s.set_nice(0, false), Err(SchedErr::NotPermitted));
+	s.set_nice(-30, true).unwrap();
+	assert_eq!(s.nice, NICE_MIN);
+    }
+
+    #[test]
+    fn slices() {
+	let t = Tunables::default();
+	let task = |policy, nice| TaskSched { policy, nice, rt_priority: 0 };
+	assert_eq!(t.slice_us(&task(Policy::Normal, 0)), Some(3000));
+	assert_eq!(t.slice_us(&task(Policy::Normal, -5)), Some(3000 * 3121 / 1024));
+	// A nice 19 task would get 43us, the minimum stops it thrashing.
+	assert_eq!(t.slice_us(&task(Policy::Batch, 19)), Some(750));
+	assert_eq!(t.slice_us(&task(Policy::RoundRobin, 0)), Some(100_000));
+	assert_eq!(t.slice_us(&task(Policy::Fifo, 0)), None);
+	assert_eq!((nice_weight(-20), nice_weight(0), nice_weight(25)), (88761, NICE_0_WEIGHT, 15));
+    }
+
+    #[test]
+    fn tunables() {
+	let mut t = Tunables::default();
+	assert_eq!(TunableOp::from_raw(1, 0, 4000), Ok(TunableOp::Set(Tunable::BaseSlice, 4000)));
+	assert_eq!(TunableOp::from_raw(0, 3, 0), Err(SchedErr::Invalid));
+	t.set(Tunable::BaseSlice, 4000).unwrap();
+	assert_eq!(t.set(Tunable::MinSlice, 5000), Err(SchedErr::OutOfRange));
+	assert_eq!(t.set(Tunable::RrSlice, 50), Err(SchedErr::OutOfRange));
+	assert_eq!(t.get(Tunable::BaseSlice), 4000);
+
+	// One bad line and nothing changes.
+	assert_eq!(t.write_from_proc("min_slice_us 1000\nrr_slice_us fast\n"), Err((2, SchedErr::Invalid)));
+	assert_eq!(t.write_from_proc("quantum 1000\n"), Err((1, SchedErr::Invalid)));
+	assert_eq!(t.min_slice_us, 750);
+	t.write_from_proc("min_slice_us 1000\n\nrr_slice_us 20000\n").unwrap();
+	assert_eq!((t.min_slice_us, t.rr_slice_us), (1000, 20000));
+
+	let mut out = [0; 256];
+	let mut b = Buf(&mut out, 0);
+	let fifo = TaskSched { policy: Policy::Fifo, nice: 0, rt_priority: 10 };
+	t.write_proc(&[(1, TaskSched::default()), (7, fifo)], &mut b).unwrap();
+	let n = b.1;
+	assert_eq!(
+	    core::str::from_utf8(&out[..n]).unwrap(),
+	    "base_slice_us 4000\nmin_slice_us 1000\nrr_slice_us 20000\n\ntid policy nice prio slice_us\n1 normal 0 0 4000\n7 fifo 0 10 -\n"
+	);
+    }
+
+    #[test]
+    fn nice_args() {
+	assert_eq!(NiceArgs::parse(&["make", "-j4"]), Some(NiceArgs { adjustment: 10, target: NiceTarget::Command(0) }));
+	assert_eq!(NiceArgs::parse(&["-n", "-5", "make"]), Some(NiceArgs { adjustment: -5, target: NiceTarget::Command(2) }));
+	assert_eq!(NiceArgs::parse(&["-n", "3", "--", "-x"]), Some(NiceArgs { adjustment: 3, target: NiceTarget::Command(3) }));
+	assert_eq!(NiceArgs::parse(&["-n", "5", "-p", "42"]), Some(NiceArgs { adjustment: 5, target: NiceTarget::Pid(42) }));
+	assert_eq!(NiceArgs::parse(&["-p", "42", "extra"]), None);
+	assert_eq!(NiceArgs::parse(&["-n", "x", "make"]), None);
+	assert_eq!(NiceArgs::parse(&["-q"]), None);
+	assert_eq!(NiceArgs::parse(&[]), None);
+    }
+}
@@ -0,0 +1,160 @@
+//! Sharing one serial port between several channels (shell, log stream, gdbstub).
+//!
+//! Only one channel is attached to the port at a time. Its output goes out and the bytes
+//! typed go to it, the others' output is dropped (the log is kept in dmesg anyway). The
+//! user switches channels with an escape sequence, like screen or QEMU's monitor:
+//!
+//! - `Ctrl-A 0`..`Ctrl-A 9`: switch to that channel
+//! - `Ctrl-A n`: switch to the next channel
+//! - `Ctrl-A ?`: list the channels
+//! - `Ctrl-A Ctrl-A`: send a literal Ctrl-A
+//!
+//! Anything else after `Ctrl-A` is dropped.
+
+use core::fmt;
+
+/// Ctrl-A.
+pub const ESCAPE: u8 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    /// A byte for the active channel.
+    Byte { channel: usize, byte: u8 },
+    /// The active channel changed, the banner should be shown.
+    Switched(usize),
+    /// The channel list was asked for.
+    Help,
+    /// Part of an escape sequence, or a dropped byte.
+    None,
+}
+
+pub struct SerialMux<'a> {
+    names: &'a [&'a str],
+    active: usize,
+    escaped: bool,
+}
+
+impl<'a> SerialMux<'a> {
+    /// A mux over the named channels, starting on the first. Channels are numbered by
+    /// their position in `names`, only the first 10 can be picked by number.
+    pub fn new(names: &'a [&'a str]) -> Self {
+	assert!(!names.is_empty(), "a serial mux needs at least one channel");
+	Self {
+	    names,
+	    active: 0,
+	    escaped: false,
+	}
+    }
+
+    pub fn active(&self) -> usize {
+	self.active
+    }
+
+    /// Whether output from `channel` should be sent to the port.
+    pub fn is_visible(&self, channel: usize) -> bool {
+	channel == self.active
+    }
+
+    /// Handle a byte read from the port.
+    pub fn input(&mut self, byte: u8) -> Input {
+	if !self.escaped {
+	    if byte == ESCAPE {
+		self.escaped = true;
+		return Input::None;
+	    }
+	    return Input::Byte { channel: self.active, byte };
+	}
+
+	self.escaped = false;
+	match byte {
+	    ESCAPE => Input::Byte { channel: self.active, byte },
+	    b'0'..=b'9' if ((byte - b'0') as usize) < self.names.len() => self.switch((byte - b'0') as usize),
+	    b'n' => self.switch((self.active + 1) % self.names.len()),
+	    b'?' => Input::Help,
+	    _ => Input::None,
+	}
+    }
+
+    fn switch(&mut self, channel: usize) -> Input {
+	self.active = channel;
+	Input::Switched(channel)
+    }
+
+    /// Shown after switching to a channel.
+    pub fn write_banner<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+	write!(w, "\r\n[serial: {} {}, Ctrl-A ? for help]\r\n", self.active, self.names[self.active])
+    }
+
+    /// The channel list and key bindings.
+    pub fn write_help<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+	write!(w, "\r\n[serial channels:")?;
+	for (i, name) in self.names.iter().enumerate() {
+	    let marker = if i == self.active { "*" } else { "" };
+	    write!(w, " {i} {name}{marker}")?;
+	}
+	write!(w, "; Ctrl-A <n> switch, Ctrl-A n next, Ctrl-A Ctrl-A literal Ctrl-A]\r\n")
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// A small fixed buffer to format into.
+    #[allow(dead_code)]
+    struct Buf {
+	b: [u8; 256],
+	len: usize,
+    }
+
+    impl fmt::Write for Buf {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.len + s.len();
+	    self.b.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.len = end;
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn switching() {
+	let mut mux = SerialMux::new(&["shell", "log", "gdb"]);
+	assert_eq!(mux.input(b'x'), Input::Byte { channel: 0, byte: b'x' });
+
+	assert_eq!(mux.input(ESCAPE), Input::None);
+	assert_eq!(mux.input(b'2'), Input::Switched(2));
+	assert!(mux.is_visible(2) && !mux.is_visible(0));
+	assert_eq!(mux.input(b'x'), Input::Byte { channel: 2, byte: b'x' });
+
+	// Next wraps around.
+	mux.input(ESCAPE);
+	assert_eq!(mux.input(b'n'), Input::Switched(0));
+
+	// No channel 5, the sequence is dropped.
+	mux.input(ESCAPE);
+	assert_eq!(mux.input(b'5'), Input::None);
+	assert_eq!(mux.active(), 0);
+
+	mux.input(ESCAPE);
+	assert_eq!(mux.input(ESCAPE), Input::Byte { channel: 0, byte: ESCAPE });
+	mux.input(ESCAPE);
+	assert_eq!(mux.input(b'?'), Input::Help);
+    }
+
+    #[test]
+    fn banner_and_help() {
+	let mut mux = SerialMux::new(&["shell", "log"]);
+	mux.input(ESCAPE);
+	mux.input(b'1');
+
+	let mut buf = Buf { b: [0; 256], len: 0 };
+	mux.write_banner(&mut buf).unwrap();
+	assert_eq!(&buf.b[..buf.len], b"\r\n[serial: 1 log, Ctrl-A ? for help]\r\n");
+
+	buf.len = 0;
+	mux.write_help(&mut buf).unwrap();
+	let help = core::str::from_utf8(&buf.b[..buf.len]).unwrap();
+	assert!(help.contains(" 0 shell 1 log*;"));
+    }
+}
@@ -0,0 +1,263 @@
+//! Kernel settings from `\yoyo\config.toml` on the ESP.
+//!
+//! Log level, console and test mode can be changed by editing one file on the ESP, no
+//! rebuilt image or edited command line needed. The file is a small subset of TOML:
+//! `[section]` headers, `key = value` lines and `#` comments, values being strings in
+//! double quotes (no escapes), integers and booleans. That's all the settings need and
+//! it parses without allocating.
+//!
+//! ```toml
+//! [log]
+//! level = "debug"
+//!
+//! [console]
+//! output = "both"
+//!
+//! [test]
+//! enabled = true
+//! exit_on_panic = true
+//! ```
+//!
+//! A bad line or an unknown setting is reported and skipped, a typo in the file shouldn't
+//! keep the kernel from booting.
+
+/// Where the settings live on the ESP.
+pub const SETTINGS_PATH: &str = "\\yoyo\\config.toml";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(i64),
+    Bool(bool),
+}
+
+/// A `key = value` line with the section it's in, "" before the first header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Item<'a> {
+    /// Counting from 1.
+    pub line: usize,
+    pub section: &'a str,
+    pub key: &'a str,
+    pub value: Value<'a>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    /// Not a header, `key = value` or comment.
+    Syntax,
+    /// A value that isn't a quoted string, integer or boolean.
+    BadValue,
+    /// A setting the kernel doesn't know.
+    Unknown,
+    /// A known setting with a value of the wrong type or out of range.
+    Invalid,
+}
+
+/// The items of a settings file, or the problem with each line that isn't one. Lines are
+/// numbered from 1.
+pub struct Parser<'a> {
+    lines: core::iter::Enumerate<core::str::Lines<'a>>,
+    section: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(text: &'a str) -> Self {
+	Self { lines: text.lines().enumerate(), section: "" }
+    }
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<Item<'a>, (usize, Problem)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	for (i, line) in self.lines.by_ref() {
+	    let line = strip_comment(line).trim();
+	    if line.is_empty() {
+		continue;
+	    }
+	    if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+		self.section = section.trim();
+		continue;
+	    }
+	    let Some((key, value)) = line.split_once('=') else {
+		return Some(Err((i + 1, Problem::Syntax)));
+	    };
+	    let key = key.trim();
+	    if key.is_empty() {
+		return Some(Err((i + 1, Problem::Syntax)));
+	    }
+	    return Some(match parse_value(value.trim()) {
+		Some(value) => Ok(Item { line: i + 1, section: self.section, key, value }),
+		None => Err((i + 1, Problem::BadValue)),
+	    });
+	}
+	None
+    }
+}
+
+/// The line up to a `#` that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+	match c {
+	    '"' => quoted = !quoted,
+	    '#' if !quoted => return &line[..i],
+	    _ => {},
+	}
+    }
+    line
+}
+
+fn parse_value(v: &str) -> Option<Value<'_>> {
+    match v {
+	"true" => return Some(Value::Bool(true)),
+	"false" => return Some(Value::Bool(false)),
+	_ => {},
+    }
+    if let Some(s) = v.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+	return (!s.contains(['"', '\\'])).then_some(Value::Str(s));
+    }
+    // TOML allows underscores between digits.
+    let mut n: i64 = 0;
+    let (neg, digits) = match v.strip_prefix('-') {
+	Some(d) => (true, d),
+	None => (false, v.strip_prefix('+').unwrap_or(v)),
+    };
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') {
+	return None;
+    }
+    for c in digits.chars().filter(|c| *c != '_') {
+	n = n.checked_mul(10)?.checked_add(c.to_digit(10)? as i64)?;
+    }
+    Some(Value::Int(if neg { -n } else { n }))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn by_name(name: &str) -> Option<Self> {
+	match name {
+	    "error" => Some(Self::Error),
+	    "warn" => Some(Self::Warn),
+	    "info" => Some(Self::Info),
+	    "debug" => Some(Self::Debug),
+	    "trace" => Some(Self::Trace),
+	    _ => None,
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Console {
+    Serial,
+    Framebuffer,
+    Both,
+}
+
+impl Console {
+    pub fn by_name(name: &str) -> Option<Self> {
+	match name {
+	    "serial" => Some(Self::Serial),
+	    "framebuffer" => Some(Self::Framebuffer),
+	    "both" => Some(Self::Both),
+	    _ => None,
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub log_level: LogLevel,
+    pub console: Console,
+    /// Run the in-kernel tests instead of init.
+    pub test_mode: bool,
+    /// In test mode, exit QEMU with a failure on a panic instead of halting.
+    pub exit_on_panic: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+	Self {
+	    log_level: LogLevel::Info,
+	    console: Console::Serial,
+	    test_mode: false,
+	    exit_on_panic: false,
+	}
+    }
+}
+
+impl Settings {
+    /// Apply the settings in `text`, calling `report(line, problem)` for every line that's
+    /// skipped.
+    pub fn apply<F: FnMut(usize, Problem)>(&mut self, text: &str, mut report: F) {
+	for item in Parser::new(text) {
+	    match item {
+		Ok(item) => {
+		    if let Err(p) = self.set(item) {
+			report(item.line, p);
+		    }
+		},
+		Err((line, p)) => report(line, p),
+	    }
+	}
+    }
+
+    pub(crate) fn set(&mut self, item: Item) -> Result<(), Problem> {
+	match (item.section, item.key, item.value) {
+	    ("log", "level", Value::Str(s)) => self.log_level = LogLevel::by_name(s).ok_or(Problem::Invalid)?,
+	    ("console", "output", Value::Str(s)) => self.console = Console::by_name(s).ok_or(Problem::Invalid)?,
+	    ("test", "enabled", Value::Bool(b)) => self.test_mode = b,
+	    ("test", "exit_on_panic", Value::Bool(b)) => self.exit_on_panic = b,
+	    ("log", "level", _) | ("console", "output", _) | ("test", "enabled" | "exit_on_panic", _) => return Err(Problem::Invalid),
+	    _ => return Err(Problem::Unknown),
+	}
+	Ok(())
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+	let text = "top = 1\n# comment\n[log]\n  level = \"debug\" # trailing\n\n[ test ]\nenabled=true\nn = -1_000\nhash = \"a#b\"\n";
+	let mut p = Parser::new(text);
+	assert_eq!(p.next(), Some(Ok(Item { line: 1, section: "", key: "top", value: Value::Int(1) })));
+	assert_eq!(p.next(), Some(Ok(Item { line: 4, section: "log", key: "level", value: Value::Str("debug") })));
+	assert_eq!(p.next(), Some(Ok(Item { line: 7, section: "test", key: "enabled", value: Value::Bool(true) })));
+	assert_eq!(p.next(), Some(Ok(Item { line: 8, section: "test", key: "n", value: Value::Int(-1000) })));
+	assert_eq!(p.next(), Some(Ok(Item { line: 9, section: "test", key: "hash", value: Value::Str("a#b") })));
+	assert_eq!(p.next(), None);
+
+	let mut p = Parser::new("junk\n= 1\nx = yes\ny = 99999999999999999999\nz = \"esc\\\"\"\n");
+	assert_eq!(p.next(), Some(Err((1, Problem::Syntax))));
+	assert_eq!(p.next(), Some(Err((2, Problem::Syntax))));
+	assert_eq!(p.next(), Some(Err((3, Problem::BadValue))));
+	assert_eq!(p.next(), Some(Err((4, Problem::BadValue))));
+	assert_eq!(p.next(), Some(Err((5, Problem::BadValue))));
+    }
+
+    #[test]
+    fn applies_settings() {
+	let text = "[log]\nlevel = \"trace\"\n\n[console]\noutput = \"tty\"\n[test]\n# on for CI\nenabled = true\nexit_on_panic = 1\ncolour = true\n";
+	let mut s = Settings::default();
+	let mut problems = [None; 4];
+	let mut n = 0;
+	s.apply(text, |line, p| {
+	    problems[n] = Some((line, p));
+	    n += 1;
+	});
+	assert_eq!(s, Settings { log_level: LogLevel::Trace, console: Console::Serial, test_mode: true, exit_on_panic: false });
+	assert_eq!(problems, [Some((5, Problem::Invalid)), Some((9, Problem::Invalid)), Some((10, Problem::Unknown)), None]);
+	assert!(LogLevel::Debug > LogLevel::Warn);
+    }
+}
@@ -0,0 +1,199 @@
+//! The shutdown sequence behind `sys_reboot`.
+//!
+//! `reboot(magic1, magic2, cmd)` takes Linux's magic numbers, so a stray call with junk
+//! arguments can't take the machine down, and Linux's command values, so libc's `reboot()`
+//! works as is. Every command goes through the same stages in order: stop userspace so
+//! nothing dirties the caches again, write back the page cache and FAT metadata, flush
+//! and quiesce the block devices, and only then power off, restart or halt. A stage that
+//! fails is logged and the sequence carries on; a machine that won't power off because
+//! one write failed is worse than an image with one stale block.
+//! Reference: reboot(2)
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const MAGIC1: u64 = 0xfee1_dead;
+/// Any of these is accepted as the second magic number.
+pub const MAGIC2: [u64; 4] = [672274793, 85072278, 369367448, 537993216];
+
+/// `cmd` values, as in linux/reboot.h.
+pub mod cmd {
+    pub const RESTART: u64 = 0x0123_4567;
+    pub const HALT: u64 = 0xCDEF_0123;
+    pub const POWER_OFF: u64 = 0x4321_FEDC;
+}
+
+/// QEMU's (and Bochs') ACPI PM1a control port and the value that powers off, for
+/// `Steps::finish` until there's an ACPI parser to find the real one.
+pub const QEMU_POWER_OFF: (u16, u16) = (0x604, 0x2000);
+pub const BOCHS_POWER_OFF: (u16, u16) = (0xB004, 0x2000);
+/// Pulsing the reset line through the 8042 keyboard controller.
+pub const KBC_RESET: (u16, u8) = (0x64, 0xFE);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RebootErr {
+    /// Wrong magic numbers, `-EINVAL`.
+    BadMagic,
+    /// An unknown or unsupported `cmd`, `-EINVAL`.
+    BadCmd(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Restart,
+    Halt,
+    PowerOff,
+}
+
+impl Action {
+    /// Decode the arguments of `sys_reboot`.
+    pub fn from_syscall(magic1: u64, magic2: u64, command: u64) -> Result<Self, RebootErr> {
+	// Userspace passes ints, the upper halves of the registers are junk.
+	let (magic1, magic2, command) = (magic1 & 0xFFFF_FFFF, magic2 & 0xFFFF_FFFF, command & 0xFFFF_FFFF);
+	if magic1 != MAGIC1 || !MAGIC2.contains(&magic2) {
+	    return Err(RebootErr::BadMagic);
+	}
+	match command {
+	    cmd::RESTART => Ok(Action::Restart),
+	    cmd::HALT => Ok(Action::Halt),
+	    cmd::POWER_OFF => Ok(Action::PowerOff),
+	    c => Err(RebootErr::BadCmd(c)),
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    StopUserspace,
+    SyncCaches,
+    QuiesceDevices,
+    Finish,
+}
+
+/// What the kernel does at each stage.
+pub trait Steps {
+    type Err;
+
+    /// Stop scheduling user tasks, so nothing dirties the caches after they're synced.
+    fn stop_userspace(&mut self) -> Result<(), Self::Err>;
+
+    /// Write back dirty pages and the FAT tables and directory entries.
+    fn sync_caches(&mut self) -> Result<(), Self::Err>;
+
+    /// Flush the block devices' write caches and stop taking requests.
+    fn quiesce_devices(&mut self) -> Result<(), Self::Err>;
+
+    /// Power off, restart or halt. Only returns if that didn't work, with why.
+    fn finish(&mut self, action: Action) -> Self::Err;
+}
+
+/// Set by the first shutdown, so a second `reboot` or a Ctrl-Alt-Del during one doesn't
+/// start the sequence again halfway through the first.
+pub struct InProgress(AtomicBool);
+
+impl Default for InProgress {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl InProgress {
+    pub const fn new() -> Self {
+	Self(AtomicBool::new(false))
+    }
+
+    /// True for the first caller only.
+    pub fn begin(&self) -> bool {
+	!self.0.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// Run the sequence for `action`, `log` seeing the outcome of each stage. Returns only
+/// if `finish` did, with the machine still running.
+pub fn run<S, L>(steps: &mut S, action: Action, mut log: L)
+where
+    S: Steps,
+    L: FnMut(Stage, Result<(), S::Err>),
+{
+    log(Stage::StopUserspace, steps.stop_userspace());
+    log(Stage::SyncCaches, steps.sync_caches());
+    log(Stage::QuiesceDevices, steps.quiesce_devices());
+    let e = steps.finish(action);
+    log(Stage::Finish, Err(e));
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn decodes_syscall() {
+	assert_eq!(Action::from_syscall(MAGIC1, MAGIC2[0], cmd::POWER_OFF), Ok(Action::PowerOff));
+	assert_eq!(Action::from_syscall(0xffff_ffff_fee1_dead, MAGIC2[3], cmd::RESTART), Ok(Action::Restart));
+	assert_eq!(Action::from_syscall(MAGIC1, 1, cmd::HALT), Err(RebootErr::BadMagic));
+	assert_eq!(Action::from_syscall(0xdead, MAGIC2[1], cmd::HALT), Err(RebootErr::BadMagic));
+	assert_eq!(Action::from_syscall(MAGIC1, MAGIC2[2], 0x89AB_CDEF), Err(RebootErr::BadCmd(0x89AB_CDEF)));
+    }
+
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct Recorder {
+	done: [Option<Stage>; 4],
+	n: usize,
+	finished: Option<Action>,
+    }
+
+    #[allow(dead_code)]
+    impl Recorder {
+	fn step(&mut self, s: Stage) {
+	    self.done[self.n] = Some(s);
+	    self.n += 1;
+	}
+    }
+
+    impl Steps for Recorder {
+	type Err = &'static str;
+
+	fn stop_userspace(&mut self) -> Result<(), &'static str> {
+	    self.step(Stage::StopUserspace);
+	    Ok(())
+	}
+
+	fn sync_caches(&mut self) -> Result<(), &'static str> {
+	    self.step(Stage::SyncCaches);
+	    Err("write error")
+	}
+
+	fn quiesce_devices(&mut self) -> Result<(), &'static str> {
+	    self.step(Stage::QuiesceDevices);
+	    Ok(())
+	}
+
+	fn finish(&mut self, action: Action) -> &'static str {
+	    self.step(Stage::Finish);
+	    self.finished = Some(action);
+	    "no ACPI"
+	}
+    }
+
+    #[test]
+    fn runs_every_stage_in_order() {
+	let mut r = Recorder::default();
+	let mut failed = [None; 2];
+	let mut n = 0;
+	run(&mut r, Action::PowerOff, |stage, result| {
+	    if let Err(e) = result {
+		failed[n] = Some((stage, e));
+		n += 1;
+	    }
+	});
+	// A failed sync doesn't stop the devices being quiesced.
+	assert_eq!(r.done, [Some(Stage::StopUserspace), Some(Stage::SyncCaches), Some(Stage::QuiesceDevices), Some(Stage::Finish)]);
+	assert_eq!(failed, [Some((Stage::SyncCaches, "write error")), Some((Stage::Finish, "no ACPI"))]);
+	assert_eq!(r.finished, Some(Action::PowerOff));
+
+	let once = InProgress::new();
+	assert!(once.begin());
+	assert!(!once.begin());
+    }
+}
@@ -0,0 +1,242 @@
+//! Snapshot export: files from a running system as a stream of checksummed text lines.
+//!
+//! The kernel writes the files of a mounted filesystem out over the serial port (or a TCP
+//! connection) and `bob snapshot` on the host puts them back together, so a test run can
+//! hand over its artifacts without shutting down. Text lines survive being interleaved
+//! with console output on the same port: the host only looks at lines with `PREFIX`.
+//! Each line is
+//!
+//! ```text
+//! @snap <seq> <kind> <payload hex, or - if empty> <crc32 hex>
+//! ```
+//!
+//! with `seq` counting up from 0 so lost lines are noticed, and the CRC-32 covering
+//! everything between the prefix and the space before it. The stream is a `Begin`, then
+//! for each file a `File` header followed by its contents in `Data` lines, then `End`.
+
+use core::fmt::{self, Write};
+
+pub const PREFIX: &str = "@snap ";
+/// Most file content bytes in one `Data` line.
+pub const CHUNK_SZ: usize = 48;
+/// Longest path a `File` record can carry.
+pub const MAX_PATH: usize = 255;
+/// Largest payload of any record, a `File` with its length and a path.
+pub const MAX_PAYLOAD: usize = 8 + MAX_PATH;
+/// Longest line body: sequence number, kind and hex payload with their separators.
+const MAX_BODY: usize = 10 + 3 + 2 * MAX_PAYLOAD;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Record<'a> {
+    Begin,
+    /// A file follows with `len` bytes of data. Paths are relative to the exported mount.
+    File { path: &'a str, len: u64 },
+    Data(&'a [u8]),
+    End { files: u32 },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapErr {
+    /// The line isn't a snapshot record at all.
+    NotARecord,
+    Malformed,
+    Checksum,
+    /// A path longer than `MAX_PATH`, or data longer than `CHUNK_SZ`.
+    TooLong,
+    /// The writer failed.
+    Write,
+}
+
+/// Writes records as lines with increasing sequence numbers.
+#[derive(Default)]
+pub struct Encoder {
+    seq: u32,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+	Self { seq: 0 }
+    }
+
+    /// Write one record as a line.
+    pub fn write<W: Write>(&mut self, w: &mut W, record: &Record) -> Result<(), SnapErr> {
+	let mut payload = [0; MAX_PAYLOAD];
+	let (kind, len) = match *record {
+	    Record::Begin => ('B', 0),
+	    Record::File { path, len } => {
+		if path.len() > MAX_PATH {
+		    return Err(SnapErr::TooLong);
+		}
+		payload[..8].copy_from_slice(&len.to_le_bytes());
+		payload[8..8 + path.len()].copy_from_slice(path.as_bytes());
+		('F', 8 + path.len())
+	    },
+	    Record::Data(d) => {
+		if d.len() > CHUNK_SZ {
+		    return Err(SnapErr::TooLong);
+		}
+		payload[..d.len()].copy_from_slice(d);
+		('D', d.len())
+	    },
+	    Record::End { files } => {
+		payload[..4].copy_from_slice(&files.to_le_bytes());
+		('E', 4)
+	    },
+	};
+
+	let mut body = LineBuf { buf: [0; MAX_BODY], len: 0 };
+	write!(body, "{} {kind} ", self.seq).map_err(|_| SnapErr::Write)?;
+	if len == 0 {
+	    body.write_str("-").map_err(|_| SnapErr::Write)?;
+	}
+	for b in &payload[..len] {
+	    write!(body, "{b:02x}").map_err(|_| SnapErr::Write)?;
+	}
+	let body = body.as_str();
+	writeln!(w, "{PREFIX}{body} {:08x}", crc32(body.as_bytes())).map_err(|_| SnapErr::Write)?;
+	self.seq += 1;
+	Ok(())
+    }
+
+    /// Write a whole file: its header and contents.
+    pub fn write_file<W: Write>(&mut self, w: &mut W, path: &str, contents: &[u8]) -> Result<(), SnapErr> {
+	self.write(w, &Record::File { path, len: contents.len() as u64 })?;
+	for chunk in contents.chunks(CHUNK_SZ) {
+	    self.write(w, &Record::Data(chunk))?;
+	}
+	Ok(())
+    }
+}
+
+/// Parse a line read from the port, with or without its line ending. The payload is
+/// decoded into `buf`, which the record borrows from. Returns the sequence number too.
+pub fn decode<'b>(line: &str, buf: &'b mut [u8; MAX_PAYLOAD]) -> Result<(u32, Record<'b>), SnapErr> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    // Console output may have come before it on the same line.
+    let start = line.find(PREFIX).ok_or(SnapErr::NotARecord)?;
+    let (body, crc) = line[start + PREFIX.len()..].rsplit_once(' ').ok_or(SnapErr::Malformed)?;
+    if u32::from_str_radix(crc, 16).map_err(|_| SnapErr::Malformed)? != crc32(body.as_bytes()) {
+	return Err(SnapErr::Checksum);
+    }
+
+    let mut fields = body.split(' ');
+    let (Some(seq), Some(kind), Some(hex), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+	return Err(SnapErr::Malformed);
+    };
+    let seq = seq.parse().map_err(|_| SnapErr::Malformed)?;
+    let hex = if hex == "-" { "" } else { hex };
+    if hex.len() > 2 * MAX_PAYLOAD || !hex.len().is_multiple_of(2) {
+	return Err(SnapErr::Malformed);
+    }
+    let len = hex.len() / 2;
+    for (i, b) in buf[..len].iter_mut().enumerate() {
+	*b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| SnapErr::Malformed)?;
+    }
+    let payload = &buf[..len];
+
+    let record = match (kind, len) {
+	("B", 0) => Record::Begin,
+	("F", 8..) => Record::File {
+	    len: u64::from_le_bytes(payload[..8].try_into().unwrap()),
+	    path: core::str::from_utf8(&payload[8..]).map_err(|_| SnapErr::Malformed)?,
+	},
+	("D", ..=CHUNK_SZ) => Record::Data(payload),
+	("E", 4) => Record::End { files: u32::from_le_bytes(payload.try_into().unwrap()) },
+	_ => return Err(SnapErr::Malformed),
+    };
+    Ok((seq, record))
+}
+
+/// A line body being formatted, before it's checksummed.
+struct LineBuf {
+    buf: [u8; MAX_BODY],
+    len: usize,
+}
+
+impl LineBuf {
+    fn as_str(&self) -> &str {
+	// Only ever written through write_str.
+	core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	let dst = self.buf.get_mut(self.len..self.len + s.len()).ok_or(fmt::Error)?;
+	dst.copy_from_slice(s.as_bytes());
+	self.len += s.len();
+	Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), the same one zlib and GPT use.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+/// Carry on `crc`, the CRC-32 of what came before, over `bytes`. For data that isn't in
+/// one piece.
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in bytes {
+	crc ^= *b as u32;
+	for _ in 0..8 {
+	    crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+	}
+    }
+    !crc
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Lines written by the encoder, in a fixed buffer.
+    #[allow(dead_code)]
+    struct Out {
+	buf: [u8; 2048],
+	len: usize,
+    }
+
+    impl Write for Out {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+	    self.len += s.len();
+	    Ok(())
+	}
+    }
+
+    #[test]
+    fn round_trip() {
+	let mut out = Out { buf: [0; 2048], len: 0 };
+	let mut enc = Encoder::new();
+	let contents = [0x5A; 50];
+	enc.write(&mut out, &Record::Begin).unwrap();
+	enc.write_file(&mut out, "logs/test.txt", &contents).unwrap();
+	enc.write(&mut out, &Record::End { files: 1 }).unwrap();
+
+	let text = core::str::from_utf8(&out.buf[..out.len]).unwrap();
+	let mut lines = text.lines();
+	let mut buf = [0; MAX_PAYLOAD];
+	assert_eq!(lines.next(), Some("@snap 0 B - 6474c1dc"));
+	assert_eq!(decode(lines.next().unwrap(), &mut buf), Ok((1, Record::File { path: "logs/test.txt", len: 50 })));
+	assert_eq!(decode(lines.next().unwrap(), &mut buf), Ok((2, Record::Data(&[0x5A; CHUNK_SZ]))));
+	assert_eq!(decode(lines.next().unwrap(), &mut buf), Ok((3, Record::Data(&[0x5A; 2]))));
+	assert_eq!(decode(lines.next().unwrap(), &mut buf), Ok((4, Record::End { files: 1 })));
+	assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn noise_and_damage() {
+	let mut buf = [0; MAX_PAYLOAD];
+	assert_eq!(decode("[ 1.23] kernel log line", &mut buf), Err(SnapErr::NotARecord));
+	// Console output glued on the front doesn't matter, a flipped bit does.
+	assert_eq!(decode("$ @snap 0 B - 6474c1dc\r\n", &mut buf), Ok((0, Record::Begin)));
+	assert_eq!(decode("@snap 0 B - 6474c1dd", &mut buf), Err(SnapErr::Checksum));
+	assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+	let mut out = Out { buf: [0; 2048], len: 0 };
+	assert_eq!(Encoder::new().write(&mut out, &Record::Data(&[0; CHUNK_SZ + 1])), Err(SnapErr::TooLong));
+    }
+}
@@ -0,0 +1,416 @@
+//! Squashfs 4.0 on-disk structures, shared with the kernel's read-only root support.
+//!
+//! Everything here works on bytes that are already in memory (and for metadata, already
+//! decompressed), so it needs neither an allocator nor a block device. Finding and
+//! inflating the blocks is up to the caller.
+//! Format reference: https://dr-emann.github.io/squashfs/squashfs.html
+
+pub const MAGIC: u32 = 0x73717368;
+pub const SUPERBLOCK_SZ: usize = 96;
+pub const METADATA_SZ: usize = 8192;
+pub const COMPRESSION_GZIP: u16 = 1;
+
+/// No table of this kind (xattr or export) in the image.
+pub const NO_TABLE: u64 = u64::MAX;
+pub const NO_FRAGMENT: u32 = u32::MAX;
+pub const UNCOMPRESSED_METADATA: u16 = 0x8000;
+pub const UNCOMPRESSED_DATA: u32 = 1 << 24;
+
+pub const INODE_DIR: u16 = 1;
+pub const INODE_FILE: u16 = 2;
+pub const INODE_SYMLINK: u16 = 3;
+pub const INODE_LDIR: u16 = 8;
+pub const INODE_LFILE: u16 = 9;
+
+const INODE_HEADER_SZ: usize = 16;
+const DIR_HEADER_SZ: usize = 12;
+const DIR_ENTRY_SZ: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum SquashfsErr {
+    InputBounds,
+    MagicNumber,
+    Version,
+    BlockSize,
+    Compression,
+    InodeType(u16),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Superblock {
+    pub inodes: u32,
+    pub mkfs_time: u32,
+    pub block_size: u32,
+    pub fragments: u32,
+    pub compression: u16,
+    pub flags: u16,
+    pub no_ids: u16,
+    pub root_inode: u64,
+    pub bytes_used: u64,
+    pub id_table_start: u64,
+    pub xattr_id_table_start: u64,
+    pub inode_table_start: u64,
+    pub directory_table_start: u64,
+    pub fragment_table_start: u64,
+    pub lookup_table_start: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InodeHeader {
+    pub inode_type: u16,
+    pub mode: u16,
+    pub uid_idx: u16,
+    pub gid_idx: u16,
+    pub mtime: u32,
+    pub inode_number: u32,
+}
+
+/// An inode, basic and extended variants are folded together.
+#[derive(Debug, PartialEq)]
+pub enum Inode<'a> {
+    Dir {
+	/// Offset of the listing's metadata block in the directory table.
+	start_block: u32,
+	/// Offset of the listing in the uncompressed block.
+	offset: u16,
+	/// Listing size, without the 3 bytes counted for . and ..
+	listing_size: u32,
+	parent_inode: u32,
+    },
+    File {
+	/// Location of the first data block in the image.
+	start_block: u64,
+	file_size: u64,
+	fragment: u32,
+	fragment_offset: u32,
+	/// Little endian u32 sizes of each data block.
+	block_sizes: &'a [u8],
+    },
+    Symlink {
+	target: &'a [u8],
+    },
+}
+
+/// A directory entry with its inode reference resolved from the header it's under.
+#[derive(Debug, PartialEq)]
+pub struct DirEntry<'a> {
+    pub name: &'a [u8],
+    /// Metadata block offset in the inode table << 16 | offset in that block.
+    pub inode_ref: u64,
+    pub inode_number: u32,
+    pub inode_type: u16,
+}
+
+fn le_u16(b: &[u8], off: usize) -> Result<u16, SquashfsErr> {
+    let s = b.get(off..off + 2).ok_or(SquashfsErr::InputBounds)?;
+    Ok(u16::from_le_bytes([s[0], s[1]]))
+}
+
+fn le_u32(b: &[u8], off: usize) -> Result<u32, SquashfsErr> {
+    let s = b.get(off..off + 4).ok_or(SquashfsErr::InputBounds)?;
+    Ok(u32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+}
+
+fn le_u64(b: &[u8], off: usize) -> Result<u64, SquashfsErr> {
+    Ok(le_u32(b, off)? as u64 | (le_u32(b, off + 4)? as u64) << 32)
+}
+
+impl Superblock {
+    pub fn parse(b: &[u8]) -> Result<Self, SquashfsErr> {
+	if b.len() < SUPERBLOCK_SZ {
+	    return Err(SquashfsErr::InputBounds);
+	}
+	if le_u32(b, 0)? != MAGIC {
+	    return Err(SquashfsErr::MagicNumber);
+	}
+	if le_u16(b, 28)? != 4 || le_u16(b, 30)? != 0 {
+	    return Err(SquashfsErr::Version);
+	}
+
+	let block_size = le_u32(b, 12)?;
+	let block_log = le_u16(b, 22)?;
+	if !(12..=20).contains(&block_log) || block_size != 1 << block_log {
+	    return Err(SquashfsErr::BlockSize);
+	}
+
+	let compression = le_u16(b, 20)?;
+	if compression != COMPRESSION_GZIP {
+	    return Err(SquashfsErr::Compression);
+	}
+
+	Ok(Self {
+	    inodes: le_u32(b, 4)?,
+	    mkfs_time: le_u32(b, 8)?,
+	    block_size,
+	    fragments: le_u32(b, 16)?,
+	    compression,
+	    flags: le_u16(b, 24)?,
+	    no_ids: le_u16(b, 26)?,
+	    root_inode: le_u64(b, 32)?,
+	    bytes_used: le_u64(b, 40)?,
+	    id_table_start: le_u64(b, 48)?,
+	    xattr_id_table_start: le_u64(b, 56)?,
+	    inode_table_start: le_u64(b, 64)?,
+	    directory_table_start: le_u64(b, 72)?,
+	    fragment_table_start: le_u64(b, 80)?,
+	    lookup_table_start: le_u64(b, 88)?,
+	})
+    }
+}
+
+/// Split a metadata block header into the stored size and whether it's compressed.
+pub fn metadata_header(h: u16) -> (usize, bool) {
+    ((h & !UNCOMPRESSED_METADATA) as usize, h & UNCOMPRESSED_METADATA == 0)
+}
+
+impl InodeHeader {
+    pub fn parse(b: &[u8]) -> Result<Self, SquashfsErr> {
+	Ok(Self {
+	    inode_type: le_u16(b, 0)?,
+	    mode: le_u16(b, 2)?,
+	    uid_idx: le_u16(b, 4)?,
+	    gid_idx: le_u16(b, 6)?,
+	    mtime: le_u32(b, 8)?,
+	    inode_number: le_u32(b, 12)?,
+	})
+    }
+}
+
+impl<'a> Inode<'a> {
+    /// Parse the inode at the start of `b`. `block_size` is needed to work out how many
+    /// block sizes follow a file inode. `b` has to include them and symlink targets.
+    pub fn parse(b: &'a [u8], block_size: u32) -> Result<(InodeHeader, Self), SquashfsErr> {
+	let hdr = InodeHeader::parse(b)?;
+	let body = b.get(INODE_HEADER_SZ..).ok_or(SquashfsErr::InputBounds)?;
+
+	let inode = match hdr.inode_type {
+	    INODE_DIR => Self::Dir {
+		start_block: le_u32(body, 0)?,
+		listing_size: (le_u16(body, 8)? as u32).saturating_sub(3),
+		offset: le_u16(body, 10)?,
+		parent_inode: le_u32(body, 12)?,
+	    },
+	    INODE_LDIR => Self::Dir {
+		listing_size: le_u32(body, 4)?.saturating_sub(3),
+		start_block: le_u32(body, 8)?,
+		parent_inode: le_u32(body, 12)?,
+		offset: le_u16(body, 18)?,
+	    },
+	    INODE_FILE => {
+		let fragment = le_u32(body, 4)?;
+		let file_size = le_u32(body, 12)? as u64;
+		Self::File {
+		    start_block: le_u32(body, 0)? as u64,
+		    file_size,
+		    fragment,
+		    fragment_offset: le_u32(body, 8)?,
+		    block_sizes: block_list(body, 16, file_size, fragment, block_size)?,
+		}
+	    },
+	    INODE_LFILE => {
+		let fragment = le_u32(body, 28)?;
+		let file_size = le_u64(body, 8)?;
+		Self::File {
+		    start_block: le_u64(body, 0)?,
+		    file_size,
+		    fragment,
+		    fragment_offset: le_u32(body, 32)?,
+		    block_sizes: block_list(body, 40, file_size, fragment, block_size)?,
+		}
+	    },
+	    INODE_SYMLINK => {
+		let len = le_u32(body, 4)? as usize;
+		Self::Symlink {
+		    target: body.get(8..8 + len).ok_or(SquashfsErr::InputBounds)?,
+		}
+	    },
+	    t => return Err(SquashfsErr::InodeType(t)),
+	};
+
+	Ok((hdr, inode))
+    }
+}
+
+/// The block size list of a file inode. The tail end of a file lives in a fragment, if
+/// it has one, instead of a block of its own.
+fn block_list(body: &[u8], off: usize, file_size: u64, fragment: u32, block_size: u32) -> Result<&[u8], SquashfsErr> {
+    let block_size = block_size as u64;
+    let blocks = if fragment == NO_FRAGMENT {
+	file_size.div_ceil(block_size)
+    } else {
+	file_size / block_size
+    };
+    let len = usize::try_from(blocks * 4).map_err(|_| SquashfsErr::InputBounds)?;
+    body.get(off..off.checked_add(len).ok_or(SquashfsErr::InputBounds)?).ok_or(SquashfsErr::InputBounds)
+}
+
+/// Iterates over a directory listing (already decompressed). Entries come out sorted by
+/// name, which `lookup` relies on.
+pub struct DirEntries<'a> {
+    listing: &'a [u8],
+    /// Entries left under the current header.
+    remaining: u32,
+    start_block: u32,
+    base_inode: u32,
+}
+
+impl<'a> DirEntries<'a> {
+    pub fn new(listing: &'a [u8]) -> Self {
+	Self {
+	    listing,
+	    remaining: 0,
+	    start_block: 0,
+	    base_inode: 0,
+	}
+    }
+
+    fn next_entry(&mut self) -> Result<DirEntry<'a>, SquashfsErr> {
+	if self.remaining == 0 {
+	    // Stored one less than the count, a corrupt u32::MAX would wrap.
+	    self.remaining = le_u32(self.listing, 0)?.checked_add(1).ok_or(SquashfsErr::InputBounds)?;
+	    self.start_block = le_u32(self.listing, 4)?;
+	    self.base_inode = le_u32(self.listing, 8)?;
+	    self.listing = &self.listing[DIR_HEADER_SZ..];
+	}
+
+	let offset = le_u16(self.listing, 0)?;
+	let delta = le_u16(self.listing, 2)? as i16;
+	let inode_type = le_u16(self.listing, 4)?;
+	let name_len = le_u16(self.listing, 6)? as usize + 1;
+	let end = DIR_ENTRY_SZ + name_len;
+	let name = self.listing.get(DIR_ENTRY_SZ..end).ok_or(SquashfsErr::InputBounds)?;
+
+	self.listing = &self.listing[end..];
+	self.remaining -= 1;
+	Ok(DirEntry {
+	    name,
+	    inode_ref: (self.start_block as u64) << 16 | offset as u64,
+	    inode_number: self.base_inode.wrapping_add_signed(delta as i32),
+	    inode_type,
+	})
+    }
+}
+
+impl<'a> Iterator for DirEntries<'a> {
+    type Item = Result<DirEntry<'a>, SquashfsErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+	if self.listing.is_empty() {
+	    return None;
+	}
+
+	let e = self.next_entry();
+	if e.is_err() {
+	    // Don't keep going over a corrupt listing.
+	    self.listing = &[];
+	}
+	Some(e)
+    }
+}
+
+/// Find `name` in a directory listing.
+pub fn lookup<'a>(listing: &'a [u8], name: &[u8]) -> Result<Option<DirEntry<'a>>, SquashfsErr> {
+    for e in DirEntries::new(listing) {
+	let e = e?;
+	match e.name.cmp(name) {
+	    core::cmp::Ordering::Equal => return Ok(Some(e)),
+	    // Sorted, so it's not further along.
+	    core::cmp::Ordering::Greater => return Ok(None),
+	    core::cmp::Ordering::Less => {},
+	}
+    }
+    Ok(None)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parse_superblock() {
+	let mut b = [0u8; SUPERBLOCK_SZ];
+	b[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+	b[4..8].copy_from_slice(&5u32.to_le_bytes());
+	b[12..16].copy_from_slice(&(128u32 * 1024).to_le_bytes());
+	b[20..22].copy_from_slice(&COMPRESSION_GZIP.to_le_bytes());
+	b[22..24].copy_from_slice(&17u16.to_le_bytes());
+	b[28..30].copy_from_slice(&4u16.to_le_bytes());
+	b[32..40].copy_from_slice(&0x1234_0056u64.to_le_bytes());
+	b[88..96].copy_from_slice(&NO_TABLE.to_le_bytes());
+
+	let sb = Superblock::parse(&b).unwrap();
+	assert_eq!(sb.inodes, 5);
+	assert_eq!(sb.root_inode, 0x1234_0056);
+	assert_eq!(sb.lookup_table_start, NO_TABLE);
+
+	let mut bad = b;
+	bad[22..24].copy_from_slice(&16u16.to_le_bytes());
+	assert_eq!(Superblock::parse(&bad), Err(SquashfsErr::BlockSize));
+	let mut bad = b;
+	bad[0] = 0;
+	assert_eq!(Superblock::parse(&bad), Err(SquashfsErr::MagicNumber));
+	assert_eq!(Superblock::parse(&b[..50]), Err(SquashfsErr::InputBounds));
+    }
+
+    #[test]
+    fn parse_file_inode() {
+	let mut b = [0u8; 40];
+	b[0..2].copy_from_slice(&INODE_FILE.to_le_bytes());
+	b[12..16].copy_from_slice(&7u32.to_le_bytes());
+	b[16..20].copy_from_slice(&96u32.to_le_bytes());
+	b[20..24].copy_from_slice(&3u32.to_le_bytes());
+	b[24..28].copy_from_slice(&100u32.to_le_bytes());
+	// One full 4KiB block plus 10 bytes in fragment 3.
+	b[28..32].copy_from_slice(&4106u32.to_le_bytes());
+	b[32..36].copy_from_slice(&(4096 | UNCOMPRESSED_DATA).to_le_bytes());
+
+	let (hdr, inode) = Inode::parse(&b, 4096).unwrap();
+	assert_eq!(hdr.inode_number, 7);
+	assert_eq!(inode, Inode::File {
+	    start_block: 96,
+	    file_size: 4106,
+	    fragment: 3,
+	    fragment_offset: 100,
+	    block_sizes: &b[32..36],
+	});
+
+	// Without the fragment the tail needs a block, which isn't there.
+	b[20..24].copy_from_slice(&NO_FRAGMENT.to_le_bytes());
+	assert_eq!(Inode::parse(&b[..36], 4096), Err(SquashfsErr::InputBounds));
+    }
+
+    #[allow(dead_code)]
+    fn entry(listing: &mut [u8], at: usize, offset: u16, delta: i16, name: &[u8]) -> usize {
+	listing[at..at + 2].copy_from_slice(&offset.to_le_bytes());
+	listing[at + 2..at + 4].copy_from_slice(&delta.to_le_bytes());
+	listing[at + 4..at + 6].copy_from_slice(&INODE_FILE.to_le_bytes());
+	listing[at + 6..at + 8].copy_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+	listing[at + 8..at + 8 + name.len()].copy_from_slice(name);
+	at + 8 + name.len()
+    }
+
+    #[test]
+    fn directory_lookup() {
+	let mut l = [0u8; 64];
+	// Header: 3 entries, inode block 0x20, base inode 10
+	l[0..4].copy_from_slice(&2u32.to_le_bytes());
+	l[4..8].copy_from_slice(&0x20u32.to_le_bytes());
+	l[8..12].copy_from_slice(&10u32.to_le_bytes());
+	let at = entry(&mut l, 12, 0, -1, b"bin");
+	let at = entry(&mut l, at, 40, 0, b"etc");
+	let at = entry(&mut l, at, 80, 1, b"usr");
+	let l = &l[..at];
+
+	assert_eq!(DirEntries::new(l).count(), 3);
+	let e = lookup(l, b"etc").unwrap().unwrap();
+	assert_eq!(e.inode_ref, 0x20 << 16 | 40);
+	assert_eq!(e.inode_number, 10);
+	assert_eq!(lookup(l, b"usr").unwrap().unwrap().inode_number, 11);
+	assert_eq!(lookup(l, b"bin").unwrap().unwrap().inode_number, 9);
+	assert_eq!(lookup(l, b"dev").unwrap(), None);
+	assert_eq!(lookup(&l[..20], b"usr"), Err(SquashfsErr::InputBounds));
+	// A count that wraps, found by fuzzing.
+	assert_eq!(lookup(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x8a], b"usr"), Err(SquashfsErr::InputBounds));
+    }
+}
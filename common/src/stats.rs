@@ -0,0 +1,158 @@
+//! Live system statistics, served as JSON over HTTP.
+//!
+//! With the kernel's `http-stats` feature a tiny responder answers `GET /stats` on a TCP
+//! port, so a long stress test can be watched from the host with nothing more than
+//! `curl` in a loop. It's deliberately not a web server: one request per connection, no
+//! keep-alive, no chunking, and anything but `GET /stats` gets an error status.
+
+use core::fmt::{self, Write};
+
+/// Port the kernel listens on for stats requests.
+pub const STATS_PORT: u16 = 8042;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    /// Bytes held by the kernel heap.
+    pub heap_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TaskStats {
+    pub total: u32,
+    pub runnable: u32,
+    /// Context switches since boot.
+    pub switches: u64,
+}
+
+/// A snapshot of the system, gathered when a request comes in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats<'a> {
+    pub uptime_ms: u64,
+    pub memory: MemoryStats,
+    pub tasks: TaskStats,
+    /// Interrupts taken per vector, summed over the CPUs. Vectors with none left out.
+    pub interrupts: &'a [(u8, u64)],
+}
+
+impl Stats<'_> {
+    pub fn write_json<W: Write>(&self, w: &mut W) -> fmt::Result {
+	let m = &self.memory;
+	let t = &self.tasks;
+	write!(w, "{{\"uptime_ms\":{},", self.uptime_ms)?;
+	write!(w, "\"memory\":{{\"total_bytes\":{},\"free_bytes\":{},\"heap_bytes\":{}}},", m.total_bytes, m.free_bytes, m.heap_bytes)?;
+	write!(w, "\"tasks\":{{\"total\":{},\"runnable\":{},\"switches\":{}}},", t.total, t.runnable, t.switches)?;
+	w.write_str("\"interrupts\":{")?;
+	for (i, (vector, count)) in self.interrupts.iter().enumerate() {
+	    let sep = if i == 0 { "" } else { "," };
+	    write!(w, "{sep}\"{vector}\":{count}")?;
+	}
+	w.write_str("}}")
+    }
+}
+
+/// Answer one HTTP request. `request` is what has been read from the connection, at least
+/// the request line. `stats` is only called for a request it's needed for.
+pub fn respond<'a, W: Write>(request: &[u8], stats: impl FnOnce() -> Stats<'a>, w: &mut W) -> fmt::Result {
+    let line = request.split(|b| *b == b'\n').next().unwrap_or(&[]);
+    let line = core::str::from_utf8(line).unwrap_or("").trim_end_matches('\r');
+    let mut parts = line.split(' ');
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    if !parts.next().is_some_and(|v| v.starts_with("HTTP/1.")) {
+	return status(w, "400 Bad Request");
+    }
+    if method != "GET" {
+	return status(w, "405 Method Not Allowed");
+    }
+    // Query strings are ignored, so `/stats?t=...` can be used to defeat caches.
+    if path.split('?').next() != Some("/stats") {
+	return status(w, "404 Not Found");
+    }
+
+    let stats = stats();
+    let mut len = Counter(0);
+    stats.write_json(&mut len)?;
+    write!(w, "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", len.0)?;
+    stats.write_json(w)
+}
+
+fn status<W: Write>(w: &mut W, status: &str) -> fmt::Result {
+    write!(w, "HTTP/1.0 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+/// Counts what's written, for the Content-Length.
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+	self.0 += s.len();
+	Ok(())
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// A fixed buffer to format a response into.
+    #[allow(dead_code)]
+    struct Buf {
+	b: [u8; 512],
+	len: usize,
+    }
+
+    impl Buf {
+	#[allow(dead_code)]
+	fn as_str(&self) -> &str {
+	    core::str::from_utf8(&self.b[..self.len]).unwrap()
+	}
+    }
+
+    impl Write for Buf {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+	    let end = self.len + s.len();
+	    self.b.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+	    self.len = end;
+	    Ok(())
+	}
+    }
+
+    #[allow(dead_code)]
+    fn stats() -> Stats<'static> {
+	Stats {
+	    uptime_ms: 1500,
+	    memory: MemoryStats { total_bytes: 128 << 20, free_bytes: 100 << 20, heap_bytes: 4096 },
+	    tasks: TaskStats { total: 3, runnable: 1, switches: 42 },
+	    interrupts: &[(32, 1500), (33, 7)],
+	}
+    }
+
+    #[test]
+    fn stats_json() {
+	let mut b = Buf { b: [0; 512], len: 0 };
+	respond(b"GET /stats?t=1 HTTP/1.1\r\nHost: yoyo\r\n\r\n", stats, &mut b).unwrap();
+	let (head, body) = b.as_str().split_once("\r\n\r\n").unwrap();
+	assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+	assert_eq!(body, concat!(
+	    r#"{"uptime_ms":1500,"memory":{"total_bytes":134217728,"free_bytes":104857600,"heap_bytes":4096},"#,
+	    r#""tasks":{"total":3,"runnable":1,"switches":42},"interrupts":{"32":1500,"33":7}}"#,
+	));
+	assert_eq!(body.len(), 173);
+	assert!(head.contains("Content-Length: 173\r\n"));
+    }
+
+    #[test]
+    fn errors() {
+	let status_of = |req: &[u8]| {
+	    let mut b = Buf { b: [0; 512], len: 0 };
+	    respond(req, || panic!("stats aren't needed"), &mut b).unwrap();
+	    <[u8; 3]>::try_from(&b.b[9..12]).unwrap()
+	};
+	assert_eq!(&status_of(b"GET / HTTP/1.1\r\n"), b"404");
+	assert_eq!(&status_of(b"POST /stats HTTP/1.1\r\n"), b"405");
+	assert_eq!(&status_of(b"\x16\x03\x01"), b"400");
+    }
+}
@@ -0,0 +1,350 @@
+//! Threads: tasks sharing an address space and file table, after Linux's clone(2).
+//!
+//! A thread is created with `clone(flags, stack, parent_tid, child_tid, tls)` and the
+//! flags a `std::thread`-style shim passes: `CLONE_VM | CLONE_FS | CLONE_FILES |
+//! CLONE_SIGHAND | CLONE_THREAD`, plus `CLONE_SETTLS` for the new thread's FS base and
+//! the TID flags. `CloneArgs::from_syscall` checks them with Linux's rules and only takes
+//! thread clones, a new process comes from exec.
+//!
+//! Joining works as in Linux: with `CLONE_CHILD_CLEARTID` the kernel zeroes the TID word
+//! at `child_tid` when the thread exits and wakes one futex waiter on it, so `join` is a
+//! `FUTEX_WAIT` loop on that word in userspace. `ThreadGroup` tracks a process's threads
+//! and says what an exit has to do, `Futexes` keeps the waiters.
+//! Reference: clone(2), futex(2), set_tid_address(2)
+
+use crate::uaccess::{UserSlice, USER_END};
+
+/// `flags` bits, as in linux/sched.h. The low byte is the exit signal, unused by threads.
+pub mod flags {
+    pub const CLONE_VM: u64 = 0x0000_0100;
+    pub const CLONE_FS: u64 = 0x0000_0200;
+    pub const CLONE_FILES: u64 = 0x0000_0400;
+    pub const CLONE_SIGHAND: u64 = 0x0000_0800;
+    pub const CLONE_THREAD: u64 = 0x0001_0000;
+    pub const CLONE_SYSVSEM: u64 = 0x0004_0000;
+    pub const CLONE_SETTLS: u64 = 0x0008_0000;
+    pub const CLONE_PARENT_SETTID: u64 = 0x0010_0000;
+    pub const CLONE_CHILD_CLEARTID: u64 = 0x0020_0000;
+    pub const CLONE_CHILD_SETTID: u64 = 0x0100_0000;
+    pub const EXIT_SIGNAL: u64 = 0xFF;
+
+    /// What every thread clone has to pass.
+    pub const THREAD: u64 = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+    pub const SUPPORTED: u64 = THREAD | CLONE_SYSVSEM | CLONE_SETTLS | CLONE_PARENT_SETTID
+	| CLONE_CHILD_CLEARTID | CLONE_CHILD_SETTID | EXIT_SIGNAL;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloneErr {
+    /// Flags that don't go together, like `CLONE_THREAD` without `CLONE_SIGHAND`, or a
+    /// null stack. `-EINVAL`.
+    Invalid,
+    /// Flags the kernel doesn't do (yet), or a clone that isn't a thread. `-ENOSYS`.
+    Unsupported(u64),
+    /// A TID pointer or TLS base outside user space. `-EFAULT`.
+    Fault,
+    /// The thread group is full. `-EAGAIN`.
+    Again,
+}
+
+/// A decoded thread clone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CloneArgs {
+    /// The new thread's stack pointer.
+    pub stack: u64,
+    /// FS base for the new thread, else it inherits the parent's.
+    pub tls: Option<u64>,
+    /// Where to store the new TID in the parent and in the child.
+    pub parent_tid: Option<UserSlice>,
+    pub child_tid: Option<UserSlice>,
+    /// Zeroed and futex-woken when the thread exits.
+    pub clear_tid: Option<UserSlice>,
+}
+
+impl CloneArgs {
+    /// The x86_64 argument order: flags, stack, parent_tid, child_tid, tls.
+    pub fn from_syscall(f: u64, stack: u64, parent_tid: u64, child_tid: u64, tls: u64) -> Result<Self, CloneErr> {
+	use flags::*;
+	if f & !SUPPORTED != 0 {
+	    return Err(CloneErr::Unsupported(f & !SUPPORTED));
+	}
+	// Linux's own consistency rules come first, so errors match its.
+	if f & CLONE_THREAD != 0 && f & CLONE_SIGHAND == 0 || f & CLONE_SIGHAND != 0 && f & CLONE_VM == 0 {
+	    return Err(CloneErr::Invalid);
+	}
+	if f & THREAD != THREAD {
+	    return Err(CloneErr::Unsupported(THREAD & !f));
+	}
+	// A thread sharing the parent's stack would trample it on the first call.
+	if stack == 0 || stack > USER_END {
+	    return Err(CloneErr::Invalid);
+	}
+	let tid_ptr = |set: bool, addr: u64| -> Result<Option<UserSlice>, CloneErr> {
+	    if !set {
+		return Ok(None);
+	    }
+	    UserSlice::new(addr, 4).map(Some).map_err(|_| CloneErr::Fault)
+	};
+	let tls = match f & CLONE_SETTLS {
+	    0 => None,
+	    _ if tls >= USER_END => return Err(CloneErr::Fault),
+	    _ => Some(tls),
+	};
+	Ok(Self {
+	    stack,
+	    tls,
+	    parent_tid: tid_ptr(f & CLONE_PARENT_SETTID != 0, parent_tid)?,
+	    child_tid: tid_ptr(f & CLONE_CHILD_SETTID != 0, child_tid)?,
+	    clear_tid: tid_ptr(f & CLONE_CHILD_CLEARTID != 0, child_tid)?,
+	})
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thread {
+    pub tid: u32,
+    /// From `CLONE_CHILD_CLEARTID` or `set_tid_address`.
+    pub clear_tid: Option<UserSlice>,
+}
+
+/// What the kernel does when a thread exits, in order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExitActions {
+    /// Write a 0 u32 here, ignoring faults, then wake one futex waiter on it.
+    pub clear_tid: Option<UserSlice>,
+    /// It was the last thread: tear down the address space and file table and report the
+    /// process's exit to its parent.
+    pub last: bool,
+}
+
+/// A process's threads, at most `N`. The first one's TID is the process ID, for as long
+/// as the process lives.
+pub struct ThreadGroup<const N: usize> {
+    tgid: u32,
+    threads: [Option<Thread>; N],
+}
+
+impl<const N: usize> ThreadGroup<N> {
+    pub const fn new(leader: u32) -> Self {
+	let mut threads = [None; N];
+	threads[0] = Some(Thread { tid: leader, clear_tid: None });
+	Self { tgid: leader, threads }
+    }
+
+    /// The process ID.
+    pub fn tgid(&self) -> u32 {
+	self.tgid
+    }
+
+    pub fn len(&self) -> usize {
+	self.threads.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.len() == 0
+    }
+
+    /// Add the thread `clone` created as `tid`.
+    pub fn add(&mut self, tid: u32, args: &CloneArgs) -> Result<(), CloneErr> {
+	let slot = self.threads.iter_mut().find(|t| t.is_none()).ok_or(CloneErr::Again)?;
+	*slot = Some(Thread { tid, clear_tid: args.clear_tid });
+	Ok(())
+    }
+
+    /// `set_tid_address`.
+    pub fn set_clear_tid(&mut self, tid: u32, addr: Option<UserSlice>) {
+	if let Some(t) = self.threads.iter_mut().flatten().find(|t| t.tid == tid) {
+	    t.clear_tid = addr;
+	}
+    }
+
+    /// `tid` exited. None if it isn't one of the group's.
+    pub fn exit(&mut self, tid: u32) -> Option<ExitActions> {
+	let slot = self.threads.iter_mut().find(|t| t.is_some_and(|t| t.tid == tid))?;
+	let thread = slot.take()?;
+	Some(ExitActions { clear_tid: thread.clear_tid, last: self.is_empty() })
+    }
+}
+
+/// Futex operations, as in linux/futex.h.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FutexOp {
+    /// Sleep if the word still holds `val`.
+    Wait { val: u32 },
+    /// Wake up to `n` waiters.
+    Wake { n: u32 },
+}
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+/// Process-private futex, the only kind there is: no memory is shared between processes.
+pub const FUTEX_PRIVATE_FLAG: u64 = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FutexErr {
+    /// The word didn't hold the expected value. `-EAGAIN`.
+    Again,
+    /// Unaligned address. `-EINVAL`.
+    Invalid,
+    /// An op other than wait or wake. `-ENOSYS`.
+    Unsupported(u64),
+    /// No room for another waiter. `-ENOMEM`.
+    Full,
+}
+
+impl FutexOp {
+    pub fn from_raw(op: u64, val: u64) -> Result<Self, FutexErr> {
+	match op & !FUTEX_PRIVATE_FLAG {
+	    FUTEX_WAIT => Ok(Self::Wait { val: val as u32 }),
+	    FUTEX_WAKE => Ok(Self::Wake { n: val as u32 }),
+	    op => Err(FutexErr::Unsupported(op)),
+	}
+    }
+}
+
+/// A futex word: the address space it's in and its address there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FutexKey {
+    pub mm: u64,
+    pub addr: u64,
+}
+
+impl FutexKey {
+    pub fn new(mm: u64, addr: u64) -> Result<Self, FutexErr> {
+	if !addr.is_multiple_of(4) || UserSlice::new(addr, 4).is_err() {
+	    return Err(FutexErr::Invalid);
+	}
+	Ok(Self { mm, addr })
+    }
+}
+
+/// Tasks sleeping on futex words, at most `N` at once. Wakes go in the order the waits
+/// came in.
+pub struct Futexes<const N: usize> {
+    waiters: [Option<(FutexKey, u32, u64)>; N],
+    next: u64,
+}
+
+impl<const N: usize> Default for Futexes<N> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl<const N: usize> Futexes<N> {
+    pub const fn new() -> Self {
+	Self { waiters: [None; N], next: 0 }
+    }
+
+    /// `tid` waits on `key` if the word, read by the caller with the table locked so a
+    /// wake can't slip in between, still holds `expected`. The caller then blocks `tid`.
+    pub fn wait(&mut self, key: FutexKey, tid: u32, word: u32, expected: u32) -> Result<(), FutexErr> {
+	if word != expected {
+	    return Err(FutexErr::Again);
+	}
+	let slot = self.waiters.iter_mut().find(|w| w.is_none()).ok_or(FutexErr::Full)?;
+	*slot = Some((key, tid, self.next));
+	self.next += 1;
+	Ok(())
+    }
+
+    /// Take up to `n` of the waiters on `key`, oldest first, handing each to `wake`.
+    /// Returns how many were woken.
+    pub fn wake<F: FnMut(u32)>(&mut self, key: FutexKey, n: u32, mut wake: F) -> u32 {
+	let mut woken = 0;
+	while woken < n {
+	    let oldest = self.waiters.iter_mut()
+		.filter(|w| w.is_some_and(|(k, _, _)| k == key))
+		.min_by_key(|w| w.map_or(u64::MAX, |(_, _, seq)| seq));
+	    let Some((_, tid, _)) = oldest.and_then(|w| w.take()) else {
+		break;
+	    };
+	    wake(tid);
+	    woken += 1;
+	}
+	woken
+    }
+
+    /// `tid` stopped waiting without a wake: a timeout, a signal or its exit.
+    pub fn cancel(&mut self, tid: u32) {
+	for w in self.waiters.iter_mut().filter(|w| w.is_some_and(|(_, t, _)| t == tid)) {
+	    *w = None;
+	}
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+    #[allow(unused_imports)]
+    use super::flags::*;
+
+    #[test]
+    fn decodes_clone() {
+	let f = THREAD | CLONE_SYSVSEM | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+	let args = CloneArgs::from_syscall(f, 0x7000_0000, 0x1000, 0x2000, 0x3000).unwrap();
+	assert_eq!(args.stack, 0x7000_0000);
+	assert_eq!(args.tls, Some(0x3000));
+	assert_eq!(args.parent_tid.map(|s| s.addr()), Some(0x1000));
+	assert_eq!(args.child_tid, None);
+	assert_eq!(args.clear_tid.map(|s| s.addr()), Some(0x2000));
+
+	assert_eq!(CloneArgs::from_syscall(THREAD & !CLONE_SIGHAND, 0x1000, 0, 0, 0), Err(CloneErr::Invalid));
+	assert_eq!(CloneArgs::from_syscall(CLONE_SIGHAND, 0x1000, 0, 0, 0), Err(CloneErr::Invalid));
+	// fork and vfork style clones aren't threads.
+	assert_eq!(CloneArgs::from_syscall(17, 0x1000, 0, 0, 0), Err(CloneErr::Unsupported(THREAD)));
+	assert_eq!(CloneArgs::from_syscall(THREAD | 0x2000_0000, 0x1000, 0, 0, 0), Err(CloneErr::Unsupported(0x2000_0000)));
+	assert_eq!(CloneArgs::from_syscall(THREAD, 0, 0, 0, 0), Err(CloneErr::Invalid));
+	assert_eq!(CloneArgs::from_syscall(THREAD | CLONE_SETTLS, 0x1000, 0, 0, USER_END), Err(CloneErr::Fault));
+	assert_eq!(CloneArgs::from_syscall(THREAD | CLONE_CHILD_SETTID, 0x1000, 0, 0, 0), Err(CloneErr::Fault));
+    }
+
+    #[test]
+    fn exits_and_joins() {
+	let args = CloneArgs::from_syscall(THREAD | CLONE_CHILD_CLEARTID, 0x8000, 0, 0x2000, 0).unwrap();
+	let mut g = ThreadGroup::<2>::new(10);
+	g.add(11, &args).unwrap();
+	assert_eq!(g.add(12, &args), Err(CloneErr::Again));
+	assert_eq!((g.tgid(), g.len()), (10, 2));
+
+	// The joiner sleeps on the TID word until the thread's exit clears it.
+	let mut futexes = Futexes::<4>::new();
+	let key = FutexKey::new(1, 0x2000).unwrap();
+	futexes.wait(key, 10, 11, 11).unwrap();
+	let exit = g.exit(11).unwrap();
+	assert_eq!((exit.clear_tid.map(|s| s.addr()), exit.last), (Some(0x2000), false));
+	let mut woken = None;
+	assert_eq!(futexes.wake(key, 1, |tid| woken = Some(tid)), 1);
+	assert_eq!(woken, Some(10));
+
+	assert_eq!(g.exit(11), None);
+	assert_eq!(g.exit(10), Some(ExitActions { clear_tid: None, last: true }));
+    }
+
+    #[test]
+    fn futex_waiters() {
+	assert_eq!(FutexOp::from_raw(FUTEX_WAIT | FUTEX_PRIVATE_FLAG, 5), Ok(FutexOp::Wait { val: 5 }));
+	assert_eq!(FutexOp::from_raw(FUTEX_WAKE, u32::MAX as u64), Ok(FutexOp::Wake { n: u32::MAX }));
+	assert_eq!(FutexOp::from_raw(3, 0), Err(FutexErr::Unsupported(3)));
+	assert_eq!(FutexKey::new(1, 0x1002), Err(FutexErr::Invalid));
+	assert_eq!(FutexKey::new(1, 0), Err(FutexErr::Invalid));
+
+	let (a, b) = (FutexKey::new(1, 0x1000).unwrap(), FutexKey::new(2, 0x1000).unwrap());
+	let mut f = Futexes::<3>::new();
+	assert_eq!(f.wait(a, 1, 0, 1), Err(FutexErr::Again));
+	f.wait(a, 1, 0, 0).unwrap();
+	f.wait(b, 2, 0, 0).unwrap();
+	f.wait(a, 3, 0, 0).unwrap();
+	assert_eq!(f.wait(a, 4, 0, 0), Err(FutexErr::Full));
+	f.cancel(1);
+	f.wait(a, 4, 0, 0).unwrap();
+
+	let mut order = [0; 3];
+	let mut n = 0;
+	assert_eq!(f.wake(a, u32::MAX, |tid| { order[n] = tid; n += 1 }), 2);
+	assert_eq!(order, [3, 4, 0]);
+	assert_eq!(f.wake(b, 1, |_| {}), 1);
+	assert_eq!(f.wake(b, 1, |_| {}), 0);
+    }
+}
@@ -0,0 +1,178 @@
+//! Wall clock time: calendar dates, Unix time and FAT timestamps.
+//!
+//! The kernel reads the date from the CMOS RTC once at boot and keeps realtime as that
+//! plus the monotonic clock. These are the conversions around it, shared so the FAT
+//! writer and `clock_gettime` agree with each other (and with bob, reading the image).
+//! Everything is UTC, there are no time zones.
+
+/// Clocks for `clock_gettime`, numbered as on Linux.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum ClockId {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+impl ClockId {
+    pub fn from_raw(id: u32) -> Option<Self> {
+	match id {
+	    0 => Some(Self::Realtime),
+	    1 => Some(Self::Monotonic),
+	    _ => None,
+	}
+    }
+}
+
+/// What `clock_gettime` writes to userspace, laid out like the C struct.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(ns: u64) -> Self {
+	Self {
+	    sec: (ns / 1_000_000_000) as i64,
+	    nsec: (ns % 1_000_000_000) as i64,
+	}
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: i32,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(&self) -> i64 {
+	let days = days_from_civil(self.year, self.month as i64, self.day as i64);
+	days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    pub fn from_unix(t: i64) -> Self {
+	let (days, secs) = (t.div_euclid(86400), t.rem_euclid(86400));
+	let (year, month, day) = civil_from_days(days);
+	Self {
+	    year,
+	    month,
+	    day,
+	    hour: (secs / 3600) as u8,
+	    minute: (secs / 60 % 60) as u8,
+	    second: (secs % 60) as u8,
+	}
+    }
+
+    /// A date from the CMOS RTC registers, which are usually BCD and hold a two digit year.
+    pub fn from_rtc(regs: RtcRegisters, bcd: bool) -> Self {
+	let dec = |v: u8| if bcd { (v >> 4) * 10 + (v & 0xF) } else { v };
+	Self {
+	    year: 2000 + dec(regs.year) as i32,
+	    month: dec(regs.month),
+	    day: dec(regs.day),
+	    hour: dec(regs.hour),
+	    minute: dec(regs.minute),
+	    second: dec(regs.second),
+	}
+    }
+
+    /// FAT directory entry date and time, None outside of 1980 to 2107 which FAT can't
+    /// represent. Times have two second resolution.
+    pub fn to_fat(&self) -> Option<(u16, u16)> {
+	if !(1980..=2107).contains(&self.year) {
+	    return None;
+	}
+	let date = ((self.year - 1980) as u16) << 9 | (self.month as u16) << 5 | self.day as u16;
+	let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | (self.second as u16 / 2);
+	Some((date, time))
+    }
+
+    pub fn from_fat(date: u16, time: u16) -> Self {
+	Self {
+	    year: 1980 + (date >> 9) as i32,
+	    month: (date >> 5 & 0xF) as u8,
+	    day: (date & 0x1F) as u8,
+	    hour: (time >> 11) as u8,
+	    minute: (time >> 5 & 0x3F) as u8,
+	    second: ((time & 0x1F) * 2) as u8,
+	}
+    }
+}
+
+/// The RTC's date and time registers, 24 hour mode assumed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RtcRegisters {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+}
+
+// Howard Hinnant's algorithms, http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(y: i32, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y as i64 - 1 } else { y as i64 };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i32, u8, u8) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y as i32, m, d)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn unix_round_trip() {
+	let dt = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 5 };
+	assert_eq!(dt.to_unix(), 1709213825);
+	assert_eq!(DateTime::from_unix(1709213825), dt);
+	assert_eq!(DateTime::from_unix(0), DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 });
+	assert_eq!(DateTime::from_unix(-1).year, 1969);
+    }
+
+    #[test]
+    fn fat_timestamps() {
+	let dt = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 37, second: 5 };
+	let (date, time) = dt.to_fat().unwrap();
+	assert_eq!((date, time), (0x585D, 0x6CA2));
+	// Seconds are rounded down to even.
+	assert_eq!(DateTime::from_fat(date, time), DateTime { second: 4, ..dt });
+	assert_eq!(DateTime::from_unix(0).to_fat(), None);
+    }
+
+    #[test]
+    fn rtc() {
+	let regs = RtcRegisters { second: 0x59, minute: 0x30, hour: 0x23, day: 0x31, month: 0x12, year: 0x25 };
+	let dt = DateTime::from_rtc(regs, true);
+	assert_eq!(dt, DateTime { year: 2025, month: 12, day: 31, hour: 23, minute: 30, second: 59 });
+	assert_eq!(Timespec::from_nanos(1_500_000_000), Timespec { sec: 1, nsec: 500_000_000 });
+	assert_eq!(ClockId::from_raw(1), Some(ClockId::Monotonic));
+    }
+}
@@ -0,0 +1,261 @@
+//! Checking and copying user memory passed to syscalls.
+//!
+//! Every pointer a syscall takes goes through `UserSlice::new` first, which only checks
+//! that the range lies in the lower half: null, wrapping, kernel and non-canonical
+//! addresses are `Fault` up front. Whether the pages are mapped is only found out by
+//! touching them, so the copy routines are the only kernel code allowed to fault on a
+//! user address. Each load and store in them is listed in a `FixupTable`, and the page
+//! fault handler asks `kernel_fault` what to do with a fault in kernel mode: resume at the
+//! fixup, which makes the syscall return `-EFAULT`, or panic, because any other kernel
+//! fault is a kernel bug.
+//!
+//! `HOSTILE` and `hostile_pointer` are the pointers the syscall fuzzer in the initramfs
+//! throws at every pointer argument.
+//! Reference: Linux Documentation/arch/x86/exception-tables.rst
+
+/// First address past the lower (user) half of a 48 bit address space.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+pub const PAGE_SIZE: u64 = 4096;
+/// Returned negated by a syscall handed a bad pointer.
+pub const EFAULT: i64 = 14;
+
+/// A bad user pointer, or a copy that hit an unmapped page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault;
+
+/// A range of user memory a syscall was handed, known to lie in the lower half.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserSlice {
+    addr: u64,
+    len: u64,
+}
+
+impl UserSlice {
+    /// An empty range is fine wherever it points, it's never touched.
+    pub fn new(addr: u64, len: u64) -> Result<Self, Fault> {
+	if len == 0 {
+	    return Ok(Self { addr, len });
+	}
+	let end = addr.checked_add(len).ok_or(Fault)?;
+	if addr == 0 || end > USER_END {
+	    return Err(Fault);
+	}
+	Ok(Self { addr, len })
+    }
+
+    pub fn addr(&self) -> u64 {
+	self.addr
+    }
+
+    pub fn len(&self) -> u64 {
+	self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+	self.len == 0
+    }
+
+    /// The range split at page boundaries, as `(addr, len)`, so a copy can stop at the
+    /// first page that isn't mapped.
+    pub fn pages(&self) -> impl Iterator<Item = (u64, u64)> {
+	let end = self.addr + self.len;
+	let mut addr = self.addr;
+	core::iter::from_fn(move || {
+	    if addr >= end {
+		return None;
+	    }
+	    let next = ((addr / PAGE_SIZE + 1) * PAGE_SIZE).min(end);
+	    let chunk = (addr, next - addr);
+	    addr = next;
+	    Some(chunk)
+	})
+    }
+}
+
+/// Copy `src` into `dst`, a page at a time with `read(addr, buf)`, which faults if the
+/// page isn't mapped readable. On a fault `dst` holds what was copied before it.
+pub fn copy_from_user(dst: &mut [u8], src: UserSlice, mut read: impl FnMut(u64, &mut [u8]) -> Result<(), Fault>) -> Result<(), Fault> {
+    if dst.len() as u64 != src.len {
+	return Err(Fault);
+    }
+    let mut done = 0;
+    for (addr, len) in src.pages() {
+	let len = len as usize;
+	read(addr, &mut dst[done..done + len])?;
+	done += len;
+    }
+    Ok(())
+}
+
+/// Copy `src` out to `dst` a page at a time with `write(addr, bytes)`, which faults if
+/// the page isn't mapped writable.
+pub fn copy_to_user(dst: UserSlice, src: &[u8], mut write: impl FnMut(u64, &[u8]) -> Result<(), Fault>) -> Result<(), Fault> {
+    if src.len() as u64 != dst.len {
+	return Err(Fault);
+    }
+    let mut done = 0;
+    for (addr, len) in dst.pages() {
+	let len = len as usize;
+	write(addr, &src[done..done + len])?;
+	done += len;
+    }
+    Ok(())
+}
+
+/// An exception table entry: a fault at instruction `insn` resumes at `fixup`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fixup {
+    pub insn: u64,
+    pub fixup: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FixupErr {
+    /// Entries must be sorted by `insn`, without duplicates.
+    Unsorted,
+}
+
+/// The kernel's exception table, sorted by `insn` at build time so lookups from the
+/// fault handler don't need to allocate.
+pub struct FixupTable<'a> {
+    entries: &'a [Fixup],
+}
+
+impl<'a> FixupTable<'a> {
+    pub fn new(entries: &'a [Fixup]) -> Result<Self, FixupErr> {
+	if entries.windows(2).any(|w| w[0].insn >= w[1].insn) {
+	    return Err(FixupErr::Unsorted);
+	}
+	Ok(Self { entries })
+    }
+
+    pub fn lookup(&self, rip: u64) -> Option<u64> {
+	let i = self.entries.binary_search_by_key(&rip, |f| f.insn).ok()?;
+	Some(self.entries[i].fixup)
+    }
+}
+
+/// What the page fault handler does with a fault taken in kernel mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelFault {
+    /// A copy routine touched an unmapped user page: resume at this address.
+    Fixup(u64),
+    /// Anything else is a kernel bug.
+    Panic,
+}
+
+/// Decide a kernel mode fault at `rip` on `addr`. Only user addresses get fixed up, a
+/// copy routine faulting on a kernel address means `UserSlice` let it through.
+pub fn kernel_fault(table: &FixupTable, rip: u64, addr: u64) -> KernelFault {
+    match table.lookup(rip) {
+	Some(fixup) if addr < USER_END => KernelFault::Fixup(fixup),
+	_ => KernelFault::Panic,
+    }
+}
+
+/// Pointers every syscall has to survive: null and near it, both sides of the end of
+/// user space, the non-canonical hole, the kernel half and the very top.
+pub const HOSTILE: [u64; 10] = [
+    0,
+    1,
+    PAGE_SIZE - 1,
+    USER_END - PAGE_SIZE,
+    USER_END - 1,
+    USER_END,
+    0x0000_dead_beef_0000,
+    0xffff_8000_0000_0000,
+    0xffff_ffff_8000_0000,
+    u64::MAX,
+];
+
+/// A pointer for the fuzzer from a random `r`: mostly `HOSTILE` ones nudged by a few
+/// bytes either way, otherwise `r` itself.
+pub fn hostile_pointer(r: u64) -> u64 {
+    if r % 4 == 3 {
+	return r;
+    }
+    let base = HOSTILE[(r >> 2) as usize % HOSTILE.len()];
+    let nudge = (r >> 8) % 17;
+    base.wrapping_add(nudge).wrapping_sub(8)
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn user_slices() {
+	assert!(UserSlice::new(0, 0).is_ok());
+	assert!(UserSlice::new(u64::MAX, 0).is_ok());
+	assert_eq!(UserSlice::new(0, 1), Err(Fault));
+	assert_eq!(UserSlice::new(USER_END - 8, 9), Err(Fault));
+	assert_eq!(UserSlice::new(u64::MAX - 1, 4), Err(Fault));
+	assert!(UserSlice::new(USER_END - 8, 8).is_ok());
+	for p in HOSTILE.iter().filter(|p| **p == 0 || **p >= USER_END) {
+	    assert_eq!(UserSlice::new(*p, 1), Err(Fault));
+	}
+
+	let s = UserSlice::new(0x1ff0, 0x2020).unwrap();
+	let mut pages = s.pages();
+	assert_eq!(pages.next(), Some((0x1ff0, 0x10)));
+	assert_eq!(pages.next(), Some((0x2000, 0x1000)));
+	assert_eq!(pages.next(), Some((0x3000, 0x1000)));
+	assert_eq!(pages.next(), Some((0x4000, 0x10)));
+	assert_eq!(pages.next(), None);
+    }
+
+    #[test]
+    fn copies_stop_at_unmapped_pages() {
+	// Only the page at 0x1000 is mapped, filled with its offset.
+	let read = |addr: u64, buf: &mut [u8]| {
+	    if addr / PAGE_SIZE != 1 {
+		return Err(Fault);
+	    }
+	    buf.iter_mut().enumerate().for_each(|(i, b)| *b = (addr as usize + i) as u8);
+	    Ok(())
+	};
+	let mut buf = [0xAA; 0x20];
+	copy_from_user(&mut buf, UserSlice::new(0x1ff0, 0x20).unwrap(), read).unwrap_err();
+	assert_eq!(buf[0], 0xF0);
+	assert_eq!(buf[0x10], 0xAA);
+	copy_from_user(&mut buf[..0x10], UserSlice::new(0x1ff0, 0x10).unwrap(), read).unwrap();
+	assert_eq!(copy_from_user(&mut buf, UserSlice::new(0x1000, 0x10).unwrap(), read), Err(Fault));
+
+	let mut written = 0;
+	let write = |addr: u64, bytes: &[u8]| {
+	    if addr / PAGE_SIZE != 1 {
+		return Err(Fault);
+	    }
+	    written += bytes.len();
+	    Ok(())
+	};
+	assert_eq!(copy_to_user(UserSlice::new(0x1ff8, 0x10).unwrap(), &[0; 0x10], write), Err(Fault));
+	assert_eq!(written, 8);
+    }
+
+    #[test]
+    fn fixups() {
+	let entries = [Fixup { insn: 0x100, fixup: 0x900 }, Fixup { insn: 0x180, fixup: 0x900 }];
+	let table = FixupTable::new(&entries).unwrap();
+	assert_eq!(table.lookup(0x180), Some(0x900));
+	assert_eq!(table.lookup(0x181), None);
+	assert_eq!(kernel_fault(&table, 0x100, 0x1000), KernelFault::Fixup(0x900));
+	assert_eq!(kernel_fault(&table, 0x100, USER_END), KernelFault::Panic);
+	assert_eq!(kernel_fault(&table, 0x104, 0x1000), KernelFault::Panic);
+
+	let unsorted = [entries[1], entries[0]];
+	assert!(matches!(FixupTable::new(&unsorted), Err(FixupErr::Unsorted)));
+    }
+
+    #[test]
+    fn hostile_pointers() {
+	assert_eq!(hostile_pointer(0x1234_5677), 0x1234_5677);
+	assert_eq!(hostile_pointer(8 << 8), PAGE_SIZE - 1);
+	for r in (0..4096).filter(|r| r % 4 != 3) {
+	    let p = hostile_pointer(r);
+	    assert!(HOSTILE.iter().any(|h| p.wrapping_sub(*h).wrapping_add(8) <= 16));
+	}
+    }
+}
@@ -0,0 +1,317 @@
+//! dm-verity compatible hash trees.
+//!
+//! A read-only partition is split into 4KiB data blocks, each hashed (SHA-256, salted)
+//! into the lowest level of a tree. Every level above hashes the blocks of the one below
+//! until a level fits in one block, whose hash is the root hash. Checking a data block
+//! only needs the one hash block per level on its path to the root.
+//!
+//! The hash partition uses the veritysetup layout (format 1): a superblock in the first
+//! block, then the levels from the top of the tree down, so images can also be checked
+//! with `veritysetup verify`.
+//! Reference: https://docs.kernel.org/admin-guide/device-mapper/verity.html
+
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{ErrorCode, Subsystem};
+
+pub const BLOCK_SZ: usize = 4096;
+pub const HASH_SZ: usize = 32;
+const HASHES_PER_BLOCK_BITS: u32 = 7;
+pub const HASHES_PER_BLOCK: u64 = 1 << HASHES_PER_BLOCK_BITS;
+/// Enough for any number of blocks that fits in a u64.
+pub const MAX_LEVELS: usize = 10;
+
+pub const SUPERBLOCK_SZ: usize = 512;
+pub const MAX_SALT_SZ: usize = 256;
+const SIGNATURE: &[u8; 8] = b"verity\0\0";
+const ALGORITHM: &[u8] = b"sha256";
+
+pub type Hash = [u8; HASH_SZ];
+
+#[derive(Debug, PartialEq)]
+pub enum VerityErr {
+    InputBounds,
+    Signature,
+    Unsupported,
+    SaltTooLong,
+    /// Reading a hash block failed.
+    Read,
+    /// The block, or the hash block at `level` on its path, doesn't match the tree.
+    Mismatch { level: usize },
+}
+
+impl ErrorCode for VerityErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Verity;
+
+    fn code(&self) -> (u16, u32) {
+	match *self {
+	    Self::InputBounds => (1, 0),
+	    Self::Signature => (2, 0),
+	    Self::Unsupported => (3, 0),
+	    Self::SaltTooLong => (4, 0),
+	    Self::Read => (5, 0),
+	    Self::Mismatch { level } => (6, level as u32),
+	}
+    }
+
+    fn describe(code: u16, arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "out of bounds"),
+	    2 => write!(f, "not a hash partition"),
+	    3 => write!(f, "unsupported hash or block size"),
+	    4 => write!(f, "salt is too long"),
+	    5 => write!(f, "reading a hash block failed"),
+	    6 => write!(f, "hash mismatch at level {arg}"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+/// Where each level of the tree lives, in blocks from the start of the hash partition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Geometry {
+    pub data_blocks: u64,
+    /// Level 0 hashes the data blocks, the top level is `levels - 1`.
+    pub levels: usize,
+    pub level_start: [u64; MAX_LEVELS],
+    pub level_blocks: [u64; MAX_LEVELS],
+}
+
+impl Geometry {
+    pub fn new(data_blocks: u64) -> Self {
+	// Same level count as the kernel and veritysetup, a single data block has no tree
+	// at all and its hash is the root hash.
+	let mut levels = 0;
+	while (HASHES_PER_BLOCK_BITS as usize * levels) < 64
+	    && data_blocks.saturating_sub(1) >> (HASHES_PER_BLOCK_BITS as usize * levels) != 0 {
+	    levels += 1;
+	}
+
+	let mut level_start = [0; MAX_LEVELS];
+	let mut level_blocks = [0; MAX_LEVELS];
+	// The superblock takes the first block.
+	let mut pos = 1;
+	for i in (0..levels).rev() {
+	    let shift = HASHES_PER_BLOCK_BITS * (i as u32 + 1);
+	    level_start[i] = pos;
+	    level_blocks[i] = if shift >= 64 { 1 } else { data_blocks.div_ceil(1 << shift) };
+	    pos += level_blocks[i];
+	}
+
+	Self {
+	    data_blocks,
+	    levels,
+	    level_start,
+	    level_blocks,
+	}
+    }
+
+    /// Size of the hash partition contents in blocks, superblock included.
+    pub fn hash_blocks(&self) -> u64 {
+	1 + self.level_blocks[..self.levels].iter().sum::<u64>()
+    }
+}
+
+/// Hash a data or hash block.
+pub fn hash_block(salt: &[u8], block: &[u8]) -> Hash {
+    let mut h = Sha256::new();
+    h.update(salt);
+    h.update(block);
+    h.finalize().into()
+}
+
+/// Check a data block against the tree, walking up from the block to the root.
+/// `read_hash_block` reads the given block of the hash partition into the buffer.
+pub fn verify_block<F>(
+    geo: &Geometry,
+    salt: &[u8],
+    root: &Hash,
+    index: u64,
+    data: &[u8],
+    buf: &mut [u8; BLOCK_SZ],
+    mut read_hash_block: F,
+) -> Result<(), VerityErr>
+where
+    F: FnMut(u64, &mut [u8; BLOCK_SZ]) -> Result<(), VerityErr>,
+{
+    if index >= geo.data_blocks || data.len() != BLOCK_SZ {
+	return Err(VerityErr::InputBounds);
+    }
+
+    let mut want = hash_block(salt, data);
+    for level in 0..geo.levels {
+	let entry = index >> (HASHES_PER_BLOCK_BITS * level as u32);
+	let block = entry >> HASHES_PER_BLOCK_BITS;
+	let offset = (entry % HASHES_PER_BLOCK) as usize * HASH_SZ;
+
+	read_hash_block(geo.level_start[level] + block, buf)?;
+	if buf[offset..offset + HASH_SZ] != want {
+	    return Err(VerityErr::Mismatch { level });
+	}
+	want = hash_block(salt, &buf[..]);
+    }
+
+    if want != *root {
+	return Err(VerityErr::Mismatch { level: geo.levels });
+    }
+    Ok(())
+}
+
+/// The veritysetup superblock at the start of the hash partition.
+#[derive(Debug, PartialEq)]
+pub struct Superblock {
+    pub uuid: [u8; 16],
+    pub data_blocks: u64,
+    pub salt_size: usize,
+    pub salt: [u8; MAX_SALT_SZ],
+}
+
+impl Superblock {
+    pub fn new(uuid: [u8; 16], data_blocks: u64, salt: &[u8]) -> Result<Self, VerityErr> {
+	if salt.len() > MAX_SALT_SZ {
+	    return Err(VerityErr::SaltTooLong);
+	}
+
+	let mut s = [0; MAX_SALT_SZ];
+	s[..salt.len()].copy_from_slice(salt);
+	Ok(Self {
+	    uuid,
+	    data_blocks,
+	    salt_size: salt.len(),
+	    salt: s,
+	})
+    }
+
+    pub fn salt(&self) -> &[u8] {
+	&self.salt[..self.salt_size]
+    }
+
+    pub fn to_bytes(&self) -> [u8; SUPERBLOCK_SZ] {
+	let mut b = [0; SUPERBLOCK_SZ];
+	b[0..8].copy_from_slice(SIGNATURE);
+	// Superblock version 1, hash type 1 (salt before the data)
+	b[8..12].copy_from_slice(&1u32.to_le_bytes());
+	b[12..16].copy_from_slice(&1u32.to_le_bytes());
+	b[16..32].copy_from_slice(&self.uuid);
+	b[32..32 + ALGORITHM.len()].copy_from_slice(ALGORITHM);
+	b[64..68].copy_from_slice(&(BLOCK_SZ as u32).to_le_bytes());
+	b[68..72].copy_from_slice(&(BLOCK_SZ as u32).to_le_bytes());
+	b[72..80].copy_from_slice(&self.data_blocks.to_le_bytes());
+	b[80..82].copy_from_slice(&(self.salt_size as u16).to_le_bytes());
+	b[88..88 + MAX_SALT_SZ].copy_from_slice(&self.salt);
+	b
+    }
+
+    pub fn parse(b: &[u8]) -> Result<Self, VerityErr> {
+	let b = b.get(..SUPERBLOCK_SZ).ok_or(VerityErr::InputBounds)?;
+	if &b[0..8] != SIGNATURE {
+	    return Err(VerityErr::Signature);
+	}
+
+	let u32_at = |off: usize| u32::from_le_bytes(b[off..off + 4].try_into().unwrap());
+	let algorithm = &b[32..64];
+	if u32_at(8) != 1
+	    || u32_at(12) != 1
+	    || &algorithm[..ALGORITHM.len()] != ALGORITHM
+	    || algorithm[ALGORITHM.len()] != 0
+	    || u32_at(64) != BLOCK_SZ as u32
+	    || u32_at(68) != BLOCK_SZ as u32 {
+	    return Err(VerityErr::Unsupported);
+	}
+
+	let salt_size = u16::from_le_bytes([b[80], b[81]]) as usize;
+	if salt_size > MAX_SALT_SZ {
+	    return Err(VerityErr::SaltTooLong);
+	}
+
+	let mut uuid = [0; 16];
+	uuid.copy_from_slice(&b[16..32]);
+	let mut salt = [0; MAX_SALT_SZ];
+	salt.copy_from_slice(&b[88..88 + MAX_SALT_SZ]);
+	Ok(Self {
+	    uuid,
+	    data_blocks: u64::from_le_bytes(b[72..80].try_into().unwrap()),
+	    salt_size,
+	    salt,
+	})
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn geometry() {
+	let g = Geometry::new(1);
+	assert_eq!((g.levels, g.hash_blocks()), (0, 1));
+
+	let g = Geometry::new(128);
+	assert_eq!(g.levels, 1);
+	assert_eq!((g.level_start[0], g.level_blocks[0]), (1, 1));
+
+	// 129 blocks need 2 hash blocks in level 0 and a top level above them.
+	let g = Geometry::new(129);
+	assert_eq!(g.levels, 2);
+	assert_eq!((g.level_start[1], g.level_blocks[1]), (1, 1));
+	assert_eq!((g.level_start[0], g.level_blocks[0]), (2, 2));
+	assert_eq!(g.hash_blocks(), 4);
+
+	let g = Geometry::new(u64::MAX);
+	assert_eq!(g.levels, MAX_LEVELS);
+	assert_eq!(g.level_blocks[MAX_LEVELS - 1], 1);
+    }
+
+    #[test]
+    fn superblock_round_trip() {
+	let sb = Superblock::new([7; 16], 1234, &[1, 2, 3]).unwrap();
+	let b = sb.to_bytes();
+	assert_eq!(&b[32..39], b"sha256\0");
+	assert_eq!(Superblock::parse(&b).unwrap(), sb);
+	assert_eq!(Superblock::parse(&b).unwrap().salt(), &[1, 2, 3]);
+
+	let mut bad = b;
+	bad[0] = b'V';
+	assert_eq!(Superblock::parse(&bad), Err(VerityErr::Signature));
+	assert_eq!(Superblock::new([0; 16], 1, &[0; 300]), Err(VerityErr::SaltTooLong));
+    }
+
+    #[test]
+    fn verify_two_level_tree() {
+	// 130 data blocks, block i is filled with i. Small enough to build the tree by hand.
+	let salt = b"salt";
+	let data = |i: u64| [i as u8; BLOCK_SZ];
+	let g = Geometry::new(130);
+	assert_eq!(g.levels, 2);
+
+	let mut level0 = [[0u8; BLOCK_SZ]; 2];
+	for i in 0..130 {
+	    let off = (i % 128) as usize * HASH_SZ;
+	    level0[i as usize / 128][off..off + HASH_SZ].copy_from_slice(&hash_block(salt, &data(i)));
+	}
+	let mut top = [0u8; BLOCK_SZ];
+	top[..HASH_SZ].copy_from_slice(&hash_block(salt, &level0[0]));
+	top[HASH_SZ..2 * HASH_SZ].copy_from_slice(&hash_block(salt, &level0[1]));
+	let root = hash_block(salt, &top);
+
+	let read = |n: u64, buf: &mut [u8; BLOCK_SZ]| {
+	    match n {
+		1 => buf.copy_from_slice(&top),
+		2 | 3 => buf.copy_from_slice(&level0[n as usize - 2]),
+		_ => return Err(VerityErr::Read),
+	    }
+	    Ok(())
+	};
+
+	let mut buf = [0; BLOCK_SZ];
+	for i in [0, 127, 128, 129] {
+	    assert_eq!(verify_block(&g, salt, &root, i, &data(i), &mut buf, read), Ok(()));
+	}
+	assert_eq!(verify_block(&g, salt, &root, 5, &data(6), &mut buf, read), Err(VerityErr::Mismatch { level: 0 }));
+	assert_eq!(verify_block(&g, salt, &[0; HASH_SZ], 5, &data(5), &mut buf, read), Err(VerityErr::Mismatch { level: 2 }));
+	assert_eq!(verify_block(&g, salt, &root, 130, &data(130), &mut buf, read), Err(VerityErr::InputBounds));
+    }
+}
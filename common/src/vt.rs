@@ -0,0 +1,311 @@
+//! Virtual terminals on the framebuffer console.
+//!
+//! Several text consoles share one screen: the shell on one, the kernel log on another, a
+//! test program on a third. Each VT keeps its own text buffer and cursor whether it's
+//! shown or not, so switching back shows what was written in the meantime. Only the
+//! active VT is drawn and gets the keyboard. Switching is Alt+F1..F12, as on Linux:
+//! `Terminals::key` takes those out of the scancode stream before the rest goes on to the
+//! `keymap::Decoder`.
+//!
+//! The compositor draws the active VT's rows that changed since the last draw, all of
+//! them after a switch. Writes go to the nodes `/dev/tty1`..`/dev/ttyN`, one per VT, or
+//! `/dev/tty0` for whichever is active.
+
+/// As many as there are function keys.
+pub const MAX_VTS: usize = 12;
+pub const TAB_WIDTH: usize = 8;
+
+const EXTENDED: u8 = 0xE0;
+const RELEASE: u8 = 0x80;
+/// Left Alt. Right Alt is AltGr, for typing, and doesn't switch.
+const ALT: u8 = 0x38;
+/// Scancode set 1 make codes of F1 to F12.
+const F_KEYS: [u8; MAX_VTS] = [0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x40, 0x41, 0x42, 0x43, 0x44, 0x57, 0x58];
+
+/// One VT's text, a grid of characters with a cursor. Text past the last column wraps,
+/// past the last row it scrolls.
+pub struct Screen<const COLS: usize, const ROWS: usize> {
+    cells: [[char; COLS]; ROWS],
+    col: usize,
+    row: usize,
+    dirty: [bool; ROWS],
+}
+
+impl<const COLS: usize, const ROWS: usize> Screen<COLS, ROWS> {
+    pub const fn new() -> Self {
+	Self { cells: [[' '; COLS]; ROWS], col: 0, row: 0, dirty: [false; ROWS] }
+    }
+
+    /// Column and row of the cursor.
+    pub fn cursor(&self) -> (usize, usize) {
+	(self.col, self.row)
+    }
+
+    pub fn row(&self, row: usize) -> &[char; COLS] {
+	&self.cells[row]
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+	for c in s.chars() {
+	    self.put(c);
+	}
+    }
+
+    /// Write `c` at the cursor. Newline, carriage return, backspace (which only moves
+    /// the cursor back) and tab are handled, other control characters are dropped.
+    pub fn put(&mut self, c: char) {
+	match c {
+	    '\n' => self.newline(),
+	    '\r' => self.col = 0,
+	    '\x08' => self.col = self.col.saturating_sub(1),
+	    '\t' => self.col = (self.col / TAB_WIDTH + 1).saturating_mul(TAB_WIDTH).min(COLS),
+	    c if c.is_control() => {},
+	    c => {
+		if self.col == COLS {
+		    self.newline();
+		}
+		self.cells[self.row][self.col] = c;
+		self.dirty[self.row] = true;
+		self.col += 1;
+	    },
+	}
+    }
+
+    fn newline(&mut self) {
+	self.col = 0;
+	if self.row + 1 < ROWS {
+	    self.row += 1;
+	    return;
+	}
+	self.cells.rotate_left(1);
+	self.cells[ROWS - 1] = [' '; COLS];
+	self.dirty = [true; ROWS];
+    }
+
+    /// Call `draw(row, text)` for each row changed since the last call, or every row with
+    /// `all`.
+    fn draw<F: FnMut(usize, &[char; COLS])>(&mut self, all: bool, mut draw: F) {
+	for (i, row) in self.cells.iter().enumerate() {
+	    if all || self.dirty[i] {
+		draw(i, row);
+	    }
+	}
+	self.dirty = [false; ROWS];
+    }
+}
+
+impl<const COLS: usize, const ROWS: usize> Default for Screen<COLS, ROWS> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+/// A tty node under /dev.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Node {
+    /// `tty0`, the active VT.
+    Active,
+    /// `tty1` and up, counting from 0 here.
+    Vt(usize),
+}
+
+impl Node {
+    /// The node named `name`, with or without `/dev/`.
+    pub fn parse(name: &str) -> Option<Self> {
+	let n = name.strip_prefix("/dev/").unwrap_or(name).strip_prefix("tty")?;
+	if n.len() > 1 && n.starts_with('0') {
+	    return None;
+	}
+	match n.parse::<usize>().ok()? {
+	    0 => Some(Self::Active),
+	    n if n <= MAX_VTS => Some(Self::Vt(n - 1)),
+	    _ => None,
+	}
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Key {
+    /// A scancode byte for the active VT's input.
+    Byte { vt: usize, byte: u8 },
+    /// The active VT changed, it needs drawing.
+    Switched(usize),
+    /// The other half of a switch, dropped.
+    None,
+}
+
+/// The VTs, which one is active and the Alt+Fn state.
+pub struct Terminals<const N: usize, const COLS: usize, const ROWS: usize> {
+    screens: [Screen<COLS, ROWS>; N],
+    active: usize,
+    redraw: bool,
+    extended: bool,
+    alt: bool,
+    /// The F key of a switch, whose release is dropped too.
+    swallowed: Option<u8>,
+}
+
+impl<const N: usize, const COLS: usize, const ROWS: usize> Terminals<N, COLS, ROWS> {
+    pub const fn new() -> Self {
+	assert!(N > 0 && N <= MAX_VTS, "between 1 and 12 VTs");
+	Self {
+	    screens: [const { Screen::new() }; N],
+	    active: 0,
+	    redraw: true,
+	    extended: false,
+	    alt: false,
+	    swallowed: None,
+	}
+    }
+
+    pub fn active(&self) -> usize {
+	self.active
+    }
+
+    pub fn screen(&self, vt: usize) -> Option<&Screen<COLS, ROWS>> {
+	self.screens.get(vt)
+    }
+
+    /// Make `vt` the active VT. False if there's no such VT.
+    pub fn switch(&mut self, vt: usize) -> bool {
+	if vt >= N {
+	    return false;
+	}
+	if vt != self.active {
+	    self.active = vt;
+	    self.redraw = true;
+	}
+	true
+    }
+
+    /// The VT `node` refers to, None for a VT past the last.
+    pub fn resolve(&self, node: Node) -> Option<usize> {
+	match node {
+	    Node::Active => Some(self.active),
+	    Node::Vt(vt) if vt < N => Some(vt),
+	    Node::Vt(_) => None,
+	}
+    }
+
+    /// Write `s` to `node`'s VT, shown or not. False if there's no such VT.
+    pub fn write(&mut self, node: Node, s: &str) -> bool {
+	let Some(vt) = self.resolve(node) else {
+	    return false;
+	};
+	self.screens[vt].write_str(s);
+	true
+    }
+
+    /// Handle a scancode set 1 byte from the keyboard.
+    pub fn key(&mut self, byte: u8) -> Key {
+	let extended = core::mem::replace(&mut self.extended, byte == EXTENDED);
+	let released = byte & RELEASE != 0;
+	let code = byte & !RELEASE;
+	if code == ALT && !extended {
+	    self.alt = !released;
+	}
+	if released && self.swallowed == Some(code) {
+	    self.swallowed = None;
+	    return Key::None;
+	}
+	if self.alt && !released && !extended {
+	    if let Some(vt) = F_KEYS.iter().position(|f| *f == code) {
+		self.swallowed = Some(code);
+		return if self.switch(vt) { Key::Switched(vt) } else { Key::None };
+	    }
+	}
+	Key::Byte { vt: self.active, byte }
+    }
+
+    /// Call `draw(row, text)` for the active VT's rows that need drawing.
+    pub fn draw<F: FnMut(usize, &[char; COLS])>(&mut self, draw: F) {
+	let all = core::mem::take(&mut self.redraw);
+	self.screens[self.active].draw(all, draw);
+    }
+}
+
+impl<const N: usize, const COLS: usize, const ROWS: usize> Default for Terminals<N, COLS, ROWS> {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+mod tests {
+
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Rows drawn, by number.
+    #[allow(dead_code)]
+    fn drawn<const N: usize, const C: usize, const R: usize>(t: &mut Terminals<N, C, R>) -> [bool; R] {
+	let mut rows = [false; R];
+	t.draw(|row, _| rows[row] = true);
+	rows
+    }
+
+    #[allow(dead_code)]
+    fn text<const C: usize, const R: usize>(s: &Screen<C, R>, row: usize) -> [u8; C] {
+	s.row(row).map(|c| c as u8)
+    }
+
+    #[test]
+    fn screen_wraps_and_scrolls() {
+	let mut s = Screen::<10, 2>::new();
+	s.write_str("abcdefghijkl");
+	assert_eq!((text(&s, 0), text(&s, 1), s.cursor()), (*b"abcdefghij", *b"kl        ", (2, 1)));
+	s.write_str("\x08X\n\tyz");
+	assert_eq!((text(&s, 0), text(&s, 1), s.cursor()), (*b"kX        ", *b"        yz", (10, 1)));
+	s.write_str("\ra\x07");
+	assert_eq!(text(&s, 1), *b"a       yz");
+	// Tab stops at the last column.
+	s.write_str("\t\t");
+	assert_eq!(s.cursor(), (10, 1));
+    }
+
+    #[test]
+    fn alt_f_keys_switch() {
+	let mut t = Terminals::<3, 8, 4>::new();
+	// Alt+F2: the alt bytes go through, the F2 make and release don't.
+	assert_eq!(t.key(0x38), Key::Byte { vt: 0, byte: 0x38 });
+	assert_eq!(t.key(0x3C), Key::Switched(1));
+	assert_eq!(t.key(0x3C | RELEASE), Key::None);
+	assert_eq!(t.key(0x38 | RELEASE), Key::Byte { vt: 1, byte: 0xB8 });
+	assert_eq!(t.key(0x1E), Key::Byte { vt: 1, byte: 0x1E });
+
+	// F2 without Alt, AltGr+F1, and Alt+F5 past the last VT.
+	assert_eq!(t.key(0x3C), Key::Byte { vt: 1, byte: 0x3C });
+	assert_eq!(t.key(EXTENDED), Key::Byte { vt: 1, byte: EXTENDED });
+	assert_eq!(t.key(0x38), Key::Byte { vt: 1, byte: 0x38 });
+	assert_eq!(t.key(0x3B), Key::Byte { vt: 1, byte: 0x3B });
+	assert_eq!(t.key(EXTENDED), Key::Byte { vt: 1, byte: EXTENDED });
+	assert_eq!(t.key(0x38 | RELEASE), Key::Byte { vt: 1, byte: 0xB8 });
+	t.key(0x38);
+	assert_eq!(t.key(0x3F), Key::None);
+	assert_eq!(t.key(0x3F | RELEASE), Key::None);
+	assert_eq!(t.active(), 1);
+    }
+
+    #[test]
+    fn routes_and_draws() {
+	let mut t = Terminals::<3, 8, 4>::new();
+	assert_eq!(drawn(&mut t), [true; 4]);
+	assert!(t.write(Node::parse("/dev/tty0").unwrap(), "$ "));
+	assert!(t.write(Node::parse("tty2").unwrap(), "log\nlog\n"));
+	assert!(!t.write(Node::Vt(5), "nowhere"));
+	assert_eq!(drawn(&mut t), [true, false, false, false]);
+	assert_eq!(drawn(&mut t), [false; 4]);
+
+	// VT 2 was written while hidden, all of it is drawn on switching.
+	assert!(t.switch(1));
+	let mut rows = [[' '; 8]; 4];
+	t.draw(|row, text| rows[row] = *text);
+	assert_eq!(rows[1][..3], ['l', 'o', 'g']);
+	assert_eq!(text(t.screen(0).unwrap(), 0)[..2], *b"$ ");
+	assert!(!t.switch(3));
+
+	assert_eq!(Node::parse("tty12"), Some(Node::Vt(11)));
+	assert_eq!(Node::parse("tty13"), None);
+	assert_eq!(Node::parse("tty01"), None);
+	assert_eq!(Node::parse("ttyS0"), None);
+    }
+}
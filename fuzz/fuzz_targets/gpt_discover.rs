@@ -0,0 +1,42 @@
+//! The kernel's GPT reader, finding the ESP and root partitions on a disk of arbitrary
+//! bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use common::dm::{BlockDevice, DmErr};
+use common::gpt::discover;
+
+const SECTOR_SZ: usize = 512;
+
+/// The input as a disk, cut to whole sectors.
+struct MemDisk<'a>(&'a [u8]);
+
+impl BlockDevice for MemDisk<'_> {
+    fn sectors(&self) -> u64 {
+	(self.0.len() / SECTOR_SZ) as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), DmErr> {
+	let at = usize::try_from(sector).map_err(|_| DmErr::InputBounds)?.checked_mul(SECTOR_SZ).ok_or(DmErr::InputBounds)?;
+	let end = at.checked_add(buf.len()).ok_or(DmErr::InputBounds)?;
+	buf.copy_from_slice(self.0.get(at..end).ok_or(DmErr::InputBounds)?);
+	Ok(())
+    }
+
+    fn write(&mut self, _: u64, _: &[u8]) -> Result<(), DmErr> {
+	Err(DmErr::ReadOnly)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // The first line is the command line, selecting partitions by name or number.
+    let (cmdline, disk) = match data.iter().position(|b| *b == b'\n') {
+	Some(n) => (std::str::from_utf8(&data[..n]).unwrap_or(""), &data[n + 1..]),
+	None => ("", data),
+    };
+    let _ = discover(&mut MemDisk(disk), cmdline, |_, e| {
+	let _ = e.name_units();
+    });
+});
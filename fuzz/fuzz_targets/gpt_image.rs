@@ -0,0 +1,45 @@
+//! bob's GPT reader on an image of arbitrary bytes, as `inspect` and `verify` read one,
+//! and the subcommands that act on a partition of it: `extract`, `wipe` and
+//! `delete-partition --wipe`.
+
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+
+use bob_core::gpt::{verify, DiskImage, GptImage, PartitionType};
+use bob_core::sink::ZeroMode;
+
+fuzz_target!(|data: &[u8]| {
+    // The reader takes a path, so the input goes through a file.
+    let path = std::env::temp_dir().join(format!("yoyo-fuzz-{}.img", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let path = path.to_str().unwrap();
+    let mut slots = 0;
+    if let Ok(mut img) = GptImage::open_read_only(path) {
+	let _ = img.inspect();
+	let _ = img.inspect_json();
+	let _ = img.layout();
+	let _ = img.partitions_of_type(PartitionType::EFISystem);
+	slots = img.partition_count();
+	for i in 0..slots {
+	    let _ = img.extract_partition(i, &mut io::sink());
+	}
+    }
+    let _ = verify(path);
+
+    // The writes each get a fresh copy of the input to scribble on.
+    let scratch = std::env::temp_dir().join(format!("yoyo-fuzz-{}-scratch.img", std::process::id()));
+    let scratch = scratch.to_str().unwrap();
+    for i in 0..slots {
+	std::fs::write(scratch, data).unwrap();
+	if let Ok(mut img) = GptImage::open(scratch) {
+	    let _ = img.wipe_partition(i, ZeroMode::Quick);
+	}
+	std::fs::write(scratch, data).unwrap();
+	if let Ok(mut img) = GptImage::open(scratch) {
+	    let _ = img.delete_partition(i, true);
+	}
+    }
+});
@@ -0,0 +1,22 @@
+//! The kernel's squashfs structures: the superblock, an inode and a directory listing,
+//! all from the same arbitrary bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use common::squashfs::{lookup, DirEntries, Inode, Superblock};
+
+fuzz_target!(|data: &[u8]| {
+    let block_size = match Superblock::parse(data) {
+	Ok(sb) => sb.block_size,
+	Err(_) => 128 * 1024,
+    };
+    let _ = Inode::parse(data, block_size);
+    for e in DirEntries::new(data) {
+	if e.is_err() {
+	    break;
+	}
+    }
+    let _ = lookup(data, b"init");
+});
@@ -0,0 +1,59 @@
+//! The kernel log.
+//!
+//! Backed by a statically allocated `LogRing`. The bootloader's log is copied in first
+//! so the whole boot story ends up in one place.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::ptr::addr_of_mut;
+use common::{
+    boot::MemRegion,
+    logbuf::LogRing,
+};
+
+const DMESG_SZ: usize = 64 * 1024;
+
+#[repr(align(8))]
+struct DmesgBuf([u8; DMESG_SZ]);
+
+static mut DMESG_BUF: DmesgBuf = DmesgBuf([0; DMESG_SZ]);
+
+struct Dmesg(UnsafeCell<Option<LogRing<'static>>>);
+
+// Only the boot processor is running.
+unsafe impl Sync for Dmesg {}
+
+static DMESG: Dmesg = Dmesg(UnsafeCell::new(None));
+
+/// Append a formatted message to the kernel log.
+#[macro_export]
+macro_rules! dmesg {
+    ($($arg:tt)*) => ($crate::dmesg::write_fmt(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Set up the kernel log, starting with the contents of the bootloader's log.
+///
+/// # Safety
+/// Must only be called once, and `loader_log` must point to memory the bootloader
+/// initialized as a `LogRing` (or be garbage the magic check will reject).
+pub unsafe fn init(loader_log: MemRegion) {
+    let mut ring = LogRing::new(&mut (*addr_of_mut!(DMESG_BUF)).0).expect("dmesg buffer to be aligned");
+
+    let mem = core::slice::from_raw_parts_mut(loader_log.addr as *mut u8, loader_log.len as usize);
+    if let Some(loader) = LogRing::attach(mem) {
+	if loader.lost() > 0 {
+	    let _ = writeln!(ring, "[loader log truncated, {} bytes lost]", loader.lost());
+	}
+	let (older, newer) = loader.contents();
+	ring.write(older);
+	ring.write(newer);
+    }
+
+    *DMESG.0.get() = Some(ring);
+}
+
+pub fn write_fmt(args: fmt::Arguments) {
+    if let Some(ring) = unsafe { (*DMESG.0.get()).as_mut() } {
+	let _ = ring.write_fmt(args);
+    }
+}
@@ -0,0 +1,11 @@
+//! GUID generation for kernel created objects (filesystem volume IDs, device identifiers).
+
+pub use common::guid::Guid;
+use crate::rand::{fill_bytes, EntropyErr};
+
+/// Generate a new random (version 4) Guid from the kernel entropy source.
+pub fn new_v4() -> Result<Guid, EntropyErr> {
+    let mut rb = [0;16];
+    fill_bytes(&mut rb)?;
+    Ok(Guid::from_random_bytes(rb))
+}
@@ -1,16 +1,131 @@
 #![no_std]
 #![no_main]
 
+mod dmesg;
+mod guid;
+mod memory;
+mod rand;
+
 use core::panic::PanicInfo;
+use common::{
+    boot::{BootInfo, BootInfoWriter, Tag, BOOT_INFO_MAGIC, BOOT_INFO_VERSION},
+    build_id::{self, Component, RECORD_SZ},
+    error::Error,
+    limine::{self, Request},
+    multiboot2,
+};
+
+/// Lets GRUB (or any Multiboot2 loader) find and boot the kernel.
+#[used]
+#[link_section = ".multiboot2"]
+static MULTIBOOT2_HEADER: multiboot2::Header = multiboot2::HEADER;
+
+// Requests for the Limine boot protocol, answered before the kernel runs.
+#[used]
+#[link_section = ".requests"]
+static LIMINE_BASE_REVISION: [u64; 3] = limine::BASE_REVISION;
+#[used]
+#[link_section = ".requests"]
+static LIMINE_HHDM: Request<limine::HhdmResponse> = Request::new(limine::HHDM_ID);
+#[used]
+#[link_section = ".requests"]
+static LIMINE_MEMMAP: Request<limine::MemmapResponse> = Request::new(limine::MEMMAP_ID);
+#[used]
+#[link_section = ".requests"]
+static LIMINE_MODULES: Request<limine::ModuleResponse> = Request::new(limine::MODULE_ID);
+#[used]
+#[link_section = ".requests"]
+static LIMINE_FRAMEBUFFER: Request<limine::FramebufferResponse> = Request::new(limine::FRAMEBUFFER_ID);
+
+/// Found by bob in the files it puts in an image, see common::build_id.
+#[used]
+static BUILD_ID: [u8; RECORD_SZ] = build_id::THIS.record(Component::Kernel);
+
+/// Size of the BootInfo built from another bootloader's handoff.
+const FOREIGN_BOOT_INFO_SZ: usize = 16 * 1024;
+
+#[repr(align(8))]
+struct BootInfoBuf([u8; FOREIGN_BOOT_INFO_SZ]);
+
+static mut FOREIGN_BOOT_INFO: BootInfoBuf = BootInfoBuf([0; FOREIGN_BOOT_INFO_SZ]);
 
 #[allow(dead_code)]
 #[no_mangle]
-pub extern "C" fn kmain() -> !{
+pub extern "C" fn kmain(boot_info: &'static BootInfo) -> !{
+    // The loader's log has to be attached before anything else can be reported.
+    let tags = unsafe { boot_info.tags() };
+    if let Some(loader_log) = tags.clone().find_map(|t| match t {
+	Tag::LoaderLog(r) => Some(r),
+	_ => None,
+    }) {
+	unsafe { dmesg::init(loader_log) };
+    }
+    dmesg!("Hello from the kernel!");
+    dmesg!("kernel {}", build_id::THIS);
+
+    if boot_info.magic != BOOT_INFO_MAGIC || boot_info.version != BOOT_INFO_VERSION {
+	dmesg!("boot info: bad header (magic {:#x}, version {}), ignoring it", boot_info.magic, boot_info.version);
+    }
+    for tag in tags {
+	match tag {
+	    Tag::Unknown { tag, data } => dmesg!("boot info: skipping unknown tag {} ({} bytes), bootloader is newer than the kernel", tag, data.len()),
+	    Tag::Cmdline(cmdline) => dmesg!("command line: {}", cmdline),
+	    Tag::BuildId(component, id) => dmesg!("{} {}", component.name(), id),
+	    Tag::Malformed { tag } => dmesg!("boot info: tag {} is too short, ignoring it", tag),
+	    _ => {},
+	}
+    }
+
+    // Paging is whatever the bootloader set up, audit it before running on it any further.
+    memory::audit_wx();
+    match guid::new_v4() {
+	Ok(boot_id) => dmesg!("boot id {}", boot_id),
+	Err(e) => dmesg!("no boot id: {}", Error::new(&e)),
+    }
     loop {}
 }
 
+/// Entry point when booted by a Multiboot2 loader, once the entry stub has switched to
+/// long mode. Translates the loader's info structure into a BootInfo and carries on in
+/// `kmain` as if our own bootloader had started us.
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn kmain_multiboot2(magic: u32, info: u64) -> ! {
+    let buf = &mut (*core::ptr::addr_of_mut!(FOREIGN_BOOT_INFO)).0;
+    let mut writer = BootInfoWriter::new(buf).expect("boot info buffer to be aligned");
+    if magic == multiboot2::BOOTLOADER_MAGIC {
+	let total = (info as *const u32).read() as usize;
+	let info = core::slice::from_raw_parts(info as *const u8, total);
+	// A bad info structure still boots, just without whatever it was missing.
+	let _ = multiboot2::normalize(info, &mut writer);
+    }
+    writer.finish();
+    kmain(&*(buf.as_ptr() as *const BootInfo))
+}
+
+/// Entry point when booted by Limine. Turns its responses into a BootInfo and carries on
+/// in `kmain`.
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn kmain_limine() -> ! {
+    let buf = &mut (*core::ptr::addr_of_mut!(FOREIGN_BOOT_INFO)).0;
+    let mut writer = BootInfoWriter::new(buf).expect("boot info buffer to be aligned");
+    let requests = limine::Requests {
+	hhdm: &LIMINE_HHDM,
+	memmap: &LIMINE_MEMMAP,
+	modules: &LIMINE_MODULES,
+	framebuffer: &LIMINE_FRAMEBUFFER,
+    };
+    if let Some(hhdm) = LIMINE_HHDM.response() {
+	memory::set_phys_offset(hhdm.offset);
+    }
+    // Whatever didn't fit is left out, the kernel still boots.
+    let _ = limine::normalize(&requests, &mut writer);
+    writer.finish();
+    kmain(&*(buf.as_ptr() as *const BootInfo))
+}
+
 #[panic_handler]
 fn panic_handler(_info: &PanicInfo) -> ! {
     loop {}
 }
-
@@ -0,0 +1,134 @@
+//! Page table auditing.
+//!
+//! Walks the active 4-level page tables and reports mappings that break the kernel's
+//! memory-safety invariants. Right now that's only W^X: no page should be both writable
+//! and executable. Permissions are the combination of every level of the walk, a page is
+//! only writable if every entry on the path has R/W set, and only executable if none of
+//! them have NX set (and EFER.NXE is enabled at all).
+//! Ref: Intel SDM Vol. 3A, 4.5 "4-Level Paging and 5-Level Paging"
+
+use core::arch::asm;
+
+const ENTRIES_PER_TABLE: usize = 512;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const IA32_EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+
+/// A contiguous range of virtual memory that failed an audit check.
+#[derive(Debug, Clone, Copy)]
+pub struct Violation {
+    pub virt_start: u64,
+    pub size: u64,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    WritableExecutable,
+}
+
+/// Walks the page tables currently loaded in CR3 and calls `report` for every range
+/// of virtual memory that is mapped both writable and executable. Adjacent pages are
+/// coalesced into a single violation.
+///
+/// `phys_offset` is the virtual address physical memory is mapped at, which is zero while
+/// we're still running on the identity map UEFI left us with.
+///
+/// # Safety
+/// All physical memory holding page tables must be readable at `phys_offset`.
+pub unsafe fn audit_wx<F: FnMut(Violation)>(phys_offset: u64, mut report: F) {
+    let nx_enabled = rdmsr(IA32_EFER) & EFER_NXE != 0;
+    let mut pending: Option<Violation> = None;
+
+    let mut emit = |virt_start: u64, size: u64| {
+	match pending.as_mut() {
+	    Some(v) if v.virt_start + v.size == virt_start => v.size += size,
+	    _ => {
+		if let Some(v) = pending.take() {
+		    report(v);
+		}
+		pending = Some(Violation { virt_start, size, kind: ViolationKind::WritableExecutable });
+	    }
+	}
+    };
+
+    let pml4 = table(phys_offset, read_cr3());
+    for (i4, &e4) in pml4.iter().enumerate() {
+	if e4 & PRESENT == 0 {
+	    continue;
+	}
+	let pdpt = table(phys_offset, e4);
+	for (i3, &e3) in pdpt.iter().enumerate() {
+	    if e3 & PRESENT == 0 {
+		continue;
+	    }
+	    let base3 = canonical((i4 << 39 | i3 << 30) as u64);
+	    if e3 & HUGE_PAGE != 0 {
+		if is_wx(&[e4, e3], nx_enabled) {
+		    emit(base3, 1 << 30);
+		}
+		continue;
+	    }
+	    let pd = table(phys_offset, e3);
+	    for (i2, &e2) in pd.iter().enumerate() {
+		if e2 & PRESENT == 0 {
+		    continue;
+		}
+		let base2 = base3 + ((i2 as u64) << 21);
+		if e2 & HUGE_PAGE != 0 {
+		    if is_wx(&[e4, e3, e2], nx_enabled) {
+			emit(base2, 1 << 21);
+		    }
+		    continue;
+		}
+		let pt = table(phys_offset, e2);
+		for (i1, &e1) in pt.iter().enumerate() {
+		    if e1 & PRESENT != 0 && is_wx(&[e4, e3, e2, e1], nx_enabled) {
+			emit(base2 + ((i1 as u64) << 12), 1 << 12);
+		    }
+		}
+	    }
+	}
+    }
+
+    if let Some(v) = pending {
+	report(v);
+    }
+}
+
+fn is_wx(path: &[u64], nx_enabled: bool) -> bool {
+    let writable = path.iter().all(|e| e & WRITABLE != 0);
+    let executable = !nx_enabled || path.iter().all(|e| e & NO_EXECUTE == 0);
+    writable && executable
+}
+
+/// Sign extend bit 47 so addresses in the upper half of the PML4 are canonical.
+fn canonical(addr: u64) -> u64 {
+    if addr & (1 << 47) != 0 {
+	addr | 0xFFFF_0000_0000_0000
+    } else {
+	addr
+    }
+}
+
+unsafe fn table(phys_offset: u64, entry: u64) -> &'static [u64; ENTRIES_PER_TABLE] {
+    &*((phys_offset + (entry & ADDR_MASK)) as *const [u64; ENTRIES_PER_TABLE])
+}
+
+unsafe fn read_cr3() -> u64 {
+    let cr3: u64;
+    asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    cr3
+}
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    ((hi as u64) << 32) | lo as u64
+}
@@ -0,0 +1,29 @@
+pub mod audit;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Where physical memory is mapped in the page tables we were started on. Zero for the
+/// identity map our bootloader and Multiboot2 leave, Limine's HHDM otherwise.
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Record where the bootloader mapped physical memory, before `kmain` runs.
+pub fn set_phys_offset(offset: u64) {
+    PHYS_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Run the W^X audit over the page tables in CR3 and report what it finds to dmesg.
+pub fn audit_wx() {
+    let mut violations = 0;
+    // The bootloader maps all physical memory at PHYS_OFFSET, page tables included.
+    unsafe {
+	audit::audit_wx(PHYS_OFFSET.load(Ordering::Relaxed), |v| {
+	    violations += 1;
+	    crate::dmesg!("memory audit: {:#x}..{:#x} is {:?}", v.virt_start, v.virt_start.wrapping_add(v.size), v.kind);
+	});
+    }
+    if violations == 0 {
+	crate::dmesg!("memory audit: no writable and executable mappings");
+    } else {
+	crate::dmesg!("memory audit: {} writable and executable range(s)", violations);
+    }
+}
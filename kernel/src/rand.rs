@@ -0,0 +1,73 @@
+//! Kernel entropy source.
+//!
+//! Backed by the CPU's RDRAND instruction (the output of an on-chip, NIST SP 800-90A
+//! CTR_DRBG), which is available on every x86_64 machine we care about. Everything
+//! in the kernel that needs unpredictable bytes should get them from here rather than
+//! rolling its own counters.
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use core::fmt;
+
+use common::error::{ErrorCode, Subsystem};
+
+/// Intel recommends retrying RDRAND up to 10 times before treating the DRNG as broken.
+/// https://www.intel.com/content/www/us/en/developer/articles/guide/intel-digital-random-number-generator-drng-software-implementation-guide.html
+const RDRAND_RETRIES: usize = 10;
+
+#[derive(Debug)]
+pub enum EntropyErr {
+    /// The CPU doesn't implement RDRAND.
+    Unsupported,
+    /// RDRAND kept reporting that no random data was ready.
+    Exhausted,
+}
+
+impl ErrorCode for EntropyErr {
+    const SUBSYSTEM: Subsystem = Subsystem::Entropy;
+
+    fn code(&self) -> (u16, u32) {
+	match self {
+	    Self::Unsupported => (1, 0),
+	    Self::Exhausted => (2, 0),
+	}
+    }
+
+    fn describe(code: u16, _arg: u32, f: &mut fmt::Formatter) -> fmt::Result {
+	match code {
+	    1 => write!(f, "CPU doesn't support RDRAND"),
+	    2 => write!(f, "RDRAND has no random data ready"),
+	    _ => write!(f, "error {code}"),
+	}
+    }
+}
+
+/// Returns true if the boot CPU supports RDRAND (CPUID.01H:ECX.RDRAND[bit 30]).
+pub fn has_rdrand() -> bool {
+    let leaf = unsafe { __cpuid(1) };
+    leaf.ecx & (1 << 30) != 0
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill_bytes(buf: &mut [u8]) -> Result<(), EntropyErr> {
+    if !has_rdrand() {
+	return Err(EntropyErr::Unsupported);
+    }
+
+    for chunk in buf.chunks_mut(8) {
+	let r = unsafe { rdrand64()? };
+	chunk.copy_from_slice(&r.to_le_bytes()[..chunk.len()]);
+    }
+
+    Ok(())
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand64() -> Result<u64, EntropyErr> {
+    let mut r = 0;
+    for _ in 0..RDRAND_RETRIES {
+	if _rdrand64_step(&mut r) == 1 {
+	    return Ok(r);
+	}
+    }
+    Err(EntropyErr::Exhausted)
+}